@@ -0,0 +1,82 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use shared::cluster::{bucketed_merge, centroid_signature, greedy_cluster, greedy_merge};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+const DIM: usize = 64;
+const GROUPS: usize = 20_000;
+const CHUNK: usize = 2_000;
+const BITS: u32 = 12;
+const THRESHOLD: f32 = 0.985;
+
+fn normalized(mut v: [f32; DIM]) -> [f32; DIM] {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    v.iter_mut().for_each(|x| *x /= norm);
+    v
+}
+
+/// Groups of 1 to 5 near duplicates, shuffled and greedily clustered per chunk like stage1 does
+fn synthetic() -> (Vec<[f32; DIM]>, Vec<Vec<HashSet<Uuid>>>) {
+    let mut rng = Pcg64::seed_from_u64(7);
+    let mut vectors = Vec::new();
+    for _ in 0..GROUPS {
+        let center: [f32; DIM] = std::array::from_fn(|_| rng.random_range(-1.0..1.0));
+        for _ in 0..rng.random_range(1..=5) {
+            vectors.push(normalized(std::array::from_fn(|i| {
+                center[i] + rng.random_range(-0.01..0.01)
+            })));
+        }
+    }
+    let mut ids: Vec<Uuid> = (0..vectors.len() as u128).map(Uuid::from_u128).collect();
+    for i in (1..ids.len()).rev() {
+        ids.swap(i, rng.random_range(0..=i));
+    }
+    let rounds = ids
+        .chunks(CHUNK)
+        .map(|chunk| greedy_cluster(chunk, similar(&vectors)))
+        .collect();
+    (vectors, rounds)
+}
+
+fn similar(vectors: &[[f32; DIM]]) -> impl Fn(&Uuid, &Uuid) -> bool + Sync + '_ {
+    move |a, b| {
+        let (a, b) = (
+            &vectors[a.as_u128() as usize],
+            &vectors[b.as_u128() as usize],
+        );
+        a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>() > THRESHOLD
+    }
+}
+
+fn bench_merge(c: &mut Criterion) {
+    let (vectors, rounds) = synthetic();
+    let similar = similar(&vectors);
+    let signature = |cluster: &HashSet<Uuid>| {
+        let members = cluster.iter().map(|id| &vectors[id.as_u128() as usize][..]);
+        centroid_signature(members, BITS)
+    };
+    let serial = || {
+        let mut global = Vec::new();
+        for local in rounds.iter().flatten() {
+            greedy_merge(local.clone(), &mut global, &similar);
+        }
+        global
+    };
+    let bucketed = || bucketed_merge(rounds.clone(), BITS, signature, &similar, |_| {});
+    println!(
+        "{} local clusters, serial merge {} clusters, bucketed merge {} clusters",
+        rounds.iter().map(Vec::len).sum::<usize>(),
+        serial().len(),
+        bucketed().len()
+    );
+    let mut group = c.benchmark_group("global_merge");
+    group.sample_size(10);
+    group.bench_function("serial", |b| b.iter(serial));
+    group.bench_function("bucketed", |b| b.iter(bucketed));
+    group.finish();
+}
+
+criterion_group!(benches, bench_merge);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use half::bf16;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use shared::cosine_sim::{Kernels, cosine_sim, simd_capabilities};
+use std::hint::black_box;
+
+/// Pairs per iteration
+const PAIRS: usize = 10_000;
+
+fn random_pairs(rng: &mut Pcg64, dim: usize) -> Vec<(Vec<f32>, Vec<f32>)> {
+    let mut vector = || -> Vec<f32> { (0..dim).map(|_| rng.random_range(-1.0..1.0)).collect() };
+    (0..PAIRS).map(|_| (vector(), vector())).collect()
+}
+
+fn to_bf16(pairs: &[(Vec<f32>, Vec<f32>)]) -> Vec<(Vec<bf16>, Vec<bf16>)> {
+    let convert = |v: &[f32]| v.iter().map(|&x| bf16::from_f32(x)).collect();
+    pairs
+        .iter()
+        .map(|(a, b)| (convert(a), convert(b)))
+        .collect()
+}
+
+/// Detecting the CPU on every call, as `cosine_sim` used to, against the cached kernels
+///
+/// Short vectors are where the dispatch shows.
+fn bench_dispatch(c: &mut Criterion) {
+    let mut rng = Pcg64::seed_from_u64(1);
+    for dim in [32, 768] {
+        let pairs = random_pairs(&mut rng, dim);
+        let mut group = c.benchmark_group(format!("cosine_dispatch_{}", dim));
+        group.throughput(Throughput::Elements(PAIRS as u64));
+        group.bench_function("detect_per_call", |b| {
+            b.iter(|| {
+                pairs
+                    .iter()
+                    .map(|(a, b)| (black_box(Kernels::detect()).f32)(a, b))
+                    .sum::<f32>()
+            })
+        });
+        group.bench_function("cached", |b| {
+            b.iter(|| pairs.iter().map(|(a, b)| cosine_sim(a, b)).sum::<f32>())
+        });
+        group.finish();
+    }
+}
+
+/// Every kernel set this CPU runs against the scalar one, NEON on aarch64, AVX2 on x86_64
+fn bench_kernels(c: &mut Criterion) {
+    let mut rng = Pcg64::seed_from_u64(2);
+    let pairs = random_pairs(&mut rng, 768);
+    let pairs_bf16 = to_bf16(&pairs);
+    println!("cosine_sim runs {}", simd_capabilities());
+    let mut group = c.benchmark_group("cosine_kernels_768");
+    group.throughput(Throughput::Elements(PAIRS as u64));
+    for kernels in Kernels::supported() {
+        group.bench_function(format!("{}_f32", kernels.name), |b| {
+            b.iter(|| pairs.iter().map(|(a, b)| (kernels.f32)(a, b)).sum::<f32>())
+        });
+        group.bench_function(format!("{}_bf16", kernels.name), |b| {
+            b.iter(|| {
+                pairs_bf16
+                    .iter()
+                    .map(|(a, b)| (kernels.bf16)(a, b))
+                    .sum::<f32>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_dispatch, bench_kernels);
+criterion_main!(benches);
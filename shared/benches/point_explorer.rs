@@ -0,0 +1,162 @@
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use shared::hamming::{HammingKernel, hamming, naive_hamming};
+use shared::point_explorer::PointExplorer;
+use std::hint::black_box;
+use uuid::Uuid;
+
+/// Points of the u8/32 explorers stages 16 to 19 load
+const POINTS: usize = 1_000_000;
+const LOOKUPS: usize = 10_000;
+/// Pairs per iteration, about one KNN neighbourhood sweep
+const PAIRS: usize = 100_000;
+const COSINE_POINTS: usize = 10_000;
+
+fn random_hashes(rng: &mut Pcg64, n: usize) -> Vec<(Uuid, [u8; 32])> {
+    (0..n)
+        .map(|_| (Uuid::from_u128(rng.random()), rng.random()))
+        .collect()
+}
+
+fn random_pairs(rng: &mut Pcg64, ids: &[Uuid], n: usize) -> Vec<(Uuid, Uuid)> {
+    (0..n)
+        .map(|_| {
+            let a = ids[rng.random_range(0..ids.len())];
+            let b = ids[rng.random_range(0..ids.len())];
+            (a, b)
+        })
+        .collect()
+}
+
+fn u8_explorer(points: &[(Uuid, [u8; 32])]) -> PointExplorer<u8, 32> {
+    let mut explorer = PointExplorer::default();
+    explorer.extend(points.iter().map(|(id, v)| (id, v)));
+    explorer
+}
+
+fn bench_u8(c: &mut Criterion) {
+    let mut rng = Pcg64::seed_from_u64(16);
+    let points = random_hashes(&mut rng, POINTS);
+    let ids: Vec<Uuid> = points.iter().map(|(id, _)| *id).collect();
+    let lookups: Vec<Uuid> = (0..LOOKUPS)
+        .map(|_| ids[rng.random_range(0..ids.len())])
+        .collect();
+    let pairs = random_pairs(&mut rng, &ids, PAIRS);
+    let explorer = u8_explorer(&points);
+
+    let mut group = c.benchmark_group("point_explorer_u8_32");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(POINTS as u64));
+    group.bench_function("extend_1m", |b| {
+        b.iter_batched(
+            PointExplorer::<u8, 32>::default,
+            |mut explorer| {
+                explorer.extend(points.iter().map(|(id, v)| (id, v)));
+                explorer
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.throughput(Throughput::Elements(LOOKUPS as u64));
+    group.bench_function("get_vector", |b| {
+        b.iter(|| {
+            lookups
+                .iter()
+                .filter_map(|id| explorer.get_vector(id))
+                .map(|v| v[0] as u64)
+                .sum::<u64>()
+        })
+    });
+    group.bench_function("uuid2index", |b| {
+        b.iter(|| {
+            lookups
+                .iter()
+                .filter_map(|id| explorer.uuid2index(id))
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+
+    let vectors: Vec<(&[u8; 32], &[u8; 32])> = pairs
+        .iter()
+        .map(|(a, b)| {
+            let a = explorer.get_vector(a).unwrap();
+            let b = explorer.get_vector(b).unwrap();
+            (a, b)
+        })
+        .collect();
+    let naive: u64 = vectors
+        .iter()
+        .map(|(a, b)| naive_hamming(&a[..], &b[..]) as u64)
+        .sum();
+    let fast: u64 = vectors
+        .iter()
+        .map(|(a, b)| hamming(&a[..], &b[..]) as u64)
+        .sum();
+    assert_eq!(naive, fast, "hamming kernel disagrees with the naive one");
+
+    let mut group = c.benchmark_group("hamming_u8_32");
+    group.throughput(Throughput::Elements(PAIRS as u64));
+    group.bench_function("naive", |b| {
+        b.iter(|| {
+            vectors
+                .iter()
+                .map(|(a, b)| naive_hamming(black_box(&a[..]), black_box(&b[..])))
+                .sum::<u32>()
+        })
+    });
+    group.bench_function("hamming", |b| {
+        b.iter(|| {
+            vectors
+                .iter()
+                .map(|(a, b)| hamming(black_box(&a[..]), black_box(&b[..])))
+                .sum::<u32>()
+        })
+    });
+    for kernel in HammingKernel::supported() {
+        group.bench_function(kernel.name, |b| {
+            b.iter(|| {
+                vectors
+                    .iter()
+                    .map(|(a, b)| (kernel.bytes)(black_box(&a[..]), black_box(&b[..])))
+                    .sum::<u32>()
+            })
+        });
+    }
+    group.bench_function("get_hamming_dist", |b| {
+        b.iter(|| {
+            pairs
+                .iter()
+                .map(|(a, b)| explorer.get_hamming_dist((a, b)).unwrap())
+                .sum::<u32>()
+        })
+    });
+    group.finish();
+}
+
+fn bench_cosine(c: &mut Criterion) {
+    let mut rng = Pcg64::seed_from_u64(1);
+    let mut explorer: PointExplorer<f32, 768> = PointExplorer::default();
+    explorer.extend((0..COSINE_POINTS).map(|_| {
+        let v: Vec<f32> = (0..768).map(|_| rng.random_range(-1.0..1.0)).collect();
+        (Uuid::from_u128(rng.random()), v)
+    }));
+    let ids: Vec<Uuid> = explorer.iter().map(|(id, _)| *id).collect();
+    let pairs = random_pairs(&mut rng, &ids, PAIRS);
+
+    let mut group = c.benchmark_group("cosine_f32_768");
+    group.throughput(Throughput::Elements(PAIRS as u64));
+    group.bench_function("get_cosine_sim", |b| {
+        b.iter(|| {
+            pairs
+                .iter()
+                .map(|(a, b)| explorer.get_cosine_sim((a, b)).unwrap())
+                .sum::<f32>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_u8, bench_cosine);
+criterion_main!(benches);
@@ -0,0 +1,147 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// `<path>.<uuid>.tmp`, next to the destination so the final rename stays on one filesystem and
+/// unique so concurrent writers to the same destination do not share it
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(format!(".{}.tmp", Uuid::new_v4().simple()));
+    path.with_file_name(name)
+}
+
+/// Streams into a [`tmp_path`] of `path` through `f`, fsyncs, then renames over `path`.
+///
+/// If `f` (or any IO step) fails the temporary file is removed and `path` is left untouched.
+pub fn atomic_write_with<P, F, E>(path: P, f: F) -> Result<(), E>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), E>,
+    E: From<io::Error>,
+{
+    let path = path.as_ref();
+    let tmp = tmp_path(path);
+    let res = (|| -> Result<(), E> {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        f(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        sync_parent(path);
+        Ok(())
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+#[inline]
+pub fn atomic_write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    atomic_write_with(path, |w| w.write_all(contents.as_ref()))
+}
+
+/// Persists the rename itself, best effort
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = match parent.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => parent,
+        };
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("atomic_write_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn leftovers(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "tmp"))
+            .collect()
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        let dir = scratch_dir();
+        let path = dir.join("out.json");
+        atomic_write(&path, b"old").unwrap();
+        atomic_write(&path, b"new content").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new content");
+        assert!(leftovers(&dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failure_keeps_old_content() {
+        let dir = scratch_dir();
+        let path = dir.join("out.bin");
+        atomic_write(&path, b"old").unwrap();
+        let res = atomic_write_with(&path, |w| {
+            w.write_all(b"partial")?;
+            Err(io::Error::other("injected"))
+        });
+        assert_eq!(res.unwrap_err().to_string(), "injected");
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert!(leftovers(&dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failure_leaves_destination_absent() {
+        let dir = scratch_dir();
+        let path = dir.join("out.bin");
+        let res: Result<(), io::Error> = atomic_write_with(&path, |w| {
+            w.write_all(&[0u8; 1 << 16])?;
+            Err(io::Error::other("injected"))
+        });
+        assert!(res.is_err());
+        assert!(!path.exists());
+        assert!(leftovers(&dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_tmp_path() {
+        let path = Path::new("a/b/final_classification.json");
+        let tmp = tmp_path(path);
+        assert_eq!(tmp.parent(), path.parent());
+        let name = tmp.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("final_classification.json.") && name.ends_with(".tmp"));
+        assert_ne!(tmp_path(path), tmp);
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let dir = scratch_dir();
+        let path = dir.join("out.bin");
+        std::thread::scope(|s| {
+            for n in 0..8u8 {
+                let path = &path;
+                s.spawn(move || atomic_write(path, [n; 1 << 16]).unwrap());
+            }
+        });
+        // one writer's content in full, never a mix
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 1 << 16);
+        assert!(data.iter().all(|b| *b == data[0]));
+        assert!(leftovers(&dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
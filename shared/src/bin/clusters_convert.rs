@@ -0,0 +1,40 @@
+use clap::{Parser, ValueEnum};
+use shared::cluster_file::{ClusterFileResult, LegacyFormat, convert_clusters};
+use std::path::PathBuf;
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum Format {
+    /// `Vec<HashSet<Uuid>>` pickle, e.g. `global_clusters.pkl`
+    Pickle,
+    /// bincode `Vec<HashSet<Uuid>>`, e.g. stage14's `clusters.bin`
+    Bincode,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "clusters-convert",
+    version,
+    about = "Rewrite a legacy cluster file as records the stages can stream"
+)]
+struct Cli {
+    input: PathBuf,
+    output: PathBuf,
+    #[arg(long, value_enum, default_value = "pickle")]
+    from: Format,
+}
+
+fn main() -> ClusterFileResult<()> {
+    let cli = Cli::parse();
+    let legacy = match cli.from {
+        Format::Pickle => LegacyFormat::Pickle,
+        Format::Bincode => LegacyFormat::Bincode,
+    };
+    let count = convert_clusters(&cli.input, legacy, &cli.output)?;
+    println!(
+        "Converted {} clusters from {} to {}",
+        count,
+        cli.input.display(),
+        cli.output.display()
+    );
+    Ok(())
+}
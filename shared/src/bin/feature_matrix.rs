@@ -0,0 +1,303 @@
+//! Compiles `shared` with every feature alone and with the feature sets the workspace crates
+//! depend on it with, so a module using another one without declaring the feature fails here
+//! rather than in the first stage that happens to enable that combination
+//!
+//! `cargo run -p shared --bin feature-matrix --features feature-matrix -- --all-targets`
+
+use anyhow::Context;
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::process::Command;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "feature-matrix",
+    version,
+    about = "Check that shared compiles with each feature and each feature set the workspace uses"
+)]
+struct Cli {
+    /// Cargo.toml of `shared`
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))]
+    manifest_path: PathBuf,
+    /// Check tests, benches and bins too, not only the library
+    #[arg(long)]
+    all_targets: bool,
+    /// Features whose combinations are skipped, e.g. `shared-pyo3` without a Python to link
+    #[arg(long, value_delimiter = ',')]
+    skip: Vec<String>,
+    /// Stop at the first failing combination
+    #[arg(long)]
+    fail_fast: bool,
+    /// Passed on to every `cargo check`, e.g. `-- --offline`
+    #[arg(last = true)]
+    cargo_args: Vec<String>,
+}
+
+/// The parts of `cargo metadata` read here
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Package {
+    name: String,
+    features: BTreeMap<String, Vec<String>>,
+    dependencies: Vec<Dependency>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Dependency {
+    name: String,
+    kind: Option<String>,
+    features: Vec<String>,
+    uses_default_features: bool,
+}
+
+/// Features `shared` is checked with, `--no-default-features` unless `default` is set
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Combination {
+    default: bool,
+    features: BTreeSet<String>,
+}
+
+impl Combination {
+    fn new<I: IntoIterator<Item = S>, S: Into<String>>(default: bool, features: I) -> Self {
+        Self {
+            default,
+            features: features.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn cargo_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if !self.default {
+            args.push("--no-default-features".to_string());
+        }
+        if !self.features.is_empty() {
+            let features: Vec<&str> = self.features.iter().map(String::as_str).collect();
+            args.push(format!("--features={}", features.join(",")));
+        }
+        args
+    }
+}
+
+/// `features` with every feature they enable, dependencies (`dep:x`, `x/y`) left out
+fn expand(features: &BTreeMap<String, Vec<String>>, roots: &BTreeSet<String>) -> BTreeSet<String> {
+    let mut enabled = BTreeSet::new();
+    let mut stack: Vec<&str> = roots.iter().map(String::as_str).collect();
+    while let Some(feature) = stack.pop() {
+        let Some(enables) = features.get(feature) else {
+            continue;
+        };
+        if enabled.insert(feature.to_string()) {
+            stack.extend(enables.iter().map(String::as_str));
+        }
+    }
+    enabled
+}
+
+/// Each feature alone, none, the defaults, and every set a workspace crate depends on `shared`
+/// with, labelled by the crates using it
+///
+/// The implicit features of optional dependencies are left out, they enable no module.
+fn combinations(metadata: &Metadata) -> anyhow::Result<BTreeMap<Combination, Vec<String>>> {
+    let shared = metadata
+        .packages
+        .iter()
+        .find(|p| p.name == "shared")
+        .context("shared is not a workspace member")?;
+    let mut matrix: BTreeMap<Combination, Vec<String>> = BTreeMap::new();
+    matrix
+        .entry(Combination::new(false, Vec::<String>::new()))
+        .or_default()
+        .push("none".to_string());
+    for (feature, enables) in &shared.features {
+        if *enables == [format!("dep:{}", feature)] {
+            continue;
+        }
+        let combination = match feature.as_str() {
+            "default" => Combination::new(true, Vec::<String>::new()),
+            feature => Combination::new(false, [feature]),
+        };
+        matrix.entry(combination).or_default().push(feature.clone());
+    }
+    for package in &metadata.packages {
+        for dep in package.dependencies.iter().filter(|d| d.name == "shared") {
+            let label = match &dep.kind {
+                Some(kind) => format!("{} ({})", package.name, kind),
+                None => package.name.clone(),
+            };
+            let combination = Combination::new(dep.uses_default_features, dep.features.clone());
+            matrix.entry(combination).or_default().push(label);
+        }
+    }
+    Ok(matrix)
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let output = Command::new(&cargo)
+        .args([
+            "metadata",
+            "--format-version=1",
+            "--no-deps",
+            "--manifest-path",
+        ])
+        .arg(&cli.manifest_path)
+        .output()
+        .context("Failed to run cargo metadata")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "cargo metadata failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let metadata: Metadata = serde_json::from_slice(&output.stdout)?;
+    let features = &metadata
+        .packages
+        .iter()
+        .find(|p| p.name == "shared")
+        .context("shared is not a workspace member")?
+        .features;
+    let skip: BTreeSet<String> = cli.skip.iter().cloned().collect();
+
+    let matrix = combinations(&metadata)?;
+    let mut failed = Vec::new();
+    let mut skipped = 0;
+    for (idx, (combination, labels)) in matrix.iter().enumerate() {
+        let mut roots = combination.features.clone();
+        if combination.default {
+            roots.insert("default".to_string());
+        }
+        let labels = labels.join(", ");
+        if !expand(features, &roots).is_disjoint(&skip) {
+            println!("[{}/{}] skipped {}", idx + 1, matrix.len(), labels);
+            skipped += 1;
+            continue;
+        }
+        println!("[{}/{}] checking {}", idx + 1, matrix.len(), labels);
+        let status = Command::new(&cargo)
+            .args(["check", "--quiet", "--package=shared", "--manifest-path"])
+            .arg(&cli.manifest_path)
+            .arg(if cli.all_targets {
+                "--all-targets"
+            } else {
+                "--lib"
+            })
+            .args(combination.cargo_args())
+            .args(&cli.cargo_args)
+            .status()
+            .context("Failed to run cargo check")?;
+        if !status.success() {
+            eprintln!("FAILED {}: {}", labels, combination.cargo_args().join(" "));
+            failed.push(labels);
+            if cli.fail_fast {
+                break;
+            }
+        }
+    }
+    println!(
+        "{} combinations, {} failed, {} skipped",
+        matrix.len(),
+        failed.len(),
+        skipped
+    );
+    anyhow::ensure!(
+        failed.is_empty(),
+        "Broken feature combinations: {}",
+        failed.join("; ")
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(entries: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        entries
+            .iter()
+            .map(|(name, enables)| {
+                let enables = enables.iter().map(|e| e.to_string()).collect();
+                (name.to_string(), enables)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let features = features(&[
+            ("default", &["shared-structure"]),
+            ("shared-structure", &[]),
+            ("point-explorer", &["dep:url", "atomic-write", "bincode"]),
+            ("point-explorer-pyo3", &["shared-pyo3", "point-explorer"]),
+            ("shared-pyo3", &["shared-structure", "pyo3/macros"]),
+            ("atomic-write", &[]),
+        ]);
+        let roots = BTreeSet::from(["point-explorer-pyo3".to_string()]);
+        let expanded: Vec<String> = expand(&features, &roots).into_iter().collect();
+        assert_eq!(
+            expanded,
+            [
+                "atomic-write",
+                "point-explorer",
+                "point-explorer-pyo3",
+                "shared-pyo3",
+                "shared-structure"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_combinations() {
+        let package = |name: &str, features: &[(&str, &[&str])], deps: Vec<Dependency>| Package {
+            name: name.to_string(),
+            features: self::features(features),
+            dependencies: deps,
+        };
+        let dep = |default: bool, features: &[&str], kind: Option<&str>| Dependency {
+            name: "shared".to_string(),
+            kind: kind.map(str::to_string),
+            features: features.iter().map(|f| f.to_string()).collect(),
+            uses_default_features: default,
+        };
+        let metadata = Metadata {
+            packages: vec![
+                package(
+                    "shared",
+                    &[
+                        ("default", &["hamming"]),
+                        ("hamming", &[]),
+                        ("cluster", &["dep:petgraph"]),
+                        ("petgraph", &["dep:petgraph"]),
+                    ],
+                    vec![],
+                ),
+                package("stage1", &[], vec![dep(true, &["cluster"], None)]),
+                package("stage2", &[], vec![dep(true, &["cluster"], Some("dev"))]),
+                package("stage3", &[], vec![dep(false, &["hamming"], None)]),
+            ],
+        };
+        let matrix = combinations(&metadata).unwrap();
+        let labels = |default, features: &[&str]| {
+            matrix[&Combination::new(default, features.iter().copied())].clone()
+        };
+        assert_eq!(matrix.len(), 5);
+        assert_eq!(labels(false, &[]), ["none"]);
+        assert_eq!(labels(true, &[]), ["default"]);
+        assert_eq!(labels(false, &["hamming"]), ["hamming", "stage3"]);
+        assert_eq!(labels(true, &["cluster"]), ["stage1", "stage2 (dev)"]);
+        assert_eq!(
+            Combination::new(false, ["b", "a"]).cargo_args(),
+            ["--no-default-features", "--features=a,b"]
+        );
+        assert!(
+            Combination::new(true, Vec::<String>::new())
+                .cargo_args()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use clap::Parser;
+use shared::fixtures::{ClusterSpec, FixtureResult, FixtureSpec, GifMotion};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "gen-fixtures",
+    version,
+    about = "Generate a small synthetic dataset with known duplicate clusters"
+)]
+struct Cli {
+    /// Directory the points map, explorers, listing, ground truth and objects are written to
+    #[arg(long, default_value = "fixtures")]
+    out: PathBuf,
+    #[arg(long, default_value = "0")]
+    seed: u64,
+    #[arg(long, default_value = "8")]
+    clusters: usize,
+    #[arg(long, default_value = "4")]
+    cluster_size: usize,
+    /// Pairwise cosine similarity of image vectors within a cluster
+    #[arg(long, default_value = "0.99")]
+    image_sim: f32,
+    /// Bits each hash of a cluster differs from its base
+    #[arg(long, default_value = "4")]
+    hash_flips: usize,
+    /// Clusters, from the first, whose points have text
+    #[arg(long, default_value = "2")]
+    text_clusters: usize,
+    /// Pairwise cosine similarity of text vectors within a cluster
+    #[arg(long, default_value = "0.95")]
+    text_sim: f32,
+    /// Clusters, from the last, of moving GIFs
+    #[arg(long, default_value = "2")]
+    gif_clusters: usize,
+    /// Clusters, before the moving ones, of GIFs whose frames are all the same
+    #[arg(long, default_value = "1")]
+    still_gif_clusters: usize,
+    #[arg(long, default_value = "8")]
+    gif_frames: usize,
+    #[arg(long, default_value = "16")]
+    singletons: usize,
+}
+
+fn main() -> FixtureResult<()> {
+    let cli = Cli::parse();
+    let mut spec = FixtureSpec::new(cli.seed).singletons(cli.singletons);
+    let moving_from = cli.clusters.saturating_sub(cli.gif_clusters);
+    let still_from = moving_from.saturating_sub(cli.still_gif_clusters);
+    for idx in 0..cli.clusters {
+        let mut cluster =
+            ClusterSpec::new(cli.cluster_size, cli.image_sim).hash_flips(cli.hash_flips);
+        if idx < cli.text_clusters {
+            cluster = cluster.text_sim(cli.text_sim);
+        }
+        match idx {
+            idx if idx >= moving_from => cluster = cluster.gif(cli.gif_frames, GifMotion::Moving),
+            idx if idx >= still_from => cluster = cluster.gif(cli.gif_frames, GifMotion::Still),
+            _ => {}
+        }
+        spec = spec.cluster(cluster);
+    }
+    let fixture = spec.generate()?;
+    let paths = fixture.write(&cli.out)?;
+    println!(
+        "{} points in {} clusters and {} singletons written to {}",
+        fixture.points.len(),
+        cli.clusters,
+        cli.singletons,
+        cli.out.display()
+    );
+    println!("{:#?}", paths);
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! Runs the steps of a pipeline plan in order, skipping those whose outputs are up to date
+//!
+//! `cargo run -p shared --features pipeline --bin pipeline -- assets/pipelines/fixtures.toml`,
+//! `assets/pipelines/shadow.toml` rehearses stage11 on a shadow collection before the live run
+
+use clap::Parser;
+use shared::pipeline::{Decision, Plan, Snapshot, resolve, run_step};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "pipeline",
+    version,
+    about = "Run the steps of a TOML pipeline plan whose outputs are missing or stale"
+)]
+struct Cli {
+    /// TOML plan, its paths are relative to it
+    plan: PathBuf,
+    /// First step to consider, the earlier ones are left alone
+    #[arg(long)]
+    from: Option<String>,
+    /// Last step to consider, the later ones are left alone
+    #[arg(long)]
+    until: Option<String>,
+    /// Run every step in the range, up to date or not
+    #[arg(long)]
+    force: bool,
+    /// Print the execution plan and exit
+    #[arg(long)]
+    dry_run: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let plan = Plan::load(&cli.plan)?;
+    let range = plan.range(cli.from.as_deref(), cli.until.as_deref())?;
+    let snapshot = Snapshot::load(&plan)?;
+    let decisions = resolve(&plan, &snapshot, range, cli.force)?;
+    let total = plan.steps.len();
+
+    let mut ran = 0;
+    for (idx, (step, decision)) in plan.steps.iter().zip(&decisions).enumerate() {
+        let reason = match decision {
+            Decision::Excluded => {
+                println!("[{}/{}] {}: excluded", idx + 1, total, step.name);
+                continue;
+            }
+            Decision::UpToDate => {
+                println!("[{}/{}] {}: up to date", idx + 1, total, step.name);
+                continue;
+            }
+            Decision::Run(reason) => reason,
+        };
+        let command = plan.command_line(step)?.join(" ");
+        if cli.dry_run {
+            println!(
+                "[{}/{}] {}: would run, {}",
+                idx + 1,
+                total,
+                step.name,
+                reason
+            );
+            println!("    {}", command);
+            continue;
+        }
+        println!("[{}/{}] {}: running, {}", idx + 1, total, step.name, reason);
+        println!("    {}", command);
+        let start = Instant::now();
+        run_step(&plan, idx)?;
+        println!(
+            "[{}/{}] {}: done in {:.1?}",
+            idx + 1,
+            total,
+            step.name,
+            start.elapsed()
+        );
+        ran += 1;
+    }
+    match cli.dry_run {
+        true => println!("Dry run, nothing executed"),
+        false => println!("{} of {} steps run", ran, total),
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use clap::Parser;
+use shared::config::DEFAULT_IMAGE_SIM;
+use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
+use shared::quant::{
+    QuantResult, calibrate, estimate_recall, params_path, quantize, sample_pairs, save_quantized,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "quantize-explorer",
+    version,
+    about = "Quantize an f32/768 explorer to int8 and estimate what it costs at a threshold"
+)]
+struct Cli {
+    input: String,
+    /// Quantized explorer, its params go next to it with a `.quant` extension
+    output: String,
+    /// Points the per dimension ranges are taken from
+    #[arg(long, default_value = "10000")]
+    sample_size: usize,
+    /// Similarity threshold the recall is estimated at
+    #[arg(long, default_value_t = DEFAULT_IMAGE_SIM)]
+    threshold: f32,
+    /// Points compared with their nearest neighbour, each one scans the whole explorer
+    #[arg(long, default_value = "100")]
+    recall_pairs: usize,
+}
+
+fn main() -> QuantResult<()> {
+    let cli = Cli::parse();
+    let explorer: PointExplorer<f32, 768> = PointExplorerBuilder::new().path(&cli.input).build()?;
+    let params = calibrate(&explorer, cli.sample_size)?;
+    let quantized = quantize(&explorer, &params)?;
+    save_quantized(&quantized, &params, &cli.output)?;
+    println!(
+        "Quantized {} points to {}, params in {}",
+        quantized.len(),
+        cli.output,
+        params_path(&cli.output)
+    );
+    let pairs = sample_pairs(&explorer, cli.recall_pairs);
+    let estimate = estimate_recall(&explorer, &quantized, &params, cli.threshold, &pairs)?;
+    println!(
+        "Recall {:.4} at {} over {} pairs: {:?}",
+        estimate.recall, cli.threshold, estimate.pairs, estimate
+    );
+    Ok(())
+}
@@ -0,0 +1,339 @@
+use petgraph::unionfind::UnionFind;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Greedy complete-linkage pass used by stage1
+///
+/// A point joins the first cluster it is similar to every member of, otherwise it opens a new one.
+/// The result depends on the order of `ids`.
+pub fn greedy_cluster<F>(ids: &[Uuid], similar: F) -> Vec<HashSet<Uuid>>
+where
+    F: Fn(&Uuid, &Uuid) -> bool,
+{
+    let mut clusters: Vec<HashSet<Uuid>> = Vec::new();
+    for &id in ids {
+        match clusters
+            .iter_mut()
+            .find(|cl| cl.iter().all(|other| similar(&id, other)))
+        {
+            Some(cl) => {
+                cl.insert(id);
+            }
+            None => clusters.push(HashSet::from([id])),
+        }
+    }
+    clusters
+}
+
+fn all_similar<F>(a: &HashSet<Uuid>, b: &HashSet<Uuid>, similar: F) -> bool
+where
+    F: Fn(&Uuid, &Uuid) -> bool,
+{
+    a.iter().all(|i| b.iter().all(|j| similar(i, j)))
+}
+
+/// Merges a chunk-local cluster into the first global cluster whose every pair with it is similar
+pub fn greedy_merge<F>(local: HashSet<Uuid>, global: &mut Vec<HashSet<Uuid>>, similar: F)
+where
+    F: Fn(&Uuid, &Uuid) -> bool,
+{
+    for g in global.iter_mut() {
+        if all_similar(&local, g, &similar) {
+            g.extend(local);
+            return;
+        }
+    }
+    global.push(local);
+}
+
+/// Sign bits of the first `bits` components of the centroid of `vectors`, a cheap bucket key for
+/// [`bucketed_merge`]
+pub fn centroid_signature<'a, I>(vectors: I, bits: u32) -> u64
+where
+    I: IntoIterator<Item = &'a [f32]>,
+{
+    assert!(bits <= u64::BITS, "at most {} signature bits", u64::BITS);
+    let mut sum = vec![0f32; bits as usize];
+    for vector in vectors {
+        for (s, x) in sum.iter_mut().zip(vector) {
+            *s += x;
+        }
+    }
+    sum.iter()
+        .enumerate()
+        .filter(|(_, s)| **s >= 0.0)
+        .fold(0, |signature, (bit, _)| signature | 1 << bit)
+}
+
+/// [`greedy_merge`] of every round of local clusters, in parallel across signature buckets
+///
+/// A local cluster is only checked against global clusters of its own bucket, buckets merge
+/// independently of each other. A serial pass then merges clusters whose signatures differ in one
+/// of the low `bits` bits, which catches groups split by a sign flip near zero. Clusters further
+/// apart in signature are never compared, so the result can have more clusters than the serial
+/// merge, but every cluster is still similar across all of its pairs.
+pub fn bucketed_merge<R, S, F, P>(
+    rounds: R,
+    bits: u32,
+    signature: S,
+    similar: F,
+    progress: P,
+) -> Vec<HashSet<Uuid>>
+where
+    R: IntoIterator<Item = Vec<HashSet<Uuid>>>,
+    S: Fn(&HashSet<Uuid>) -> u64 + Sync,
+    F: Fn(&Uuid, &Uuid) -> bool + Sync,
+    P: Fn(usize),
+{
+    let mut buckets: HashMap<u64, Vec<HashSet<Uuid>>> = HashMap::new();
+    for round in rounds {
+        let len = round.len();
+        let signed: Vec<(u64, HashSet<Uuid>)> = round
+            .into_par_iter()
+            .map(|local| (signature(&local), local))
+            .collect();
+        let mut grouped: HashMap<u64, Vec<HashSet<Uuid>>> = HashMap::new();
+        for (key, local) in signed {
+            grouped.entry(key).or_default().push(local);
+        }
+        for &key in grouped.keys() {
+            buckets.entry(key).or_default();
+        }
+        let work: Vec<_> = buckets
+            .iter_mut()
+            .filter_map(|(key, global)| grouped.remove(key).map(|locals| (global, locals)))
+            .collect();
+        work.into_par_iter().for_each(|(global, locals)| {
+            for local in locals {
+                greedy_merge(local, global, &similar);
+            }
+        });
+        progress(len);
+    }
+    reconcile_buckets(buckets, bits, &similar)
+}
+
+/// Serial part of [`bucketed_merge`], buckets in key order so the result does not depend on hashing
+fn reconcile_buckets<F>(
+    mut buckets: HashMap<u64, Vec<HashSet<Uuid>>>,
+    bits: u32,
+    similar: F,
+) -> Vec<HashSet<Uuid>>
+where
+    F: Fn(&Uuid, &Uuid) -> bool,
+{
+    let mut keys: Vec<u64> = buckets.keys().copied().collect();
+    keys.sort_unstable();
+    let mut done: HashMap<u64, Vec<HashSet<Uuid>>> = HashMap::with_capacity(keys.len());
+    for key in keys.iter() {
+        let clusters = buckets.remove(key).unwrap_or_default();
+        let mut kept = Vec::with_capacity(clusters.len());
+        for cluster in clusters {
+            let target = (0..bits).map(|bit| key ^ 1 << bit).find_map(|neighbour| {
+                done.get(&neighbour)?
+                    .iter()
+                    .position(|g| all_similar(&cluster, g, &similar))
+                    .map(|i| (neighbour, i))
+            });
+            match target {
+                Some((neighbour, i)) => {
+                    done.get_mut(&neighbour).expect("found above")[i].extend(cluster)
+                }
+                None => kept.push(cluster),
+            }
+        }
+        done.insert(*key, kept);
+    }
+    keys.iter()
+        .flat_map(|key| done.remove(key).unwrap_or_default())
+        .collect()
+}
+
+/// Member pairs not similar to each other, checked for up to `samples` evenly spaced clusters
+///
+/// Empty for the output of [`greedy_merge`] and [`bucketed_merge`], which only ever join clusters
+/// that are similar across all pairs.
+pub fn sampled_linkage_violations<F>(
+    clusters: &[HashSet<Uuid>],
+    samples: usize,
+    similar: F,
+) -> Vec<(Uuid, Uuid)>
+where
+    F: Fn(&Uuid, &Uuid) -> bool + Sync,
+{
+    let step = (clusters.len() / samples.max(1)).max(1);
+    clusters
+        .par_iter()
+        .step_by(step)
+        .flat_map_iter(|cluster| {
+            let members: Vec<&Uuid> = cluster.iter().collect();
+            let mut violations = Vec::new();
+            for (i, a) in members.iter().enumerate() {
+                for b in members[i + 1..].iter() {
+                    if !similar(a, b) {
+                        violations.push((**a, **b));
+                    }
+                }
+            }
+            violations
+        })
+        .collect()
+}
+
+/// [`greedy_cluster`] over chunks of `chunk_size` followed by [`greedy_merge`], stage1 without the rayon fan-out
+pub fn greedy_cluster_chunked<F>(ids: &[Uuid], chunk_size: usize, similar: F) -> Vec<HashSet<Uuid>>
+where
+    F: Fn(&Uuid, &Uuid) -> bool,
+{
+    let mut global = Vec::new();
+    for chunk in ids.chunks(chunk_size.max(1)) {
+        for local in greedy_cluster(chunk, &similar) {
+            greedy_merge(local, &mut global, &similar);
+        }
+    }
+    global
+}
+
+/// Single-linkage clustering used by stage14, `linked` is asked once for every pair `i < j` in `0..n`
+pub fn union_find_cluster<F>(n: usize, mut linked: F) -> Vec<Vec<usize>>
+where
+    F: FnMut(usize, usize) -> bool,
+{
+    let mut uf = UnionFind::<usize>::new(n);
+    for i in 0..n {
+        for j in (i + 1)..n {
+            if linked(i, j) {
+                uf.union(i, j);
+            }
+        }
+    }
+    group_roots(n, &mut uf)
+}
+
+/// Connected components of the graph on `0..n`, e.g. built from kNN edges
+pub fn connected_components<I>(n: usize, edges: I) -> Vec<Vec<usize>>
+where
+    I: IntoIterator<Item = (usize, usize)>,
+{
+    let mut uf = UnionFind::<usize>::new(n);
+    for (i, j) in edges {
+        uf.union(i, j);
+    }
+    group_roots(n, &mut uf)
+}
+
+/// Components ordered by their smallest member, members in ascending order
+fn group_roots(n: usize, uf: &mut UnionFind<usize>) -> Vec<Vec<usize>> {
+    let mut slots: HashMap<usize, usize> = HashMap::new();
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for i in 0..n {
+        let root = uf.find_mut(i);
+        let slot = *slots.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[slot].push(i);
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points on a line, similar when at most 1 apart
+    fn near(a: &Uuid, b: &Uuid) -> bool {
+        a.as_u128().abs_diff(b.as_u128()) <= 1
+    }
+
+    #[test]
+    fn test_greedy_is_complete_linkage() {
+        let ids: Vec<Uuid> = [1, 2, 3, 10].map(Uuid::from_u128).to_vec();
+        let clusters = greedy_cluster(&ids, near);
+        let expected: Vec<HashSet<Uuid>> = vec![
+            HashSet::from([1, 2].map(Uuid::from_u128)),
+            HashSet::from([Uuid::from_u128(3)]),
+            HashSet::from([Uuid::from_u128(10)]),
+        ];
+        assert_eq!(clusters, expected);
+        // a chunk boundary between 2 and 3 does not change the outcome
+        assert_eq!(greedy_cluster_chunked(&ids, 2, near), expected);
+    }
+
+    #[test]
+    fn test_union_find_is_single_linkage() {
+        let points = [1u128, 2, 3, 10];
+        let clusters = union_find_cluster(points.len(), |i, j| points[i].abs_diff(points[j]) <= 1);
+        assert_eq!(clusters, vec![vec![0, 1, 2], vec![3]]);
+        assert_eq!(
+            connected_components(5, [(4, 1), (2, 3)]),
+            vec![vec![0], vec![1, 4], vec![2, 3]]
+        );
+    }
+
+    fn ids(v: &[u128]) -> HashSet<Uuid> {
+        v.iter().copied().map(Uuid::from_u128).collect()
+    }
+
+    #[test]
+    fn test_centroid_signature() {
+        let vectors: [&[f32]; 2] = [&[1.0, -2.0, 0.5, 9.0], &[1.0, 1.0, -1.0, 9.0]];
+        assert_eq!(centroid_signature(vectors, 3), 0b001);
+        assert_eq!(centroid_signature(vectors, 4), 0b1001);
+        assert_eq!(centroid_signature([&[0.0f32][..]], 1), 1);
+    }
+
+    #[test]
+    fn test_bucketed_merge_single_bucket_is_serial() {
+        let ids: Vec<Uuid> = (0..40u128).map(|i| Uuid::from_u128(i * 3 % 40)).collect();
+        let rounds: Vec<_> = ids.chunks(7).map(|c| greedy_cluster(c, near)).collect();
+        let merged = bucketed_merge(rounds, 0, |_| 0, near, |_| {});
+        assert_eq!(merged, greedy_cluster_chunked(&ids, 7, near));
+    }
+
+    #[test]
+    fn test_bucketed_merge_keeps_linkage() {
+        let ids: Vec<Uuid> = (0..500u128).map(|i| Uuid::from_u128(i * 7 % 500)).collect();
+        let rounds: Vec<_> = ids.chunks(50).map(|c| greedy_cluster(c, near)).collect();
+        let locals: usize = rounds.iter().map(Vec::len).sum();
+        let seen = std::sync::atomic::AtomicUsize::new(0);
+        let signature = |c: &HashSet<Uuid>| (c.iter().min().unwrap().as_u128() / 5) as u64 & 0b111;
+        let merged = bucketed_merge(rounds, 3, signature, near, |n| {
+            seen.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+        });
+        assert_eq!(seen.into_inner(), locals);
+        let mut members: Vec<Uuid> = merged.iter().flatten().copied().collect();
+        members.sort();
+        let mut expected = ids.clone();
+        expected.sort();
+        assert_eq!(members, expected);
+        assert!(merged.iter().all(|c| all_similar(c, c, near)));
+        assert!(sampled_linkage_violations(&merged, merged.len(), near).is_empty());
+        // complete linkage on a line caps clusters at 2 points
+        assert!(merged.iter().all(|c| c.len() <= 2));
+    }
+
+    #[test]
+    fn test_reconcile_neighbouring_buckets() {
+        let keys = HashMap::from([(1u128, 0b01u64), (2, 0b11), (5, 0b00), (6, 0b11)]);
+        let signature = |c: &HashSet<Uuid>| keys[&c.iter().next().unwrap().as_u128()];
+        let rounds = vec![vec![ids(&[1]), ids(&[5])], vec![ids(&[2]), ids(&[6])]];
+        let merged = bucketed_merge(rounds, 3, signature, near, |_| {});
+        // 1 and 2 differ in one signature bit, 5 and 6 in two
+        assert_eq!(merged, vec![ids(&[5]), ids(&[1, 2]), ids(&[6])]);
+    }
+
+    #[test]
+    fn test_sampled_linkage_violations() {
+        let clusters = vec![ids(&[1, 2]), ids(&[1, 5]), ids(&[7, 8]), ids(&[10, 20])];
+        let mut found: Vec<_> = sampled_linkage_violations(&clusters, 4, near)
+            .into_iter()
+            .map(|(a, b)| (a.min(b).as_u128(), a.max(b).as_u128()))
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![(1, 5), (10, 20)]);
+        // every second cluster, the broken ones at odd positions are skipped
+        assert!(sampled_linkage_violations(&clusters, 2, near).is_empty());
+    }
+}
@@ -0,0 +1,375 @@
+//! Cluster files read one cluster at a time, a multi-GB `global_clusters.pkl` never has to be
+//! held as raw bytes and as a `Vec<HashSet<Uuid>>` at once
+
+use crate::atomic_write::atomic_write_with;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Layout: `MAGIC | count: u64 LE | count * (len: u32 LE | bincode (standard config) HashSet<Uuid>)`
+///
+/// Files without the header are read as the [`LegacyFormat`] their consumer names.
+pub const CLUSTERS_MAGIC: &[u8; 8] = b"NKCLSTR1";
+
+#[derive(Debug, Error)]
+pub enum ClusterFileError {
+    #[error("Cluster file ends after {read} of {expected} clusters")]
+    Truncated { read: usize, expected: u64 },
+    #[error("Cluster record {index} is {len} bytes but decodes from {consumed}")]
+    RecordLength {
+        index: usize,
+        len: usize,
+        consumed: usize,
+    },
+    #[error("Cluster record of {0} bytes does not fit the u32 length prefix")]
+    RecordTooLarge(usize),
+    #[error(transparent)]
+    DecodeError(#[from] bincode::error::DecodeError),
+    #[error(transparent)]
+    EncodeError(#[from] bincode::error::EncodeError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    SerdePickleError(#[from] serde_pickle::Error),
+}
+
+pub type ClusterFileResult<T> = Result<T, ClusterFileError>;
+
+/// What a cluster file without [`CLUSTERS_MAGIC`] holds
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LegacyFormat {
+    /// `serde_pickle` `Vec<HashSet<Uuid>>` as stage1 writes it, decoded whole before iterating
+    Pickle,
+    /// bincode (standard config) `Vec<HashSet<Uuid>>` as stage14 writes it, decoded a cluster
+    /// at a time
+    Bincode,
+}
+
+enum Source<R> {
+    Records { reader: R, buf: Vec<u8> },
+    Bincode(R),
+    Pickle(std::vec::IntoIter<HashSet<Uuid>>),
+}
+
+/// `(index, cluster)` of every cluster of a cluster file, in file order, fused after an error
+pub struct ClusterReader<R> {
+    source: Source<R>,
+    count: u64,
+    next: usize,
+    failed: bool,
+}
+
+impl<R: BufRead> ClusterReader<R> {
+    pub fn new(mut reader: R, legacy: LegacyFormat) -> ClusterFileResult<Self> {
+        let config = bincode::config::standard();
+        let (source, count) = if reader.fill_buf()?.starts_with(CLUSTERS_MAGIC) {
+            reader.consume(CLUSTERS_MAGIC.len());
+            let mut count = [0u8; 8];
+            reader.read_exact(&mut count)?;
+            let buf = Vec::new();
+            (Source::Records { reader, buf }, u64::from_le_bytes(count))
+        } else {
+            match legacy {
+                LegacyFormat::Bincode => {
+                    // a Vec is its varint length followed by its elements
+                    let count: u64 = bincode::serde::decode_from_std_read(&mut reader, config)?;
+                    (Source::Bincode(reader), count)
+                }
+                LegacyFormat::Pickle => {
+                    let clusters: Vec<HashSet<Uuid>> =
+                        serde_pickle::from_reader(reader, Default::default())?;
+                    let count = clusters.len() as u64;
+                    (Source::Pickle(clusters.into_iter()), count)
+                }
+            }
+        };
+        Ok(Self {
+            source,
+            count,
+            next: 0,
+            failed: false,
+        })
+    }
+
+    /// Clusters in the file, read or not
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.count
+    }
+
+    /// `false` for a legacy pickle, which is fully in memory until iterated
+    #[inline]
+    pub fn is_streaming(&self) -> bool {
+        !matches!(self.source, Source::Pickle(_))
+    }
+
+    fn read_cluster(&mut self) -> ClusterFileResult<HashSet<Uuid>> {
+        let config = bincode::config::standard();
+        let index = self.next;
+        match &mut self.source {
+            Source::Records { reader, buf } => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len)?;
+                buf.resize(u32::from_le_bytes(len) as usize, 0);
+                reader.read_exact(buf)?;
+                let (cluster, consumed) = bincode::serde::decode_from_slice(buf, config)?;
+                match consumed == buf.len() {
+                    true => Ok(cluster),
+                    false => Err(ClusterFileError::RecordLength {
+                        index,
+                        len: buf.len(),
+                        consumed,
+                    }),
+                }
+            }
+            Source::Bincode(reader) => Ok(bincode::serde::decode_from_std_read(reader, config)?),
+            Source::Pickle(clusters) => clusters.next().ok_or(ClusterFileError::Truncated {
+                read: index,
+                expected: self.count,
+            }),
+        }
+    }
+}
+
+fn ends_early(e: &ClusterFileError) -> bool {
+    use bincode::error::DecodeError;
+    match e {
+        ClusterFileError::IOError(e)
+        | ClusterFileError::DecodeError(DecodeError::Io { inner: e, .. }) => {
+            e.kind() == ErrorKind::UnexpectedEof
+        }
+        ClusterFileError::DecodeError(DecodeError::UnexpectedEnd { .. }) => true,
+        _ => false,
+    }
+}
+
+impl<R: BufRead> Iterator for ClusterReader<R> {
+    type Item = ClusterFileResult<(usize, HashSet<Uuid>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.next as u64 >= self.count {
+            return None;
+        }
+        let index = self.next;
+        let res = self.read_cluster().map_err(|e| match ends_early(&e) {
+            true => ClusterFileError::Truncated {
+                read: index,
+                expected: self.count,
+            },
+            false => e,
+        });
+        self.failed = res.is_err();
+        self.next += 1;
+        Some(res.map(|cluster| (index, cluster)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = match self.failed {
+            true => 0,
+            false => (self.count - self.next as u64) as usize,
+        };
+        (0, Some(left))
+    }
+}
+
+pub fn open_clusters<P: AsRef<Path>>(
+    path: P,
+    legacy: LegacyFormat,
+) -> ClusterFileResult<ClusterReader<BufReader<File>>> {
+    ClusterReader::new(BufReader::new(File::open(path)?), legacy)
+}
+
+/// All clusters of a file in either format, without buffering the file itself
+pub fn read_clusters<P: AsRef<Path>>(
+    path: P,
+    legacy: LegacyFormat,
+) -> ClusterFileResult<Vec<HashSet<Uuid>>> {
+    let reader = open_clusters(path, legacy)?;
+    let mut clusters = Vec::with_capacity(reader.total() as usize);
+    for cluster in reader {
+        clusters.push(cluster?.1);
+    }
+    Ok(clusters)
+}
+
+fn write_header<W: Write>(writer: &mut W, count: u64) -> ClusterFileResult<()> {
+    writer.write_all(CLUSTERS_MAGIC)?;
+    writer.write_all(&count.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_record<W: Write>(writer: &mut W, cluster: &HashSet<Uuid>) -> ClusterFileResult<()> {
+    let record = bincode::serde::encode_to_vec(cluster, bincode::config::standard())?;
+    let len =
+        u32::try_from(record.len()).map_err(|_| ClusterFileError::RecordTooLarge(record.len()))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&record)?;
+    Ok(())
+}
+
+pub fn write_clusters<'a, W, I>(writer: &mut W, clusters: I) -> ClusterFileResult<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a HashSet<Uuid>>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let clusters = clusters.into_iter();
+    write_header(writer, clusters.len() as u64)?;
+    for cluster in clusters {
+        write_record(writer, cluster)?;
+    }
+    Ok(())
+}
+
+/// Rewrites a cluster file of any format as records, returns the number of clusters
+pub fn convert_clusters<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    legacy: LegacyFormat,
+    output: Q,
+) -> ClusterFileResult<u64> {
+    let reader = open_clusters(input, legacy)?;
+    let count = reader.total();
+    atomic_write_with(output, |w| {
+        write_header(w, count)?;
+        for cluster in reader {
+            write_record(w, &cluster?.1)?;
+        }
+        Ok::<_, ClusterFileError>(())
+    })?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clusters() -> Vec<HashSet<Uuid>> {
+        (0..50u128)
+            .map(|c| {
+                (0..c % 7 + 1)
+                    .map(|i| Uuid::from_u128(c << 8 | i))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn collect<R: BufRead>(reader: ClusterReader<R>) -> Vec<(usize, HashSet<Uuid>)> {
+        reader.collect::<ClusterFileResult<_>>().unwrap()
+    }
+
+    fn indexed(clusters: Vec<HashSet<Uuid>>) -> Vec<(usize, HashSet<Uuid>)> {
+        clusters.into_iter().enumerate().collect()
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let mut bytes = Vec::new();
+        write_clusters(&mut bytes, &clusters()).unwrap();
+        assert!(bytes.starts_with(CLUSTERS_MAGIC));
+        // the legacy format is only a fallback, the header wins
+        for legacy in [LegacyFormat::Pickle, LegacyFormat::Bincode] {
+            let reader = ClusterReader::new(bytes.as_slice(), legacy).unwrap();
+            assert!(reader.is_streaming());
+            assert_eq!(reader.total(), 50);
+            assert_eq!(collect(reader), indexed(clusters()));
+        }
+    }
+
+    #[test]
+    fn test_legacy_equivalence() {
+        let legacy = clusters();
+        let pickle = serde_pickle::to_vec(&legacy, Default::default()).unwrap();
+        let loaded: Vec<HashSet<Uuid>> =
+            serde_pickle::from_slice(&pickle, Default::default()).unwrap();
+        let reader = ClusterReader::new(pickle.as_slice(), LegacyFormat::Pickle).unwrap();
+        assert!(!reader.is_streaming());
+        assert_eq!(collect(reader), indexed(loaded));
+
+        let bincode = bincode::serde::encode_to_vec(&legacy, bincode::config::standard()).unwrap();
+        let reader = ClusterReader::new(bincode.as_slice(), LegacyFormat::Bincode).unwrap();
+        assert!(reader.is_streaming());
+        assert_eq!(reader.total(), 50);
+        assert_eq!(collect(reader), indexed(legacy));
+    }
+
+    #[test]
+    fn test_streaming_reads_lazily() {
+        let mut bytes = Vec::new();
+        write_clusters(&mut bytes, &clusters()).unwrap();
+        let mut source = bytes.as_slice();
+        let mut reader = ClusterReader::new(&mut source, LegacyFormat::Pickle).unwrap();
+        let (index, first) = reader.next().unwrap().unwrap();
+        assert_eq!((index, first), (0, clusters().swap_remove(0)));
+        drop(reader);
+        // the rest of the file was not read ahead, beyond what the slice reader buffers
+        let record = bincode::serde::encode_to_vec(&clusters()[0], bincode::config::standard())
+            .unwrap()
+            .len();
+        assert_eq!(
+            source.len(),
+            bytes.len() - CLUSTERS_MAGIC.len() - 8 - 4 - record
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut bytes = Vec::new();
+        write_clusters(&mut bytes, &clusters()).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        let results: Vec<_> = ClusterReader::new(bytes.as_slice(), LegacyFormat::Pickle)
+            .unwrap()
+            .collect();
+        assert_eq!(results.len(), 50);
+        assert!(matches!(
+            results.last().unwrap(),
+            Err(ClusterFileError::Truncated {
+                read: 49,
+                expected: 50
+            })
+        ));
+
+        let legacy =
+            bincode::serde::encode_to_vec(clusters(), bincode::config::standard()).unwrap();
+        let results: Vec<_> =
+            ClusterReader::new(&legacy[..legacy.len() - 3], LegacyFormat::Bincode)
+                .unwrap()
+                .collect();
+        assert!(matches!(
+            results.last().unwrap(),
+            Err(ClusterFileError::Truncated { read: 49, .. })
+        ));
+    }
+
+    #[test]
+    fn test_convert() {
+        let dir = std::env::temp_dir().join(format!("cluster_file_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pickle = dir.join("global_clusters.pkl");
+        std::fs::write(
+            &pickle,
+            serde_pickle::to_vec(&clusters(), Default::default()).unwrap(),
+        )
+        .unwrap();
+        let converted = dir.join("global_clusters.clusters");
+        assert_eq!(
+            convert_clusters(&pickle, LegacyFormat::Pickle, &converted).unwrap(),
+            50
+        );
+        let legacy = read_clusters(&pickle, LegacyFormat::Pickle).unwrap();
+        assert_eq!(
+            read_clusters(&converted, LegacyFormat::Pickle).unwrap(),
+            legacy
+        );
+        // a converted file converts to the same clusters
+        let again = dir.join("again.clusters");
+        convert_clusters(&converted, LegacyFormat::Bincode, &again).unwrap();
+        assert_eq!(
+            read_clusters(&again, LegacyFormat::Bincode).unwrap(),
+            legacy
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+//! Similarity thresholds the stages share, read at startup so re-tuning them needs no rebuild
+//!
+//! ```toml
+//! text_sim = 0.9
+//! image_sim = 0.985
+//! hamming_cutoff = 0.625
+//! frame_hash_distance = 24
+//! ```
+//!
+//! [`Thresholds::load`] reads the file `NEKO_THRESHOLDS` points to, keys it leaves out keep
+//! their defaults, then applies `NEKO_TEXT_SIM`, `NEKO_IMAGE_SIM`, `NEKO_HAMMING_CUTOFF` and
+//! `NEKO_FRAME_HASH_DISTANCE` over it. A stage's own flag still wins over both.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+pub const THRESHOLDS_ENV: &str = "NEKO_THRESHOLDS";
+/// Cosine similarity of two OCR text vectors in the same cluster
+pub const DEFAULT_TEXT_SIM: f32 = 0.9;
+/// Cosine similarity of two image vectors in the same cluster
+pub const DEFAULT_IMAGE_SIM: f32 = 0.985;
+/// Normalized Hamming distance of two hash vectors past which stage17 stops widening its search
+pub const DEFAULT_HAMMING_CUTOFF: f32 = 0.625;
+/// Gradient hash distance (of 256 bits) of two GIF frames considered the same
+pub const DEFAULT_FRAME_HASH_DISTANCE: u32 = 24;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Cannot read thresholds {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Malformed thresholds: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("{var}={value} is not a valid threshold")]
+    Env { var: &'static str, value: String },
+    #[error("{name} is {value}, outside [0, 1]")]
+    OutOfRange { name: &'static str, value: f32 },
+}
+
+pub type ConfigResult<T> = Result<T, ConfigError>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    pub text_sim: f32,
+    pub image_sim: f32,
+    pub hamming_cutoff: f32,
+    pub frame_hash_distance: u32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            text_sim: DEFAULT_TEXT_SIM,
+            image_sim: DEFAULT_IMAGE_SIM,
+            hamming_cutoff: DEFAULT_HAMMING_CUTOFF,
+            frame_hash_distance: DEFAULT_FRAME_HASH_DISTANCE,
+        }
+    }
+}
+
+static GLOBAL: OnceLock<Thresholds> = OnceLock::new();
+
+impl Thresholds {
+    pub fn parse(toml: &str) -> ConfigResult<Self> {
+        let thresholds: Thresholds = toml::from_str(toml)?;
+        thresholds.validate()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> ConfigResult<Self> {
+        let toml = std::fs::read_to_string(path.as_ref()).map_err(|source| ConfigError::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })?;
+        Self::parse(&toml)
+    }
+
+    /// Overrides from whatever `var` returns for the `NEKO_*` names
+    pub fn with_vars<F>(mut self, var: F) -> ConfigResult<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        fn parsed<T: FromStr>(var: &'static str, value: String) -> ConfigResult<T> {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| ConfigError::Env { var, value })
+        }
+        if let Some(value) = var("NEKO_TEXT_SIM") {
+            self.text_sim = parsed("NEKO_TEXT_SIM", value)?;
+        }
+        if let Some(value) = var("NEKO_IMAGE_SIM") {
+            self.image_sim = parsed("NEKO_IMAGE_SIM", value)?;
+        }
+        if let Some(value) = var("NEKO_HAMMING_CUTOFF") {
+            self.hamming_cutoff = parsed("NEKO_HAMMING_CUTOFF", value)?;
+        }
+        if let Some(value) = var("NEKO_FRAME_HASH_DISTANCE") {
+            self.frame_hash_distance = parsed("NEKO_FRAME_HASH_DISTANCE", value)?;
+        }
+        self.validate()
+    }
+
+    /// The file at `NEKO_THRESHOLDS` or the defaults, with the environment over them
+    pub fn load() -> ConfigResult<Self> {
+        let thresholds = match std::env::var_os(THRESHOLDS_ENV) {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        thresholds.with_vars(|name| std::env::var(name).ok())
+    }
+
+    /// The process-wide thresholds, loaded on the first call, which mains make early to fail on a
+    /// malformed config before any work
+    pub fn init() -> ConfigResult<&'static Thresholds> {
+        if let Some(thresholds) = GLOBAL.get() {
+            return Ok(thresholds);
+        }
+        let thresholds = Self::load()?;
+        Ok(GLOBAL.get_or_init(|| thresholds))
+    }
+
+    fn validate(self) -> ConfigResult<Self> {
+        for (name, value) in [
+            ("text_sim", self.text_sim),
+            ("image_sim", self.image_sim),
+            ("hamming_cutoff", self.hamming_cutoff),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(ConfigError::OutOfRange { name, value });
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Thresholds::parse("").unwrap(), Thresholds::default());
+        let thresholds = Thresholds::parse("image_sim = 0.97\nframe_hash_distance = 16").unwrap();
+        assert_eq!(
+            thresholds,
+            Thresholds {
+                image_sim: 0.97,
+                frame_hash_distance: 16,
+                ..Default::default()
+            }
+        );
+        // a typo must not silently keep the default
+        assert!(matches!(
+            Thresholds::parse("image_similarity = 0.97"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Thresholds::parse("text_sim = 1.5"),
+            Err(ConfigError::OutOfRange {
+                name: "text_sim",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_with_vars() {
+        let vars = |pairs: &[(&str, &str)]| {
+            let map: HashMap<String, String> = pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            move |name: &str| map.get(name).cloned()
+        };
+        let base = Thresholds::parse("text_sim = 0.8").unwrap();
+        assert_eq!(base.with_vars(vars(&[])).unwrap(), base);
+        let thresholds = base
+            .with_vars(vars(&[
+                ("NEKO_HAMMING_CUTOFF", " 0.5 "),
+                ("NEKO_FRAME_HASH_DISTANCE", "20"),
+            ]))
+            .unwrap();
+        assert_eq!((thresholds.text_sim, thresholds.hamming_cutoff), (0.8, 0.5));
+        assert_eq!(thresholds.frame_hash_distance, 20);
+        assert!(matches!(
+            base.with_vars(vars(&[("NEKO_FRAME_HASH_DISTANCE", "-1")])),
+            Err(ConfigError::Env {
+                var: "NEKO_FRAME_HASH_DISTANCE",
+                ..
+            })
+        ));
+        assert!(matches!(
+            base.with_vars(vars(&[("NEKO_IMAGE_SIM", "2")])),
+            Err(ConfigError::OutOfRange { .. })
+        ));
+    }
+}
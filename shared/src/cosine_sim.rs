@@ -1,6 +1,14 @@
+//! Cosine similarity of `f32` and `bf16` vectors, on the fastest kernels the CPU supports
+//!
+//! The kernels are picked once per process, see [`kernels`]. [`simd_capabilities`] names them for
+//! the logs of a run.
+
 use half::bf16;
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::*;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
+use std::sync::OnceLock;
 
 pub trait Cosine {
     fn cosine_sim(a: &[Self], b: &[Self]) -> f32
@@ -11,34 +19,102 @@ pub trait Cosine {
 impl Cosine for f32 {
     #[inline]
     fn cosine_sim(a: &[f32], b: &[f32]) -> f32 {
-        #[cfg(target_arch = "x86_64")]
-        {
-            cosine_sim_f32(a, b)
-        }
-        #[cfg(not(target_arch = "x86_64"))]
-        {
-            common_cosine_sim_f32(a, b)
-        }
+        debug_assert_eq!(
+            a.len(),
+            b.len(),
+            "cosine_sim of vectors of different lengths"
+        );
+        (kernels().f32)(a, b)
     }
 }
 
 impl Cosine for bf16 {
     #[inline]
     fn cosine_sim(a: &[bf16], b: &[bf16]) -> f32 {
+        debug_assert_eq!(
+            a.len(),
+            b.len(),
+            "cosine_sim of vectors of different lengths"
+        );
+        (kernels().bf16)(a, b)
+    }
+}
+
+/// Vectors of different lengths are a bug of the caller, they fail debug builds and are cut to the
+/// shorter one otherwise
+#[inline]
+pub fn cosine_sim<T: Cosine>(a: &[T], b: &[T]) -> f32 {
+    T::cosine_sim(a, b)
+}
+
+/// Cosine kernels for one instruction set
+///
+/// Only handed out for instruction sets the CPU supports, which is what makes calling the SIMD
+/// ones through a safe `fn` sound.
+#[derive(Debug, Clone, Copy)]
+pub struct Kernels {
+    /// `avx2+fma`, `neon` or `scalar`
+    pub name: &'static str,
+    pub f32: fn(&[f32], &[f32]) -> f32,
+    pub bf16: fn(&[bf16], &[bf16]) -> f32,
+}
+
+impl Kernels {
+    pub const SCALAR: Kernels = Kernels {
+        name: "scalar",
+        f32: common_cosine_sim_f32,
+        bf16: common_cosine_sim_bf16,
+    };
+
+    #[cfg(target_arch = "x86_64")]
+    const AVX2_FMA: Kernels = Kernels {
+        name: "avx2+fma",
+        f32: |a, b| unsafe { cosine_sim_f32_avx2(a, b) },
+        bf16: |a, b| unsafe { cosine_sim_bf16_avx2(a, b) },
+    };
+
+    #[cfg(target_arch = "aarch64")]
+    const NEON: Kernels = Kernels {
+        name: "neon",
+        f32: |a, b| unsafe { cosine_sim_f32_neon(a, b) },
+        bf16: |a, b| unsafe { cosine_sim_bf16_neon(a, b) },
+    };
+
+    /// Fastest kernels of this CPU, detected anew on every call, see [`kernels`] for the cached
+    /// ones
+    pub fn detect() -> Kernels {
         #[cfg(target_arch = "x86_64")]
-        {
-            cosine_sim_bf16(a, b)
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            return Self::AVX2_FMA;
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return Self::NEON;
         }
-        #[cfg(not(target_arch = "x86_64"))]
-        {
-            common_cosine_sim_bf16(a, b)
+        Self::SCALAR
+    }
+
+    /// Every set this CPU can run, [`Kernels::SCALAR`] first, e.g. to compare them
+    pub fn supported() -> Vec<Kernels> {
+        let mut supported = vec![Self::SCALAR];
+        let best = Self::detect();
+        if best.name != Self::SCALAR.name {
+            supported.push(best);
         }
+        supported
     }
 }
 
+/// Kernels [`cosine_sim`] runs, detected on first use
 #[inline]
-pub fn cosine_sim<T: Cosine>(a: &[T], b: &[T]) -> f32 {
-    T::cosine_sim(a, b)
+pub fn kernels() -> &'static Kernels {
+    static KERNELS: OnceLock<Kernels> = OnceLock::new();
+    KERNELS.get_or_init(Kernels::detect)
+}
+
+/// Name of the kernels [`cosine_sim`] runs on this CPU, `avx2+fma`, `neon` or `scalar`
+pub fn simd_capabilities() -> &'static str {
+    kernels().name
 }
 
 #[inline(always)]
@@ -55,17 +131,6 @@ unsafe fn hsum256(v: __m256) -> f32 {
     _mm_cvtss_f32(sums2)
 }
 
-#[inline]
-#[cfg(target_arch = "x86_64")]
-#[allow(unsafe_op_in_unsafe_fn)]
-fn cosine_sim_f32(a: &[f32], b: &[f32]) -> f32 {
-    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
-        unsafe { cosine_sim_f32_avx2(a, b) }
-    } else {
-        common_cosine_sim_f32(a, b)
-    }
-}
-
 #[inline]
 fn common_cosine_sim_f32(a: &[f32], b: &[f32]) -> f32 {
     let dot = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum::<f32>();
@@ -79,7 +144,7 @@ fn common_cosine_sim_f32(a: &[f32], b: &[f32]) -> f32 {
 #[target_feature(enable = "avx2,fma")]
 #[allow(unsafe_op_in_unsafe_fn)]
 unsafe fn cosine_sim_f32_avx2(a: &[f32], b: &[f32]) -> f32 {
-    let len = a.len();
+    let len = a.len().min(b.len());
     let mut sum_dot = _mm256_setzero_ps();
     let mut sum_a2 = _mm256_setzero_ps();
     let mut sum_b2 = _mm256_setzero_ps();
@@ -106,17 +171,6 @@ unsafe fn cosine_sim_f32_avx2(a: &[f32], b: &[f32]) -> f32 {
     dot / (a2.sqrt() * b2.sqrt())
 }
 
-#[inline]
-#[cfg(target_arch = "x86_64")]
-#[allow(unsafe_op_in_unsafe_fn)]
-fn cosine_sim_bf16(a: &[bf16], b: &[bf16]) -> f32 {
-    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
-        unsafe { cosine_sim_bf16_avx2(a, b) }
-    } else {
-        common_cosine_sim_bf16(a, b)
-    }
-}
-
 #[inline]
 fn common_cosine_sim_bf16(a: &[bf16], b: &[bf16]) -> f32 {
     let a_f: Vec<f32> = a.iter().map(|&x| x.to_f32()).collect();
@@ -128,7 +182,7 @@ fn common_cosine_sim_bf16(a: &[bf16], b: &[bf16]) -> f32 {
 #[target_feature(enable = "avx2,fma")]
 #[allow(unsafe_op_in_unsafe_fn)]
 unsafe fn cosine_sim_bf16_avx2(a: &[bf16], b: &[bf16]) -> f32 {
-    let len = a.len();
+    let len = a.len().min(b.len());
     let mut sum_dot = _mm256_setzero_ps();
     let mut sum_a2 = _mm256_setzero_ps();
     let mut sum_b2 = _mm256_setzero_ps();
@@ -172,6 +226,78 @@ unsafe fn cosine_sim_bf16_avx2(a: &[bf16], b: &[bf16]) -> f32 {
     dot / (a2.sqrt() * b2.sqrt())
 }
 
+#[inline]
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn cosine_sim_f32_neon(a: &[f32], b: &[f32]) -> f32 {
+    let len = a.len().min(b.len());
+    let mut sum_dot = vdupq_n_f32(0.0);
+    let mut sum_a2 = vdupq_n_f32(0.0);
+    let mut sum_b2 = vdupq_n_f32(0.0);
+    let chunks = len / 4;
+    for i in 0..chunks {
+        let va = vld1q_f32(a.as_ptr().add(i * 4));
+        let vb = vld1q_f32(b.as_ptr().add(i * 4));
+        sum_dot = vfmaq_f32(sum_dot, va, vb);
+        sum_a2 = vfmaq_f32(sum_a2, va, va);
+        sum_b2 = vfmaq_f32(sum_b2, vb, vb);
+    }
+    let mut dot = vaddvq_f32(sum_dot);
+    let mut a2 = vaddvq_f32(sum_a2);
+    let mut b2 = vaddvq_f32(sum_b2);
+    for i in (chunks * 4)..len {
+        let ai = *a.get_unchecked(i);
+        let bi = *b.get_unchecked(i);
+        dot += ai * bi;
+        a2 += ai * ai;
+        b2 += bi * bi;
+    }
+    dot / (a2.sqrt() * b2.sqrt())
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn cosine_sim_bf16_neon(a: &[bf16], b: &[bf16]) -> f32 {
+    let len = a.len().min(b.len());
+    let mut sum_dot = vdupq_n_f32(0.0);
+    let mut sum_a2 = vdupq_n_f32(0.0);
+    let mut sum_b2 = vdupq_n_f32(0.0);
+
+    let chunks = len / 8;
+    for i in 0..chunks {
+        let va_u16 = vld1q_u16(a.as_ptr().add(i * 8) as *const u16);
+        let vb_u16 = vld1q_u16(b.as_ptr().add(i * 8) as *const u16);
+
+        // a bf16 is the upper half of the f32 it rounds
+        let fa_lo = vreinterpretq_f32_u32(vshll_n_u16::<16>(vget_low_u16(va_u16)));
+        let fa_hi = vreinterpretq_f32_u32(vshll_high_n_u16::<16>(va_u16));
+        let fb_lo = vreinterpretq_f32_u32(vshll_n_u16::<16>(vget_low_u16(vb_u16)));
+        let fb_hi = vreinterpretq_f32_u32(vshll_high_n_u16::<16>(vb_u16));
+
+        sum_dot = vfmaq_f32(sum_dot, fa_lo, fb_lo);
+        sum_dot = vfmaq_f32(sum_dot, fa_hi, fb_hi);
+        sum_a2 = vfmaq_f32(sum_a2, fa_lo, fa_lo);
+        sum_a2 = vfmaq_f32(sum_a2, fa_hi, fa_hi);
+        sum_b2 = vfmaq_f32(sum_b2, fb_lo, fb_lo);
+        sum_b2 = vfmaq_f32(sum_b2, fb_hi, fb_hi);
+    }
+    let mut dot = vaddvq_f32(sum_dot);
+    let mut a2 = vaddvq_f32(sum_a2);
+    let mut b2 = vaddvq_f32(sum_b2);
+
+    for i in (chunks * 8)..len {
+        let ai = a.get_unchecked(i).to_f32();
+        let bi = b.get_unchecked(i).to_f32();
+        dot += ai * bi;
+        a2 += ai * ai;
+        b2 += bi * bi;
+    }
+
+    dot / (a2.sqrt() * b2.sqrt())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -318,4 +444,81 @@ mod tests {
             expected
         );
     }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_cosine_sim_neon_against_cpu() {
+        assert_eq!(simd_capabilities(), "neon");
+        let mut rng = StdRng::seed_from_u64(42);
+        // odd lengths run the scalar tail too
+        for dim in [DIM, DIM + 3, 5] {
+            let a: Vec<f32> = (0..dim).map(|_| rng.random_range(-1.0..1.0)).collect();
+            let b: Vec<f32> = (0..dim).map(|_| rng.random_range(-1.0..1.0)).collect();
+            let sim = unsafe { cosine_sim_f32_neon(&a, &b) };
+            let expected = common_cosine_sim_f32(&a, &b);
+            assert!(
+                (sim - expected).abs() < EPS,
+                "mismatch: got {} vs expected {}",
+                sim,
+                expected
+            );
+            let a: Vec<bf16> = a.iter().map(|&x| bf16::from_f32(x)).collect();
+            let b: Vec<bf16> = b.iter().map(|&x| bf16::from_f32(x)).collect();
+            let sim = unsafe { cosine_sim_bf16_neon(&a, &b) };
+            let expected = common_cosine_sim_bf16(&a, &b);
+            assert!(
+                (sim - expected).abs() < EPS,
+                "bf16 mismatch: got {} vs expected {}",
+                sim,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_kernels_agree() {
+        assert_eq!(simd_capabilities(), Kernels::detect().name);
+        let supported = Kernels::supported();
+        assert_eq!(supported[0].name, "scalar");
+        assert_eq!(supported.last().unwrap().name, simd_capabilities());
+        let mut rng = StdRng::seed_from_u64(7);
+        let a: Vec<f32> = (0..DIM + 1).map(|_| rng.random_range(-1.0..1.0)).collect();
+        let b: Vec<f32> = (0..DIM + 1).map(|_| rng.random_range(-1.0..1.0)).collect();
+        let a16: Vec<bf16> = a.iter().map(|&x| bf16::from_f32(x)).collect();
+        let b16: Vec<bf16> = b.iter().map(|&x| bf16::from_f32(x)).collect();
+        for kernels in supported {
+            let sim = (kernels.f32)(&a, &b);
+            assert!(
+                (sim - common_cosine_sim_f32(&a, &b)).abs() < EPS,
+                "{}",
+                kernels.name
+            );
+            let sim = (kernels.bf16)(&a16, &b16);
+            assert!(
+                (sim - common_cosine_sim_bf16(&a16, &b16)).abs() < EPS,
+                "{}",
+                kernels.name
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "different lengths")]
+    fn test_cosine_sim_length_mismatch() {
+        cosine_sim(&[1.0_f32; 768], &[1.0_f32; 384]);
+    }
+
+    /// Garbage but within bounds, the SIMD kernels never read past the shorter vector
+    #[test]
+    fn test_kernels_length_mismatch() {
+        let (a, b) = ([1.0_f32; 24], [1.0_f32; 9]);
+        let (a16, b16) = ([bf16::ONE; 40], [bf16::ONE; 17]);
+        for kernels in Kernels::supported() {
+            assert!((kernels.f32)(&a, &b).is_finite(), "{}", kernels.name);
+            assert!((kernels.f32)(&b, &a).is_finite(), "{}", kernels.name);
+            assert!((kernels.bf16)(&a16, &b16).is_finite(), "{}", kernels.name);
+            assert!((kernels.bf16)(&b16, &a16).is_finite(), "{}", kernels.name);
+        }
+    }
 }
@@ -0,0 +1,207 @@
+//! Sign-bit pre-filter for thresholded cosine similarity
+//!
+//! For unit vectors `x`, `y` with `cos(x, y) >= t`, `|x - y|^2 = 2 (1 - cos) <= 2 (1 - t) = B`.
+//! On every coordinate `i` where the sign bits differ `x_i` and `y_i` lie on opposite sides of
+//! zero, so `(x_i - y_i)^2 = (|x_i| + |y_i|)^2 >= x_i^2 + y_i^2`. With `D` the set of differing
+//! bits and `h = |D|` the Hamming distance:
+//!
+//! `B >= |x - y|^2 >= sum_D x_i^2 + sum_D y_i^2 >= m_x(h) + m_y(h)`
+//!
+//! where `m_x(h)` is the sum of the `h` smallest `x_i^2`. Both terms are non-negative, so each
+//! is at most `B` on its own and `h` cannot exceed `max_flips(x) = max { h : m_x(h) <= B }`, nor
+//! the same for `y`. A pair whose Hamming distance is above the smaller of the two is provably
+//! below the threshold and never needs the exact cosine. The bound only depends on the vectors,
+//! never on how the data is distributed, so it cannot drop a true match.
+
+use std::cmp::Ordering;
+
+/// Added to `1 - t`, covers the f32 rounding of the exact cosine the survivors go through
+const COSINE_SLACK: f64 = 1e-4;
+
+/// Signatures for one similarity threshold
+#[derive(Debug, Clone, Copy)]
+pub struct SignBitsFilter {
+    budget: f64,
+}
+
+/// Sign bits packed 64 per word, 96 bytes for a 768-d vector
+#[derive(Debug, Clone)]
+pub struct SignBits {
+    bits: Vec<u64>,
+    max_flips: u32,
+}
+
+impl SignBitsFilter {
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            budget: 2.0 * (1.0 - threshold as f64 + COSINE_SLACK),
+        }
+    }
+
+    pub fn signature(&self, v: &[f32]) -> SignBits {
+        let mut bits = vec![0u64; v.len().div_ceil(64)];
+        for (i, x) in v.iter().enumerate() {
+            if x.is_sign_negative() {
+                bits[i / 64] |= 1 << (i % 64);
+            }
+        }
+        let norm2 = v.iter().map(|&x| x as f64 * x as f64).sum::<f64>();
+        // a zero vector has no direction to bound, let everything through
+        let max_flips = if norm2 > 0.0 {
+            let mut squares: Vec<f64> = v.iter().map(|&x| x as f64 * x as f64 / norm2).collect();
+            squares.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            let mut total = 0.0;
+            squares
+                .iter()
+                .take_while(|&&s| {
+                    total += s;
+                    total <= self.budget
+                })
+                .count() as u32
+        } else {
+            v.len() as u32
+        };
+        SignBits { bits, max_flips }
+    }
+}
+
+impl SignBits {
+    #[inline]
+    pub fn hamming(&self, other: &SignBits) -> u32 {
+        self.bits
+            .iter()
+            .zip(other.bits.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
+    }
+
+    /// Most differing signs a vector at or above the threshold can have with this one
+    #[inline]
+    pub fn max_flips(&self) -> u32 {
+        self.max_flips
+    }
+
+    /// `false` only when the pair is provably below the threshold
+    #[inline]
+    pub fn may_reach(&self, other: &SignBits) -> bool {
+        self.hamming(other) <= self.max_flips.min(other.max_flips)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cosine_sim::cosine_sim;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    const DIM: usize = 768;
+    // `config::DEFAULT_IMAGE_SIM`, copied as `distance` builds without `config`
+    const IMAGE_SIM_THRESHOLD: f32 = 0.985;
+
+    fn random_vector(rng: &mut Pcg64) -> Vec<f32> {
+        (0..DIM).map(|_| rng.random_range(-1.0..1.0)).collect()
+    }
+
+    /// Clusters of noisy copies around random centers, the cosine spread straddles the threshold
+    fn synthetic(rng: &mut Pcg64, clusters: usize, size: usize) -> Vec<Vec<f32>> {
+        let mut out = Vec::with_capacity(clusters * size);
+        for c in 0..clusters {
+            let center = random_vector(rng);
+            let noise = 0.01 + 0.06 * (c % 4) as f32;
+            for _ in 0..size {
+                out.push(
+                    center
+                        .iter()
+                        .map(|x| x + noise * rng.random_range(-1.0..1.0))
+                        .collect(),
+                );
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_hamming() {
+        let filter = SignBitsFilter::new(0.9);
+        let a = filter.signature(&[1.0; 130]);
+        let mut v = vec![1.0; 130];
+        v[0] = -1.0;
+        v[64] = -0.0;
+        v[129] = -2.0;
+        let b = filter.signature(&v);
+        assert_eq!(a.bits.len(), 3);
+        assert_eq!(a.hamming(&b), 3);
+        assert_eq!(b.hamming(&b), 0);
+    }
+
+    #[test]
+    fn test_never_drops_a_match() {
+        let mut rng = Pcg64::seed_from_u64(42);
+        let vectors = synthetic(&mut rng, 20, 10);
+        for threshold in [0.5, 0.9, IMAGE_SIM_THRESHOLD, 0.999] {
+            let filter = SignBitsFilter::new(threshold);
+            let signatures: Vec<SignBits> = vectors.iter().map(|v| filter.signature(v)).collect();
+            let mut matches = 0;
+            for i in 0..vectors.len() {
+                for j in i + 1..vectors.len() {
+                    if cosine_sim(&vectors[i], &vectors[j]) >= threshold {
+                        matches += 1;
+                        assert!(
+                            signatures[i].may_reach(&signatures[j]),
+                            "pair ({}, {}) dropped at {}",
+                            i,
+                            j,
+                            threshold
+                        );
+                    }
+                }
+            }
+            assert!(matches > 0, "no pair reaches {}", threshold);
+        }
+    }
+
+    #[test]
+    fn test_tiny_coordinates_flipping() {
+        // almost every sign differs yet the vectors are nearly identical
+        let mut a = vec![1e-4f32; DIM];
+        a[0] = 1.0;
+        let b: Vec<f32> = a
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| match i {
+                0 => x,
+                _ => -x,
+            })
+            .collect();
+        assert!(cosine_sim(&a, &b) >= IMAGE_SIM_THRESHOLD);
+        let filter = SignBitsFilter::new(IMAGE_SIM_THRESHOLD);
+        let (sa, sb) = (filter.signature(&a), filter.signature(&b));
+        assert_eq!(sa.hamming(&sb), DIM as u32 - 1);
+        assert!(sa.may_reach(&sb));
+        // zero vectors are never filtered
+        assert!(filter.signature(&[0.0; DIM]).may_reach(&sa));
+    }
+
+    #[test]
+    fn test_prunes_unrelated_pairs() {
+        let mut rng = Pcg64::seed_from_u64(7);
+        let vectors = synthetic(&mut rng, 40, 5);
+        let filter = SignBitsFilter::new(IMAGE_SIM_THRESHOLD);
+        let signatures: Vec<SignBits> = vectors.iter().map(|v| filter.signature(v)).collect();
+        let (mut pairs, mut survivors) = (0, 0);
+        for i in 0..vectors.len() {
+            for j in i + 1..vectors.len() {
+                pairs += 1;
+                survivors += signatures[i].may_reach(&signatures[j]) as usize;
+            }
+        }
+        // only pairs inside a cluster can survive, 40 * 10 of 19900
+        assert!(
+            survivors <= 400,
+            "{} of {} pairs survived",
+            survivors,
+            pairs
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! Byte-identical objects, found by content hash before any similarity clustering
+//!
+//! Exact duplicates are grouped once, clustering then sees one representative per group and the
+//! other members rejoin whatever cluster their representative lands in.
+
+use crate::opendal::{Entry, EntryMode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use uuid::Uuid;
+
+/// Content identity of an object
+///
+/// Objects with an md5 and objects hashed after download are never compared, a duplicate pair
+/// split across the two kinds stays undetected.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ContentId {
+    /// Lowercase hex md5 from the listing
+    Md5(String),
+    /// [`NekoUuid`](crate::neko_uuid::NekoUuid) of the downloaded bytes
+    Neko(Uuid),
+}
+
+fn hex_md5(s: &str) -> Option<String> {
+    let s = s.trim_matches('"');
+    (s.len() == 32 && s.bytes().all(|b| b.is_ascii_hexdigit())).then(|| s.to_ascii_lowercase())
+}
+
+/// md5 of a listed file from `content_md5` or its etag
+///
+/// Etags of multipart uploads (`<md5>-<parts>`) and base64 `Content-MD5` headers are not a hex md5
+/// of the content, those objects have to be downloaded.
+pub fn listed_content_id(entry: &Entry) -> Option<ContentId> {
+    if entry.metadata.mode != EntryMode::FILE {
+        return None;
+    }
+    let metadata = &entry.metadata;
+    metadata
+        .content_md5
+        .as_deref()
+        .and_then(hex_md5)
+        .or_else(|| metadata.etag.as_deref().and_then(hex_md5))
+        .map(ContentId::Md5)
+}
+
+/// Groups of two or more points sharing a content identity, ordered by their smallest id
+///
+/// Several objects of one point collapse into a single member, and a point sharing objects with
+/// points of different contents joins their groups into one, so every point is in one group.
+pub fn group_by_content<I>(points: I) -> Vec<HashSet<Uuid>>
+where
+    I: IntoIterator<Item = (Uuid, ContentId)>,
+{
+    let mut index: HashMap<Uuid, usize> = HashMap::new();
+    let mut parent: Vec<usize> = Vec::new();
+    let mut first_of: HashMap<ContentId, usize> = HashMap::new();
+    fn find(parent: &mut [usize], mut x: usize) -> usize {
+        while parent[x] != x {
+            parent[x] = parent[parent[x]];
+            x = parent[x];
+        }
+        x
+    }
+    let mut ids = Vec::new();
+    for (id, content) in points {
+        let idx = *index.entry(id).or_insert_with(|| {
+            parent.push(parent.len());
+            ids.push(id);
+            parent.len() - 1
+        });
+        let first = *first_of.entry(content).or_insert(idx);
+        let (a, b) = (find(&mut parent, first), find(&mut parent, idx));
+        if a != b {
+            parent[b] = a;
+        }
+    }
+    let mut by_root: HashMap<usize, HashSet<Uuid>> = HashMap::new();
+    for (idx, id) in ids.into_iter().enumerate() {
+        let root = find(&mut parent, idx);
+        by_root.entry(root).or_default().insert(id);
+    }
+    let mut groups: Vec<HashSet<Uuid>> = by_root
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect();
+    // deterministic output for identical listings
+    groups.sort_unstable_by_key(|group| group.iter().min().copied());
+    groups
+}
+
+/// The exact duplicates left out of a point set, by the representative they follow
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExactDupReduction {
+    /// Ordered so the clusters [`ExactDupReduction::expand`] adds come out the same every run
+    members: BTreeMap<Uuid, Vec<Uuid>>,
+}
+
+impl ExactDupReduction {
+    /// `ids` without every exact duplicate but the smallest id of its group present in `ids`
+    ///
+    /// Group members outside `ids` are ignored, the clustered point set never grows.
+    pub fn reduce(ids: &[Uuid], exact_groups: &[HashSet<Uuid>]) -> (Vec<Uuid>, Self) {
+        let present: HashSet<&Uuid> = ids.iter().collect();
+        let mut members: BTreeMap<Uuid, Vec<Uuid>> = BTreeMap::new();
+        let mut dropped: HashSet<Uuid> = HashSet::new();
+        for group in exact_groups {
+            let mut in_set: Vec<Uuid> = group
+                .iter()
+                .filter(|id| present.contains(id))
+                .copied()
+                .collect();
+            if in_set.len() < 2 {
+                continue;
+            }
+            in_set.sort_unstable();
+            let representative = in_set[0];
+            dropped.extend(&in_set[1..]);
+            members
+                .entry(representative)
+                .or_default()
+                .extend(&in_set[1..]);
+        }
+        let kept = ids
+            .iter()
+            .filter(|id| !dropped.contains(id))
+            .copied()
+            .collect();
+        (kept, ExactDupReduction { members })
+    }
+
+    /// Number of ids [`ExactDupReduction::reduce`] left out
+    pub fn dropped(&self) -> usize {
+        self.members.values().map(Vec::len).sum()
+    }
+
+    /// Adds the left out duplicates to the cluster of their representative
+    ///
+    /// A representative in no cluster, e.g. a singleton the clusterer drops, gets a cluster of
+    /// its exact group, appended in representative order.
+    pub fn expand(&self, mut clusters: Vec<HashSet<Uuid>>) -> Vec<HashSet<Uuid>> {
+        let cluster_of: HashMap<Uuid, usize> = clusters
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, cluster)| cluster.iter().map(move |id| (*id, idx)))
+            .collect();
+        for (representative, members) in &self.members {
+            match cluster_of.get(representative) {
+                Some(&idx) => clusters[idx].extend(members),
+                None => clusters.push(
+                    std::iter::once(*representative)
+                        .chain(members.iter().copied())
+                        .collect(),
+                ),
+            }
+        }
+        clusters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opendal::Metadata;
+
+    fn set(ids: &[u128]) -> HashSet<Uuid> {
+        ids.iter().copied().map(Uuid::from_u128).collect()
+    }
+
+    fn ids(ids: &[u128]) -> Vec<Uuid> {
+        ids.iter().copied().map(Uuid::from_u128).collect()
+    }
+
+    fn entry(mode: EntryMode, content_md5: Option<&str>, etag: Option<&str>) -> Entry {
+        Entry {
+            path: "a.png".to_string(),
+            metadata: Metadata {
+                mode,
+                is_current: None,
+                is_deleted: false,
+                cache_control: None,
+                content_disposition: None,
+                content_length: Some(1),
+                content_md5: content_md5.map(String::from),
+                content_range: None,
+                content_type: None,
+                content_encoding: None,
+                etag: etag.map(String::from),
+                last_modified: None,
+                version: None,
+                user_metadata: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_listed_content_id() {
+        let md5 = "0123456789abcdef0123456789ABCDEF";
+        let lower = ContentId::Md5(md5.to_ascii_lowercase());
+        let quoted = format!("\"{}\"", md5);
+        let cases = [
+            (entry(EntryMode::FILE, Some(md5), None), Some(lower.clone())),
+            (
+                entry(EntryMode::FILE, None, Some(&quoted)),
+                Some(lower.clone()),
+            ),
+            (
+                entry(EntryMode::FILE, Some("ASNFZ4mrze8BI0VniavN7w=="), Some(md5)),
+                Some(lower.clone()),
+            ),
+            (
+                entry(EntryMode::FILE, None, Some(&format!("\"{}-3\"", md5))),
+                None,
+            ),
+            (entry(EntryMode::FILE, None, None), None),
+            (entry(EntryMode::DIR, Some(md5), Some(md5)), None),
+        ];
+        for (entry, expected) in cases {
+            assert_eq!(listed_content_id(&entry), expected, "{:?}", entry.metadata);
+        }
+    }
+
+    #[test]
+    fn test_group_by_content() {
+        let md5 = |s: &str| ContentId::Md5(s.to_string());
+        let neko = |n: u128| ContentId::Neko(Uuid::from_u128(n));
+        let points = [
+            (Uuid::from_u128(3), md5("a")),
+            (Uuid::from_u128(1), md5("a")),
+            (Uuid::from_u128(2), md5("b")),
+            (Uuid::from_u128(4), neko(9)),
+            (Uuid::from_u128(5), neko(9)),
+            // a second object of point 5
+            (Uuid::from_u128(5), neko(9)),
+            (Uuid::from_u128(6), neko(8)),
+            (Uuid::from_u128(6), neko(8)),
+        ];
+        assert_eq!(group_by_content(points), vec![set(&[1, 3]), set(&[4, 5])]);
+    }
+
+    #[test]
+    fn test_group_by_content_overlapping() {
+        let md5 = |s: &str| ContentId::Md5(s.to_string());
+        // point 2 has one object like point 1 and another like point 3
+        let points = [
+            (Uuid::from_u128(1), md5("a")),
+            (Uuid::from_u128(2), md5("a")),
+            (Uuid::from_u128(2), md5("b")),
+            (Uuid::from_u128(3), md5("b")),
+            (Uuid::from_u128(4), md5("c")),
+            (Uuid::from_u128(5), md5("d")),
+            (Uuid::from_u128(5), md5("e")),
+            (Uuid::from_u128(6), md5("e")),
+        ];
+        let groups = group_by_content(points);
+        assert_eq!(groups, vec![set(&[1, 2, 3]), set(&[5, 6])]);
+
+        let all = ids(&[1, 2, 3, 4, 5, 6]);
+        let (kept, reduction) = ExactDupReduction::reduce(&all, &groups);
+        assert_eq!(kept, ids(&[1, 4, 5]));
+        let expanded = reduction.expand(vec![set(&[1, 4]), set(&[5])]);
+        let total: usize = expanded.iter().map(HashSet::len).sum();
+        assert_eq!(total, all.len());
+    }
+
+    #[test]
+    fn test_reduce() {
+        let groups = [set(&[1, 2, 3]), set(&[4, 5]), set(&[6, 7])];
+        // 7 is not in the clustered set, 5 is the only one of its group
+        let (kept, reduction) = ExactDupReduction::reduce(&ids(&[3, 2, 1, 5, 6, 8]), &groups);
+        assert_eq!(kept, ids(&[1, 5, 6, 8]));
+        assert_eq!(reduction.dropped(), 2);
+
+        let (kept, reduction) = ExactDupReduction::reduce(&ids(&[3, 2]), &groups);
+        assert_eq!(kept, ids(&[2]));
+        assert_eq!(reduction.dropped(), 1);
+
+        let (kept, reduction) = ExactDupReduction::reduce(&ids(&[1, 4]), &[]);
+        assert_eq!(kept, ids(&[1, 4]));
+        assert_eq!(reduction, ExactDupReduction::default());
+    }
+
+    #[test]
+    fn test_expand() {
+        let groups = [set(&[1, 2, 3]), set(&[4, 5]), set(&[6, 7])];
+        let all = ids(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let (kept, reduction) = ExactDupReduction::reduce(&all, &groups);
+        assert_eq!(kept, ids(&[1, 4, 6, 8]));
+
+        // 1 and 8 cluster together, 4 alone, 6 is dropped by the clusterer
+        let clusters = vec![set(&[1, 8]), set(&[4])];
+        let expanded = reduction.expand(clusters);
+        assert_eq!(
+            expanded,
+            vec![set(&[1, 2, 3, 8]), set(&[4, 5]), set(&[6, 7])]
+        );
+        // 4 and 6 both dropped, their clusters follow the representatives' order
+        assert_eq!(
+            reduction.expand(vec![set(&[1, 8])]),
+            vec![set(&[1, 2, 3, 8]), set(&[4, 5]), set(&[6, 7])]
+        );
+        let total: usize = expanded.iter().map(HashSet::len).sum();
+        assert_eq!(total, all.len());
+
+        assert_eq!(
+            ExactDupReduction::default().expand(vec![set(&[1])]),
+            vec![set(&[1])]
+        );
+    }
+}
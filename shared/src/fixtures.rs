@@ -0,0 +1,589 @@
+//! Small synthetic datasets with a known duplicate structure, for tests and local runs
+//!
+//! A [`FixtureSpec`] lists clusters of near-duplicates, each with the pairwise cosine similarity
+//! of its image (and optionally text) vectors and how far its perceptual hashes drift, plus
+//! unrelated singletons. [`FixtureSpec::generate`] is deterministic for a seed and yields every
+//! input the stages read: the points map, the `f32`/768 and `u8`/32 point explorers, a listing
+//! whose keys are `<uuid>.<ext>`, the objects themselves and the ground-truth groups.
+//!
+//! Within a cluster the similarity is exact up to float rounding, vectors of different clusters
+//! are random and so close to orthogonal.
+
+use crate::opendal::{Entry, EntryListError, EntryMode, Metadata, save_entry_list};
+use crate::point_explorer::{PointExplorer, PointExplorerError};
+use crate::structure::{NekoPoint, NekoPointExt, NekoPointExtResource, NekoPointText};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, DynamicImage, Frame, ImageFormat, Rgba, RgbaImage};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Dimension of image and text vectors
+pub const VECTOR_DIM: usize = 768;
+/// Bytes of a perceptual hash
+pub const HASH_LEN: usize = 32;
+
+pub const POINTS_MAP_FILE: &str = "points_map.pkl";
+pub const POINTS_MAP_EXT_FILE: &str = "points_map_ext.pkl";
+pub const IMAGE_EXPLORER_FILE: &str = "image_f32d768.bin";
+pub const HASH_EXPLORER_FILE: &str = "hash_u8d32.bin";
+pub const LISTING_FILE: &str = "listing.bin";
+pub const GROUND_TRUTH_FILE: &str = "ground_truth.json";
+/// Directory of the objects, a root for [`crate::opendal::Backend::Fs`]
+pub const OBJECTS_DIR: &str = "objects";
+
+#[derive(Debug, thiserror::Error)]
+pub enum FixtureError {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    ImageError(#[from] image::ImageError),
+    #[error(transparent)]
+    PointExplorerError(#[from] PointExplorerError),
+    #[error(transparent)]
+    EntryListError(#[from] EntryListError),
+    #[error(transparent)]
+    SerdePickleError(#[from] serde_pickle::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error("Cluster {cluster} of the spec: {reason}")]
+    InvalidSpec {
+        cluster: usize,
+        reason: &'static str,
+    },
+}
+
+pub type FixtureResult<T> = Result<T, FixtureError>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GifMotion {
+    /// Every frame is the same, what stage9 discards as a still GIF
+    Still,
+    /// A square crossing the canvas, every frame differs from the others
+    Moving,
+}
+
+/// Content of the GIFs of a cluster, every member is the same animation on a canvas of its own
+/// size
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GifSpec {
+    pub frames: usize,
+    pub motion: GifMotion,
+}
+
+/// A ground-truth group of near-duplicates
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterSpec {
+    pub size: usize,
+    /// Pairwise cosine similarity of the image vectors, in `[0, 1]`
+    pub image_sim: f32,
+    /// Bits by which each hash differs from the cluster's base hash, pairs differ by at most
+    /// twice as many
+    pub hash_flips: usize,
+    /// Pairwise cosine similarity of the text vectors, `None` for points without text
+    pub text_sim: Option<f32>,
+    /// The objects are GIFs, PNGs otherwise
+    pub gif: Option<GifSpec>,
+}
+
+impl ClusterSpec {
+    /// PNGs without text, hashes 4 bits off the base
+    pub fn new(size: usize, image_sim: f32) -> Self {
+        Self {
+            size,
+            image_sim,
+            hash_flips: 4,
+            text_sim: None,
+            gif: None,
+        }
+    }
+
+    pub fn hash_flips(mut self, flips: usize) -> Self {
+        self.hash_flips = flips;
+        self
+    }
+
+    pub fn text_sim(mut self, sim: f32) -> Self {
+        self.text_sim = Some(sim);
+        self
+    }
+
+    pub fn gif(mut self, frames: usize, motion: GifMotion) -> Self {
+        self.gif = Some(GifSpec { frames, motion });
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FixtureSpec {
+    seed: u64,
+    clusters: Vec<ClusterSpec>,
+    singletons: usize,
+}
+
+impl FixtureSpec {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            clusters: Vec::new(),
+            singletons: 0,
+        }
+    }
+
+    pub fn cluster(mut self, cluster: ClusterSpec) -> Self {
+        self.clusters.push(cluster);
+        self
+    }
+
+    /// Unrelated PNG points without text, each its own ground-truth group
+    pub fn singletons(mut self, count: usize) -> Self {
+        self.singletons = count;
+        self
+    }
+
+    pub fn generate(&self) -> FixtureResult<Fixture> {
+        let mut rng = Pcg64::seed_from_u64(self.seed);
+        let mut fixture = Fixture::default();
+        for (idx, cluster) in self.clusters.iter().enumerate() {
+            if !(0.0..=1.0).contains(&cluster.image_sim)
+                || cluster.text_sim.is_some_and(|s| !(0.0..=1.0).contains(&s))
+            {
+                return Err(FixtureError::InvalidSpec {
+                    cluster: idx,
+                    reason: "similarities must be in [0, 1]",
+                });
+            }
+            if cluster.size >= VECTOR_DIM || cluster.hash_flips > HASH_LEN * 8 {
+                return Err(FixtureError::InvalidSpec {
+                    cluster: idx,
+                    reason: "too many points or hash flips",
+                });
+            }
+            let images = similar_vectors(&mut rng, cluster.size, cluster.image_sim);
+            let texts = cluster
+                .text_sim
+                .map(|sim| similar_vectors(&mut rng, cluster.size, sim));
+            let base_hash: [u8; HASH_LEN] = rng.random();
+            let mut group = Vec::with_capacity(cluster.size);
+            for (member, image) in images.into_iter().enumerate() {
+                let text = texts.as_ref().map(|texts| NekoPointText {
+                    text: format!("cluster {} text {}", idx, member),
+                    text_vector: texts[member].clone(),
+                });
+                let hash = flip_bits(&mut rng, base_hash, cluster.hash_flips);
+                let object = match cluster.gif {
+                    Some(gif) => Object::gif(idx, member, gif)?,
+                    None => Object::png(idx, member)?,
+                };
+                group.push(fixture.push(&mut rng, image, hash, text, object));
+            }
+            fixture.truth.push(group);
+        }
+        for idx in 0..self.singletons {
+            let image = similar_vectors(&mut rng, 1, 1.0).remove(0);
+            let object = Object::png(self.clusters.len() + idx, 0)?;
+            let hash = rng.random();
+            let id = fixture.push(&mut rng, image, hash, None, object);
+            fixture.truth.push(vec![id]);
+        }
+        Ok(fixture)
+    }
+}
+
+/// Encoded object of a point
+struct Object {
+    ext: &'static str,
+    width: usize,
+    height: usize,
+    bytes: Vec<u8>,
+}
+
+impl Object {
+    /// Members grow by 8px so the largest is the last one
+    fn side(member: usize) -> u32 {
+        48 + 8 * member as u32
+    }
+
+    fn png(cluster: usize, member: usize) -> FixtureResult<Self> {
+        let side = Self::side(member);
+        let shade = (cluster * 37 % 256) as u8;
+        let image = RgbaImage::from_pixel(side, side, Rgba([shade, 255 - shade, 128, 255]));
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(image).write_to(&mut bytes, ImageFormat::Png)?;
+        Ok(Self {
+            ext: "png",
+            width: side as usize,
+            height: side as usize,
+            bytes: bytes.into_inner(),
+        })
+    }
+
+    fn gif(cluster: usize, member: usize, spec: GifSpec) -> FixtureResult<Self> {
+        let side = Self::side(member);
+        Ok(Self {
+            ext: "gif",
+            width: side as usize,
+            height: side as usize,
+            bytes: gif_bytes(side, cluster, spec)?,
+        })
+    }
+}
+
+/// One animation scaled to `side`, a dark square on a background set by `cluster`
+pub fn gif_bytes(side: u32, cluster: usize, spec: GifSpec) -> FixtureResult<Vec<u8>> {
+    let shade = (cluster * 37 % 256) as u8;
+    let square = side / 4;
+    let frames = (0..spec.frames).map(|idx| {
+        let offset = match (spec.motion, spec.frames) {
+            (GifMotion::Still, _) | (GifMotion::Moving, 0..=1) => 0,
+            (GifMotion::Moving, n) => (side - square) * idx as u32 / (n as u32 - 1),
+        };
+        let image = RgbaImage::from_fn(side, side, |x, y| {
+            let inside =
+                (offset..offset + square).contains(&x) && (offset..offset + square).contains(&y);
+            match inside {
+                true => Rgba([0, 0, 0, 255]),
+                false => Rgba([255, shade, 255 - shade, 255]),
+            }
+        });
+        Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(100, 1))
+    });
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
+    }
+    Ok(bytes)
+}
+
+/// `count` unit vectors whose pairwise cosine similarity is `sim`
+///
+/// Each is `sqrt(sim) * c + sqrt(1 - sim) * e_i` with `c` and the `e_i` orthonormal, which makes
+/// the similarity exact rather than approximate.
+fn similar_vectors(rng: &mut Pcg64, count: usize, sim: f32) -> Vec<Vec<f32>> {
+    let mut basis: Vec<Vec<f32>> = Vec::with_capacity(count + 1);
+    while basis.len() <= count {
+        let mut v: Vec<f32> = (0..VECTOR_DIM)
+            .map(|_| rng.random_range(-1.0..1.0))
+            .collect();
+        // Gram-Schmidt, twice for stability
+        for _ in 0..2 {
+            for b in &basis {
+                let dot: f32 = v.iter().zip(b).map(|(x, y)| x * y).sum();
+                v.iter_mut().zip(b).for_each(|(x, y)| *x -= dot * y);
+            }
+        }
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 1e-3 {
+            v.iter_mut().for_each(|x| *x /= norm);
+            basis.push(v);
+        }
+    }
+    let (shared, own) = (sim.sqrt(), (1.0 - sim).sqrt());
+    let (center, rest) = basis.split_first().unwrap();
+    rest.iter()
+        .map(|e| {
+            center
+                .iter()
+                .zip(e)
+                .map(|(c, e)| shared * c + own * e)
+                .collect()
+        })
+        .collect()
+}
+
+/// `base` with `flips` distinct bits flipped
+fn flip_bits(rng: &mut Pcg64, mut base: [u8; HASH_LEN], flips: usize) -> [u8; HASH_LEN] {
+    for bit in rand::seq::index::sample(rng, HASH_LEN * 8, flips) {
+        base[bit / 8] ^= 1 << (bit % 8);
+    }
+    base
+}
+
+/// Generated points, in the order of their [`FixtureSpec`]
+#[derive(Debug, Default, Clone)]
+pub struct Fixture {
+    pub points: Vec<NekoPoint>,
+    pub exts: HashMap<Uuid, NekoPointExt>,
+    pub image_vectors: Vec<(Uuid, Vec<f32>)>,
+    pub hashes: Vec<(Uuid, [u8; HASH_LEN])>,
+    /// `(key, bytes)` of every object
+    pub objects: Vec<(String, Vec<u8>)>,
+    /// One group per cluster, then one per singleton
+    pub truth: Vec<Vec<Uuid>>,
+}
+
+/// Where [`Fixture::write`] put everything
+#[derive(Debug, Clone)]
+pub struct FixturePaths {
+    pub points_map: PathBuf,
+    pub points_map_ext: PathBuf,
+    pub image_explorer: PathBuf,
+    pub hash_explorer: PathBuf,
+    pub listing: PathBuf,
+    pub ground_truth: PathBuf,
+    pub objects: PathBuf,
+}
+
+impl Fixture {
+    fn push(
+        &mut self,
+        rng: &mut Pcg64,
+        image: Vec<f32>,
+        hash: [u8; HASH_LEN],
+        text_info: Option<NekoPointText>,
+        object: Object,
+    ) -> Uuid {
+        let id = uuid::Builder::from_random_bytes(rng.random()).into_uuid();
+        let key = format!("{}.{}", id, object.ext);
+        self.points.push(NekoPoint {
+            id,
+            height: object.height,
+            width: object.width,
+            size: Some(object.bytes.len()),
+            categories: None,
+            text_info,
+            qdrant_num_id: None,
+            extra: None,
+        });
+        self.exts.insert(
+            id,
+            NekoPointExt {
+                source: Some(NekoPointExtResource::Local(key.clone())),
+            },
+        );
+        self.image_vectors.push((id, image));
+        self.hashes.push((id, hash));
+        self.objects.push((key, object.bytes));
+        id
+    }
+
+    pub fn points_map(&self) -> HashMap<Uuid, NekoPoint> {
+        self.points.iter().map(|p| (p.id, p.clone())).collect()
+    }
+
+    pub fn image_explorer(&self) -> PointExplorer<f32, VECTOR_DIM> {
+        let mut explorer = PointExplorer::default();
+        explorer.extend(self.image_vectors.iter().map(|(id, v)| (id, v)));
+        explorer
+    }
+
+    pub fn hash_explorer(&self) -> PointExplorer<u8, HASH_LEN> {
+        let mut explorer = PointExplorer::default();
+        explorer.extend(self.hashes.iter().map(|(id, h)| (id, h)));
+        explorer
+    }
+
+    /// Listing of the objects as a file backend would return it
+    pub fn entries(&self) -> Vec<Entry> {
+        self.objects
+            .iter()
+            .map(|(key, bytes)| Entry {
+                path: key.clone(),
+                metadata: Metadata {
+                    mode: EntryMode::FILE,
+                    is_current: None,
+                    is_deleted: false,
+                    cache_control: None,
+                    content_disposition: None,
+                    content_length: Some(bytes.len() as u64),
+                    content_md5: None,
+                    content_range: None,
+                    content_type: None,
+                    content_encoding: None,
+                    etag: None,
+                    last_modified: None,
+                    version: None,
+                    user_metadata: None,
+                },
+            })
+            .collect()
+    }
+
+    /// Writes every file into `dir`, created if missing
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> FixtureResult<FixturePaths> {
+        let dir = dir.as_ref();
+        let paths = FixturePaths {
+            points_map: dir.join(POINTS_MAP_FILE),
+            points_map_ext: dir.join(POINTS_MAP_EXT_FILE),
+            image_explorer: dir.join(IMAGE_EXPLORER_FILE),
+            hash_explorer: dir.join(HASH_EXPLORER_FILE),
+            listing: dir.join(LISTING_FILE),
+            ground_truth: dir.join(GROUND_TRUTH_FILE),
+            objects: dir.join(OBJECTS_DIR),
+        };
+        fs::create_dir_all(&paths.objects)?;
+        for (key, bytes) in &self.objects {
+            fs::write(paths.objects.join(key), bytes)?;
+        }
+        let points_map = serde_pickle::to_vec(&self.points_map(), Default::default())?;
+        fs::write(&paths.points_map, points_map)?;
+        let exts = serde_pickle::to_vec(&self.exts, Default::default())?;
+        fs::write(&paths.points_map_ext, exts)?;
+        self.image_explorer()
+            .save(&paths.image_explorer.to_string_lossy())?;
+        self.hash_explorer()
+            .save(&paths.hash_explorer.to_string_lossy())?;
+        save_entry_list(&paths.listing, &self.entries())?;
+        fs::write(&paths.ground_truth, serde_json::to_vec(&self.truth)?)?;
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cosine_sim::cosine_sim;
+    use crate::hamming::hamming;
+    use crate::opendal::load_entry_list;
+    use crate::point_explorer::PointExplorerBuilder;
+    use image::AnimationDecoder;
+    use image::codecs::gif::GifDecoder;
+
+    fn spec() -> FixtureSpec {
+        FixtureSpec::new(7)
+            .cluster(ClusterSpec::new(4, 0.97).text_sim(0.95))
+            .cluster(ClusterSpec::new(3, 0.9).hash_flips(10).text_sim(0.3))
+            .cluster(ClusterSpec::new(2, 0.99).gif(6, GifMotion::Moving))
+            .singletons(3)
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let (a, b) = (spec().generate().unwrap(), spec().generate().unwrap());
+        assert_eq!(a.truth, b.truth);
+        assert_eq!(a.image_vectors, b.image_vectors);
+        assert_eq!(a.objects, b.objects);
+        let other = FixtureSpec::new(8).singletons(3).generate().unwrap();
+        assert_ne!(a.truth[3..], other.truth[..]);
+    }
+
+    #[test]
+    fn test_invalid_spec() {
+        let invalid = |cluster: ClusterSpec| {
+            FixtureSpec::new(0)
+                .cluster(ClusterSpec::new(2, 0.9))
+                .cluster(cluster)
+                .generate()
+        };
+        assert!(matches!(
+            invalid(ClusterSpec::new(2, 1.5)),
+            Err(FixtureError::InvalidSpec { cluster: 1, .. })
+        ));
+        assert!(matches!(
+            invalid(ClusterSpec::new(2, 0.9).text_sim(-0.1)),
+            Err(FixtureError::InvalidSpec { cluster: 1, .. })
+        ));
+        assert!(matches!(
+            invalid(ClusterSpec::new(VECTOR_DIM, 0.9)),
+            Err(FixtureError::InvalidSpec { cluster: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_similarity_structure() {
+        let fixture = spec().generate().unwrap();
+        let sizes: Vec<usize> = fixture.truth.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![4, 3, 2, 1, 1, 1]);
+        let points = fixture.points_map();
+        let images = fixture.image_explorer();
+        let hashes = fixture.hash_explorer();
+        let cluster_of: HashMap<Uuid, usize> = fixture
+            .truth
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, group)| group.iter().map(move |id| (*id, idx)))
+            .collect();
+        let specs = [(0.97, 4, Some(0.95)), (0.9, 10, Some(0.3)), (0.99, 4, None)];
+        for (i, a) in cluster_of.keys().enumerate() {
+            for b in cluster_of.keys().skip(i + 1) {
+                let image_sim = images.get_cosine_sim((a, b)).unwrap();
+                let distance =
+                    hamming(hashes.get_vector(a).unwrap(), hashes.get_vector(b).unwrap());
+                match (cluster_of[a], cluster_of[b]) {
+                    (x, y) if x == y => {
+                        let (sim, flips, text_sim) = specs[x];
+                        assert!((image_sim - sim).abs() < 1e-4, "{} vs {}", image_sim, sim);
+                        assert!(distance as usize <= 2 * flips);
+                        let texts = (&points[a].text_info, &points[b].text_info);
+                        match (texts, text_sim) {
+                            ((Some(ta), Some(tb)), Some(sim)) => {
+                                let text_sim = cosine_sim(&ta.text_vector, &tb.text_vector);
+                                assert!((text_sim - sim).abs() < 1e-4);
+                            }
+                            ((None, None), None) => {}
+                            _ => panic!("text of {} and {} does not follow the spec", a, b),
+                        }
+                    }
+                    _ => {
+                        assert!(image_sim.abs() < 0.2, "unrelated points at {}", image_sim);
+                        assert!(distance > 64, "unrelated hashes {} apart", distance);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_gif_frames() {
+        let still = GifSpec {
+            frames: 4,
+            motion: GifMotion::Still,
+        };
+        let moving = GifSpec {
+            motion: GifMotion::Moving,
+            ..still
+        };
+        let decode = |spec| {
+            let bytes = gif_bytes(64, 0, spec).unwrap();
+            let decoder = GifDecoder::new(Cursor::new(bytes)).unwrap();
+            let frames = decoder.into_frames().collect_frames().unwrap();
+            frames
+                .into_iter()
+                .map(|f| f.into_buffer().into_raw())
+                .collect::<Vec<_>>()
+        };
+        let frames = decode(still);
+        assert_eq!(frames.len(), 4);
+        assert!(frames.windows(2).all(|w| w[0] == w[1]));
+        let frames = decode(moving);
+        assert_eq!(frames.len(), 4);
+        assert!(frames.windows(2).all(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let fixture = spec().generate().unwrap();
+        let dir = std::env::temp_dir().join(format!("fixtures_{}", Uuid::new_v4()));
+        let paths = fixture.write(&dir).unwrap();
+        let explorer = PointExplorerBuilder::new()
+            .path(paths.image_explorer.to_string_lossy())
+            .metadata_path(paths.points_map.to_string_lossy())
+            .metadata_ext_path(paths.points_map_ext.to_string_lossy())
+            .build::<f32, VECTOR_DIM>()
+            .unwrap();
+        assert_eq!(explorer.len(), fixture.points.len());
+        let hashes = PointExplorerBuilder::new()
+            .path(paths.hash_explorer.to_string_lossy())
+            .build::<u8, HASH_LEN>()
+            .unwrap();
+        assert_eq!(hashes.len(), fixture.points.len());
+        let (entries, _) = load_entry_list(&paths.listing).unwrap();
+        for (entry, point) in entries.iter().zip(&fixture.points) {
+            assert_eq!(entry.to_point(), point.id.to_string());
+            let meta = explorer.get_point_metadata(&point.id).unwrap();
+            assert_eq!(entry.metadata.content_length, meta.size.map(|s| s as u64));
+            let on_disk = fs::metadata(paths.objects.join(&entry.path)).unwrap().len();
+            assert_eq!(Some(on_disk), entry.metadata.content_length);
+        }
+        let truth: Vec<Vec<Uuid>> =
+            serde_json::from_slice(&fs::read(&paths.ground_truth).unwrap()).unwrap();
+        assert_eq!(truth, fixture.truth);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
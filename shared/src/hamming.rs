@@ -0,0 +1,251 @@
+//! Bitwise Hamming distance of byte strings such as perceptual hashes
+//!
+//! [`hamming`] runs the fastest kernel of the CPU, picked once per process: 32 bytes at a time
+//! with a nibble lookup table on AVX2, 16 with `cnt` on NEON, otherwise 64 bits at a time with one
+//! popcount per word, the `popcnt` instruction when the CPU has it. Without any of them
+//! `count_ones` falls back to a bit-twiddling sequence that is several times slower.
+
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+use std::sync::OnceLock;
+
+pub trait Hamming {
+    fn hamming(a: &[Self], b: &[Self]) -> u32
+    where
+        Self: Sized;
+}
+
+impl Hamming for u8 {
+    #[inline]
+    fn hamming(a: &[u8], b: &[u8]) -> u32 {
+        hamming(a, b)
+    }
+}
+
+/// Strings of different lengths are a bug of the caller, they fail debug builds and are cut to the
+/// shorter one otherwise
+#[inline]
+pub fn hamming(a: &[u8], b: &[u8]) -> u32 {
+    debug_assert_eq!(a.len(), b.len(), "Hamming distance of unequal lengths");
+    (kernel().bytes)(a, b)
+}
+
+/// Byte by byte, the reference the other kernels are checked against
+#[inline]
+pub fn naive_hamming(a: &[u8], b: &[u8]) -> u32 {
+    a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+/// Hamming kernel for one instruction set, only handed out when the CPU supports it
+#[derive(Debug, Clone, Copy)]
+pub struct HammingKernel {
+    /// `avx2`, `popcnt`, `neon` or `scalar`
+    pub name: &'static str,
+    pub bytes: fn(&[u8], &[u8]) -> u32,
+}
+
+impl HammingKernel {
+    pub const SCALAR: HammingKernel = HammingKernel {
+        name: "scalar",
+        bytes: common_hamming,
+    };
+
+    #[cfg(target_arch = "x86_64")]
+    const POPCNT: HammingKernel = HammingKernel {
+        name: "popcnt",
+        bytes: |a, b| unsafe { hamming_popcnt(a, b) },
+    };
+
+    #[cfg(target_arch = "x86_64")]
+    const AVX2: HammingKernel = HammingKernel {
+        name: "avx2",
+        bytes: |a, b| unsafe { hamming_avx2(a, b) },
+    };
+
+    #[cfg(target_arch = "aarch64")]
+    const NEON: HammingKernel = HammingKernel {
+        name: "neon",
+        bytes: |a, b| unsafe { hamming_neon(a, b) },
+    };
+
+    /// Fastest kernel of this CPU, detected anew on every call, see [`kernel`] for the cached one
+    pub fn detect() -> HammingKernel {
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("popcnt") {
+            return Self::AVX2;
+        }
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("popcnt") {
+            return Self::POPCNT;
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return Self::NEON;
+        }
+        Self::SCALAR
+    }
+
+    /// Every kernel this CPU can run, [`HammingKernel::SCALAR`] first
+    pub fn supported() -> Vec<HammingKernel> {
+        let mut supported = vec![Self::SCALAR];
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("popcnt") {
+            supported.push(Self::POPCNT);
+        }
+        let best = Self::detect();
+        if supported.iter().all(|k| k.name != best.name) {
+            supported.push(best);
+        }
+        supported
+    }
+}
+
+/// Kernel [`hamming`] runs, detected on first use
+#[inline]
+pub fn kernel() -> &'static HammingKernel {
+    static KERNEL: OnceLock<HammingKernel> = OnceLock::new();
+    KERNEL.get_or_init(HammingKernel::detect)
+}
+
+#[inline(always)]
+fn common_hamming(a: &[u8], b: &[u8]) -> u32 {
+    let len = a.len().min(b.len());
+    let (words_a, words_b) = (a[..len].chunks_exact(8), b[..len].chunks_exact(8));
+    let tail = naive_hamming(words_a.remainder(), words_b.remainder());
+    words_a
+        .zip(words_b)
+        .map(|(x, y)| {
+            let x = u64::from_ne_bytes(x.try_into().unwrap());
+            let y = u64::from_ne_bytes(y.try_into().unwrap());
+            (x ^ y).count_ones()
+        })
+        .sum::<u32>()
+        + tail
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "popcnt")]
+unsafe fn hamming_popcnt(a: &[u8], b: &[u8]) -> u32 {
+    common_hamming(a, b)
+}
+
+/// Bits of every byte from two lookups of its nibbles, summed per 64-bit lane by `sad`
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,popcnt")]
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn hamming_avx2(a: &[u8], b: &[u8]) -> u32 {
+    let len = a.len().min(b.len());
+    let lookup = _mm256_setr_epi8(
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4, 0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3,
+        3, 4,
+    );
+    let low_nibbles = _mm256_set1_epi8(0x0f);
+    let mut lanes = _mm256_setzero_si256();
+    let chunks = len / 32;
+    for i in 0..chunks {
+        let va = _mm256_loadu_si256(a.as_ptr().add(i * 32) as *const __m256i);
+        let vb = _mm256_loadu_si256(b.as_ptr().add(i * 32) as *const __m256i);
+        let x = _mm256_xor_si256(va, vb);
+        let lo = _mm256_and_si256(x, low_nibbles);
+        let hi = _mm256_and_si256(_mm256_srli_epi16::<4>(x), low_nibbles);
+        let counts = _mm256_add_epi8(
+            _mm256_shuffle_epi8(lookup, lo),
+            _mm256_shuffle_epi8(lookup, hi),
+        );
+        lanes = _mm256_add_epi64(lanes, _mm256_sad_epu8(counts, _mm256_setzero_si256()));
+    }
+    let mut sums = [0u64; 4];
+    _mm256_storeu_si256(sums.as_mut_ptr() as *mut __m256i, lanes);
+    let done = chunks * 32;
+    sums.iter().sum::<u64>() as u32 + common_hamming(&a[done..len], &b[done..len])
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe fn hamming_neon(a: &[u8], b: &[u8]) -> u32 {
+    let len = a.len().min(b.len());
+    let chunks = len / 16;
+    let mut total = 0u32;
+    for i in 0..chunks {
+        let x = veorq_u8(
+            vld1q_u8(a.as_ptr().add(i * 16)),
+            vld1q_u8(b.as_ptr().add(i * 16)),
+        );
+        total += vaddlvq_u8(vcntq_u8(x)) as u32;
+    }
+    let done = chunks * 16;
+    total + common_hamming(&a[done..len], &b[done..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn test_hamming_known() {
+        assert_eq!(hamming(&[], &[]), 0);
+        assert_eq!(hamming(&[0b1010], &[0b0110]), 2);
+        let a = [0xffu8; 32];
+        assert_eq!(hamming(&a, &[0u8; 32]), 256);
+        assert_eq!(hamming(&a, &a), 0);
+    }
+
+    #[test]
+    fn test_hamming_matches_naive() {
+        let mut rng = Pcg64::seed_from_u64(42);
+        // word aligned, with a tail, and the u8/32 hashes of stages 16 to 19
+        for len in (0..=70).chain([32, 128, 1024]) {
+            for _ in 0..50 {
+                let a: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+                let b: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+                assert_eq!(hamming(&a, &b), naive_hamming(&a, &b), "length {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_kernels_agree() {
+        let mut rng = Pcg64::seed_from_u64(7);
+        let supported = HammingKernel::supported();
+        assert_eq!(supported[0].name, "scalar");
+        assert!(supported.iter().any(|k| k.name == kernel().name));
+        for len in (0..=100).chain([255, 256, 257, 4096]) {
+            let a: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+            let b: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+            for kernel in &supported {
+                assert_eq!(
+                    (kernel.bytes)(&a, &b),
+                    naive_hamming(&a, &b),
+                    "{} at length {}",
+                    kernel.name,
+                    len
+                );
+            }
+        }
+        // every bit set over many blocks, the per-lane sums must not wrap
+        let ones = vec![0xffu8; 1 << 16];
+        for kernel in &supported {
+            assert_eq!(
+                (kernel.bytes)(&ones, &[0u8; 1 << 16]),
+                1 << 19,
+                "{}",
+                kernel.name
+            );
+        }
+    }
+
+    /// Cut to the shorter string, never read past it
+    #[test]
+    fn test_kernels_length_mismatch() {
+        let (a, b) = ([0xffu8; 70], [0x0fu8; 33]);
+        for kernel in HammingKernel::supported() {
+            assert_eq!((kernel.bytes)(&a, &b), 33 * 4, "{}", kernel.name);
+            assert_eq!((kernel.bytes)(&b, &a), 33 * 4, "{}", kernel.name);
+        }
+    }
+}
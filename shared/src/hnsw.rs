@@ -1,10 +1,15 @@
+use crate::atomic_write::{atomic_write, atomic_write_with};
+use chrono::{DateTime, Utc};
 use hnsw_rs::prelude::*;
 use rayon::prelude::*;
-use serde::Serialize;
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
+use uuid::Uuid;
 #[cfg(feature = "pyo3")]
 use {
     ::pyo3::prelude::*,
@@ -23,7 +28,21 @@ pub struct HnswSearchResult {
     distance: f32,
 }
 
-#[cfg_attr(feature = "hnsw-pyo3", gen_stub_pymethods, pymethods)]
+impl HnswSearchResult {
+    #[inline]
+    pub fn point_id(&self) -> usize {
+        self.point_id
+    }
+
+    #[inline]
+    pub fn distance(&self) -> f32 {
+        self.distance
+    }
+}
+
+#[cfg(feature = "hnsw-pyo3")]
+#[gen_stub_pymethods]
+#[pymethods]
 impl HnswSearchResult {
     #[new]
     fn py_new(point_id: usize, distance: f32) -> Self {
@@ -41,15 +60,492 @@ impl HnswSearchResult {
     }
 }
 
+/// Neighbors [`adaptive_search`] found under the distance cap
+#[derive(Debug, Clone)]
+pub struct AdaptiveSearchResult {
+    pub neighbors: Vec<HnswSearchResult>,
+    /// k of the last search
+    pub k: usize,
+    /// The last search was still saturated at `max_k`, the neighborhood may be truncated
+    pub max_k_hit: bool,
+}
+
+/// Searches with `initial_k` and doubles k up to `max_k` while every neighbor found is within
+/// `max_distance`
+///
+/// Isolated points return after one cheap search, dense clusters keep widening until the
+/// result holds a neighbor beyond the cap. ef is `ef_factor` times k.
+pub fn adaptive_search<V, D>(
+    hnsw: &Hnsw<'_, V, D>,
+    query: &[V],
+    max_distance: f32,
+    initial_k: usize,
+    max_k: usize,
+    ef_factor: f32,
+) -> AdaptiveSearchResult
+where
+    V: Serialize + DeserializeOwned + Clone + Debug + Default + Send + Sync + 'static,
+    D: Distance<V> + Default + Send + Sync,
+{
+    adaptive_search_filtered(
+        hnsw,
+        query,
+        max_distance,
+        initial_k,
+        max_k,
+        ef_factor,
+        |_| true,
+    )
+}
+
+/// [`adaptive_search`] without the tombstones of `ids`
+///
+/// Tombstones under the cap still take their slot in each search, so k widens past them.
+pub fn adaptive_search_live<V, D>(
+    hnsw: &Hnsw<'_, V, D>,
+    query: &[V],
+    max_distance: f32,
+    initial_k: usize,
+    max_k: usize,
+    ef_factor: f32,
+    ids: &HnswIdMap,
+) -> AdaptiveSearchResult
+where
+    V: Serialize + DeserializeOwned + Clone + Debug + Default + Send + Sync + 'static,
+    D: Distance<V> + Default + Send + Sync,
+{
+    adaptive_search_filtered(
+        hnsw,
+        query,
+        max_distance,
+        initial_k,
+        max_k,
+        ef_factor,
+        |point_id| ids.is_live(point_id),
+    )
+}
+
+fn adaptive_search_filtered<V, D>(
+    hnsw: &Hnsw<'_, V, D>,
+    query: &[V],
+    max_distance: f32,
+    initial_k: usize,
+    max_k: usize,
+    ef_factor: f32,
+    keep: impl Fn(usize) -> bool,
+) -> AdaptiveSearchResult
+where
+    V: Serialize + DeserializeOwned + Clone + Debug + Default + Send + Sync + 'static,
+    D: Distance<V> + Default + Send + Sync,
+{
+    let mut k = initial_k.clamp(1, max_k.max(1));
+    loop {
+        let ef = ((k as f32 * ef_factor).ceil() as usize).max(k);
+        let res = hnsw.search(query, k, ef);
+        let saturated = res.len() == k && res.iter().all(|n| n.distance <= max_distance);
+        if !saturated || k >= max_k {
+            let neighbors = res
+                .into_iter()
+                .filter(|n| n.distance <= max_distance && keep(n.d_id))
+                .map(|n| HnswSearchResult {
+                    point_id: n.d_id,
+                    distance: n.distance,
+                })
+                .collect();
+            return AdaptiveSearchResult {
+                neighbors,
+                k,
+                max_k_hit: saturated,
+            };
+        }
+        k = (k * 2).min(max_k);
+    }
+}
+
+/// The `k` nearest live neighbors, searching `k + over_fetch` and widening while tombstones
+/// leave fewer than `k`
+pub fn search_live<V, D>(
+    hnsw: &Hnsw<'_, V, D>,
+    query: &[V],
+    k: usize,
+    ef: usize,
+    ids: &HnswIdMap,
+    over_fetch: usize,
+) -> Vec<HnswSearchResult>
+where
+    V: Serialize + DeserializeOwned + Clone + Debug + Default + Send + Sync + 'static,
+    D: Distance<V> + Default + Send + Sync,
+{
+    // never more than every tombstone could hide
+    let max_k = k + ids.tombstones().len();
+    let mut fetch = (k + over_fetch).min(max_k);
+    loop {
+        let res = hnsw.search(query, fetch, ef.max(fetch));
+        let exhausted = res.len() < fetch;
+        let mut live: Vec<HnswSearchResult> = res
+            .into_iter()
+            .filter(|n| ids.is_live(n.d_id))
+            .map(|n| HnswSearchResult {
+                point_id: n.d_id,
+                distance: n.distance,
+            })
+            .collect();
+        if live.len() >= k || exhausted || fetch >= max_k {
+            live.truncate(k);
+            return live;
+        }
+        fetch = (fetch * 2).min(max_k);
+    }
+}
+
+/// Sha1 over the ids in order, the explorer positions an index's point ids refer to
+pub fn uuid_list_hash<'u, I>(ids: I) -> String
+where
+    I: IntoIterator<Item = &'u Uuid>,
+{
+    let mut hasher = Sha1::new();
+    for id in ids {
+        hasher.update(id.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// What the graph was built with
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HnswParams {
+    pub max_nb_connection: usize,
+    pub max_elements: usize,
+    pub max_layer: usize,
+    pub ef_construction: usize,
+}
+
+/// Sidecar of a dumped index, `<basename>.hnsw.meta.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HnswMeta {
+    pub params: HnswParams,
+    /// Name of the distance type, e.g. `DistHamming`
+    pub distance: String,
+    pub point_count: usize,
+    /// [`uuid_list_hash`] of the explorer the index was built from
+    pub uuid_hash: String,
+    pub built_at: DateTime<Utc>,
+    /// Points removed since the build and still in the graph, see [`HnswIdMap`]
+    #[serde(default)]
+    pub tombstones: usize,
+}
+
+impl HnswMeta {
+    pub fn path<P: AsRef<Path>>(dir: P, basename: &str) -> PathBuf {
+        dir.as_ref().join(format!("{}.hnsw.meta.json", basename))
+    }
+
+    /// The index's point ids only mean something for the explorer it was built from
+    pub fn validate<'u, I>(&self, ids: I) -> Result<(), HnswMetaError>
+    where
+        I: IntoIterator<Item = &'u Uuid>,
+    {
+        let mut count = 0;
+        let actual = uuid_list_hash(ids.into_iter().inspect(|_| count += 1));
+        if actual != self.uuid_hash {
+            return Err(HnswMetaError::Mismatch {
+                expected: self.uuid_hash.clone(),
+                expected_count: self.point_count,
+                actual,
+                actual_count: count,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Points added to and removed from the explorer since an index was built
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HnswDiff {
+    /// In explorer order
+    pub added: Vec<Uuid>,
+    /// Point ids
+    pub removed: Vec<usize>,
+}
+
+impl HnswDiff {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Uuid of every point id of an index and the ones removed since, `<basename>.hnsw.ids.bin`
+///
+/// A freshly built index has the explorer positions as point ids, an updated one appends the
+/// added points after them. hnsw_rs cannot delete, removed points stay in the graph as tombstones
+/// the `*_live` searches skip.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HnswIdMap {
+    ids: Vec<Uuid>,
+    tombstones: BTreeSet<usize>,
+    /// Point id of every live uuid, rebuilt on load
+    #[serde(skip)]
+    point_ids: HashMap<Uuid, usize>,
+}
+
+impl HnswIdMap {
+    pub fn from_ids<'u, I>(ids: I) -> Self
+    where
+        I: IntoIterator<Item = &'u Uuid>,
+    {
+        let mut map = HnswIdMap {
+            ids: ids.into_iter().copied().collect(),
+            ..Default::default()
+        };
+        map.index();
+        map
+    }
+
+    fn index(&mut self) {
+        self.point_ids = self
+            .ids
+            .iter()
+            .enumerate()
+            .filter(|(point_id, _)| !self.tombstones.contains(point_id))
+            .map(|(point_id, id)| (*id, point_id))
+            .collect();
+    }
+
+    pub fn path<P: AsRef<Path>>(dir: P, basename: &str) -> PathBuf {
+        dir.as_ref().join(format!("{}.hnsw.ids.bin", basename))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, HnswMetaError> {
+        let data = match std::fs::read(path.as_ref()) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(HnswMetaError::MissingIds(path.as_ref().to_path_buf()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut map: HnswIdMap =
+            bincode::serde::decode_from_slice(&data, bincode::config::standard())?.0;
+        map.index();
+        Ok(map)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), HnswMetaError> {
+        let data = bincode::serde::encode_to_vec(self, bincode::config::standard())?;
+        atomic_write(path, data)?;
+        Ok(())
+    }
+
+    /// Point ids in the graph, tombstones included
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    #[inline]
+    pub fn live_len(&self) -> usize {
+        self.point_ids.len()
+    }
+
+    /// By point id, tombstones included
+    #[inline]
+    pub fn ids(&self) -> &[Uuid] {
+        &self.ids
+    }
+
+    #[inline]
+    pub fn tombstones(&self) -> &BTreeSet<usize> {
+        &self.tombstones
+    }
+
+    #[inline]
+    pub fn is_live(&self, point_id: usize) -> bool {
+        point_id < self.ids.len() && !self.tombstones.contains(&point_id)
+    }
+
+    /// `None` for tombstones
+    #[inline]
+    pub fn uuid(&self, point_id: usize) -> Option<&Uuid> {
+        self.is_live(point_id).then(|| &self.ids[point_id])
+    }
+
+    #[inline]
+    pub fn point_id(&self, id: &Uuid) -> Option<usize> {
+        self.point_ids.get(id).copied()
+    }
+
+    /// What `current`, the explorer's ids, holds that the live points do not and the other way
+    /// around
+    pub fn diff<'u, I>(&self, current: I) -> HnswDiff
+    where
+        I: IntoIterator<Item = &'u Uuid>,
+    {
+        let mut seen = BTreeSet::new();
+        let added = current
+            .into_iter()
+            .filter(|id| match self.point_id(id) {
+                Some(point_id) => {
+                    seen.insert(point_id);
+                    false
+                }
+                None => true,
+            })
+            .copied()
+            .collect();
+        let removed = self
+            .point_ids
+            .values()
+            .copied()
+            .filter(|point_id| !seen.contains(point_id))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        HnswDiff { added, removed }
+    }
+
+    /// Point id of `id`, now the last one
+    pub fn push(&mut self, id: Uuid) -> usize {
+        let point_id = self.ids.len();
+        self.ids.push(id);
+        if let Some(old) = self.point_ids.insert(id, point_id) {
+            self.tombstones.insert(old);
+        }
+        point_id
+    }
+
+    pub fn tombstone(&mut self, point_id: usize) {
+        if self.is_live(point_id) {
+            self.point_ids.remove(&self.ids[point_id]);
+            self.tombstones.insert(point_id);
+        }
+    }
+}
+
+/// What [`HnswIndex::add_points`] made of the points it was given
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HnswMerge {
+    /// Inserted, in the order given
+    pub added: Vec<Uuid>,
+    /// Already live in the index or given twice, skipped
+    pub present: Vec<Uuid>,
+}
+
+/// One (ef_search, k) cell of [`HnswIndex::tune`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TunePoint {
+    pub ef_search: usize,
+    pub k: usize,
+    /// Share of the exact k nearest found, averaged over the queries. A neighbor as close as the
+    /// exact k-th counts as found, ties are common under Hamming distances
+    pub recall: f32,
+    pub latency_mean_ms: f64,
+    pub latency_p99_ms: f64,
+    /// Exact distance of the k-th nearest, averaged over the queries
+    pub kth_distance: f32,
+}
+
+/// Recall and latency of an index over a grid of searches, see [`HnswIndex::tune`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TuneReport {
+    pub queries: usize,
+    /// Points the exact search ran over
+    pub points: usize,
+    /// By k then ef_search
+    pub grid: Vec<TunePoint>,
+}
+
+impl TuneReport {
+    /// Fastest cell for `k` reaching `recall`
+    pub fn fastest(&self, k: usize, recall: f32) -> Option<&TunePoint> {
+        self.grid
+            .iter()
+            .filter(|p| p.k == k && p.recall >= recall)
+            .min_by(|a, b| a.latency_mean_ms.total_cmp(&b.latency_mean_ms))
+    }
+}
+
+/// Mean and 99th percentile of `latencies`, in milliseconds
+fn latency_stats(mut latencies: Vec<f64>) -> (f64, f64) {
+    if latencies.is_empty() {
+        return (0.0, 0.0);
+    }
+    latencies.sort_by(f64::total_cmp);
+    let mean = latencies.iter().sum::<f64>() / latencies.len() as f64;
+    let p99 = (latencies.len() as f64 * 0.99).ceil() as usize;
+    (mean, latencies[p99.clamp(1, latencies.len()) - 1])
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HnswMetaError {
+    #[error("No HNSW metadata at {0}")]
+    MissingMeta(PathBuf),
+    #[error("No HNSW id map at {0}")]
+    MissingIds(PathBuf),
+    #[error(
+        "HNSW index was built from {expected_count} points hashing to {expected}, the explorer \
+         has {actual_count} hashing to {actual}"
+    )]
+    Mismatch {
+        expected: String,
+        expected_count: usize,
+        actual: String,
+        actual_count: usize,
+    },
+    #[error("HNSW index dump or load failed: {0}")]
+    Index(String),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error(transparent)]
+    BinCodeSerdeEncodeError(#[from] bincode::error::EncodeError),
+    #[error(transparent)]
+    BinCodeSerdeDecodeError(#[from] bincode::error::DecodeError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Last path segment of `D`'s type name
+fn distance_name<D>() -> String {
+    let name = std::any::type_name::<D>();
+    name.rsplit("::").next().unwrap_or(name).to_owned()
+}
+
 #[derive(Default)]
 pub struct HnswStorage {
     io: HnswIo,
+    dir: PathBuf,
+    basename: String,
 }
 
 impl HnswStorage {
     pub fn open<P: AsRef<Path>>(dir: P, basename: &str) -> Self {
         let io = HnswIo::new(dir.as_ref(), basename);
-        HnswStorage { io }
+        HnswStorage {
+            io,
+            dir: dir.as_ref().to_path_buf(),
+            basename: basename.to_owned(),
+        }
+    }
+
+    /// The sidecar [`HnswIndex::dump_with_meta`] wrote
+    pub fn meta(&self) -> Result<HnswMeta, HnswMetaError> {
+        let path = HnswMeta::path(&self.dir, &self.basename);
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(HnswMetaError::MissingMeta(path));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    /// The id map [`HnswIndex::dump_with_ids`] wrote
+    pub fn ids(&self) -> Result<HnswIdMap, HnswMetaError> {
+        HnswIdMap::load(HnswIdMap::path(&self.dir, &self.basename))
     }
 
     pub fn load<V, D>(&mut self) -> Hnsw<'_, V, D>
@@ -67,6 +563,7 @@ where
     D: Distance<V> + Default + Send + Sync,
 {
     inner: Hnsw<'a, V, D>,
+    params: HnswParams,
     search_mode_flag: AtomicBool,
 }
 
@@ -91,18 +588,186 @@ where
         );
         HnswIndex {
             inner,
+            params: HnswParams {
+                max_nb_connection,
+                max_elements,
+                max_layer,
+                ef_construction,
+            },
             search_mode_flag: AtomicBool::new(false),
         }
     }
 
+    /// `max_elements` of an index loaded this way is its point count
     pub fn new_from_storage(storage: &mut HnswStorage) -> HnswIndex<'_, V, D> {
         let inner = storage.load();
+        let params = HnswParams {
+            max_nb_connection: inner.get_max_nb_connection() as usize,
+            max_elements: inner.get_nb_point(),
+            max_layer: inner.get_max_level(),
+            ef_construction: inner.get_ef_construction(),
+        };
         HnswIndex {
             inner,
+            params,
             search_mode_flag: AtomicBool::new(false),
         }
     }
 
+    /// Loads the index after checking `ids`, the explorer's in order, are the ones it was built
+    /// from
+    pub fn load_with_meta<'u, I>(
+        storage: &mut HnswStorage,
+        ids: I,
+    ) -> Result<(HnswIndex<'_, V, D>, HnswMeta), HnswMetaError>
+    where
+        I: IntoIterator<Item = &'u Uuid>,
+    {
+        let meta = storage.meta()?;
+        meta.validate(ids)?;
+        let inner = storage
+            .io
+            .load_hnsw()
+            .map_err(|e| HnswMetaError::Index(format!("{:#}", e)))?;
+        let index = HnswIndex {
+            inner,
+            params: meta.params,
+            search_mode_flag: AtomicBool::new(false),
+        };
+        Ok((index, meta))
+    }
+
+    /// Dumps the graph and data under `basename` in `dir` with an [`HnswMeta`] sidecar, `ids`
+    /// being the explorer's in order. Returns the basename actually used
+    pub fn dump_with_meta<'u, P, I>(
+        &self,
+        dir: P,
+        basename: &str,
+        ids: I,
+    ) -> Result<String, HnswMetaError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = &'u Uuid>,
+    {
+        self.dump(dir.as_ref(), basename, ids, 0)
+    }
+
+    /// [`HnswIndex::dump_with_meta`] plus the id map, for indices kept up to date with
+    /// [`HnswIndex::update`]
+    pub fn dump_with_ids<P>(
+        &self,
+        dir: P,
+        basename: &str,
+        ids: &HnswIdMap,
+    ) -> Result<String, HnswMetaError>
+    where
+        P: AsRef<Path>,
+    {
+        let dumped = self.dump(dir.as_ref(), basename, ids.ids(), ids.tombstones().len())?;
+        ids.save(HnswIdMap::path(dir, &dumped))?;
+        Ok(dumped)
+    }
+
+    fn dump<'u, I>(
+        &self,
+        dir: &Path,
+        basename: &str,
+        ids: I,
+        tombstones: usize,
+    ) -> Result<String, HnswMetaError>
+    where
+        I: IntoIterator<Item = &'u Uuid>,
+    {
+        let dumped = self
+            .inner
+            .file_dump(dir, basename)
+            .map_err(|e| HnswMetaError::Index(format!("{:#}", e)))?;
+        let meta = HnswMeta {
+            params: self.params,
+            distance: distance_name::<D>(),
+            point_count: self.inner.get_nb_point(),
+            uuid_hash: uuid_list_hash(ids),
+            built_at: Utc::now(),
+            tombstones,
+        };
+        atomic_write_with(HnswMeta::path(dir, &dumped), |w| {
+            Ok::<_, HnswMetaError>(serde_json::to_writer_pretty(w, &meta)?)
+        })?;
+        Ok(dumped)
+    }
+
+    /// [`HnswIndex::dump_with_ids`] under `basename` itself, replacing the files of a previous
+    /// save there, so an index appended to every night keeps its name. The dump goes to a scratch
+    /// basename first and each file is then renamed over the old one
+    pub fn save<P>(&self, dir: P, basename: &str, ids: &HnswIdMap) -> Result<(), HnswMetaError>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let scratch = format!("{}.{}", basename, Uuid::new_v4().simple());
+        let dumped = self.dump_with_ids(dir, &scratch, ids)?;
+        // the sidecars last, a crash in between leaves them disagreeing with the graph
+        for ext in ["hnsw.graph", "hnsw.data", "hnsw.ids.bin", "hnsw.meta.json"] {
+            std::fs::rename(
+                dir.join(format!("{}.{}", dumped, ext)),
+                dir.join(format!("{}.{}", basename, ext)),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Appends the points `ids` has no live point id for after the existing ones, without looking
+    /// at what is missing from `points`, see [`HnswIndex::update`] for that
+    pub fn add_points<'u, I>(&mut self, ids: &mut HnswIdMap, points: I) -> HnswMerge
+    where
+        I: IntoIterator<Item = (&'u Uuid, &'u [V])>,
+    {
+        let mut merge = HnswMerge::default();
+        let mut vectors: Vec<(Vec<V>, usize)> = Vec::new();
+        for (id, v) in points {
+            if ids.point_id(id).is_some() {
+                merge.present.push(*id);
+                continue;
+            }
+            vectors.push((v.to_vec(), ids.push(*id)));
+            merge.added.push(*id);
+        }
+        let points: Vec<(&Vec<V>, usize)> = vectors.iter().map(|(v, id)| (v, *id)).collect();
+        self.insert(&points);
+        self.params.max_elements = self.params.max_elements.max(ids.len());
+        merge
+    }
+
+    /// Brings the index to `current`, the explorer's points: inserts the added ones after the
+    /// existing point ids and tombstones the removed ones
+    pub fn update<'u, I>(&mut self, ids: &mut HnswIdMap, current: I) -> HnswDiff
+    where
+        I: IntoIterator<Item = (&'u Uuid, &'u [V])>,
+    {
+        let current: Vec<(&Uuid, &[V])> = current.into_iter().collect();
+        let diff = ids.diff(current.iter().map(|(id, _)| *id));
+        let added: HashSet<&Uuid> = diff.added.iter().collect();
+        for point_id in diff.removed.iter() {
+            ids.tombstone(*point_id);
+        }
+        self.add_points(
+            ids,
+            current.into_iter().filter(|(id, _)| added.contains(id)),
+        );
+        diff
+    }
+
+    #[inline]
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    /// Switches to search mode and hands out the graph, for searches this wrapper does not cover
+    pub fn searcher(&mut self) -> &Hnsw<'a, V, D> {
+        self.check_search();
+        &self.inner
+    }
+
     fn check_insert(&mut self) {
         if self
             .search_mode_flag
@@ -114,7 +779,7 @@ where
 
     pub fn insert(&mut self, points: &[(&Vec<V>, usize)]) {
         self.check_insert();
-        self.inner.parallel_insert(&points);
+        self.inner.parallel_insert(points);
     }
 
     fn check_search(&mut self) {
@@ -139,6 +804,112 @@ where
             .collect()
     }
 
+    /// [`search_live`] on this index
+    pub fn search_live(
+        &mut self,
+        query: &[V],
+        k: usize,
+        ef: usize,
+        ids: &HnswIdMap,
+        over_fetch: usize,
+    ) -> Vec<HnswSearchResult> {
+        self.check_search();
+        search_live(&self.inner, query, k, ef, ids, over_fetch)
+    }
+
+    /// [`adaptive_search`] on this index
+    pub fn search_adaptive(
+        &mut self,
+        query: &[V],
+        max_distance: f32,
+        initial_k: usize,
+        max_k: usize,
+        ef_factor: f32,
+    ) -> AdaptiveSearchResult {
+        self.check_search();
+        adaptive_search(
+            &self.inner,
+            query,
+            max_distance,
+            initial_k,
+            max_k,
+            ef_factor,
+        )
+    }
+
+    /// Runs every (ef_search, k) of the grid from the `queries`, positions in `data`, and
+    /// compares with an exact search over `data`, the live points as inserted. Queries find
+    /// themselves at distance 0, as they do in the exact search
+    ///
+    /// Each search is timed alone so the latencies are single query ones, cells with `ef_search`
+    /// below k are left out since hnsw_rs searches with k then.
+    pub fn tune(
+        &mut self,
+        data: &[(&Vec<V>, usize)],
+        queries: &[usize],
+        ef_search: &[usize],
+        ks: &[usize],
+    ) -> TuneReport {
+        let max_k = ks.iter().copied().max().unwrap_or(0).min(data.len());
+        let distance = D::default();
+        let exact: Vec<Vec<f32>> = queries
+            .par_iter()
+            .map(|&query| {
+                let mut distances: Vec<f32> = data
+                    .iter()
+                    .map(|(v, _)| distance.eval(data[query].0, v))
+                    .collect();
+                if max_k > 0 && max_k < distances.len() {
+                    distances.select_nth_unstable_by(max_k - 1, f32::total_cmp);
+                }
+                distances.truncate(max_k);
+                distances.sort_by(f32::total_cmp);
+                distances
+            })
+            .collect();
+        let live: HashSet<usize> = data.iter().map(|(_, id)| *id).collect();
+        self.check_search();
+        let mut grid = Vec::new();
+        for &k in ks {
+            let k = k.min(data.len());
+            if k == 0 {
+                continue;
+            }
+            for &ef in ef_search.iter().filter(|&&ef| ef >= k) {
+                let mut recall = 0.0;
+                let mut kth_distance = 0.0;
+                let mut latencies = Vec::with_capacity(queries.len());
+                for (&query, exact) in queries.iter().zip(exact.iter()) {
+                    let start = std::time::Instant::now();
+                    let res = self.inner.search(data[query].0, k, ef);
+                    latencies.push(start.elapsed().as_secs_f64() * 1e3);
+                    let kth = exact[k - 1];
+                    let found = res
+                        .iter()
+                        .filter(|n| live.contains(&n.d_id) && n.distance <= kth)
+                        .count();
+                    recall += found.min(k) as f32 / k as f32;
+                    kth_distance += kth;
+                }
+                let (latency_mean_ms, latency_p99_ms) = latency_stats(latencies);
+                let n = queries.len().max(1) as f32;
+                grid.push(TunePoint {
+                    ef_search: ef,
+                    k,
+                    recall: recall / n,
+                    latency_mean_ms,
+                    latency_p99_ms,
+                    kth_distance: kth_distance / n,
+                });
+            }
+        }
+        TuneReport {
+            queries: queries.len(),
+            points: data.len(),
+            grid,
+        }
+    }
+
     // TODO: indicatif
     pub fn search_batch(
         &mut self,
@@ -309,3 +1080,294 @@ pub mod pyo3 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIM: usize = 8;
+    const BLOB: usize = 300;
+    const ISOLATED: usize = 100;
+
+    /// A tight blob of `BLOB` points at the origin and `ISOLATED` points far from it and each other
+    fn dataset() -> Vec<Vec<f32>> {
+        let blob = (0..BLOB).map(|i| {
+            let mut v = vec![0.0; DIM];
+            v[i % DIM] = (i / DIM) as f32 * 1e-3;
+            v
+        });
+        let isolated = (0..ISOLATED).map(|i| {
+            let mut v = vec![0.0; DIM];
+            v[i % DIM] = 100.0 * (i + 1) as f32;
+            v
+        });
+        blob.chain(isolated).collect()
+    }
+
+    #[test]
+    fn test_search_adaptive() {
+        let data = dataset();
+        let mut index = HnswIndex::new(16, data.len(), 16, 200, DistL2);
+        let points: Vec<(&Vec<f32>, usize)> = data.iter().zip(0..).collect();
+        index.insert(&points);
+
+        for query in [0, BLOB / 2, BLOB - 1] {
+            let res = index.search_adaptive(&data[query], 1.0, 16, 256, 2.5);
+            assert_eq!(res.k, 256, "blob query {} did not escalate", query);
+            assert!(res.max_k_hit);
+            assert_eq!(res.neighbors.len(), 256);
+            assert!(res.neighbors.iter().all(|n| n.point_id() < BLOB));
+        }
+        // the blob fits under max_k, the search stops once it reaches beyond it
+        let res = index.search_adaptive(&data[0], 1.0, 16, 1024, 2.5);
+        assert_eq!(res.k, 512);
+        assert!(!res.max_k_hit);
+        assert_eq!(res.neighbors.len(), BLOB);
+
+        for query in [BLOB, BLOB + ISOLATED / 2, BLOB + ISOLATED - 1] {
+            let res = index.search_adaptive(&data[query], 1.0, 16, 256, 2.5);
+            assert_eq!(res.k, 16, "isolated query {} escalated", query);
+            assert!(!res.max_k_hit);
+            assert_eq!(res.neighbors.len(), 1);
+            assert_eq!(res.neighbors[0].point_id(), query);
+            assert_eq!(res.neighbors[0].distance(), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_update_with_tombstones() {
+        use rand::{Rng, SeedableRng};
+        use rand_pcg::Pcg64;
+        let mut rng = Pcg64::seed_from_u64(42);
+        let mut point = || -> (Uuid, Vec<f32>) {
+            let v = (0..DIM).map(|_| rng.random_range(-1.0..1.0)).collect();
+            (Uuid::from_u128(rng.random()), v)
+        };
+        let mut points: Vec<(Uuid, Vec<f32>)> = (0..1000).map(|_| point()).collect();
+        let vectors: Vec<(&Vec<f32>, usize)> = points.iter().map(|(_, v)| v).zip(0..).collect();
+        let mut index = HnswIndex::new(16, points.len(), 16, 200, DistL2);
+        index.insert(&vectors);
+        let mut ids = HnswIdMap::from_ids(points.iter().map(|(id, _)| id));
+
+        let added: Vec<(Uuid, Vec<f32>)> = (0..100).map(|_| point()).collect();
+        let removed: Vec<(Uuid, Vec<f32>)> = points.drain(..50).collect();
+        points.extend(added.iter().cloned());
+        let diff = index.update(&mut ids, points.iter().map(|(id, v)| (id, v.as_slice())));
+        assert_eq!(diff.added.len(), 100);
+        assert_eq!(diff.removed, (0..50).collect::<Vec<_>>());
+        assert_eq!((ids.len(), ids.live_len()), (1100, 1050));
+        assert!(ids.diff(points.iter().map(|(id, _)| id)).is_empty());
+
+        for (id, v) in added.iter() {
+            let res = index.search_live(v, 10, 64, &ids, 0);
+            assert_eq!(res.len(), 10);
+            assert_eq!(ids.uuid(res[0].point_id()), Some(id));
+        }
+        for (id, v) in removed.iter() {
+            // its own vector is the closest, the tombstone must not come back
+            let res = index.search_live(v, 10, 64, &ids, 0);
+            assert_eq!(res.len(), 10);
+            assert!(res.iter().all(|n| ids.is_live(n.point_id())));
+            assert!(res.iter().all(|n| ids.uuid(n.point_id()) != Some(id)));
+            let res = index.search_adaptive(v, f32::MAX, 4, 16, 2.5);
+            assert!(res.neighbors.iter().any(|n| !ids.is_live(n.point_id())));
+            let res = adaptive_search_live(index.searcher(), v, f32::MAX, 4, 16, 2.5, &ids);
+            assert!(res.neighbors.iter().all(|n| ids.is_live(n.point_id())));
+        }
+    }
+
+    #[test]
+    fn test_add_points_and_save() {
+        use rand::{Rng, SeedableRng};
+        use rand_pcg::Pcg64;
+        let dir = std::env::temp_dir().join(format!("hnsw_save_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut rng = Pcg64::seed_from_u64(7);
+        let mut point = || -> (Uuid, Vec<f32>) {
+            let v = (0..DIM).map(|_| rng.random_range(-1.0..1.0)).collect();
+            (Uuid::from_u128(rng.random()), v)
+        };
+        let points: Vec<(Uuid, Vec<f32>)> = (0..500).map(|_| point()).collect();
+        let vectors: Vec<(&Vec<f32>, usize)> = points.iter().map(|(_, v)| v).zip(0..).collect();
+        let mut index = HnswIndex::new(16, points.len(), 16, 200, DistL2);
+        index.insert(&vectors);
+        let ids = HnswIdMap::from_ids(points.iter().map(|(id, _)| id));
+        index.save(&dir, "index", &ids).unwrap();
+
+        let added: Vec<(Uuid, Vec<f32>)> = (0..100).map(|_| point()).collect();
+        for round in 0..2 {
+            let mut storage = HnswStorage::open(&dir, "index");
+            let mut ids = storage.ids().unwrap();
+            let (mut index, meta) =
+                HnswIndex::<f32, DistL2>::load_with_meta(&mut storage, ids.ids()).unwrap();
+            assert_eq!(meta.point_count, 500 + 100 * round);
+            // a nightly batch overlapping what is already there, one point twice
+            let batch: Vec<&(Uuid, Vec<f32>)> = points[..20]
+                .iter()
+                .chain(added.iter())
+                .chain(added.first())
+                .collect();
+            let merge = index.add_points(&mut ids, batch.iter().map(|(id, v)| (id, v.as_slice())));
+            if round == 0 {
+                assert_eq!(
+                    merge.added,
+                    added.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+                );
+                assert_eq!(merge.present.len(), 21);
+                assert_eq!(merge.present[20], added[0].0);
+            } else {
+                assert!(merge.added.is_empty());
+                assert_eq!(merge.present.len(), 121);
+            }
+            assert_eq!((ids.len(), ids.live_len()), (600, 600));
+            index.save(&dir, "index", &ids).unwrap();
+        }
+
+        let mut storage = HnswStorage::open(&dir, "index");
+        let ids = storage.ids().unwrap();
+        let (mut index, _) =
+            HnswIndex::<f32, DistL2>::load_with_meta(&mut storage, ids.ids()).unwrap();
+        for (id, v) in points.iter().step_by(50).chain(added.iter()) {
+            let res = index.search(v, 1, 64);
+            assert_eq!(ids.uuid(res[0].point_id()), Some(id));
+        }
+        // only the saved basename is left
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "index.hnsw.data",
+                "index.hnsw.graph",
+                "index.hnsw.ids.bin",
+                "index.hnsw.meta.json"
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tune() {
+        use rand::{Rng, SeedableRng};
+        use rand_pcg::Pcg64;
+        let mut rng = Pcg64::seed_from_u64(42);
+        let data: Vec<Vec<f32>> = (0..1000)
+            .map(|_| (0..DIM).map(|_| rng.random_range(-1.0..1.0)).collect())
+            .collect();
+        let points: Vec<(&Vec<f32>, usize)> = data.iter().zip(0..).collect();
+        let mut index = HnswIndex::new(16, data.len(), 16, 200, DistL2);
+        index.insert(&points);
+        let queries: Vec<usize> = (0..data.len()).step_by(20).collect();
+
+        let report = index.tune(&points, &queries, &[4, 32, 256], &[1, 10, 50]);
+        assert_eq!((report.queries, report.points), (50, 1000));
+        let cells: Vec<(usize, usize)> = report.grid.iter().map(|p| (p.k, p.ef_search)).collect();
+        // ef 4 is below k 10 and 50
+        assert_eq!(
+            cells,
+            [(1, 4), (1, 32), (1, 256), (10, 32), (10, 256), (50, 256)]
+        );
+        for p in report.grid.iter() {
+            assert!((0.0..=1.0).contains(&p.recall));
+            assert!(p.latency_mean_ms <= p.latency_p99_ms);
+        }
+        // every query finds itself
+        assert_eq!(report.grid[0].kth_distance, 0.0);
+        assert!(report.grid[5].kth_distance > report.grid[3].kth_distance);
+        assert!(report.grid[4].recall >= 0.95, "{:?}", report.grid[4]);
+        let fastest = report.fastest(10, 0.95).unwrap();
+        assert_eq!(fastest.k, 10);
+        assert!(report.fastest(10, 1.01).is_none());
+    }
+
+    #[test]
+    fn test_id_map() {
+        let dir = std::env::temp_dir().join(format!("hnsw_ids_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let uuid = Uuid::from_u128;
+        let mut ids = HnswIdMap::from_ids(&[uuid(1), uuid(2), uuid(3)]);
+        ids.tombstone(1);
+        // removed then back, under a new point id
+        assert_eq!(ids.push(uuid(2)), 3);
+        assert_eq!(ids.push(uuid(4)), 4);
+        assert_eq!(ids.uuid(1), None);
+        assert_eq!(ids.point_id(&uuid(2)), Some(3));
+        let diff = ids.diff(&[uuid(4), uuid(5), uuid(1)]);
+        assert_eq!(diff.added, vec![uuid(5)]);
+        assert_eq!(diff.removed, vec![2, 3]);
+
+        let path = HnswIdMap::path(&dir, "index");
+        assert!(matches!(
+            HnswIdMap::load(&path),
+            Err(HnswMetaError::MissingIds(_))
+        ));
+        ids.save(&path).unwrap();
+        let loaded = HnswIdMap::load(&path).unwrap();
+        assert_eq!(loaded, ids);
+        assert_eq!(loaded.point_id(&uuid(2)), Some(3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_uuid_list_hash() {
+        let ids: Vec<Uuid> = (1..=3).map(Uuid::from_u128).collect();
+        assert_eq!(
+            uuid_list_hash(&ids),
+            "65ab7213670c5a0899db43dca09d58c89ade13b8"
+        );
+        // the order is what maps point ids to uuids
+        assert_ne!(uuid_list_hash(ids.iter().rev()), uuid_list_hash(&ids));
+        assert_eq!(
+            uuid_list_hash(&[]),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+    }
+
+    #[test]
+    fn test_meta_mismatch() {
+        use crate::point_explorer::{PointExplorer, PointExplorerBuilder};
+        let mut explorer: PointExplorer<u8, 4> = PointExplorerBuilder::new().build().unwrap();
+        explorer.extend((1..=3).map(|i| (Uuid::from_u128(i), [i as u8; 4])));
+        let ids = |e: &PointExplorer<u8, 4>| e.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let meta = HnswMeta {
+            params: HnswParams {
+                max_nb_connection: 48,
+                max_elements: 3,
+                max_layer: 16,
+                ef_construction: 600,
+            },
+            distance: distance_name::<DistHamming>(),
+            point_count: 3,
+            uuid_hash: uuid_list_hash(&ids(&explorer)),
+            built_at: Utc::now(),
+            tombstones: 0,
+        };
+        assert_eq!(meta.distance, "DistHamming");
+        meta.validate(&ids(&explorer)).unwrap();
+
+        explorer.insert(Uuid::from_u128(4), [4u8; 4]);
+        let err = meta.validate(&ids(&explorer)).unwrap_err();
+        assert!(matches!(
+            err,
+            HnswMetaError::Mismatch {
+                expected_count: 3,
+                actual_count: 4,
+                ..
+            }
+        ));
+        // same points, another order
+        explorer.remove(&Uuid::from_u128(4));
+        explorer.remove(&Uuid::from_u128(1));
+        explorer.insert(Uuid::from_u128(1), [1u8; 4]);
+        assert!(matches!(
+            meta.validate(&ids(&explorer)),
+            Err(HnswMetaError::Mismatch {
+                actual_count: 3,
+                ..
+            })
+        ));
+    }
+}
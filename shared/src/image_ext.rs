@@ -0,0 +1,141 @@
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, ImageResult};
+use std::io::{BufRead, Cursor, Seek};
+use std::path::Path;
+
+/// Decodes an image, optionally applying its EXIF orientation
+pub fn decode_image<R: BufRead + Seek>(
+    reader: ImageReader<R>,
+    apply_orientation: bool,
+) -> ImageResult<DynamicImage> {
+    let mut decoder = reader.with_guessed_format()?.into_decoder()?;
+    let orientation = match apply_orientation {
+        true => decoder.orientation()?,
+        false => Orientation::NoTransforms,
+    };
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+#[inline]
+pub fn open_image<P: AsRef<Path>>(path: P, apply_orientation: bool) -> ImageResult<DynamicImage> {
+    decode_image(ImageReader::open(path)?, apply_orientation)
+}
+
+/// Format of `header`, the leading bytes of a file, if the image crate can also parse its header
+///
+/// Only the magic and the dimensions are decoded. `truncated` tells that the file goes on past
+/// `header`, a failed parse then only means the header outgrew the window, e.g. a JPEG with a
+/// large EXIF block, and the guessed format stands.
+pub fn sniff_image_format(header: &[u8], truncated: bool) -> Option<ImageFormat> {
+    let format = image::guess_format(header).ok()?;
+    match ImageReader::with_format(Cursor::new(header), format).into_dimensions() {
+        Ok(_) => Some(format),
+        Err(_) if truncated => Some(format),
+        Err(_) => None,
+    }
+}
+
+/// Whether `ext` is one of the extensions of `format`, case-insensitive
+pub fn format_has_ext(format: ImageFormat, ext: &str) -> bool {
+    format
+        .extensions_str()
+        .iter()
+        .any(|known| known.eq_ignore_ascii_case(ext))
+}
+
+/// Inserts a minimal EXIF APP1 segment carrying `orientation` right after the JPEG SOI marker
+///
+/// Mostly useful for fixtures, returns `None` if `jpeg` does not start with SOI.
+pub fn set_jpeg_orientation(jpeg: &[u8], orientation: Orientation) -> Option<Vec<u8>> {
+    let body = jpeg.strip_prefix(&[0xFF, 0xD8])?;
+    let mut tiff = Vec::with_capacity(26);
+    tiff.extend_from_slice(b"II*\0");
+    tiff.extend_from_slice(&8u32.to_le_bytes()); // IFD0 offset
+    tiff.extend_from_slice(&1u16.to_le_bytes()); // entry count
+    tiff.extend_from_slice(&0x0112u16.to_le_bytes()); // Orientation
+    tiff.extend_from_slice(&3u16.to_le_bytes()); // SHORT
+    tiff.extend_from_slice(&1u32.to_le_bytes());
+    tiff.extend_from_slice(&(orientation.to_exif() as u16).to_le_bytes());
+    tiff.extend_from_slice(&0u16.to_le_bytes());
+    tiff.extend_from_slice(&0u32.to_le_bytes()); // next IFD
+    let segment_len = (2 + 6 + tiff.len()) as u16;
+    let mut out = Vec::with_capacity(jpeg.len() + segment_len as usize + 2);
+    out.extend_from_slice(&[0xFF, 0xD8, 0xFF, 0xE1]);
+    out.extend_from_slice(&segment_len.to_be_bytes());
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(&tiff);
+    out.extend_from_slice(body);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{Rgb, RgbImage};
+    use std::io::Cursor;
+
+    fn sample_jpeg() -> Vec<u8> {
+        let img = RgbImage::from_fn(64, 32, |x, y| Rgb([(x * 4) as u8, (y * 8) as u8, 128]));
+        let mut out = Vec::new();
+        JpegEncoder::new_with_quality(&mut out, 95)
+            .encode_image(&img)
+            .unwrap();
+        out
+    }
+
+    fn decode(bytes: &[u8], apply: bool) -> DynamicImage {
+        decode_image(ImageReader::new(Cursor::new(bytes)), apply).unwrap()
+    }
+
+    #[test]
+    fn test_orientation_applied() {
+        let plain = sample_jpeg();
+        let tagged = set_jpeg_orientation(&plain, Orientation::Rotate90).unwrap();
+        let raw = decode(&plain, true);
+        let corrected = decode(&tagged, true);
+        assert_eq!((corrected.width(), corrected.height()), (32, 64));
+        assert_eq!(corrected.to_rgb8(), raw.rotate90().to_rgb8());
+        let ignored = decode(&tagged, false);
+        assert_eq!(ignored.to_rgb8(), raw.to_rgb8());
+    }
+
+    #[test]
+    fn test_sniff_image_format() {
+        let jpeg = sample_jpeg();
+        assert_eq!(sniff_image_format(&jpeg, false), Some(ImageFormat::Jpeg));
+        // a PNG followed by unrelated bytes still parses
+        let mut png = Vec::new();
+        RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png.extend_from_slice(b"PK\x03\x04trailing");
+        assert_eq!(sniff_image_format(&png, false), Some(ImageFormat::Png));
+        // header cut short of the frame by a large APP segment
+        let mut padded = vec![0xFF, 0xD8, 0xFF, 0xE2, 0xFF, 0xFF];
+        padded.extend(std::iter::repeat_n(0u8, 0xFFFD));
+        padded.extend_from_slice(&jpeg[2..]);
+        assert_eq!(
+            sniff_image_format(&padded[..8193], true),
+            Some(ImageFormat::Jpeg)
+        );
+        // a HEIF brand is recognised by infer but not by the image crate
+        assert_eq!(
+            sniff_image_format(b"\0\0\0\x18ftypheic\0\0\0\0mif1heic", false),
+            None
+        );
+        // right magic, broken header
+        let broken = b"\x89PNG\r\n\x1a\nnot an IHDR";
+        assert_eq!(sniff_image_format(broken, false), None);
+        assert_eq!(sniff_image_format(broken, true), Some(ImageFormat::Png));
+        assert!(format_has_ext(ImageFormat::Jpeg, "JPG"));
+        assert!(!format_has_ext(ImageFormat::Png, "zip"));
+    }
+
+    #[test]
+    fn test_set_jpeg_orientation_rejects_non_jpeg() {
+        assert!(set_jpeg_orientation(b"GIF89a", Orientation::Rotate90).is_none());
+    }
+}
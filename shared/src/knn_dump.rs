@@ -0,0 +1,345 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Layout:
+/// `MAGIC | k: u32 | point_count: u64 | record_count: u64 | point_count * 16 uuid bytes | records`
+///
+/// Every record is `query_index: u32 | k * (neighbor_index: u32, distance: f32)`, all little
+/// endian, missing neighbors are padded with [`KNN_DUMP_EMPTY_NEIGHBOR`] / `f32::INFINITY`.
+pub const KNN_DUMP_MAGIC: &[u8; 8] = b"NKKNN001";
+pub const KNN_DUMP_EMPTY_NEIGHBOR: u32 = u32::MAX;
+const HEADER_LEN: u64 = 8 + 4 + 8 + 8;
+const RECORD_COUNT_OFFSET: u64 = 8 + 4 + 8;
+
+#[derive(Debug, Error)]
+pub enum KnnDumpError {
+    #[error("Invalid KNN dump magic")]
+    InvalidMagic,
+    #[error("Records must be written in order: expected query {expected}, got {got}")]
+    OutOfOrder { expected: u32, got: u32 },
+    #[error("Record has {got} neighbors, dump is fixed to k = {k}")]
+    TooManyNeighbors { k: u32, got: usize },
+    #[error("Query index {0} out of range")]
+    IndexOutOfRange(u32),
+    #[error("KNN dump header claims {expected} bytes, file holds {actual}")]
+    Truncated { expected: u64, actual: u64 },
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+pub type KnnDumpResult<T> = Result<T, KnnDumpError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnnRecord {
+    pub query: u32,
+    /// (neighbor index, distance), padding stripped
+    pub neighbors: Vec<(u32, f32)>,
+}
+
+#[inline]
+fn record_len(k: u32) -> u64 {
+    4 + 8 * k as u64
+}
+
+/// Streams fixed-size records, patches the record count on [`KnnDumpWriter::finish`]
+pub struct KnnDumpWriter<W: Write + Seek> {
+    inner: W,
+    k: u32,
+    next_query: u32,
+    buf: Vec<u8>,
+}
+
+impl KnnDumpWriter<BufWriter<File>> {
+    pub fn create<P, I>(path: P, k: u32, uuids: I) -> KnnDumpResult<Self>
+    where
+        P: AsRef<Path>,
+        I: ExactSizeIterator<Item = Uuid>,
+    {
+        Self::new(BufWriter::new(File::create(path)?), k, uuids)
+    }
+}
+
+impl<W: Write + Seek> KnnDumpWriter<W> {
+    pub fn new<I>(mut inner: W, k: u32, uuids: I) -> KnnDumpResult<Self>
+    where
+        I: ExactSizeIterator<Item = Uuid>,
+    {
+        inner.write_all(KNN_DUMP_MAGIC)?;
+        inner.write_all(&k.to_le_bytes())?;
+        inner.write_all(&(uuids.len() as u64).to_le_bytes())?;
+        inner.write_all(&0u64.to_le_bytes())?;
+        for id in uuids {
+            inner.write_all(id.as_bytes())?;
+        }
+        Ok(Self {
+            inner,
+            k,
+            next_query: 0,
+            buf: Vec::with_capacity(record_len(k) as usize),
+        })
+    }
+
+    /// `query` must be exactly the previous one plus one, starting at 0
+    pub fn write_record(&mut self, query: u32, neighbors: &[(u32, f32)]) -> KnnDumpResult<()> {
+        if query != self.next_query {
+            return Err(KnnDumpError::OutOfOrder {
+                expected: self.next_query,
+                got: query,
+            });
+        }
+        if neighbors.len() > self.k as usize {
+            return Err(KnnDumpError::TooManyNeighbors {
+                k: self.k,
+                got: neighbors.len(),
+            });
+        }
+        self.buf.clear();
+        self.buf.extend_from_slice(&query.to_le_bytes());
+        let padding = std::iter::repeat((KNN_DUMP_EMPTY_NEIGHBOR, f32::INFINITY));
+        for (idx, dist) in neighbors
+            .iter()
+            .copied()
+            .chain(padding)
+            .take(self.k as usize)
+        {
+            self.buf.extend_from_slice(&idx.to_le_bytes());
+            self.buf.extend_from_slice(&dist.to_le_bytes());
+        }
+        self.inner.write_all(&self.buf)?;
+        self.next_query += 1;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn records_written(&self) -> u32 {
+        self.next_query
+    }
+
+    pub fn finish(mut self) -> KnnDumpResult<W> {
+        self.inner.seek(SeekFrom::Start(RECORD_COUNT_OFFSET))?;
+        self.inner
+            .write_all(&(self.next_query as u64).to_le_bytes())?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+pub struct KnnDumpReader<R: Read + Seek> {
+    inner: R,
+    k: u32,
+    record_count: u64,
+    uuids: Vec<Uuid>,
+    records_start: u64,
+}
+
+impl KnnDumpReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> KnnDumpResult<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> KnnDumpReader<R> {
+    pub fn new(mut inner: R) -> KnnDumpResult<Self> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        inner.read_exact(&mut header)?;
+        if &header[..8] != KNN_DUMP_MAGIC {
+            return Err(KnnDumpError::InvalidMagic);
+        }
+        let k = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let point_count = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        let record_count = u64::from_le_bytes(header[20..28].try_into().unwrap());
+        // a corrupt header must not size the allocations below
+        let actual = inner.seek(SeekFrom::End(0))?;
+        let expected = point_count
+            .checked_mul(16)
+            .zip(record_count.checked_mul(record_len(k)))
+            .and_then(|(uuids, records)| HEADER_LEN.checked_add(uuids)?.checked_add(records))
+            .unwrap_or(u64::MAX);
+        if expected > actual {
+            return Err(KnnDumpError::Truncated { expected, actual });
+        }
+        inner.seek(SeekFrom::Start(HEADER_LEN))?;
+        let mut uuids = Vec::with_capacity(point_count as usize);
+        let mut raw = [0u8; 16];
+        for _ in 0..point_count {
+            inner.read_exact(&mut raw)?;
+            uuids.push(Uuid::from_bytes(raw));
+        }
+        Ok(Self {
+            inner,
+            k,
+            record_count,
+            uuids,
+            records_start: HEADER_LEN + 16 * point_count,
+        })
+    }
+
+    #[inline]
+    pub fn k(&self) -> u32 {
+        self.k
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.record_count
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.record_count == 0
+    }
+
+    #[inline]
+    pub fn uuid(&self, index: u32) -> Option<&Uuid> {
+        self.uuids.get(index as usize)
+    }
+
+    #[inline]
+    pub fn uuids(&self) -> &[Uuid] {
+        &self.uuids
+    }
+
+    fn read_next(&mut self) -> KnnDumpResult<KnnRecord> {
+        let mut buf = vec![0u8; record_len(self.k) as usize];
+        self.inner.read_exact(&mut buf)?;
+        let query = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        let neighbors = buf[4..]
+            .chunks_exact(8)
+            .map(|pair| {
+                (
+                    u32::from_le_bytes(pair[..4].try_into().unwrap()),
+                    f32::from_le_bytes(pair[4..].try_into().unwrap()),
+                )
+            })
+            .take_while(|&(idx, _)| idx != KNN_DUMP_EMPTY_NEIGHBOR)
+            .collect();
+        Ok(KnnRecord { query, neighbors })
+    }
+
+    /// O(1) seek to the record of `query`
+    pub fn record(&mut self, query: u32) -> KnnDumpResult<KnnRecord> {
+        if query as u64 >= self.record_count {
+            return Err(KnnDumpError::IndexOutOfRange(query));
+        }
+        let offset = self.records_start + query as u64 * record_len(self.k);
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.read_next()
+    }
+
+    /// Sequential scan from the first record
+    pub fn records(&mut self) -> KnnDumpResult<impl Iterator<Item = KnnDumpResult<KnnRecord>>> {
+        self.inner.seek(SeekFrom::Start(self.records_start))?;
+        Ok((0..self.record_count).map(move |_| self.read_next()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sample() -> (Vec<Uuid>, Vec<Vec<(u32, f32)>>) {
+        let uuids = (0..5).map(|i| Uuid::from_u128(i + 100)).collect();
+        let neighbors = vec![
+            vec![(1, 0.1), (2, 0.2), (3, 0.3)],
+            vec![(0, 0.1)],
+            vec![],
+            vec![(4, 0.0), (0, 0.3), (2, 0.5)],
+            vec![(3, 0.0), (1, 0.9)],
+        ];
+        (uuids, neighbors)
+    }
+
+    fn dump() -> Vec<u8> {
+        let (uuids, neighbors) = sample();
+        let mut writer = KnnDumpWriter::new(Cursor::new(Vec::new()), 3, uuids.into_iter()).unwrap();
+        for (q, n) in neighbors.iter().enumerate() {
+            writer.write_record(q as u32, n).unwrap();
+        }
+        assert_eq!(writer.records_written(), 5);
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = dump();
+        let (uuids, neighbors) = sample();
+        assert_eq!(bytes.len() as u64, HEADER_LEN + 16 * 5 + 5 * record_len(3));
+        let mut reader = KnnDumpReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.k(), 3);
+        assert_eq!(reader.len(), 5);
+        assert_eq!(reader.uuids(), uuids.as_slice());
+        let records: Vec<KnnRecord> = reader
+            .records()
+            .unwrap()
+            .collect::<KnnDumpResult<_>>()
+            .unwrap();
+        for (q, record) in records.iter().enumerate() {
+            assert_eq!(record.query, q as u32);
+            assert_eq!(record.neighbors, neighbors[q]);
+        }
+    }
+
+    #[test]
+    fn test_seek() {
+        let (uuids, neighbors) = sample();
+        let mut reader = KnnDumpReader::new(Cursor::new(dump())).unwrap();
+        for q in [3u32, 0, 4, 2, 3] {
+            let record = reader.record(q).unwrap();
+            assert_eq!(record.query, q);
+            assert_eq!(record.neighbors, neighbors[q as usize]);
+        }
+        assert_eq!(reader.uuid(4), Some(&uuids[4]));
+        assert!(matches!(
+            reader.record(5),
+            Err(KnnDumpError::IndexOutOfRange(5))
+        ));
+        // sequential scan still starts at the first record after seeking
+        assert_eq!(reader.records().unwrap().count(), 5);
+    }
+
+    #[test]
+    fn test_writer_rejects_invalid_records() {
+        let mut writer =
+            KnnDumpWriter::new(Cursor::new(Vec::new()), 2, std::iter::empty()).unwrap();
+        assert!(matches!(
+            writer.write_record(1, &[]),
+            Err(KnnDumpError::OutOfOrder {
+                expected: 0,
+                got: 1
+            })
+        ));
+        assert!(matches!(
+            writer.write_record(0, &[(1, 0.0), (2, 0.0), (3, 0.0)]),
+            Err(KnnDumpError::TooManyNeighbors { k: 2, got: 3 })
+        ));
+        assert!(matches!(
+            KnnDumpReader::new(Cursor::new(vec![0u8; 64])),
+            Err(KnnDumpError::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn test_reader_rejects_truncated() {
+        let mut bytes = dump();
+        bytes.pop();
+        assert!(matches!(
+            KnnDumpReader::new(Cursor::new(bytes)),
+            Err(KnnDumpError::Truncated { .. })
+        ));
+        // a point count no file can hold is rejected before allocating for it
+        let mut bytes = dump();
+        bytes[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            KnnDumpReader::new(Cursor::new(bytes)),
+            Err(KnnDumpError::Truncated {
+                expected: u64::MAX,
+                ..
+            })
+        ));
+    }
+}
@@ -1,55 +1,253 @@
+#[cfg(feature = "atomic-write")]
+pub mod atomic_write;
+#[cfg(feature = "cluster")]
+pub mod cluster;
+#[cfg(feature = "cluster-file")]
+pub mod cluster_file;
+#[cfg(feature = "config")]
+pub mod config;
 #[cfg(feature = "cosine-sim")]
 pub mod cosine_sim;
+#[cfg(feature = "distance")]
+pub mod distance;
+#[cfg(feature = "exact-dup")]
+pub mod exact_dup;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
+#[cfg(feature = "hamming")]
+pub mod hamming;
 #[cfg(feature = "hnsw")]
 pub mod hnsw;
+#[cfg(feature = "image-ext")]
+pub mod image_ext;
+#[cfg(feature = "knn-dump")]
+pub mod knn_dump;
+#[cfg(feature = "tracings")]
+pub mod logging;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "migrations")]
+pub mod migrations;
 #[cfg(feature = "neko-uuid")]
 pub mod neko_uuid;
+#[cfg(feature = "qdrant-ext")]
+pub mod op_ledger;
 #[cfg(any(feature = "opendal-data-compat", feature = "opendal-ext"))]
 pub mod opendal;
+#[cfg(feature = "optics")]
+pub mod optics;
+#[cfg(feature = "pipeline")]
+pub mod pipeline;
 #[cfg(feature = "point-explorer")]
 pub mod point_explorer;
+#[cfg(feature = "prefetch")]
+pub mod prefetch;
 #[cfg(feature = "qdrant-ext")]
 pub mod qdrant;
+#[cfg(feature = "quant")]
+pub mod quant;
+#[cfg(feature = "report")]
+pub mod report;
+#[cfg(feature = "stage-lock")]
+pub mod stage_lock;
+#[cfg(feature = "stall-detect")]
+pub mod stall;
 #[cfg(feature = "shared-structure")]
 pub mod structure;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[cfg(feature = "text-sanitize")]
+pub mod text;
+#[cfg(feature = "uuid-set")]
+pub mod uuid_set;
+#[cfg(feature = "shared-structure")]
+pub mod validation;
 
 #[cfg(feature = "pyo3")]
 mod pyo3 {
     use crate::structure::{NekoPoint, NekoPointText};
     use pyo3::prelude::*;
-    use pyo3::py_run;
-
-    macro_rules! add_submodule {
-        ($py:expr, $m:expr, $name:literal, $func:path) => {
-            let submodule = PyModule::new($py, $name)?;
-            py_run!(
-                $py,
-                submodule,
-                concat!(
-                    "import sys\n",
-                    "sys.modules['shared.",
-                    $name,
-                    "'] = submodule"
-                )
-            );
-            $func($py, &submodule)?;
-            $m.add_submodule(&submodule)?;
+    use pyo3::types::{PyDict, PyList};
+
+    type SubmoduleInit = fn(Python, &Bound<'_, PyModule>) -> PyResult<()>;
+
+    macro_rules! features {
+        ($($name:literal),* $(,)?) => {
+            &[$(($name, cfg!(feature = $name))),*]
         };
     }
 
-    #[pymodule]
-    fn shared(py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
-        #[cfg(feature = "point-explorer-pyo3")]
-        add_submodule!(
-            py,
-            m,
+    /// Every feature of Cargo.toml and whether this build has it
+    const FEATURES: &[(&str, bool)] = features!(
+        "shared-structure",
+        "tracings",
+        "otel",
+        "neko-uuid",
+        "cosine-sim",
+        "hamming",
+        "opendal-data-compat",
+        "opendal-ext",
+        "qdrant-ext",
+        "qdrant-snapshot",
+        "point-explorer",
+        "shared-pyo3",
+        "point-explorer-pyo3",
+        "atomic-write",
+        "metrics",
+        "migrations",
+        "cluster",
+        "cluster-file",
+        "distance",
+        "exact-dup",
+        "uuid-set",
+        "knn-dump",
+        "image-ext",
+        "optics",
+        "report",
+        "stall-detect",
+        "stage-lock",
+        "prefetch",
+        "hnsw",
+        "hnsw-pyo3",
+        "fixtures",
+        "quant",
+        "feature-matrix",
+        "pipeline",
+        "text-sanitize",
+        "config",
+        "test-util",
+    );
+
+    /// `shared.<name>` submodules, with the feature building them and their init when built
+    const SUBMODULES: &[(&str, &str, Option<SubmoduleInit>)] = &[
+        (
             "point_explorer",
-            crate::point_explorer::pyo3::point_explorer
-        );
-        #[cfg(feature = "hnsw-pyo3")]
-        add_submodule!(py, m, "hnsw", crate::hnsw::pyo3::hnsw);
+            "point-explorer-pyo3",
+            #[cfg(feature = "point-explorer-pyo3")]
+            Some(crate::point_explorer::pyo3::point_explorer),
+            #[cfg(not(feature = "point-explorer-pyo3"))]
+            None,
+        ),
+        (
+            "hnsw",
+            "hnsw-pyo3",
+            #[cfg(feature = "hnsw-pyo3")]
+            Some(crate::hnsw::pyo3::hnsw),
+            #[cfg(not(feature = "hnsw-pyo3"))]
+            None,
+        ),
+    ];
+
+    /// Adds `shared.<name>` as an attribute and to `sys.modules`, so `import shared.<name>` and
+    /// `from shared.<name> import ...` both work
+    fn add_submodule(
+        py: Python,
+        parent: &Bound<'_, PyModule>,
+        name: &str,
+        init: SubmoduleInit,
+    ) -> PyResult<()> {
+        let submodule = PyModule::new(py, name)?;
+        init(py, &submodule)?;
+        parent.add_submodule(&submodule)?;
+        py.import("sys")?
+            .getattr("modules")?
+            .set_item(format!("shared.{}", name), &submodule)?;
+        Ok(())
+    }
+
+    /// Version, git hash, features and compiled explorer dimensions of this build
+    ///
+    /// The git hash is taken from `GIT_HASH` at build time, e.g.
+    /// `GIT_HASH=$(git rev-parse HEAD) maturin build`.
+    #[pyfunction]
+    fn build_info(py: Python<'_>) -> PyResult<Bound<'_, PyDict>> {
+        let info = PyDict::new(py);
+        info.set_item("version", env!("CARGO_PKG_VERSION"))?;
+        info.set_item("git_hash", option_env!("GIT_HASH"))?;
+        let enabled: Vec<&str> = FEATURES
+            .iter()
+            .filter(|(_, on)| *on)
+            .map(|(name, _)| *name)
+            .collect();
+        info.set_item("features", enabled)?;
+        let submodules = PyList::empty(py);
+        let missing = PyDict::new(py);
+        for (name, feature, init) in SUBMODULES {
+            match init {
+                Some(_) => submodules.append(name)?,
+                None => missing.set_item(name, feature)?,
+            }
+        }
+        info.set_item("submodules", submodules)?;
+        info.set_item("missing_submodules", missing)?;
+        let dims = PyDict::new(py);
+        #[cfg(feature = "point-explorer-pyo3")]
+        for (class, dtype, dim) in crate::point_explorer::pyo3::FIXED_DIMS {
+            dims.set_item(class, (dtype, dim))?;
+        }
+        info.set_item("point_explorer_dims", dims)?;
+        Ok(info)
+    }
+
+    fn init(py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
+        // the others are listed by build_info
+        for (name, _, init) in SUBMODULES {
+            if let Some(init) = init {
+                add_submodule(py, m, name, *init)?;
+            }
+        }
+        m.add_function(wrap_pyfunction!(build_info, m)?)?;
         m.add_class::<NekoPoint>()?;
         m.add_class::<NekoPointText>()?;
         Ok(())
     }
+
+    #[pymodule]
+    fn shared(py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
+        init(py, m)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::ffi::CString;
+
+        const CHECK_BUILD_INFO: &str = r#"
+import importlib
+import shared
+
+info = shared.build_info()
+assert set(info) == {
+    "version", "git_hash", "features", "submodules", "missing_submodules", "point_explorer_dims"
+}, info
+assert info["version"] == version
+assert "shared-structure" in info["features"]
+for name in info["submodules"]:
+    assert importlib.import_module("shared." + name) is getattr(shared, name)
+for name, feature in info["missing_submodules"].items():
+    assert feature not in info["features"]
+    assert not hasattr(shared, name)
+if "point-explorer-pyo3" in info["features"]:
+    from shared.point_explorer import PyPointExplorerU8D32
+    assert info["point_explorer_dims"]["PyPointExplorerU8D32"] == ("u8", 32)
+    assert len(PyPointExplorerU8D32()) == 0
+"#;
+
+        #[test]
+        fn test_build_info() {
+            pyo3::prepare_freethreaded_python();
+            Python::with_gil(|py| {
+                let m = PyModule::new(py, "shared").unwrap();
+                init(py, &m).unwrap();
+                let sys_modules = py.import("sys").unwrap().getattr("modules").unwrap();
+                sys_modules.set_item("shared", &m).unwrap();
+                let locals = PyDict::new(py);
+                locals
+                    .set_item("version", env!("CARGO_PKG_VERSION"))
+                    .unwrap();
+                let code = CString::new(CHECK_BUILD_INFO).unwrap();
+                py.run(&code, None, Some(&locals)).unwrap();
+            });
+        }
+    }
 }
@@ -0,0 +1,293 @@
+//! Tracing setup shared by the stage binaries: stdout, an hourly rolling file under `logs/` and,
+//! with the `otel` feature, an OTLP exporter
+//!
+//! `$NEKO_LOG` takes [`EnvFilter`] directives and applies to every sink, e.g.
+//! `info,stage9::gif_worker=debug`. Without it stdout and the file fall back to
+//! `$STDOUT_LOG_LEVEL` and `$FILE_LOG_LEVEL`, then to the stage's defaults.
+//!
+//! The OTLP exporter is configured by the standard `OTEL_EXPORTER_OTLP_*` variables and only
+//! started when an endpoint is set, the stage name is its `service.name`.
+//!
+//! # Span names
+//!
+//! Spans are named `<component>.<operation>`, the component being the stage (`stage9.refine`)
+//! or the shared client doing the work (`qdrant.write`). Spans about points carry their id as
+//! `uuid`, one point per span where the work allows it. Phases of a stage are spans without
+//! a `uuid`, batches carry their cluster range as `clusters`.
+
+use std::path::PathBuf;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::prelude::*;
+
+/// Filter directives for every sink, overriding the per-sink levels
+pub const NEKO_LOG_ENV: &str = "NEKO_LOG";
+pub const STDOUT_LEVEL_ENV: &str = "STDOUT_LOG_LEVEL";
+pub const FILE_LEVEL_ENV: &str = "FILE_LOG_LEVEL";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoggingError {
+    #[error("Invalid log filter {directives:?} in ${var}: {source}")]
+    Filter {
+        var: &'static str,
+        directives: String,
+        source: tracing_subscriber::filter::ParseError,
+    },
+    #[error(transparent)]
+    Init(#[from] tracing_subscriber::util::TryInitError),
+    #[cfg(feature = "otel")]
+    #[error("OTLP exporter: {0}")]
+    Otel(String),
+}
+
+/// The filter of a sink, `neko_log` if set, else `level` if set, else `default`
+///
+/// Invalid directives are an error rather than silently dropped like [`EnvFilter::new`] does.
+pub fn sink_filter(
+    neko_log: Option<&str>,
+    level: Option<(&'static str, &str)>,
+    default: &str,
+) -> Result<EnvFilter, LoggingError> {
+    let (var, directives) = match (neko_log, level) {
+        (Some(directives), _) => (NEKO_LOG_ENV, directives),
+        (None, Some((var, level))) => (var, level),
+        (None, None) => ("", default),
+    };
+    EnvFilter::try_new(directives).map_err(|source| LoggingError::Filter {
+        var,
+        directives: directives.to_owned(),
+        source,
+    })
+}
+
+/// Builds the subscriber of a stage
+///
+/// ```ignore
+/// let _logging = Logging::new("stage9").init()?;
+/// ```
+pub struct Logging {
+    stage: String,
+    dir: PathBuf,
+    stdout_level: String,
+    file_level: String,
+}
+
+impl Logging {
+    /// Logs to `logs/<stage>.log`, `info` on both sinks
+    pub fn new(stage: &str) -> Self {
+        Self {
+            stage: stage.to_owned(),
+            dir: PathBuf::from("logs"),
+            stdout_level: "info".to_owned(),
+            file_level: "info".to_owned(),
+        }
+    }
+
+    pub fn dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Stdout filter when neither `$NEKO_LOG` nor `$STDOUT_LOG_LEVEL` is set
+    pub fn stdout_level(mut self, level: &str) -> Self {
+        self.stdout_level = level.to_owned();
+        self
+    }
+
+    /// File filter when neither `$NEKO_LOG` nor `$FILE_LOG_LEVEL` is set
+    pub fn file_level(mut self, level: &str) -> Self {
+        self.file_level = level.to_owned();
+        self
+    }
+
+    fn filter(&self, var: &'static str, default: &str) -> Result<EnvFilter, LoggingError> {
+        let neko_log = std::env::var(NEKO_LOG_ENV).ok();
+        let level = std::env::var(var).ok();
+        sink_filter(
+            neko_log.as_deref(),
+            level.as_deref().map(|level| (var, level)),
+            default,
+        )
+    }
+
+    /// Installs the subscriber globally, keep the guard until the end of `main` so the exported
+    /// spans are flushed
+    pub fn init(self) -> Result<LoggingGuard, LoggingError> {
+        let stdout = tracing_subscriber::fmt::layer()
+            .with_filter(self.filter(STDOUT_LEVEL_ENV, &self.stdout_level)?);
+        let file_appender =
+            RollingFileAppender::new(Rotation::HOURLY, &self.dir, format!("{}.log", self.stage));
+        let file = tracing_subscriber::fmt::layer()
+            .with_writer(file_appender)
+            .with_filter(self.filter(FILE_LEVEL_ENV, &self.file_level)?);
+        let registry = tracing_subscriber::registry().with(stdout).with(file);
+        #[cfg(feature = "otel")]
+        {
+            let (layer, provider) = match otel::enabled() {
+                true => {
+                    let (layer, provider) = otel::layer(&self.stage)?;
+                    // no level variable of its own, $NEKO_LOG or the stdout default
+                    let filter = self.filter(NEKO_LOG_ENV, &self.stdout_level)?;
+                    (Some(layer.with_filter(filter)), Some(provider))
+                }
+                false => (None, None),
+            };
+            registry.with(layer).try_init()?;
+            if provider.is_some() {
+                tracing::info!("Exporting spans of {} over OTLP", self.stage);
+            }
+            Ok(LoggingGuard { provider })
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            registry.try_init()?;
+            Ok(LoggingGuard {})
+        }
+    }
+}
+
+/// Flushes and shuts the OTLP exporter down when dropped
+#[must_use = "spans still buffered are lost once the guard is dropped"]
+pub struct LoggingGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for LoggingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush the OTLP exporter: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use super::LoggingError;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+    use tracing::Subscriber;
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// An endpoint is configured and the SDK not disabled
+    pub fn enabled() -> bool {
+        let set = |var: &str| std::env::var(var).is_ok_and(|v| !v.is_empty());
+        let disabled =
+            std::env::var("OTEL_SDK_DISABLED").is_ok_and(|v| v.eq_ignore_ascii_case("true"));
+        !disabled
+            && (set("OTEL_EXPORTER_OTLP_ENDPOINT") || set("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"))
+    }
+
+    pub fn layer<S>(
+        stage: &str,
+    ) -> Result<(OpenTelemetryLayer<S, Tracer>, SdkTracerProvider), LoggingError>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+            .map_err(|e| LoggingError::Otel(e.to_string()))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(stage.to_owned())
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer(stage.to_owned());
+        Ok((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::span::{Attributes, Id};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::layer::Context;
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// Span names with their fields, and event targets, as they reach the subscriber
+    #[derive(Clone, Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<String>>>,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl<S> Layer<S> for Recorder
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+            let fields: Vec<&str> = attrs.fields().iter().map(|f| f.name()).collect();
+            let span = format!("{}{:?}", attrs.metadata().name(), fields);
+            self.spans.lock().unwrap().push(span);
+        }
+
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            let target = event.metadata().target().to_owned();
+            self.events.lock().unwrap().push(target);
+        }
+    }
+
+    #[test]
+    fn test_sink_filter() {
+        let directives = "info,stage9::gif_worker=debug";
+        let filter = sink_filter(Some(directives), Some((STDOUT_LEVEL_ENV, "warn")), "info");
+        // the most specific directive first
+        assert_eq!(filter.unwrap().to_string(), "stage9::gif_worker=debug,info");
+        let filter = sink_filter(None, Some((STDOUT_LEVEL_ENV, "warn")), "info");
+        assert_eq!(filter.unwrap().to_string(), "warn");
+        assert_eq!(
+            sink_filter(None, None, "debug").unwrap().to_string(),
+            "debug"
+        );
+        let err = sink_filter(Some("info,stage9=loud"), None, "info").unwrap_err();
+        assert!(matches!(
+            err,
+            LoggingError::Filter {
+                var: NEKO_LOG_ENV,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_module_filter_and_spans() {
+        let recorder = Recorder::default();
+        let filter = sink_filter(Some("info,stage9::gif_worker=debug"), None, "info").unwrap();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone().with_filter(filter));
+        tracing::subscriber::with_default(subscriber, || {
+            let uuid = uuid::Uuid::from_u128(1);
+            let span = tracing::info_span!("stage9.refine", clusters = 3);
+            let _entered = span.enter();
+            tracing::info_span!("qdrant.write", uuid = %uuid, points = 1).in_scope(|| {
+                tracing::debug!(target: "stage9::gif_worker", "kept");
+                tracing::debug!(target: "stage9::clip_worker", "dropped");
+                tracing::info!(target: "stage9::clip_worker", "kept");
+            });
+            // below the info default
+            let _ = tracing::debug_span!("stage9.hidden");
+        });
+        assert_eq!(
+            *recorder.spans.lock().unwrap(),
+            vec![
+                "stage9.refine[\"clusters\"]".to_owned(),
+                "qdrant.write[\"uuid\", \"points\"]".to_owned(),
+            ]
+        );
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec!["stage9::gif_worker", "stage9::clip_worker"]
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+/// Latency buckets in seconds, same as the Prometheus client defaults
+pub const DEFAULT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// Per bucket, not cumulative, the last one is `+Inf`
+    buckets: Vec<AtomicU64>,
+    sum_bits: AtomicU64,
+}
+
+impl Histogram {
+    /// `bounds` are the upper bounds of the finite buckets, sorted and deduplicated here
+    pub fn new(bounds: &[f64]) -> Self {
+        let mut bounds: Vec<f64> = bounds.iter().copied().filter(|b| b.is_finite()).collect();
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+        let buckets = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            bounds,
+            buckets,
+            sum_bits: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let idx = self.bounds.partition_point(|&b| b < value);
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum_bits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    /// Observes the seconds elapsed since `start`
+    #[inline]
+    pub fn observe_since(&self, start: Instant) {
+        self.observe(start.elapsed().as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum_bits.load(Ordering::Relaxed))
+    }
+
+    /// Cumulative count per upper bound as Prometheus reports them, `+Inf` last
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds
+            .iter()
+            .copied()
+            .chain([f64::INFINITY])
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect()
+    }
+}
+
+/// Metric name and its labels sorted by label name
+type MetricKey = (String, Vec<(String, String)>);
+
+fn metric_key(name: &str, labels: &[(&str, &str)]) -> MetricKey {
+    let mut labels: Vec<(String, String)> = labels
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    labels.sort();
+    (name.to_owned(), labels)
+}
+
+fn write_labels(out: &mut String, labels: &[(String, String)], le: Option<f64>) {
+    let le = le.map(|b| match b.is_infinite() {
+        true => "+Inf".to_owned(),
+        false => b.to_string(),
+    });
+    let pairs = labels
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .chain(le.as_deref().map(|le| ("le", le)));
+    let mut first = true;
+    for (k, v) in pairs {
+        out.push(if first { '{' } else { ',' });
+        first = false;
+        let v = v
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(out, "{}=\"{}\"", k, v);
+    }
+    if !first {
+        out.push('}');
+    }
+}
+
+/// Counters and histograms registered by name and labels
+///
+/// Asking twice for the same name and labels returns the same metric, so call sites can look
+/// metrics up on every use instead of threading handles around.
+#[derive(Debug, Default)]
+pub struct Registry {
+    counters: Mutex<BTreeMap<MetricKey, Arc<Counter>>>,
+    histograms: Mutex<BTreeMap<MetricKey, Arc<Histogram>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The process-wide registry behind [`counter`], [`histogram`] and [`dump_prometheus`]
+    pub fn global() -> &'static Registry {
+        static GLOBAL: OnceLock<Registry> = OnceLock::new();
+        GLOBAL.get_or_init(Registry::new)
+    }
+
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> Arc<Counter> {
+        let mut counters = self.counters.lock().unwrap();
+        counters
+            .entry(metric_key(name, labels))
+            .or_default()
+            .clone()
+    }
+
+    /// Histogram with [`DEFAULT_BUCKETS`]
+    pub fn histogram(&self, name: &str, labels: &[(&str, &str)]) -> Arc<Histogram> {
+        self.histogram_with_buckets(name, labels, &DEFAULT_BUCKETS)
+    }
+
+    /// `bounds` only apply when the histogram is first registered
+    pub fn histogram_with_buckets(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        bounds: &[f64],
+    ) -> Arc<Histogram> {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms
+            .entry(metric_key(name, labels))
+            .or_insert_with(|| Arc::new(Histogram::new(bounds)))
+            .clone()
+    }
+
+    /// Prometheus text exposition format, metrics sorted by name
+    pub fn dump_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut last_name = None;
+        for ((name, labels), counter) in self.counters.lock().unwrap().iter() {
+            if last_name != Some(name) {
+                let _ = writeln!(out, "# TYPE {} counter", name);
+                last_name = Some(name);
+            }
+            out.push_str(name);
+            write_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", counter.get());
+        }
+        let mut last_name = None;
+        for ((name, labels), histogram) in self.histograms.lock().unwrap().iter() {
+            if last_name != Some(name) {
+                let _ = writeln!(out, "# TYPE {} histogram", name);
+                last_name = Some(name);
+            }
+            for (bound, count) in histogram.buckets() {
+                let _ = write!(out, "{}_bucket", name);
+                write_labels(&mut out, labels, Some(bound));
+                let _ = writeln!(out, " {}", count);
+            }
+            let _ = write!(out, "{}_sum", name);
+            write_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", histogram.sum());
+            let _ = write!(out, "{}_count", name);
+            write_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", histogram.count());
+        }
+        out
+    }
+}
+
+#[inline]
+pub fn counter(name: &str, labels: &[(&str, &str)]) -> Arc<Counter> {
+    Registry::global().counter(name, labels)
+}
+
+#[inline]
+pub fn histogram(name: &str, labels: &[(&str, &str)]) -> Arc<Histogram> {
+    Registry::global().histogram(name, labels)
+}
+
+#[inline]
+pub fn dump_prometheus() -> String {
+    Registry::global().dump_prometheus()
+}
+
+/// Writes the global registry, meant for stage exit when nothing scrapes the process
+pub fn dump_prometheus_to<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    crate::atomic_write::atomic_write(path, dump_prometheus())
+}
+
+/// Awaits a remote call, counting it in `{prefix}_requests_total`, its failures in
+/// `{prefix}_errors_total` and its latency in `{prefix}_request_seconds`, all labeled by `op`
+pub async fn observe<T, E, F>(prefix: &str, op: &str, fut: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let start = Instant::now();
+    let res = fut.await;
+    let labels = [("op", op)];
+    histogram(&format!("{}_request_seconds", prefix), &labels).observe_since(start);
+    counter(&format!("{}_requests_total", prefix), &labels).inc();
+    if res.is_err() {
+        counter(&format!("{}_errors_total", prefix), &labels).inc();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter() {
+        let registry = Registry::new();
+        registry
+            .counter("s3_requests_total", &[("op", "read")])
+            .inc();
+        registry
+            .counter("s3_requests_total", &[("op", "read")])
+            .add(2);
+        registry
+            .counter("s3_requests_total", &[("op", "write")])
+            .inc();
+        assert_eq!(
+            registry
+                .counter("s3_requests_total", &[("op", "read")])
+                .get(),
+            3
+        );
+        assert_eq!(
+            registry
+                .counter("s3_requests_total", &[("op", "write")])
+                .get(),
+            1
+        );
+        // label order does not matter
+        let a = registry.counter("x", &[("a", "1"), ("b", "2")]);
+        let b = registry.counter("x", &[("b", "2"), ("a", "1")]);
+        assert!(Arc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let registry = Registry::new();
+        let h = registry.histogram_with_buckets("clip_batch_seconds", &[], &[1.0, 0.1, 0.5]);
+        for v in [0.05, 0.1, 0.3, 0.7, 2.0, 3.0] {
+            h.observe(v);
+        }
+        // upper bounds are inclusive
+        assert_eq!(
+            h.buckets(),
+            vec![(0.1, 2), (0.5, 3), (1.0, 4), (f64::INFINITY, 6)]
+        );
+        assert_eq!(h.count(), 6);
+        assert!((h.sum() - 6.15).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_concurrent_increments() {
+        let registry = Registry::new();
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        registry.counter("n", &[]).inc();
+                        registry.histogram("h", &[]).observe(0.2);
+                    }
+                });
+            }
+        });
+        assert_eq!(registry.counter("n", &[]).get(), 8000);
+        assert_eq!(registry.histogram("h", &[]).count(), 8000);
+    }
+
+    #[tokio::test]
+    async fn test_observe() {
+        let ok: Result<u8, &str> = observe("test_observe", "get", async { Ok(1) }).await;
+        assert_eq!(ok, Ok(1));
+        let err: Result<u8, &str> = observe("test_observe", "get", async { Err("boom") }).await;
+        assert_eq!(err, Err("boom"));
+        let labels = [("op", "get")];
+        assert_eq!(counter("test_observe_requests_total", &labels).get(), 2);
+        assert_eq!(counter("test_observe_errors_total", &labels).get(), 1);
+        assert_eq!(
+            histogram("test_observe_request_seconds", &labels).count(),
+            2
+        );
+        assert!(dump_prometheus().contains("test_observe_errors_total{op=\"get\"} 1"));
+    }
+
+    #[test]
+    fn test_dump_prometheus() {
+        let registry = Registry::new();
+        registry
+            .counter("gif_decode_failures_total", &[("reason", "io \"x\"")])
+            .add(2);
+        registry.counter("up", &[]).inc();
+        let h = registry.histogram_with_buckets("qdrant_request_seconds", &[("op", "get")], &[0.5]);
+        h.observe(0.25);
+        h.observe(1.0);
+        let expected = "\
+# TYPE gif_decode_failures_total counter
+gif_decode_failures_total{reason=\"io \\\"x\\\"\"} 2
+# TYPE up counter
+up 1
+# TYPE qdrant_request_seconds histogram
+qdrant_request_seconds_bucket{op=\"get\",le=\"0.5\"} 1
+qdrant_request_seconds_bucket{op=\"get\",le=\"+Inf\"} 2
+qdrant_request_seconds_sum{op=\"get\"} 1.25
+qdrant_request_seconds_count{op=\"get\"} 2
+";
+        assert_eq!(registry.dump_prometheus(), expected);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::structure::{NEKO_POINT_SCHEMA_VERSION, NekoPoint, key_num_id};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Layout: `MAGIC | schema_version: u32 | bincode (standard config) HashMap<Uuid, NekoPoint>`
+///
+/// Maps written before versioning have no header and are read as version 0.
+pub const NEKO_POINTS_MAGIC: &[u8; 8] = b"NKPOINTS";
+
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    #[error("Schema version {found} is newer than the supported {supported}, rebuild this tool")]
+    UnsupportedVersion { found: u32, supported: u32 },
+    #[error(transparent)]
+    DecodeError(#[from] bincode::error::DecodeError),
+    #[error(transparent)]
+    EncodeError(#[from] bincode::error::EncodeError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+pub type MigrationResult<T> = Result<T, MigrationError>;
+
+/// Representations as they were stored before `schema_version` 1
+pub mod v0 {
+    use crate::structure::NekoPointText;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct NekoPoint {
+        pub id: Uuid,
+        pub height: usize,
+        /// The width, misnamed
+        pub weight: usize,
+        pub size: Option<usize>,
+        pub categories: Option<Vec<String>>,
+        pub text_info: Option<NekoPointText>,
+    }
+}
+
+/// Representations as they were stored with `schema_version` 1
+pub mod v1 {
+    use crate::structure::NekoPointText;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct NekoPoint {
+        pub id: Uuid,
+        pub height: usize,
+        pub width: usize,
+        pub size: Option<usize>,
+        pub categories: Option<Vec<String>>,
+        pub text_info: Option<NekoPointText>,
+    }
+}
+
+/// Representations as they were stored with `schema_version` 2
+pub mod v2 {
+    use crate::structure::NekoPointText;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct NekoPoint {
+        pub id: Uuid,
+        pub height: usize,
+        pub width: usize,
+        pub size: Option<usize>,
+        pub categories: Option<Vec<String>>,
+        pub text_info: Option<NekoPointText>,
+        pub qdrant_num_id: Option<u64>,
+    }
+}
+
+/// v0 -> v1: `weight` becomes `width`, `size` stays unknown until stage9 fills it from S3
+pub fn migrate_v0(point: v0::NekoPoint) -> NekoPoint {
+    migrate_v1(v1::NekoPoint {
+        id: point.id,
+        height: point.height,
+        width: point.weight,
+        size: point.size,
+        categories: point.categories,
+        text_info: point.text_info,
+    })
+}
+
+/// v1 -> v2: `qdrant_num_id` is recovered from the key stage2 derived from numeric ids
+pub fn migrate_v1(point: v1::NekoPoint) -> NekoPoint {
+    migrate_v2(v2::NekoPoint {
+        qdrant_num_id: key_num_id(&point.id),
+        id: point.id,
+        height: point.height,
+        width: point.width,
+        size: point.size,
+        categories: point.categories,
+        text_info: point.text_info,
+    })
+}
+
+/// v2 -> v3: no `extra` payload was fetched
+pub fn migrate_v2(point: v2::NekoPoint) -> NekoPoint {
+    NekoPoint {
+        id: point.id,
+        height: point.height,
+        width: point.width,
+        size: point.size,
+        categories: point.categories,
+        text_info: point.text_info,
+        qdrant_num_id: point.qdrant_num_id,
+        extra: None,
+    }
+}
+
+/// Schema version of a points map, the header is consumed when there is one
+pub fn read_schema_version<R: BufRead>(reader: &mut R) -> MigrationResult<u32> {
+    if !reader.fill_buf()?.starts_with(NEKO_POINTS_MAGIC) {
+        return Ok(0);
+    }
+    reader.consume(NEKO_POINTS_MAGIC.len());
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    Ok(u32::from_le_bytes(version))
+}
+
+/// Decodes a points map of any known version, upgraded to the current one
+pub fn read_neko_points<R: BufRead>(reader: &mut R) -> MigrationResult<HashMap<Uuid, NekoPoint>> {
+    let config = bincode::config::standard();
+    match read_schema_version(reader)? {
+        0 => {
+            let points: HashMap<Uuid, v0::NekoPoint> =
+                bincode::serde::decode_from_std_read(reader, config)?;
+            Ok(points
+                .into_iter()
+                .map(|(id, point)| (id, migrate_v0(point)))
+                .collect())
+        }
+        1 => {
+            let points: HashMap<Uuid, v1::NekoPoint> =
+                bincode::serde::decode_from_std_read(reader, config)?;
+            Ok(points
+                .into_iter()
+                .map(|(id, point)| (id, migrate_v1(point)))
+                .collect())
+        }
+        2 => {
+            let points: HashMap<Uuid, v2::NekoPoint> =
+                bincode::serde::decode_from_std_read(reader, config)?;
+            Ok(points
+                .into_iter()
+                .map(|(id, point)| (id, migrate_v2(point)))
+                .collect())
+        }
+        NEKO_POINT_SCHEMA_VERSION => Ok(bincode::serde::decode_from_std_read(reader, config)?),
+        found => Err(MigrationError::UnsupportedVersion {
+            found,
+            supported: NEKO_POINT_SCHEMA_VERSION,
+        }),
+    }
+}
+
+/// Loads `points_map.bin` as written by stage2, whichever version wrote it
+pub fn load_neko_points<P: AsRef<Path>>(path: P) -> MigrationResult<HashMap<Uuid, NekoPoint>> {
+    let bytes = std::fs::read(path)?;
+    read_neko_points(&mut bytes.as_slice())
+}
+
+/// Always writes the current version
+pub fn write_neko_points<W: Write>(
+    writer: &mut W,
+    points: &HashMap<Uuid, NekoPoint>,
+) -> MigrationResult<()> {
+    writer.write_all(NEKO_POINTS_MAGIC)?;
+    writer.write_all(&NEKO_POINT_SCHEMA_VERSION.to_le_bytes())?;
+    bincode::serde::encode_into_std_write(points, writer, bincode::config::standard())?;
+    Ok(())
+}
+
+pub fn encode_neko_points(points: &HashMap<Uuid, NekoPoint>) -> MigrationResult<Vec<u8>> {
+    let mut out = Vec::new();
+    write_neko_points(&mut out, points)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::NekoPointText;
+    use serde::Serialize;
+
+    /// Field for field what stage2 serialized before versioning
+    #[derive(Serialize)]
+    struct LegacyNekoPoint {
+        id: Uuid,
+        height: usize,
+        weight: usize,
+        size: Option<usize>,
+        categories: Option<Vec<String>>,
+        text_info: Option<NekoPointText>,
+    }
+
+    fn legacy_fixture() -> (Uuid, Uuid, Vec<u8>) {
+        let a = Uuid::from_u128(1);
+        let b = Uuid::from_u128(2);
+        let legacy = HashMap::from([
+            (
+                a,
+                LegacyNekoPoint {
+                    id: a,
+                    height: 1080,
+                    weight: 1920,
+                    size: None,
+                    categories: Some(vec!["cat".to_string()]),
+                    text_info: Some(NekoPointText {
+                        text: "nya".to_string(),
+                        text_vector: vec![0.5, -0.5],
+                    }),
+                },
+            ),
+            (
+                b,
+                LegacyNekoPoint {
+                    id: b,
+                    height: 10,
+                    weight: 20,
+                    size: Some(300),
+                    categories: None,
+                    text_info: None,
+                },
+            ),
+        ]);
+        let bytes = bincode::serde::encode_to_vec(&legacy, bincode::config::standard()).unwrap();
+        (a, b, bytes)
+    }
+
+    #[test]
+    fn test_v0_migrated() {
+        let (a, b, bytes) = legacy_fixture();
+        assert_eq!(read_schema_version(&mut bytes.as_slice()).unwrap(), 0);
+        let points = read_neko_points(&mut bytes.as_slice()).unwrap();
+        assert_eq!(points.len(), 2);
+        let pa = &points[&a];
+        assert_eq!((pa.id, pa.height, pa.width, pa.size), (a, 1080, 1920, None));
+        assert_eq!(pa.categories.as_deref(), Some(&["cat".to_string()][..]));
+        let text = pa.text_info.as_ref().unwrap();
+        assert_eq!(
+            (text.text.as_str(), &text.text_vector[..]),
+            ("nya", &[0.5, -0.5][..])
+        );
+        let pb = &points[&b];
+        assert_eq!((pb.height, pb.width, pb.size), (10, 20, Some(300)));
+        assert!(pb.categories.is_none() && pb.text_info.is_none());
+    }
+
+    #[test]
+    fn test_current_round_trip() {
+        let (a, _, bytes) = legacy_fixture();
+        let mut points = read_neko_points(&mut bytes.as_slice()).unwrap();
+        points.get_mut(&a).unwrap().extra = Some(r#"{"exif":{"iso":100}}"#.to_string());
+        let encoded = encode_neko_points(&points).unwrap();
+        assert!(encoded.starts_with(NEKO_POINTS_MAGIC));
+        assert_eq!(
+            read_schema_version(&mut encoded.as_slice()).unwrap(),
+            NEKO_POINT_SCHEMA_VERSION
+        );
+        let decoded = read_neko_points(&mut encoded.as_slice()).unwrap();
+        assert_eq!(decoded.len(), points.len());
+        for (id, point) in points.iter() {
+            let other = &decoded[id];
+            assert_eq!(
+                (other.id, other.height, other.width, other.size),
+                (point.id, point.height, point.width, point.size)
+            );
+            assert_eq!(other.categories, point.categories);
+            assert_eq!(other.qdrant_num_id, point.qdrant_num_id);
+            assert_eq!(other.extra, point.extra);
+            assert_eq!(
+                other.text_info.as_ref().map(|t| (&t.text, &t.text_vector)),
+                point.text_info.as_ref().map(|t| (&t.text, &t.text_vector))
+            );
+        }
+    }
+
+    #[test]
+    fn test_v1_num_ids_recovered() {
+        let real = Uuid::parse_str("9b2f6c1e-3d4a-4f7b-8c5d-1e2f3a4b5c6d").unwrap();
+        let num = Uuid::from_u128(42);
+        let v1_points: HashMap<Uuid, v1::NekoPoint> = [real, num]
+            .into_iter()
+            .map(|id| {
+                let point = v1::NekoPoint {
+                    id,
+                    height: 1,
+                    width: 2,
+                    size: None,
+                    categories: None,
+                    text_info: None,
+                };
+                (id, point)
+            })
+            .collect();
+        let mut bytes = NEKO_POINTS_MAGIC.to_vec();
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend(
+            bincode::serde::encode_to_vec(&v1_points, bincode::config::standard()).unwrap(),
+        );
+        let points = read_neko_points(&mut bytes.as_slice()).unwrap();
+        assert_eq!(points[&real].qdrant_num_id, None);
+        assert_eq!(points[&num].qdrant_num_id, Some(42));
+        assert_eq!((points[&num].height, points[&num].width), (1, 2));
+    }
+
+    #[test]
+    fn test_v2_migrated() {
+        let id = Uuid::from_u128(7);
+        let v2_points = HashMap::from([(
+            id,
+            v2::NekoPoint {
+                id,
+                height: 3,
+                width: 4,
+                size: Some(5),
+                categories: None,
+                text_info: None,
+                qdrant_num_id: Some(7),
+            },
+        )]);
+        let mut bytes = NEKO_POINTS_MAGIC.to_vec();
+        bytes.extend(2u32.to_le_bytes());
+        bytes.extend(
+            bincode::serde::encode_to_vec(&v2_points, bincode::config::standard()).unwrap(),
+        );
+        let point = &read_neko_points(&mut bytes.as_slice()).unwrap()[&id];
+        assert_eq!(
+            (point.height, point.width, point.size, point.qdrant_num_id),
+            (3, 4, Some(5), Some(7))
+        );
+        assert!(point.extra.is_none());
+    }
+
+    #[test]
+    fn test_newer_version_rejected() {
+        let mut bytes = NEKO_POINTS_MAGIC.to_vec();
+        bytes.extend((NEKO_POINT_SCHEMA_VERSION + 1).to_le_bytes());
+        bytes.push(0);
+        assert!(matches!(
+            read_neko_points(&mut bytes.as_slice()),
+            Err(MigrationError::UnsupportedVersion { found, .. }) if found == NEKO_POINT_SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_width_alias() {
+        // self-describing formats, e.g. the pickles point explorer reads, keep the old name
+        let point: NekoPoint = serde_json::from_str(
+            r#"{"id":"00000000-0000-0000-0000-000000000001","height":1,"weight":2,"size":null,"categories":null,"text_info":null}"#,
+        )
+        .unwrap();
+        assert_eq!(point.width, 2);
+        assert_eq!(point.qdrant_num_id, None);
+        assert_eq!(point.extra, None);
+    }
+}
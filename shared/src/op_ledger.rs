@@ -0,0 +1,383 @@
+//! Mutations already applied to a collection, so that re-running a stage does not send them again
+//!
+//! Every point written by a [`QdrantWriteScheduler`](crate::qdrant::QdrantWriteScheduler) with a
+//! ledger appends `{collection, point, op, hash, at}` to a JSON lines file. `hash` covers the exact
+//! payload set or the deletion, a later run skips a point whose op has the same hash and sends it
+//! again when the hash changed, superseding the entry. The file is only ever appended to and
+//! fsynced every [`DEFAULT_SYNC_EVERY`] entries: a crash loses at most the unsynced entries, whose
+//! ops are sent again, and a torn last line is dropped on the next open.
+
+use crate::qdrant::WriteOp;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Entries appended between two fsyncs
+pub const DEFAULT_SYNC_EVERY: usize = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LedgerError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Malformed ledger entry {path}:{line}: {source}")]
+    Malformed {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+pub type LedgerResult<T> = Result<T, LedgerError>;
+
+/// One line of the ledger
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub collection: String,
+    pub point: Uuid,
+    /// `set_payload` or `delete_points`
+    pub op: String,
+    /// See [`op_hash`]
+    pub hash: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Hex SHA-1 of the op's kind and payload, the same for every point of the op
+///
+/// Object keys are hashed in sorted order, so the hash does not depend on how the payload was built.
+pub fn op_hash(op: &WriteOp) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(op.name().as_bytes());
+    if let WriteOp::SetPayload { payload, .. } = op {
+        hasher.update(b"\n");
+        hash_object(&mut hasher, payload);
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn hash_object(hasher: &mut Sha1, map: &Map<String, Value>) {
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    hasher.update(b"{");
+    for key in keys {
+        hash_value(hasher, &Value::from(key.as_str()));
+        hasher.update(b":");
+        hash_value(hasher, &map[key]);
+    }
+    hasher.update(b"}");
+}
+
+fn hash_value(hasher: &mut Sha1, value: &Value) {
+    match value {
+        Value::Object(map) => hash_object(hasher, map),
+        Value::Array(values) => {
+            hasher.update(b"[");
+            for value in values {
+                hash_value(hasher, value);
+            }
+            hasher.update(b"]");
+        }
+        // scalars serialize the same whatever the map order
+        scalar => hasher.update(scalar.to_string().as_bytes()),
+    }
+    hasher.update(b",");
+}
+
+/// Applied ops of one collection, backed by an append-only JSON lines file
+pub struct OpLedger {
+    path: PathBuf,
+    collection: String,
+    file: File,
+    /// Point -> hash of the last op applied to it
+    applied: HashMap<Uuid, String>,
+    unsynced: usize,
+    sync_every: usize,
+    recovered_bytes: usize,
+}
+
+impl OpLedger {
+    /// Opens or creates the ledger at `path`, keeping the entries of `collection`
+    ///
+    /// A last line that is cut short or unparseable is what a crash mid-append leaves behind, it
+    /// is truncated away. A malformed line before it is an error.
+    pub fn open<P: AsRef<Path>>(path: P, collection: &str) -> LedgerResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let mut applied = HashMap::new();
+        let mut valid_len = 0;
+        for (idx, line) in content.split_inclusive(|b| *b == b'\n').enumerate() {
+            let complete = line.ends_with(b"\n");
+            let last = valid_len + line.len() == content.len();
+            if line.iter().all(u8::is_ascii_whitespace) && complete {
+                valid_len += line.len();
+                continue;
+            }
+            let entry = match serde_json::from_slice::<LedgerEntry>(line) {
+                Ok(entry) if complete => entry,
+                _ if last => break,
+                Ok(_) => unreachable!("only the last line may lack a newline"),
+                Err(source) => {
+                    return Err(LedgerError::Malformed {
+                        path,
+                        line: idx + 1,
+                        source,
+                    });
+                }
+            };
+            if entry.collection == collection {
+                applied.insert(entry.point, entry.hash);
+            }
+            valid_len += line.len();
+        }
+        let recovered_bytes = content.len() - valid_len;
+        if recovered_bytes > 0 {
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        Ok(Self {
+            path,
+            collection: collection.to_owned(),
+            file,
+            applied,
+            unsynced: 0,
+            sync_every: DEFAULT_SYNC_EVERY,
+            recovered_bytes,
+        })
+    }
+
+    pub fn with_sync_every(mut self, entries: usize) -> Self {
+        self.sync_every = entries.max(1);
+        self
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes of a torn last line dropped by [`OpLedger::open`]
+    #[inline]
+    pub fn recovered_bytes(&self) -> usize {
+        self.recovered_bytes
+    }
+
+    /// Points with an applied op
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.applied.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty()
+    }
+
+    /// Whether the last op applied to `point` hashes to `hash`
+    pub fn is_applied(&self, point: &Uuid, hash: &str) -> bool {
+        self.applied.get(point).is_some_and(|h| h == hash)
+    }
+
+    /// Appends one entry per point, in a single write
+    pub fn record(&mut self, points: &[Uuid], op: &str, hash: &str) -> LedgerResult<()> {
+        let at = Utc::now();
+        let mut buf = Vec::new();
+        for point in points {
+            let entry = LedgerEntry {
+                collection: self.collection.clone(),
+                point: *point,
+                op: op.to_owned(),
+                hash: hash.to_owned(),
+                at,
+            };
+            serde_json::to_writer(&mut buf, &entry).map_err(io::Error::from)?;
+            buf.push(b'\n');
+        }
+        self.file.write_all(&buf)?;
+        for point in points {
+            self.applied.insert(*point, hash.to_owned());
+        }
+        self.unsynced += points.len();
+        if self.unsynced >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the entries appended since the last fsync
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OpLedger {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            tracing::error!("Failed to sync ledger {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn set_url(url: &str) -> WriteOp {
+        WriteOp::SetPayload {
+            points: Vec::new(),
+            payload: Map::from_iter([("url".to_owned(), Value::from(url))]),
+        }
+    }
+
+    fn ledger_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("op_ledger_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("ledger.jsonl")
+    }
+
+    #[test]
+    fn test_op_hash() {
+        let a = WriteOp::SetPayload {
+            points: vec![Uuid::from_u128(1)],
+            payload: Map::from_iter([
+                ("url".to_owned(), Value::from("a")),
+                ("format".to_owned(), Value::from("png")),
+            ]),
+        };
+        let b = WriteOp::SetPayload {
+            points: vec![Uuid::from_u128(2)],
+            payload: Map::from_iter([
+                ("format".to_owned(), Value::from("png")),
+                ("url".to_owned(), Value::from("a")),
+            ]),
+        };
+        assert_eq!(op_hash(&a), op_hash(&b));
+        assert_ne!(op_hash(&a), op_hash(&set_url("a")));
+        assert_ne!(op_hash(&set_url("a")), op_hash(&set_url("b")));
+        let delete = WriteOp::DeletePoints { points: Vec::new() };
+        assert_ne!(op_hash(&delete), op_hash(&set_url("a")));
+        assert_eq!(op_hash(&delete).len(), 40);
+    }
+
+    #[test]
+    fn test_skip_and_supersede() {
+        let path = ledger_path("supersede");
+        let (p1, p2) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let (old, new) = (op_hash(&set_url("old")), op_hash(&set_url("new")));
+        {
+            let mut ledger = OpLedger::open(&path, "neko").unwrap();
+            assert!(ledger.is_empty());
+            ledger.record(&[p1, p2], "set_payload", &old).unwrap();
+        }
+        let mut ledger = OpLedger::open(&path, "neko").unwrap();
+        assert_eq!(ledger.len(), 2);
+        assert!(ledger.is_applied(&p1, &old));
+        assert!(!ledger.is_applied(&p1, &new));
+        ledger.record(&[p1], "set_payload", &new).unwrap();
+        drop(ledger);
+
+        // the later entry wins
+        let ledger = OpLedger::open(&path, "neko").unwrap();
+        assert!(ledger.is_applied(&p1, &new));
+        assert!(!ledger.is_applied(&p1, &old));
+        assert!(ledger.is_applied(&p2, &old));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        // entries of other collections are ignored
+        let other = OpLedger::open(&path, "shadow").unwrap();
+        assert!(other.is_empty());
+    }
+
+    #[test]
+    fn test_sync_every() {
+        let path = ledger_path("sync");
+        let mut ledger = OpLedger::open(&path, "neko").unwrap().with_sync_every(3);
+        let points: Vec<Uuid> = (0..2).map(Uuid::from_u128).collect();
+        ledger.record(&points, "delete_points", "h").unwrap();
+        assert_eq!(ledger.unsynced, 2);
+        ledger.record(&points, "delete_points", "h").unwrap();
+        assert_eq!(ledger.unsynced, 0);
+    }
+
+    #[test]
+    fn test_torn_last_line() {
+        let path = ledger_path("torn");
+        let hash = op_hash(&set_url("a"));
+        {
+            let mut ledger = OpLedger::open(&path, "neko").unwrap();
+            ledger
+                .record(
+                    &[Uuid::from_u128(1), Uuid::from_u128(2)],
+                    "set_payload",
+                    &hash,
+                )
+                .unwrap();
+        }
+        let intact = fs::read(&path).unwrap();
+
+        // cut short mid-entry
+        let mut torn = intact.clone();
+        torn.truncate(intact.len() - 10);
+        fs::write(&path, &torn).unwrap();
+        let ledger = OpLedger::open(&path, "neko").unwrap();
+        assert_eq!(ledger.len(), 1);
+        assert!(ledger.is_applied(&Uuid::from_u128(1), &hash));
+        assert!(ledger.recovered_bytes() > 0);
+        drop(ledger);
+        let first_line = intact.iter().position(|b| *b == b'\n').unwrap() + 1;
+        assert_eq!(fs::read(&path).unwrap(), &intact[..first_line]);
+
+        // garbage the filesystem left past the last entry
+        let mut garbage = intact.clone();
+        garbage.extend_from_slice(b"\0\0\0\0\n");
+        fs::write(&path, &garbage).unwrap();
+        let mut ledger = OpLedger::open(&path, "neko").unwrap();
+        assert_eq!(ledger.len(), 2);
+        assert_eq!(ledger.recovered_bytes(), 5);
+        ledger
+            .record(&[Uuid::from_u128(3)], "set_payload", &hash)
+            .unwrap();
+        drop(ledger);
+        let ledger = OpLedger::open(&path, "neko").unwrap();
+        assert_eq!((ledger.len(), ledger.recovered_bytes()), (3, 0));
+    }
+
+    #[test]
+    fn test_malformed_middle_line() {
+        let path = ledger_path("malformed");
+        {
+            let mut ledger = OpLedger::open(&path, "neko").unwrap();
+            ledger
+                .record(&[Uuid::from_u128(1)], "set_payload", "h")
+                .unwrap();
+        }
+        let mut content = b"not json\n".to_vec();
+        content.extend(fs::read(&path).unwrap());
+        fs::write(&path, &content).unwrap();
+        let err = OpLedger::open(&path, "neko").err().unwrap();
+        assert!(
+            matches!(err, LedgerError::Malformed { line: 1, .. }),
+            "{}",
+            err
+        );
+        // left untouched for a human to look at
+        assert_eq!(fs::read(&path).unwrap(), content);
+    }
+}
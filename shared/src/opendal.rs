@@ -1,6 +1,10 @@
+#[cfg(feature = "opendal-data-compat")]
 use chrono::{DateTime, Utc};
+#[cfg(feature = "opendal-data-compat")]
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "opendal-data-compat")]
 use std::collections::HashMap;
+#[cfg(feature = "opendal-data-compat")]
 use std::path::Path;
 
 #[cfg(feature = "opendal-data-compat")]
@@ -106,6 +110,215 @@ impl From<opendal::Entry> for Entry {
     }
 }
 
+/// Layout: `MAGIC | version: u32 | bincode (standard config) Vec<Entry>`
+///
+/// Listings written before versioning have no header, they hold either the current [`Entry`] or
+/// the legacy layout and are read as version 0.
+#[cfg(feature = "opendal-data-compat")]
+pub const ENTRY_LIST_MAGIC: &[u8; 8] = b"NKENTRYS";
+#[cfg(feature = "opendal-data-compat")]
+pub const ENTRY_LIST_VERSION: u32 = 1;
+
+#[cfg(feature = "opendal-data-compat")]
+#[derive(Debug, thiserror::Error)]
+pub enum EntryListError {
+    #[error("Listing version {found} is newer than the supported {supported}, rebuild this tool")]
+    UnsupportedVersion { found: u32, supported: u32 },
+    #[error("Listing matches no known layout, as current: {current}, as legacy: {legacy}")]
+    UnknownLayout {
+        current: bincode::error::DecodeError,
+        legacy: bincode::error::DecodeError,
+    },
+    #[error(transparent)]
+    DecodeError(#[from] bincode::error::DecodeError),
+    #[error(transparent)]
+    EncodeError(#[from] bincode::error::EncodeError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+#[cfg(feature = "opendal-data-compat")]
+pub type EntryListResult<T> = Result<T, EntryListError>;
+
+/// How a listing was stored, anything but `Current` is rewritten by [`load_entry_list`]
+#[cfg(feature = "opendal-data-compat")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EntryListLayout {
+    Current,
+    /// Current [`Entry`] without the header
+    Unversioned,
+    /// [`legacy::LegacyEntry`] without the header
+    Legacy,
+}
+
+/// What stage5 listed before `Metadata` followed the newer opendal fields
+#[cfg(feature = "opendal-data-compat")]
+mod legacy {
+    use super::{BytesContentRange, Entry, EntryMode, Metadata};
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct LegacyMetadata {
+        pub mode: EntryMode,
+        pub cache_control: Option<String>,
+        pub content_disposition: Option<String>,
+        pub content_length: u64,
+        pub content_md5: Option<String>,
+        pub content_range: Option<BytesContentRange>,
+        pub content_type: Option<String>,
+        pub etag: Option<String>,
+        pub last_modified: Option<DateTime<Utc>>,
+        pub version: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct LegacyEntry {
+        pub path: String,
+        pub metadata: LegacyMetadata,
+    }
+
+    impl From<LegacyEntry> for Entry {
+        fn from(e: LegacyEntry) -> Self {
+            let m = e.metadata;
+            Entry {
+                path: e.path,
+                metadata: Metadata {
+                    mode: m.mode,
+                    is_current: None,
+                    is_deleted: false,
+                    cache_control: m.cache_control,
+                    content_disposition: m.content_disposition,
+                    content_length: Some(m.content_length),
+                    content_md5: m.content_md5,
+                    content_range: m.content_range,
+                    content_type: m.content_type,
+                    content_encoding: None,
+                    etag: m.etag,
+                    last_modified: m.last_modified,
+                    version: m.version,
+                    user_metadata: None,
+                },
+            }
+        }
+    }
+}
+
+/// Version of a listing, `bytes` advanced past the header when there is one
+#[cfg(feature = "opendal-data-compat")]
+fn split_entry_list_version(bytes: &[u8]) -> (u32, &[u8]) {
+    let header_len = ENTRY_LIST_MAGIC.len() + 4;
+    match bytes.starts_with(ENTRY_LIST_MAGIC) && bytes.len() >= header_len {
+        true => {
+            let version = bytes[ENTRY_LIST_MAGIC.len()..header_len]
+                .try_into()
+                .unwrap();
+            (u32::from_le_bytes(version), &bytes[header_len..])
+        }
+        false => (0, bytes),
+    }
+}
+
+/// Decodes `bytes` as a whole, a layout that leaves bytes over is the wrong one
+#[cfg(feature = "opendal-data-compat")]
+fn decode_exact<T: serde::de::DeserializeOwned>(
+    bytes: &[u8],
+) -> Result<T, bincode::error::DecodeError> {
+    let (value, read) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())?;
+    match read == bytes.len() {
+        true => Ok(value),
+        false => Err(bincode::error::DecodeError::OtherString(format!(
+            "{} trailing bytes",
+            bytes.len() - read
+        ))),
+    }
+}
+
+/// Decodes a listing of any known layout into the current [`Entry`]
+#[cfg(feature = "opendal-data-compat")]
+pub fn decode_entry_list(bytes: &[u8]) -> EntryListResult<(Vec<Entry>, EntryListLayout)> {
+    match split_entry_list_version(bytes) {
+        (0, body) => match decode_exact::<Vec<Entry>>(body) {
+            Ok(entries) => Ok((entries, EntryListLayout::Unversioned)),
+            Err(current) => match decode_exact::<Vec<legacy::LegacyEntry>>(body) {
+                Ok(entries) => Ok((
+                    entries.into_iter().map(Entry::from).collect(),
+                    EntryListLayout::Legacy,
+                )),
+                Err(legacy) => Err(EntryListError::UnknownLayout { current, legacy }),
+            },
+        },
+        (ENTRY_LIST_VERSION, body) => Ok((decode_exact(body)?, EntryListLayout::Current)),
+        (found, _) => Err(EntryListError::UnsupportedVersion {
+            found,
+            supported: ENTRY_LIST_VERSION,
+        }),
+    }
+}
+
+/// Checks the length and the first record of a listing from its leading bytes, which must hold
+/// that record whole
+#[cfg(feature = "opendal-data-compat")]
+pub fn probe_entry_list(head: &[u8]) -> EntryListResult<EntryListLayout> {
+    fn first<T: serde::de::DeserializeOwned>(
+        body: &[u8],
+    ) -> Result<(), bincode::error::DecodeError> {
+        let config = bincode::config::standard();
+        let (len, read): (u64, usize) = bincode::decode_from_slice(body, config)?;
+        if len > 0 {
+            bincode::serde::decode_from_slice::<T, _>(&body[read..], config)?;
+        }
+        Ok(())
+    }
+    match split_entry_list_version(head) {
+        (0, body) => match first::<Entry>(body) {
+            Ok(()) => Ok(EntryListLayout::Unversioned),
+            Err(current) => match first::<legacy::LegacyEntry>(body) {
+                Ok(()) => Ok(EntryListLayout::Legacy),
+                Err(legacy) => Err(EntryListError::UnknownLayout { current, legacy }),
+            },
+        },
+        (ENTRY_LIST_VERSION, body) => {
+            first::<Entry>(body)?;
+            Ok(EntryListLayout::Current)
+        }
+        (found, _) => Err(EntryListError::UnsupportedVersion {
+            found,
+            supported: ENTRY_LIST_VERSION,
+        }),
+    }
+}
+
+/// Always writes the current version
+#[cfg(feature = "opendal-data-compat")]
+pub fn write_entry_list<W: std::io::Write>(
+    writer: &mut W,
+    entries: &[Entry],
+) -> EntryListResult<()> {
+    writer.write_all(ENTRY_LIST_MAGIC)?;
+    writer.write_all(&ENTRY_LIST_VERSION.to_le_bytes())?;
+    bincode::serde::encode_into_std_write(entries, writer, bincode::config::standard())?;
+    Ok(())
+}
+
+#[cfg(feature = "opendal-data-compat")]
+pub fn save_entry_list<P: AsRef<Path>>(path: P, entries: &[Entry]) -> EntryListResult<()> {
+    crate::atomic_write::atomic_write_with(path, |w| write_entry_list(w, entries))
+}
+
+/// Loads a listing of any known layout and rewrites it in place as the current one if it was
+/// older, the returned layout is the one found on disk
+#[cfg(feature = "opendal-data-compat")]
+pub fn load_entry_list<P: AsRef<Path>>(path: P) -> EntryListResult<(Vec<Entry>, EntryListLayout)> {
+    let bytes = std::fs::read(&path)?;
+    let (entries, layout) = decode_entry_list(&bytes)?;
+    drop(bytes);
+    if layout != EntryListLayout::Current {
+        save_entry_list(&path, &entries)?;
+    }
+    Ok((entries, layout))
+}
+
 #[cfg(feature = "opendal-ext")]
 #[derive(Debug)]
 pub struct GenShinOperator {
@@ -121,19 +334,113 @@ impl std::ops::Deref for GenShinOperator {
     }
 }
 
+/// Object store behind [`GenShinOperator::new`], `$OBJECT_STORE_BACKEND` with S3 by default
+#[cfg(feature = "opendal-ext")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// `S3_BUCKET`, `S3_ACCESS_KEY`, `S3_SECRET_ACCESS_KEY`, `S3_ENDPOINT`, `S3_REGION`
+    #[default]
+    S3,
+    /// `AZBLOB_CONTAINER`, `AZBLOB_ENDPOINT`, `AZBLOB_ACCOUNT_NAME`, `AZBLOB_ACCOUNT_KEY`
+    Azblob,
+    /// `GCS_BUCKET`, optionally `GCS_CREDENTIAL_PATH` and `GCS_ENDPOINT`
+    Gcs,
+    /// `FS_ROOT`, a plain directory
+    Fs,
+}
+
+#[cfg(feature = "opendal-ext")]
+impl std::str::FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "s3" => Ok(Backend::S3),
+            "azblob" => Ok(Backend::Azblob),
+            "gcs" => Ok(Backend::Gcs),
+            "fs" => Ok(Backend::Fs),
+            other => anyhow::bail!(
+                "Unknown object store backend {}, expected s3|azblob|gcs|fs",
+                other
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "opendal-ext")]
+impl Backend {
+    pub const ENV: &'static str = "OBJECT_STORE_BACKEND";
+
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        match std::env::var(Self::ENV) {
+            Ok(backend) => backend.parse(),
+            Err(_) => Ok(Backend::default()),
+        }
+    }
+
+    /// The bare operator configured from the backend's env variables
+    fn operator(self) -> Result<opendal::Operator, anyhow::Error> {
+        use opendal::services::{Azblob, Fs, Gcs, S3};
+        use std::env;
+        let op = match self {
+            Backend::S3 => {
+                let builder = S3::default()
+                    .bucket(&env::var("S3_BUCKET")?)
+                    .access_key_id(&env::var("S3_ACCESS_KEY")?)
+                    .secret_access_key(&env::var("S3_SECRET_ACCESS_KEY")?)
+                    .endpoint(&env::var("S3_ENDPOINT")?)
+                    .region(&env::var("S3_REGION")?);
+                opendal::Operator::new(builder)?.finish()
+            }
+            Backend::Azblob => {
+                let builder = Azblob::default()
+                    .container(&env::var("AZBLOB_CONTAINER")?)
+                    .endpoint(&env::var("AZBLOB_ENDPOINT")?)
+                    .account_name(&env::var("AZBLOB_ACCOUNT_NAME")?)
+                    .account_key(&env::var("AZBLOB_ACCOUNT_KEY")?);
+                opendal::Operator::new(builder)?.finish()
+            }
+            Backend::Gcs => {
+                let mut builder = Gcs::default().bucket(&env::var("GCS_BUCKET")?);
+                if let Ok(path) = env::var("GCS_CREDENTIAL_PATH") {
+                    builder = builder.credential_path(&path);
+                }
+                if let Ok(endpoint) = env::var("GCS_ENDPOINT") {
+                    builder = builder.endpoint(&endpoint);
+                }
+                opendal::Operator::new(builder)?.finish()
+            }
+            Backend::Fs => {
+                opendal::Operator::new(Fs::default().root(&env::var("FS_ROOT")?))?.finish()
+            }
+        };
+        Ok(op)
+    }
+}
+
 #[cfg(feature = "opendal-ext")]
 impl GenShinOperator {
+    /// Backend picked by [`Backend::from_env`]
     pub fn new() -> Result<Self, anyhow::Error> {
+        Self::with_backend(Backend::from_env()?)
+    }
+
+    pub fn with_backend(backend: Backend) -> Result<Self, anyhow::Error> {
+        tracing::info!("Using the {:?} object store backend", backend);
+        Ok(Self::layered(backend.operator()?))
+    }
+
+    /// Objects as files under `root`, for local runs and tests
+    pub fn fs(root: &str) -> Result<Self, anyhow::Error> {
+        let op = opendal::Operator::new(opendal::services::Fs::default().root(root))?.finish();
+        Ok(Self::layered(op))
+    }
+
+    /// Same layer stack for every backend
+    fn layered(op: opendal::Operator) -> Self {
         use opendal::layers::{ConcurrentLimitLayer, RetryLayer, TracingLayer};
-        use std::env;
         use std::time::Duration;
-        let builder = opendal::services::S3::default()
-            .bucket(&env::var("S3_BUCKET")?)
-            .access_key_id(&env::var("S3_ACCESS_KEY")?)
-            .secret_access_key(&env::var("S3_SECRET_ACCESS_KEY")?)
-            .endpoint(&env::var("S3_ENDPOINT")?)
-            .region(&env::var("S3_REGION")?);
-        let op = opendal::Operator::new(builder)?
+        let op = op
             .layer(TracingLayer)
             .layer(
                 RetryLayer::default()
@@ -142,8 +449,265 @@ impl GenShinOperator {
                     .with_min_delay(Duration::from_millis(50))
                     .with_max_delay(Duration::from_millis(20000)),
             )
-            .layer(ConcurrentLimitLayer::new(4096))
-            .finish();
-        Ok(GenShinOperator { op })
+            .layer(ConcurrentLimitLayer::new(4096));
+        GenShinOperator { op }
+    }
+
+    // The methods below shadow their `Operator` counterparts so every stage reports `s3_*` metrics,
+    // retries happen inside one observed call
+
+    pub async fn read(&self, path: &str) -> opendal::Result<opendal::Buffer> {
+        crate::metrics::observe("s3", "read", self.op.read(path)).await
+    }
+
+    /// The bytes of `range` only, for ranged multipart downloads
+    pub async fn read_range(
+        &self,
+        path: &str,
+        range: std::ops::Range<u64>,
+    ) -> opendal::Result<opendal::Buffer> {
+        let read = async { self.op.read_with(path).range(range).await };
+        crate::metrics::observe("s3", "read_range", read).await
+    }
+
+    pub async fn write(
+        &self,
+        path: &str,
+        bs: impl Into<opendal::Buffer>,
+    ) -> opendal::Result<opendal::Metadata> {
+        crate::metrics::observe("s3", "write", self.op.write(path, bs)).await
+    }
+
+    pub async fn stat(&self, path: &str) -> opendal::Result<opendal::Metadata> {
+        crate::metrics::observe("s3", "stat", self.op.stat(path)).await
+    }
+
+    pub async fn copy(&self, from: &str, to: &str) -> opendal::Result<()> {
+        crate::metrics::observe("s3", "copy", self.op.copy(from, to)).await
+    }
+
+    pub async fn delete(&self, path: &str) -> opendal::Result<()> {
+        crate::metrics::observe("s3", "delete", self.op.delete(path)).await
+    }
+}
+
+#[cfg(all(feature = "opendal-data-compat", feature = "opendal-ext"))]
+impl GenShinOperator {
+    /// Every entry under `path` in the layout stage5 saves
+    pub async fn list_all(&self, path: &str, recursive: bool) -> opendal::Result<Vec<Entry>> {
+        let list = async { self.op.list_with(path).recursive(recursive).await };
+        let entries = crate::metrics::observe("s3", "list", list).await?;
+        Ok(entries.into_iter().map(Entry::from).collect())
+    }
+}
+
+#[cfg(feature = "opendal-ext")]
+impl From<opendal::Operator> for GenShinOperator {
+    fn from(op: opendal::Operator) -> Self {
+        GenShinOperator { op }
+    }
+}
+
+#[cfg(all(test, feature = "opendal-data-compat"))]
+mod tests {
+    use super::*;
+
+    /// Field for field what stage5 serialized before versioning
+    #[derive(Serialize)]
+    struct LegacyMetadata {
+        mode: EntryMode,
+        cache_control: Option<String>,
+        content_disposition: Option<String>,
+        content_length: u64,
+        content_md5: Option<String>,
+        content_range: Option<BytesContentRange>,
+        content_type: Option<String>,
+        etag: Option<String>,
+        last_modified: Option<DateTime<Utc>>,
+        version: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct LegacyEntry {
+        path: String,
+        metadata: LegacyMetadata,
+    }
+
+    fn legacy_fixture() -> Vec<u8> {
+        let entries = vec![
+            LegacyEntry {
+                path: "a/00000000-0000-0000-0000-000000000001.png".to_string(),
+                metadata: LegacyMetadata {
+                    mode: EntryMode::FILE,
+                    cache_control: None,
+                    content_disposition: None,
+                    content_length: 1024,
+                    content_md5: Some("md5".to_string()),
+                    content_range: Some(BytesContentRange(Some(0), Some(1023), Some(1024))),
+                    content_type: Some("image/png".to_string()),
+                    etag: Some("\"etag\"".to_string()),
+                    last_modified: Some(DateTime::from_timestamp(1_700_000_000, 0).unwrap()),
+                    version: None,
+                },
+            },
+            LegacyEntry {
+                path: "a/".to_string(),
+                metadata: LegacyMetadata {
+                    mode: EntryMode::DIR,
+                    cache_control: None,
+                    content_disposition: None,
+                    content_length: 0,
+                    content_md5: None,
+                    content_range: None,
+                    content_type: None,
+                    etag: None,
+                    last_modified: None,
+                    version: Some("v1".to_string()),
+                },
+            },
+        ];
+        bincode::serde::encode_to_vec(&entries, bincode::config::standard()).unwrap()
+    }
+
+    fn entry(path: &str, len: u64) -> Entry {
+        Entry {
+            path: path.to_string(),
+            metadata: Metadata {
+                mode: EntryMode::FILE,
+                is_current: Some(true),
+                is_deleted: false,
+                cache_control: None,
+                content_disposition: None,
+                content_length: Some(len),
+                content_md5: None,
+                content_range: None,
+                content_type: None,
+                content_encoding: Some("gzip".to_string()),
+                etag: None,
+                last_modified: None,
+                version: None,
+                user_metadata: Some(HashMap::from([("k".to_string(), "v".to_string())])),
+            },
+        }
+    }
+
+    #[test]
+    fn test_legacy_upgraded() {
+        let bytes = legacy_fixture();
+        let (entries, layout) = decode_entry_list(&bytes).unwrap();
+        assert_eq!(layout, EntryListLayout::Legacy);
+        assert_eq!(entries.len(), 2);
+        let file = &entries[0];
+        assert_eq!(file.to_point(), "00000000-0000-0000-0000-000000000001");
+        assert_eq!(file.metadata.mode, EntryMode::FILE);
+        assert_eq!(file.metadata.content_length, Some(1024));
+        assert_eq!(
+            file.metadata.content_range,
+            Some(BytesContentRange(Some(0), Some(1023), Some(1024)))
+        );
+        assert_eq!(file.metadata.content_type.as_deref(), Some("image/png"));
+        assert_eq!(
+            file.metadata.last_modified.map(|t| t.timestamp()),
+            Some(1_700_000_000)
+        );
+        assert!(file.metadata.is_current.is_none() && !file.metadata.is_deleted);
+        let dir = &entries[1];
+        assert_eq!(dir.metadata.mode, EntryMode::DIR);
+        assert_eq!(dir.metadata.version.as_deref(), Some("v1"));
+        assert_eq!(probe_entry_list(&bytes).unwrap(), EntryListLayout::Legacy);
+    }
+
+    #[test]
+    fn test_unversioned_and_current() {
+        let entries = vec![entry("a/1.png", 1), entry("a/2.gif", 2)];
+        let unversioned =
+            bincode::serde::encode_to_vec(&entries, bincode::config::standard()).unwrap();
+        let (decoded, layout) = decode_entry_list(&unversioned).unwrap();
+        assert_eq!(layout, EntryListLayout::Unversioned);
+        assert_eq!(decoded[1].metadata.content_length, Some(2));
+        let mut current = Vec::new();
+        write_entry_list(&mut current, &decoded).unwrap();
+        assert!(current.starts_with(ENTRY_LIST_MAGIC));
+        let (decoded, layout) = decode_entry_list(&current).unwrap();
+        assert_eq!(layout, EntryListLayout::Current);
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].path, "a/1.png");
+        assert_eq!(
+            decoded[0].metadata.user_metadata,
+            entries[0].metadata.user_metadata
+        );
+        assert_eq!(
+            probe_entry_list(&current).unwrap(),
+            EntryListLayout::Current
+        );
+        assert_eq!(
+            probe_entry_list(&unversioned).unwrap(),
+            EntryListLayout::Unversioned
+        );
+    }
+
+    #[test]
+    fn test_rejected() {
+        let mut newer = ENTRY_LIST_MAGIC.to_vec();
+        newer.extend((ENTRY_LIST_VERSION + 1).to_le_bytes());
+        newer.push(0);
+        assert!(matches!(
+            decode_entry_list(&newer),
+            Err(EntryListError::UnsupportedVersion { found, .. }) if found == ENTRY_LIST_VERSION + 1
+        ));
+        let mut truncated = legacy_fixture();
+        truncated.truncate(truncated.len() - 3);
+        assert!(matches!(
+            decode_entry_list(&truncated),
+            Err(EntryListError::UnknownLayout { .. })
+        ));
+    }
+
+    #[test]
+    fn test_load_rewrites_in_place() {
+        let path = std::env::temp_dir().join(format!("entry_list_{}.bin", uuid::Uuid::new_v4()));
+        std::fs::write(&path, legacy_fixture()).unwrap();
+        let (entries, layout) = load_entry_list(&path).unwrap();
+        assert_eq!(layout, EntryListLayout::Legacy);
+        assert!(std::fs::read(&path).unwrap().starts_with(ENTRY_LIST_MAGIC));
+        let (again, layout) = load_entry_list(&path).unwrap();
+        assert_eq!(layout, EntryListLayout::Current);
+        assert_eq!(
+            again.iter().map(|e| &e.path).collect::<Vec<_>>(),
+            entries.iter().map(|e| &e.path).collect::<Vec<_>>()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "opendal-ext")]
+    #[test]
+    fn test_backend_from_str() {
+        assert_eq!("s3".parse::<Backend>().unwrap(), Backend::S3);
+        assert_eq!(" AzBlob ".parse::<Backend>().unwrap(), Backend::Azblob);
+        assert_eq!("gcs".parse::<Backend>().unwrap(), Backend::Gcs);
+        assert_eq!("fs".parse::<Backend>().unwrap(), Backend::Fs);
+        assert!("ftp".parse::<Backend>().is_err());
+    }
+
+    #[cfg(feature = "opendal-ext")]
+    #[tokio::test]
+    async fn test_fs_backend_round_trip() {
+        let root = std::env::temp_dir().join(format!("genshin_fs_{}", uuid::Uuid::new_v4()));
+        let op = GenShinOperator::fs(root.to_str().unwrap()).unwrap();
+        op.write("a/1.png", b"png".to_vec()).await.unwrap();
+        op.copy("a/1.png", "a/1.jpg").await.unwrap();
+        op.delete("a/1.png").await.unwrap();
+        assert_eq!(op.stat("a/1.jpg").await.unwrap().content_length(), 3);
+        let files: Vec<String> = op
+            .list_all("a/", true)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.metadata.mode == EntryMode::FILE)
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(files, vec!["a/1.jpg".to_string()]);
+        assert_eq!(std::fs::read(root.join("a/1.jpg")).unwrap(), b"png");
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -0,0 +1,184 @@
+use ndarray::Array2;
+use petal_clustering::{Fit, Optics};
+use petal_neighbors::distance::{Euclidean, Hamming};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// `(cluster id -> row indices, noise row indices)` as returned by `Optics::fit`
+pub type OpticsClusters = (HashMap<usize, Vec<usize>>, Vec<usize>);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpticsMetric {
+    Hamming,
+    Euclidean,
+}
+
+impl FromStr for OpticsMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hamming" => Ok(OpticsMetric::Hamming),
+            "euclidean" => Ok(OpticsMetric::Euclidean),
+            other => Err(format!(
+                "unknown metric {}, expected hamming or euclidean",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for OpticsMetric {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpticsMetric::Hamming => write!(f, "hamming"),
+            OpticsMetric::Euclidean => write!(f, "euclidean"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct OpticsParams {
+    /// Radius in units of `metric`, for Hamming the number of differing hash components
+    pub eps: f32,
+    pub min_samples: usize,
+    pub metric: OpticsMetric,
+}
+
+impl Default for OpticsParams {
+    /// What stage18 and stage19 ran with before their parameters were configurable
+    fn default() -> Self {
+        Self {
+            eps: 10.0,
+            min_samples: 2,
+            metric: OpticsMetric::Hamming,
+        }
+    }
+}
+
+impl OpticsParams {
+    pub fn with_eps(&self, eps: f32) -> Self {
+        Self { eps, ..*self }
+    }
+}
+
+pub fn fit(data: &Array2<f32>, params: &OpticsParams) -> OpticsClusters {
+    match params.metric {
+        OpticsMetric::Hamming => {
+            Optics::new(params.eps, params.min_samples, Hamming::default()).fit(data, None)
+        }
+        OpticsMetric::Euclidean => {
+            Optics::new(params.eps, params.min_samples, Euclidean::default()).fit(data, None)
+        }
+    }
+}
+
+/// One line of a sweep summary
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SweepSummary {
+    #[serde(flatten)]
+    pub params: OpticsParams,
+    pub cluster_count: usize,
+    pub noise_count: usize,
+    /// Members of the largest cluster, 0 without clusters
+    pub largest_cluster: usize,
+}
+
+impl SweepSummary {
+    pub fn new(params: OpticsParams, (clusters, noise): &OpticsClusters) -> Self {
+        Self {
+            params,
+            cluster_count: clusters.len(),
+            noise_count: noise.len(),
+            largest_cluster: clusters.values().map(Vec::len).max().unwrap_or(0),
+        }
+    }
+}
+
+/// Fits once per `eps`, everything else taken from `base`, and hands each result to `save`
+/// before the next fit starts so a long sweep keeps what it finished
+pub fn sweep<F, E>(
+    data: &Array2<f32>,
+    base: &OpticsParams,
+    eps: &[f32],
+    mut save: F,
+) -> Result<Vec<SweepSummary>, E>
+where
+    F: FnMut(&OpticsParams, &OpticsClusters) -> Result<(), E>,
+{
+    eps.iter()
+        .map(|&eps| {
+            let params = base.with_eps(eps);
+            let clusters = fit(data, &params);
+            save(&params, &clusters)?;
+            Ok(SweepSummary::new(params, &clusters))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_parse() {
+        assert_eq!("hamming".parse(), Ok(OpticsMetric::Hamming));
+        assert_eq!("Euclidean".parse(), Ok(OpticsMetric::Euclidean));
+        assert!("cosine".parse::<OpticsMetric>().is_err());
+        assert_eq!(OpticsMetric::Euclidean.to_string(), "euclidean");
+    }
+
+    #[test]
+    fn test_summary() {
+        let clusters: OpticsClusters = (
+            HashMap::from([(0, vec![0, 1, 2]), (1, vec![3, 4])]),
+            vec![5],
+        );
+        let params = OpticsParams::default().with_eps(4.0);
+        let summary = SweepSummary::new(params, &clusters);
+        assert_eq!(
+            (
+                summary.cluster_count,
+                summary.noise_count,
+                summary.largest_cluster
+            ),
+            (2, 1, 3)
+        );
+        let empty = SweepSummary::new(params, &(HashMap::new(), vec![0, 1]));
+        assert_eq!(empty.largest_cluster, 0);
+        assert_eq!(
+            serde_json::to_string(&summary).unwrap(),
+            r#"{"eps":4.0,"min_samples":2,"metric":"hamming","cluster_count":2,"noise_count":1,"largest_cluster":3}"#
+        );
+    }
+
+    #[test]
+    fn test_sweep() {
+        // two tight groups 1.0 apart internally and 10.0 apart from each other
+        let data =
+            Array2::from_shape_vec((4, 2), vec![0.0, 0.0, 0.0, 1.0, 10.0, 0.0, 10.0, 1.0]).unwrap();
+        let base = OpticsParams {
+            metric: OpticsMetric::Euclidean,
+            ..Default::default()
+        };
+        let mut saved = Vec::new();
+        let summaries = sweep(&data, &base, &[0.5, 2.0, 20.0], |params, clusters| {
+            saved.push((params.eps, clusters.0.len()));
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        assert_eq!(saved, vec![(0.5, 0), (2.0, 2), (20.0, 1)]);
+        assert_eq!(summaries[0].noise_count, 4);
+        assert_eq!(summaries[1].largest_cluster, 2);
+        assert_eq!(summaries[2].largest_cluster, 4);
+        assert!(summaries.iter().all(|s| s.params.min_samples == 2));
+        let failed = sweep(&data, &base, &[0.5, 2.0], |params, _| match params.eps {
+            eps if eps > 1.0 => Err(eps),
+            _ => Ok(()),
+        });
+        assert_eq!(failed, Err(2.0));
+    }
+}
@@ -0,0 +1,912 @@
+//! Declarative pipeline plans, an ordered list of steps each running a command that reads and
+//! writes named artifacts, executed make-like
+//!
+//! A step in the selected range runs when it is forced, was never recorded as run, its command
+//! line changed, one of its outputs is missing, the content of one of its inputs changed since
+//! its last run, or a step producing one of its inputs runs too. After a successful run its
+//! record, the command line and the SHA-1 of every input, goes to `<state_dir>/<step>.json`,
+//! what it printed to `<state_dir>/<step>.log`.
+//!
+//! ```toml
+//! state_dir = ".pipeline"
+//!
+//! [artifacts]
+//! explorer = "data/image_f32d768.bin"
+//! eval = { path = "data/eval", dir = true }
+//!
+//! [[step]]
+//! name = "eval"
+//! command = ["cargo", "run", "--package=stage14", "--bin=cluster-eval", "--"]
+//! inputs = ["explorer"]
+//! outputs = ["eval"]
+//! params = { point-explorer = "{explorer}", save-result-prefix = "{eval}/greedy" }
+//! ```
+//!
+//! Paths are relative to the plan, commands run in its directory with `$NEKO_WORK_DIR` set to it
+//! and `{name}` in the command or a parameter is replaced by the path of artifact `name`.
+
+use crate::stage_lock::WORK_DIR_ENV;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_STATE_DIR: &str = ".pipeline";
+const RECORD_EXT: &str = "json";
+const LOG_EXT: &str = "log";
+
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Malformed plan: {0}")]
+    Plan(#[from] toml::de::Error),
+    #[error("Malformed run record {path}: {source}")]
+    Record {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("Step {0} is defined more than once")]
+    DuplicateStep(String),
+    #[error("Step {step} references unknown artifact {artifact}")]
+    UnknownArtifact { step: String, artifact: String },
+    #[error("Artifact {artifact} is produced by both {first} and {second}")]
+    ProducedTwice {
+        artifact: String,
+        first: String,
+        second: String,
+    },
+    #[error("Step {step} reads {artifact} before {producer} produces it")]
+    ProducedLater {
+        step: String,
+        artifact: String,
+        producer: String,
+    },
+    #[error("Parameter {param} of step {step} is not a string, number, boolean or array of them")]
+    Param { step: String, param: String },
+    #[error("Unknown step {0}")]
+    UnknownStep(String),
+    #[error("--from {from} comes after --until {until}")]
+    EmptyRange { from: String, until: String },
+    #[error("Input {artifact} of step {step} does not exist at {path}")]
+    MissingInput {
+        step: String,
+        artifact: String,
+        path: PathBuf,
+    },
+    #[error("Step {step} failed ({status}), see {log}")]
+    StepFailed {
+        step: String,
+        status: ExitStatus,
+        log: PathBuf,
+    },
+}
+
+pub type PipelineResult<T> = Result<T, PipelineError>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Artifact {
+    File(PathBuf),
+    /// With `dir = true` created before its producing step runs and hashed as a tree
+    Table {
+        path: PathBuf,
+        #[serde(default)]
+        dir: bool,
+    },
+}
+
+impl Artifact {
+    pub fn path(&self) -> &Path {
+        match self {
+            Artifact::File(path) | Artifact::Table { path, .. } => path,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Artifact::Table { dir: true, .. })
+    }
+
+    fn rebase(&mut self, base: &Path) {
+        match self {
+            Artifact::File(path) | Artifact::Table { path, .. } => *path = base.join(&*path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub name: String,
+    /// Program and leading arguments
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    /// Appended as `--key value`, `true` as a bare `--key`, `false` left out, arrays joined by
+    /// commas
+    #[serde(default)]
+    pub params: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    /// Run records and logs
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    #[serde(default)]
+    pub artifacts: BTreeMap<String, Artifact>,
+    #[serde(rename = "step", default)]
+    pub steps: Vec<Step>,
+    /// Directory the paths are relative to and the commands run in
+    #[serde(skip)]
+    pub base: PathBuf,
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from(DEFAULT_STATE_DIR)
+}
+
+impl Plan {
+    /// Parses and validates a plan whose paths are relative to `base`
+    pub fn parse(toml: &str, base: &Path) -> PipelineResult<Self> {
+        let mut plan: Plan = toml::from_str(toml)?;
+        plan.base = base.to_path_buf();
+        plan.state_dir = base.join(&plan.state_dir);
+        for artifact in plan.artifacts.values_mut() {
+            artifact.rebase(base);
+        }
+        plan.validate()?;
+        Ok(plan)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> PipelineResult<Self> {
+        let path = path.as_ref();
+        let base = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        // absolute, the commands run in `base` and get the paths as arguments
+        Self::parse(&fs::read_to_string(path)?, &std::path::absolute(base)?)
+    }
+
+    /// Every artifact is declared and produced by at most one step, before the steps reading it
+    fn validate(&self) -> PipelineResult<()> {
+        let mut names = BTreeSet::new();
+        let mut producers: HashMap<&str, &str> = HashMap::new();
+        for step in &self.steps {
+            if !names.insert(step.name.as_str()) {
+                return Err(PipelineError::DuplicateStep(step.name.clone()));
+            }
+            for artifact in step.inputs.iter().chain(&step.outputs) {
+                if !self.artifacts.contains_key(artifact) {
+                    return Err(PipelineError::UnknownArtifact {
+                        step: step.name.clone(),
+                        artifact: artifact.clone(),
+                    });
+                }
+            }
+            for artifact in &step.outputs {
+                if let Some(first) = producers.insert(artifact, &step.name) {
+                    return Err(PipelineError::ProducedTwice {
+                        artifact: artifact.clone(),
+                        first: first.to_string(),
+                        second: step.name.clone(),
+                    });
+                }
+            }
+        }
+        let producers = self.producers();
+        for (idx, step) in self.steps.iter().enumerate() {
+            for artifact in &step.inputs {
+                let Some(&producer) = producers.get(artifact.as_str()) else {
+                    continue;
+                };
+                if producer >= idx {
+                    return Err(PipelineError::ProducedLater {
+                        step: step.name.clone(),
+                        artifact: artifact.clone(),
+                        producer: self.steps[producer].name.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Index of the step producing every produced artifact
+    fn producers(&self) -> HashMap<&str, usize> {
+        self.steps
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, step)| step.outputs.iter().map(move |a| (a.as_str(), idx)))
+            .collect()
+    }
+
+    pub fn step_index(&self, name: &str) -> PipelineResult<usize> {
+        self.steps
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| PipelineError::UnknownStep(name.to_string()))
+    }
+
+    /// Steps from `from` through `until`, both included, all of them by default
+    pub fn range(&self, from: Option<&str>, until: Option<&str>) -> PipelineResult<Range<usize>> {
+        let start = from.map(|s| self.step_index(s)).transpose()?.unwrap_or(0);
+        let end = match until {
+            Some(until) => self.step_index(until)? + 1,
+            None => self.steps.len(),
+        };
+        if start >= end && !self.steps.is_empty() {
+            return Err(PipelineError::EmptyRange {
+                from: from.unwrap_or_default().to_string(),
+                until: until.unwrap_or_default().to_string(),
+            });
+        }
+        Ok(start..end)
+    }
+
+    /// `text` with every `{artifact}` replaced by its path
+    fn substitute(&self, step: &Step, text: &str) -> PipelineResult<String> {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            let Some(close) = rest[open..].find('}') else {
+                break;
+            };
+            let name = &rest[open + 1..open + close];
+            let artifact =
+                self.artifacts
+                    .get(name)
+                    .ok_or_else(|| PipelineError::UnknownArtifact {
+                        step: step.name.clone(),
+                        artifact: name.to_string(),
+                    })?;
+            out.push_str(&rest[..open]);
+            out.push_str(&artifact.path().to_string_lossy());
+            rest = &rest[open + close + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn param_value(&self, step: &Step, param: &str, value: &toml::Value) -> PipelineResult<String> {
+        match value {
+            toml::Value::String(s) => self.substitute(step, s),
+            toml::Value::Integer(i) => Ok(i.to_string()),
+            toml::Value::Float(f) => Ok(f.to_string()),
+            toml::Value::Boolean(b) => Ok(b.to_string()),
+            toml::Value::Array(values) => {
+                let values = values
+                    .iter()
+                    .map(|v| match v {
+                        toml::Value::Array(_) => Err(PipelineError::Param {
+                            step: step.name.clone(),
+                            param: param.to_string(),
+                        }),
+                        v => self.param_value(step, param, v),
+                    })
+                    .collect::<PipelineResult<Vec<_>>>()?;
+                Ok(values.join(","))
+            }
+            _ => Err(PipelineError::Param {
+                step: step.name.clone(),
+                param: param.to_string(),
+            }),
+        }
+    }
+
+    /// Full command line of `step`, the program first
+    pub fn command_line(&self, step: &Step) -> PipelineResult<Vec<String>> {
+        let mut args = step
+            .command
+            .iter()
+            .map(|arg| self.substitute(step, arg))
+            .collect::<PipelineResult<Vec<_>>>()?;
+        for (param, value) in &step.params {
+            match value {
+                toml::Value::Boolean(true) => args.push(format!("--{}", param)),
+                toml::Value::Boolean(false) => {}
+                value => {
+                    args.push(format!("--{}", param));
+                    args.push(self.param_value(step, param, value)?);
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    pub fn record_path(&self, step: &Step) -> PathBuf {
+        self.state_dir.join(format!("{}.{}", step.name, RECORD_EXT))
+    }
+
+    pub fn log_path(&self, step: &Step) -> PathBuf {
+        self.state_dir.join(format!("{}.{}", step.name, LOG_EXT))
+    }
+}
+
+/// Left by a successful run of a step
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub command: Vec<String>,
+    /// Content digest of every input, by artifact
+    pub inputs: BTreeMap<String, String>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// Why a step runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    Forced,
+    NeverRun,
+    CommandChanged,
+    MissingOutput(String),
+    InputChanged(String),
+    /// `step`, which produces `artifact`, runs before
+    Upstream {
+        artifact: String,
+        step: String,
+    },
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Forced => write!(f, "forced"),
+            Reason::NeverRun => write!(f, "no run record"),
+            Reason::CommandChanged => write!(f, "command line changed"),
+            Reason::MissingOutput(artifact) => write!(f, "output {} is missing", artifact),
+            Reason::InputChanged(artifact) => write!(f, "input {} changed", artifact),
+            Reason::Upstream { artifact, step } => {
+                write!(f, "input {} is rebuilt by {}", artifact, step)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Run(Reason),
+    UpToDate,
+    /// Outside `--from` / `--until`
+    Excluded,
+}
+
+/// State of the artifacts and past runs a plan is resolved against
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub existing: BTreeSet<String>,
+    /// Content digest of existing artifacts read by a step, by artifact
+    pub digests: BTreeMap<String, String>,
+    pub records: BTreeMap<String, StepRecord>,
+}
+
+impl Snapshot {
+    pub fn load(plan: &Plan) -> PipelineResult<Self> {
+        let mut snapshot = Snapshot::default();
+        for (name, artifact) in &plan.artifacts {
+            if artifact.path().exists() {
+                snapshot.existing.insert(name.clone());
+            }
+        }
+        let read: BTreeSet<&String> = plan.steps.iter().flat_map(|s| &s.inputs).collect();
+        for name in read {
+            if snapshot.existing.contains(name) {
+                let digest = digest(plan.artifacts[name].path())?;
+                snapshot.digests.insert(name.clone(), digest);
+            }
+        }
+        for step in &plan.steps {
+            let path = plan.record_path(step);
+            let record = match fs::read(&path) {
+                Ok(record) => record,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let record = serde_json::from_slice(&record)
+                .map_err(|source| PipelineError::Record { path, source })?;
+            snapshot.records.insert(step.name.clone(), record);
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Decision for every step of the plan, steps outside `range` never run
+pub fn resolve(
+    plan: &Plan,
+    snapshot: &Snapshot,
+    range: Range<usize>,
+    force: bool,
+) -> PipelineResult<Vec<Decision>> {
+    let producers = plan.producers();
+    let mut decisions: Vec<Decision> = Vec::with_capacity(plan.steps.len());
+    for (idx, step) in plan.steps.iter().enumerate() {
+        if !range.contains(&idx) {
+            decisions.push(Decision::Excluded);
+            continue;
+        }
+        if force {
+            decisions.push(Decision::Run(Reason::Forced));
+            continue;
+        }
+        let upstream = step.inputs.iter().find_map(|artifact| {
+            let producer = *producers.get(artifact.as_str())?;
+            matches!(decisions[producer], Decision::Run(_)).then(|| Reason::Upstream {
+                artifact: artifact.clone(),
+                step: plan.steps[producer].name.clone(),
+            })
+        });
+        let reason = match (upstream, snapshot.records.get(&step.name)) {
+            (Some(upstream), _) => Some(upstream),
+            (None, None) => Some(Reason::NeverRun),
+            (None, Some(record)) if record.command != plan.command_line(step)? => {
+                Some(Reason::CommandChanged)
+            }
+            (None, Some(record)) => step
+                .outputs
+                .iter()
+                .find(|a| !snapshot.existing.contains(*a))
+                .map(|a| Reason::MissingOutput(a.clone()))
+                .or_else(|| {
+                    step.inputs
+                        .iter()
+                        .find(|a| snapshot.digests.get(*a) != record.inputs.get(*a))
+                        .map(|a| Reason::InputChanged(a.clone()))
+                }),
+        };
+        decisions.push(match reason {
+            Some(reason) => Decision::Run(reason),
+            None => Decision::UpToDate,
+        });
+    }
+    Ok(decisions)
+}
+
+/// SHA-1 of a file, or of the relative path and content of every file under a directory
+pub fn digest(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha1::new();
+    if path.is_dir() {
+        let mut files = Vec::new();
+        collect_files(path, &mut files)?;
+        files.sort();
+        for file in files {
+            let relative = file.strip_prefix(path).unwrap_or(&file);
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hash_file(&file, &mut hasher)?;
+        }
+    } else {
+        hash_file(path, &mut hasher)?;
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.is_dir() {
+            true => collect_files(&path, files)?,
+            false => files.push(path),
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path, hasher: &mut Sha1) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 1 << 16];
+    hasher.update(file.metadata()?.len().to_le_bytes());
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..read]);
+    }
+}
+
+/// Copies every line of `from` to `console` and `log`
+fn tee<R: Read + Send + 'static, W: Write + Send + 'static>(
+    from: R,
+    mut console: W,
+    log: Arc<Mutex<File>>,
+) -> std::thread::JoinHandle<io::Result<()>> {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(from);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            console.write_all(&line)?;
+            log.lock().unwrap().write_all(&line)?;
+            line.clear();
+        }
+        Ok(())
+    })
+}
+
+/// Runs step `idx`, its output echoed and logged, and records the run
+pub fn run_step(plan: &Plan, idx: usize) -> PipelineResult<StepRecord> {
+    let step = &plan.steps[idx];
+    let mut inputs = BTreeMap::new();
+    for artifact in &step.inputs {
+        let path = plan.artifacts[artifact].path();
+        if !path.exists() {
+            return Err(PipelineError::MissingInput {
+                step: step.name.clone(),
+                artifact: artifact.clone(),
+                path: path.to_path_buf(),
+            });
+        }
+        inputs.insert(artifact.clone(), digest(path)?);
+    }
+    for artifact in &step.outputs {
+        let artifact = &plan.artifacts[artifact];
+        match (artifact.is_dir(), artifact.path().parent()) {
+            (true, _) => fs::create_dir_all(artifact.path())?,
+            (false, Some(parent)) => fs::create_dir_all(parent)?,
+            (false, None) => {}
+        }
+    }
+    fs::create_dir_all(&plan.state_dir)?;
+    let command = plan.command_line(step)?;
+    let log_path = plan.log_path(step);
+    let log = Arc::new(Mutex::new(File::create(&log_path)?));
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .current_dir(&plan.base)
+        .env(WORK_DIR_ENV, &plan.base)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = tee(child.stdout.take().unwrap(), io::stdout(), log.clone());
+    let stderr = tee(child.stderr.take().unwrap(), io::stderr(), log);
+    let status = child.wait()?;
+    for handle in [stdout, stderr] {
+        handle.join().expect("tee thread panicked")?;
+    }
+    if !status.success() {
+        return Err(PipelineError::StepFailed {
+            step: step.name.clone(),
+            status,
+            log: log_path,
+        });
+    }
+    let record = StepRecord {
+        command,
+        inputs,
+        finished_at: Utc::now(),
+    };
+    let json = serde_json::to_vec_pretty(&record).expect("records serialize");
+    crate::atomic_write::atomic_write(plan.record_path(step), json)?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAN: &str = r#"
+state_dir = "state"
+
+[artifacts]
+raw = "raw.bin"
+explorer = "data/explorer.bin"
+eval = { path = "eval", dir = true }
+report = "report.json"
+
+[[step]]
+name = "import"
+command = ["import"]
+inputs = ["raw"]
+outputs = ["explorer"]
+params = { seed = 3, verbose = true, quiet = false, tags = ["a", "b"] }
+
+[[step]]
+name = "eval"
+command = ["eval", "{explorer}"]
+inputs = ["explorer"]
+outputs = ["eval"]
+params = { save-result-prefix = "{eval}/greedy" }
+
+[[step]]
+name = "report"
+command = ["report"]
+inputs = ["raw"]
+outputs = ["report"]
+"#;
+
+    fn plan() -> Plan {
+        Plan::parse(PLAN, Path::new("/work")).unwrap()
+    }
+
+    /// Every step ran with the current command line and inputs, every artifact exists
+    fn up_to_date(plan: &Plan) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for name in plan.artifacts.keys() {
+            snapshot.existing.insert(name.clone());
+            snapshot
+                .digests
+                .insert(name.clone(), format!("{}-v1", name));
+        }
+        for step in &plan.steps {
+            let record = StepRecord {
+                command: plan.command_line(step).unwrap(),
+                inputs: step
+                    .inputs
+                    .iter()
+                    .map(|a| (a.clone(), snapshot.digests[a].clone()))
+                    .collect(),
+                finished_at: Utc::now(),
+            };
+            snapshot.records.insert(step.name.clone(), record);
+        }
+        snapshot
+    }
+
+    fn reasons(decisions: &[Decision]) -> Vec<Option<String>> {
+        decisions
+            .iter()
+            .map(|d| match d {
+                Decision::Run(reason) => Some(reason.to_string()),
+                Decision::UpToDate => None,
+                Decision::Excluded => Some("excluded".to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_and_command_line() {
+        let plan = plan();
+        assert_eq!(plan.state_dir, Path::new("/work/state"));
+        assert!(plan.artifacts["eval"].is_dir());
+        assert_eq!(
+            plan.artifacts["explorer"].path(),
+            Path::new("/work/data/explorer.bin")
+        );
+        assert_eq!(
+            plan.command_line(&plan.steps[0]).unwrap(),
+            ["import", "--seed", "3", "--tags", "a,b", "--verbose"]
+        );
+        assert_eq!(
+            plan.command_line(&plan.steps[1]).unwrap(),
+            [
+                "eval",
+                "/work/data/explorer.bin",
+                "--save-result-prefix",
+                "/work/eval/greedy"
+            ]
+        );
+        assert_eq!(
+            plan.log_path(&plan.steps[1]),
+            Path::new("/work/state/eval.log")
+        );
+    }
+
+    #[test]
+    fn test_invalid_plans() {
+        let parse = |toml: &str| Plan::parse(toml, Path::new(".")).unwrap_err();
+        let artifacts = "[artifacts]\na = \"a\"\nb = \"b\"\n";
+        assert!(matches!(
+            parse(&format!(
+                "{}[[step]]\nname = \"x\"\ncommand = [\"x\"]\n[[step]]\nname = \"x\"\ncommand = [\"y\"]\n",
+                artifacts
+            )),
+            PipelineError::DuplicateStep(step) if step == "x"
+        ));
+        assert!(matches!(
+            parse(&format!(
+                "{}[[step]]\nname = \"x\"\ncommand = [\"x\"]\ninputs = [\"c\"]\n",
+                artifacts
+            )),
+            PipelineError::UnknownArtifact { artifact, .. } if artifact == "c"
+        ));
+        assert!(matches!(
+            parse(&format!(
+                "{}[[step]]\nname = \"x\"\ncommand = [\"x\"]\noutputs = [\"a\"]\n[[step]]\nname = \"y\"\ncommand = [\"y\"]\noutputs = [\"a\"]\n",
+                artifacts
+            )),
+            PipelineError::ProducedTwice { first, second, .. } if first == "x" && second == "y"
+        ));
+        assert!(matches!(
+            parse(&format!(
+                "{}[[step]]\nname = \"x\"\ncommand = [\"x\"]\ninputs = [\"a\"]\n[[step]]\nname = \"y\"\ncommand = [\"y\"]\noutputs = [\"a\"]\n",
+                artifacts
+            )),
+            PipelineError::ProducedLater { step, producer, .. } if step == "x" && producer == "y"
+        ));
+        assert!(matches!(
+            parse(&format!(
+                "{}[[step]]\nname = \"x\"\ncommand = [\"x\"]\ninputs = [\"a\"]\noutputs = [\"a\"]\n",
+                artifacts
+            )),
+            PipelineError::ProducedLater { .. }
+        ));
+        let plan = plan();
+        let mut step = plan.steps[0].clone();
+        step.command.push("{nope}".to_string());
+        assert!(matches!(
+            plan.command_line(&step),
+            Err(PipelineError::UnknownArtifact { artifact, .. }) if artifact == "nope"
+        ));
+    }
+
+    #[test]
+    fn test_range() {
+        let plan = plan();
+        assert_eq!(plan.range(None, None).unwrap(), 0..3);
+        assert_eq!(plan.range(Some("eval"), None).unwrap(), 1..3);
+        assert_eq!(plan.range(None, Some("eval")).unwrap(), 0..2);
+        assert_eq!(plan.range(Some("eval"), Some("eval")).unwrap(), 1..2);
+        assert!(matches!(
+            plan.range(Some("report"), Some("import")),
+            Err(PipelineError::EmptyRange { .. })
+        ));
+        assert!(matches!(
+            plan.range(Some("nope"), None),
+            Err(PipelineError::UnknownStep(_))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_fresh_and_up_to_date() {
+        let plan = plan();
+        let all = plan.range(None, None).unwrap();
+        let mut snapshot = Snapshot::default();
+        snapshot.existing.insert("raw".to_string());
+        assert_eq!(
+            reasons(&resolve(&plan, &snapshot, all.clone(), false).unwrap()),
+            [
+                Some("no run record".to_string()),
+                Some("input explorer is rebuilt by import".to_string()),
+                Some("no run record".to_string()),
+            ]
+        );
+        let snapshot = up_to_date(&plan);
+        assert_eq!(
+            resolve(&plan, &snapshot, all.clone(), false).unwrap(),
+            [Decision::UpToDate, Decision::UpToDate, Decision::UpToDate]
+        );
+        assert_eq!(
+            reasons(&resolve(&plan, &snapshot, 1..2, true).unwrap()),
+            [
+                Some("excluded".to_string()),
+                Some("forced".to_string()),
+                Some("excluded".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_staleness() {
+        let plan = plan();
+        let all = plan.range(None, None).unwrap();
+
+        // a changed source reruns its readers and what depends on them
+        let mut snapshot = up_to_date(&plan);
+        snapshot
+            .digests
+            .insert("raw".to_string(), "raw-v2".to_string());
+        assert_eq!(
+            reasons(&resolve(&plan, &snapshot, all.clone(), false).unwrap()),
+            [
+                Some("input raw changed".to_string()),
+                Some("input explorer is rebuilt by import".to_string()),
+                Some("input raw changed".to_string()),
+            ]
+        );
+        // ... unless the producer is left out of the range
+        assert_eq!(
+            resolve(&plan, &snapshot, 1..2, false).unwrap()[1],
+            Decision::UpToDate
+        );
+
+        let mut snapshot = up_to_date(&plan);
+        snapshot.existing.remove("eval");
+        assert_eq!(
+            reasons(&resolve(&plan, &snapshot, all.clone(), false).unwrap()),
+            [None, Some("output eval is missing".to_string()), None]
+        );
+
+        let mut snapshot = up_to_date(&plan);
+        snapshot.records.get_mut("report").unwrap().command = vec!["old".to_string()];
+        assert_eq!(
+            reasons(&resolve(&plan, &snapshot, all.clone(), false).unwrap()),
+            [None, None, Some("command line changed".to_string())]
+        );
+
+        // an input gone since the last run
+        let mut snapshot = up_to_date(&plan);
+        snapshot.digests.remove("explorer");
+        assert_eq!(
+            reasons(&resolve(&plan, &snapshot, all, false).unwrap()),
+            [None, Some("input explorer changed".to_string()), None]
+        );
+    }
+
+    #[test]
+    fn test_digest() {
+        let dir = std::env::temp_dir().join(format!("pipeline_digest_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.bin"), b"a").unwrap();
+        fs::write(dir.join("sub/b.bin"), b"b").unwrap();
+        let before = digest(&dir).unwrap();
+        assert_eq!(digest(&dir).unwrap(), before);
+        assert_ne!(digest(&dir.join("a.bin")).unwrap(), before);
+        fs::write(dir.join("sub/b.bin"), b"c").unwrap();
+        assert_ne!(digest(&dir).unwrap(), before);
+        fs::write(dir.join("sub/b.bin"), b"b").unwrap();
+        fs::rename(dir.join("sub/b.bin"), dir.join("sub/c.bin")).unwrap();
+        assert_ne!(digest(&dir).unwrap(), before);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_shipped_plans() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../assets/pipelines");
+        for name in ["fixtures.toml", "shadow.toml"] {
+            Plan::load(dir.join(name)).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        }
+        // the live run reads the report, so it never comes before the rehearsal
+        let shadow = Plan::load(dir.join("shadow.toml")).unwrap();
+        let names: Vec<&str> = shadow.steps.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["create-shadow", "rewrite-shadow", "apply"]);
+        assert!(
+            shadow.steps[2]
+                .inputs
+                .contains(&"shadow_report".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_step() {
+        let dir = std::env::temp_dir().join(format!("pipeline_run_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("in.txt"), b"hello").unwrap();
+        let toml = r#"
+[artifacts]
+input = "in.txt"
+output = "out/copy.txt"
+
+[[step]]
+name = "copy"
+command = ["sh", "-c", "echo copying && cp {input} {output}"]
+inputs = ["input"]
+outputs = ["output"]
+
+[[step]]
+name = "fail"
+command = ["sh", "-c", "echo broken >&2; exit 3"]
+"#;
+        let plan = Plan::parse(toml, &dir).unwrap();
+        let record = run_step(&plan, 0).unwrap();
+        assert_eq!(fs::read(dir.join("out/copy.txt")).unwrap(), b"hello");
+        assert_eq!(
+            fs::read_to_string(plan.log_path(&plan.steps[0])).unwrap(),
+            "copying\n"
+        );
+        let snapshot = Snapshot::load(&plan).unwrap();
+        assert_eq!(snapshot.records["copy"], record);
+        assert_eq!(
+            resolve(&plan, &snapshot, 0..1, false).unwrap(),
+            [Decision::UpToDate, Decision::Excluded]
+        );
+        match run_step(&plan, 1) {
+            Err(PipelineError::StepFailed { step, status, log }) => {
+                assert_eq!((step.as_str(), status.code()), ("fail", Some(3)));
+                assert_eq!(fs::read_to_string(log).unwrap(), "broken\n");
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(!plan.record_path(&plan.steps[1]).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
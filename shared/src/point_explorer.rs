@@ -1,15 +1,26 @@
+use crate::atomic_write::atomic_write;
 use crate::cosine_sim::{Cosine, cosine_sim};
+use crate::hamming::Hamming;
+#[cfg(feature = "shared-structure")]
 use crate::structure::{NekoPoint, NekoPointExt};
 use indexmap::IndexMap;
+#[cfg(feature = "ndarray")]
+use ndarray::Array2;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::borrow::Borrow;
+#[cfg(feature = "shared-structure")]
+use std::borrow::Cow;
 use std::collections::HashMap;
+#[cfg(feature = "shared-structure")]
+use std::collections::VecDeque;
 use std::fmt::{Debug, Display};
 use std::fs;
 use std::hash::Hash;
 use std::path::PathBuf;
+#[cfg(feature = "shared-structure")]
+use std::sync::Mutex;
 use url::Url;
 use uuid::Uuid;
 
@@ -26,6 +37,17 @@ pub enum PointExplorerError {
     BinCodeSerdeDecodeError(bincode::error::DecodeError),
     #[error("Point with ID {0} not found")]
     PointNotFound(Uuid),
+    #[error("Vector dimension mismatch: expected {expected}, got {got}")]
+    DimensionMismatch { expected: usize, got: usize },
+    #[error("{len} values from dimension {offset} do not fit in {dim} dimensions")]
+    RangeOutOfBounds {
+        offset: usize,
+        len: usize,
+        dim: usize,
+    },
+    #[cfg(feature = "migrations")]
+    #[error(transparent)]
+    MigrationError(#[from] crate::migrations::MigrationError),
 }
 
 pub type PointExplorerResult<T> = Result<T, PointExplorerError>;
@@ -34,7 +56,9 @@ pub type PointExplorerResult<T> = Result<T, PointExplorerError>;
 pub struct PointExplorerBuilder {
     capacity: Option<usize>,
     point_explorer_path: Option<String>,
+    #[cfg(feature = "shared-structure")]
     metadata_path: Option<String>,
+    #[cfg(feature = "shared-structure")]
     metadata_ext_path: Option<String>,
     point_uri_prefix_map: Option<HashMap<String, String>>,
 }
@@ -44,7 +68,9 @@ impl PointExplorerBuilder {
         Self {
             capacity: None,
             point_explorer_path: None,
+            #[cfg(feature = "shared-structure")]
             metadata_path: None,
+            #[cfg(feature = "shared-structure")]
             metadata_ext_path: None,
             point_uri_prefix_map: None,
         }
@@ -60,11 +86,13 @@ impl PointExplorerBuilder {
         self
     }
 
+    #[cfg(feature = "shared-structure")]
     pub fn metadata_path<P: Into<String>>(mut self, path: P) -> Self {
         self.metadata_path = Some(path.into());
         self
     }
 
+    #[cfg(feature = "shared-structure")]
     pub fn metadata_ext_path<P: Into<String>>(mut self, path: P) -> Self {
         self.metadata_ext_path = Some(path.into());
         self
@@ -100,9 +128,11 @@ impl PointExplorerBuilder {
         };
         // TODO: load builtin self.metadata_ext_path & self.point_explorer_path
         // TODO: overwrite warn (use tracing)
+        #[cfg(feature = "shared-structure")]
         if let Some(meta_path) = self.metadata_path {
             explorer.load_metadata(&meta_path)?;
         }
+        #[cfg(feature = "shared-structure")]
         if let Some(ext_path) = self.metadata_ext_path {
             explorer.load_metadata_ext(&ext_path)?;
         }
@@ -111,6 +141,145 @@ impl PointExplorerBuilder {
         }
         Ok(explorer)
     }
+
+    /// Same as [`PointExplorerBuilder::build`] with the dimension checked at runtime
+    pub fn build_dyn<T>(self, dim: usize) -> PointExplorerResult<DynPointExplorer<T>>
+    where
+        T: Copy + Debug + Default + Serialize + DeserializeOwned,
+    {
+        let mut explorer = if let Some(path) = self.point_explorer_path {
+            let explorer = DynPointExplorer::load(&path)?;
+            if explorer.dim() != dim {
+                return Err(PointExplorerError::DimensionMismatch {
+                    expected: dim,
+                    got: explorer.dim(),
+                });
+            }
+            explorer
+        } else {
+            DynPointExplorer::with_capacity(dim, self.capacity.unwrap_or_default())
+        };
+        #[cfg(feature = "shared-structure")]
+        if let Some(meta_path) = self.metadata_path {
+            explorer.load_metadata(&meta_path)?;
+        }
+        #[cfg(feature = "shared-structure")]
+        if let Some(ext_path) = self.metadata_ext_path {
+            explorer.load_metadata_ext(&ext_path)?;
+        }
+        if let Some(prefix) = self.point_uri_prefix_map {
+            explorer.load_points_uri_prefix(&prefix);
+        }
+        Ok(explorer)
+    }
+}
+
+#[cfg(feature = "shared-structure")]
+fn read_pickle_map<V: DeserializeOwned>(path: &str) -> PointExplorerResult<HashMap<Uuid, V>> {
+    let data = fs::read(path).map_err(|_| PointExplorerError::PathNotFound(path.to_string()))?;
+    serde_pickle::from_slice(&data, serde_pickle::DeOptions::default())
+        .map_err(PointExplorerError::SerdePickleError)
+}
+
+/// Looks up a point missing from the loaded metadata, e.g. in a points map or in Qdrant
+#[cfg(feature = "shared-structure")]
+pub type MetadataResolver = Box<dyn Fn(&Uuid) -> Option<NekoPoint> + Send + Sync>;
+
+/// Lookups of a [`MetadataResolver`] kept, found or not
+#[cfg(feature = "shared-structure")]
+pub const RESOLVED_METADATA_CACHE: usize = 256;
+
+/// Resolver over a points map, read here so a bad file fails the call instead of every lookup
+///
+/// Versioned maps go through [`crate::migrations`], a `.pkl` extension marks a pickled one.
+#[cfg(feature = "migrations")]
+pub fn points_map_resolver(path: &str) -> PointExplorerResult<MetadataResolver> {
+    let map: HashMap<Uuid, NekoPoint> = match path.ends_with(".pkl") {
+        true => read_pickle_map(path)?,
+        false => {
+            let data =
+                fs::read(path).map_err(|_| PointExplorerError::PathNotFound(path.to_string()))?;
+            crate::migrations::read_neko_points(&mut data.as_slice())?
+        }
+    };
+    Ok(Box::new(move |id| map.get(id).cloned()))
+}
+
+#[cfg(feature = "shared-structure")]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct MetadataStats {
+    /// Points in the loaded metadata, `None` if none was loaded
+    pub loaded: Option<usize>,
+    /// Resolver calls that found the point
+    pub resolved: usize,
+    /// Resolver calls that did not, the point is in neither
+    pub missing: usize,
+}
+
+#[cfg(feature = "shared-structure")]
+#[derive(Default)]
+struct ResolvedCache {
+    entries: HashMap<Uuid, Option<NekoPoint>>,
+    /// Least recently used first
+    order: VecDeque<Uuid>,
+    resolved: usize,
+    missing: usize,
+}
+
+#[cfg(feature = "shared-structure")]
+impl ResolvedCache {
+    fn touch(&mut self, id: &Uuid) {
+        if let Some(pos) = self.order.iter().position(|o| o == id) {
+            self.order.remove(pos);
+        }
+        self.order.push_back(*id);
+    }
+
+    fn insert(&mut self, id: Uuid, point: Option<NekoPoint>) {
+        if self.entries.len() == RESOLVED_METADATA_CACHE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.touch(&id);
+        self.entries.insert(id, point);
+    }
+}
+
+#[cfg(feature = "shared-structure")]
+struct ResolvedMetadata {
+    resolver: MetadataResolver,
+    cache: Mutex<ResolvedCache>,
+}
+
+#[cfg(feature = "shared-structure")]
+impl Debug for ResolvedMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cache = self.cache.lock().unwrap();
+        f.debug_struct("ResolvedMetadata")
+            .field("cached", &cache.entries.len())
+            .field("resolved", &cache.resolved)
+            .field("missing", &cache.missing)
+            .finish()
+    }
+}
+
+#[cfg(feature = "shared-structure")]
+impl ResolvedMetadata {
+    fn resolve(&self, id: &Uuid) -> Option<NekoPoint> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(point) = cache.entries.get(id).cloned() {
+            cache.touch(id);
+            return point;
+        }
+        let point = (self.resolver)(id);
+        match point {
+            Some(_) => cache.resolved += 1,
+            None => cache.missing += 1,
+        }
+        cache.insert(*id, point.clone());
+        point
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -119,6 +288,59 @@ enum PointUri {
     Url(Url),
 }
 
+/// `C:\`, `C:/`, bare `C:` and UNC `\\server\share` prefixes
+#[inline]
+fn is_windows_path(raw: &str) -> bool {
+    let bytes = raw.as_bytes();
+    let drive_letter = bytes.len() >= 2
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && (bytes.len() == 2 || bytes[2] == b'\\' || bytes[2] == b'/');
+    drive_letter || raw.starts_with("\\\\")
+}
+
+impl PointUri {
+    fn parse(raw: &str) -> Self {
+        // `Url::parse` happily treats a drive letter as a single-letter scheme
+        if is_windows_path(raw) {
+            return PointUri::Path(PathBuf::from(raw));
+        }
+        match Url::parse(raw) {
+            Ok(mut url) if !url.cannot_be_a_base() => {
+                // without a trailing slash `Url::join` would replace the last segment
+                if !url.path().ends_with('/') {
+                    let path = format!("{}/", url.path());
+                    url.set_path(&path);
+                }
+                PointUri::Url(url)
+            }
+            _ => PointUri::Path(PathBuf::from(raw)),
+        }
+    }
+
+    /// Only used by `get_point_uri`, which needs `shared-structure`
+    #[cfg_attr(not(feature = "shared-structure"), allow(dead_code))]
+    fn join(&self, filename: &str) -> Option<String> {
+        match self {
+            PointUri::Url(base) => base.join(filename).ok().map(|u| u.into()),
+            PointUri::Path(base) => {
+                let raw = base.to_string_lossy();
+                if is_windows_path(&raw) {
+                    // keep Windows prefixes stable regardless of the host separator
+                    let normalized = raw.replace('/', "\\");
+                    Some(format!(
+                        "{}\\{}",
+                        normalized.trim_end_matches('\\'),
+                        filename
+                    ))
+                } else {
+                    Some(base.join(filename).to_string_lossy().into_owned())
+                }
+            }
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[serde_as]
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -136,14 +358,19 @@ where
     point_uri_prefix: Option<PointUri>,
     #[serde(default)]
     point_uri_prefix_map: Option<HashMap<String, PointUri>>,
+    #[cfg(feature = "shared-structure")]
     #[serde(skip)]
     point_metadata: Option<HashMap<Uuid, NekoPoint>>,
     #[serde(default)]
     point_metadata_path: Option<PathBuf>,
+    #[cfg(feature = "shared-structure")]
     #[serde(skip)]
     point_metadata_ext: Option<HashMap<Uuid, NekoPointExt>>,
     #[serde(default)]
     point_metadata_ext_path: Option<PathBuf>,
+    #[cfg(feature = "shared-structure")]
+    #[serde(skip)]
+    metadata_resolver: Option<ResolvedMetadata>,
 }
 
 impl<T, const D: usize> Display for PointExplorer<T, D>
@@ -153,6 +380,7 @@ where
     for<'a> <[T; D] as TryFrom<&'a [T]>>::Error: Debug,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        #[cfg(feature = "shared-structure")]
         #[inline]
         fn display_hashmap<K, V>(path: &Option<PathBuf>, map: &Option<HashMap<K, V>>) -> String {
             format!(
@@ -165,12 +393,15 @@ where
             )
         }
 
-        f.debug_struct("PointExplorer")
+        let mut debug = f.debug_struct("PointExplorer");
+        debug
             .field(
                 "point_vector_map",
                 &format!("len = {}", self.point_vector_map.len()),
             )
-            .field("dim", &D)
+            .field("dim", &D);
+        #[cfg(feature = "shared-structure")]
+        debug
             .field(
                 "point_metadata",
                 &display_hashmap(&self.point_metadata_path, &self.point_metadata),
@@ -178,7 +409,12 @@ where
             .field(
                 "point_metadata_ext",
                 &display_hashmap(&self.point_metadata_ext_path, &self.point_metadata_ext),
-            )
+            );
+        #[cfg(not(feature = "shared-structure"))]
+        debug
+            .field("point_metadata_path", &self.point_metadata_path)
+            .field("point_metadata_ext_path", &self.point_metadata_ext_path);
+        debug
             .field("point_uri_prefix_map", &self.point_uri_prefix_map)
             .finish()
     }
@@ -197,12 +433,16 @@ where
     fn with_capacity(capacity: usize) -> Self {
         Self {
             point_vector_map: IndexMap::with_capacity(capacity),
+            #[cfg(feature = "shared-structure")]
             point_metadata: None,
             point_metadata_path: None,
+            #[cfg(feature = "shared-structure")]
             point_metadata_ext: None,
             point_metadata_ext_path: None,
             point_uri_prefix: None,
             point_uri_prefix_map: None,
+            #[cfg(feature = "shared-structure")]
+            metadata_resolver: None,
         }
     }
 
@@ -216,41 +456,11 @@ where
         Ok(explorer)
     }
 
-    fn load_metadata(&mut self, path: &str) -> PointExplorerResult<()> {
-        let data =
-            fs::read(path).map_err(|_| PointExplorerError::PathNotFound(path.to_string()))?;
-        let metadata: HashMap<Uuid, NekoPoint> =
-            serde_pickle::from_slice(&data, serde_pickle::DeOptions::default())
-                .map_err(PointExplorerError::SerdePickleError)?;
-        self.point_metadata = Some(metadata);
-        self.point_metadata_path = Some(PathBuf::from(path));
-        Ok(())
-    }
-
-    fn load_metadata_ext(&mut self, path: &str) -> PointExplorerResult<()> {
-        let data =
-            fs::read(path).map_err(|_| PointExplorerError::PathNotFound(path.to_string()))?;
-        let metadata_ext: HashMap<Uuid, NekoPointExt> =
-            serde_pickle::from_slice(&data, serde_pickle::DeOptions::default())
-                .map_err(PointExplorerError::SerdePickleError)?;
-        self.point_metadata_ext = Some(metadata_ext);
-        self.point_metadata_ext_path = Some(PathBuf::from(path));
-        Ok(())
-    }
-
     pub fn load_points_uri_prefix(&mut self, prefix: &HashMap<String, String>) {
         self.point_uri_prefix_map = Some(
             prefix
                 .iter()
-                .map(|(k, v)| {
-                    (
-                        k.to_owned(),
-                        match Url::parse(v) {
-                            Ok(url) if !url.cannot_be_a_base() => PointUri::Url(url),
-                            _ => PointUri::Path(PathBuf::from(v)),
-                        },
-                    )
-                })
+                .map(|(k, v)| (k.to_owned(), PointUri::parse(v)))
                 .collect(),
         );
     }
@@ -258,7 +468,7 @@ where
     pub fn save(&self, path: &str) -> PointExplorerResult<()> {
         let data = bincode::serde::encode_to_vec(self, bincode::config::standard())
             .map_err(PointExplorerError::BinCodeSerdeEncodeError)?;
-        fs::write(path, data).map_err(|_| PointExplorerError::PathNotFound(path.to_string()))?;
+        atomic_write(path, data).map_err(|_| PointExplorerError::PathNotFound(path.to_string()))?;
         Ok(())
     }
 
@@ -309,8 +519,443 @@ where
     }
 
     #[inline]
-    pub fn get_vector(&self, point_id: &Uuid) -> Option<&[T; D]> {
-        self.point_vector_map.get(point_id)
+    pub fn get_vector(&self, point_id: &Uuid) -> Option<&[T; D]> {
+        self.point_vector_map.get(point_id)
+    }
+
+    #[inline]
+    pub fn contains(&self, point_id: &Uuid) -> bool {
+        self.point_vector_map.contains_key(point_id)
+    }
+
+    #[inline]
+    pub fn remove(&mut self, point_id: &Uuid) -> Option<[T; D]> {
+        self.point_vector_map.shift_remove(point_id)
+    }
+
+    /// Overwrites the dimensions `offset..offset + values.len()` of one point in place
+    pub fn update_range(
+        &mut self,
+        point_id: &Uuid,
+        offset: usize,
+        values: &[T],
+    ) -> PointExplorerResult<()> {
+        let end = offset
+            .checked_add(values.len())
+            .filter(|&end| end <= D)
+            .ok_or(PointExplorerError::RangeOutOfBounds {
+                offset,
+                len: values.len(),
+                dim: D,
+            })?;
+        let vector = self
+            .point_vector_map
+            .get_mut(point_id)
+            .ok_or(PointExplorerError::PointNotFound(*point_id))?;
+        vector[offset..end].copy_from_slice(values);
+        Ok(())
+    }
+
+    /// Applies `f` to every vector in place, in insertion order
+    pub fn map_vectors_in_place(&mut self, f: impl Fn(&Uuid, &mut [T; D])) {
+        for (id, vector) in self.point_vector_map.iter_mut() {
+            f(id, vector);
+        }
+    }
+
+    /// Same points in the same order with every vector converted by `f`, loaded metadata and uri
+    /// prefixes are carried over but not the metadata resolver
+    pub fn map_vectors<U>(&self, f: impl Fn(&[T; D]) -> [U; D]) -> PointExplorer<U, D>
+    where
+        U: Copy + Debug + Default + Serialize + DeserializeOwned,
+        [U; D]: for<'a> TryFrom<&'a [U]>,
+        for<'a> <[U; D] as TryFrom<&'a [U]>>::Error: Debug,
+    {
+        PointExplorer {
+            point_vector_map: self
+                .point_vector_map
+                .iter()
+                .map(|(id, vector)| (*id, f(vector)))
+                .collect(),
+            point_uri_prefix: self.point_uri_prefix.clone(),
+            point_uri_prefix_map: self.point_uri_prefix_map.clone(),
+            #[cfg(feature = "shared-structure")]
+            point_metadata: self.point_metadata.clone(),
+            point_metadata_path: self.point_metadata_path.clone(),
+            #[cfg(feature = "shared-structure")]
+            point_metadata_ext: self.point_metadata_ext.clone(),
+            point_metadata_ext_path: self.point_metadata_ext_path.clone(),
+            #[cfg(feature = "shared-structure")]
+            metadata_resolver: None,
+        }
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.point_vector_map.clear();
+    }
+
+    #[inline]
+    pub fn index2uuid(&self, index: usize) -> Option<&Uuid> {
+        self.point_vector_map.get_index(index).map(|(id, _)| id)
+    }
+
+    #[inline]
+    pub fn uuid2index(&self, point_id: &Uuid) -> Option<usize> {
+        self.point_vector_map
+            .get_full(point_id)
+            .map(|(idx, _, _)| idx)
+    }
+}
+
+/// Point metadata, only with `shared-structure` which defines [`NekoPoint`]
+#[cfg(feature = "shared-structure")]
+impl<T, const D: usize> PointExplorer<T, D>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned,
+    [T; D]: for<'a> TryFrom<&'a [T]>,
+    for<'a> <[T; D] as TryFrom<&'a [T]>>::Error: Debug,
+{
+    fn load_metadata(&mut self, path: &str) -> PointExplorerResult<()> {
+        self.point_metadata = Some(read_pickle_map(path)?);
+        self.point_metadata_path = Some(PathBuf::from(path));
+        Ok(())
+    }
+
+    fn load_metadata_ext(&mut self, path: &str) -> PointExplorerResult<()> {
+        self.point_metadata_ext = Some(read_pickle_map(path)?);
+        self.point_metadata_ext_path = Some(PathBuf::from(path));
+        Ok(())
+    }
+
+    /// Loaded metadata of the point, else what the metadata resolver finds for it
+    pub fn get_point_metadata(&self, point_id: &Uuid) -> Option<Cow<'_, NekoPoint>> {
+        if let Some(point) = self.point_metadata.as_ref().and_then(|m| m.get(point_id)) {
+            return Some(Cow::Borrowed(point));
+        }
+        self.metadata_resolver
+            .as_ref()?
+            .resolve(point_id)
+            .map(Cow::Owned)
+    }
+
+    /// Consulted by [`Self::get_point_metadata`] for points missing from the loaded metadata,
+    /// the last [`RESOLVED_METADATA_CACHE`] lookups are cached
+    pub fn set_metadata_resolver(&mut self, resolver: MetadataResolver) {
+        self.metadata_resolver = Some(ResolvedMetadata {
+            resolver,
+            cache: Mutex::default(),
+        });
+    }
+
+    pub fn metadata_stats(&self) -> MetadataStats {
+        let (resolved, missing) = self.metadata_resolver.as_ref().map_or((0, 0), |r| {
+            let cache = r.cache.lock().unwrap();
+            (cache.resolved, cache.missing)
+        });
+        MetadataStats {
+            loaded: self.point_metadata.as_ref().map(HashMap::len),
+            resolved,
+            missing,
+        }
+    }
+
+    pub fn get_point_uri(&self, pm_prefix: &str, point_id: &Uuid) -> Option<String> {
+        let prefix = self.point_uri_prefix_map.as_ref()?.get(pm_prefix)?;
+        let point = self.point_metadata_ext.as_ref()?.get(point_id)?;
+        let filename = format!("{}.{}", point_id, point.ext());
+        prefix.join(&filename)
+    }
+}
+
+impl<T, const D: usize> PointExplorer<T, D>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned + Cosine,
+    [T; D]: for<'a> TryFrom<&'a [T]>,
+    for<'a> <[T; D] as TryFrom<&'a [T]>>::Error: Debug,
+{
+    pub fn get_cosine_sim(&self, point_id: (&Uuid, &Uuid)) -> PointExplorerResult<f32> {
+        let (id_a, id_b) = point_id;
+        let vector_a = self
+            .point_vector_map
+            .get(id_a)
+            .ok_or(PointExplorerError::PointNotFound(*id_a))?;
+        let vector_b = self
+            .point_vector_map
+            .get(id_b)
+            .ok_or(PointExplorerError::PointNotFound(*id_b))?;
+        Ok(cosine_sim(vector_a, vector_b))
+    }
+}
+
+impl<T, const D: usize> PointExplorer<T, D>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned + Hamming,
+    [T; D]: for<'a> TryFrom<&'a [T]>,
+    for<'a> <[T; D] as TryFrom<&'a [T]>>::Error: Debug,
+{
+    /// Differing bits of the two vectors, see [`crate::hamming`]
+    pub fn get_hamming_dist(&self, point_id: (&Uuid, &Uuid)) -> PointExplorerResult<u32> {
+        let (id_a, id_b) = point_id;
+        let vector_a = self
+            .point_vector_map
+            .get(id_a)
+            .ok_or(PointExplorerError::PointNotFound(*id_a))?;
+        let vector_b = self
+            .point_vector_map
+            .get(id_b)
+            .ok_or(PointExplorerError::PointNotFound(*id_b))?;
+        Ok(T::hamming(vector_a, vector_b))
+    }
+}
+
+impl<const D: usize> PointExplorer<u8, D>
+where
+    [u8; D]: for<'a> TryFrom<&'a [u8]>,
+    for<'a> <[u8; D] as TryFrom<&'a [u8]>>::Error: Debug,
+{
+    /// Points by vector, the points of every vector in explorer order
+    pub fn reverse_index(&self) -> HashMap<&[u8; D], Vec<Uuid>> {
+        let mut index: HashMap<&[u8; D], Vec<Uuid>> = HashMap::with_capacity(self.len());
+        for (id, vector) in self.point_vector_map.iter() {
+            index.entry(vector).or_default().push(*id);
+        }
+        index
+    }
+
+    /// Points sharing their whole vector with another one, e.g. byte-different files with the
+    /// same perceptual hash, in explorer order of their first member
+    pub fn exact_duplicate_groups(&self) -> Vec<Vec<Uuid>> {
+        let mut groups: Vec<Vec<Uuid>> = self
+            .reverse_index()
+            .into_values()
+            .filter(|ids| ids.len() > 1)
+            .collect();
+        groups.sort_by_cached_key(|ids| self.uuid2index(&ids[0]));
+        groups
+    }
+}
+
+/// Packs `rows` into one contiguous `(rows.len(), D)` buffer, casting every element on the way
+#[cfg(feature = "ndarray")]
+fn rows_to_array2<T, U, const D: usize>(rows: &[&[T; D]], cast: impl Fn(T) -> U) -> Array2<U>
+where
+    T: Copy,
+{
+    let mut data = Vec::with_capacity(rows.len() * D);
+    for row in rows {
+        data.extend(row.iter().map(|&x| cast(x)));
+    }
+    Array2::from_shape_vec((rows.len(), D), data).expect("every row is D long")
+}
+
+#[cfg(feature = "ndarray")]
+impl<T, const D: usize> PointExplorer<T, D>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned,
+    [T; D]: for<'a> TryFrom<&'a [T]>,
+    for<'a> <[T; D] as TryFrom<&'a [T]>>::Error: Debug,
+{
+    fn select_rows(&self, ids: &[Uuid]) -> (Vec<&[T; D]>, Vec<Uuid>) {
+        ids.iter()
+            .filter_map(|id| self.point_vector_map.get(id).map(|v| (v, *id)))
+            .unzip()
+    }
+
+    /// All vectors, row `i` is the point at [`Self::index2uuid`] `i`
+    pub fn to_array2(&self) -> Array2<T> {
+        let rows: Vec<&[T; D]> = self.point_vector_map.values().collect();
+        rows_to_array2(&rows, |x| x)
+    }
+
+    /// Vectors of `ids` in the given order, ids not in the explorer are skipped
+    ///
+    /// Returns the id of every row alongside the array.
+    pub fn select_to_array2(&self, ids: &[Uuid]) -> (Array2<T>, Vec<Uuid>) {
+        let (rows, row_ids) = self.select_rows(ids);
+        (rows_to_array2(&rows, |x| x), row_ids)
+    }
+}
+
+#[cfg(feature = "ndarray")]
+impl<const D: usize> PointExplorer<u8, D>
+where
+    [u8; D]: for<'a> TryFrom<&'a [u8]>,
+    for<'a> <[u8; D] as TryFrom<&'a [u8]>>::Error: Debug,
+{
+    /// [`Self::to_array2`] cast to `f32`, for consumers that only take floats
+    pub fn to_array2_f32(&self) -> Array2<f32> {
+        let rows: Vec<&[u8; D]> = self.point_vector_map.values().collect();
+        rows_to_array2(&rows, f32::from)
+    }
+
+    /// [`Self::select_to_array2`] cast to `f32`
+    pub fn select_to_array2_f32(&self, ids: &[Uuid]) -> (Array2<f32>, Vec<Uuid>) {
+        let (rows, row_ids) = self.select_rows(ids);
+        (rows_to_array2(&rows, f32::from), row_ids)
+    }
+}
+
+/// [`PointExplorer`] with the vector dimension chosen at runtime
+///
+/// Serialized with `dim` ahead of the rows, every row is checked against it on insert and load.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: DeserializeOwned",))]
+pub struct DynPointExplorer<T>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned,
+{
+    dim: usize,
+    point_vector_map: IndexMap<Uuid, Vec<T>>,
+    #[serde(default)]
+    point_uri_prefix_map: Option<HashMap<String, PointUri>>,
+    #[cfg(feature = "shared-structure")]
+    #[serde(skip)]
+    point_metadata: Option<HashMap<Uuid, NekoPoint>>,
+    #[serde(default)]
+    point_metadata_path: Option<PathBuf>,
+    #[cfg(feature = "shared-structure")]
+    #[serde(skip)]
+    point_metadata_ext: Option<HashMap<Uuid, NekoPointExt>>,
+    #[serde(default)]
+    point_metadata_ext_path: Option<PathBuf>,
+}
+
+impl<T> Display for DynPointExplorer<T>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DynPointExplorer")
+            .field(
+                "point_vector_map",
+                &format!("len = {}", self.point_vector_map.len()),
+            )
+            .field("dim", &self.dim)
+            .field("point_metadata_path", &self.point_metadata_path)
+            .field("point_metadata_ext_path", &self.point_metadata_ext_path)
+            .field("point_uri_prefix_map", &self.point_uri_prefix_map)
+            .finish()
+    }
+}
+
+impl<T> DynPointExplorer<T>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned,
+{
+    pub fn new(dim: usize) -> Self {
+        Self::with_capacity(dim, 0)
+    }
+
+    pub fn with_capacity(dim: usize, capacity: usize) -> Self {
+        Self {
+            dim,
+            point_vector_map: IndexMap::with_capacity(capacity),
+            point_uri_prefix_map: None,
+            #[cfg(feature = "shared-structure")]
+            point_metadata: None,
+            point_metadata_path: None,
+            #[cfg(feature = "shared-structure")]
+            point_metadata_ext: None,
+            point_metadata_ext_path: None,
+        }
+    }
+
+    pub fn load(path: &str) -> PointExplorerResult<Self> {
+        let data =
+            fs::read(path).map_err(|_| PointExplorerError::PathNotFound(path.to_string()))?;
+        let explorer: DynPointExplorer<T> =
+            bincode::serde::decode_from_slice(&data, bincode::config::standard())
+                .map_err(PointExplorerError::BinCodeSerdeDecodeError)?
+                .0;
+        if let Some(row) = explorer
+            .point_vector_map
+            .values()
+            .find(|row| row.len() != explorer.dim)
+        {
+            return Err(PointExplorerError::DimensionMismatch {
+                expected: explorer.dim,
+                got: row.len(),
+            });
+        }
+        Ok(explorer)
+    }
+
+    pub fn load_points_uri_prefix(&mut self, prefix: &HashMap<String, String>) {
+        self.point_uri_prefix_map = Some(
+            prefix
+                .iter()
+                .map(|(k, v)| (k.to_owned(), PointUri::parse(v)))
+                .collect(),
+        );
+    }
+
+    pub fn save(&self, path: &str) -> PointExplorerResult<()> {
+        let data = bincode::serde::encode_to_vec(self, bincode::config::standard())
+            .map_err(PointExplorerError::BinCodeSerdeEncodeError)?;
+        atomic_write(path, data).map_err(|_| PointExplorerError::PathNotFound(path.to_string()))?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.point_vector_map.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.point_vector_map.is_empty()
+    }
+
+    #[inline]
+    pub fn iter(&self) -> indexmap::map::Iter<'_, Uuid, Vec<T>> {
+        self.point_vector_map.iter()
+    }
+
+    #[inline]
+    fn check_dim(&self, slice: &[T]) -> PointExplorerResult<()> {
+        match slice.len() == self.dim {
+            true => Ok(()),
+            false => Err(PointExplorerError::DimensionMismatch {
+                expected: self.dim,
+                got: slice.len(),
+            }),
+        }
+    }
+
+    pub fn insert<K, V>(&mut self, key_like: K, vec_like: V) -> PointExplorerResult<()>
+    where
+        K: Borrow<Uuid>,
+        V: AsRef<[T]>,
+    {
+        let slice = vec_like.as_ref();
+        self.check_dim(slice)?;
+        self.point_vector_map
+            .insert(*key_like.borrow(), slice.to_vec());
+        Ok(())
+    }
+
+    /// Stops at the first mismatching row, rows before it stay inserted
+    pub fn extend<I, K, V>(&mut self, points: I) -> PointExplorerResult<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Borrow<Uuid>,
+        V: AsRef<[T]>,
+    {
+        let mut iter = points.into_iter();
+        let (_, higher) = iter.size_hint();
+        self.point_vector_map.reserve(higher.unwrap_or_default());
+        iter.try_for_each(|(key_like, vec_like)| self.insert(key_like, vec_like))
+    }
+
+    #[inline]
+    pub fn get_vector(&self, point_id: &Uuid) -> Option<&[T]> {
+        self.point_vector_map.get(point_id).map(Vec::as_slice)
     }
 
     #[inline]
@@ -319,7 +964,7 @@ where
     }
 
     #[inline]
-    pub fn remove(&mut self, point_id: &Uuid) -> Option<[T; D]> {
+    pub fn remove(&mut self, point_id: &Uuid) -> Option<Vec<T>> {
         self.point_vector_map.shift_remove(point_id)
     }
 
@@ -339,6 +984,24 @@ where
             .get_full(point_id)
             .map(|(idx, _, _)| idx)
     }
+}
+
+#[cfg(feature = "shared-structure")]
+impl<T> DynPointExplorer<T>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned,
+{
+    fn load_metadata(&mut self, path: &str) -> PointExplorerResult<()> {
+        self.point_metadata = Some(read_pickle_map(path)?);
+        self.point_metadata_path = Some(PathBuf::from(path));
+        Ok(())
+    }
+
+    fn load_metadata_ext(&mut self, path: &str) -> PointExplorerResult<()> {
+        self.point_metadata_ext = Some(read_pickle_map(path)?);
+        self.point_metadata_ext_path = Some(PathBuf::from(path));
+        Ok(())
+    }
 
     pub fn get_point_metadata(&self, point_id: &Uuid) -> Option<&NekoPoint> {
         self.point_metadata.as_ref()?.get(point_id)
@@ -348,43 +1011,114 @@ where
         let prefix = self.point_uri_prefix_map.as_ref()?.get(pm_prefix)?;
         let point = self.point_metadata_ext.as_ref()?.get(point_id)?;
         let filename = format!("{}.{}", point_id, point.ext());
-        match prefix {
-            PointUri::Url(base) => base.join(&filename).ok().map(|u| u.into()),
-            PointUri::Path(base) => {
-                let mut path = base.clone();
-                path.push(filename);
-                Some(path.to_string_lossy().into_owned())
-            }
-        }
+        prefix.join(&filename)
     }
 }
 
-impl<T, const D: usize> PointExplorer<T, D>
+impl<T> DynPointExplorer<T>
 where
     T: Copy + Debug + Default + Serialize + DeserializeOwned + Cosine,
-    [T; D]: for<'a> TryFrom<&'a [T]>,
-    for<'a> <[T; D] as TryFrom<&'a [T]>>::Error: Debug,
 {
     pub fn get_cosine_sim(&self, point_id: (&Uuid, &Uuid)) -> PointExplorerResult<f32> {
         let (id_a, id_b) = point_id;
         let vector_a = self
-            .point_vector_map
-            .get(id_a)
+            .get_vector(id_a)
             .ok_or(PointExplorerError::PointNotFound(*id_a))?;
         let vector_b = self
-            .point_vector_map
-            .get(id_b)
+            .get_vector(id_b)
             .ok_or(PointExplorerError::PointNotFound(*id_b))?;
         Ok(cosine_sim(vector_a, vector_b))
     }
 }
 
-// TODO: impl hamming distance for u8
+impl<T> DynPointExplorer<T>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned + Hamming,
+{
+    /// Differing bits of the two vectors, see [`crate::hamming`]
+    pub fn get_hamming_dist(&self, point_id: (&Uuid, &Uuid)) -> PointExplorerResult<u32> {
+        let (id_a, id_b) = point_id;
+        let vector_a = self
+            .get_vector(id_a)
+            .ok_or(PointExplorerError::PointNotFound(*id_a))?;
+        let vector_b = self
+            .get_vector(id_b)
+            .ok_or(PointExplorerError::PointNotFound(*id_b))?;
+        Ok(T::hamming(vector_a, vector_b))
+    }
+}
+
+impl<T, const D: usize> From<PointExplorer<T, D>> for DynPointExplorer<T>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned,
+    [T; D]: for<'a> TryFrom<&'a [T]>,
+    for<'a> <[T; D] as TryFrom<&'a [T]>>::Error: Debug,
+{
+    fn from(explorer: PointExplorer<T, D>) -> Self {
+        Self {
+            dim: D,
+            point_vector_map: explorer
+                .point_vector_map
+                .into_iter()
+                .map(|(id, arr)| (id, arr.to_vec()))
+                .collect(),
+            point_uri_prefix_map: explorer.point_uri_prefix_map,
+            #[cfg(feature = "shared-structure")]
+            point_metadata: explorer.point_metadata,
+            point_metadata_path: explorer.point_metadata_path,
+            #[cfg(feature = "shared-structure")]
+            point_metadata_ext: explorer.point_metadata_ext,
+            point_metadata_ext_path: explorer.point_metadata_ext_path,
+        }
+    }
+}
+
+impl<T, const D: usize> TryFrom<DynPointExplorer<T>> for PointExplorer<T, D>
+where
+    T: Copy + Debug + Default + Serialize + DeserializeOwned,
+    [T; D]: for<'a> TryFrom<&'a [T]>,
+    for<'a> <[T; D] as TryFrom<&'a [T]>>::Error: Debug,
+{
+    type Error = PointExplorerError;
+
+    fn try_from(explorer: DynPointExplorer<T>) -> Result<Self, Self::Error> {
+        if explorer.dim != D {
+            return Err(PointExplorerError::DimensionMismatch {
+                expected: D,
+                got: explorer.dim,
+            });
+        }
+        Ok(Self {
+            point_vector_map: explorer
+                .point_vector_map
+                .into_iter()
+                .map(|(id, row)| {
+                    let arr: [T; D] = row.as_slice().try_into().expect("Row length checked");
+                    (id, arr)
+                })
+                .collect(),
+            point_uri_prefix: None,
+            point_uri_prefix_map: explorer.point_uri_prefix_map,
+            #[cfg(feature = "shared-structure")]
+            point_metadata: explorer.point_metadata,
+            point_metadata_path: explorer.point_metadata_path,
+            #[cfg(feature = "shared-structure")]
+            point_metadata_ext: explorer.point_metadata_ext,
+            point_metadata_ext_path: explorer.point_metadata_ext_path,
+            #[cfg(feature = "shared-structure")]
+            metadata_resolver: None,
+        })
+    }
+}
 
 #[cfg(feature = "point-explorer-pyo3")]
 pub mod pyo3 {
-    use crate::point_explorer::{PointExplorer, PointExplorerBuilder, PointExplorerError};
-    use pyo3::exceptions::{PyIOError, PyKeyError, PyValueError};
+    use crate::point_explorer::{
+        DynPointExplorer, PointExplorer, PointExplorerBuilder, PointExplorerError,
+        points_map_resolver,
+    };
+    use pyo3::IntoPyObjectExt;
+    use pyo3::exceptions::{PyIOError, PyIndexError, PyKeyError, PyValueError};
     use pyo3::prelude::*;
     use pyo3_stub_gen::{define_stub_info_gatherer, derive::*};
 
@@ -404,6 +1138,13 @@ pub mod pyo3 {
                 PointExplorerError::PointNotFound(id) => {
                     PyKeyError::new_err(format!("Point with ID {} not found", id))
                 }
+                e @ PointExplorerError::DimensionMismatch { .. } => {
+                    PyValueError::new_err(e.to_string())
+                }
+                e @ PointExplorerError::RangeOutOfBounds { .. } => {
+                    PyIndexError::new_err(e.to_string())
+                }
+                e @ PointExplorerError::MigrationError(_) => PyValueError::new_err(e.to_string()),
             }
         }
     }
@@ -476,6 +1217,20 @@ pub mod pyo3 {
             let explorer = self.builder.clone().build::<u8, 128>()?;
             Ok(PyPointExplorerU8D128 { inner: explorer })
         }
+
+        /// `dtype` is one of `"f32"` / `"u8"`
+        pub fn build_dyn(&self, dtype: &str, dim: usize) -> PyResult<PyDynPointExplorer> {
+            let inner = match dtype {
+                "f32" => DynInner::F32(self.builder.clone().build_dyn::<f32>(dim)?),
+                "u8" => DynInner::U8(self.builder.clone().build_dyn::<u8>(dim)?),
+                _ => {
+                    return Err(PyValueError::new_err(format!(
+                        "Unsupported dtype: {dtype}, expected f32 or u8"
+                    )));
+                }
+            };
+            Ok(PyDynPointExplorer { inner })
+        }
     }
 
     macro_rules! py_point_explorer_impl {
@@ -511,6 +1266,17 @@ pub mod pyo3 {
                     Ok(self.inner.remove(&uuid).map(|v| v.to_vec()))
                 }
 
+                pub fn update_range(
+                    &mut self,
+                    point_id: &str,
+                    offset: usize,
+                    values: Vec<$scalar>,
+                ) -> PyResult<()> {
+                    let uuid = uuid::Uuid::parse_str(point_id)
+                        .map_err(|e| PyValueError::new_err(format!("Invalid UUID: {e}")))?;
+                    Ok(self.inner.update_range(&uuid, offset, &values)?)
+                }
+
                 pub fn clear(&mut self) {
                     self.inner.clear();
                 }
@@ -593,7 +1359,23 @@ pub mod pyo3 {
                 ) -> PyResult<Option<crate::structure::NekoPoint>> {
                     let uuid = uuid::Uuid::parse_str(point_id)
                         .map_err(|e| PyValueError::new_err(format!("Invalid UUID: {e}")))?;
-                    Ok(self.inner.get_point_metadata(&uuid).cloned())
+                    Ok(self
+                        .inner
+                        .get_point_metadata(&uuid)
+                        .map(std::borrow::Cow::into_owned))
+                }
+
+                /// Resolves points missing from the loaded metadata from a points map, versioned
+                /// or pickled
+                pub fn set_metadata_resolver_path(&mut self, path: &str) -> PyResult<()> {
+                    self.inner.set_metadata_resolver(points_map_resolver(path)?);
+                    Ok(())
+                }
+
+                /// `(loaded, resolved, missing)`, `loaded` is `None` without loaded metadata
+                pub fn metadata_stats(&self) -> (Option<usize>, usize, usize) {
+                    let stats = self.inner.metadata_stats();
+                    (stats.loaded, stats.resolved, stats.missing)
                 }
 
                 pub fn get_point_uri(
@@ -613,6 +1395,163 @@ pub mod pyo3 {
     py_point_explorer_impl!(PyPointExplorerU8D32, u8, 32);
     py_point_explorer_impl!(PyPointExplorerU8D128, u8, 128);
 
+    /// Class, dtype and dimension of every fixed size explorer above, for `shared.build_info()`
+    pub const FIXED_DIMS: &[(&str, &str, usize)] = &[
+        ("PyPointExplorerF32D768", "f32", 768),
+        ("PyPointExplorerU8D32", "u8", 32),
+        ("PyPointExplorerU8D128", "u8", 128),
+    ];
+
+    pub(crate) enum DynInner {
+        F32(DynPointExplorer<f32>),
+        U8(DynPointExplorer<u8>),
+    }
+
+    macro_rules! with_dyn_inner {
+        ($inner:expr, $e:ident => $body:expr) => {
+            match $inner {
+                DynInner::F32($e) => $body,
+                DynInner::U8($e) => $body,
+            }
+        };
+    }
+
+    fn parse_uuid(point_id: &str) -> PyResult<uuid::Uuid> {
+        uuid::Uuid::parse_str(point_id)
+            .map_err(|e| PyValueError::new_err(format!("Invalid UUID: {e}")))
+    }
+
+    /// Runtime-dimension explorer covering every dim for a given dtype
+    #[gen_stub_pyclass]
+    #[pyclass(module = "shared.point_explorer")]
+    pub struct PyDynPointExplorer {
+        pub(crate) inner: DynInner,
+    }
+
+    #[gen_stub_pymethods]
+    #[pymethods]
+    impl PyDynPointExplorer {
+        pub fn dim(&self) -> usize {
+            with_dyn_inner!(&self.inner, e => e.dim())
+        }
+
+        pub fn dtype(&self) -> &'static str {
+            match self.inner {
+                DynInner::F32(_) => "f32",
+                DynInner::U8(_) => "u8",
+            }
+        }
+
+        pub fn insert(&mut self, point_id: &str, vector: &Bound<'_, PyAny>) -> PyResult<()> {
+            let uuid = parse_uuid(point_id)?;
+            match &mut self.inner {
+                DynInner::F32(e) => e.insert(uuid, vector.extract::<Vec<f32>>()?)?,
+                DynInner::U8(e) => e.insert(uuid, vector.extract::<Vec<u8>>()?)?,
+            }
+            Ok(())
+        }
+
+        pub fn contains(&self, point_id: &str) -> PyResult<bool> {
+            let uuid = parse_uuid(point_id)?;
+            Ok(with_dyn_inner!(&self.inner, e => e.contains(&uuid)))
+        }
+
+        pub fn remove(&mut self, py: Python<'_>, point_id: &str) -> PyResult<PyObject> {
+            let uuid = parse_uuid(point_id)?;
+            with_dyn_inner!(&mut self.inner, e => e.remove(&uuid).into_py_any(py))
+        }
+
+        pub fn clear(&mut self) {
+            with_dyn_inner!(&mut self.inner, e => e.clear())
+        }
+
+        pub fn len(&self) -> usize {
+            with_dyn_inner!(&self.inner, e => e.len())
+        }
+
+        pub fn is_empty(&self) -> bool {
+            with_dyn_inner!(&self.inner, e => e.is_empty())
+        }
+
+        pub fn index2uuid(&self, index: usize) -> Option<String> {
+            with_dyn_inner!(&self.inner, e => e.index2uuid(index).map(|id| id.to_string()))
+        }
+
+        pub fn uuid2index(&self, point_id: &str) -> PyResult<Option<usize>> {
+            let uuid = parse_uuid(point_id)?;
+            Ok(with_dyn_inner!(&self.inner, e => e.uuid2index(&uuid)))
+        }
+
+        pub fn get_all_ids(&self) -> Vec<String> {
+            with_dyn_inner!(&self.inner, e => e.iter().map(|(id, _)| id.to_string()).collect())
+        }
+
+        pub fn get_vector(&self, py: Python<'_>, point_id: &str) -> PyResult<PyObject> {
+            let uuid = parse_uuid(point_id)?;
+            with_dyn_inner!(&self.inner, e => e.get_vector(&uuid).map(<[_]>::to_vec).into_py_any(py))
+        }
+
+        pub fn get_cosine_sim(&self, id_a: &str, id_b: &str) -> PyResult<f32> {
+            let (a, b) = (parse_uuid(id_a)?, parse_uuid(id_b)?);
+            match &self.inner {
+                DynInner::F32(e) => Ok(e.get_cosine_sim((&a, &b))?),
+                DynInner::U8(_) => Err(PyValueError::new_err(
+                    "Cosine similarity is not supported for u8",
+                )),
+            }
+        }
+
+        pub fn get_hamming_dist(&self, id_a: &str, id_b: &str) -> PyResult<u32> {
+            let (a, b) = (parse_uuid(id_a)?, parse_uuid(id_b)?);
+            match &self.inner {
+                DynInner::U8(e) => Ok(e.get_hamming_dist((&a, &b))?),
+                DynInner::F32(_) => Err(PyValueError::new_err(
+                    "Hamming distance is not supported for f32",
+                )),
+            }
+        }
+
+        pub fn get_point_metadata(
+            &self,
+            point_id: &str,
+        ) -> PyResult<Option<crate::structure::NekoPoint>> {
+            let uuid = parse_uuid(point_id)?;
+            Ok(with_dyn_inner!(&self.inner, e => e.get_point_metadata(&uuid).cloned()))
+        }
+
+        pub fn get_point_uri(&self, pm_key: &str, point_id: &str) -> PyResult<Option<String>> {
+            let uuid = parse_uuid(point_id)?;
+            Ok(with_dyn_inner!(&self.inner, e => e.get_point_uri(pm_key, &uuid)))
+        }
+
+        pub fn save(&self, path: &str) -> PyResult<()> {
+            Ok(with_dyn_inner!(&self.inner, e => e.save(path))?)
+        }
+
+        pub fn __len__(&self) -> usize {
+            self.len()
+        }
+
+        pub fn __bool__(&self) -> bool {
+            !self.is_empty()
+        }
+
+        pub fn __contains__(&self, point_id: &str) -> PyResult<bool> {
+            self.contains(point_id)
+        }
+
+        pub fn __repr__(&self) -> String {
+            with_dyn_inner!(&self.inner, e => format!("{}", e))
+        }
+
+        pub fn __iter__(slf: PyRef<'_, Self>) -> PyPointExplorerIterator {
+            PyPointExplorerIterator {
+                ids: slf.get_all_ids(),
+                index: 0,
+            }
+        }
+    }
+
     #[gen_stub_pyclass]
     #[pyclass(module = "shared.point_explorer")]
     pub struct PyPointExplorerIterator {
@@ -643,6 +1582,7 @@ pub mod pyo3 {
         m.add_class::<PyPointExplorerF32D768>()?;
         m.add_class::<PyPointExplorerU8D32>()?;
         m.add_class::<PyPointExplorerU8D128>()?;
+        m.add_class::<PyDynPointExplorer>()?;
         m.add_class::<PyPointExplorerIterator>()?;
         Ok(())
     }
@@ -664,6 +1604,44 @@ mod tests {
         v
     }
 
+    #[test]
+    fn exact_duplicate_groups() {
+        let mut explorer: PointExplorer<u8, 4> = PointExplorer::new();
+        let ids: Vec<Uuid> = (0..7).map(Uuid::from_u128).collect();
+        // 2 and 5 collide with 0, 4 with 1, 6 differs from 3 by a single bit
+        let vectors: [[u8; 4]; 7] = [
+            [1, 2, 3, 4],
+            [9, 9, 9, 9],
+            [1, 2, 3, 4],
+            [0, 0, 0, 0],
+            [9, 9, 9, 9],
+            [1, 2, 3, 4],
+            [0, 0, 0, 1],
+        ];
+        for (id, vector) in ids.iter().zip(vectors) {
+            explorer.insert(id, vector.to_vec());
+        }
+        assert_eq!(explorer.reverse_index().len(), 4);
+        assert_eq!(
+            explorer.reverse_index()[&[1, 2, 3, 4]],
+            [ids[0], ids[2], ids[5]]
+        );
+        assert_eq!(
+            explorer.exact_duplicate_groups(),
+            [vec![ids[0], ids[2], ids[5]], vec![ids[1], ids[4]]]
+        );
+        explorer.remove(&ids[4]);
+        assert_eq!(
+            explorer.exact_duplicate_groups(),
+            [vec![ids[0], ids[2], ids[5]]]
+        );
+        assert!(
+            PointExplorer::<u8, 4>::new()
+                .exact_duplicate_groups()
+                .is_empty()
+        );
+    }
+
     #[test]
     fn insert_and_similarity() {
         let mut explorer: PointExplorer<f32, 768> = PointExplorer::new();
@@ -707,6 +1685,88 @@ mod tests {
         assert_eq!(explorer.index2uuid(2), None);
     }
 
+    #[test]
+    fn test_update_range() {
+        let mut explorer: PointExplorer<f32, 768> = PointExplorer::new();
+        let id1 = Uuid::new_v4();
+        let id2 = Uuid::new_v4();
+        explorer.insert(id1, make_unit_vector(768, 0));
+        explorer.insert(id2, make_unit_vector(768, 767));
+        assert!(explorer.get_cosine_sim((&id1, &id2)).unwrap().abs() < EPS);
+        // a shared tail makes them half similar
+        explorer.update_range(&id1, 767, &[1.0]).unwrap();
+        let sim = explorer.get_cosine_sim((&id1, &id2)).unwrap();
+        assert!((sim - 1.0 / 2f32.sqrt()).abs() < EPS);
+        explorer.update_range(&id1, 0, &[0.0]).unwrap();
+        let sim = explorer.get_cosine_sim((&id1, &id2)).unwrap();
+        assert!((sim - 1.0).abs() < EPS);
+        explorer.update_range(&id2, 768, &[]).unwrap();
+        for (offset, len) in [(767, 2), (768, 1), (usize::MAX, 1)] {
+            let err = explorer
+                .update_range(&id2, offset, &vec![0.0; len])
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                PointExplorerError::RangeOutOfBounds { dim: 768, .. }
+            ));
+        }
+        assert_eq!(explorer.get_vector(&id2).unwrap()[767], 1.0);
+        let missing = Uuid::new_v4();
+        let err = explorer.update_range(&missing, 0, &[1.0]).unwrap_err();
+        assert!(matches!(err, PointExplorerError::PointNotFound(id) if id == missing));
+    }
+
+    #[test]
+    fn test_update_range_keeps_order() {
+        let mut explorer: PointExplorer<f32, 768> = PointExplorer::new();
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            explorer.insert(id, make_unit_vector(768, i));
+        }
+        explorer.update_range(&ids[0], 10, &[2.0, 3.0]).unwrap();
+        assert_eq!(explorer.uuid2index(&ids[0]), Some(0));
+        explorer.remove(&ids[0]);
+        assert!(matches!(
+            explorer.update_range(&ids[0], 0, &[1.0]),
+            Err(PointExplorerError::PointNotFound(_))
+        ));
+        // re-inserted points go last and start from the inserted vector
+        explorer.insert(ids[0], make_unit_vector(768, 0));
+        explorer.update_range(&ids[1], 0, &[1.0]).unwrap();
+        assert_eq!(explorer.index2uuid(0), Some(&ids[1]));
+        assert_eq!(explorer.index2uuid(2), Some(&ids[0]));
+        assert_eq!(explorer.get_vector(&ids[0]).unwrap()[10], 0.0);
+        let sim = explorer.get_cosine_sim((&ids[0], &ids[1])).unwrap();
+        assert!((sim - 1.0 / 2f32.sqrt()).abs() < EPS);
+    }
+
+    #[test]
+    fn test_map_vectors_in_place() {
+        let mut explorer: PointExplorer<f32, 768> = PointExplorer::new();
+        let id1 = Uuid::new_v4();
+        let id2 = Uuid::new_v4();
+        explorer.insert(id1, vec![2.0; 768]);
+        explorer.insert(id2, vec![3.0; 768]);
+        explorer.map_vectors_in_place(|id, vector| {
+            let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+            vector.iter_mut().for_each(|x| *x /= norm);
+            if *id == id2 {
+                vector[0] = 0.0;
+            }
+        });
+        let norm = |id| {
+            explorer
+                .get_vector(id)
+                .unwrap()
+                .iter()
+                .map(|x| x * x)
+                .sum::<f32>()
+        };
+        assert!((norm(&id1) - 1.0).abs() < 1e-4);
+        assert!((norm(&id2) - 767.0 / 768.0).abs() < 1e-4);
+        assert_eq!(explorer.index2uuid(1), Some(&id2));
+    }
+
     #[test]
     fn serialize_deserialize_simple() {
         let mut explorer: PointExplorer<f32, 768> = PointExplorer::new();
@@ -770,11 +1830,214 @@ mod tests {
         }
     }
 
+    #[test]
+    fn dyn_insert_dimension_mismatch() {
+        let mut explorer: DynPointExplorer<f32> = DynPointExplorer::new(1024);
+        let id = Uuid::new_v4();
+        explorer.insert(&id, vec![0.5; 1024]).unwrap();
+        let err = explorer.insert(Uuid::new_v4(), vec![0.5; 768]).unwrap_err();
+        assert!(matches!(
+            err,
+            PointExplorerError::DimensionMismatch {
+                expected: 1024,
+                got: 768
+            }
+        ));
+        let err = explorer
+            .extend([
+                (Uuid::new_v4(), vec![1.0; 1024]),
+                (Uuid::new_v4(), vec![1.0; 3]),
+            ])
+            .unwrap_err();
+        assert!(matches!(err, PointExplorerError::DimensionMismatch { .. }));
+        assert_eq!(explorer.len(), 2);
+        assert_eq!(explorer.get_vector(&id).map(|v| v.len()), Some(1024));
+    }
+
+    #[test]
+    fn dyn_save_load_round_trip() {
+        let mut explorer: DynPointExplorer<f32> = DynPointExplorer::new(1024);
+        let id1 = Uuid::new_v4();
+        let id2 = Uuid::new_v4();
+        explorer.insert(&id1, make_unit_vector(1024, 3)).unwrap();
+        explorer.insert(&id2, vec![1.0; 1024]).unwrap();
+        let path = std::env::temp_dir().join(format!("dyn_pe_{}.bin", Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        explorer.save(path).unwrap();
+        let loaded = PointExplorerBuilder::new()
+            .path(path)
+            .build_dyn::<f32>(1024)
+            .unwrap();
+        assert_eq!(loaded.dim(), 1024);
+        assert_eq!(loaded.index2uuid(1), Some(&id2));
+        assert_eq!(loaded.get_vector(&id1), explorer.get_vector(&id1));
+        assert_eq!(
+            loaded.get_cosine_sim((&id1, &id2)).unwrap(),
+            explorer.get_cosine_sim((&id1, &id2)).unwrap()
+        );
+        let err = PointExplorerBuilder::new()
+            .path(path)
+            .build_dyn::<f32>(768)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PointExplorerError::DimensionMismatch {
+                expected: 768,
+                got: 1024
+            }
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dyn_conversion_with_const_generic() {
+        let mut explorer: DynPointExplorer<f32> = DynPointExplorer::new(768);
+        let id1 = Uuid::new_v4();
+        let id2 = Uuid::new_v4();
+        explorer.insert(&id1, make_unit_vector(768, 0)).unwrap();
+        explorer.insert(&id2, vec![1.0; 768]).unwrap();
+        let expected = explorer.get_cosine_sim((&id1, &id2)).unwrap();
+        let fixed: PointExplorer<f32, 768> = explorer.try_into().unwrap();
+        assert_eq!(fixed.len(), 2);
+        assert_eq!(fixed.uuid2index(&id2), Some(1));
+        assert_eq!(fixed.get_cosine_sim((&id1, &id2)).unwrap(), expected);
+        let back = DynPointExplorer::from(fixed);
+        assert_eq!(back.dim(), 768);
+        assert_eq!(
+            back.get_vector(&id1),
+            Some(make_unit_vector(768, 0).as_slice())
+        );
+        let err = PointExplorer::<f32, 32>::try_from(back).unwrap_err();
+        assert!(matches!(
+            err,
+            PointExplorerError::DimensionMismatch {
+                expected: 32,
+                got: 768
+            }
+        ));
+    }
+
+    #[cfg(feature = "shared-structure")]
+    #[test]
+    fn test_metadata_resolver_cache() {
+        let loaded = Uuid::from_u128(1);
+        let resolvable = Uuid::from_u128(2);
+        let absent = Uuid::from_u128(3);
+        let point = |id| NekoPoint {
+            id,
+            height: 1,
+            width: 1,
+            categories: None,
+            text_info: None,
+            size: None,
+            qdrant_num_id: None,
+            extra: None,
+        };
+        let mut explorer: PointExplorer<u8, 32> = PointExplorer::new();
+        assert!(explorer.get_point_metadata(&loaded).is_none());
+        assert_eq!(explorer.metadata_stats(), MetadataStats::default());
+        explorer.point_metadata = Some(HashMap::from([(loaded, point(loaded))]));
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        explorer.set_metadata_resolver(Box::new(move |id| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            (*id == resolvable).then(|| point(*id))
+        }));
+        let calls = || calls.load(std::sync::atomic::Ordering::SeqCst);
+        // loaded points never reach the resolver
+        assert!(matches!(
+            explorer.get_point_metadata(&loaded),
+            Some(Cow::Borrowed(p)) if p.id == loaded
+        ));
+        assert_eq!(calls(), 0);
+        for _ in 0..3 {
+            assert_eq!(
+                explorer.get_point_metadata(&resolvable).unwrap().id,
+                resolvable
+            );
+            assert!(explorer.get_point_metadata(&absent).is_none());
+        }
+        // misses are cached too
+        assert_eq!(calls(), 2);
+        assert_eq!(
+            explorer.metadata_stats(),
+            MetadataStats {
+                loaded: Some(1),
+                resolved: 1,
+                missing: 1,
+            }
+        );
+    }
+
+    #[cfg(feature = "migrations")]
+    #[test]
+    fn test_points_map_resolver() {
+        let id = Uuid::from_u128(7);
+        let point = NekoPoint {
+            id,
+            height: 2,
+            width: 3,
+            categories: None,
+            text_info: None,
+            size: Some(4),
+            qdrant_num_id: None,
+            extra: None,
+        };
+        let dir = std::env::temp_dir().join(format!("points_map_resolver_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("points_map.bin");
+        let points = HashMap::from([(id, point)]);
+        fs::write(
+            &path,
+            crate::migrations::encode_neko_points(&points).unwrap(),
+        )
+        .unwrap();
+        let resolver = points_map_resolver(path.to_str().unwrap()).unwrap();
+        let resolved = resolver(&id).unwrap();
+        assert_eq!((resolved.id, resolved.size), (id, Some(4)));
+        assert!(resolver(&Uuid::from_u128(8)).is_none());
+        // a broken map fails the call, not every lookup
+        fs::write(&path, b"NKPOINTS\x01").unwrap();
+        assert!(points_map_resolver(path.to_str().unwrap()).is_err());
+        assert!(matches!(
+            points_map_resolver("/nonexistent/metadata.pkl"),
+            Err(PointExplorerError::PathNotFound(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "shared-structure")]
+    #[test]
+    fn test_metadata_resolver_evicts_least_recent() {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut explorer: PointExplorer<u8, 32> = PointExplorer::new();
+        explorer.set_metadata_resolver(Box::new(move |_| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            None
+        }));
+        let first = Uuid::from_u128(0);
+        for n in 0..RESOLVED_METADATA_CACHE as u128 {
+            explorer.get_point_metadata(&Uuid::from_u128(n));
+        }
+        // `first` is now the most recent, the second one gets evicted instead
+        explorer.get_point_metadata(&first);
+        explorer.get_point_metadata(&Uuid::from_u128(RESOLVED_METADATA_CACHE as u128));
+        explorer.get_point_metadata(&first);
+        let calls_before = calls.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(calls_before, RESOLVED_METADATA_CACHE + 1);
+        explorer.get_point_metadata(&Uuid::from_u128(1));
+        assert_eq!(
+            calls.load(std::sync::atomic::Ordering::SeqCst),
+            calls_before + 1
+        );
+        assert_eq!(explorer.metadata_stats().loaded, None);
+    }
+
     #[test]
     fn test_resource_prefix() {
         let url = "https://example.com/resources/";
         let unix_path = "/path/to/resources/";
-        // FIXME: currently, c:/xxx will be parsed as URL
         let windows_path = "C:\\path\\to\\resources\\";
         let pe = PointExplorerBuilder::new()
             .point_url_prefix("url", url)
@@ -795,4 +2058,125 @@ mod tests {
             Some(&PointUri::Path(PathBuf::from(windows_path)))
         );
     }
+
+    #[test]
+    fn test_point_uri_windows_drive_letter() {
+        for raw in ["C:\\path\\to\\resources\\", "c:/path/to/resources/", "D:"] {
+            assert_eq!(PointUri::parse(raw), PointUri::Path(PathBuf::from(raw)));
+        }
+        let uri = PointUri::parse("C:\\path\\to\\resources\\");
+        assert_eq!(uri.join("a.png").unwrap(), "C:\\path\\to\\resources\\a.png");
+        let mixed = PointUri::parse("C:/path/to/resources");
+        assert_eq!(
+            mixed.join("a.png").unwrap(),
+            "C:\\path\\to\\resources\\a.png"
+        );
+    }
+
+    #[test]
+    fn test_point_uri_windows_unc() {
+        let raw = "\\\\server\\share\\";
+        let uri = PointUri::parse(raw);
+        assert_eq!(uri, PointUri::Path(PathBuf::from(raw)));
+        assert_eq!(uri.join("a.png").unwrap(), "\\\\server\\share\\a.png");
+    }
+
+    #[test]
+    fn test_point_uri_file_url() {
+        let uri = PointUri::parse("file:///C:/path/to/resources/");
+        assert!(matches!(uri, PointUri::Url(_)));
+        assert_eq!(
+            uri.join("a.png").unwrap(),
+            "file:///C:/path/to/resources/a.png"
+        );
+    }
+
+    #[test]
+    fn test_point_uri_join_without_trailing_separator() {
+        let url = PointUri::parse("https://example.com/resources");
+        assert_eq!(
+            url.join("a.png").unwrap(),
+            "https://example.com/resources/a.png"
+        );
+        let unix = PointUri::parse("/path/to/resources");
+        assert_eq!(unix.join("a.png").unwrap(), "/path/to/resources/a.png");
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn test_to_array2() {
+        let mut explorer: PointExplorer<f32, 4> = PointExplorer::new();
+        let ids: Vec<Uuid> = (1..=3).map(Uuid::from_u128).collect();
+        for (i, id) in ids.iter().enumerate() {
+            explorer.insert(id, make_unit_vector(4, i));
+        }
+        let mut manual = Vec::new();
+        for (_, vector) in explorer.iter() {
+            manual.extend_from_slice(vector);
+        }
+        let expected = Array2::from_shape_vec((3, 4), manual).unwrap();
+        assert_eq!(explorer.to_array2(), expected);
+        // a missing id is skipped and the mapping follows the request order
+        let (arr, rows) = explorer.select_to_array2(&[ids[2], Uuid::from_u128(9), ids[0]]);
+        assert_eq!(rows, vec![ids[2], ids[0]]);
+        assert_eq!(arr.shape(), &[2, 4]);
+        for (row, id) in rows.iter().enumerate() {
+            assert_eq!(
+                arr.row(row).to_vec(),
+                explorer.get_vector(id).unwrap().to_vec()
+            );
+        }
+        let (empty, rows) = explorer.select_to_array2(&[]);
+        assert_eq!((empty.shape(), rows.len()), (&[0, 4][..], 0));
+    }
+
+    #[test]
+    fn test_get_hamming() {
+        let mut explorer: PointExplorer<u8, 32> = PointExplorer::new();
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut hash = [0u8; 32];
+        explorer.insert(a, hash);
+        hash[0] = 0b101;
+        hash[31] = 0xff;
+        explorer.insert(b, hash);
+        assert_eq!(explorer.get_hamming_dist((&a, &b)).unwrap(), 10);
+        assert_eq!(explorer.get_hamming_dist((&b, &b)).unwrap(), 0);
+        assert!(matches!(
+            explorer.get_hamming_dist((&a, &Uuid::from_u128(3))),
+            Err(PointExplorerError::PointNotFound(_))
+        ));
+        let explorer = DynPointExplorer::from(explorer);
+        assert_eq!(explorer.get_hamming_dist((&a, &b)).unwrap(), 10);
+        assert!(matches!(
+            explorer.get_hamming_dist((&Uuid::from_u128(3), &b)),
+            Err(PointExplorerError::PointNotFound(_))
+        ));
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn test_to_array2_f32() {
+        let mut explorer: PointExplorer<u8, 3> = PointExplorer::new();
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        explorer.insert(a, [0u8, 127, 255]);
+        explorer.insert(b, [1u8, 2, 3]);
+        let manual: Vec<f32> = explorer
+            .iter()
+            .flat_map(|(_, v)| v.iter().map(|&byte| byte as f32))
+            .collect();
+        assert_eq!(
+            explorer.to_array2_f32(),
+            Array2::from_shape_vec((2, 3), manual).unwrap()
+        );
+        let (arr, rows) = explorer.select_to_array2_f32(&[b, a]);
+        assert_eq!(rows, vec![b, a]);
+        assert_eq!(
+            arr,
+            Array2::from_shape_vec((2, 3), vec![1.0, 2.0, 3.0, 0.0, 127.0, 255.0]).unwrap()
+        );
+        assert_eq!(
+            explorer.select_to_array2(&[a]).0.row(0).to_vec(),
+            vec![0u8, 127, 255]
+        );
+    }
 }
@@ -0,0 +1,453 @@
+use crate::metrics::counter;
+use crate::opendal::GenShinOperator;
+use futures::FutureExt;
+use futures::future::{BoxFuture, Shared};
+use opendal::Buffer;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+
+/// Where a [`Prefetcher`] reads objects from, [`GenShinOperator`] outside of tests
+pub trait ObjectSource: Send + Sync + 'static {
+    fn fetch(&self, key: &str) -> impl Future<Output = anyhow::Result<Buffer>> + Send;
+}
+
+impl ObjectSource for GenShinOperator {
+    async fn fetch(&self, key: &str) -> anyhow::Result<Buffer> {
+        Ok(self.read(key).await?)
+    }
+}
+
+/// A failed fetch, shared by everyone waiting on it
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Prefetch of {key} failed: {message}")]
+pub struct PrefetchError {
+    pub key: String,
+    pub message: String,
+}
+
+type Fetch = Shared<BoxFuture<'static, Result<Buffer, PrefetchError>>>;
+
+struct Inflight {
+    id: u64,
+    fetch: Fetch,
+    /// Task driving a scheduled fetch, `None` when a [`Prefetcher::get`] started it
+    driver: Option<AbortHandle>,
+}
+
+/// Least recently used objects up to `capacity` bytes in total
+struct Lru {
+    capacity: usize,
+    bytes: usize,
+    tick: u64,
+    entries: HashMap<String, (Buffer, u64)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            bytes: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn get(&mut self, key: &str) -> Option<Buffer> {
+        self.tick += 1;
+        let (buf, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+        Some(buf.clone())
+    }
+
+    /// Objects larger than the whole cache are not kept
+    fn insert(&mut self, key: String, buf: Buffer) {
+        if buf.len() > self.capacity {
+            return;
+        }
+        if let Some((old, used)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+            self.bytes -= old.len();
+        }
+        while self.bytes + buf.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("bytes are cached");
+            let (evicted, _) = self
+                .entries
+                .remove(&oldest)
+                .expect("ordered keys are cached");
+            self.bytes -= evicted.len();
+        }
+        self.tick += 1;
+        self.bytes += buf.len();
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (buf, self.tick));
+    }
+}
+
+struct Inner {
+    cache: Lru,
+    inflight: HashMap<String, Inflight>,
+    next_id: u64,
+}
+
+/// How [`Prefetcher::get`] calls were answered so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefetchStats {
+    /// From the cache
+    pub hits: u64,
+    /// By joining a fetch already running
+    pub joined: u64,
+    /// By a fetch of their own
+    pub misses: u64,
+    /// Scheduled fetches dropped by [`Prefetcher::cancel`]
+    pub cancelled: u64,
+}
+
+impl PrefetchStats {
+    /// Share of gets that did not start a fetch
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.joined + self.misses {
+            0 => 0.0,
+            total => (self.hits + self.joined) as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    hits: AtomicU64,
+    joined: AtomicU64,
+    misses: AtomicU64,
+    cancelled: AtomicU64,
+}
+
+/// Fetches objects in the background ahead of their use
+///
+/// At most `concurrency` fetches run at once, finished objects stay in a LRU of `cache_bytes`,
+/// and concurrent requests for the same key share a single fetch. Failed fetches are not cached.
+/// [`Self::schedule`] spawns onto the current tokio runtime.
+pub struct Prefetcher<S> {
+    source: Arc<S>,
+    permits: Arc<Semaphore>,
+    inner: Arc<Mutex<Inner>>,
+    stats: Arc<Stats>,
+}
+
+impl<S> std::fmt::Debug for Prefetcher<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Prefetcher")
+            .field("cached_bytes", &self.inner.lock().unwrap().cache.bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: ObjectSource> Prefetcher<S> {
+    pub fn new(source: S, concurrency: usize, cache_bytes: usize) -> Self {
+        Self {
+            source: Arc::new(source),
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            inner: Arc::new(Mutex::new(Inner {
+                cache: Lru::new(cache_bytes),
+                inflight: HashMap::new(),
+                next_id: 0,
+            })),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Registers a fetch of `key` as in flight, the caller drives it
+    fn start(&self, inner: &mut Inner, key: &str) -> Fetch {
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let (source, permits, state) = (
+            self.source.clone(),
+            self.permits.clone(),
+            self.inner.clone(),
+        );
+        let owned = key.to_owned();
+        let fetch = async move {
+            let key = owned;
+            let res = {
+                let _permit = permits.acquire_owned().await.expect("never closed");
+                source.fetch(&key).await
+            };
+            let mut inner = state.lock().unwrap();
+            // a cancelled fetch may have been replaced by a newer one of the same key
+            if inner.inflight.get(&key).is_some_and(|f| f.id == id) {
+                inner.inflight.remove(&key);
+            }
+            match res {
+                Ok(buf) => {
+                    inner.cache.insert(key, buf.clone());
+                    Ok(buf)
+                }
+                Err(e) => Err(PrefetchError {
+                    key,
+                    message: format!("{:#}", e),
+                }),
+            }
+        }
+        .boxed()
+        .shared();
+        inner.inflight.insert(
+            key.to_owned(),
+            Inflight {
+                id,
+                fetch: fetch.clone(),
+                driver: None,
+            },
+        );
+        fetch
+    }
+
+    /// Starts fetching the keys neither cached nor in flight, returns how many were started
+    pub fn schedule<I, K>(&self, keys: I) -> usize
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<str>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let mut started = 0;
+        for key in keys {
+            let key = key.as_ref();
+            if inner.cache.contains(key) || inner.inflight.contains_key(key) {
+                continue;
+            }
+            let fetch = self.start(&mut inner, key);
+            let driver = tokio::spawn(fetch.map(|_| ())).abort_handle();
+            inner.inflight.get_mut(key).expect("just started").driver = Some(driver);
+            started += 1;
+        }
+        counter("prefetch_scheduled_total", &[]).add(started as u64);
+        started
+    }
+
+    /// The object of `key`, from the cache, a running fetch or a new one
+    pub async fn get(&self, key: &str) -> Result<Buffer, PrefetchError> {
+        let fetch = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(buf) = inner.cache.get(key) {
+                self.record(&self.stats.hits, "hit");
+                return Ok(buf);
+            }
+            match inner.inflight.get(key) {
+                Some(inflight) => {
+                    self.record(&self.stats.joined, "inflight");
+                    inflight.fetch.clone()
+                }
+                None => {
+                    self.record(&self.stats.misses, "miss");
+                    self.start(&mut inner, key)
+                }
+            }
+        };
+        fetch.await
+    }
+
+    /// Drops the scheduled fetches of `keys` still running, a [`Self::get`] already waiting on
+    /// one keeps it alive. Returns how many were dropped
+    pub fn cancel<I, K>(&self, keys: I) -> usize
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<str>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let mut cancelled = 0;
+        for key in keys {
+            let key = key.as_ref();
+            let Some(driver) = inner.inflight.get(key).and_then(|f| f.driver.clone()) else {
+                continue;
+            };
+            driver.abort();
+            inner.inflight.remove(key);
+            cancelled += 1;
+        }
+        self.stats
+            .cancelled
+            .fetch_add(cancelled as u64, Ordering::Relaxed);
+        counter("prefetch_cancelled_total", &[]).add(cancelled as u64);
+        cancelled
+    }
+
+    fn record(&self, stat: &AtomicU64, result: &str) {
+        stat.fetch_add(1, Ordering::Relaxed);
+        counter("prefetch_requests_total", &[("result", result)]).inc();
+    }
+
+    pub fn stats(&self) -> PrefetchStats {
+        PrefetchStats {
+            hits: self.stats.hits.load(Ordering::Relaxed),
+            joined: self.stats.joined.load(Ordering::Relaxed),
+            misses: self.stats.misses.load(Ordering::Relaxed),
+            cancelled: self.stats.cancelled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Bytes held by the cache
+    pub fn cached_bytes(&self) -> usize {
+        self.inner.lock().unwrap().cache.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    /// The memory backend behind a fixed latency, counting reads
+    struct Delayed {
+        op: GenShinOperator,
+        delay: Duration,
+        reads: Mutex<HashMap<String, usize>>,
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    impl Delayed {
+        async fn new(delay: Duration, objects: &[(&str, usize)]) -> Self {
+            let op = opendal::Operator::new(opendal::services::Memory::default())
+                .unwrap()
+                .finish();
+            for (key, len) in objects {
+                op.write(key, vec![0u8; *len]).await.unwrap();
+            }
+            Self {
+                op: GenShinOperator::from(op),
+                delay,
+                reads: Mutex::default(),
+                running: AtomicUsize::new(0),
+                max_running: AtomicUsize::new(0),
+            }
+        }
+
+        fn reads(&self, key: &str) -> usize {
+            self.reads.lock().unwrap().get(key).copied().unwrap_or(0)
+        }
+    }
+
+    impl ObjectSource for Delayed {
+        async fn fetch(&self, key: &str) -> anyhow::Result<Buffer> {
+            *self
+                .reads
+                .lock()
+                .unwrap()
+                .entry(key.to_owned())
+                .or_default() += 1;
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.op.fetch(key).await
+        }
+    }
+
+    const DELAY: Duration = Duration::from_millis(20);
+
+    #[tokio::test]
+    async fn test_scheduled_then_cached() {
+        let keys = ["a", "b", "c"];
+        let source = Delayed::new(DELAY, &[("a", 4), ("b", 4), ("c", 4)]).await;
+        let prefetcher = Prefetcher::new(source, 2, 1024);
+        assert_eq!(prefetcher.schedule(keys), 3);
+        // already in flight
+        assert_eq!(prefetcher.schedule(keys), 0);
+        for key in keys {
+            assert_eq!(prefetcher.get(key).await.unwrap().len(), 4);
+        }
+        for key in keys {
+            prefetcher.get(key).await.unwrap();
+            assert_eq!(prefetcher.source.reads(key), 1);
+        }
+        assert_eq!(prefetcher.schedule(keys), 0);
+        let stats = prefetcher.stats();
+        assert_eq!((stats.joined + stats.hits, stats.misses), (6, 0));
+        assert_eq!(stats.hit_rate(), 1.0);
+        assert_eq!(prefetcher.cached_bytes(), 12);
+        assert!(prefetcher.source.max_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_gets_share_a_fetch() {
+        let source = Delayed::new(DELAY, &[("a", 4)]).await;
+        let prefetcher = Prefetcher::new(source, 4, 1024);
+        let (x, y) = tokio::join!(prefetcher.get("a"), prefetcher.get("a"));
+        assert_eq!((x.unwrap().len(), y.unwrap().len()), (4, 4));
+        assert_eq!(prefetcher.source.reads("a"), 1);
+        let stats = prefetcher.stats();
+        assert_eq!((stats.hits, stats.joined, stats.misses), (0, 1, 1));
+        assert_eq!(stats.hit_rate(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let keys: Vec<String> = (0..6).map(|i| i.to_string()).collect();
+        let objects: Vec<(&str, usize)> = keys.iter().map(|k| (k.as_str(), 1)).collect();
+        let source = Delayed::new(DELAY, &objects).await;
+        let prefetcher = Prefetcher::new(source, 2, 1024);
+        prefetcher.schedule(&keys);
+        for key in &keys {
+            prefetcher.get(key).await.unwrap();
+        }
+        assert_eq!(prefetcher.source.max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let source = Delayed::new(DELAY, &[("a", 4), ("b", 4)]).await;
+        let prefetcher = Prefetcher::new(source, 4, 1024);
+        prefetcher.schedule(["a", "b"]);
+        // the spawned fetches have not been polled yet on this single threaded runtime
+        assert_eq!(prefetcher.cancel(["b", "missing"]), 1);
+        prefetcher.get("a").await.unwrap();
+        tokio::time::sleep(DELAY * 2).await;
+        assert_eq!(prefetcher.source.reads("b"), 0);
+        // fetched again on demand
+        assert_eq!(prefetcher.get("b").await.unwrap().len(), 4);
+        assert_eq!(prefetcher.source.reads("b"), 1);
+        let stats = prefetcher.stats();
+        assert_eq!((stats.cancelled, stats.misses), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetch_not_cached() {
+        let source = Delayed::new(Duration::ZERO, &[]).await;
+        let prefetcher = Prefetcher::new(source, 1, 1024);
+        let err = prefetcher.get("missing").await.unwrap_err();
+        assert_eq!(err.key, "missing");
+        assert!(prefetcher.get("missing").await.is_err());
+        assert_eq!(prefetcher.source.reads("missing"), 2);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let buf = |len: usize| Buffer::from(vec![0u8; len]);
+        let mut lru = Lru::new(10);
+        lru.insert("a".to_owned(), buf(4));
+        lru.insert("b".to_owned(), buf(4));
+        assert!(lru.get("a").is_some());
+        lru.insert("c".to_owned(), buf(4));
+        // b was used least recently
+        assert!(!lru.contains("b"));
+        assert!(lru.contains("a") && lru.contains("c"));
+        assert_eq!(lru.bytes, 8);
+        lru.insert("big".to_owned(), buf(11));
+        assert!(!lru.contains("big"));
+        lru.insert("a".to_owned(), buf(6));
+        assert_eq!(lru.bytes, 10);
+        lru.insert("d".to_owned(), buf(1));
+        assert!(!lru.contains("c"));
+        assert_eq!(lru.bytes, 7);
+    }
+}
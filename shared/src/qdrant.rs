@@ -1,8 +1,38 @@
+use crate::metrics::{counter, observe};
+use crate::op_ledger::{OpLedger, op_hash};
+use crate::stall::{StallConfig, StallError, for_each_watched};
+use crate::structure::{NekoPoint, key_num_id, num_id_key};
+use futures::{Stream, StreamExt, stream};
 use qdrant_client::config::CompressionEncoding;
-use qdrant_client::{Qdrant, QdrantBuilder, QdrantError};
+use qdrant_client::qdrant::point_id::PointIdOptions;
+use qdrant_client::qdrant::vectors_output::VectorsOptions as VectorsOptionsOutput;
+use qdrant_client::qdrant::with_payload_selector::SelectorOptions as PayloadSelectorOptions;
+use qdrant_client::qdrant::{
+    CollectionConfig, CountPointsBuilder, CreateCollectionBuilder,
+    CreateFieldIndexCollectionBuilder, DeletePointsBuilder, FieldType, GetPointsBuilder, ListValue,
+    PayloadIncludeSelector, PayloadSchemaInfo, PayloadSchemaType, PointId, PointStruct,
+    PointsIdsList, RetrievedPoint, ScrollPointsBuilder, SetPayloadPointsBuilder,
+    UpsertPointsBuilder, Value as QdrantValue, value,
+};
+#[cfg(feature = "qdrant-snapshot")]
+use qdrant_client::qdrant::{
+    DeleteSnapshotRequestBuilder, SnapshotDescription, SnapshotDownloadBuilder,
+};
+use qdrant_client::{Payload, Qdrant, QdrantBuilder, QdrantError};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
 use std::env;
+use std::fmt::{Display, Formatter};
 use std::ops::Deref;
-use std::time::Duration;
+#[cfg(feature = "qdrant-snapshot")]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+use uuid::Uuid;
 
 pub type QdrantResult<T> = Result<T, QdrantError>; // TODO: extend it using thiserror
 
@@ -32,3 +62,2834 @@ impl GenShinQdrantClient {
         Ok(GenShinQdrantClient(config.build()?))
     }
 }
+
+/// One point with its named vectors and payload, e.g. a line of a JSON lines export
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PointRecord {
+    pub id: Uuid,
+    /// Named vectors, e.g. `image_vector` and `text_contain_vector`
+    pub vectors: HashMap<String, Vec<f32>>,
+    pub payload: Map<String, Value>,
+}
+
+/// A collection, Qdrant in production, in memory in tests
+///
+/// The futures are only ever polled on the caller's task, so they need not be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait PointStore {
+    /// Inserts or overwrites the points, returning once they are persisted
+    async fn upsert(&self, points: &[PointRecord]) -> anyhow::Result<()>;
+    /// Fetches points with vectors and payload, unknown ids are left out
+    async fn get(&self, ids: &[Uuid]) -> anyhow::Result<Vec<PointRecord>>;
+    /// [`PointStore::get`] for callers that only need the payload, stores may leave out the vectors
+    async fn get_payloads(&self, ids: &[Uuid]) -> anyhow::Result<Vec<PointRecord>> {
+        self.get(ids).await
+    }
+    /// Merges `payload` into the payload of every point
+    async fn set_payload(&self, ids: &[Uuid], payload: &Map<String, Value>) -> anyhow::Result<()>;
+    async fn delete_points(&self, ids: &[Uuid]) -> anyhow::Result<()>;
+
+    /// `Some(at_least)` if retrying after `error` may succeed
+    fn retry_hint(&self, error: &anyhow::Error) -> Option<Duration> {
+        qdrant_retry_hint(error)
+    }
+}
+
+/// Throttling, timeouts, an unavailable node and I/O errors are worth a retry
+pub fn qdrant_retry_hint(error: &anyhow::Error) -> Option<Duration> {
+    retry_hint(error.downcast_ref::<QdrantError>()?)
+}
+
+fn retry_hint(error: &QdrantError) -> Option<Duration> {
+    match error {
+        QdrantError::ResourceExhaustedError {
+            retry_after_seconds,
+            ..
+        } => Some(Duration::from_secs(*retry_after_seconds)),
+        // DEADLINE_EXCEEDED, ABORTED, UNAVAILABLE
+        QdrantError::ResponseError { status } => {
+            matches!(i32::from(status.code()), 4 | 10 | 14).then_some(Duration::ZERO)
+        }
+        QdrantError::Io(_) => Some(Duration::ZERO),
+        _ => None,
+    }
+}
+
+/// A Qdrant point id, collections may hold both kinds
+///
+/// The rest of the pipeline keys points by `Uuid`, numeric ids by their
+/// [`num_id_key`](crate::structure::num_id_key). Convert back before addressing Qdrant, a numeric
+/// point looked up by the string of its key matches nothing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PointRef {
+    Uuid(Uuid),
+    Num(u64),
+}
+
+impl PointRef {
+    /// How Qdrant addresses `point`
+    pub fn of(point: &NekoPoint) -> Self {
+        match point.qdrant_num_id {
+            Some(num) => PointRef::Num(num),
+            None => PointRef::Uuid(point.id),
+        }
+    }
+
+    /// For keys without their [`NekoPoint`] at hand, see [`key_num_id`]
+    pub fn from_key(key: Uuid) -> Self {
+        match key_num_id(&key) {
+            Some(num) => PointRef::Num(num),
+            None => PointRef::Uuid(key),
+        }
+    }
+
+    pub fn key(&self) -> Uuid {
+        match self {
+            PointRef::Uuid(id) => *id,
+            PointRef::Num(num) => num_id_key(*num),
+        }
+    }
+}
+
+impl From<PointRef> for PointId {
+    fn from(point: PointRef) -> Self {
+        match point {
+            PointRef::Uuid(id) => id.to_string().into(),
+            PointRef::Num(num) => num.into(),
+        }
+    }
+}
+
+impl TryFrom<PointId> for PointRef {
+    type Error = String;
+
+    fn try_from(id: PointId) -> Result<Self, Self::Error> {
+        match id.point_id_options {
+            Some(PointIdOptions::Uuid(s)) => Uuid::parse_str(&s)
+                .map(PointRef::Uuid)
+                .map_err(|e| format!("invalid point uuid {}: {}", s, e)),
+            Some(PointIdOptions::Num(num)) => Ok(PointRef::Num(num)),
+            None => Err("point id without a value".to_owned()),
+        }
+    }
+}
+
+impl FromStr for PointRef {
+    type Err = String;
+
+    /// A UUID or, for numeric points, the decimal id
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<u64>() {
+            Ok(num) => Ok(PointRef::Num(num)),
+            Err(_) => Uuid::parse_str(s)
+                .map(PointRef::Uuid)
+                .map_err(|e| format!("invalid point id {}: {}", s, e)),
+        }
+    }
+}
+
+impl Display for PointRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PointRef::Uuid(id) => write!(f, "{}", id),
+            PointRef::Num(num) => write!(f, "{}", num),
+        }
+    }
+}
+
+pub fn points_ids_list<I: IntoIterator<Item = PointRef>>(points: I) -> PointsIdsList {
+    PointsIdsList {
+        ids: points.into_iter().map(PointId::from).collect(),
+    }
+}
+
+/// Id kinds of a set of points, only the compat layer is meant to handle both
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IdKindCounts {
+    pub uuid: usize,
+    pub num: usize,
+}
+
+impl IdKindCounts {
+    pub fn count<I: IntoIterator<Item = PointRef>>(points: I) -> Self {
+        points
+            .into_iter()
+            .fold(Self::default(), |mut counts, point| {
+                match point {
+                    PointRef::Uuid(_) => counts.uuid += 1,
+                    PointRef::Num(_) => counts.num += 1,
+                }
+                counts
+            })
+    }
+
+    pub fn is_mixed(&self) -> bool {
+        self.uuid > 0 && self.num > 0
+    }
+
+    /// Logs a warning naming `source` if both kinds are present
+    pub fn warn_if_mixed(&self, source: &str) {
+        if self.is_mixed() {
+            tracing::warn!(
+                "{} mixes point id types: {} uuid, {} numeric",
+                source,
+                self.uuid,
+                self.num
+            );
+        }
+    }
+}
+
+/// How payload extraction treated a field that was not in its expected shape
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadHandling {
+    /// Converted, e.g. a single category string into a one element list
+    Coerced,
+    /// Stored as null, read as absent
+    Null,
+    /// A required field is absent
+    Missing,
+    /// Unusable, read as absent
+    Dropped,
+    /// A list held items of another kind, they were left out
+    DroppedItems,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadAnomaly {
+    pub field: String,
+    /// Kind of the stored value, e.g. `string` or `list`
+    pub kind: String,
+    pub handling: PayloadHandling,
+}
+
+fn kind_name(kind: &Option<value::Kind>) -> &'static str {
+    match kind {
+        None | Some(value::Kind::NullValue(_)) => "null",
+        Some(value::Kind::DoubleValue(_)) => "double",
+        Some(value::Kind::IntegerValue(_)) => "integer",
+        Some(value::Kind::StringValue(_)) => "string",
+        Some(value::Kind::BoolValue(_)) => "bool",
+        Some(value::Kind::StructValue(_)) => "struct",
+        Some(value::Kind::ListValue(_)) => "list",
+    }
+}
+
+/// Every payload key [`PayloadReader::neko_fields`] reads, the projection stage2 fetches
+pub const NEKO_POINT_PAYLOAD_KEYS: [&str; 4] = ["height", "width", "categories", "ocr_text"];
+
+/// [`NEKO_POINT_PAYLOAD_KEYS`] followed by the `extra` keys not already in it
+pub fn payload_projection(extra: &[String]) -> Vec<String> {
+    let mut keys: Vec<String> = NEKO_POINT_PAYLOAD_KEYS.map(String::from).to_vec();
+    for key in extra {
+        if !keys.contains(key) {
+            keys.push(key.clone());
+        }
+    }
+    keys
+}
+
+/// Include-list selector of [`payload_projection`], large fields such as EXIF dumps stay behind
+pub fn payload_selector(extra: &[String]) -> PayloadSelectorOptions {
+    PayloadSelectorOptions::Include(PayloadIncludeSelector {
+        fields: payload_projection(extra),
+    })
+}
+
+/// Payload part of a [`NekoPoint`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NekoPayloadFields {
+    /// An unknown size reads as 0, the anomalies list those points
+    pub height: usize,
+    pub width: usize,
+    pub categories: Option<Vec<String>>,
+    pub ocr_text: Option<String>,
+}
+
+/// Reads payload fields whatever shape old points stored them in, recording every deviation
+pub struct PayloadReader<'a> {
+    payload: &'a HashMap<String, QdrantValue>,
+    anomalies: Vec<PayloadAnomaly>,
+}
+
+impl<'a> PayloadReader<'a> {
+    pub fn new(payload: &'a HashMap<String, QdrantValue>) -> Self {
+        PayloadReader {
+            payload,
+            anomalies: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, field: &str, kind: &Option<value::Kind>, handling: PayloadHandling) {
+        self.anomalies.push(PayloadAnomaly {
+            field: field.to_string(),
+            kind: kind_name(kind).to_string(),
+            handling,
+        });
+    }
+
+    /// Present, non-null value of `field`, a null is recorded
+    fn get(&mut self, field: &str) -> Option<&'a value::Kind> {
+        let kind = &self.payload.get(field)?.kind;
+        match kind {
+            None | Some(value::Kind::NullValue(_)) => {
+                self.record(field, kind, PayloadHandling::Null);
+                None
+            }
+            Some(kind) => Some(kind),
+        }
+    }
+
+    fn list_strings(&mut self, field: &str, list: &'a ListValue) -> Vec<&'a str> {
+        let strings: Vec<&str> = list
+            .values
+            .iter()
+            .filter_map(|item| match &item.kind {
+                Some(value::Kind::StringValue(s)) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect();
+        if strings.len() < list.values.len() {
+            let kind = Some(value::Kind::ListValue(list.clone()));
+            self.record(field, &kind, PayloadHandling::DroppedItems);
+        }
+        strings
+    }
+
+    /// A list of strings, or a single string as a one element list
+    pub fn strings(&mut self, field: &str) -> Option<Vec<String>> {
+        match self.get(field)? {
+            value::Kind::ListValue(list) => Some(
+                self.list_strings(field, list)
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            ),
+            kind @ value::Kind::StringValue(s) => {
+                self.record(field, &Some(kind.clone()), PayloadHandling::Coerced);
+                Some(vec![s.clone()])
+            }
+            kind => {
+                self.record(field, &Some(kind.clone()), PayloadHandling::Dropped);
+                None
+            }
+        }
+    }
+
+    /// A string, or a list of per-line strings joined by newlines
+    pub fn text(&mut self, field: &str) -> Option<String> {
+        match self.get(field)? {
+            value::Kind::StringValue(s) => Some(s.clone()),
+            kind @ value::Kind::ListValue(list) => {
+                self.record(field, &Some(kind.clone()), PayloadHandling::Coerced);
+                Some(self.list_strings(field, list).join("\n"))
+            }
+            kind => {
+                self.record(field, &Some(kind.clone()), PayloadHandling::Dropped);
+                None
+            }
+        }
+    }
+
+    /// A non-negative integer, also accepted as an integral double or a numeric string
+    ///
+    /// Required, its absence is recorded as well.
+    pub fn usize(&mut self, field: &str) -> Option<usize> {
+        if !self.payload.contains_key(field) {
+            self.record(field, &None, PayloadHandling::Missing);
+            return None;
+        }
+        let kind = self.get(field)?;
+        let (value, handling) = match kind {
+            value::Kind::IntegerValue(n) => (usize::try_from(*n).ok(), None),
+            value::Kind::DoubleValue(f) if f.fract() == 0.0 && *f >= 0.0 => {
+                (Some(*f as usize), Some(PayloadHandling::Coerced))
+            }
+            value::Kind::StringValue(s) => (s.trim().parse().ok(), Some(PayloadHandling::Coerced)),
+            _ => (None, None),
+        };
+        match value {
+            Some(_) => {
+                if let Some(handling) = handling {
+                    self.record(field, &Some(kind.clone()), handling);
+                }
+            }
+            None => self.record(field, &Some(kind.clone()), PayloadHandling::Dropped),
+        }
+        value
+    }
+
+    /// Reads exactly [`NEKO_POINT_PAYLOAD_KEYS`]
+    pub fn neko_fields(&mut self) -> NekoPayloadFields {
+        let [height, width, categories, ocr_text] = NEKO_POINT_PAYLOAD_KEYS;
+        NekoPayloadFields {
+            height: self.usize(height).unwrap_or(0),
+            width: self.usize(width).unwrap_or(0),
+            categories: self.strings(categories),
+            ocr_text: self.text(ocr_text),
+        }
+    }
+
+    /// The `keys` present in the payload as a JSON object, `None` if none is
+    pub fn extra(&self, keys: &[String]) -> Option<String> {
+        let extra: HashMap<String, QdrantValue> = keys
+            .iter()
+            .filter_map(|key| Some((key.clone(), self.payload.get(key)?.clone())))
+            .collect();
+        if extra.is_empty() {
+            return None;
+        }
+        let extra: Map<String, Value> = Payload::from(extra).into();
+        Some(Value::Object(extra).to_string())
+    }
+
+    pub fn into_anomalies(self) -> Vec<PayloadAnomaly> {
+        self.anomalies
+    }
+}
+
+/// [`PointStore`] of one Qdrant collection, every write waits for persistence
+pub struct QdrantPointStore {
+    client: GenShinQdrantClient,
+    collection_name: String,
+    /// Keys of the points Qdrant addresses by number
+    num_ids: HashMap<Uuid, u64>,
+}
+
+impl QdrantPointStore {
+    pub fn new(client: GenShinQdrantClient, collection_name: &str) -> Self {
+        Self {
+            client,
+            collection_name: collection_name.to_owned(),
+            num_ids: HashMap::new(),
+        }
+    }
+
+    /// Addresses these points numerically, every other key is sent as a UUID
+    pub fn with_num_ids<I: IntoIterator<Item = PointRef>>(mut self, points: I) -> Self {
+        self.num_ids
+            .extend(points.into_iter().filter_map(|point| match point {
+                PointRef::Num(num) => Some((point.key(), num)),
+                PointRef::Uuid(_) => None,
+            }));
+        self
+    }
+
+    /// Exact number of points in the collection
+    pub async fn count(&self) -> anyhow::Result<u64> {
+        let count = self
+            .client
+            .count(CountPointsBuilder::new(&self.collection_name).exact(true));
+        let resp = observe("qdrant", "count", count).await?;
+        Ok(resp.result.map(|r| r.count).unwrap_or_default())
+    }
+
+    fn point_ref(&self, id: &Uuid) -> PointRef {
+        match self.num_ids.get(id) {
+            Some(num) => PointRef::Num(*num),
+            None => PointRef::Uuid(*id),
+        }
+    }
+
+    fn point_ids(&self, ids: &[Uuid]) -> PointsIdsList {
+        points_ids_list(ids.iter().map(|id| self.point_ref(id)))
+    }
+
+    async fn fetch(&self, ids: &[Uuid], with_vectors: bool) -> anyhow::Result<Vec<PointRecord>> {
+        let ids: Vec<PointId> = ids.iter().map(|id| self.point_ref(id).into()).collect();
+        let get = self.client.get_points(
+            GetPointsBuilder::new(&self.collection_name, ids)
+                .with_vectors(with_vectors)
+                .with_payload(true),
+        );
+        let resp = observe("qdrant", "get_points", get).await?;
+        Ok(resp
+            .result
+            .into_iter()
+            .filter_map(|p| {
+                let id = PointRef::try_from(p.id?).ok()?.key();
+                let vectors = match p.vectors.and_then(|v| v.vectors_options) {
+                    Some(VectorsOptionsOutput::Vectors(named)) => named
+                        .vectors
+                        .into_iter()
+                        .map(|(name, v)| (name, v.data))
+                        .collect(),
+                    _ => Default::default(),
+                };
+                Some(PointRecord {
+                    id,
+                    vectors,
+                    payload: Payload::from(p.payload).into(),
+                })
+            })
+            .collect())
+    }
+}
+
+impl PointStore for QdrantPointStore {
+    async fn upsert(&self, points: &[PointRecord]) -> anyhow::Result<()> {
+        let points: Vec<PointStruct> = points
+            .iter()
+            .map(|p| {
+                PointStruct::new(
+                    self.point_ref(&p.id),
+                    p.vectors.clone(),
+                    Payload::from(p.payload.clone()),
+                )
+            })
+            .collect();
+        let upsert = self
+            .client
+            .upsert_points(UpsertPointsBuilder::new(&self.collection_name, points).wait(true));
+        observe("qdrant", "upsert_points", upsert).await?;
+        Ok(())
+    }
+
+    async fn get(&self, ids: &[Uuid]) -> anyhow::Result<Vec<PointRecord>> {
+        self.fetch(ids, true).await
+    }
+
+    async fn get_payloads(&self, ids: &[Uuid]) -> anyhow::Result<Vec<PointRecord>> {
+        self.fetch(ids, false).await
+    }
+
+    async fn set_payload(&self, ids: &[Uuid], payload: &Map<String, Value>) -> anyhow::Result<()> {
+        let set_payload = self.client.set_payload(
+            SetPayloadPointsBuilder::new(&self.collection_name, Payload::from(payload.clone()))
+                .points_selector(self.point_ids(ids))
+                .wait(true),
+        );
+        observe("qdrant", "set_payload", set_payload).await?;
+        Ok(())
+    }
+
+    async fn delete_points(&self, ids: &[Uuid]) -> anyhow::Result<()> {
+        let delete_points = self.client.delete_points(
+            DeletePointsBuilder::new(&self.collection_name)
+                .points(self.point_ids(ids))
+                .wait(true),
+        );
+        observe("qdrant", "delete_points", delete_points).await?;
+        Ok(())
+    }
+}
+
+/// Collection level operations, Qdrant in production, in memory in tests
+#[allow(async_fn_in_trait)]
+pub trait CollectionAdmin {
+    /// Everything needed to create an empty collection like an existing one
+    type Schema;
+
+    async fn collection_exists(&self, name: &str) -> anyhow::Result<bool>;
+    async fn collection_schema(&self, name: &str) -> anyhow::Result<Self::Schema>;
+    async fn create_collection(&self, name: &str, schema: &Self::Schema) -> anyhow::Result<()>;
+}
+
+/// Config and payload indexes of a Qdrant collection
+#[derive(Debug, Clone)]
+pub struct QdrantCollectionSchema {
+    pub config: CollectionConfig,
+    pub payload_schema: HashMap<String, PayloadSchemaInfo>,
+}
+
+/// `create_field_index` type of an indexed payload field as `collection_info` reports it
+fn field_type(data_type: i32) -> Option<FieldType> {
+    Some(match PayloadSchemaType::try_from(data_type).ok()? {
+        PayloadSchemaType::Keyword => FieldType::Keyword,
+        PayloadSchemaType::Integer => FieldType::Integer,
+        PayloadSchemaType::Float => FieldType::Float,
+        PayloadSchemaType::Geo => FieldType::Geo,
+        PayloadSchemaType::Text => FieldType::Text,
+        PayloadSchemaType::Bool => FieldType::Bool,
+        PayloadSchemaType::Datetime => FieldType::Datetime,
+        PayloadSchemaType::Uuid => FieldType::Uuid,
+        PayloadSchemaType::UnknownType => return None,
+    })
+}
+
+impl CollectionAdmin for GenShinQdrantClient {
+    type Schema = QdrantCollectionSchema;
+
+    async fn collection_exists(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(observe(
+            "qdrant",
+            "collection_exists",
+            self.0.collection_exists(name),
+        )
+        .await?)
+    }
+
+    async fn collection_schema(&self, name: &str) -> anyhow::Result<Self::Schema> {
+        let resp = observe("qdrant", "collection_info", self.0.collection_info(name)).await?;
+        let info = resp
+            .result
+            .ok_or_else(|| anyhow::anyhow!("No info returned for collection {}", name))?;
+        Ok(QdrantCollectionSchema {
+            config: info
+                .config
+                .ok_or_else(|| anyhow::anyhow!("Collection {} has no config", name))?,
+            payload_schema: info.payload_schema,
+        })
+    }
+
+    async fn create_collection(&self, name: &str, schema: &Self::Schema) -> anyhow::Result<()> {
+        let config = schema.config.clone();
+        let mut create = CreateCollectionBuilder::new(name);
+        if let Some(params) = config.params {
+            create = create
+                .shard_number(params.shard_number)
+                .on_disk_payload(params.on_disk_payload);
+            if let Some(vectors) = params.vectors_config {
+                create = create.vectors_config(vectors);
+            }
+            if let Some(sparse) = params.sparse_vectors_config {
+                create = create.sparse_vectors_config(sparse);
+            }
+            if let Some(factor) = params.replication_factor {
+                create = create.replication_factor(factor);
+            }
+            if let Some(factor) = params.write_consistency_factor {
+                create = create.write_consistency_factor(factor);
+            }
+            if let Some(method) = params.sharding_method {
+                create = create.sharding_method(method);
+            }
+        }
+        if let Some(hnsw) = config.hnsw_config {
+            create = create.hnsw_config(hnsw);
+        }
+        if let Some(optimizers) = config.optimizer_config {
+            create = create.optimizers_config(optimizers);
+        }
+        if let Some(wal) = config.wal_config {
+            create = create.wal_config(wal);
+        }
+        if let Some(quantization) = config.quantization_config.and_then(|q| q.quantization) {
+            create = create.quantization_config(quantization);
+        }
+        observe(
+            "qdrant",
+            "create_collection",
+            self.0.create_collection(create),
+        )
+        .await?;
+        for (field, info) in schema.payload_schema.iter() {
+            let field_type = field_type(info.data_type).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Payload index {} has unknown type {}",
+                    field,
+                    info.data_type
+                )
+            })?;
+            let mut index =
+                CreateFieldIndexCollectionBuilder::new(name, field, field_type).wait(true);
+            if let Some(params) = info.params.clone().and_then(|p| p.index_params) {
+                index = index.field_index_params(params);
+            }
+            observe(
+                "qdrant",
+                "create_field_index",
+                self.0.create_field_index(index),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Default REST port, the one next to the default gRPC port 6334
+#[cfg(feature = "qdrant-snapshot")]
+const REST_PORT: &str = "6333";
+#[cfg(feature = "qdrant-snapshot")]
+const SNAPSHOT_CHUNK: usize = 1 << 20;
+
+/// REST endpoint next to the gRPC `uri`, `QDRANT_REST_URL` when it is set
+///
+/// Snapshot files only go through the REST API. Without `QDRANT_REST_URL` the gRPC port is
+/// swapped for the REST one, which matches a default Qdrant deployment.
+#[cfg(feature = "qdrant-snapshot")]
+fn rest_url(uri: &str, configured: Option<String>) -> String {
+    let url = match configured {
+        Some(url) => url,
+        None => match uri.rsplit_once(':') {
+            Some((host, port)) if port.trim_end_matches('/').parse::<u16>().is_ok() => {
+                format!("{}:{}", host, REST_PORT)
+            }
+            _ => uri.to_owned(),
+        },
+    };
+    url.trim_end_matches('/').to_owned()
+}
+
+/// What goes before and after the file in a `multipart/form-data` body with a single `snapshot`
+/// field
+#[cfg(feature = "qdrant-snapshot")]
+fn multipart_frame(boundary: &str, file_name: &str) -> (Vec<u8>, Vec<u8>) {
+    let head = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"snapshot\"; filename=\"{}\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n",
+        boundary,
+        file_name.replace(['"', '\r', '\n'], "_")
+    );
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    (head.into_bytes(), tail.into_bytes())
+}
+
+/// `path` with `.part` appended, where a download goes until it is complete
+#[cfg(feature = "qdrant-snapshot")]
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    PathBuf::from(partial)
+}
+
+#[cfg(feature = "qdrant-snapshot")]
+impl GenShinQdrantClient {
+    pub fn rest_url(&self) -> String {
+        rest_url(&self.0.config.uri, env::var("QDRANT_REST_URL").ok())
+    }
+
+    /// Snapshots `collection` and downloads the snapshot to `path`
+    ///
+    /// The snapshot is deleted from the server once downloaded, `path` only appears when the
+    /// download is complete and has the size the server reported.
+    pub async fn export_snapshot<P: AsRef<Path>>(
+        &self,
+        collection: &str,
+        path: P,
+    ) -> anyhow::Result<SnapshotDescription> {
+        let path = path.as_ref();
+        let created = observe(
+            "qdrant",
+            "create_snapshot",
+            self.0.create_snapshot(collection),
+        )
+        .await?;
+        let snapshot = created.snapshot_description.ok_or_else(|| {
+            anyhow::anyhow!(
+                "No snapshot description returned for collection {}",
+                collection
+            )
+        })?;
+        let partial = partial_path(path);
+        let download = SnapshotDownloadBuilder::new(&partial, collection)
+            .snapshot_name(&snapshot.name)
+            .rest_api_uri(self.rest_url());
+        let downloaded = observe(
+            "qdrant",
+            "download_snapshot",
+            self.0.download_snapshot(download),
+        )
+        .await;
+        let deleted = observe(
+            "qdrant",
+            "delete_snapshot",
+            self.0.delete_snapshot(DeleteSnapshotRequestBuilder::new(
+                collection,
+                &snapshot.name,
+            )),
+        )
+        .await;
+        if let Err(e) = deleted {
+            tracing::warn!(
+                "Could not delete snapshot {} of {} from the server: {}",
+                snapshot.name,
+                collection,
+                e
+            );
+        }
+        downloaded?;
+        let size = std::fs::metadata(&partial)?.len();
+        if size as i64 != snapshot.size {
+            let _ = std::fs::remove_file(&partial);
+            anyhow::bail!(
+                "Snapshot {} of {} is {} bytes, downloaded {}",
+                snapshot.name,
+                collection,
+                snapshot.size,
+                size
+            );
+        }
+        std::fs::rename(&partial, path)?;
+        tracing::info!(
+            "Exported snapshot {} of {} to {} ({} bytes)",
+            snapshot.name,
+            collection,
+            path.display(),
+            size
+        );
+        Ok(snapshot)
+    }
+
+    /// Uploads the snapshot at `path` and recovers `collection` from it
+    ///
+    /// Everything in `collection` is replaced by the snapshot, a missing collection is created.
+    /// Returns once the recovery is done.
+    pub async fn import_snapshot<P: AsRef<Path>>(
+        &self,
+        collection: &str,
+        path: P,
+    ) -> anyhow::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let path = path.as_ref();
+        let file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| format!("{}.snapshot", collection));
+        let boundary = format!("neko-snapshot-{}", Uuid::new_v4().simple());
+        let (head, tail) = multipart_frame(&boundary, &file_name);
+        let chunks = stream::unfold(Some(file), |file| async move {
+            let mut file = file?;
+            let mut buf = vec![0u8; SNAPSHOT_CHUNK];
+            match file.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(buf), Some(file)))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        let body = stream::once(async { Ok(head) })
+            .chain(chunks)
+            .chain(stream::once(async { Ok(tail) }));
+        let url = format!(
+            "{}/collections/{}/snapshots/upload?wait=true&priority=snapshot",
+            self.rest_url(),
+            collection
+        );
+        let mut request = reqwest::Client::builder()
+            .timeout(self.0.config.timeout)
+            .build()?
+            .put(&url)
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(reqwest::Body::wrap_stream(body));
+        if let Some(key) = &self.0.config.api_key {
+            request = request.header("api-key", key.as_str());
+        }
+        let resp = observe("qdrant", "upload_snapshot", request.send()).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            anyhow::bail!(
+                "Recovering {} from {} failed with {}: {}",
+                collection,
+                path.display(),
+                status,
+                body
+            );
+        }
+        tracing::info!(
+            "Recovered {} from snapshot {} ({} bytes)",
+            collection,
+            path.display(),
+            size
+        );
+        Ok(())
+    }
+}
+
+/// One page of a scroll, `next` is where the following page starts, `None` after the last one
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrollPage<P, O> {
+    pub points: Vec<P>,
+    pub next: Option<O>,
+}
+
+/// Fetches the scroll page starting at an offset, Qdrant in production, scripted in tests
+#[allow(async_fn_in_trait)]
+pub trait PageSource {
+    type Point;
+    type Offset: Clone;
+
+    /// The first page for `None`
+    async fn fetch_page(
+        &self,
+        offset: Option<Self::Offset>,
+    ) -> QdrantResult<ScrollPage<Self::Point, Self::Offset>>;
+}
+
+/// [`PageSource`] of a [`ScrollPointsBuilder`] template, which sets the collection, filter and
+/// page size
+struct QdrantScroll<'a> {
+    client: &'a GenShinQdrantClient,
+    template: ScrollPointsBuilder,
+}
+
+impl PageSource for QdrantScroll<'_> {
+    type Point = RetrievedPoint;
+    type Offset = PointId;
+
+    async fn fetch_page(
+        &self,
+        offset: Option<PointId>,
+    ) -> QdrantResult<ScrollPage<RetrievedPoint, PointId>> {
+        let mut builder = self.template.clone();
+        if let Some(offset) = offset {
+            builder = builder.offset(offset);
+        }
+        let resp = observe("qdrant", "scroll", self.client.scroll(builder)).await?;
+        Ok(ScrollPage {
+            points: resp.result,
+            next: resp.next_page_offset,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScrollOptions {
+    /// Pages fetched at most, empty ones included
+    pub max_pages: Option<usize>,
+    /// Points yielded at most, the last page is cut short
+    pub limit: Option<usize>,
+    /// Transient failures in a row of one page before the scroll gives up on it
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled on every further one
+    pub retry_backoff: Duration,
+}
+
+impl Default for ScrollOptions {
+    fn default() -> Self {
+        Self {
+            max_pages: None,
+            limit: None,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+struct ScrollState<S: PageSource> {
+    source: S,
+    options: ScrollOptions,
+    offset: Option<S::Offset>,
+    pages: usize,
+    points: usize,
+    failures: u32,
+    wait: Option<Duration>,
+    done: bool,
+}
+
+impl<S: PageSource> ScrollState<S> {
+    fn exhausted(&self) -> bool {
+        self.done
+            || self.options.max_pages.is_some_and(|max| self.pages >= max)
+            || self.options.limit.is_some_and(|limit| self.points >= limit)
+    }
+}
+
+/// Non-empty pages of `source` in order, following the offsets until the last page
+///
+/// A transient error (see [`qdrant_retry_hint`]) is yielded and the same page fetched again after
+/// a backoff, any other error, or the `max_retries + 1`-th in a row, is yielded last.
+pub fn paginate<S: PageSource>(
+    source: S,
+    options: ScrollOptions,
+) -> impl Stream<Item = QdrantResult<Vec<S::Point>>> {
+    let state = ScrollState {
+        source,
+        options,
+        offset: None,
+        pages: 0,
+        points: 0,
+        failures: 0,
+        wait: None,
+        done: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if state.exhausted() {
+                return None;
+            }
+            if let Some(wait) = state.wait.take() {
+                tokio::time::sleep(wait).await;
+            }
+            match state.source.fetch_page(state.offset.clone()).await {
+                Ok(page) => {
+                    state.failures = 0;
+                    state.pages += 1;
+                    let mut points = page.points;
+                    if let Some(limit) = state.options.limit {
+                        points.truncate(limit - state.points);
+                    }
+                    state.points += points.len();
+                    state.done = page.next.is_none();
+                    state.offset = page.next;
+                    if !points.is_empty() {
+                        return Some((Ok(points), state));
+                    }
+                }
+                Err(e) => {
+                    state.failures += 1;
+                    match retry_hint(&e) {
+                        Some(hint) if state.failures <= state.options.max_retries => {
+                            let backoff = state
+                                .options
+                                .retry_backoff
+                                .saturating_mul(1 << (state.failures - 1).min(16))
+                                .max(hint);
+                            tracing::warn!(
+                                "Scroll page {} failed on attempt {}, retrying in {:?}: {}",
+                                state.pages,
+                                state.failures,
+                                backoff,
+                                e
+                            );
+                            counter("qdrant_retries_total", &[("op", "scroll")]).inc();
+                            state.wait = Some(backoff);
+                        }
+                        _ => state.done = true,
+                    }
+                    return Some((Err(e), state));
+                }
+            }
+        }
+    })
+}
+
+impl GenShinQdrantClient {
+    /// Pages of the scroll `template` describes, see [`paginate`], the offset is managed here
+    pub fn scroll_pages(
+        &self,
+        template: ScrollPointsBuilder,
+        options: ScrollOptions,
+    ) -> impl Stream<Item = QdrantResult<Vec<RetrievedPoint>>> + '_ {
+        paginate(
+            QdrantScroll {
+                client: self,
+                template,
+            },
+            options,
+        )
+    }
+
+    /// [`GenShinQdrantClient::scroll_pages`] one point at a time, errors in between
+    pub fn scroll_stream(
+        &self,
+        template: ScrollPointsBuilder,
+        options: ScrollOptions,
+    ) -> impl Stream<Item = QdrantResult<RetrievedPoint>> + '_ {
+        self.scroll_pages(template, options).flat_map(|page| {
+            let (points, error) = match page {
+                Ok(points) => (points, None),
+                Err(e) => (Vec::new(), Some(Err(e))),
+            };
+            stream::iter(points.into_iter().map(Ok).chain(error))
+        })
+    }
+}
+
+/// One mutation submitted to [`QdrantWriteScheduler`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    SetPayload {
+        points: Vec<Uuid>,
+        payload: Map<String, Value>,
+    },
+    DeletePoints {
+        points: Vec<Uuid>,
+    },
+}
+
+impl WriteOp {
+    pub fn points(&self) -> &[Uuid] {
+        match self {
+            WriteOp::SetPayload { points, .. } | WriteOp::DeletePoints { points } => points,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            WriteOp::SetPayload { .. } => "set_payload",
+            WriteOp::DeletePoints { .. } => "delete_points",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteStatus {
+    Written,
+    /// Nothing was sent, see [`WriteSchedulerConfig::dry_run`]
+    DryRun,
+    /// `points` are those of the failed requests, the others went through
+    Failed {
+        error: String,
+        points: Vec<Uuid>,
+    },
+    /// The run aborted on a stall before every request of the operation finished
+    NotAttempted,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteOutcome {
+    /// Position of the operation in the submitted list
+    pub op: usize,
+    pub status: WriteStatus,
+    /// Requests sent, retries included
+    pub attempts: u32,
+    /// Points left out as already applied, see [`QdrantWriteScheduler::with_ledger`]
+    pub skipped: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteSchedulerConfig {
+    /// Most points per request, larger operations are split
+    pub batch_size: usize,
+    /// Requests in flight
+    pub concurrency: usize,
+    /// Retries of a request failing with a transient error, see [`PointStore::retry_hint`]
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled on every further one
+    pub retry_backoff: Duration,
+    /// Requests started per second across all workers, retries included
+    pub max_requests_per_sec: Option<f64>,
+    /// Log the operations instead of sending them
+    pub dry_run: bool,
+    pub stall: StallConfig,
+}
+
+impl Default for WriteSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 256,
+            concurrency: 16,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            max_requests_per_sec: None,
+            dry_run: false,
+            stall: StallConfig::default(),
+        }
+    }
+}
+
+/// Hands out evenly spaced start times
+struct Pacer {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl Pacer {
+    fn new(per_sec: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / per_sec),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self) {
+        let at = {
+            let mut next = self.next.lock().unwrap();
+            let at = (*next).max(Instant::now());
+            *next = at + self.interval;
+            at
+        };
+        tokio::time::sleep_until(tokio::time::Instant::from_std(at)).await;
+    }
+}
+
+/// Chunking, bounded concurrency, rate limiting and retries around [`PointStore`] writes
+pub struct QdrantWriteScheduler<S> {
+    store: S,
+    config: WriteSchedulerConfig,
+    pacer: Option<Pacer>,
+    ledger: Option<Mutex<OpLedger>>,
+}
+
+impl<S: PointStore> QdrantWriteScheduler<S> {
+    pub fn new(store: S, config: WriteSchedulerConfig) -> Self {
+        let pacer = config
+            .max_requests_per_sec
+            .filter(|rate| *rate > 0.0)
+            .map(Pacer::new);
+        Self {
+            store,
+            config,
+            pacer,
+            ledger: None,
+        }
+    }
+
+    /// Leaves out the points the ledger lists with the same op and records every point written
+    pub fn with_ledger(mut self, ledger: OpLedger) -> Self {
+        self.ledger = Some(Mutex::new(ledger));
+        self
+    }
+
+    #[inline]
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    #[inline]
+    pub fn config(&self) -> &WriteSchedulerConfig {
+        &self.config
+    }
+
+    /// Sends one chunk, returns the requests it took
+    async fn send(
+        &self,
+        op: &WriteOp,
+        hash: Option<&str>,
+        points: &[Uuid],
+    ) -> (u32, Result<(), String>) {
+        if self.config.dry_run {
+            tracing::info!("Dry run: would {} {:?}: {:?}", op.name(), points, op);
+            return (0, Ok(()));
+        }
+        let mut attempts = 0;
+        loop {
+            if let Some(pacer) = &self.pacer {
+                pacer.wait().await;
+            }
+            attempts += 1;
+            let res = match op {
+                WriteOp::SetPayload { payload, .. } => {
+                    self.store.set_payload(points, payload).await
+                }
+                WriteOp::DeletePoints { .. } => self.store.delete_points(points).await,
+            };
+            let error = match res {
+                Ok(()) => {
+                    self.record(op, hash, points);
+                    return (attempts, Ok(()));
+                }
+                Err(e) => e,
+            };
+            match self.store.retry_hint(&error) {
+                Some(hint) if attempts <= self.config.max_retries => {
+                    let backoff = self
+                        .config
+                        .retry_backoff
+                        .saturating_mul(1 << (attempts - 1).min(16))
+                        .max(hint);
+                    tracing::warn!(
+                        "{} of {} points failed on attempt {}, retrying in {:?}: {}",
+                        op.name(),
+                        points.len(),
+                        attempts,
+                        backoff,
+                        error
+                    );
+                    counter("qdrant_retries_total", &[("op", op.name())]).inc();
+                    tokio::time::sleep(backoff).await;
+                }
+                _ => return (attempts, Err(error.to_string())),
+            }
+        }
+    }
+
+    /// A ledger that failed to record only means the points are sent again on the next run
+    fn record(&self, op: &WriteOp, hash: Option<&str>, points: &[Uuid]) {
+        let (Some(ledger), Some(hash)) = (&self.ledger, hash) else {
+            return;
+        };
+        let mut ledger = ledger.lock().unwrap();
+        if let Err(e) = ledger.record(points, op.name(), hash) {
+            tracing::error!(
+                "Failed to record {} of {} points in ledger {}: {}",
+                op.name(),
+                points.len(),
+                ledger.path().display(),
+                e
+            );
+        }
+    }
+
+    /// Runs every operation, `on_progress(done, total)` is called with the requests finished so far
+    ///
+    /// Returns one outcome per operation in submission order. On a stall abort the operations left
+    /// unfinished are [`WriteStatus::NotAttempted`] and the error is returned alongside.
+    pub async fn run<F>(
+        &self,
+        ops: &[WriteOp],
+        mut on_progress: F,
+    ) -> (Vec<WriteOutcome>, Option<StallError>)
+    where
+        F: FnMut(usize, usize),
+    {
+        let batch_size = self.config.batch_size.max(1);
+        let hashes: Vec<Option<String>> = ops
+            .iter()
+            .map(|op| self.ledger.as_ref().map(|_| op_hash(op)))
+            .collect();
+        let unapplied: Vec<Cow<[Uuid]>> = match &self.ledger {
+            None => ops.iter().map(|op| Cow::Borrowed(op.points())).collect(),
+            Some(ledger) => {
+                let ledger = ledger.lock().unwrap();
+                ops.iter()
+                    .zip(&hashes)
+                    .map(|(op, hash)| {
+                        let hash = hash.as_deref().unwrap_or_default();
+                        let points = op.points().iter();
+                        Cow::Owned(
+                            points
+                                .filter(|p| !ledger.is_applied(p, hash))
+                                .copied()
+                                .collect(),
+                        )
+                    })
+                    .collect()
+            }
+        };
+        let skipped: Vec<usize> = ops
+            .iter()
+            .zip(&unapplied)
+            .map(|(op, points)| op.points().len() - points.len())
+            .collect();
+        if let Some(ledger) = &self.ledger {
+            let total: usize = skipped.iter().sum();
+            if total > 0 {
+                tracing::info!(
+                    "Skipping {} points already applied according to ledger {}",
+                    total,
+                    ledger.lock().unwrap().path().display()
+                );
+            }
+        }
+        let chunks: Vec<(usize, &[Uuid])> = unapplied
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, points)| points.chunks(batch_size).map(move |c| (idx, c)))
+            .collect();
+        let total = chunks.len();
+        let mut pending = vec![0usize; ops.len()];
+        for (idx, _) in chunks.iter() {
+            pending[*idx] += 1;
+        }
+        let done_status = match self.config.dry_run {
+            true => WriteStatus::DryRun,
+            false => WriteStatus::Written,
+        };
+        let mut outcomes: Vec<WriteOutcome> = pending
+            .iter()
+            .enumerate()
+            .map(|(op, &n)| WriteOutcome {
+                op,
+                // nothing to send
+                status: match n {
+                    0 => done_status.clone(),
+                    _ => WriteStatus::NotAttempted,
+                },
+                attempts: 0,
+                skipped: skipped[op],
+            })
+            .collect();
+        let tasks = chunks.into_iter().enumerate().map(|(seq, (idx, points))| {
+            let id = format!("{} #{} chunk {}", ops[idx].name(), idx, seq);
+            let span = tracing::info_span!(
+                "qdrant.write",
+                op = ops[idx].name(),
+                points = points.len(),
+                uuid = tracing::field::Empty
+            );
+            if let [point] = points {
+                span.record("uuid", tracing::field::display(point));
+            }
+            let send = self
+                .send(&ops[idx], hashes[idx].as_deref(), points)
+                .instrument(span);
+            (id, async move { (idx, points, send.await) })
+        });
+        let mut done = 0;
+        let watched = for_each_watched(
+            tasks,
+            self.config.concurrency.max(1),
+            &self.config.stall,
+            |(idx, points, (attempts, res))| {
+                let outcome = &mut outcomes[idx];
+                outcome.attempts += attempts;
+                pending[idx] -= 1;
+                match (&mut outcome.status, res) {
+                    (WriteStatus::Failed { points: failed, .. }, Err(_)) => {
+                        failed.extend_from_slice(points)
+                    }
+                    (status, Err(error)) => {
+                        *status = WriteStatus::Failed {
+                            error,
+                            points: points.to_vec(),
+                        }
+                    }
+                    (status @ WriteStatus::NotAttempted, Ok(())) if pending[idx] == 0 => {
+                        *status = done_status.clone()
+                    }
+                    _ => {}
+                }
+                done += 1;
+                on_progress(done, total);
+            },
+        )
+        .await;
+        (outcomes, watched.err())
+    }
+}
+
+/// Creates `shadow` with the schema of `source`, an existing `shadow` is an error and left as is
+pub async fn clone_collection<A: CollectionAdmin>(
+    admin: &A,
+    source: &str,
+    shadow: &str,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        source != shadow,
+        "The shadow collection cannot be the source collection {}",
+        source
+    );
+    anyhow::ensure!(
+        !admin.collection_exists(shadow).await?,
+        "Collection {} already exists",
+        shadow
+    );
+    let schema = admin.collection_schema(source).await?;
+    admin.create_collection(shadow, &schema).await
+}
+
+/// Copies `ids` from `source` to `target` in batches of `batch_size`
+///
+/// Returns the copied records, an export of them in the `restore-points` format. Ids `source` does
+/// not know are left out.
+pub async fn copy_points<S: PointStore, T: PointStore>(
+    source: &S,
+    target: &T,
+    ids: &[Uuid],
+    batch_size: usize,
+) -> anyhow::Result<Vec<PointRecord>> {
+    let mut copied = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(batch_size.max(1)) {
+        let records = source.get(chunk).await?;
+        if !records.is_empty() {
+            target.upsert(&records).await?;
+        }
+        copied.extend(records);
+    }
+    Ok(copied)
+}
+
+/// Payload every point should end up with, `None` for a deleted one
+pub type ExpectedPoints = HashMap<Uuid, Option<Map<String, Value>>>;
+
+/// Applies `ops` to the `baseline` points the way Qdrant would, points outside it are ignored
+pub fn expected_points(baseline: &[PointRecord], ops: &[WriteOp]) -> ExpectedPoints {
+    let mut expected: ExpectedPoints = baseline
+        .iter()
+        .map(|p| (p.id, Some(p.payload.clone())))
+        .collect();
+    for op in ops {
+        for id in op.points() {
+            let Some(state) = expected.get_mut(id) else {
+                continue;
+            };
+            match (op, state) {
+                (WriteOp::SetPayload { payload, .. }, Some(current)) => {
+                    current.extend(payload.clone())
+                }
+                (WriteOp::DeletePoints { .. }, state) => *state = None,
+                (WriteOp::SetPayload { .. }, None) => {}
+            }
+        }
+    }
+    expected
+}
+
+/// One payload field differing from the expectation, `None` for an absent field
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PayloadDiff {
+    pub id: Uuid,
+    pub field: String,
+    pub expected: Option<Value>,
+    pub actual: Option<Value>,
+}
+
+/// Collection state after a run compared with [`ExpectedPoints`], every list sorted
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ShadowReport {
+    /// Points expected in the collection and found there
+    pub present: Vec<Uuid>,
+    /// Points deleted as expected
+    pub deleted: usize,
+    /// Points expected to be deleted but still there
+    pub unexpectedly_present: Vec<Uuid>,
+    /// Points expected to be kept but gone
+    pub unexpectedly_missing: Vec<Uuid>,
+    pub payload_diffs: Vec<PayloadDiff>,
+}
+
+impl ShadowReport {
+    pub fn matches(&self) -> bool {
+        self.unexpectedly_present.is_empty()
+            && self.unexpectedly_missing.is_empty()
+            && self.payload_diffs.is_empty()
+    }
+}
+
+/// Reads the `expected` points back from `store` in batches and compares them
+pub async fn compare_shadow<S: PointStore>(
+    store: &S,
+    expected: &ExpectedPoints,
+    batch_size: usize,
+) -> anyhow::Result<ShadowReport> {
+    let ids: Vec<Uuid> = expected
+        .keys()
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let actual = fetch_payloads(store, &ids, batch_size).await?;
+    let mut report = ShadowReport::default();
+    for id in ids {
+        match (&expected[&id], actual.get(&id)) {
+            (None, None) => report.deleted += 1,
+            (None, Some(_)) => report.unexpectedly_present.push(id),
+            (Some(_), None) => report.unexpectedly_missing.push(id),
+            (Some(want), Some(got)) => {
+                report.present.push(id);
+                let fields: BTreeSet<&String> = want.keys().chain(got.keys()).collect();
+                for field in fields {
+                    if want.get(field) != got.get(field) {
+                        report.payload_diffs.push(PayloadDiff {
+                            id,
+                            field: field.clone(),
+                            expected: want.get(field).cloned(),
+                            actual: got.get(field).cloned(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Current payload of `ids`, fetched `batch_size` at a time, unknown ids left out
+pub async fn fetch_payloads<S: PointStore>(
+    store: &S,
+    ids: &[Uuid],
+    batch_size: usize,
+) -> anyhow::Result<HashMap<Uuid, Map<String, Value>>> {
+    let mut payloads = HashMap::with_capacity(ids.len());
+    for chunk in ids.chunks(batch_size.max(1)) {
+        payloads.extend(
+            store
+                .get_payloads(chunk)
+                .await?
+                .into_iter()
+                .map(|p| (p.id, p.payload)),
+        );
+    }
+    Ok(payloads)
+}
+
+/// What to do with a write whose fields someone else changed since the snapshot it was planned
+/// from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// Union of `categories`, the current value of the other fields
+    #[default]
+    Merge,
+    /// Write nothing, leave the point for manual review
+    Skip,
+    /// Write as planned, the other change is lost
+    Overwrite,
+}
+
+impl FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(OnConflict::Merge),
+            "skip" => Ok(OnConflict::Skip),
+            "overwrite" => Ok(OnConflict::Overwrite),
+            _ => Err(format!(
+                "unknown conflict policy {:?}, expected merge, skip or overwrite",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for OnConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OnConflict::Merge => "merge",
+            OnConflict::Skip => "skip",
+            OnConflict::Overwrite => "overwrite",
+        })
+    }
+}
+
+/// Fields merged as a set of strings, every other field is taken as a whole
+const UNION_FIELDS: &[&str] = &["categories"];
+
+/// A field about to be written that changed since the snapshot, `None` for an absent field
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldConflict {
+    pub field: String,
+    pub snapshot: Option<Value>,
+    pub current: Option<Value>,
+    pub intended: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GuardDecision {
+    /// No written field changed since the snapshot, or it already has the intended value
+    Write(Map<String, Value>),
+    /// Conflicts resolved by the policy into `payload`, empty when the current payload wins
+    Resolved {
+        payload: Map<String, Value>,
+        conflicts: Vec<FieldConflict>,
+    },
+    /// Left for manual review
+    Skip(Vec<FieldConflict>),
+    /// The point is gone from the collection
+    Missing,
+}
+
+/// `null` and an absent field are the same to Qdrant filters and to the snapshot
+fn same_value(a: Option<&Value>, b: Option<&Value>) -> bool {
+    a.filter(|v| !v.is_null()) == b.filter(|v| !v.is_null())
+}
+
+/// Strings of both arrays, those of `first` first, each once
+fn union_strings(first: &Value, second: Option<&Value>) -> Option<Value> {
+    let first = first.as_array()?;
+    let second = match second {
+        Some(Value::Array(second)) => second.as_slice(),
+        Some(Value::Null) | None => &[],
+        Some(_) => return None,
+    };
+    let mut union: Vec<Value> = Vec::with_capacity(first.len() + second.len());
+    for value in first.iter().chain(second) {
+        if !value.is_string() {
+            return None;
+        }
+        if !union.contains(value) {
+            union.push(value.clone());
+        }
+    }
+    Some(Value::Array(union))
+}
+
+/// Decides what to write over `current`, the payload a point has now, given the `intended` fields
+/// and the `snapshot` values they were planned from
+///
+/// A field conflicts when its current value is neither the snapshot's nor the intended one. On
+/// [`OnConflict::Merge`] a conflicting [`UNION_FIELDS`] field gets both values, any other conflict
+/// keeps the current value of every field outside [`UNION_FIELDS`], so `format` and `url` are
+/// never split between two writers. A missing point is never written to but on
+/// [`OnConflict::Overwrite`], where the write fails like a blind one would.
+pub fn decide_write(
+    intended: &Map<String, Value>,
+    snapshot: &Map<String, Value>,
+    current: Option<&Map<String, Value>>,
+    policy: OnConflict,
+) -> GuardDecision {
+    let Some(current) = current else {
+        return match policy {
+            OnConflict::Overwrite => GuardDecision::Write(intended.clone()),
+            OnConflict::Merge | OnConflict::Skip => GuardDecision::Missing,
+        };
+    };
+    let conflicts: Vec<FieldConflict> = intended
+        .iter()
+        .filter(|(field, value)| {
+            let now = current.get(*field);
+            !same_value(now, snapshot.get(*field)) && !same_value(now, Some(value))
+        })
+        .map(|(field, value)| FieldConflict {
+            field: field.clone(),
+            snapshot: snapshot.get(field).cloned(),
+            current: current.get(field).cloned(),
+            intended: value.clone(),
+        })
+        .collect();
+    if conflicts.is_empty() {
+        return GuardDecision::Write(intended.clone());
+    }
+    let payload = match policy {
+        OnConflict::Skip => return GuardDecision::Skip(conflicts),
+        OnConflict::Overwrite => intended.clone(),
+        OnConflict::Merge => {
+            let mut payload = intended.clone();
+            let mut keep_current = false;
+            for conflict in &conflicts {
+                let merged = UNION_FIELDS
+                    .contains(&conflict.field.as_str())
+                    .then(|| union_strings(&conflict.intended, conflict.current.as_ref()))
+                    .flatten();
+                match merged {
+                    Some(merged) => {
+                        payload.insert(conflict.field.clone(), merged);
+                    }
+                    None => keep_current = true,
+                }
+            }
+            if keep_current {
+                payload.retain(|field, _| UNION_FIELDS.contains(&field.as_str()));
+            }
+            payload
+        }
+    };
+    GuardDecision::Resolved { payload, conflicts }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictAction {
+    Merged,
+    Skipped,
+    Overwritten,
+    /// The point is gone, nothing written
+    Missing,
+}
+
+/// A point whose payload changed under a planned write, and what was done about it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PayloadConflict {
+    pub id: Uuid,
+    pub action: ConflictAction,
+    pub fields: Vec<FieldConflict>,
+    /// What was written instead of the intended payload, `None` when nothing was
+    pub written: Option<Map<String, Value>>,
+}
+
+/// Operations left to run after [`guard_writes`]
+#[derive(Debug, Default)]
+pub struct GuardedWrites {
+    pub ops: Vec<WriteOp>,
+    /// Position in the checked list of every operation of `ops`
+    pub owners: Vec<usize>,
+    pub conflicts: Vec<PayloadConflict>,
+    /// Position in the checked list of the operation of every conflict
+    pub conflict_owners: Vec<usize>,
+}
+
+/// Checks the [`WriteOp::SetPayload`]s of `ops` against the payload their points have now and
+/// applies `policy` where it changed since `snapshot`, the payload of every point when the writes
+/// were planned
+///
+/// The payloads are fetched `batch_size` points at a time. Operations on several points are split
+/// into one per point, deletions are passed through. A point without a snapshot is compared with
+/// an empty one.
+pub async fn guard_writes<S: PointStore>(
+    store: &S,
+    ops: &[WriteOp],
+    snapshot: &HashMap<Uuid, Map<String, Value>>,
+    policy: OnConflict,
+    batch_size: usize,
+) -> anyhow::Result<GuardedWrites> {
+    let ids: Vec<Uuid> = ops
+        .iter()
+        .filter(|op| matches!(op, WriteOp::SetPayload { .. }))
+        .flat_map(|op| op.points().iter().copied())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let current = fetch_payloads(store, &ids, batch_size).await?;
+    let empty = Map::new();
+    let mut guarded = GuardedWrites::default();
+    for (idx, op) in ops.iter().enumerate() {
+        let WriteOp::SetPayload { points, payload } = op else {
+            guarded.ops.push(op.clone());
+            guarded.owners.push(idx);
+            continue;
+        };
+        for id in points {
+            let before = snapshot.get(id).unwrap_or(&empty);
+            let (write, conflict) = match decide_write(payload, before, current.get(id), policy) {
+                GuardDecision::Write(payload) => (Some(payload), None),
+                GuardDecision::Resolved { payload, conflicts } => {
+                    let action = match policy {
+                        OnConflict::Overwrite => ConflictAction::Overwritten,
+                        _ => ConflictAction::Merged,
+                    };
+                    let written = (!payload.is_empty()).then_some(payload);
+                    (written.clone(), Some((action, conflicts, written)))
+                }
+                GuardDecision::Skip(conflicts) => {
+                    (None, Some((ConflictAction::Skipped, conflicts, None)))
+                }
+                GuardDecision::Missing => (None, Some((ConflictAction::Missing, vec![], None))),
+            };
+            if let Some((action, fields, written)) = conflict {
+                guarded.conflicts.push(PayloadConflict {
+                    id: *id,
+                    action,
+                    fields,
+                    written,
+                });
+                guarded.conflict_owners.push(idx);
+            }
+            if let Some(payload) = write {
+                guarded.ops.push(WriteOp::SetPayload {
+                    points: vec![*id],
+                    payload,
+                });
+                guarded.owners.push(idx);
+            }
+        }
+    }
+    Ok(guarded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MemoryPointStore;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    fn ids(range: std::ops::RangeInclusive<u128>) -> Vec<Uuid> {
+        range.map(Uuid::from_u128).collect()
+    }
+
+    fn config(batch_size: usize) -> WriteSchedulerConfig {
+        WriteSchedulerConfig {
+            batch_size,
+            concurrency: 2,
+            retry_backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    fn set_categories(points: Vec<Uuid>, tag: &str) -> WriteOp {
+        WriteOp::SetPayload {
+            points,
+            payload: json!({ "categories": [tag] }).as_object().unwrap().clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunked_writes() {
+        let scheduler = QdrantWriteScheduler::new(MemoryPointStore::default(), config(2));
+        let ops = vec![
+            set_categories(ids(1..=5), "a"),
+            WriteOp::DeletePoints { points: ids(6..=7) },
+            WriteOp::DeletePoints { points: vec![] },
+        ];
+        let mut progress = Vec::new();
+        let (outcomes, stalled) = scheduler
+            .run(&ops, |done, total| progress.push((done, total)))
+            .await;
+        assert!(stalled.is_none());
+        assert_eq!(progress.last(), Some(&(4, 4)));
+        let statuses: Vec<(&WriteStatus, u32)> =
+            outcomes.iter().map(|o| (&o.status, o.attempts)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (&WriteStatus::Written, 3),
+                (&WriteStatus::Written, 1),
+                (&WriteStatus::Written, 0)
+            ]
+        );
+        let store = scheduler.store();
+        assert!(store.requests.lock().unwrap().iter().all(|r| r.len() <= 2));
+        let payloads = store.payloads();
+        assert_eq!(payloads.len(), 5);
+        assert_eq!(payloads[&Uuid::from_u128(3)]["categories"], json!(["a"]));
+        let mut deleted = store.deleted.lock().unwrap().clone();
+        deleted.sort();
+        assert_eq!(deleted, ids(6..=7));
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_retried() {
+        let store = MemoryPointStore {
+            flaky: Mutex::new(HashMap::from([
+                (Uuid::from_u128(1), 2),
+                (Uuid::from_u128(3), 9),
+            ])),
+            ..Default::default()
+        };
+        let scheduler = QdrantWriteScheduler::new(store, config(1));
+        let ops = vec![
+            WriteOp::DeletePoints { points: ids(1..=2) },
+            WriteOp::DeletePoints { points: ids(3..=3) },
+        ];
+        let (outcomes, _) = scheduler.run(&ops, |_, _| {}).await;
+        // two failures then success, plus the untouched second chunk
+        assert_eq!(outcomes[0].status, WriteStatus::Written);
+        assert_eq!(outcomes[0].attempts, 4);
+        // gives up after max_retries
+        assert_eq!(
+            outcomes[1].status,
+            WriteStatus::Failed {
+                error: "injected transient failure".to_owned(),
+                points: ids(3..=3),
+            }
+        );
+        assert_eq!(outcomes[1].attempts, 4);
+        assert!(
+            !scheduler
+                .store()
+                .deleted
+                .lock()
+                .unwrap()
+                .contains(&Uuid::from_u128(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_not_retried() {
+        let store = MemoryPointStore {
+            broken: HashSet::from([Uuid::from_u128(2), Uuid::from_u128(5)]),
+            ..Default::default()
+        };
+        let scheduler = QdrantWriteScheduler::new(store, config(2));
+        let ops = vec![
+            set_categories(ids(1..=6), "a"),
+            set_categories(ids(7..=8), "b"),
+        ];
+        let (outcomes, _) = scheduler.run(&ops, |_, _| {}).await;
+        match &outcomes[0].status {
+            WriteStatus::Failed { error, points } => {
+                assert_eq!(error, "rejected");
+                let mut points = points.clone();
+                points.sort();
+                assert_eq!(
+                    points,
+                    ids(1..=2).into_iter().chain(ids(5..=6)).collect::<Vec<_>>()
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(outcomes[0].attempts, 3);
+        assert_eq!(outcomes[1].status, WriteStatus::Written);
+        let payloads = scheduler.store().payloads();
+        assert!(payloads.contains_key(&Uuid::from_u128(3)));
+        assert!(!payloads.contains_key(&Uuid::from_u128(5)));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let scheduler = QdrantWriteScheduler::new(
+            MemoryPointStore::default(),
+            WriteSchedulerConfig {
+                dry_run: true,
+                ..config(2)
+            },
+        );
+        let (outcomes, _) = scheduler
+            .run(&[WriteOp::DeletePoints { points: ids(1..=3) }], |_, _| {})
+            .await;
+        assert_eq!(outcomes[0].status, WriteStatus::DryRun);
+        assert_eq!(outcomes[0].attempts, 0);
+        assert!(scheduler.store().requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ledger_skips_applied() {
+        let dir = std::env::temp_dir().join(format!("qdrant_ledger_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ledger.jsonl");
+        let with_ledger = |store: MemoryPointStore| {
+            let ledger = OpLedger::open(&path, "neko").unwrap();
+            QdrantWriteScheduler::new(store, config(1)).with_ledger(ledger)
+        };
+
+        let store = MemoryPointStore {
+            broken: HashSet::from([Uuid::from_u128(2)]),
+            ..Default::default()
+        };
+        let (outcomes, _) = with_ledger(store)
+            .run(&[set_categories(ids(1..=3), "a")], |_, _| {})
+            .await;
+        assert!(matches!(outcomes[0].status, WriteStatus::Failed { .. }));
+
+        // only the failed point is sent again
+        let scheduler = with_ledger(MemoryPointStore::default());
+        let (outcomes, _) = scheduler
+            .run(&[set_categories(ids(1..=3), "a")], |_, _| {})
+            .await;
+        assert_eq!(outcomes[0].status, WriteStatus::Written);
+        assert_eq!((outcomes[0].attempts, outcomes[0].skipped), (1, 2));
+        assert_eq!(
+            *scheduler.store().requests.lock().unwrap(),
+            vec![ids(2..=2)]
+        );
+        drop(scheduler);
+
+        // a changed payload goes through and supersedes the entries, dry runs record nothing
+        let dry_run = QdrantWriteScheduler::new(
+            MemoryPointStore::default(),
+            WriteSchedulerConfig {
+                dry_run: true,
+                ..config(1)
+            },
+        )
+        .with_ledger(OpLedger::open(&path, "neko").unwrap());
+        let (outcomes, _) = dry_run
+            .run(&[set_categories(ids(1..=3), "b")], |_, _| {})
+            .await;
+        assert_eq!(
+            (&outcomes[0].status, outcomes[0].skipped),
+            (&WriteStatus::DryRun, 0)
+        );
+        drop(dry_run);
+        let scheduler = with_ledger(MemoryPointStore::default());
+        let ops = [set_categories(ids(1..=3), "b")];
+        let (outcomes, _) = scheduler.run(&ops, |_, _| {}).await;
+        assert_eq!(outcomes[0].skipped, 0);
+        assert_eq!(scheduler.store().requests.lock().unwrap().len(), 3);
+        drop(scheduler);
+        let ledger = OpLedger::open(&path, "neko").unwrap();
+        let applied = ids(1..=3);
+        assert!(
+            applied
+                .iter()
+                .all(|p| ledger.is_applied(p, &op_hash(&ops[0])))
+        );
+        assert!(!ledger.is_applied(&applied[0], &op_hash(&set_categories(vec![], "a"))));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let scheduler = QdrantWriteScheduler::new(
+            MemoryPointStore::default(),
+            WriteSchedulerConfig {
+                max_requests_per_sec: Some(200.0),
+                concurrency: 8,
+                ..config(1)
+            },
+        );
+        let start = Instant::now();
+        let (outcomes, _) = scheduler
+            .run(
+                &[WriteOp::DeletePoints {
+                    points: ids(1..=11),
+                }],
+                |_, _| {},
+            )
+            .await;
+        assert_eq!(outcomes[0].attempts, 11);
+        // the first request starts right away, ten more at 5ms intervals
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_qdrant_retry_hint() {
+        let io = anyhow::Error::from(QdrantError::Io(std::io::ErrorKind::ConnectionReset.into()));
+        assert_eq!(qdrant_retry_hint(&io), Some(Duration::ZERO));
+        let conversion = anyhow::Error::from(QdrantError::ConversionError("bad".to_owned()));
+        assert_eq!(qdrant_retry_hint(&conversion), None);
+        assert_eq!(qdrant_retry_hint(&anyhow::anyhow!("other")), None);
+    }
+
+    /// Answers with the scripted pages in order, recording the offsets asked for
+    struct ScriptedPages {
+        pages: Mutex<Vec<QdrantResult<ScrollPage<u32, u32>>>>,
+        offsets: Mutex<Vec<Option<u32>>>,
+    }
+
+    impl ScriptedPages {
+        fn new(mut pages: Vec<QdrantResult<ScrollPage<u32, u32>>>) -> Self {
+            pages.reverse();
+            Self {
+                pages: Mutex::new(pages),
+                offsets: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    impl PageSource for &ScriptedPages {
+        type Point = u32;
+        type Offset = u32;
+
+        async fn fetch_page(&self, offset: Option<u32>) -> QdrantResult<ScrollPage<u32, u32>> {
+            self.offsets.lock().unwrap().push(offset);
+            self.pages
+                .lock()
+                .unwrap()
+                .pop()
+                .expect("scroll past the script")
+        }
+    }
+
+    fn page(points: &[u32], next: Option<u32>) -> QdrantResult<ScrollPage<u32, u32>> {
+        Ok(ScrollPage {
+            points: points.to_vec(),
+            next,
+        })
+    }
+
+    fn transient() -> QdrantResult<ScrollPage<u32, u32>> {
+        Err(QdrantError::Io(std::io::ErrorKind::ConnectionReset.into()))
+    }
+
+    fn fatal() -> QdrantResult<ScrollPage<u32, u32>> {
+        Err(QdrantError::ConversionError("bad".to_owned()))
+    }
+
+    /// Pages as `Ok(points)` / `Err(())` and the offsets fetched
+    async fn scroll(
+        pages: Vec<QdrantResult<ScrollPage<u32, u32>>>,
+        options: ScrollOptions,
+    ) -> (Vec<Result<Vec<u32>, ()>>, Vec<Option<u32>>) {
+        let source = ScriptedPages::new(pages);
+        let options = ScrollOptions {
+            retry_backoff: Duration::ZERO,
+            ..options
+        };
+        let res = paginate(&source, options)
+            .map(|page| page.map_err(|_| ()))
+            .collect()
+            .await;
+        (res, source.offsets.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_paginate_offsets() {
+        let (res, offsets) = scroll(
+            vec![
+                page(&[1, 2], Some(10)),
+                page(&[3], Some(20)),
+                page(&[], None),
+            ],
+            ScrollOptions::default(),
+        )
+        .await;
+        // the empty final page ends the stream without being yielded
+        assert_eq!(res, vec![Ok(vec![1, 2]), Ok(vec![3])]);
+        assert_eq!(offsets, vec![None, Some(10), Some(20)]);
+        let (res, _) = scroll(vec![page(&[], None)], ScrollOptions::default()).await;
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_paginate_errors() {
+        // a transient error is yielded and the same page fetched again
+        let (res, offsets) = scroll(
+            vec![page(&[1], Some(5)), transient(), page(&[2], None)],
+            ScrollOptions::default(),
+        )
+        .await;
+        assert_eq!(res, vec![Ok(vec![1]), Err(()), Ok(vec![2])]);
+        assert_eq!(offsets, vec![None, Some(5), Some(5)]);
+        // a fatal one ends the stream
+        let (res, offsets) = scroll(
+            vec![page(&[1], Some(5)), fatal(), page(&[2], None)],
+            ScrollOptions::default(),
+        )
+        .await;
+        assert_eq!(res, vec![Ok(vec![1]), Err(())]);
+        assert_eq!(offsets, vec![None, Some(5)]);
+        // as do too many transient ones in a row
+        let options = ScrollOptions {
+            max_retries: 1,
+            ..Default::default()
+        };
+        let (res, offsets) = scroll(
+            vec![transient(), page(&[1], Some(5)), transient(), transient()],
+            options,
+        )
+        .await;
+        assert_eq!(res, vec![Err(()), Ok(vec![1]), Err(()), Err(())]);
+        assert_eq!(offsets, vec![None, None, Some(5), Some(5)]);
+    }
+
+    #[tokio::test]
+    async fn test_paginate_limits() {
+        let pages = || {
+            vec![
+                page(&[1, 2], Some(10)),
+                page(&[3, 4], Some(20)),
+                page(&[5], None),
+            ]
+        };
+        let max_pages = ScrollOptions {
+            max_pages: Some(2),
+            ..Default::default()
+        };
+        let (res, offsets) = scroll(pages(), max_pages).await;
+        assert_eq!(res, vec![Ok(vec![1, 2]), Ok(vec![3, 4])]);
+        assert_eq!(offsets.len(), 2);
+        let limit = ScrollOptions {
+            limit: Some(3),
+            ..Default::default()
+        };
+        let (res, offsets) = scroll(pages(), limit).await;
+        assert_eq!(res, vec![Ok(vec![1, 2]), Ok(vec![3])]);
+        assert_eq!(offsets.len(), 2);
+    }
+
+    const REAL: &str = "9b2f6c1e-3d4a-4f7b-8c5d-1e2f3a4b5c6d";
+
+    #[test]
+    fn test_point_ref_conversions() {
+        let real = Uuid::parse_str(REAL).unwrap();
+        for point in [PointRef::Uuid(real), PointRef::Num(7)] {
+            assert_eq!(PointRef::try_from(PointId::from(point)), Ok(point));
+            assert_eq!(point.to_string().parse(), Ok(point));
+        }
+        assert_eq!(
+            PointId::from(PointRef::Num(7)).point_id_options,
+            Some(PointIdOptions::Num(7))
+        );
+        assert_eq!(
+            PointId::from(PointRef::Uuid(real)).point_id_options,
+            Some(PointIdOptions::Uuid(REAL.to_owned()))
+        );
+        assert!(PointRef::try_from(PointId::from("not a uuid")).is_err());
+        assert!("not a uuid".parse::<PointRef>().is_err());
+
+        // numeric points travel as their key and come back numeric
+        let key = PointRef::Num(7).key();
+        assert_eq!(key, Uuid::from_u128(7));
+        assert_eq!(PointRef::from_key(key), PointRef::Num(7));
+        assert_eq!(PointRef::from_key(real), PointRef::Uuid(real));
+        let point = NekoPoint {
+            id: key,
+            height: 1,
+            width: 1,
+            size: None,
+            categories: None,
+            text_info: None,
+            qdrant_num_id: Some(7),
+            extra: None,
+        };
+        assert_eq!(PointRef::of(&point), PointRef::Num(7));
+        let point = NekoPoint {
+            id: real,
+            qdrant_num_id: None,
+            ..point
+        };
+        assert_eq!(PointRef::of(&point), PointRef::Uuid(real));
+    }
+
+    #[test]
+    fn test_points_ids_list_mixed() {
+        let real = Uuid::parse_str(REAL).unwrap();
+        let points = [PointRef::Num(3), PointRef::Uuid(real), PointRef::Num(0)];
+        let list = points_ids_list(points);
+        let options: Vec<_> = list
+            .ids
+            .into_iter()
+            .map(|id| id.point_id_options.unwrap())
+            .collect();
+        assert_eq!(
+            options,
+            vec![
+                PointIdOptions::Num(3),
+                PointIdOptions::Uuid(REAL.to_owned()),
+                PointIdOptions::Num(0),
+            ]
+        );
+        let counts = IdKindCounts::count(points);
+        assert_eq!(counts, IdKindCounts { uuid: 1, num: 2 });
+        assert!(counts.is_mixed());
+        assert!(!IdKindCounts::count([PointRef::Num(1)]).is_mixed());
+
+        // the store sends keys registered as numeric by number, everything else as a UUID
+        let client = Qdrant::from_url("http://127.0.0.1:6334").build().unwrap();
+        let store =
+            QdrantPointStore::new(GenShinQdrantClient(client), "points").with_num_ids(points);
+        let list = store.point_ids(&[Uuid::from_u128(3), real, Uuid::from_u128(4)]);
+        let options: Vec<_> = list
+            .ids
+            .into_iter()
+            .map(|id| id.point_id_options.unwrap())
+            .collect();
+        assert_eq!(
+            options,
+            vec![
+                PointIdOptions::Num(3),
+                PointIdOptions::Uuid(REAL.to_owned()),
+                PointIdOptions::Uuid(Uuid::from_u128(4).to_string()),
+            ]
+        );
+    }
+
+    /// Stored value, what the reader returns and what it records
+    type Case<T> = (Option<QdrantValue>, Option<T>, Vec<PayloadAnomaly>);
+
+    fn qv(kind: value::Kind) -> QdrantValue {
+        QdrantValue { kind: Some(kind) }
+    }
+
+    fn qlist(items: Vec<value::Kind>) -> value::Kind {
+        value::Kind::ListValue(ListValue {
+            values: items.into_iter().map(qv).collect(),
+        })
+    }
+
+    fn qstr(s: &str) -> value::Kind {
+        value::Kind::StringValue(s.to_string())
+    }
+
+    fn anomaly(kind: &str, handling: PayloadHandling) -> PayloadAnomaly {
+        PayloadAnomaly {
+            field: "f".to_string(),
+            kind: kind.to_string(),
+            handling,
+        }
+    }
+
+    fn read<T>(
+        value: Option<QdrantValue>,
+        f: impl FnOnce(&mut PayloadReader, &str) -> Option<T>,
+    ) -> (Option<T>, Vec<PayloadAnomaly>) {
+        let payload: HashMap<String, QdrantValue> =
+            value.into_iter().map(|v| ("f".to_string(), v)).collect();
+        let mut reader = PayloadReader::new(&payload);
+        let res = f(&mut reader, "f");
+        (res, reader.into_anomalies())
+    }
+
+    #[test]
+    fn test_payload_strings() {
+        use PayloadHandling::*;
+        let cases: Vec<Case<Vec<&str>>> = vec![
+            (None, None, vec![]),
+            (
+                Some(qv(qlist(vec![qstr("cat"), qstr("meme")]))),
+                Some(vec!["cat", "meme"]),
+                vec![],
+            ),
+            (Some(qv(qlist(vec![]))), Some(vec![]), vec![]),
+            (
+                Some(qv(qstr("cat"))),
+                Some(vec!["cat"]),
+                vec![anomaly("string", Coerced)],
+            ),
+            (
+                Some(qv(qlist(vec![qstr("cat"), value::Kind::IntegerValue(1)]))),
+                Some(vec!["cat"]),
+                vec![anomaly("list", DroppedItems)],
+            ),
+            (
+                Some(qv(value::Kind::NullValue(0))),
+                None,
+                vec![anomaly("null", Null)],
+            ),
+            (
+                Some(QdrantValue { kind: None }),
+                None,
+                vec![anomaly("null", Null)],
+            ),
+            (
+                Some(qv(value::Kind::IntegerValue(3))),
+                None,
+                vec![anomaly("integer", Dropped)],
+            ),
+            (
+                Some(qv(value::Kind::BoolValue(true))),
+                None,
+                vec![anomaly("bool", Dropped)],
+            ),
+            (
+                Some(qv(value::Kind::StructValue(Default::default()))),
+                None,
+                vec![anomaly("struct", Dropped)],
+            ),
+        ];
+        for (value, expected, anomalies) in cases {
+            let (res, found) = read(value.clone(), |r, f| r.strings(f));
+            let expected = expected.map(|v| v.into_iter().map(String::from).collect());
+            assert_eq!(res, expected, "{:?}", value);
+            assert_eq!(found, anomalies, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_payload_text() {
+        use PayloadHandling::*;
+        let cases: Vec<Case<&str>> = vec![
+            (None, None, vec![]),
+            (Some(qv(qstr("hello"))), Some("hello"), vec![]),
+            (
+                Some(qv(qlist(vec![qstr("line 1"), qstr("line 2")]))),
+                Some("line 1\nline 2"),
+                vec![anomaly("list", Coerced)],
+            ),
+            (
+                Some(qv(qlist(vec![qstr("a"), value::Kind::BoolValue(false)]))),
+                Some("a"),
+                vec![anomaly("list", Coerced), anomaly("list", DroppedItems)],
+            ),
+            (
+                Some(qv(value::Kind::NullValue(0))),
+                None,
+                vec![anomaly("null", Null)],
+            ),
+            (
+                Some(qv(value::Kind::DoubleValue(1.5))),
+                None,
+                vec![anomaly("double", Dropped)],
+            ),
+        ];
+        for (value, expected, anomalies) in cases {
+            let (res, found) = read(value.clone(), |r, f| r.text(f));
+            assert_eq!(res.as_deref(), expected, "{:?}", value);
+            assert_eq!(found, anomalies, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_payload_usize() {
+        use PayloadHandling::*;
+        let cases: Vec<Case<usize>> = vec![
+            (None, None, vec![anomaly("null", Missing)]),
+            (Some(qv(value::Kind::IntegerValue(640))), Some(640), vec![]),
+            (
+                Some(qv(value::Kind::IntegerValue(-1))),
+                None,
+                vec![anomaly("integer", Dropped)],
+            ),
+            (
+                Some(qv(value::Kind::DoubleValue(480.0))),
+                Some(480),
+                vec![anomaly("double", Coerced)],
+            ),
+            (
+                Some(qv(value::Kind::DoubleValue(480.5))),
+                None,
+                vec![anomaly("double", Dropped)],
+            ),
+            (
+                Some(qv(qstr(" 320 "))),
+                Some(320),
+                vec![anomaly("string", Coerced)],
+            ),
+            (
+                Some(qv(qstr("wide"))),
+                None,
+                vec![anomaly("string", Dropped)],
+            ),
+            (
+                Some(qv(value::Kind::NullValue(0))),
+                None,
+                vec![anomaly("null", Null)],
+            ),
+            (
+                Some(qv(qlist(vec![]))),
+                None,
+                vec![anomaly("list", Dropped)],
+            ),
+        ];
+        for (value, expected, anomalies) in cases {
+            let (res, found) = read(value.clone(), |r, f| r.usize(f));
+            assert_eq!(res, expected, "{:?}", value);
+            assert_eq!(found, anomalies, "{:?}", value);
+        }
+    }
+    #[test]
+    fn test_payload_projection() {
+        assert_eq!(payload_projection(&[]), NEKO_POINT_PAYLOAD_KEYS);
+        let extra = ["exif".to_string(), "width".to_string(), "exif".to_string()];
+        assert_eq!(
+            payload_projection(&extra),
+            ["height", "width", "categories", "ocr_text", "exif"]
+        );
+        let PayloadSelectorOptions::Include(selector) = payload_selector(&extra[..1]) else {
+            panic!("projection must be an include list");
+        };
+        assert_eq!(selector.fields, payload_projection(&extra));
+    }
+
+    #[test]
+    fn test_payload_neko_fields_and_extra() {
+        let payload: HashMap<String, QdrantValue> = [
+            ("height", qv(value::Kind::IntegerValue(480))),
+            ("width", qv(value::Kind::DoubleValue(640.0))),
+            ("categories", qv(qstr("cat"))),
+            (
+                "exif",
+                qv(qlist(vec![qstr("iso"), value::Kind::IntegerValue(100)])),
+            ),
+            ("source", qv(qstr("pixiv"))),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+        let mut reader = PayloadReader::new(&payload);
+        let fields = reader.neko_fields();
+        assert_eq!(
+            fields,
+            NekoPayloadFields {
+                height: 480,
+                width: 640,
+                categories: Some(vec!["cat".to_string()]),
+                ocr_text: None,
+            }
+        );
+        let keys = ["exif".to_string(), "missing".to_string()];
+        let extra: Value = serde_json::from_str(&reader.extra(&keys).unwrap()).unwrap();
+        assert_eq!(extra, json!({"exif": ["iso", 100]}));
+        assert_eq!(reader.extra(&keys[1..]), None);
+        assert_eq!(reader.extra(&[]), None);
+        let fields: Vec<String> = reader
+            .into_anomalies()
+            .into_iter()
+            .map(|a| a.field)
+            .collect();
+        assert_eq!(fields, ["width", "categories"]);
+    }
+
+    /// Collections by name, each one a schema and its points
+    #[derive(Default)]
+    struct MemoryCollections(Mutex<HashMap<String, (String, MemoryPointStore)>>);
+
+    impl CollectionAdmin for MemoryCollections {
+        type Schema = String;
+
+        async fn collection_exists(&self, name: &str) -> anyhow::Result<bool> {
+            Ok(self.0.lock().unwrap().contains_key(name))
+        }
+
+        async fn collection_schema(&self, name: &str) -> anyhow::Result<String> {
+            let collections = self.0.lock().unwrap();
+            let (schema, _) = collections
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("no collection {}", name))?;
+            Ok(schema.clone())
+        }
+
+        async fn create_collection(&self, name: &str, schema: &String) -> anyhow::Result<()> {
+            self.0.lock().unwrap().insert(
+                name.to_owned(),
+                (schema.clone(), MemoryPointStore::default()),
+            );
+            Ok(())
+        }
+    }
+
+    fn record(id: u128, tag: &str) -> PointRecord {
+        PointRecord {
+            id: Uuid::from_u128(id),
+            vectors: HashMap::from([("image_vector".to_owned(), vec![id as f32; 4])]),
+            payload: json!({ "categories": [tag], "width": 64 })
+                .as_object()
+                .unwrap()
+                .clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clone_collection() {
+        let admin = MemoryCollections::default();
+        admin
+            .create_collection("prod", &"768d".to_owned())
+            .await
+            .unwrap();
+        clone_collection(&admin, "prod", "shadow").await.unwrap();
+        assert_eq!(admin.collection_schema("shadow").await.unwrap(), "768d");
+        // never reuses a collection, least of all the source
+        let err = clone_collection(&admin, "prod", "shadow")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        assert!(clone_collection(&admin, "prod", "prod").await.is_err());
+        assert!(clone_collection(&admin, "missing", "other").await.is_err());
+        assert!(!admin.collection_exists("other").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_copy_points() {
+        let source = MemoryPointStore::default();
+        source
+            .upsert(&(1..=10).map(|id| record(id, "a")).collect::<Vec<_>>())
+            .await
+            .unwrap();
+        let target = MemoryPointStore::default();
+        let wanted: Vec<Uuid> = [2, 4, 6, 8, 42].into_iter().map(Uuid::from_u128).collect();
+        let copied = copy_points(&source, &target, &wanted, 2).await.unwrap();
+        let ids: Vec<Uuid> = copied.iter().map(|p| p.id).collect();
+        assert_eq!(ids, wanted[..4]);
+        assert_eq!(target.get(&wanted).await.unwrap(), copied);
+        assert_eq!(copied[0], record(2, "a"));
+    }
+
+    #[tokio::test]
+    async fn test_compare_shadow() {
+        let baseline: Vec<PointRecord> = (1..=6).map(|id| record(id, "a")).collect();
+        let store = MemoryPointStore::default();
+        store.upsert(&baseline).await.unwrap();
+        let ops = vec![
+            set_categories(ids(1..=1), "b"),
+            WriteOp::DeletePoints { points: ids(2..=3) },
+            // outside the baseline, e.g. not copied into the shadow
+            WriteOp::DeletePoints { points: ids(9..=9) },
+        ];
+        let expected = expected_points(&baseline, &ops);
+        assert_eq!(expected.len(), 6);
+        assert_eq!(expected[&Uuid::from_u128(2)], None);
+        assert_eq!(
+            expected[&Uuid::from_u128(1)].as_ref().unwrap()["categories"],
+            json!(["b"])
+        );
+
+        let scheduler = QdrantWriteScheduler::new(store, config(2));
+        let (outcomes, _) = scheduler.run(&ops, |_, _| {}).await;
+        assert!(outcomes.iter().all(|o| o.status == WriteStatus::Written));
+        let report = compare_shadow(scheduler.store(), &expected, 4)
+            .await
+            .unwrap();
+        assert!(report.matches(), "{:?}", report);
+        assert_eq!(report.present, [ids(1..=1), ids(4..=6)].concat());
+        assert_eq!(report.deleted, 2);
+
+        // a discard restored, a keep lost and an untouched point rewritten
+        let store = scheduler.store();
+        store
+            .upsert(&[record(2, "a"), record(4, "c")])
+            .await
+            .unwrap();
+        store.delete_points(&ids(5..=5)).await.unwrap();
+        let report = compare_shadow(store, &expected, 4).await.unwrap();
+        assert!(!report.matches());
+        assert_eq!(report.unexpectedly_present, ids(2..=2));
+        assert_eq!(report.unexpectedly_missing, ids(5..=5));
+        assert_eq!(report.deleted, 1);
+        assert_eq!(
+            report.payload_diffs,
+            vec![PayloadDiff {
+                id: Uuid::from_u128(4),
+                field: "categories".to_owned(),
+                expected: Some(json!(["a"])),
+                actual: Some(json!(["c"])),
+            }]
+        );
+    }
+
+    fn payload(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_decide_write_unchanged() {
+        let intended = payload(json!({ "categories": ["a", "b"] }));
+        let snapshot = payload(json!({ "categories": ["a"] }));
+        for current in [
+            json!({ "categories": ["a"], "width": 64 }),
+            // already written, e.g. by an earlier run
+            json!({ "categories": ["a", "b"] }),
+        ] {
+            for policy in [OnConflict::Merge, OnConflict::Skip, OnConflict::Overwrite] {
+                assert_eq!(
+                    decide_write(
+                        &intended,
+                        &snapshot,
+                        Some(&payload(current.clone())),
+                        policy
+                    ),
+                    GuardDecision::Write(intended.clone())
+                );
+            }
+        }
+        // null and absent alike
+        let snapshot = payload(json!({ "categories": null }));
+        assert_eq!(
+            decide_write(&intended, &snapshot, Some(&Map::new()), OnConflict::Skip),
+            GuardDecision::Write(intended.clone())
+        );
+    }
+
+    #[test]
+    fn test_decide_write_categories_conflict() {
+        let intended = payload(json!({ "categories": ["a", "b"] }));
+        let snapshot = payload(json!({ "categories": ["a"] }));
+        let current = payload(json!({ "categories": ["a", "c"] }));
+        let conflicts = vec![FieldConflict {
+            field: "categories".to_owned(),
+            snapshot: Some(json!(["a"])),
+            current: Some(json!(["a", "c"])),
+            intended: json!(["a", "b"]),
+        }];
+        let decide = |policy| decide_write(&intended, &snapshot, Some(&current), policy);
+        assert_eq!(
+            decide(OnConflict::Merge),
+            GuardDecision::Resolved {
+                payload: payload(json!({ "categories": ["a", "b", "c"] })),
+                conflicts: conflicts.clone(),
+            }
+        );
+        assert_eq!(
+            decide(OnConflict::Skip),
+            GuardDecision::Skip(conflicts.clone())
+        );
+        assert_eq!(
+            decide(OnConflict::Overwrite),
+            GuardDecision::Resolved {
+                payload: intended.clone(),
+                conflicts,
+            }
+        );
+        // categories that are not a list of strings cannot be merged, the current ones stay
+        let current = payload(json!({ "categories": "a,c" }));
+        let GuardDecision::Resolved {
+            payload: written, ..
+        } = decide_write(&intended, &snapshot, Some(&current), OnConflict::Merge)
+        else {
+            panic!("not resolved");
+        };
+        assert_eq!(written, intended);
+    }
+
+    #[test]
+    fn test_decide_write_url_conflict() {
+        let intended = payload(json!({ "format": "jpg", "url": "http://host/1.jpg" }));
+        let snapshot = payload(json!({ "format": "png", "url": "http://host/1.png" }));
+        // renamed elsewhere, format unchanged
+        let current = payload(json!({ "format": "png", "url": "http://cdn/1.png" }));
+        let GuardDecision::Resolved {
+            payload: written,
+            conflicts,
+        } = decide_write(&intended, &snapshot, Some(&current), OnConflict::Merge)
+        else {
+            panic!("not resolved");
+        };
+        // the current url wins and format is left consistent with it
+        assert!(written.is_empty(), "{:?}", written);
+        let fields: Vec<&str> = conflicts.iter().map(|c| c.field.as_str()).collect();
+        assert_eq!(fields, vec!["url"]);
+
+        // categories still merged alongside
+        let mut intended = intended;
+        intended.insert("categories".to_owned(), json!(["b"]));
+        let mut current = current;
+        current.insert("categories".to_owned(), json!(["c"]));
+        let GuardDecision::Resolved {
+            payload: written, ..
+        } = decide_write(&intended, &snapshot, Some(&current), OnConflict::Merge)
+        else {
+            panic!("not resolved");
+        };
+        assert_eq!(written, payload(json!({ "categories": ["b", "c"] })));
+    }
+
+    #[test]
+    fn test_decide_write_missing() {
+        let intended = payload(json!({ "categories": ["a"] }));
+        let snapshot = Map::new();
+        assert_eq!(
+            decide_write(&intended, &snapshot, None, OnConflict::Merge),
+            GuardDecision::Missing
+        );
+        assert_eq!(
+            decide_write(&intended, &snapshot, None, OnConflict::Overwrite),
+            GuardDecision::Write(intended.clone())
+        );
+    }
+
+    #[test]
+    fn test_on_conflict_from_str() {
+        for policy in [OnConflict::Merge, OnConflict::Skip, OnConflict::Overwrite] {
+            assert_eq!(policy.to_string().parse::<OnConflict>(), Ok(policy));
+        }
+        assert!("clobber".parse::<OnConflict>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_guard_writes() {
+        let store = MemoryPointStore::default();
+        store
+            .upsert(&[record(1, "a"), record(2, "c"), record(3, "c")])
+            .await
+            .unwrap();
+        let snapshot: HashMap<Uuid, Map<String, Value>> = (1..=4)
+            .map(|id| {
+                let snapshot = payload(json!({ "categories": ["a"] }));
+                (Uuid::from_u128(id), snapshot)
+            })
+            .collect();
+        let ops = vec![
+            set_categories(ids(1..=2), "b"),
+            WriteOp::DeletePoints { points: ids(5..=5) },
+            set_categories(ids(3..=4), "b"),
+        ];
+        let guarded = guard_writes(&store, &ops, &snapshot, OnConflict::Merge, 2)
+            .await
+            .unwrap();
+        let merged = |id| WriteOp::SetPayload {
+            points: ids(id..=id),
+            payload: payload(json!({ "categories": ["b", "c"] })),
+        };
+        assert_eq!(
+            guarded.ops,
+            vec![
+                set_categories(ids(1..=1), "b"),
+                merged(2),
+                WriteOp::DeletePoints { points: ids(5..=5) },
+                merged(3),
+            ]
+        );
+        assert_eq!(guarded.owners, vec![0, 0, 1, 2]);
+        let actions: Vec<(Uuid, usize, ConflictAction)> = guarded
+            .conflicts
+            .iter()
+            .zip(&guarded.conflict_owners)
+            .map(|(c, op)| (c.id, *op, c.action))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                (Uuid::from_u128(2), 0, ConflictAction::Merged),
+                (Uuid::from_u128(3), 2, ConflictAction::Merged),
+                (Uuid::from_u128(4), 2, ConflictAction::Missing),
+            ]
+        );
+        assert_eq!(
+            guarded.conflicts[0].written.as_ref(),
+            Some(&payload(json!({ "categories": ["b", "c"] })))
+        );
+
+        let guarded = guard_writes(&store, &ops, &snapshot, OnConflict::Skip, 2)
+            .await
+            .unwrap();
+        assert_eq!(guarded.owners, vec![0, 1]);
+        assert!(
+            guarded.conflicts[..2]
+                .iter()
+                .all(|c| c.action == ConflictAction::Skipped && c.written.is_none())
+        );
+    }
+
+    #[cfg(feature = "qdrant-snapshot")]
+    #[test]
+    fn test_rest_url() {
+        assert_eq!(
+            rest_url("http://localhost:6334", None),
+            "http://localhost:6333"
+        );
+        assert_eq!(
+            rest_url("https://qdrant.lan:6334/", None),
+            "https://qdrant.lan:6333"
+        );
+        assert_eq!(rest_url("https://qdrant.lan", None), "https://qdrant.lan");
+        assert_eq!(
+            rest_url(
+                "http://localhost:6334",
+                Some("http://rest.lan:8080/".to_owned())
+            ),
+            "http://rest.lan:8080"
+        );
+    }
+
+    #[cfg(feature = "qdrant-snapshot")]
+    #[test]
+    fn test_multipart_frame() {
+        let (head, tail) = multipart_frame("b0", "neko\"img\".snapshot");
+        let mut body = head;
+        body.extend_from_slice(b"DATA");
+        body.extend_from_slice(&tail);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "--b0\r\nContent-Disposition: form-data; name=\"snapshot\"; \
+             filename=\"neko_img_.snapshot\"\r\nContent-Type: application/octet-stream\r\n\r\n\
+             DATA\r\n--b0--\r\n"
+        );
+        assert_eq!(
+            partial_path(Path::new("backup/images.snapshot")),
+            PathBuf::from("backup/images.snapshot.part")
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! int8 scalar quantization of explorers, per dimension `x ≈ (q - zero_point) * scale`
+//!
+//! The `quantize-explorer` binary quantizes an explorer file and reports its estimated recall.
+
+use crate::atomic_write::atomic_write;
+use crate::cosine_sim::cosine_sim;
+use crate::point_explorer::{PointExplorer, PointExplorerBuilder, PointExplorerError};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::fs;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Max `|quantized cosine - f32 cosine|` expected for CLIP-like vectors calibrated on a
+/// representative sample, values outside the calibrated range are clamped and may exceed it
+pub const QUANT_COSINE_TOLERANCE: f32 = 0.01;
+
+/// Extension appended to the explorer path for its [`QuantParams`]
+pub const QUANT_PARAMS_EXT: &str = "quant";
+
+#[derive(Debug, Error)]
+pub enum QuantError {
+    #[error("Cannot calibrate on an empty explorer")]
+    EmptyExplorer,
+    #[error("QuantParams have {actual} dimensions, explorer has {expected}")]
+    DimMismatch { expected: usize, actual: usize },
+    #[error("Point not found: {0}")]
+    PointNotFound(Uuid),
+    #[error(transparent)]
+    PointExplorerError(#[from] PointExplorerError),
+    #[error(transparent)]
+    BinCodeSerdeEncodeError(#[from] bincode::error::EncodeError),
+    #[error(transparent)]
+    BinCodeSerdeDecodeError(#[from] bincode::error::DecodeError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+pub type QuantResult<T> = Result<T, QuantError>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantParams {
+    pub scale: Vec<f32>,
+    pub zero_point: Vec<i32>,
+    /// Points the ranges were taken from
+    pub sample_size: usize,
+}
+
+impl QuantParams {
+    pub fn dim(&self) -> usize {
+        self.scale.len()
+    }
+
+    #[inline]
+    pub fn quantize(&self, v: &[f32]) -> Vec<i8> {
+        v.iter()
+            .zip(self.scale.iter().zip(&self.zero_point))
+            .map(|(&x, (&s, &z))| ((x / s).round() as i32 + z).clamp(-128, 127) as i8)
+            .collect()
+    }
+
+    #[inline]
+    pub fn dequantize(&self, q: &[i8]) -> Vec<f32> {
+        q.iter()
+            .zip(self.scale.iter().zip(&self.zero_point))
+            .map(|(&q, (&s, &z))| (q as i32 - z) as f32 * s)
+            .collect()
+    }
+
+    /// Cosine of two quantized vectors, dequantized on the fly
+    pub fn cosine(&self, a: &[i8], b: &[i8]) -> f32 {
+        let (mut dot, mut norm_a, mut norm_b) = (0f32, 0f32, 0f32);
+        for (i, (&qa, &qb)) in a.iter().zip(b).enumerate() {
+            let (s, z) = (self.scale[i], self.zero_point[i]);
+            let x = (qa as i32 - z) as f32 * s;
+            let y = (qb as i32 - z) as f32 * s;
+            dot += x * y;
+            norm_a += x * x;
+            norm_b += y * y;
+        }
+        if norm_a == 0.0 || norm_b == 0.0 {
+            return 0.0;
+        }
+        dot / (norm_a.sqrt() * norm_b.sqrt())
+    }
+
+    pub fn save(&self, path: &str) -> QuantResult<()> {
+        let data = bincode::serde::encode_to_vec(self, bincode::config::standard())?;
+        atomic_write(path, data)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> QuantResult<Self> {
+        let data = fs::read(path)?;
+        Ok(bincode::serde::decode_from_slice(&data, bincode::config::standard())?.0)
+    }
+}
+
+/// Where the params of the quantized explorer at `explorer_path` are kept
+pub fn params_path(explorer_path: &str) -> String {
+    format!("{}.{}", explorer_path, QUANT_PARAMS_EXT)
+}
+
+/// Per dimension min/max over `sample_size` points evenly spread over the explorer
+pub fn calibrate<const D: usize>(
+    explorer: &PointExplorer<f32, D>,
+    sample_size: usize,
+) -> QuantResult<QuantParams>
+where
+    [f32; D]: for<'a> TryFrom<&'a [f32]>,
+    for<'a> <[f32; D] as TryFrom<&'a [f32]>>::Error: Debug,
+{
+    if explorer.is_empty() {
+        return Err(QuantError::EmptyExplorer);
+    }
+    let sample_size = sample_size.clamp(1, explorer.len());
+    let step = explorer.len() / sample_size;
+    let (mut min, mut max) = ([f32::INFINITY; D], [f32::NEG_INFINITY; D]);
+    for (_, vector) in explorer.iter().step_by(step).take(sample_size) {
+        for (i, &x) in vector.iter().enumerate() {
+            min[i] = min[i].min(x);
+            max[i] = max[i].max(x);
+        }
+    }
+    let (scale, zero_point) = min
+        .iter()
+        .zip(&max)
+        .map(|(&lo, &hi)| {
+            if hi - lo > f32::EPSILON {
+                let scale = (hi - lo) / 255.0;
+                (scale, -128 - (lo / scale).round() as i32)
+            } else {
+                // constant dimension, keep it exact
+                (lo.abs().max(f32::EPSILON) / 127.0, 0)
+            }
+        })
+        .unzip();
+    Ok(QuantParams {
+        scale,
+        zero_point,
+        sample_size,
+    })
+}
+
+pub fn quantize<const D: usize>(
+    explorer: &PointExplorer<f32, D>,
+    params: &QuantParams,
+) -> QuantResult<PointExplorer<i8, D>>
+where
+    [f32; D]: for<'a> TryFrom<&'a [f32]>,
+    for<'a> <[f32; D] as TryFrom<&'a [f32]>>::Error: Debug,
+    [i8; D]: for<'a> TryFrom<&'a [i8]>,
+    for<'a> <[i8; D] as TryFrom<&'a [i8]>>::Error: Debug,
+{
+    check_dim::<D>(params)?;
+    Ok(explorer.map_vectors(|v| {
+        let mut q = [0i8; D];
+        q.copy_from_slice(&params.quantize(v));
+        q
+    }))
+}
+
+/// Saves the quantized explorer at `path` and its params at [`params_path`]
+pub fn save_quantized<const D: usize>(
+    explorer: &PointExplorer<i8, D>,
+    params: &QuantParams,
+    path: &str,
+) -> QuantResult<()>
+where
+    [i8; D]: for<'a> TryFrom<&'a [i8]>,
+    for<'a> <[i8; D] as TryFrom<&'a [i8]>>::Error: Debug,
+{
+    check_dim::<D>(params)?;
+    explorer.save(path)?;
+    params.save(&params_path(path))
+}
+
+pub fn load_quantized<const D: usize>(
+    path: &str,
+) -> QuantResult<(PointExplorer<i8, D>, QuantParams)>
+where
+    [i8; D]: for<'a> TryFrom<&'a [i8]>,
+    for<'a> <[i8; D] as TryFrom<&'a [i8]>>::Error: Debug,
+{
+    let params = QuantParams::load(&params_path(path))?;
+    check_dim::<D>(&params)?;
+    let explorer = PointExplorerBuilder::new().path(path).build()?;
+    Ok((explorer, params))
+}
+
+fn check_dim<const D: usize>(params: &QuantParams) -> QuantResult<()> {
+    if params.dim() != D {
+        return Err(QuantError::DimMismatch {
+            expected: D,
+            actual: params.dim(),
+        });
+    }
+    Ok(())
+}
+
+/// Each of `n` points evenly spread over the explorer with its f32 nearest neighbour, the pairs
+/// closest to a dedup threshold are the ones quantization can flip
+pub fn sample_pairs<const D: usize>(explorer: &PointExplorer<f32, D>, n: usize) -> Vec<(Uuid, Uuid)>
+where
+    [f32; D]: for<'a> TryFrom<&'a [f32]>,
+    for<'a> <[f32; D] as TryFrom<&'a [f32]>>::Error: Debug,
+{
+    if explorer.len() < 2 || n == 0 {
+        return Vec::new();
+    }
+    let n = n.min(explorer.len());
+    explorer
+        .iter()
+        .step_by(explorer.len() / n)
+        .take(n)
+        .filter_map(|(id, vector)| {
+            explorer
+                .iter()
+                .filter(|(other, _)| *other != id)
+                .map(|(other, v)| (*other, cosine_sim(vector, v)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(other, _)| (*id, other))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RecallEstimate {
+    pub pairs: usize,
+    /// Pairs at or above the threshold in f32
+    pub above_f32: usize,
+    pub above_both: usize,
+    /// Below in f32, above once quantized
+    pub flipped_up: usize,
+    /// Above in f32, below once quantized, i.e. lost matches
+    pub flipped_down: usize,
+    /// `above_both / above_f32`, 1 when no pair is above in f32
+    pub recall: f32,
+    pub max_abs_error: f32,
+    pub mean_abs_error: f32,
+}
+
+/// Compares f32 and quantized similarity of `pairs` around `threshold`
+pub fn estimate_recall<const D: usize>(
+    orig: &PointExplorer<f32, D>,
+    quant: &PointExplorer<i8, D>,
+    params: &QuantParams,
+    threshold: f32,
+    pairs: &[(Uuid, Uuid)],
+) -> QuantResult<RecallEstimate>
+where
+    [f32; D]: for<'a> TryFrom<&'a [f32]>,
+    for<'a> <[f32; D] as TryFrom<&'a [f32]>>::Error: Debug,
+    [i8; D]: for<'a> TryFrom<&'a [i8]>,
+    for<'a> <[i8; D] as TryFrom<&'a [i8]>>::Error: Debug,
+{
+    check_dim::<D>(params)?;
+    let mut estimate = RecallEstimate {
+        pairs: pairs.len(),
+        ..Default::default()
+    };
+    let mut total_error = 0f64;
+    for (a, b) in pairs {
+        let get = |id: &Uuid| {
+            Ok::<_, QuantError>((
+                orig.get_vector(id).ok_or(QuantError::PointNotFound(*id))?,
+                quant.get_vector(id).ok_or(QuantError::PointNotFound(*id))?,
+            ))
+        };
+        let ((fa, qa), (fb, qb)) = (get(a)?, get(b)?);
+        let exact = cosine_sim(fa, fb);
+        let approx = params.cosine(qa, qb);
+        let error = (exact - approx).abs();
+        estimate.max_abs_error = estimate.max_abs_error.max(error);
+        total_error += error as f64;
+        match (exact >= threshold, approx >= threshold) {
+            (true, true) => {
+                estimate.above_f32 += 1;
+                estimate.above_both += 1;
+            }
+            (true, false) => {
+                estimate.above_f32 += 1;
+                estimate.flipped_down += 1;
+            }
+            (false, true) => estimate.flipped_up += 1,
+            (false, false) => {}
+        }
+    }
+    if !pairs.is_empty() {
+        estimate.mean_abs_error = (total_error / pairs.len() as f64) as f32;
+    }
+    estimate.recall = match estimate.above_f32 {
+        0 => 1.0,
+        above => estimate.above_both as f32 / above as f32,
+    };
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    const DIM: usize = 768;
+    /// `config::DEFAULT_IMAGE_SIM`, quant does not need `config`
+    const IMAGE_SIM_THRESHOLD: f32 = 0.985;
+
+    /// Clusters of noisy copies around random centers, the cosine spread straddles the threshold
+    fn synthetic(rng: &mut Pcg64, clusters: usize, size: usize) -> PointExplorer<f32, DIM> {
+        let mut explorer = PointExplorerBuilder::new().build().unwrap();
+        for c in 0..clusters {
+            let center: Vec<f32> = (0..DIM).map(|_| rng.random_range(-1.0..1.0)).collect();
+            let noise = 0.01 + 0.06 * (c % 4) as f32;
+            for _ in 0..size {
+                let v: Vec<f32> = center
+                    .iter()
+                    .map(|x| x + noise * rng.random_range(-1.0..1.0))
+                    .collect();
+                explorer.insert(Uuid::new_v4(), v);
+            }
+        }
+        explorer
+    }
+
+    #[test]
+    fn test_round_trip_within_one_step() {
+        let mut rng = Pcg64::seed_from_u64(42);
+        let explorer = synthetic(&mut rng, 4, 8);
+        let params = calibrate(&explorer, explorer.len()).unwrap();
+        for (_, v) in explorer.iter() {
+            let back = params.dequantize(&params.quantize(v));
+            for (i, (x, y)) in v.iter().zip(&back).enumerate() {
+                assert!((x - y).abs() <= params.scale[i] * 0.5 + 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_constant_dimension() {
+        let mut explorer: PointExplorer<f32, 4> = PointExplorerBuilder::new().build().unwrap();
+        explorer.insert(Uuid::new_v4(), [0.5, 0.0, 1.0, -1.0]);
+        explorer.insert(Uuid::new_v4(), [0.5, 0.0, -1.0, 1.0]);
+        let params = calibrate(&explorer, 10).unwrap();
+        assert_eq!(params.sample_size, 2);
+        let v = [0.5, 0.0, 1.0, -1.0];
+        let q = params.quantize(&v);
+        assert_eq!(&q[..2], &[127, 0]);
+        // constant dimensions come back exact, the others within half a step
+        let back = params.dequantize(&q);
+        assert!((back[0] - 0.5).abs() < 1e-6 && back[1] == 0.0);
+        for i in 2..4 {
+            assert!((back[i] - v[i]).abs() <= params.scale[i] * 0.5 + 1e-6);
+        }
+        assert!(matches!(
+            calibrate::<4>(&PointExplorerBuilder::new().build().unwrap(), 10),
+            Err(QuantError::EmptyExplorer)
+        ));
+    }
+
+    #[test]
+    fn test_cosine_within_tolerance() {
+        let mut rng = Pcg64::seed_from_u64(42);
+        let explorer = synthetic(&mut rng, 20, 10);
+        let params = calibrate(&explorer, 50).unwrap();
+        let quant = quantize(&explorer, &params).unwrap();
+        assert_eq!(quant.len(), explorer.len());
+        let ids: Vec<Uuid> = explorer.iter().map(|(id, _)| *id).collect();
+        let pairs: Vec<(Uuid, Uuid)> = (0..ids.len())
+            .flat_map(|i| (i + 1..ids.len()).map(move |j| (i, j)))
+            .step_by(7)
+            .map(|(i, j)| (ids[i], ids[j]))
+            .collect();
+        let estimate =
+            estimate_recall(&explorer, &quant, &params, IMAGE_SIM_THRESHOLD, &pairs).unwrap();
+        assert_eq!(estimate.pairs, pairs.len());
+        assert!(
+            estimate.max_abs_error < QUANT_COSINE_TOLERANCE,
+            "{:?}",
+            estimate
+        );
+        assert!(estimate.mean_abs_error < estimate.max_abs_error);
+    }
+
+    #[test]
+    fn test_recall_estimation() {
+        let mut rng = Pcg64::seed_from_u64(7);
+        let explorer = synthetic(&mut rng, 40, 5);
+        let params = calibrate(&explorer, 100).unwrap();
+        let quant = quantize(&explorer, &params).unwrap();
+        let pairs = sample_pairs(&explorer, 100);
+        assert_eq!(pairs.len(), 100);
+        let estimate =
+            estimate_recall(&explorer, &quant, &params, IMAGE_SIM_THRESHOLD, &pairs).unwrap();
+        assert!(estimate.above_f32 > 0 && estimate.above_f32 < pairs.len());
+        assert!(estimate.recall >= 0.95, "{:?}", estimate);
+        assert_eq!(
+            estimate.above_both + estimate.flipped_down,
+            estimate.above_f32
+        );
+
+        // a threshold right on top of a pair similarity, any error flips it one way or the other
+        let (a, b) = pairs[0];
+        let exact = cosine_sim(
+            explorer.get_vector(&a).unwrap(),
+            explorer.get_vector(&b).unwrap(),
+        );
+        let approx = params.cosine(quant.get_vector(&a).unwrap(), quant.get_vector(&b).unwrap());
+        let between = (exact + approx) / 2.0;
+        let estimate = estimate_recall(&explorer, &quant, &params, between, &[(a, b)]).unwrap();
+        match exact > approx {
+            true => assert_eq!((estimate.flipped_down, estimate.recall), (1, 0.0)),
+            false => assert_eq!((estimate.flipped_up, estimate.recall), (1, 1.0)),
+        }
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut rng = Pcg64::seed_from_u64(42);
+        let explorer = synthetic(&mut rng, 2, 4);
+        let params = calibrate(&explorer, 8).unwrap();
+        let quant = quantize(&explorer, &params).unwrap();
+        let dir = std::env::temp_dir().join(format!("quant_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("explorer.bin");
+        let path = path.to_str().unwrap();
+        save_quantized(&quant, &params, path).unwrap();
+        assert!(fs::exists(params_path(path)).unwrap());
+        let (loaded, loaded_params) = load_quantized::<DIM>(path).unwrap();
+        assert_eq!(loaded_params, params);
+        for (id, v) in quant.iter() {
+            assert_eq!(loaded.get_vector(id), Some(v));
+        }
+        assert!(matches!(
+            load_quantized::<32>(path),
+            Err(QuantError::DimMismatch {
+                expected: 32,
+                actual: DIM
+            })
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
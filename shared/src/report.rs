@@ -0,0 +1,160 @@
+use std::fmt::Write;
+
+/// Escapes text and attribute values, quotes included so the result is safe in either
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `url` if it is relative or http(s)/file, `#` for any other scheme such as `javascript:`
+pub fn safe_url(url: &str) -> &str {
+    let scheme = url
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| {
+            scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        });
+    match scheme.map(str::to_ascii_lowercase).as_deref() {
+        None | Some("http" | "https" | "file") => url,
+        // `C:\...`, a Windows path rather than a scheme
+        Some(s) if s.len() == 1 => url,
+        Some(_) => "#",
+    }
+}
+
+/// HTML built element by element
+///
+/// Tag and attribute names are `'static` and trusted, every text and attribute value is escaped,
+/// `src` and `href` values also go through [`safe_url`].
+#[derive(Debug, Default, Clone)]
+pub struct Html(String);
+
+impl Html {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn start_tag(&mut self, tag: &'static str, attrs: &[(&'static str, &str)]) {
+        self.0.push('<');
+        self.0.push_str(tag);
+        for &(name, value) in attrs {
+            let value = match name {
+                "src" | "href" => safe_url(value),
+                _ => value,
+            };
+            let _ = write!(self.0, " {}=\"{}\"", name, escape(value));
+        }
+        self.0.push('>');
+    }
+
+    pub fn open(&mut self, tag: &'static str, attrs: &[(&'static str, &str)]) -> &mut Self {
+        self.start_tag(tag, attrs);
+        self
+    }
+
+    pub fn close(&mut self, tag: &'static str) -> &mut Self {
+        let _ = write!(self.0, "</{}>", tag);
+        self
+    }
+
+    /// Element without content or end tag, e.g. `img`
+    pub fn void(&mut self, tag: &'static str, attrs: &[(&'static str, &str)]) -> &mut Self {
+        self.start_tag(tag, attrs);
+        self
+    }
+
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.0.push_str(&escape(text));
+        self
+    }
+
+    /// `open`, `text` and `close` in one
+    pub fn element(
+        &mut self,
+        tag: &'static str,
+        attrs: &[(&'static str, &str)],
+        text: &str,
+    ) -> &mut Self {
+        self.open(tag, attrs).text(text).close(tag)
+    }
+
+    /// Appends already built HTML
+    pub fn append(&mut self, other: &Html) -> &mut Self {
+        self.0.push_str(&other.0);
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+/// A standalone document around `body`, `style` is trusted CSS
+pub fn page(title: &str, style: &str, body: &Html) -> String {
+    let mut head = Html::new();
+    head.void("meta", &[("charset", "utf-8")])
+        .element("title", &[], title);
+    format!(
+        "<!DOCTYPE html>\n<html><head>{}<style>{}</style></head><body>{}</body></html>\n",
+        head.as_str(),
+        style,
+        body.as_str()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+        assert_eq!(escape("plain ねこ"), "plain ねこ");
+    }
+
+    #[test]
+    fn test_safe_url() {
+        assert_eq!(safe_url("https://cdn/a.png"), "https://cdn/a.png");
+        assert_eq!(safe_url("thumbs/a.png"), "thumbs/a.png");
+        assert_eq!(safe_url("/data/thumbs/a:b.png"), "/data/thumbs/a:b.png");
+        assert_eq!(safe_url(r"C:\thumbs\a.png"), r"C:\thumbs\a.png");
+        assert_eq!(safe_url("JavaScript:alert(1)"), "#");
+        assert_eq!(safe_url("data:text/html,x"), "#");
+    }
+
+    #[test]
+    fn test_build() {
+        let mut html = Html::new();
+        html.open("div", &[("class", "a\"b")])
+            .void("img", &[("src", "javascript:x"), ("alt", "<id>")])
+            .element("code", &[], "1 < 2")
+            .close("div");
+        assert_eq!(
+            html.as_str(),
+            r##"<div class="a&quot;b"><img src="#" alt="&lt;id&gt;"><code>1 &lt; 2</code></div>"##
+        );
+        let doc = page("a & b", "body{}", &html);
+        assert!(doc.starts_with("<!DOCTYPE html>"));
+        assert!(doc.contains("<title>a &amp; b</title>"));
+        assert!(doc.contains(html.as_str()));
+    }
+}
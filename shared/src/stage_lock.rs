@@ -0,0 +1,535 @@
+//! Advisory locks keeping stages that mutate the same bucket, collection or artifacts apart
+//!
+//! Every running stage holds `<data_dir>/.stage_locks/<stage>.lock` with its pid, host and start
+//! time. A stage refuses to start while a conflicting lock is held, a stage always conflicts with
+//! itself. Locks whose process is gone (same host only) or older than the age limit are broken.
+//! A lock is written aside and linked into place, so it is never seen incomplete, and the other
+//! locks are checked again once it is: two conflicting stages started in the same instant may
+//! both back out, never both get through.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+const LOCK_DIR: &str = ".stage_locks";
+const LOCK_EXT: &str = "lock";
+/// Unreadable locks younger than this are left alone, older ones are broken
+const UNREADABLE_GRACE: Duration = Duration::from_secs(60);
+/// Directory the pipeline runner runs its steps in, where [`default_lock_dir`] puts the locks
+pub const WORK_DIR_ENV: &str = "NEKO_WORK_DIR";
+/// JSON array of conflicting `["stageA", "stageB"]` pairs replacing [`ConflictMatrix::default`]
+pub const CONFLICTS_ENV: &str = "STAGE_LOCK_CONFLICTS";
+
+#[derive(Debug, thiserror::Error)]
+pub enum StageLockError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{stage} conflicts with {holder}, pass --force-break-lock if it is gone")]
+    Conflict { stage: String, holder: LockInfo },
+    #[error("Lock {path} is unreadable ({source}), pass --force-break-lock if it stays so")]
+    Unreadable {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("Malformed conflict matrix in $STAGE_LOCK_CONFLICTS: {0}")]
+    Conflicts(serde_json::Error),
+}
+
+pub type StageLockResult<T> = Result<T, StageLockError>;
+
+/// Content of a lock file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub stage: String,
+    pub pid: u32,
+    pub host: String,
+    pub started_at: DateTime<Utc>,
+}
+
+impl Display for LockInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (pid {} on {}, started {})",
+            self.stage, self.pid, self.host, self.started_at
+        )
+    }
+}
+
+impl LockInfo {
+    fn current(stage: &str) -> Self {
+        LockInfo {
+            stage: stage.to_string(),
+            pid: std::process::id(),
+            host: hostname(),
+            started_at: Utc::now(),
+        }
+    }
+
+    /// Why the lock can be broken without asking, `None` while its holder may still run
+    fn stale_reason(&self, host: &str, now: DateTime<Utc>, max_age: Duration) -> Option<String> {
+        if self.host == host && !pid_alive(self.pid) {
+            return Some(format!("pid {} is not running", self.pid));
+        }
+        let age = (now - self.started_at).to_std().unwrap_or_default();
+        (age > max_age).then(|| format!("older than {}h", max_age.as_secs() / 3600))
+    }
+}
+
+/// `$NEKO_WORK_DIR` when run by the pipeline, else the current directory
+pub fn default_lock_dir() -> PathBuf {
+    std::env::var_os(WORK_DIR_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|host| host.trim().to_string())
+        .ok()
+        .filter(|host| !host.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Without `/proc` every process counts as running
+fn pid_alive(pid: u32) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        Path::new("/proc").join(pid.to_string()).exists()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+/// Stage pairs that may not run at the same time, in either order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictMatrix {
+    pairs: HashSet<(String, String)>,
+}
+
+impl Default for ConflictMatrix {
+    /// stage6 verifies what stage7 renames, stage8 and stage11 write the Qdrant collection
+    /// stage7 renames are mirrored to, stage15 writes the wrong extension lists of both, stage21
+    /// writes converted objects next to the ones stage8 and stage15 work on
+    fn default() -> Self {
+        ConflictMatrix::new([
+            ("stage6", "stage7"),
+            ("stage6", "stage11"),
+            ("stage6", "stage15"),
+            ("stage7", "stage8"),
+            ("stage7", "stage11"),
+            ("stage7", "stage15"),
+            ("stage8", "stage11"),
+            ("stage8", "stage21"),
+            ("stage15", "stage21"),
+        ])
+    }
+}
+
+impl ConflictMatrix {
+    pub fn new<I, S>(pairs: I) -> Self
+    where
+        I: IntoIterator<Item = (S, S)>,
+        S: Into<String>,
+    {
+        let pairs = pairs
+            .into_iter()
+            .map(|(a, b)| {
+                let (a, b) = (a.into(), b.into());
+                match a <= b {
+                    true => (a, b),
+                    false => (b, a),
+                }
+            })
+            .collect();
+        ConflictMatrix { pairs }
+    }
+
+    /// `$STAGE_LOCK_CONFLICTS` when set, else [`ConflictMatrix::default`]
+    pub fn from_env() -> StageLockResult<Self> {
+        match std::env::var(CONFLICTS_ENV) {
+            Ok(json) => {
+                let pairs: Vec<(String, String)> =
+                    serde_json::from_str(&json).map_err(StageLockError::Conflicts)?;
+                Ok(ConflictMatrix::new(pairs))
+            }
+            Err(_) => Ok(ConflictMatrix::default()),
+        }
+    }
+
+    pub fn conflicts(&self, a: &str, b: &str) -> bool {
+        let key = match a <= b {
+            true => (a.to_string(), b.to_string()),
+            false => (b.to_string(), a.to_string()),
+        };
+        a == b || self.pairs.contains(&key)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LockOptions {
+    conflicts: ConflictMatrix,
+    max_age: Duration,
+    force_break: bool,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        LockOptions {
+            conflicts: ConflictMatrix::default(),
+            max_age: Duration::from_secs(7 * 24 * 3600),
+            force_break: false,
+        }
+    }
+}
+
+impl LockOptions {
+    /// Defaults with the conflicts of [`ConflictMatrix::from_env`]
+    pub fn from_env() -> StageLockResult<Self> {
+        Ok(LockOptions::default().conflicts(ConflictMatrix::from_env()?))
+    }
+
+    pub fn conflicts(mut self, conflicts: ConflictMatrix) -> Self {
+        self.conflicts = conflicts;
+        self
+    }
+
+    /// Locks older than this are stale whatever their host, a week by default
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Break conflicting locks instead of refusing to start
+    pub fn force_break(mut self, force_break: bool) -> Self {
+        self.force_break = force_break;
+        self
+    }
+
+    pub fn acquire<P: AsRef<Path>>(&self, data_dir: P, stage: &str) -> StageLockResult<StageLock> {
+        let dir = data_dir.as_ref().join(LOCK_DIR);
+        fs::create_dir_all(&dir)?;
+        self.scan(&dir, stage, None)?;
+        let path = dir.join(format!("{}.{}", stage, LOCK_EXT));
+        let info = LockInfo::current(stage);
+        publish(&dir, &path, &info)?;
+        let lock = StageLock { path, info };
+        // a conflicting stage may have scanned before our lock appeared, and we before its lock
+        // did: both see each other now and back out, the dropped lock is removed
+        self.scan(&dir, stage, Some(lock.path()))?;
+        tracing::info!("Acquired stage lock {}", lock.path().display());
+        Ok(lock)
+    }
+
+    /// Breaks the stale locks in `dir` and refuses to go on past a conflicting one, `own` aside
+    fn scan(&self, dir: &Path, stage: &str, own: Option<&Path>) -> StageLockResult<()> {
+        let host = hostname();
+        let now = Utc::now();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != LOCK_EXT) || Some(path.as_path()) == own {
+                continue;
+            }
+            let holder = match read_lock(&path) {
+                Ok(Ok(holder)) => holder,
+                // locks are published whole, only something else can have written this one
+                Ok(Err(e)) => {
+                    let age = fs::metadata(&path)?
+                        .modified()?
+                        .elapsed()
+                        .unwrap_or_default();
+                    if age < UNREADABLE_GRACE && !self.force_break {
+                        return Err(StageLockError::Unreadable { path, source: e });
+                    }
+                    tracing::warn!("Breaking unreadable lock {}: {}", path.display(), e);
+                    remove_lock(&path)?;
+                    continue;
+                }
+                // released meanwhile
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(reason) = holder.stale_reason(&host, now, self.max_age) {
+                tracing::warn!("Breaking stale lock of {}: {}", holder, reason);
+                remove_lock(&path)?;
+                continue;
+            }
+            if !self.conflicts.conflicts(stage, &holder.stage) {
+                continue;
+            }
+            match self.force_break {
+                true => {
+                    tracing::warn!("Force breaking the lock of {}", holder);
+                    remove_lock(&path)?;
+                }
+                false => {
+                    return Err(StageLockError::Conflict {
+                        stage: stage.to_string(),
+                        holder,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flags of the stages taking a lock, `#[command(flatten)]` them
+#[derive(Debug, Clone, clap::Args)]
+pub struct LockArgs {
+    /// Directory of the `.stage_locks` kept by every running stage, the pipeline's work directory
+    /// by default
+    #[arg(long, default_value_os_t = default_lock_dir())]
+    pub lock_dir: PathBuf,
+    /// Break conflicting stage locks, for when their holder is gone but not detected as stale
+    #[arg(long)]
+    pub force_break_lock: bool,
+}
+
+impl LockArgs {
+    /// [`LockOptions::from_env`] honouring `--force-break-lock`
+    pub fn options(&self) -> StageLockResult<LockOptions> {
+        Ok(LockOptions::from_env()?.force_break(self.force_break_lock))
+    }
+
+    pub fn acquire(&self, stage: &str) -> StageLockResult<StageLock> {
+        self.options()?.acquire(&self.lock_dir, stage)
+    }
+}
+
+fn read_lock(path: &Path) -> io::Result<serde_json::Result<LockInfo>> {
+    fs::read(path).map(|data| serde_json::from_slice(&data))
+}
+
+/// Writes `info` aside and links it to `path`, so the lock never exists incomplete and is only
+/// created if no lock of the stage is there yet
+fn publish(dir: &Path, path: &Path, info: &LockInfo) -> StageLockResult<()> {
+    let tmp = dir.join(format!("{}.{}.tmp", info.stage, Uuid::new_v4().simple()));
+    let res = (|| {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        file.write_all(&serde_json::to_vec(info).map_err(io::Error::from)?)?;
+        file.sync_all()?;
+        fs::hard_link(&tmp, path)
+    })();
+    let _ = fs::remove_file(&tmp);
+    match res {
+        Ok(()) => Ok(()),
+        // the same stage got there first
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => match read_lock(path) {
+            Ok(Ok(holder)) => Err(StageLockError::Conflict {
+                stage: info.stage.clone(),
+                holder,
+            }),
+            _ => Err(e.into()),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_lock(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Held for the whole run, the lock file is removed on drop
+#[derive(Debug)]
+pub struct StageLock {
+    path: PathBuf,
+    info: LockInfo,
+}
+
+impl StageLock {
+    /// Acquires with [`LockOptions::from_env`]
+    pub fn acquire<P: AsRef<Path>>(data_dir: P, stage: &str) -> StageLockResult<Self> {
+        LockOptions::from_env()?.acquire(data_dir, stage)
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[inline]
+    pub fn info(&self) -> &LockInfo {
+        &self.info
+    }
+}
+
+impl Drop for StageLock {
+    fn drop(&mut self) {
+        if let Err(e) = remove_lock(&self.path) {
+            tracing::warn!("Failed to release {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn data_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("stage_lock_{}", Uuid::new_v4()))
+    }
+
+    fn plant(dir: &Path, info: &LockInfo) -> PathBuf {
+        let locks = dir.join(LOCK_DIR);
+        fs::create_dir_all(&locks).unwrap();
+        let path = locks.join(format!("{}.{}", info.stage, LOCK_EXT));
+        fs::write(&path, serde_json::to_vec(info).unwrap()).unwrap();
+        path
+    }
+
+    fn options() -> LockOptions {
+        LockOptions::default().conflicts(ConflictMatrix::new([("stage6", "stage7")]))
+    }
+
+    #[test]
+    fn test_conflict_matrix() {
+        let matrix = ConflictMatrix::new([("stage7", "stage6")]);
+        assert!(matrix.conflicts("stage6", "stage7"));
+        assert!(matrix.conflicts("stage7", "stage6"));
+        assert!(matrix.conflicts("stage3", "stage3"));
+        assert!(!matrix.conflicts("stage6", "stage3"));
+        assert!(ConflictMatrix::default().conflicts("stage8", "stage7"));
+        assert!(ConflictMatrix::default().conflicts("stage15", "stage7"));
+        assert!(ConflictMatrix::default().conflicts("stage21", "stage8"));
+    }
+
+    #[test]
+    fn test_acquire_and_release_on_drop() {
+        let dir = data_dir();
+        let lock = options().acquire(&dir, "stage7").unwrap();
+        assert!(lock.path().is_file());
+        let on_disk: LockInfo = serde_json::from_slice(&fs::read(lock.path()).unwrap()).unwrap();
+        assert_eq!(&on_disk, lock.info());
+        assert_eq!(on_disk.pid, std::process::id());
+        let path = lock.path().to_path_buf();
+        drop(lock);
+        assert!(!path.exists());
+        // free again
+        drop(options().acquire(&dir, "stage7").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_conflict_refused() {
+        let dir = data_dir();
+        let held = options().acquire(&dir, "stage7").unwrap();
+        let err = options().acquire(&dir, "stage6").unwrap_err();
+        assert!(
+            matches!(&err, StageLockError::Conflict { stage, holder } if stage == "stage6" && holder == held.info()),
+            "{err}"
+        );
+        // the same stage twice
+        assert!(matches!(
+            options().acquire(&dir, "stage7"),
+            Err(StageLockError::Conflict { .. })
+        ));
+        let other = options().acquire(&dir, "stage3").unwrap();
+        assert!(held.path().is_file());
+        drop(other);
+
+        let forced = options().force_break(true).acquire(&dir, "stage6").unwrap();
+        assert!(!held.path().exists());
+        drop(forced);
+        drop(held);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_published_whole_and_rescanned() {
+        let dir = data_dir();
+        let held = options().acquire(&dir, "stage7").unwrap();
+        let locks = dir.join(LOCK_DIR);
+        // nothing but the lock itself is left behind
+        let names: Vec<_> = fs::read_dir(&locks)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["stage7.lock"]);
+        // a lock of the same stage published meanwhile
+        let err = publish(&locks, held.path(), &LockInfo::current("stage7")).unwrap_err();
+        assert!(matches!(err, StageLockError::Conflict { holder, .. } if &holder == held.info()));
+
+        // the check after publishing skips its own lock, not one that appeared in between
+        options().scan(&locks, "stage7", Some(held.path())).unwrap();
+        let other = plant(&dir, &LockInfo::current("stage6"));
+        assert!(matches!(
+            options().scan(&locks, "stage7", Some(held.path())),
+            Err(StageLockError::Conflict { .. })
+        ));
+        fs::remove_file(other).unwrap();
+        drop(held);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_locks_broken() {
+        let dir = data_dir();
+        let old = LockInfo {
+            started_at: Utc::now() - chrono::Duration::days(30),
+            host: "elsewhere".to_string(),
+            ..LockInfo::current("stage7")
+        };
+        let path = plant(&dir, &old);
+        drop(options().acquire(&dir, "stage6").unwrap());
+        assert!(!path.exists());
+
+        // a live pid on another host is left alone
+        let remote = LockInfo {
+            pid: u32::MAX - 1,
+            host: "elsewhere".to_string(),
+            ..LockInfo::current("stage7")
+        };
+        let path = plant(&dir, &remote);
+        assert!(options().acquire(&dir, "stage6").is_err());
+        fs::remove_file(&path).unwrap();
+
+        // unreadable, only broken once it is old enough not to be mid-write
+        let path = dir.join(LOCK_DIR).join("stage7.lock");
+        fs::write(&path, b"{\"stage\":").unwrap();
+        assert!(matches!(
+            options().acquire(&dir, "stage6"),
+            Err(StageLockError::Unreadable { .. })
+        ));
+        let modified = std::time::SystemTime::now() - 2 * UNREADABLE_GRACE;
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        drop(options().acquire(&dir, "stage6").unwrap());
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dead_pid_on_same_host_broken() {
+        let dir = data_dir();
+        let dead = LockInfo {
+            pid: u32::MAX - 1,
+            ..LockInfo::current("stage7")
+        };
+        assert!(!pid_alive(dead.pid));
+        let path = plant(&dir, &dead);
+        drop(options().acquire(&dir, "stage6").unwrap());
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
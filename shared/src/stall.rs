@@ -0,0 +1,267 @@
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Exponential moving average of the completion rate
+#[derive(Debug, Clone, Copy)]
+pub struct EmaRate {
+    alpha: f64,
+    per_sec: Option<f64>,
+}
+
+impl EmaRate {
+    pub fn new(alpha: f64) -> Self {
+        assert!(alpha > 0.0 && alpha <= 1.0, "alpha must be in (0, 1]");
+        Self {
+            alpha,
+            per_sec: None,
+        }
+    }
+
+    /// Feeds the gap between two consecutive completions
+    pub fn update(&mut self, gap: Duration) {
+        let rate = 1.0 / gap.as_secs_f64().max(1e-6);
+        self.per_sec = Some(match self.per_sec {
+            Some(prev) => self.alpha * rate + (1.0 - self.alpha) * prev,
+            None => rate,
+        });
+    }
+
+    #[inline]
+    pub fn per_sec(&self) -> Option<f64> {
+        self.per_sec
+    }
+
+    /// Time left for `remaining` items at the current rate
+    pub fn eta(&self, remaining: usize) -> Option<Duration> {
+        self.per_sec
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+impl Default for EmaRate {
+    fn default() -> Self {
+        Self::new(0.1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StallConfig {
+    /// Warn every time nothing completed for this long
+    pub warn_after: Duration,
+    /// Stop polling once nothing completed for this long
+    pub abort_after: Option<Duration>,
+    /// Most recently started unfinished ids listed per warning
+    pub report_ids: usize,
+}
+
+impl StallConfig {
+    pub fn from_secs(warn_after: u64, abort_after: Option<u64>) -> Self {
+        Self {
+            warn_after: Duration::from_secs(warn_after),
+            abort_after: abort_after.map(Duration::from_secs),
+            ..Default::default()
+        }
+    }
+}
+
+/// Flags of the stages watching their tasks for stalls, `#[command(flatten)]` them
+#[derive(Debug, Clone, clap::Args)]
+pub struct StallArgs {
+    /// Warn when no task completed for this many seconds
+    #[arg(long, default_value = "60")]
+    pub stall_warn_secs: u64,
+    /// Abort and save partial results when no task completed for this many seconds
+    #[arg(long)]
+    pub stall_abort_secs: Option<u64>,
+}
+
+impl From<&StallArgs> for StallConfig {
+    fn from(args: &StallArgs) -> Self {
+        StallConfig::from_secs(args.stall_warn_secs, args.stall_abort_secs)
+    }
+}
+
+impl Default for StallConfig {
+    fn default() -> Self {
+        Self {
+            warn_after: Duration::from_secs(60),
+            abort_after: None,
+            report_ids: 8,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StallError {
+    #[error(
+        "No task completed for {idle:?} after {completed} done, aborted with {} in flight: {in_flight:?}",
+        in_flight.len()
+    )]
+    Aborted {
+        idle: Duration,
+        completed: usize,
+        /// Ids of the started but unfinished tasks, oldest first
+        in_flight: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchSummary {
+    pub completed: usize,
+    /// Number of stall warnings logged
+    pub stalls: usize,
+    pub rate: EmaRate,
+}
+
+/// `buffer_unordered` over `(id, future)` pairs, handing every output to `on_item` as it completes
+///
+/// Nothing completing for `warn_after` logs the in-flight count and the latest started ids, nothing
+/// completing for `abort_after` drops the remaining work and returns [`StallError::Aborted`], so
+/// whatever `on_item` collected so far can be saved as a partial result.
+pub async fn for_each_watched<I, Id, Fut, T, F>(
+    tasks: I,
+    limit: usize,
+    config: &StallConfig,
+    mut on_item: F,
+) -> Result<WatchSummary, StallError>
+where
+    I: IntoIterator<Item = (Id, Fut)>,
+    Id: Display,
+    Fut: Future<Output = T>,
+    F: FnMut(T),
+{
+    let in_flight: Arc<Mutex<BTreeMap<u64, String>>> = Default::default();
+    let mut stream =
+        futures::stream::iter(tasks.into_iter().zip(0u64..).map(|((id, fut), seq)| {
+            let in_flight = in_flight.clone();
+            async move {
+                in_flight.lock().unwrap().insert(seq, id.to_string());
+                let out = fut.await;
+                in_flight.lock().unwrap().remove(&seq);
+                out
+            }
+        }))
+        .buffer_unordered(limit);
+    let mut summary = WatchSummary {
+        completed: 0,
+        stalls: 0,
+        rate: EmaRate::default(),
+    };
+    let mut last = Instant::now();
+    loop {
+        let wait = match config.abort_after {
+            Some(abort) => config.warn_after.min(abort.saturating_sub(last.elapsed())),
+            None => config.warn_after,
+        };
+        match tokio::time::timeout(wait, stream.next()).await {
+            Ok(Some(out)) => {
+                let now = Instant::now();
+                summary.rate.update(now - last);
+                summary.completed += 1;
+                last = now;
+                on_item(out);
+            }
+            Ok(None) => return Ok(summary),
+            Err(_) => {
+                let idle = last.elapsed();
+                let in_flight = in_flight.lock().unwrap();
+                let latest: Vec<&str> = in_flight
+                    .values()
+                    .rev()
+                    .take(config.report_ids)
+                    .map(String::as_str)
+                    .collect();
+                summary.stalls += 1;
+                tracing::warn!(
+                    "No task completed for {:.1?}, {} done at {:.2} items/s, {} in flight, latest started: {:?}",
+                    idle,
+                    summary.completed,
+                    summary.rate.per_sec().unwrap_or(0.0),
+                    in_flight.len(),
+                    latest
+                );
+                if config.abort_after.is_some_and(|abort| idle >= abort) {
+                    return Err(StallError::Aborted {
+                        idle,
+                        completed: summary.completed,
+                        in_flight: in_flight.values().cloned().collect(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use futures::future::BoxFuture;
+
+    fn config(warn_ms: u64, abort_ms: Option<u64>) -> StallConfig {
+        StallConfig {
+            warn_after: Duration::from_millis(warn_ms),
+            abort_after: abort_ms.map(Duration::from_millis),
+            report_ids: 8,
+        }
+    }
+
+    #[test]
+    fn test_ema_rate() {
+        let mut ema = EmaRate::new(0.5);
+        assert!(ema.per_sec().is_none());
+        ema.update(Duration::from_millis(500));
+        assert_eq!(ema.per_sec(), Some(2.0));
+        ema.update(Duration::from_millis(250));
+        assert_eq!(ema.per_sec(), Some(3.0));
+        assert_eq!(ema.eta(6), Some(Duration::from_secs(2)));
+    }
+
+    #[tokio::test]
+    async fn test_abort_on_pending_futures() {
+        let tasks: Vec<(String, BoxFuture<'static, u32>)> = vec![
+            ("a".to_owned(), async { 1 }.boxed()),
+            ("stuck".to_owned(), futures::future::pending().boxed()),
+            ("b".to_owned(), async { 2 }.boxed()),
+            ("late".to_owned(), async { 3 }.boxed()),
+        ];
+        let mut done = Vec::new();
+        let err = for_each_watched(tasks, 3, &config(10, Some(50)), |out| done.push(out))
+            .await
+            .unwrap_err();
+        done.sort();
+        assert_eq!(done, vec![1, 2, 3]);
+        let StallError::Aborted {
+            idle,
+            completed,
+            in_flight,
+        } = err;
+        assert!(idle >= Duration::from_millis(50));
+        assert_eq!(completed, 3);
+        assert_eq!(in_flight, vec!["stuck".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn test_warn_without_abort() {
+        let tasks = (0..4u64).map(|i| {
+            (i, async move {
+                tokio::time::sleep(Duration::from_millis(if i == 2 { 60 } else { 0 })).await;
+                i
+            })
+        });
+        let mut done = Vec::new();
+        let summary = for_each_watched(tasks, 2, &config(10, None), |out| done.push(out))
+            .await
+            .unwrap();
+        done.sort();
+        assert_eq!(done, vec![0, 1, 2, 3]);
+        assert_eq!(summary.completed, 4);
+        assert!(summary.stalls >= 1);
+        assert!(summary.rate.per_sec().is_some());
+    }
+}
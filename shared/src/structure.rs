@@ -1,19 +1,70 @@
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
 use uuid::Uuid;
 #[cfg(feature = "pyo3")]
 use {pyo3::pyclass, pyo3_stub_gen::derive::gen_stub_pyclass};
 
+/// Bumped with every layout change of [`NekoPoint`], see `migrations` for the upgrades
+pub const NEKO_POINT_SCHEMA_VERSION: u32 = 3;
+
 /// P1
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(feature = "pyo3", gen_stub_pyclass, pyclass(get_all))]
 pub struct NekoPoint {
     pub id: Uuid,
     pub height: usize,
-    pub weight: usize,
+    #[serde(alias = "weight")]
+    pub width: usize,
     pub size: Option<usize>, // FIXME: always None in stage2
     pub categories: Option<Vec<String>>,
     pub text_info: Option<NekoPointText>,
+    /// Set for points Qdrant addresses by number, `id` is then their [`num_id_key`]
+    #[serde(default)]
+    pub qdrant_num_id: Option<u64>,
+    /// JSON object of the payload keys stage2 was asked for with `--payload-keys`, for debugging
+    ///
+    /// Text rather than a `serde_json::Value` so the binary points map can hold it.
+    #[serde(default)]
+    pub extra: Option<String>,
+}
+
+impl NekoPoint {
+    /// `(width, height)` in pixels, 0x0 when stage2 could not read them
+    #[inline]
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Long side over short side whatever the orientation, `None` when a side is unknown
+    pub fn aspect_ratio(&self) -> Option<f32> {
+        let (long, short) = match self.height >= self.width {
+            true => (self.height, self.width),
+            false => (self.width, self.height),
+        };
+        (short > 0).then(|| long as f32 / short as f32)
+    }
+
+    #[inline]
+    pub fn pixel_count(&self) -> usize {
+        self.width * self.height
+    }
+}
+
+/// Key standing in for a numeric Qdrant id wherever points are keyed by `Uuid`
+#[inline]
+pub fn num_id_key(num: u64) -> Uuid {
+    Uuid::from_u128(num as u128)
+}
+
+/// Inverse of [`num_id_key`], for keys written without their [`NekoPoint`]
+///
+/// Real UUIDs carry their version in the high half, so only [`num_id_key`] leaves it zero.
+#[inline]
+pub fn key_num_id(key: &Uuid) -> Option<u64> {
+    let bits = key.as_u128();
+    (bits >> 64 == 0).then_some(bits as u64)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,22 +105,61 @@ pub struct WrongExtFile {
     pub expected_ext: String,
 }
 
+impl WrongExtFile {
+    /// Extension the object currently has, `""` if none
+    pub fn current_ext(&self) -> &str {
+        Path::new(&self.path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+    }
+
+    /// Point id encoded in the file stem
+    pub fn point_id(&self) -> Option<Uuid> {
+        Path::new(&self.path).file_stem()?.to_str()?.parse().ok()
+    }
+
+    /// Same key with `expected_ext` in place of the current extension
+    pub fn renamed_path(&self) -> String {
+        Path::new(&self.path)
+            .with_extension(&self.expected_ext)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// One successfully renamed object, entry of stage7 `renamed_manifest.json`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RenamedFile {
+    pub point_id: Uuid,
+    pub old_key: String,
+    pub new_key: String,
+    pub new_ext: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FailedExtFile {
     pub path: String,
     pub error: String,
 }
 
+/// infer and the image crate disagree on the content, kept out of the automatic rename
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AmbiguousExtFile {
+    pub path: String,
+    /// `None` if infer did not recognise the content
+    pub infer_ext: Option<String>,
+    /// `None` if the image crate could not parse the header
+    pub image_ext: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum TriageFile {
     Wrong(WrongExtFile),
     Failed(FailedExtFile),
+    Ambiguous(AmbiguousExtFile),
 }
 
-/// P3
-pub static TEXT_SIM_THRESHOLD: f32 = 0.9;
-pub static IMAGE_SIM_THRESHOLD: f32 = 0.985; // TODO: ?
-
 #[derive(Debug, Serialize)]
 pub struct TriageGif<'a> {
     pub uuid: &'a Uuid,
@@ -84,6 +174,19 @@ pub type TriageGifGroupsGifStageReq<'a> = Vec<Option<TriageGifPair<'a>>>;
 pub type GifFrame = Vec<u8>; // TODO: make it into really "new type" ?
 pub type GifFrames = Vec<GifFrame>;
 
+/// Summary of a decoded GIF, `duration_ms` is the sum of all frame delays
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GifMeta {
+    pub frame_count: usize,
+    pub duration_ms: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Canvas of the original when stage9 stored a downscaled copy, `width` and `height` being
+    /// the copy's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downscaled_from: Option<(u32, u32)>,
+}
+
 #[derive(Debug)]
 pub struct TriageGifClip<'a> {
     pub id: &'a Uuid,
@@ -114,21 +217,43 @@ pub struct TriageGifGroupsGifStagePair<'a> {
     pub discard_same_frame_gif_id: Option<Vec<&'a Uuid>>,
     // pub discard_poor_frame_gif_id: Option<Vec<&'a Uuid>>,
     pub prepare_clip_gif_pair: Option<TriageGifClipPair<'a>>,
+    /// Every GIF sent to the clip stage, outlives `prepare_clip_gif_pair` being taken
+    pub gif_metadata: Option<HashMap<&'a Uuid, GifMeta>>,
 }
 
 pub type TriageGifGroupsGifStageRes<'a> = Vec<Option<TriageGifGroupsGifStagePair<'a>>>;
 
 pub type TriageGifGroupsClipStageReq<'a> = Vec<Option<Option<TriageGifClipPair<'a>>>>;
 
+/// A GIF the frame check kept although the pooled CLIP similarity merged it with `kept`
+#[derive(Debug, Serialize)]
+pub struct UnmergedGif<'a> {
+    pub gif: &'a Uuid,
+    pub kept: &'a Uuid,
+    /// Fraction of the shorter GIF's frames found in order in the other one
+    pub coverage: f32,
+}
+
 #[derive(Debug, Serialize)]
 pub struct TriageGifGroupsClipStagePair<'a> {
     pub kept_gifs: Option<Vec<TriageGif<'a>>>,
     pub discard_duplicate_gifs: Option<Vec<TriageGif<'a>>>,
+    /// Kept GIFs split off their cluster by the frame check, also in `kept_gifs`
+    pub unmerged_gifs: Option<Vec<UnmergedGif<'a>>>,
 }
 
 pub type TriageGifGroupsClipStageRes<'a> = Vec<Option<Option<TriageGifGroupsClipStagePair<'a>>>>;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// How the clusters of a sampled stage9 run were drawn, see its `--sample-fraction`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SampleInfo {
+    pub fraction: f64,
+    pub seed: u64,
+    /// Strata allocated by points rather than by clusters
+    pub by_size: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FinalClassification {
     /// KeptTextAnomaliesPic region
     pub kept_text_anomalies_group: Option<Vec<Uuid>>,
@@ -141,4 +266,200 @@ pub struct FinalClassification {
     pub kept_non_gif: Option<Uuid>,
     /// OtherNeedDeletePics region
     pub other_need_delete_group: Option<Vec<Uuid>>,
+    /// ManualReview region, replaces the automatic keeps when a reviewer overrode the entry
+    #[serde(default)]
+    pub reviewed_keep_group: Option<Vec<Uuid>>,
+    /// Decode summary of the triaged GIFs that reached the clip stage
+    #[serde(default)]
+    pub gif_metadata: Option<HashMap<Uuid, GifMeta>>,
+    /// Index of the cluster in the `global_clusters.pkl` the entry was made from
+    #[serde(default)]
+    pub cluster_index: Option<usize>,
+    /// Set on the entries of a sampled run, which only serve estimates and must not be applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample: Option<SampleInfo>,
+}
+
+impl FinalClassification {
+    /// Every point the entry keeps
+    pub fn kept(&self) -> Vec<Uuid> {
+        [
+            self.kept_text_anomalies_group.as_ref(),
+            self.triaged_gif_and_then_will_keep_group.as_ref(),
+            self.reviewed_keep_group.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .copied()
+        .chain(self.kept_non_gif)
+        .collect()
+    }
+
+    /// Every point the entry deletes
+    pub fn discarded(&self) -> Vec<Uuid> {
+        [
+            self.triaged_gif_and_invalid_group
+                .as_ref()
+                .map(|(ids, _)| ids),
+            self.triaged_gif_and_discard_same_frame_group.as_ref(),
+            self.triaged_gif_and_then_will_delete_group.as_ref(),
+            self.other_need_delete_group.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .copied()
+        .collect()
+    }
+
+    /// Every point the entry decided on, kept or deleted
+    pub fn handled(&self) -> Vec<Uuid> {
+        let mut ids = self.kept();
+        ids.extend(self.discarded());
+        ids
+    }
+
+    #[inline]
+    pub fn is_sample(&self) -> bool {
+        self.sample.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triage_file_before_ambiguous_parses() {
+        let wrong: TriageFile =
+            serde_json::from_str(r#"{"Wrong":{"path":"a/1.png","expected_ext":"jpg"}}"#).unwrap();
+        assert!(matches!(wrong, TriageFile::Wrong(w) if w.expected_ext == "jpg"));
+        let failed: TriageFile =
+            serde_json::from_str(r#"{"Failed":{"path":"a/2.png","error":"read error"}}"#).unwrap();
+        assert!(matches!(failed, TriageFile::Failed(f) if f.error == "read error"));
+        // stage6 output files hold the inner structs, their shape is unchanged
+        let files: Vec<WrongExtFile> =
+            serde_json::from_str(r#"[{"path":"a/1.png","expected_ext":"jpg"}]"#).unwrap();
+        assert_eq!(files[0].renamed_path(), "a/1.jpg");
+    }
+
+    #[test]
+    fn test_triage_file_ambiguous_round_trip() {
+        let file = TriageFile::Ambiguous(AmbiguousExtFile {
+            path: "a/3.png".to_string(),
+            infer_ext: Some("zip".to_string()),
+            image_ext: Some("png".to_string()),
+        });
+        let json = serde_json::to_string(&file).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Ambiguous":{"path":"a/3.png","infer_ext":"zip","image_ext":"png"}}"#
+        );
+        let back: TriageFile = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            back,
+            TriageFile::Ambiguous(AmbiguousExtFile { infer_ext: Some(i), image_ext: Some(m), .. })
+                if i == "zip" && m == "png"
+        ));
+        let unknown: AmbiguousExtFile =
+            serde_json::from_str(r#"{"path":"a/4","infer_ext":null,"image_ext":"gif"}"#).unwrap();
+        assert!(unknown.infer_ext.is_none());
+    }
+
+    #[test]
+    fn test_final_classification_gif_metadata() {
+        let old: FinalClassification = serde_json::from_str(
+            r#"{"kept_text_anomalies_group":null,"triaged_gif_and_invalid_group":null,
+            "triaged_gif_and_discard_same_frame_group":null,
+            "triaged_gif_and_then_will_keep_group":null,
+            "triaged_gif_and_then_will_delete_group":null,
+            "kept_non_gif":null,"other_need_delete_group":null}"#,
+        )
+        .unwrap();
+        assert!(old.gif_metadata.is_none());
+        assert!(old.cluster_index.is_none());
+        assert!(!old.is_sample());
+        let id = Uuid::from_u128(1);
+        let meta = GifMeta {
+            frame_count: 12,
+            duration_ms: 1200,
+            width: 320,
+            height: 240,
+            downscaled_from: Some((1280, 960)),
+        };
+        let json = serde_json::to_string(&FinalClassification {
+            gif_metadata: Some(HashMap::from([(id, meta)])),
+            ..old.clone()
+        })
+        .unwrap();
+        assert!(!json.contains("sample"));
+        let back: FinalClassification = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.gif_metadata.unwrap()[&id], meta);
+        let sample = SampleInfo {
+            fraction: 0.05,
+            seed: 7,
+            by_size: true,
+        };
+        let json = serde_json::to_string(&FinalClassification {
+            sample: Some(sample),
+            ..old
+        })
+        .unwrap();
+        let back: FinalClassification = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.sample, Some(sample));
+    }
+
+    #[test]
+    fn test_neko_point_width() {
+        let json = |field: &str| {
+            format!(
+                r#"{{"id":"00000000-0000-0000-0000-000000000001","height":1080,"{}":1920,"size":null,"categories":null,"text_info":null}}"#,
+                field
+            )
+        };
+        let legacy: NekoPoint = serde_json::from_str(&json("weight")).unwrap();
+        let current: NekoPoint = serde_json::from_str(&json("width")).unwrap();
+        for point in [&legacy, &current] {
+            assert_eq!(point.resolution(), (1920, 1080));
+        }
+        let written = serde_json::to_string(&legacy).unwrap();
+        assert!(written.contains(r#""width":1920"#) && !written.contains("weight"));
+
+        assert_eq!(legacy.pixel_count(), 1920 * 1080);
+        assert_eq!(legacy.aspect_ratio(), Some(16.0 / 9.0));
+        let portrait = NekoPoint {
+            height: 1920,
+            width: 1080,
+            ..legacy.clone()
+        };
+        assert_eq!(portrait.aspect_ratio(), legacy.aspect_ratio());
+        let unknown = NekoPoint {
+            height: 0,
+            width: 0,
+            ..legacy
+        };
+        assert_eq!((unknown.aspect_ratio(), unknown.pixel_count()), (None, 0));
+    }
+
+    #[test]
+    fn test_final_classification_handled() {
+        let id = Uuid::from_u128;
+        let entry = FinalClassification {
+            kept_text_anomalies_group: Some(vec![id(1)]),
+            triaged_gif_and_invalid_group: Some((vec![id(2)], vec!["broken".to_string()])),
+            triaged_gif_and_discard_same_frame_group: None,
+            triaged_gif_and_then_will_keep_group: None,
+            triaged_gif_and_then_will_delete_group: Some(vec![id(3)]),
+            kept_non_gif: Some(id(4)),
+            other_need_delete_group: Some(vec![id(5), id(6)]),
+            reviewed_keep_group: None,
+            gif_metadata: None,
+            cluster_index: None,
+            sample: None,
+        };
+        let mut handled = entry.handled();
+        handled.sort_unstable();
+        assert_eq!(handled, (1..=6).map(id).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,165 @@
+//! Test doubles for the traits stages are generic over and shared fixtures, built with the
+//! `test-util` feature
+
+#[cfg(feature = "shared-structure")]
+use crate::structure::FinalClassification;
+use uuid::Uuid;
+
+#[cfg(feature = "qdrant-ext")]
+pub use memory_store::{MemoryPointStore, Transient};
+
+/// Fixture ids spelled as small numbers
+pub fn id(n: u128) -> Uuid {
+    Uuid::from_u128(n)
+}
+
+/// Entry keeping the non-GIF `kept` and deleting `deleted`, its other groups empty
+#[cfg(feature = "shared-structure")]
+pub fn classification(kept: u128, deleted: &[u128]) -> FinalClassification {
+    FinalClassification {
+        kept_non_gif: Some(id(kept)),
+        other_need_delete_group: Some(deleted.iter().copied().map(id).collect()),
+        ..Default::default()
+    }
+}
+
+#[cfg(feature = "qdrant-ext")]
+mod memory_store {
+    use crate::qdrant::{PointRecord, PointStore};
+    use serde_json::{Map, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    /// Error of the writes to [`MemoryPointStore::flaky`] points, the only one worth a retry
+    #[derive(Debug, thiserror::Error)]
+    #[error("injected transient failure")]
+    pub struct Transient;
+
+    /// [`PointStore`] over a map, with injectable failures
+    ///
+    /// Writes touching `flaky` fail with [`Transient`] that many times, those touching `broken`
+    /// are always rejected, reads touching `unreadable` always time out. Setting a payload merges
+    /// it as Qdrant does, into an empty record for points the store does not hold.
+    #[derive(Debug, Default)]
+    pub struct MemoryPointStore {
+        pub points: Mutex<HashMap<Uuid, PointRecord>>,
+        pub broken: HashSet<Uuid>,
+        pub flaky: Mutex<HashMap<Uuid, u32>>,
+        pub unreadable: HashSet<Uuid>,
+        /// Points losing their payload on upsert
+        pub corrupt: HashSet<Uuid>,
+        /// Ids of every write request, in the order they came in
+        pub requests: Mutex<Vec<Vec<Uuid>>>,
+        /// Ids of every delete that went through, in order
+        pub deleted: Mutex<Vec<Uuid>>,
+    }
+
+    impl MemoryPointStore {
+        pub fn new<I: IntoIterator<Item = PointRecord>>(points: I) -> Self {
+            Self {
+                points: Mutex::new(points.into_iter().map(|p| (p.id, p)).collect()),
+                ..Default::default()
+            }
+        }
+
+        /// Points without vectors
+        pub fn with_payloads<I: IntoIterator<Item = (Uuid, Map<String, Value>)>>(
+            payloads: I,
+        ) -> Self {
+            Self::new(payloads.into_iter().map(|(id, payload)| PointRecord {
+                id,
+                vectors: HashMap::new(),
+                payload,
+            }))
+        }
+
+        /// Payload of every point held
+        pub fn payloads(&self) -> HashMap<Uuid, Map<String, Value>> {
+            let points = self.points.lock().unwrap();
+            points
+                .iter()
+                .map(|(id, p)| (*id, p.payload.clone()))
+                .collect()
+        }
+
+        fn check_read(&self, ids: &[Uuid]) -> anyhow::Result<()> {
+            if ids.iter().any(|id| self.unreadable.contains(id)) {
+                anyhow::bail!("read timed out");
+            }
+            Ok(())
+        }
+
+        fn check_write(&self, ids: &[Uuid]) -> anyhow::Result<()> {
+            self.requests.lock().unwrap().push(ids.to_vec());
+            if ids.iter().any(|id| self.broken.contains(id)) {
+                anyhow::bail!("rejected");
+            }
+            let mut flaky = self.flaky.lock().unwrap();
+            for id in ids {
+                if let Some(left @ 1..) = flaky.get_mut(id) {
+                    *left -= 1;
+                    return Err(Transient.into());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl PointStore for MemoryPointStore {
+        async fn upsert(&self, points: &[PointRecord]) -> anyhow::Result<()> {
+            self.check_write(&points.iter().map(|p| p.id).collect::<Vec<_>>())?;
+            let mut stored = self.points.lock().unwrap();
+            for point in points {
+                let mut point = point.clone();
+                if self.corrupt.contains(&point.id) {
+                    point.payload.clear();
+                }
+                stored.insert(point.id, point);
+            }
+            Ok(())
+        }
+
+        async fn get(&self, ids: &[Uuid]) -> anyhow::Result<Vec<PointRecord>> {
+            self.check_read(ids)?;
+            let stored = self.points.lock().unwrap();
+            Ok(ids
+                .iter()
+                .filter_map(|id| stored.get(id).cloned())
+                .collect())
+        }
+
+        async fn set_payload(
+            &self,
+            ids: &[Uuid],
+            payload: &Map<String, Value>,
+        ) -> anyhow::Result<()> {
+            self.check_write(ids)?;
+            let mut stored = self.points.lock().unwrap();
+            for id in ids {
+                let point = stored.entry(*id).or_insert_with(|| PointRecord {
+                    id: *id,
+                    vectors: HashMap::new(),
+                    payload: Map::new(),
+                });
+                point.payload.extend(payload.clone());
+            }
+            Ok(())
+        }
+
+        async fn delete_points(&self, ids: &[Uuid]) -> anyhow::Result<()> {
+            self.check_write(ids)?;
+            let mut stored = self.points.lock().unwrap();
+            for id in ids {
+                stored.remove(id);
+            }
+            self.deleted.lock().unwrap().extend_from_slice(ids);
+            Ok(())
+        }
+
+        fn retry_hint(&self, error: &anyhow::Error) -> Option<Duration> {
+            error.downcast_ref::<Transient>().map(|_| Duration::ZERO)
+        }
+    }
+}
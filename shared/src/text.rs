@@ -0,0 +1,237 @@
+//! Display forms of OCR text
+//!
+//! OCR output is whatever the model read off an image: decomposed accents, stray control and
+//! direction characters, pages of noise off a screenshot of a hex dump. Reports and representative
+//! policies work on the forms built here, the stored text stays as it was read.
+
+use std::borrow::Cow;
+use unicode_normalization::UnicodeNormalization;
+use unicode_script::{Script, UnicodeScript};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Graphemes of a text shown by reports
+pub const DEFAULT_MAX_GRAPHEMES: usize = 200;
+/// Appended to a truncated text
+pub const ELLIPSIS: &str = "…";
+/// [`GarbageScore::score`] from which a text counts as garbage
+pub const DEFAULT_GARBAGE_THRESHOLD: f32 = 0.5;
+
+/// Direction marks, embeddings, overrides and isolates, they reorder whatever is displayed around
+/// them
+fn is_bidi_control(c: char) -> bool {
+    matches!(
+        c,
+        '\u{61c}' | '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}'
+    )
+}
+
+/// NFC, with tabs and line breaks turned into spaces and the other control characters and the
+/// bidi formatting characters dropped
+pub fn clean(text: &str) -> String {
+    text.nfc()
+        .filter(|&c| !is_bidi_control(c))
+        .filter_map(|c| match c.is_control() {
+            true => c.is_whitespace().then_some(' '),
+            false => Some(c),
+        })
+        .collect()
+}
+
+/// The first `max` graphemes of `text` followed by [`ELLIPSIS`], `text` itself if it is not longer
+///
+/// Cuts between graphemes, never inside a flag, a joined emoji or a letter and its marks.
+pub fn truncate_graphemes(text: &str, max: usize) -> Cow<'_, str> {
+    match text.grapheme_indices(true).nth(max) {
+        Some((at, _)) => Cow::Owned(format!("{}{}", &text[..at], ELLIPSIS)),
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// [`clean`] and [`truncate_graphemes`]
+pub fn sanitize(text: &str, max_graphemes: usize) -> String {
+    let cleaned = clean(text);
+    match truncate_graphemes(&cleaned, max_graphemes) {
+        Cow::Borrowed(_) => cleaned,
+        Cow::Owned(truncated) => truncated,
+    }
+}
+
+/// How little a text looks like something written, from 0 for prose to 1
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GarbageScore {
+    /// Characters counted, whitespace and combining marks are not
+    pub chars: usize,
+    /// Share of them that are neither letters nor digits
+    pub non_alphanumeric: f32,
+    /// Script changes between consecutive letters per letter, Han, kana and Hangul are one script
+    pub script_switches: f32,
+    /// The larger of the two, 1 for a text without any counted character
+    pub score: f32,
+}
+
+impl GarbageScore {
+    #[inline]
+    pub fn is_garbage(&self, threshold: f32) -> bool {
+        self.score >= threshold
+    }
+}
+
+/// Scripts written together within one sentence, switching between them is not noise
+fn script_group(script: Script) -> Option<Script> {
+    match script {
+        Script::Common | Script::Inherited | Script::Unknown => None,
+        Script::Hiragana | Script::Katakana | Script::Hangul | Script::Bopomofo => {
+            Some(Script::Han)
+        }
+        script => Some(script),
+    }
+}
+
+/// Scores the text as it was read, composed first so that decomposed accents count like the
+/// composed ones
+pub fn garbage_score(text: &str) -> GarbageScore {
+    let (mut chars, mut non_alphanumeric, mut letters, mut switches) = (0usize, 0usize, 0usize, 0);
+    let mut last_group = None;
+    for c in text.nfc() {
+        if c.is_whitespace() || c.script() == Script::Inherited {
+            continue;
+        }
+        chars += 1;
+        if !c.is_alphanumeric() {
+            non_alphanumeric += 1;
+        }
+        if !c.is_alphabetic() {
+            continue;
+        }
+        letters += 1;
+        let Some(group) = script_group(c.script()) else {
+            continue;
+        };
+        if last_group.is_some_and(|last| last != group) {
+            switches += 1;
+        }
+        last_group = Some(group);
+    }
+    if chars == 0 {
+        return GarbageScore {
+            score: 1.0,
+            ..Default::default()
+        };
+    }
+    let non_alphanumeric = non_alphanumeric as f32 / chars as f32;
+    let script_switches = match letters {
+        0 => 0.0,
+        _ => switches as f32 / letters as f32,
+    };
+    GarbageScore {
+        chars,
+        non_alphanumeric,
+        script_switches,
+        score: non_alphanumeric.max(script_switches),
+    }
+}
+
+/// [`garbage_score`] at [`DEFAULT_GARBAGE_THRESHOLD`]
+pub fn is_garbage(text: &str) -> bool {
+    garbage_score(text).is_garbage(DEFAULT_GARBAGE_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean() {
+        // decomposed and composed é are one text
+        assert_eq!(clean("caf\u{65}\u{301}"), "caf\u{e9}");
+        assert_eq!(clean("a\u{0}b\u{7}c\u{9f}"), "abc");
+        assert_eq!(clean("line\none\tand\r\ntwo"), "line one and  two");
+        // an override must not flip the rest of a report line
+        assert_eq!(
+            clean("\u{202e}txt.exe\u{202c} \u{2067}x\u{2069}"),
+            "txt.exe x"
+        );
+        assert_eq!(clean("\u{200f}(1)\u{200e}"), "(1)");
+        let rtl = "שלום עולם مرحبا";
+        assert_eq!(clean(rtl), rtl);
+        // joiners and variation selectors hold emoji together
+        let family = "👨\u{200d}👩\u{200d}👧 ❤\u{fe0f}";
+        assert_eq!(clean(family), family);
+    }
+
+    #[test]
+    fn test_truncate_graphemes() {
+        assert_eq!(truncate_graphemes("short", 10), Cow::Borrowed("short"));
+        assert_eq!(truncate_graphemes("exact", 5), Cow::Borrowed("exact"));
+        assert_eq!(truncate_graphemes("abcdef", 3), "abc…");
+        assert_eq!(truncate_graphemes("abc", 0), "…");
+        assert_eq!(truncate_graphemes("", 0), "");
+        assert_eq!(truncate_graphemes("日本語のテキスト", 3), "日本語…");
+        assert_eq!(truncate_graphemes("🇯🇵🇫🇷🇩🇪", 2), "🇯🇵🇫🇷…");
+        assert_eq!(
+            truncate_graphemes("👨\u{200d}👩\u{200d}👧👍🏽x", 2),
+            "👨\u{200d}👩\u{200d}👧👍🏽…"
+        );
+        assert_eq!(
+            truncate_graphemes("e\u{301}e\u{301}e\u{301}", 1),
+            "e\u{301}…"
+        );
+        assert_eq!(truncate_graphemes("مرحبا بالعالم", 5), "مرحبا…");
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let long: String = "meme caption ".repeat(10_000);
+        let sanitized = sanitize(&format!("\u{1b}[31m{}", long), 12);
+        assert_eq!(sanitized, "[31mmeme cap…");
+        assert_eq!(sanitize("ok", DEFAULT_MAX_GRAPHEMES), "ok");
+    }
+
+    #[test]
+    fn test_garbage_score() {
+        for text in [
+            "When the code compiles on the first try",
+            "Ça fait 2 ans, déjà.",
+            "今日はいい天気ですね",
+            "배고파 라면 먹자",
+            "مرحبا بالعالم",
+            "שלום עולם",
+            "Привет, мир!",
+            "这个 meme 太好笑了",
+        ] {
+            let score = garbage_score(text);
+            assert!(
+                !score.is_garbage(DEFAULT_GARBAGE_THRESHOLD),
+                "{}: {:?}",
+                text,
+                score
+            );
+            assert!(!is_garbage(text));
+        }
+        for text in [
+            "|||:;.,~~-- ==__ ##",
+            "0x1f @@ %% &&& ** ^^",
+            "aБcДeЖgИ",
+            "日aБ日aБ",
+            "😂😂😂🔥🔥",
+            "\u{0}\u{1}\u{2}",
+            "",
+            "  \n\t ",
+        ] {
+            assert!(is_garbage(text), "{:?}: {:?}", text, garbage_score(text));
+        }
+
+        let prose = garbage_score("Hello, world!");
+        assert_eq!(prose.chars, 12);
+        assert!((prose.non_alphanumeric - 2.0 / 12.0).abs() < 1e-6);
+        assert_eq!(prose.script_switches, 0.0);
+        // combining marks are not counted, composed or not
+        assert_eq!(
+            garbage_score("e\u{301}t\u{e9}"),
+            garbage_score("\u{e9}t\u{e9}")
+        );
+        assert_eq!(garbage_score("e\u{301}t\u{e9}").chars, 3);
+        let empty = garbage_score("");
+        assert_eq!((empty.chars, empty.score), (0, 1.0));
+    }
+}
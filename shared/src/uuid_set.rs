@@ -0,0 +1,310 @@
+use crate::atomic_write::atomic_write_with;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// File layout: `MAGIC | count: u64 LE | count * 16 raw uuid bytes, strictly ascending`
+pub const UUID_SET_MAGIC: &[u8; 8] = b"NKUSET01";
+const HEADER_LEN: usize = UUID_SET_MAGIC.len() + size_of::<u64>();
+const UUID_LEN: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum UuidSetError {
+    #[error("Invalid UuidSet magic")]
+    InvalidMagic,
+    #[error("Invalid UuidSet length: {0} bytes")]
+    InvalidLength(usize),
+    #[error("UuidSet header claims {expected} uuids, body holds {actual}")]
+    CountMismatch { expected: u64, actual: usize },
+    #[error("UuidSet is not strictly sorted at index {0}")]
+    Unsorted(usize),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    SerdePickleError(#[from] serde_pickle::Error),
+}
+
+pub type UuidSetResult<T> = Result<T, UuidSetError>;
+
+/// Sorted set of uuids borrowed from an encoded buffer (e.g. a memory-mapped file)
+#[derive(Debug, Copy, Clone)]
+pub struct UuidSetRef<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> UuidSetRef<'a> {
+    /// Validates header, length and ordering, O(n) once
+    pub fn parse(bytes: &'a [u8]) -> UuidSetResult<Self> {
+        if bytes.len() < HEADER_LEN {
+            return Err(UuidSetError::InvalidLength(bytes.len()));
+        }
+        let (header, body) = bytes.split_at(HEADER_LEN);
+        if &header[..UUID_SET_MAGIC.len()] != UUID_SET_MAGIC {
+            return Err(UuidSetError::InvalidMagic);
+        }
+        if body.len() % UUID_LEN != 0 {
+            return Err(UuidSetError::InvalidLength(bytes.len()));
+        }
+        let expected = u64::from_le_bytes(header[UUID_SET_MAGIC.len()..].try_into().unwrap());
+        let actual = body.len() / UUID_LEN;
+        if expected != actual as u64 {
+            return Err(UuidSetError::CountMismatch { expected, actual });
+        }
+        let set = Self { body };
+        if let Some(idx) = (1..actual).find(|&i| set.raw(i - 1) >= set.raw(i)) {
+            return Err(UuidSetError::Unsorted(idx));
+        }
+        Ok(set)
+    }
+
+    #[inline]
+    fn raw(&self, idx: usize) -> &'a [u8] {
+        &self.body[idx * UUID_LEN..(idx + 1) * UUID_LEN]
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.body.len() / UUID_LEN
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.body.is_empty()
+    }
+
+    /// O(log n) binary search over the raw bytes
+    pub fn contains(&self, id: &Uuid) -> bool {
+        let needle = id.as_bytes().as_slice();
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.raw(mid).cmp(needle) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return true,
+            }
+        }
+        false
+    }
+
+    /// Ascending order
+    pub fn iter(self) -> impl ExactSizeIterator<Item = Uuid> + 'a {
+        self.body
+            .chunks_exact(UUID_LEN)
+            .map(|chunk| Uuid::from_slice(chunk).unwrap())
+    }
+
+    pub fn to_hash_set(&self) -> HashSet<Uuid> {
+        self.iter().collect()
+    }
+}
+
+/// Owned, encoded [`UuidSetRef`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UuidSet {
+    bytes: Vec<u8>,
+}
+
+impl UuidSet {
+    pub fn from_bytes(bytes: Vec<u8>) -> UuidSetResult<Self> {
+        UuidSetRef::parse(&bytes)?;
+        Ok(Self { bytes })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> UuidSetResult<Self> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> UuidSetResult<()> {
+        atomic_write_with(path, |w| w.write_all(&self.bytes))?;
+        Ok(())
+    }
+
+    /// Reads a pickled `HashSet<Uuid>`
+    pub fn from_pickle(data: &[u8]) -> UuidSetResult<Self> {
+        let ids: HashSet<Uuid> = serde_pickle::from_slice(data, Default::default())?;
+        Ok(ids.into_iter().collect())
+    }
+
+    pub fn to_pickle(&self) -> UuidSetResult<Vec<u8>> {
+        Ok(serde_pickle::to_vec(
+            &self.to_hash_set(),
+            serde_pickle::SerOptions::default(),
+        )?)
+    }
+
+    /// Picks the format from the `.pkl` extension, anything else is the binary layout
+    pub fn load_any<P: AsRef<Path>>(path: P) -> UuidSetResult<Self> {
+        let path = path.as_ref();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("pkl") => Self::from_pickle(&fs::read(path)?),
+            _ => Self::load(path),
+        }
+    }
+
+    #[inline]
+    pub fn as_set_ref(&self) -> UuidSetRef<'_> {
+        UuidSetRef {
+            body: &self.bytes[HEADER_LEN..],
+        }
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.as_set_ref().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.as_set_ref().is_empty()
+    }
+
+    #[inline]
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.as_set_ref().contains(id)
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = Uuid> + '_ {
+        self.as_set_ref().iter()
+    }
+
+    pub fn to_hash_set(&self) -> HashSet<Uuid> {
+        self.as_set_ref().to_hash_set()
+    }
+}
+
+impl FromIterator<Uuid> for UuidSet {
+    /// Sorts and drops duplicates
+    fn from_iter<I: IntoIterator<Item = Uuid>>(iter: I) -> Self {
+        let mut ids: Vec<Uuid> = iter.into_iter().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut bytes = Vec::with_capacity(HEADER_LEN + ids.len() * UUID_LEN);
+        bytes.extend_from_slice(UUID_SET_MAGIC);
+        bytes.extend_from_slice(&(ids.len() as u64).to_le_bytes());
+        ids.iter()
+            .for_each(|id| bytes.extend_from_slice(id.as_bytes()));
+        Self { bytes }
+    }
+}
+
+impl<'a> FromIterator<&'a Uuid> for UuidSet {
+    fn from_iter<I: IntoIterator<Item = &'a Uuid>>(iter: I) -> Self {
+        iter.into_iter().copied().collect()
+    }
+}
+
+impl From<&HashSet<Uuid>> for UuidSet {
+    fn from(set: &HashSet<Uuid>) -> Self {
+        set.iter().collect()
+    }
+}
+
+impl From<&UuidSet> for HashSet<Uuid> {
+    fn from(set: &UuidSet) -> Self {
+        set.to_hash_set()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_ids(rng: &mut StdRng, n: usize) -> Vec<Uuid> {
+        (0..n).map(|_| Uuid::from_u128(rng.random())).collect()
+    }
+
+    #[test]
+    fn test_sorted_invariant() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let set: UuidSet = random_ids(&mut rng, 1000).into_iter().collect();
+        let ids: Vec<Uuid> = set.iter().collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(UuidSet::from_bytes(set.as_bytes().to_vec()).unwrap(), set);
+    }
+
+    #[test]
+    fn test_duplicates_on_construction() {
+        let a = Uuid::from_u128(3);
+        let b = Uuid::from_u128(1);
+        let set: UuidSet = [a, b, a, a, b].into_iter().collect();
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![b, a]);
+        let empty: UuidSet = std::iter::empty::<Uuid>().collect();
+        assert!(empty.is_empty());
+        assert!(!empty.contains(&a));
+    }
+
+    #[test]
+    fn test_rejects_invalid_bytes() {
+        let set: UuidSet = [Uuid::from_u128(1), Uuid::from_u128(2)]
+            .into_iter()
+            .collect();
+        let mut swapped = set.as_bytes().to_vec();
+        swapped[HEADER_LEN..].rotate_left(UUID_LEN);
+        assert!(matches!(
+            UuidSetRef::parse(&swapped),
+            Err(UuidSetError::Unsorted(1))
+        ));
+        let mut dup = set.as_bytes().to_vec();
+        let first = dup[HEADER_LEN..HEADER_LEN + UUID_LEN].to_vec();
+        dup[HEADER_LEN + UUID_LEN..].copy_from_slice(&first);
+        assert!(matches!(
+            UuidSetRef::parse(&dup),
+            Err(UuidSetError::Unsorted(1))
+        ));
+        let truncated = &set.as_bytes()[..set.as_bytes().len() - 1];
+        assert!(matches!(
+            UuidSetRef::parse(truncated),
+            Err(UuidSetError::InvalidLength(_))
+        ));
+        let short = &set.as_bytes()[..HEADER_LEN + UUID_LEN];
+        assert!(matches!(
+            UuidSetRef::parse(short),
+            Err(UuidSetError::CountMismatch {
+                expected: 2,
+                actual: 1
+            })
+        ));
+        assert!(matches!(
+            UuidSetRef::parse(&[0u8; HEADER_LEN]),
+            Err(UuidSetError::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn test_membership_against_hash_set() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let ids = random_ids(&mut rng, 10_000);
+        let oracle: HashSet<Uuid> = ids.iter().copied().collect();
+        let set = UuidSet::from(&oracle);
+        let borrowed = UuidSetRef::parse(set.as_bytes()).unwrap();
+        assert_eq!(borrowed.len(), oracle.len());
+        for id in ids.iter().chain(random_ids(&mut rng, 10_000).iter()) {
+            assert_eq!(borrowed.contains(id), oracle.contains(id));
+        }
+        assert_eq!(HashSet::from(&set), oracle);
+    }
+
+    #[test]
+    fn test_pickle_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let oracle: HashSet<Uuid> = random_ids(&mut rng, 100).into_iter().collect();
+        let pickled = serde_pickle::to_vec(&oracle, Default::default()).unwrap();
+        let set = UuidSet::from_pickle(&pickled).unwrap();
+        assert_eq!(set.to_hash_set(), oracle);
+        let back: HashSet<Uuid> =
+            serde_pickle::from_slice(&set.to_pickle().unwrap(), Default::default()).unwrap();
+        assert_eq!(back, oracle);
+    }
+}
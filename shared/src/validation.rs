@@ -0,0 +1,234 @@
+//! Sanity checks of a loaded points map, before a stage computes anything from it
+//!
+//! A text vector of the wrong length does not fail anywhere downstream, cosine similarities of it
+//! are just wrong. Stages run [`validate_neko_points`] right after loading and refuse to go on
+//! unless told to be lenient.
+
+use crate::structure::NekoPoint;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use uuid::Uuid;
+
+/// Length of every [`NekoPointText::text_vector`](crate::structure::NekoPointText)
+pub const TEXT_VECTOR_DIM: usize = 768;
+/// Points listed per violation
+pub const MAX_EXAMPLES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    /// Text vector of another length than expected
+    WrongDimension,
+    /// Text vector with a NaN or infinite component
+    NonFinite,
+    /// Text vector of a blank text
+    EmptyText,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Violation::WrongDimension => "wrong text vector dimension",
+            Violation::NonFinite => "non-finite text vector",
+            Violation::EmptyText => "text vector without text",
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ViolationSummary {
+    pub count: usize,
+    /// The [`MAX_EXAMPLES`] smallest ids, so that reruns list the same ones
+    pub examples: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ValidationReport {
+    pub points: usize,
+    pub text_vectors: usize,
+    pub expected_dim: usize,
+    /// Length of the text vectors of a wrong dimension -> how many
+    pub wrong_dims: BTreeMap<usize, usize>,
+    pub violations: BTreeMap<Violation, ViolationSummary>,
+}
+
+impl ValidationReport {
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    fn record(&mut self, violation: Violation, id: Uuid) {
+        let summary = self.violations.entry(violation).or_default();
+        summary.count += 1;
+        if summary.examples.len() < MAX_EXAMPLES || summary.examples.last() > Some(&id) {
+            let at = summary.examples.partition_point(|e| *e < id);
+            summary.examples.insert(at, id);
+            summary.examples.truncate(MAX_EXAMPLES);
+        }
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} points, {} text vectors",
+            self.points, self.text_vectors
+        )?;
+        for (violation, summary) in &self.violations {
+            write!(f, ", {} {}", summary.count, violation)?;
+            if *violation == Violation::WrongDimension {
+                write!(
+                    f,
+                    " (expected {}, found {:?})",
+                    self.expected_dim, self.wrong_dims
+                )?;
+            }
+            let examples: Vec<String> = summary.examples.iter().map(Uuid::to_string).collect();
+            write!(f, " e.g. {}", examples.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// [`validate_neko_points_dim`] against [`TEXT_VECTOR_DIM`]
+pub fn validate_neko_points(points: &HashMap<Uuid, NekoPoint>) -> ValidationReport {
+    validate_neko_points_dim(points, TEXT_VECTOR_DIM)
+}
+
+/// Checks the text vector of every point is `expected_dim` long, finite and of a non-blank text
+pub fn validate_neko_points_dim(
+    points: &HashMap<Uuid, NekoPoint>,
+    expected_dim: usize,
+) -> ValidationReport {
+    let mut report = ValidationReport {
+        points: points.len(),
+        text_vectors: 0,
+        expected_dim,
+        wrong_dims: BTreeMap::new(),
+        violations: BTreeMap::new(),
+    };
+    for (id, point) in points {
+        let Some(text) = &point.text_info else {
+            continue;
+        };
+        report.text_vectors += 1;
+        if text.text_vector.len() != expected_dim {
+            *report.wrong_dims.entry(text.text_vector.len()).or_default() += 1;
+            report.record(Violation::WrongDimension, *id);
+        }
+        if !text.text_vector.iter().all(|x| x.is_finite()) {
+            report.record(Violation::NonFinite, *id);
+        }
+        if text.text.trim().is_empty() {
+            report.record(Violation::EmptyText, *id);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::NekoPointText;
+
+    fn point(id: u128, text: Option<(&str, Vec<f32>)>) -> (Uuid, NekoPoint) {
+        let id = Uuid::from_u128(id);
+        let point = NekoPoint {
+            id,
+            height: 1,
+            width: 1,
+            size: None,
+            categories: None,
+            text_info: text.map(|(text, text_vector)| NekoPointText {
+                text: text.to_owned(),
+                text_vector,
+            }),
+            qdrant_num_id: None,
+            extra: None,
+        };
+        (id, point)
+    }
+
+    #[test]
+    fn test_clean() {
+        let points = HashMap::from([
+            point(1, Some(("hello", vec![0.5; TEXT_VECTOR_DIM]))),
+            point(2, None),
+        ]);
+        let report = validate_neko_points(&points);
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!((report.points, report.text_vectors), (2, 1));
+        assert_eq!(report.to_string(), "2 points, 1 text vectors");
+    }
+
+    #[test]
+    fn test_wrong_dimension() {
+        let points: HashMap<Uuid, NekoPoint> = (1..=8)
+            .map(|id| {
+                point(
+                    id,
+                    Some(("text", vec![0.5; if id % 2 == 0 { 384 } else { 4 }])),
+                )
+            })
+            .chain([point(9, Some(("text", vec![0.5; 2])))])
+            .collect();
+        let report = validate_neko_points_dim(&points, 4);
+        assert_eq!(report.wrong_dims, BTreeMap::from([(2, 1), (384, 4)]));
+        let summary = &report.violations[&Violation::WrongDimension];
+        assert_eq!(summary.count, 5);
+        let expected: Vec<Uuid> = [2, 4, 6, 8, 9].into_iter().map(Uuid::from_u128).collect();
+        assert_eq!(summary.examples, expected);
+        assert_eq!(report.violations.len(), 1);
+        assert!(
+            report
+                .to_string()
+                .contains("expected 4, found {2: 1, 384: 4}")
+        );
+    }
+
+    #[test]
+    fn test_non_finite() {
+        let points = HashMap::from([
+            point(1, Some(("a", vec![f32::NAN, 0.0]))),
+            point(2, Some(("b", vec![0.0, f32::INFINITY]))),
+            point(3, Some(("c", vec![0.0, 1.0]))),
+        ]);
+        let report = validate_neko_points_dim(&points, 2);
+        let summary = &report.violations[&Violation::NonFinite];
+        assert_eq!(summary.count, 2);
+        assert_eq!(
+            summary.examples,
+            vec![Uuid::from_u128(1), Uuid::from_u128(2)]
+        );
+        assert_eq!(report.violations.len(), 1);
+    }
+
+    #[test]
+    fn test_empty_text() {
+        let points = HashMap::from([
+            point(1, Some(("", vec![0.0, 1.0]))),
+            point(2, Some((" \n", vec![0.0, 1.0]))),
+            // one point, every violation
+            point(3, Some(("", vec![f32::NAN]))),
+        ]);
+        let report = validate_neko_points_dim(&points, 2);
+        assert_eq!(report.violations[&Violation::EmptyText].count, 3);
+        assert_eq!(report.violations[&Violation::NonFinite].count, 1);
+        assert_eq!(report.violations[&Violation::WrongDimension].count, 1);
+    }
+
+    #[test]
+    fn test_examples_capped() {
+        let points: HashMap<Uuid, NekoPoint> = (0..100)
+            .map(|id| point(id, Some(("", vec![0.0; TEXT_VECTOR_DIM]))))
+            .collect();
+        let report = validate_neko_points(&points);
+        let summary = &report.violations[&Violation::EmptyText];
+        assert_eq!(summary.count, 100);
+        let smallest: Vec<Uuid> = (0..MAX_EXAMPLES as u128).map(Uuid::from_u128).collect();
+        assert_eq!(summary.examples, smallest);
+    }
+}
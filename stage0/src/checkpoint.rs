@@ -0,0 +1,160 @@
+use crate::snapshot_guard::CountDrift;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use shared::atomic_write::atomic_write;
+use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// How far an export got, written after the partial explorer it describes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportCheckpoint {
+    pub collection_name: String,
+    pub started_at: DateTime<Local>,
+    pub saved_at: DateTime<Local>,
+    pub initial_count: u64,
+    pub pages: usize,
+    pub scrolled_count: u64,
+    /// Last point scrolled, scroll offsets are inclusive so a resumed scroll starts on it again
+    pub last_id: Option<Uuid>,
+    pub drifts: Vec<CountDrift>,
+}
+
+/// Flushes the explorer being filled to `<output>.partial` every `every` pages, `0` never
+#[derive(Debug, Clone)]
+pub struct Checkpointer {
+    explorer_path: PathBuf,
+    meta_path: PathBuf,
+    every: usize,
+}
+
+impl Checkpointer {
+    pub fn new<P: AsRef<Path>>(output: P, every: usize) -> Self {
+        let output = output.as_ref().as_os_str();
+        let with_suffix = |suffix: &str| {
+            let mut path = output.to_owned();
+            path.push(suffix);
+            PathBuf::from(path)
+        };
+        Self {
+            explorer_path: with_suffix(".partial"),
+            meta_path: with_suffix(".partial.json"),
+            every,
+        }
+    }
+
+    #[inline]
+    pub fn should_flush(&self, page: usize) -> bool {
+        self.every != 0 && page != 0 && page.is_multiple_of(self.every)
+    }
+
+    /// The explorer first, the checkpoint only ever describes a complete one
+    pub fn flush<const D: usize>(
+        &self,
+        explorer: &PointExplorer<f32, D>,
+        checkpoint: &ExportCheckpoint,
+    ) -> anyhow::Result<()> {
+        explorer.save(&self.explorer_path.to_string_lossy())?;
+        atomic_write(&self.meta_path, serde_json::to_vec_pretty(checkpoint)?)?;
+        tracing::info!(
+            "Checkpointed {} points after {} pages to {}",
+            explorer.len(),
+            checkpoint.pages,
+            self.explorer_path.display()
+        );
+        Ok(())
+    }
+
+    /// The partial explorer of the last run on `collection_name`, `None` if there is none
+    pub fn load<const D: usize>(
+        &self,
+        collection_name: &str,
+    ) -> anyhow::Result<Option<(PointExplorer<f32, D>, ExportCheckpoint)>> {
+        if !self.meta_path.exists() {
+            return Ok(None);
+        }
+        let checkpoint: ExportCheckpoint = serde_json::from_slice(&fs::read(&self.meta_path)?)?;
+        anyhow::ensure!(
+            checkpoint.collection_name == collection_name,
+            "{} is a checkpoint of collection {}, not {}",
+            self.meta_path.display(),
+            checkpoint.collection_name,
+            collection_name
+        );
+        let explorer = PointExplorerBuilder::new()
+            .path(self.explorer_path.to_string_lossy())
+            .build()?;
+        Ok(Some((explorer, checkpoint)))
+    }
+
+    /// Drops the partial files once the full explorer is saved
+    pub fn clear(&self) -> std::io::Result<()> {
+        for path in [&self.meta_path, &self.explorer_path] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(collection_name: &str, last_id: Option<Uuid>) -> ExportCheckpoint {
+        let now = Local::now();
+        ExportCheckpoint {
+            collection_name: collection_name.to_string(),
+            started_at: now,
+            saved_at: now,
+            initial_count: 3,
+            pages: 2,
+            scrolled_count: 2,
+            last_id,
+            drifts: vec![CountDrift {
+                page: 1,
+                expected: 3,
+                observed: 4,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_should_flush() {
+        let checkpointer = Checkpointer::new("out.pkl", 4);
+        let flushed: Vec<usize> = (0..10).filter(|&p| checkpointer.should_flush(p)).collect();
+        assert_eq!(flushed, vec![4, 8]);
+        assert!(!Checkpointer::new("out.pkl", 0).should_flush(4));
+    }
+
+    #[test]
+    fn test_flush_load_clear() {
+        let dir = std::env::temp_dir().join(format!("stage0_checkpoint_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let checkpointer = Checkpointer::new(dir.join("out.pkl"), 1);
+        assert!(checkpointer.load::<2>("images").unwrap().is_none());
+
+        let mut explorer: PointExplorer<f32, 2> = PointExplorerBuilder::new().build().unwrap();
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        explorer.insert(a, [1.0, 0.0]);
+        explorer.insert(b, [0.0, 1.0]);
+        let saved = checkpoint("images", Some(b));
+        checkpointer.flush(&explorer, &saved).unwrap();
+        assert!(dir.join("out.pkl.partial").exists());
+
+        let (loaded, meta) = checkpointer.load::<2>("images").unwrap().unwrap();
+        assert_eq!(meta, saved);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get_vector(&b), Some(&[0.0, 1.0]));
+        assert!(checkpointer.load::<2>("videos").is_err());
+
+        checkpointer.clear().unwrap();
+        checkpointer.clear().unwrap();
+        assert!(checkpointer.load::<2>("images").unwrap().is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
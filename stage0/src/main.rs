@@ -1,17 +1,23 @@
+mod checkpoint;
+mod snapshot_guard;
+
+use crate::checkpoint::{Checkpointer, ExportCheckpoint};
+use crate::snapshot_guard::{DriftGuard, ScrollSnapshotMeta};
 use clap::Parser;
+use futures::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use mimalloc::MiMalloc;
+use qdrant_client::QdrantError;
 use qdrant_client::qdrant::vectors_output::VectorsOptions as VectorsOptionsOutput;
-use qdrant_client::qdrant::{PointId, ScrollPointsBuilder, point_id};
+use qdrant_client::qdrant::{PointId, RetrievedPoint, ScrollPointsBuilder, point_id};
+use shared::logging::Logging;
 use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
-use shared::qdrant::{GenShinQdrantClient, QdrantResult};
-use std::env;
+use shared::qdrant::{GenShinQdrantClient, QdrantResult, ScrollOptions};
 use std::ops::Deref;
+use std::path::PathBuf;
+use std::pin::pin;
 use std::sync::Arc;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
+use std::{env, fs};
 use uuid::Uuid;
 
 #[global_allocator]
@@ -46,59 +52,140 @@ impl Stage0GenshinQdrantClient {
         Ok(collection_info.result.unwrap().points_count.unwrap())
     }
 
-    pub async fn fetch_all_points(
+    /// Scrolls the `image_vector` of every point into `explorer` one page at a time, so only a
+    /// page is held besides the explorer, and flushes `checkpointer` along the way
+    ///
+    /// `resume` is the checkpoint `explorer` was loaded from, the scroll carries on after it.
+    pub async fn export_points<const D: usize>(
         self: Arc<Self>,
-        pre_num: usize,
-    ) -> QdrantResult<Vec<(Uuid, Vec<f32>)>> {
-        let pb = ProgressBar::new(pre_num as u64);
+        explorer: &mut PointExplorer<f32, D>,
+        pre_num: u64,
+        check_every: usize,
+        strict: bool,
+        checkpointer: &Checkpointer,
+        resume: Option<ExportCheckpoint>,
+    ) -> anyhow::Result<ScrollSnapshotMeta> {
+        let (started_at, initial_count, mut pages, mut scrolled, mut last_id, mut guard) =
+            match resume {
+                Some(c) => (
+                    c.started_at,
+                    c.initial_count,
+                    c.pages,
+                    c.scrolled_count,
+                    c.last_id,
+                    DriftGuard::resume(c.initial_count, check_every, strict, c.drifts),
+                ),
+                None => (
+                    chrono::Local::now(),
+                    pre_num,
+                    0,
+                    0,
+                    None,
+                    DriftGuard::new(pre_num, check_every, strict),
+                ),
+            };
+        let pb = ProgressBar::new(pre_num);
         let style = ProgressStyle::default_bar()
             .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
             .unwrap();
         pb.set_style(style);
-        pb.set_message("Overwriting Qdrant payload...");
-        let mut offset: Option<PointId> = None;
-        let mut out: Vec<(Uuid, Vec<f32>)> = Vec::with_capacity(pre_num);
-        loop {
-            let mut sc = ScrollPointsBuilder::new(&self.collection_name)
-                .limit(1000)
-                .with_payload(false)
-                .with_vectors(true);
-            if let Some(ov) = offset {
-                sc = sc.offset(ov);
+        pb.set_position(scrolled);
+        let mut template = ScrollPointsBuilder::new(&self.collection_name)
+            .limit(1000)
+            .with_payload(false)
+            .with_vectors(true);
+        if let Some(id) = last_id {
+            template = template.offset(PointId::from(id.to_string()));
+        }
+        // the offset is inclusive, the first page starts on the last point of the checkpoint
+        let mut resumed_from = last_id;
+        let scroll = self.client.scroll_pages(template, ScrollOptions::default());
+        let mut scroll = pin!(scroll);
+        // the scroll ends on the error it could not get past, transient ones are retried
+        let mut failed: Option<QdrantError> = None;
+        while let Some(page) = scroll.next().await {
+            let mut points = match page {
+                Ok(points) => points,
+                Err(e) => {
+                    tracing::warn!("Scroll page {} failed: {}", pages + 1, e);
+                    failed = Some(e);
+                    continue;
+                }
+            };
+            failed = None;
+            let resumed = resumed_from.take();
+            if resumed.is_some() && points.first().and_then(point_uuid) == resumed {
+                points.remove(0);
             }
-            let resp = self.client.scroll(sc).await?;
-            let size = resp.result.len();
-            offset = resp.next_page_offset.to_owned();
-            out.extend(resp.result.into_iter().filter_map(|mut p| {
-                let uuid =
-                    p.id.as_ref()
-                        .and_then(|pid| pid.point_id_options.as_ref())
-                        .and_then(|opt| match opt {
-                            point_id::PointIdOptions::Uuid(s) => Some(Uuid::parse_str(s).ok()?),
-                            _ => None,
-                        })?;
-                let vectors = p.vectors.take()?;
-                let named = match vectors.vectors_options? {
-                    VectorsOptionsOutput::Vectors(named) => named,
-                    _ => return None,
+            let size = points.len();
+            pages += 1;
+            scrolled += size as u64;
+            for point in points {
+                let Some(id) = point_uuid(&point) else {
+                    continue;
                 };
-                let vec = named
-                    .vectors
-                    .into_iter()
-                    .find(|(k, _)| k == "image_vector")?
-                    .1
-                    .data;
-                Some((uuid, vec))
-            }));
+                last_id = Some(id);
+                if let Some(vector) = image_vector(point) {
+                    explorer.insert(id, vector);
+                }
+            }
             pb.inc(size as u64);
-            if offset.is_none() {
-                break;
+            if guard.should_check(pages) {
+                let count = self.clone().fetch_point_num().await?;
+                guard.observe(pages, count)?;
+            }
+            if checkpointer.should_flush(pages) {
+                let checkpoint = ExportCheckpoint {
+                    collection_name: self.collection_name.clone(),
+                    started_at,
+                    saved_at: chrono::Local::now(),
+                    initial_count,
+                    pages,
+                    scrolled_count: scrolled,
+                    last_id,
+                    drifts: guard.drifts().to_vec(),
+                };
+                checkpointer.flush(explorer, &checkpoint)?;
             }
         }
-        Ok(out)
+        if let Some(e) = failed {
+            return Err(e.into());
+        }
+        pb.finish();
+        let final_count = self.clone().fetch_point_num().await?;
+        let drifts = guard.finish(pages, final_count, scrolled)?;
+        Ok(ScrollSnapshotMeta {
+            collection_name: self.collection_name.clone(),
+            started_at,
+            finished_at: chrono::Local::now(),
+            initial_count,
+            final_count,
+            scrolled_count: scrolled,
+            pages,
+            drifts,
+        })
+    }
+}
+
+fn point_uuid(point: &RetrievedPoint) -> Option<Uuid> {
+    match point.id.as_ref()?.point_id_options.as_ref()? {
+        point_id::PointIdOptions::Uuid(s) => Uuid::parse_str(s).ok(),
+        _ => None,
     }
 }
 
+fn image_vector(point: RetrievedPoint) -> Option<Vec<f32>> {
+    let named = match point.vectors?.vectors_options? {
+        VectorsOptionsOutput::Vectors(named) => named,
+        _ => return None,
+    };
+    named
+        .vectors
+        .into_iter()
+        .find(|(k, _)| k == "image_vector")
+        .map(|(_, v)| v.data)
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "Stage0", version)]
 struct Cli {
@@ -106,32 +193,83 @@ struct Cli {
     worker_num: usize,
     #[arg(long, default_value = "qdrant_point_reset_errors")]
     save_result_prefix: String,
+    /// Re-check the collection point count every N scroll pages (0 = only at the end)
+    #[arg(long, default_value = "50")]
+    drift_check_every: usize,
+    /// Abort instead of warning when the collection changes during the scroll
+    #[arg(long, default_value = "false")]
+    strict_snapshot: bool,
+    /// PointExplorer written at the end, the snapshot sidecar goes next to it as `.meta.json`
+    #[arg(long, default_value = "qdrant_point_explorer_250611.pkl")]
+    output: PathBuf,
+    /// Save the points scrolled so far to `<output>.partial` every N scroll pages (0 = never)
+    #[arg(long, default_value = "200")]
+    checkpoint_every: usize,
+    /// Carry on from the `<output>.partial` checkpoint of an interrupted run
+    #[arg(long)]
+    resume: bool,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new("info"));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage0.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new("info"));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let _logging = Logging::new("stage0").init()?;
     let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
     let client = Arc::new(Stage0GenshinQdrantClient::new(
         &collection_name,
         cli.worker_num,
     )?);
     let point_num = client.clone().fetch_point_num().await?;
-    let points = client.clone().fetch_all_points(point_num as usize).await?;
-    tracing::info!("Found {} points", points.len());
-    let mut point_explorer: PointExplorer<f32, 768> =
-        PointExplorerBuilder::new().capacity(points.len()).build()?;
-    point_explorer.extend(points);
+    let checkpointer = Checkpointer::new(&cli.output, cli.checkpoint_every);
+    let checkpoint = match cli.resume {
+        true => checkpointer.load(&collection_name)?,
+        false => None,
+    };
+    let (mut point_explorer, resume) = match checkpoint {
+        Some((explorer, checkpoint)) => {
+            tracing::info!(
+                "Resuming from {} points scrolled over {} pages, saved at {}",
+                checkpoint.scrolled_count,
+                checkpoint.pages,
+                checkpoint.saved_at
+            );
+            (explorer, Some(checkpoint))
+        }
+        None => {
+            if cli.resume {
+                tracing::warn!("No checkpoint to resume from, starting over");
+            }
+            let explorer: PointExplorer<f32, 768> = PointExplorerBuilder::new()
+                .capacity(point_num as usize)
+                .build()?;
+            (explorer, None)
+        }
+    };
+    let snapshot_meta = client
+        .clone()
+        .export_points(
+            &mut point_explorer,
+            point_num,
+            cli.drift_check_every,
+            cli.strict_snapshot,
+            &checkpointer,
+            resume,
+        )
+        .await?;
+    tracing::info!("Found {} points", point_explorer.len());
+    if !snapshot_meta.is_consistent() {
+        tracing::warn!(
+            "Snapshot may be torn, {} drift(s) detected: {:?}",
+            snapshot_meta.drifts.len(),
+            snapshot_meta.drifts
+        );
+    }
     tracing::info!("Saving {} points into PointExplorer", point_explorer.len());
-    point_explorer.save("qdrant_point_explorer_250611.pkl")?; // TODO: with metadata?
+    point_explorer.save(&cli.output.to_string_lossy())?; // TODO: with metadata?
+    fs::write(
+        cli.output.with_extension("meta.json"),
+        serde_json::to_string_pretty(&snapshot_meta)?,
+    )?;
+    checkpointer.clear()?;
     Ok(())
 }
@@ -0,0 +1,235 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// A point count that differs from the last one seen during the scroll
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CountDrift {
+    pub page: usize,
+    pub expected: u64,
+    pub observed: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error(
+        "Collection mutated during scroll at page {}: expected {} points, observed {}",
+        .0.page, .0.expected, .0.observed
+    )]
+    Drift(CountDrift),
+}
+
+/// Tracks `points_count` across a long scroll, without touching the network
+#[derive(Debug)]
+pub struct DriftGuard {
+    last_count: u64,
+    check_every: usize,
+    strict: bool,
+    drifts: Vec<CountDrift>,
+}
+
+impl DriftGuard {
+    pub fn new(initial_count: u64, check_every: usize, strict: bool) -> Self {
+        Self {
+            last_count: initial_count,
+            check_every,
+            strict,
+            drifts: Vec::new(),
+        }
+    }
+
+    /// Carries on the guard of an interrupted scroll from the drifts it had recorded
+    pub fn resume(
+        initial_count: u64,
+        check_every: usize,
+        strict: bool,
+        drifts: Vec<CountDrift>,
+    ) -> Self {
+        Self {
+            last_count: drifts.last().map_or(initial_count, |d| d.observed),
+            check_every,
+            strict,
+            drifts,
+        }
+    }
+
+    #[inline]
+    pub fn drifts(&self) -> &[CountDrift] {
+        &self.drifts
+    }
+
+    /// `page` is 1-based, `0` disables periodic checks
+    #[inline]
+    pub fn should_check(&self, page: usize) -> bool {
+        self.check_every != 0 && page != 0 && page.is_multiple_of(self.check_every)
+    }
+
+    pub fn observe(&mut self, page: usize, observed: u64) -> Result<(), SnapshotError> {
+        if observed == self.last_count {
+            return Ok(());
+        }
+        let drift = CountDrift {
+            page,
+            expected: self.last_count,
+            observed,
+        };
+        self.last_count = observed;
+        tracing::warn!(
+            "Collection point count drifted at page {}: {} -> {}",
+            page,
+            drift.expected,
+            drift.observed
+        );
+        self.drifts.push(drift);
+        match self.strict {
+            true => Err(SnapshotError::Drift(drift)),
+            false => Ok(()),
+        }
+    }
+
+    /// Checks the final count and the number of points actually scrolled
+    pub fn finish(
+        mut self,
+        pages: usize,
+        final_count: u64,
+        scrolled: u64,
+    ) -> Result<Vec<CountDrift>, SnapshotError> {
+        self.observe(pages, final_count)?;
+        if scrolled != final_count {
+            let drift = CountDrift {
+                page: pages,
+                expected: final_count,
+                observed: scrolled,
+            };
+            tracing::warn!(
+                "Scrolled {} points but collection reports {}",
+                scrolled,
+                final_count
+            );
+            self.drifts.push(drift);
+            if self.strict {
+                return Err(SnapshotError::Drift(drift));
+            }
+        }
+        Ok(self.drifts)
+    }
+}
+
+/// Sidecar written next to the exported PointExplorer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrollSnapshotMeta {
+    pub collection_name: String,
+    pub started_at: DateTime<Local>,
+    pub finished_at: DateTime<Local>,
+    pub initial_count: u64,
+    pub final_count: u64,
+    pub scrolled_count: u64,
+    pub pages: usize,
+    pub drifts: Vec<CountDrift>,
+}
+
+impl ScrollSnapshotMeta {
+    #[inline]
+    pub fn is_consistent(&self) -> bool {
+        self.drifts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(
+        initial: u64,
+        check_every: usize,
+        strict: bool,
+        counts: &[u64],
+        scrolled: u64,
+    ) -> Result<Vec<CountDrift>, SnapshotError> {
+        let mut guard = DriftGuard::new(initial, check_every, strict);
+        let mut pages = 0;
+        let mut counts = counts.iter();
+        for page in 1..=10 {
+            pages = page;
+            if guard.should_check(page) {
+                guard.observe(page, *counts.next().unwrap())?;
+            }
+        }
+        guard.finish(pages, *counts.next().unwrap(), scrolled)
+    }
+
+    #[test]
+    fn test_should_check() {
+        let guard = DriftGuard::new(0, 3, false);
+        let checked: Vec<usize> = (0..10).filter(|&p| guard.should_check(p)).collect();
+        assert_eq!(checked, vec![3, 6, 9]);
+        assert!(!DriftGuard::new(0, 0, false).should_check(5));
+    }
+
+    #[test]
+    fn test_stable_collection() {
+        let drifts = run(100, 5, true, &[100, 100, 100], 100).unwrap();
+        assert!(drifts.is_empty());
+    }
+
+    #[test]
+    fn test_drift_recorded_once_per_change() {
+        let drifts = run(100, 5, false, &[101, 101, 99], 99).unwrap();
+        assert_eq!(
+            drifts,
+            vec![
+                CountDrift {
+                    page: 5,
+                    expected: 100,
+                    observed: 101
+                },
+                CountDrift {
+                    page: 10,
+                    expected: 101,
+                    observed: 99
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scrolled_mismatch() {
+        let drifts = run(100, 0, false, &[100], 98).unwrap();
+        assert_eq!(
+            drifts,
+            vec![CountDrift {
+                page: 10,
+                expected: 100,
+                observed: 98
+            }]
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let drift = CountDrift {
+            page: 5,
+            expected: 100,
+            observed: 101,
+        };
+        let mut guard = DriftGuard::resume(100, 5, false, vec![drift]);
+        // the count the interrupted run last saw is no drift
+        guard.observe(10, 101).unwrap();
+        assert_eq!(guard.drifts(), &[drift]);
+        let drifts = guard.finish(12, 101, 101).unwrap();
+        assert_eq!(drifts, vec![drift]);
+        assert!(
+            DriftGuard::resume(100, 5, true, vec![])
+                .observe(5, 100)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_strict_aborts_on_first_drift() {
+        let err = run(100, 2, true, &[100, 100, 103, 103, 103, 103], 103).unwrap_err();
+        let SnapshotError::Drift(drift) = err;
+        assert_eq!(drift.page, 6);
+        assert_eq!(drift.observed, 103);
+    }
+}
@@ -1,56 +1,30 @@
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use shared::atomic_write::atomic_write_with;
+use shared::cluster::{
+    bucketed_merge, centroid_signature, greedy_cluster, sampled_linkage_violations,
+};
+use shared::cluster_file::{LegacyFormat, read_clusters, write_clusters};
+use shared::config::Thresholds;
+use shared::cosine_sim::simd_capabilities;
+use shared::exact_dup::ExactDupReduction;
 use shared::point_explorer::PointExplorer;
 use std::collections::HashSet;
 use uuid::Uuid;
 
-const THRESHOLD: f32 = 0.985;
+/// 4096 merge buckets
+const MERGE_BITS: u32 = 12;
+const VERIFY_SAMPLES: usize = 2000;
 
-fn cluster_chunk(ids: &[Uuid], sim_map: &PointExplorer<f32, 768>) -> Vec<HashSet<Uuid>> {
-    let mut clusters: Vec<HashSet<Uuid>> = Vec::new(); // a b c d e
-    for &id in ids {
-        let mut placed = false;
-        for cl in clusters.iter_mut() {
-            let ok = cl.iter().all(|&other| {
-                let sim = sim_map.get_cosine_sim((&id, &other)).unwrap();
-                sim > THRESHOLD
-            });
-            if ok {
-                cl.insert(id);
-                placed = true;
-                break;
-            }
-        }
-        if !placed {
-            let mut newc = HashSet::new();
-            newc.insert(id);
-            clusters.push(newc);
-        }
-    }
-    clusters
-}
-
-fn merge_cluster(
-    local: HashSet<Uuid>,
-    global: &mut Vec<HashSet<Uuid>>,
+fn similar(
     sim_map: &PointExplorer<f32, 768>,
-) {
-    for g in global.iter_mut() {
-        let ok = local.iter().all(|&i| {
-            g.iter().all(|&j| {
-                let sim = sim_map.get_cosine_sim((&i, &j)).unwrap();
-                sim > THRESHOLD
-            })
-        });
-        if ok {
-            g.extend(local.into_iter());
-            return;
-        }
-    }
-    global.push(local);
+    threshold: f32,
+) -> impl Fn(&Uuid, &Uuid) -> bool + '_ {
+    move |a, b| sim_map.get_cosine_sim((a, b)).unwrap() > threshold
 }
 
 pub fn main() {
+    let threshold = Thresholds::init().expect("similarity thresholds").image_sim;
     let data = std::fs::read(r"img_sim_clean_new.bin").unwrap();
     // FIXME: it won't work
     let sim_explorer: PointExplorer<f32, 768> =
@@ -59,9 +33,19 @@ pub fn main() {
             .0;
 
     let all_ids: Vec<Uuid> = sim_explorer.iter().map(|(id, p)| *id).collect();
+    // stage23 groups, each exact duplicate group is clustered through one representative
+    let (all_ids, exact_dups) = match std::env::var("STAGE1_EXACT_CLUSTERS") {
+        Ok(path) => {
+            let groups = read_clusters(path, LegacyFormat::Pickle).unwrap();
+            ExactDupReduction::reduce(&all_ids, &groups)
+        }
+        Err(_) => (all_ids, ExactDupReduction::default()),
+    };
+    println!("{} exact duplicates left out", exact_dups.dropped());
     let chunk_size = 20000;
     let chunks: Vec<&[Uuid]> = all_ids.chunks(chunk_size).collect();
     println!("Total {} ids, {} chunks", all_ids.len(), chunks.len());
+    println!("Cosine kernels: {}", simd_capabilities());
 
     let m = MultiProgress::new();
     let pb_local = m.add(ProgressBar::new(chunks.len() as u64));
@@ -75,27 +59,47 @@ pub fn main() {
     let local_vec: Vec<Vec<HashSet<Uuid>>> = chunks
         .par_iter()
         .map(|&chunk| {
-            let res = cluster_chunk(chunk, &sim_explorer);
+            let res = greedy_cluster(chunk, similar(&sim_explorer, threshold));
             pb_local.inc(1);
             res
         })
         .collect();
     pb_local.finish_with_message("Local clustering done");
 
-    let all_local_clusters: Vec<HashSet<Uuid>> = local_vec.into_iter().flatten().collect();
-    let mut global_clusters = Vec::new();
-    let pb_merge = m.add(ProgressBar::new(0));
-    pb_merge.set_length(all_local_clusters.len() as u64);
+    let local_count = local_vec.iter().map(Vec::len).sum::<usize>();
+    let pb_merge = m.add(ProgressBar::new(local_count as u64));
     pb_merge.set_style(style);
     pb_merge.set_message("Global merging");
-    for lc in all_local_clusters {
-        merge_cluster(lc, &mut global_clusters, &sim_explorer);
-        pb_merge.inc(1);
-    }
+    let signature = |cluster: &HashSet<Uuid>| {
+        let vectors = cluster
+            .iter()
+            .map(|id| &sim_explorer.get_vector(id).expect("clustered id")[..]);
+        centroid_signature(vectors, MERGE_BITS)
+    };
+    let global_clusters = bucketed_merge(
+        local_vec,
+        MERGE_BITS,
+        signature,
+        similar(&sim_explorer, threshold),
+        |n| pb_merge.inc(n as u64),
+    );
     pb_merge.finish_with_message("Global merging done");
+    let violations = sampled_linkage_violations(
+        &global_clusters,
+        VERIFY_SAMPLES,
+        similar(&sim_explorer, threshold),
+    );
+    assert!(
+        violations.is_empty(),
+        "merged clusters hold dissimilar pairs: {:?}",
+        violations
+    );
+    let global_clusters = exact_dups.expand(global_clusters);
 
-    let mut saved_file = std::fs::File::create(r"global_clusters_new_0607.pkl").unwrap();
-    serde_pickle::to_writer(&mut saved_file, &global_clusters, Default::default()).unwrap();
+    atomic_write_with(r"global_clusters_new_0607.pkl", |w| {
+        write_clusters(w, &global_clusters)
+    })
+    .unwrap();
 
     println!("最终得到 {} 个簇", global_clusters.len());
 }
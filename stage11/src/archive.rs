@@ -0,0 +1,246 @@
+use crate::task::{FailedReSetPointTask, FailureReason, ReSetPointTask};
+use shared::opendal::GenShinOperator;
+use shared::qdrant::PointStore;
+use shared::stall::{StallConfig, StallError, for_each_watched};
+use tracing::Instrument;
+use uuid::Uuid;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveSummary {
+    pub points: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct ArchiveOutcome<'a> {
+    /// Tasks whose discards are all archived, or that have none
+    pub ready: Vec<ReSetPointTask<'a>>,
+    pub failed: Vec<FailedReSetPointTask<'a>>,
+    pub summary: ArchiveSummary,
+    pub stalled: Option<StallError>,
+}
+
+/// Copies of the points stage11 deletes, one `<prefix>/<uuid>.json` object per point
+///
+/// Every object is a [`PointRecord`](shared::qdrant::PointRecord), the line format `restore-points` reads. Its vectors are
+/// empty unless archived with vectors.
+pub struct PayloadArchive {
+    op: GenShinOperator,
+    prefix: String,
+    with_vectors: bool,
+}
+
+impl PayloadArchive {
+    pub fn new(op: GenShinOperator, prefix: &str, with_vectors: bool) -> Self {
+        Self {
+            op,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            with_vectors,
+        }
+    }
+
+    pub fn key(&self, id: &Uuid) -> String {
+        format!("{}/{}.json", self.prefix, id)
+    }
+
+    /// Uploads every point of `ids` still in `store`, ids it does not know are left out
+    pub async fn archive<S: PointStore>(
+        &self,
+        store: &S,
+        ids: &[Uuid],
+    ) -> anyhow::Result<ArchiveSummary> {
+        let records = match self.with_vectors {
+            true => store.get(ids).await?,
+            false => store.get_payloads(ids).await?,
+        };
+        if records.len() < ids.len() {
+            tracing::warn!(
+                "{} of {} points to archive are not in the collection",
+                ids.len() - records.len(),
+                ids.len()
+            );
+        }
+        let mut summary = ArchiveSummary::default();
+        for mut record in records {
+            if !self.with_vectors {
+                record.vectors.clear();
+            }
+            let data = serde_json::to_vec(&record)?;
+            let len = data.len() as u64;
+            self.op.write(&self.key(&record.id), data).await?;
+            summary.points += 1;
+            summary.bytes += len;
+        }
+        Ok(summary)
+    }
+
+    /// Archives the discards of every task, `concurrency` tasks at a time
+    ///
+    /// A task only comes back ready once all of its discards are uploaded, so writing the ready
+    /// tasks never deletes a point without its copy. The others fail with
+    /// [`FailureReason::ArchiveFailed`], including those left unstarted by a stall abort.
+    pub async fn archive_tasks<'a, S: PointStore>(
+        &self,
+        store: &S,
+        tasks: &[ReSetPointTask<'a>],
+        concurrency: usize,
+        stall: &StallConfig,
+    ) -> ArchiveOutcome<'a> {
+        let mut results: Vec<Option<anyhow::Result<ArchiveSummary>>> =
+            tasks.iter().map(|_| None).collect();
+        let jobs = tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, task)| !task.discard_point_list.is_empty())
+            .map(|(idx, task)| {
+                let ids: Vec<Uuid> = task.discard_point_list.iter().map(|id| **id).collect();
+                let id = format!("archive of task #{}", idx);
+                // the task's kept point stands for the cluster
+                let span = tracing::info_span!(
+                    "stage11.archive",
+                    task = idx,
+                    points = ids.len(),
+                    uuid = tracing::field::Empty
+                );
+                if let Some(keep) = task.keep_point_list.first() {
+                    span.record("uuid", tracing::field::display(keep));
+                }
+                let archive = async move { self.archive(store, &ids).await }.instrument(span);
+                (id, async move { (idx, archive.await) })
+            });
+        let watched = for_each_watched(jobs, concurrency.max(1), stall, |(idx, res)| {
+            results[idx] = Some(res)
+        })
+        .await;
+        let mut outcome = ArchiveOutcome {
+            ready: Vec::new(),
+            failed: Vec::new(),
+            summary: ArchiveSummary::default(),
+            stalled: watched.err(),
+        };
+        for (task, result) in tasks.iter().zip(results) {
+            let error = match result {
+                None if task.discard_point_list.is_empty() => {
+                    outcome.ready.push(task.clone());
+                    continue;
+                }
+                Some(Ok(summary)) => {
+                    outcome.summary.points += summary.points;
+                    outcome.summary.bytes += summary.bytes;
+                    outcome.ready.push(task.clone());
+                    continue;
+                }
+                Some(Err(e)) => e.to_string(),
+                None => "not attempted, run aborted".to_owned(),
+            };
+            tracing::error!("Failed to archive the discards of a task: {}", error);
+            outcome.failed.push(FailedReSetPointTask {
+                task: task.clone(),
+                reason: FailureReason::ArchiveFailed,
+                error,
+            });
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::write_ops;
+    use opendal::Operator;
+    use opendal::services::Memory;
+    use serde_json::json;
+    use shared::qdrant::{PointRecord, QdrantWriteScheduler, WriteSchedulerConfig};
+    use shared::test_util::MemoryPointStore;
+    use std::collections::HashMap;
+
+    /// Points with a description and an image vector
+    fn memory_store(ids: &[Uuid]) -> MemoryPointStore {
+        MemoryPointStore::new(ids.iter().map(|&id| {
+            let payload = json!({ "description": format!("curated {}", id) });
+            PointRecord {
+                id,
+                vectors: HashMap::from([("image_vector".to_owned(), vec![0.5; 4])]),
+                payload: payload.as_object().unwrap().clone(),
+            }
+        }))
+    }
+
+    fn task<'a>(keep: &'a [Uuid], discard: &'a [Uuid]) -> ReSetPointTask<'a> {
+        ReSetPointTask {
+            entry: 0,
+            keep_point_list: keep.iter().collect(),
+            discard_point_list: discard.iter().collect(),
+            transfer_tag_list: keep.iter().map(|_| vec!["a"]).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_then_delete() {
+        let ids: Vec<Uuid> = (1..=6).map(Uuid::from_u128).collect();
+        let op = Operator::new(Memory::default()).unwrap().finish();
+        let mut store = memory_store(&ids);
+        store.unreadable.insert(ids[4]);
+        let archive = PayloadArchive::new(GenShinOperator::from(op.clone()), "archive/", false);
+        let tasks = vec![
+            task(&ids[0..1], &ids[1..3]),
+            // the read of 5 fails, neither 4 nor 5 may be deleted
+            task(&[], &ids[3..5]),
+            task(&ids[5..6], &[]),
+        ];
+        let outcome = archive
+            .archive_tasks(&store, &tasks, 2, &StallConfig::default())
+            .await;
+        assert!(outcome.stalled.is_none());
+        assert_eq!(outcome.ready.len(), 2);
+        assert_eq!(outcome.failed.len(), 1);
+        assert_eq!(outcome.failed[0].reason, FailureReason::ArchiveFailed);
+        assert_eq!(outcome.failed[0].error, "read timed out");
+        assert_eq!(
+            outcome.failed[0].task.discard_point_list,
+            vec![&ids[3], &ids[4]]
+        );
+
+        let mut bytes = 0;
+        for id in &ids[1..3] {
+            let data = op.read(&archive.key(id)).await.unwrap().to_vec();
+            bytes += data.len() as u64;
+            let record: PointRecord = serde_json::from_slice(&data).unwrap();
+            assert_eq!(record.id, *id);
+            assert_eq!(record.payload["description"], format!("curated {}", id));
+            assert!(record.vectors.is_empty());
+        }
+        assert!(!op.exists(&archive.key(&ids[3])).await.unwrap());
+        assert_eq!(outcome.summary, ArchiveSummary { points: 2, bytes });
+
+        let scheduler = QdrantWriteScheduler::new(store, WriteSchedulerConfig::default());
+        let (ops, _) = write_ops(&outcome.ready);
+        scheduler.run(&ops, |_, _| {}).await;
+        let store = scheduler.store();
+        let deleted = store.deleted.lock().unwrap().clone();
+        assert_eq!(deleted, ids[1..3].to_vec());
+        // archived before the writes ran, and archives are never deleted
+        for id in &deleted {
+            assert!(op.exists(&archive.key(id)).await.unwrap());
+        }
+        assert!(store.points.lock().unwrap().contains_key(&ids[3]));
+    }
+
+    #[tokio::test]
+    async fn test_archive_vectors() {
+        let ids = [Uuid::from_u128(1), Uuid::from_u128(2)];
+        let op = Operator::new(Memory::default()).unwrap().finish();
+        let store = memory_store(&ids[..1]);
+        let archive = PayloadArchive::new(GenShinOperator::from(op.clone()), "archive", true);
+        // 2 is already gone and has nothing to archive
+        let summary = archive.archive(&store, &ids).await.unwrap();
+        assert_eq!(summary.points, 1);
+        let data = op
+            .read("archive/00000000-0000-0000-0000-000000000001.json")
+            .await;
+        let record: PointRecord = serde_json::from_slice(&data.unwrap().to_vec()).unwrap();
+        assert_eq!(record.vectors["image_vector"], vec![0.5; 4]);
+        assert!(!op.exists(&archive.key(&ids[1])).await.unwrap());
+    }
+}
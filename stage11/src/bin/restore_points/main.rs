@@ -0,0 +1,113 @@
+mod record;
+mod restore;
+
+use crate::record::read_export;
+use crate::restore::{FailedRestoreTask, restore_points};
+use clap::Parser;
+use shared::atomic_write::atomic_write_with;
+use shared::logging::Logging;
+use shared::qdrant::{GenShinQdrantClient, PointRef, QdrantPointStore};
+use shared::stall::StallConfig;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::{env, fs};
+use uuid::Uuid;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "restore-points",
+    version,
+    about = "Re-insert deleted points from an export taken before deletion"
+)]
+struct Cli {
+    /// JSON lines export, one `{id, vectors, payload}` record per point
+    #[arg(long)]
+    export_file: PathBuf,
+    /// JSON array of the point ids to restore
+    #[arg(long)]
+    uuid_list: PathBuf,
+    #[arg(long, default_value = "64")]
+    batch_size: usize,
+    #[arg(long, default_value = "4")]
+    worker_num: usize,
+    #[arg(long, default_value = "qdrant_point_restore_errors")]
+    save_result_prefix: String,
+    /// Prometheus text dump of this run's metrics
+    #[arg(long, default_value = "restore_points_metrics.prom")]
+    metrics_file: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let _logging = Logging::new("restore_points").init()?;
+    let wanted: Vec<Uuid> = serde_json::from_slice(&fs::read(&cli.uuid_list)?)?;
+    let (points, missing) = read_export(&cli.export_file, &wanted)?;
+    tracing::info!(
+        "Restoring {} of {} requested points, {} not in the export",
+        points.len(),
+        wanted.len(),
+        missing.len()
+    );
+    // records carry no NekoPoint, numeric points are told apart by their key
+    let store = QdrantPointStore::new(
+        GenShinQdrantClient::new()?,
+        &env::var("QDRANT_COLLECTION_NAME")?,
+    )
+    .with_num_ids(points.iter().map(|p| PointRef::from_key(p.id)));
+    let (mut report, stalled) = restore_points(
+        &store,
+        &points,
+        cli.batch_size,
+        cli.worker_num,
+        &StallConfig::default(),
+    )
+    .await;
+    report
+        .failed
+        .extend(missing.into_iter().map(|point_id| FailedRestoreTask {
+            point_id,
+            error: "not found in export".to_owned(),
+        }));
+    if stalled.is_some() {
+        let seen: HashSet<Uuid> = report
+            .restored
+            .iter()
+            .chain(report.failed.iter().map(|f| &f.point_id))
+            .copied()
+            .collect();
+        let unattempted: Vec<FailedRestoreTask> = points
+            .iter()
+            .filter(|p| !seen.contains(&p.id))
+            .map(|p| FailedRestoreTask {
+                point_id: p.id,
+                error: "not attempted, run aborted".to_owned(),
+            })
+            .collect();
+        report.failed.extend(unattempted);
+    }
+    tracing::info!("Restored and verified {} points", report.restored.len());
+    if !report.failed.is_empty() {
+        let filename = format!(
+            "{}_{}.json",
+            cli.save_result_prefix,
+            chrono::Local::now().format("%Y%m%d_%H%M%S")
+        );
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report.failed)?)
+        })?;
+        tracing::error!(
+            "Some points failed, details saved to {}. Total failed points: {}",
+            &filename,
+            report.failed.len()
+        );
+    } else {
+        tracing::info!("All points restored successfully.");
+    }
+    shared::metrics::dump_prometheus_to(&cli.metrics_file)?;
+    tracing::info!("Metrics saved to {}", cli.metrics_file.display());
+    if let Some(e) = stalled {
+        return Err(e.into());
+    }
+    Ok(())
+}
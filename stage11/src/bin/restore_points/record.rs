@@ -0,0 +1,34 @@
+use shared::qdrant::PointRecord;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use uuid::Uuid;
+
+/// Reads the export, keeping only the points listed in `wanted`
+///
+/// Returns the matching records in file order and the wanted ids absent from the export.
+pub fn read_export(
+    path: impl AsRef<Path>,
+    wanted: &[Uuid],
+) -> anyhow::Result<(Vec<PointRecord>, Vec<Uuid>)> {
+    let mut pending: HashSet<Uuid> = wanted.iter().copied().collect();
+    let mut records = Vec::with_capacity(wanted.len());
+    for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: PointRecord = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("Invalid export record at line {}: {}", idx + 1, e))?;
+        if pending.remove(&record.id) {
+            records.push(record);
+        }
+    }
+    let missing = wanted
+        .iter()
+        .filter(|id| pending.contains(id))
+        .copied()
+        .collect();
+    Ok((records, missing))
+}
@@ -0,0 +1,175 @@
+use serde::Serialize;
+use shared::qdrant::{PointRecord, PointStore};
+use shared::stall::{StallConfig, StallError, for_each_watched};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedRestoreTask {
+    pub point_id: Uuid,
+    pub error: String,
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub restored: Vec<Uuid>,
+    pub failed: Vec<FailedRestoreTask>,
+}
+
+/// Checks a point read back against its export record
+fn verify(expected: &PointRecord, actual: Option<&PointRecord>) -> Result<(), String> {
+    let actual = actual.ok_or("point missing after upsert")?;
+    for (name, vector) in expected.vectors.iter() {
+        match actual.vectors.get(name) {
+            None => return Err(format!("vector {} missing after upsert", name)),
+            Some(v) if v.len() != vector.len() => {
+                return Err(format!(
+                    "vector {} has dimension {}, expected {}",
+                    name,
+                    v.len(),
+                    vector.len()
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+    if actual.payload != expected.payload {
+        return Err("payload mismatch after upsert".to_owned());
+    }
+    Ok(())
+}
+
+async fn restore_batch<S: PointStore>(store: &S, batch: &[PointRecord]) -> RestoreReport {
+    let fail_all = |error: String| RestoreReport {
+        restored: Vec::new(),
+        failed: batch
+            .iter()
+            .map(|p| FailedRestoreTask {
+                point_id: p.id,
+                error: error.clone(),
+            })
+            .collect(),
+    };
+    if let Err(e) = store.upsert(batch).await {
+        return fail_all(format!("upsert failed: {}", e));
+    }
+    let ids: Vec<Uuid> = batch.iter().map(|p| p.id).collect();
+    let fetched = match store.get(&ids).await {
+        Ok(points) => points,
+        Err(e) => return fail_all(format!("verification read failed: {}", e)),
+    };
+    let fetched: HashMap<Uuid, PointRecord> = fetched.into_iter().map(|p| (p.id, p)).collect();
+    let mut report = RestoreReport::default();
+    for expected in batch {
+        match verify(expected, fetched.get(&expected.id)) {
+            Ok(()) => report.restored.push(expected.id),
+            Err(error) => report.failed.push(FailedRestoreTask {
+                point_id: expected.id,
+                error,
+            }),
+        }
+    }
+    report
+}
+
+/// Upserts `points` in batches of `batch_size` and reads every batch back
+///
+/// A batch whose upsert fails is reported as failed as a whole, points written but not matching
+/// their record are reported one by one.
+pub async fn restore_points<S: PointStore>(
+    store: &S,
+    points: &[PointRecord],
+    batch_size: usize,
+    worker_num: usize,
+    stall: &StallConfig,
+) -> (RestoreReport, Option<StallError>) {
+    let batches = points.chunks(batch_size.max(1)).map(|batch| {
+        let id = format!("batch starting at {}", batch[0].id);
+        (id, restore_batch(store, batch))
+    });
+    let mut report = RestoreReport::default();
+    let watched = for_each_watched(batches, worker_num, stall, |batch| {
+        report.restored.extend(batch.restored);
+        report.failed.extend(batch.failed);
+    })
+    .await;
+    (report, watched.err())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use shared::test_util::MemoryPointStore;
+    use std::collections::HashSet;
+
+    fn record(n: u128) -> PointRecord {
+        PointRecord {
+            id: Uuid::from_u128(n),
+            vectors: HashMap::from([("image_vector".to_owned(), vec![n as f32; 4])]),
+            payload: json!({"format": "jpg", "width": n, "categories": ["a"]})
+                .as_object()
+                .unwrap()
+                .clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_in_batches() {
+        let points: Vec<PointRecord> = (1..=5).map(record).collect();
+        let store = MemoryPointStore::default();
+        let (report, stalled) =
+            restore_points(&store, &points, 2, 2, &StallConfig::default()).await;
+        assert!(stalled.is_none());
+        assert!(report.failed.is_empty());
+        let restored: HashSet<Uuid> = report.restored.into_iter().collect();
+        assert_eq!(restored, points.iter().map(|p| p.id).collect());
+        assert_eq!(store.points.lock().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_partial_failures() {
+        let points: Vec<PointRecord> = (1..=5).map(record).collect();
+        let store = MemoryPointStore {
+            broken: HashSet::from([Uuid::from_u128(3)]),
+            corrupt: HashSet::from([Uuid::from_u128(5)]),
+            ..Default::default()
+        };
+        let (report, _) = restore_points(&store, &points, 2, 1, &StallConfig::default()).await;
+        let mut restored = report.restored.clone();
+        restored.sort();
+        assert_eq!(restored, vec![Uuid::from_u128(1), Uuid::from_u128(2)]);
+        let failed: HashMap<Uuid, String> = report
+            .failed
+            .into_iter()
+            .map(|f| (f.point_id, f.error))
+            .collect();
+        assert_eq!(failed.len(), 3);
+        // the whole batch [3, 4] is rejected
+        assert!(failed[&Uuid::from_u128(3)].starts_with("upsert failed"));
+        assert!(failed[&Uuid::from_u128(4)].starts_with("upsert failed"));
+        assert_eq!(failed[&Uuid::from_u128(5)], "payload mismatch after upsert");
+    }
+
+    #[test]
+    fn test_verify_vectors() {
+        let expected = record(1);
+        let mut actual = expected.clone();
+        assert!(verify(&expected, Some(&actual)).is_ok());
+        assert!(verify(&expected, None).is_err());
+        actual
+            .vectors
+            .insert("image_vector".to_owned(), vec![1.0; 3]);
+        assert!(
+            verify(&expected, Some(&actual))
+                .unwrap_err()
+                .contains("dimension")
+        );
+        actual.vectors.clear();
+        assert!(
+            verify(&expected, Some(&actual))
+                .unwrap_err()
+                .contains("missing")
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Puts a collection back the way a `--backup-snapshot` of stage8 or stage11 left it
+//!
+//! The snapshot replaces every point of the collection, writes made since it was taken are lost.
+use clap::Parser;
+use shared::logging::Logging;
+use shared::qdrant::GenShinQdrantClient;
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "restore-snapshot",
+    version,
+    about = "Recover a collection from a snapshot taken before a destructive stage"
+)]
+struct Cli {
+    /// Snapshot file written by `--backup-snapshot`
+    #[arg(long)]
+    snapshot_file: PathBuf,
+    /// Collection to recover instead of `QDRANT_COLLECTION_NAME`
+    #[arg(long)]
+    collection: Option<String>,
+    /// Recover without asking for confirmation
+    #[arg(long)]
+    yes: bool,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let _logging = Logging::new("restore_snapshot").init()?;
+    let collection = match cli.collection {
+        Some(collection) => collection,
+        None => env::var("QDRANT_COLLECTION_NAME")?,
+    };
+    anyhow::ensure!(
+        cli.snapshot_file.is_file(),
+        "Snapshot {} does not exist",
+        cli.snapshot_file.display()
+    );
+    if !cli.yes {
+        print!(
+            "Type the collection name to replace {} with {}: ",
+            collection,
+            cli.snapshot_file.display()
+        );
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        anyhow::ensure!(
+            answer.trim() == collection,
+            "Not confirmed, nothing was recovered"
+        );
+    }
+    GenShinQdrantClient::new()?
+        .import_snapshot(&collection, &cli.snapshot_file)
+        .await?;
+    Ok(())
+}
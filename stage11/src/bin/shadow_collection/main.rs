@@ -0,0 +1,122 @@
+//! Disposable copy of the collection to rehearse the destructive tail on
+//!
+//! 1. `shadow-collection --shadow <name>` next to the `final_classification.json` and
+//!    `points_map.bin` stage9 left, creates `<name>` with the schema of `QDRANT_COLLECTION_NAME`
+//!    and copies every point the classification references plus `--extra` others into it
+//! 2. `stage11 --collection <name> --shadow-baseline shadow_baseline.jsonl` runs against the copy
+//!    and saves a report of how the copy differs from what the classification expects, failing
+//!    when they differ
+//! 3. drop `<name>` once the report is read
+//!
+//! `assets/pipelines/shadow.toml` runs both, then stage11 on the live collection once the
+//! report matches.
+use clap::Parser;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use shared::atomic_write::atomic_write_with;
+use shared::logging::Logging;
+use shared::migrations::load_neko_points;
+use shared::qdrant::{
+    GenShinQdrantClient, PointRef, QdrantPointStore, clone_collection, copy_points,
+};
+use shared::structure::{FinalClassification, NekoPoint};
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::path::PathBuf;
+use std::{env, fs};
+use uuid::Uuid;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "shadow-collection",
+    version,
+    about = "Copy the points final_classification.json touches into a new collection to run stage11 on"
+)]
+struct Cli {
+    /// Collection to create, it must not exist yet
+    #[arg(long)]
+    shadow: String,
+    /// Points the classification does not reference copied along, picked at random
+    #[arg(long, default_value = "1000")]
+    extra: usize,
+    /// Seed of the extra points, random by default
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long, default_value = "256")]
+    batch_size: usize,
+    /// JSON lines export of the copied points, stage11's `--shadow-baseline`
+    #[arg(long, default_value = "shadow_baseline.jsonl")]
+    export_file: PathBuf,
+}
+
+/// Every referenced point, then `extra` of the other snapshot points
+fn sample(
+    res: &[FinalClassification],
+    snapshot: &HashMap<Uuid, NekoPoint>,
+    extra: usize,
+    seed: u64,
+) -> Vec<Uuid> {
+    let referenced: BTreeSet<Uuid> = res.iter().flat_map(FinalClassification::handled).collect();
+    let others: Vec<Uuid> = snapshot
+        .keys()
+        .filter(|id| !referenced.contains(id))
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let mut rng = Pcg64::seed_from_u64(seed);
+    let picked = rand::seq::index::sample(&mut rng, others.len(), extra.min(others.len()));
+    let mut ids: Vec<Uuid> = referenced.into_iter().collect();
+    ids.extend(picked.into_iter().map(|idx| others[idx]));
+    ids
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let _logging = Logging::new("shadow_collection").init()?;
+    let source = env::var("QDRANT_COLLECTION_NAME")?;
+    let res: Vec<FinalClassification> =
+        serde_json::from_slice(&fs::read("final_classification.json")?)?;
+    let snapshot: HashMap<Uuid, NekoPoint> = load_neko_points(r"points_map.bin")?;
+    let seed = cli.seed.unwrap_or_else(rand::random);
+    let ids = sample(&res, &snapshot, cli.extra, seed);
+    tracing::info!(
+        "Copying {} points from {} to {} (seed {})",
+        ids.len(),
+        source,
+        cli.shadow,
+        seed
+    );
+
+    let client = GenShinQdrantClient::new()?;
+    clone_collection(&client, &source, &cli.shadow).await?;
+    let num_ids = || snapshot.values().map(PointRef::of);
+    let source_store = QdrantPointStore::new(client, &source).with_num_ids(num_ids());
+    let shadow_store =
+        QdrantPointStore::new(GenShinQdrantClient::new()?, &cli.shadow).with_num_ids(num_ids());
+    let records = copy_points(&source_store, &shadow_store, &ids, cli.batch_size).await?;
+    atomic_write_with(&cli.export_file, |w| {
+        for record in records.iter() {
+            serde_json::to_writer(&mut *w, record)?;
+            w.write_all(b"\n")?;
+        }
+        Ok::<_, anyhow::Error>(())
+    })?;
+    if records.len() < ids.len() {
+        tracing::warn!(
+            "{} of {} points are not in {}",
+            ids.len() - records.len(),
+            ids.len(),
+            source
+        );
+    }
+    tracing::info!(
+        "Copied {} points, baseline saved to {}, next: stage11 --collection {} --shadow-baseline {}",
+        records.len(),
+        cli.export_file.display(),
+        cli.shadow,
+        cli.export_file.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! Points more than one task writes to, whose outcome would depend on which write lands last
+
+use crate::task::ReSetPointTask;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// What a task does to a point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PointOp {
+    /// Kept, its categories overwritten
+    Keep,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskOp {
+    /// Position in the task list
+    pub task: usize,
+    /// Position of the final_classification.json entry the task was built from
+    pub entry: usize,
+    pub op: PointOp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PointConflict {
+    pub id: Uuid,
+    pub tasks: Vec<TaskOp>,
+    /// Kept by one task and deleted by another, the classification contradicts itself
+    pub keep_vs_delete: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ConflictReport {
+    /// Sorted by point
+    pub conflicts: Vec<PointConflict>,
+    pub keep_vs_delete: usize,
+    /// Tasks sharing a point with another one
+    pub tasks: usize,
+}
+
+impl ConflictReport {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// Tasks of every conflicting point, in order, each task once
+    pub fn groups(&self) -> Vec<Vec<usize>> {
+        self.conflicts
+            .iter()
+            .map(|c| {
+                let mut tasks: Vec<usize> = c.tasks.iter().map(|t| t.task).collect();
+                tasks.dedup();
+                tasks
+            })
+            .collect()
+    }
+}
+
+/// Points referenced by more than one task, a point kept and deleted by the same task is that
+/// task's business
+pub fn find_conflicts(tasks: &[ReSetPointTask<'_>]) -> ConflictReport {
+    let mut referenced: BTreeMap<Uuid, Vec<TaskOp>> = BTreeMap::new();
+    for (idx, task) in tasks.iter().enumerate() {
+        let keeps = task.keep_point_list.iter().map(|id| (id, PointOp::Keep));
+        let deletes = task
+            .discard_point_list
+            .iter()
+            .map(|id| (id, PointOp::Delete));
+        for (id, op) in keeps.chain(deletes) {
+            referenced.entry(**id).or_default().push(TaskOp {
+                task: idx,
+                entry: task.entry,
+                op,
+            });
+        }
+    }
+    let mut report = ConflictReport::default();
+    let mut involved = vec![false; tasks.len()];
+    for (id, ops) in referenced {
+        if ops.iter().all(|op| op.task == ops[0].task) {
+            continue;
+        }
+        let keep_vs_delete = ops.iter().any(|a| {
+            a.op == PointOp::Keep
+                && ops
+                    .iter()
+                    .any(|b| b.op == PointOp::Delete && b.task != a.task)
+        });
+        report.keep_vs_delete += keep_vs_delete as usize;
+        for op in ops.iter() {
+            involved[op.task] = true;
+        }
+        report.conflicts.push(PointConflict {
+            id,
+            tasks: ops,
+            keep_vs_delete,
+        });
+    }
+    report.tasks = involved.into_iter().filter(|i| *i).count();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task<'a>(entry: usize, keep: &'a [Uuid], discard: &'a [Uuid]) -> ReSetPointTask<'a> {
+        ReSetPointTask {
+            entry,
+            keep_point_list: keep.iter().collect(),
+            discard_point_list: discard.iter().collect(),
+            transfer_tag_list: keep.iter().map(|_| vec!["a"]).collect(),
+        }
+    }
+
+    #[test]
+    fn test_find_conflicts() {
+        let id: Vec<Uuid> = (0..8).map(Uuid::from_u128).collect();
+        let tasks = vec![
+            task(0, &id[0..1], &id[1..3]),
+            // deletes what task 0 keeps
+            task(2, &id[3..4], &id[0..1]),
+            // keeps what task 0 deletes, deletes what task 1 deletes too
+            task(3, &id[1..2], &id[4..5]),
+            task(5, &id[5..6], &id[4..5]),
+            // kept and deleted by itself only
+            task(6, &id[6..7], &id[6..7]),
+        ];
+        let report = find_conflicts(&tasks);
+        let summary: Vec<_> = report
+            .conflicts
+            .iter()
+            .map(|c| {
+                let ops: Vec<_> = c.tasks.iter().map(|t| (t.task, t.entry, t.op)).collect();
+                (c.id, ops, c.keep_vs_delete)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    id[0],
+                    vec![(0, 0, PointOp::Keep), (1, 2, PointOp::Delete)],
+                    true
+                ),
+                (
+                    id[1],
+                    vec![(0, 0, PointOp::Delete), (2, 3, PointOp::Keep)],
+                    true
+                ),
+                (
+                    id[4],
+                    vec![(2, 3, PointOp::Delete), (3, 5, PointOp::Delete)],
+                    false
+                ),
+            ]
+        );
+        assert_eq!((report.keep_vs_delete, report.tasks), (2, 4));
+        assert_eq!(report.groups(), vec![vec![0, 1], vec![0, 2], vec![2, 3]]);
+        assert!(find_conflicts(&tasks[3..]).is_empty());
+    }
+}
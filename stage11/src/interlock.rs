@@ -0,0 +1,281 @@
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use shared::qdrant::PointStore;
+use shared::structure::{FinalClassification, NekoPoint};
+use std::collections::{BTreeSet, HashMap};
+use uuid::Uuid;
+
+/// Points fetched per request while sampling
+const SAMPLE_CHUNK: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct InterlockConfig {
+    /// Referenced points fetched from the collection
+    pub sample_size: usize,
+    /// Least fraction of the sample that has to exist in the collection
+    pub min_present: f64,
+    /// Least fraction of the comparable present points whose dimensions match `points_map.bin`
+    pub min_matching: f64,
+    pub seed: u64,
+}
+
+/// A sampled point whose stored dimensions are not those of the snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DimensionMismatch {
+    pub id: Uuid,
+    /// `(height, width)` in `points_map.bin`
+    pub expected: (usize, usize),
+    /// `(height, width)` in the payload, `None` if absent or not an integer
+    pub found: (Option<usize>, Option<usize>),
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct InterlockReport {
+    pub collection: String,
+    /// Points the classification keeps or deletes
+    pub referenced: usize,
+    pub sampled: usize,
+    /// Sampled points the collection does not hold
+    pub missing: Vec<Uuid>,
+    /// Present points the snapshot has no usable dimensions for
+    pub not_comparable: usize,
+    pub mismatched: Vec<DimensionMismatch>,
+    /// Why the collection does not look like the one the classification was made from
+    pub failures: Vec<String>,
+}
+
+impl InterlockReport {
+    pub fn present(&self) -> usize {
+        self.sampled - self.missing.len()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn check(&mut self, config: &InterlockConfig) {
+        if self.sampled == 0 {
+            return;
+        }
+        let present = self.present() as f64 / self.sampled as f64;
+        if present < config.min_present {
+            self.failures.push(format!(
+                "only {} of {} sampled points exist in {} ({:.1}%, at least {:.1}% required)",
+                self.present(),
+                self.sampled,
+                self.collection,
+                present * 100.0,
+                config.min_present * 100.0
+            ));
+        }
+        let comparable = self.present() - self.not_comparable;
+        if comparable == 0 {
+            return;
+        }
+        let matching = (comparable - self.mismatched.len()) as f64 / comparable as f64;
+        if matching < config.min_matching {
+            self.failures.push(format!(
+                "{} of {} comparable points have other dimensions than in points_map.bin ({:.1}% match, at least {:.1}% required)",
+                self.mismatched.len(),
+                comparable,
+                matching * 100.0,
+                config.min_matching * 100.0
+            ));
+        }
+    }
+}
+
+/// Every point the classification keeps or deletes, sorted
+pub fn referenced_points(res: &[FinalClassification]) -> Vec<Uuid> {
+    res.iter()
+        .flat_map(FinalClassification::handled)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// An integer payload field, also accepted as an integral float
+fn payload_usize(payload: &Map<String, Value>, field: &str) -> Option<usize> {
+    match payload.get(field)? {
+        Value::Number(n) => n
+            .as_u64()
+            .or_else(|| {
+                n.as_f64()
+                    .filter(|f| f.fract() == 0.0 && *f >= 0.0)
+                    .map(|f| f as u64)
+            })
+            .map(|n| n as usize),
+        _ => None,
+    }
+}
+
+/// Fetches a sample of `referenced` and compares it with the `points_map.bin` snapshot
+///
+/// The snapshot carries no format, only the dimensions are compared. Points stage2 could not read
+/// the dimensions of are stored as 0x0 and left out of the comparison.
+pub async fn verify_sample<S: PointStore>(
+    store: &S,
+    collection: &str,
+    referenced: &[Uuid],
+    snapshot: &HashMap<Uuid, NekoPoint>,
+    config: &InterlockConfig,
+) -> anyhow::Result<InterlockReport> {
+    let mut rng = Pcg64::seed_from_u64(config.seed);
+    let amount = config.sample_size.min(referenced.len());
+    let mut sample: Vec<Uuid> = rand::seq::index::sample(&mut rng, referenced.len(), amount)
+        .into_iter()
+        .map(|idx| referenced[idx])
+        .collect();
+    sample.sort_unstable();
+
+    let mut report = InterlockReport {
+        collection: collection.to_owned(),
+        referenced: referenced.len(),
+        sampled: sample.len(),
+        ..Default::default()
+    };
+    for chunk in sample.chunks(SAMPLE_CHUNK) {
+        let found: HashMap<Uuid, Map<String, Value>> = store
+            .get_payloads(chunk)
+            .await?
+            .into_iter()
+            .map(|p| (p.id, p.payload))
+            .collect();
+        for id in chunk {
+            let Some(payload) = found.get(id) else {
+                report.missing.push(*id);
+                continue;
+            };
+            let expected = match snapshot.get(id) {
+                Some(p) if p.pixel_count() > 0 => (p.height, p.width),
+                _ => {
+                    report.not_comparable += 1;
+                    continue;
+                }
+            };
+            let found = (
+                payload_usize(payload, "height"),
+                payload_usize(payload, "width"),
+            );
+            if found != (Some(expected.0), Some(expected.1)) {
+                report.mismatched.push(DimensionMismatch {
+                    id: *id,
+                    expected,
+                    found,
+                });
+            }
+        }
+    }
+    report.check(config);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use shared::test_util::MemoryPointStore;
+
+    fn memory_store<I: IntoIterator<Item = (u128, Value)>>(points: I) -> MemoryPointStore {
+        MemoryPointStore::with_payloads(
+            points
+                .into_iter()
+                .map(|(n, payload)| (Uuid::from_u128(n), payload.as_object().unwrap().clone())),
+        )
+    }
+
+    fn snapshot(n: u128) -> HashMap<Uuid, NekoPoint> {
+        (1..=n)
+            .map(|n| {
+                let id = Uuid::from_u128(n);
+                let point = NekoPoint {
+                    id,
+                    height: 100 + n as usize,
+                    width: 200,
+                    size: None,
+                    categories: None,
+                    text_info: None,
+                    qdrant_num_id: None,
+                    extra: None,
+                };
+                (id, point)
+            })
+            .collect()
+    }
+
+    fn config(sample_size: usize) -> InterlockConfig {
+        InterlockConfig {
+            sample_size,
+            min_present: 0.9,
+            min_matching: 0.9,
+            seed: 7,
+        }
+    }
+
+    fn ids(n: u128) -> Vec<Uuid> {
+        (1..=n).map(Uuid::from_u128).collect()
+    }
+
+    #[tokio::test]
+    async fn test_verify_sample_match() {
+        let store = memory_store((1..=20).map(|n| {
+            let height = 100 + n;
+            // old points stored floats, 20 is not in the snapshot
+            match n {
+                1 => (n, json!({"height": height as f64, "width": 200.0})),
+                _ => (n, json!({"height": height, "width": 200, "format": "jpg"})),
+            }
+        }));
+        let report = verify_sample(&store, "memes", &ids(20), &snapshot(19), &config(50))
+            .await
+            .unwrap();
+        assert_eq!(report.sampled, 20);
+        assert!(report.missing.is_empty());
+        assert!(report.mismatched.is_empty());
+        assert_eq!(report.not_comparable, 1);
+        assert!(report.passed(), "{:?}", report.failures);
+
+        let report = verify_sample(&store, "memes", &ids(20), &snapshot(20), &config(5))
+            .await
+            .unwrap();
+        assert_eq!(report.sampled, 5);
+        assert!(report.passed());
+    }
+
+    #[tokio::test]
+    async fn test_verify_sample_mismatch() {
+        // another collection reusing some of the ids for other images
+        let store = memory_store((1..=10).map(|n| (n * 2, json!({"height": 50, "width": 200}))));
+        let report = verify_sample(&store, "other", &ids(20), &snapshot(20), &config(20))
+            .await
+            .unwrap();
+        assert_eq!(report.sampled, 20);
+        assert_eq!(report.missing.len(), 10);
+        assert_eq!(report.mismatched.len(), 10);
+        assert_eq!(
+            report.mismatched[0],
+            DimensionMismatch {
+                id: Uuid::from_u128(2),
+                expected: (102, 200),
+                found: (Some(50), Some(200)),
+            }
+        );
+        assert_eq!(report.failures.len(), 2);
+        assert!(report.failures[0].starts_with("only 10 of 20 sampled points exist in other"));
+
+        // every point exists but a few lost their dimensions
+        let store = memory_store((1..=20).map(|n| match n {
+            1..=3 => (n, json!({"height": "tall"})),
+            _ => (n, json!({"height": 100 + n, "width": 200})),
+        }));
+        let report = verify_sample(&store, "memes", &ids(20), &snapshot(20), &config(20))
+            .await
+            .unwrap();
+        assert!(report.missing.is_empty());
+        assert_eq!(report.mismatched.len(), 3);
+        assert_eq!(report.mismatched[0].found, (None, None));
+        assert_eq!(report.failures.len(), 1);
+    }
+}
@@ -1,201 +1,176 @@
+mod archive;
+mod conflict;
+mod interlock;
+mod plan;
+mod shadow;
+mod task;
+mod waves;
+
+use crate::archive::PayloadArchive;
+use crate::conflict::find_conflicts;
+use crate::interlock::{InterlockConfig, referenced_points, verify_sample};
+use crate::plan::{PlanFormat, PlanSummary, plan_tasks, write_plan};
+use crate::shadow::read_baseline;
+use crate::task::{
+    FailedReSetPointTask, FailureReason, ReSetPointTask, TaskStats, build_tasks, failed_tasks,
+    snapshot_payloads, write_ops,
+};
+use crate::waves::partition_waves;
 use clap::Parser;
-use futures::StreamExt;
-use futures::future::join_all;
 use indicatif::{ProgressBar, ProgressStyle};
-use qdrant_client::qdrant::{
-    DeletePointsBuilder, PointsIdsList, PointsOperationResponse, SetPayloadPointsBuilder,
-};
-use qdrant_client::{Payload, QdrantError};
 use serde::Serialize;
-use serde_json::json;
-use shared::qdrant::GenShinQdrantClient;
+use serde_json::{Map, Value};
+use shared::atomic_write::atomic_write_with;
+use shared::logging::Logging;
+use shared::migrations::load_neko_points;
+use shared::op_ledger::OpLedger;
+use shared::opendal::GenShinOperator;
+use shared::qdrant::{
+    ConflictAction, GenShinQdrantClient, IdKindCounts, OnConflict, PayloadConflict, PointRef,
+    PointStore, QdrantPointStore, QdrantWriteScheduler, WriteOp, WriteSchedulerConfig, WriteStatus,
+    compare_shadow, expected_points, guard_writes,
+};
+use shared::stage_lock::LockArgs;
+use shared::stall::{StallArgs, StallConfig, StallError};
 use shared::structure::{FinalClassification, NekoPoint};
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::ops::Deref;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
 use std::{env, fs};
-use tokio::join;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize)]
-struct ReSetPointTask<'a> {
-    keep_point_list: Vec<&'a Uuid>,
-    discard_point_list: Vec<&'a Uuid>,
-    transfer_tag_list: Vec<Vec<&'a str>>,
-}
-
+/// A kept point whose payload changed since points_map.bin
 #[derive(Debug, Serialize)]
-struct FailedReSetPointTask<'a> {
+struct KeepConflict {
+    /// Position of the final_classification.json entry of the task keeping it
+    entry: usize,
     #[serde(flatten)]
-    task: ReSetPointTask<'a>,
-    error: String,
+    conflict: PayloadConflict,
 }
 
-struct Stage11GenshinQdrantClient {
-    client: GenShinQdrantClient,
-    collection_name: String,
-    dry_run: bool,
-    worker_num: usize,
-    url_prefix: String,
+/// Keep writes checked against the collection right before they are sent, see [`guard_writes`]
+struct PayloadCheck {
+    policy: OnConflict,
+    batch_size: usize,
+    /// Payload of the kept points as this run last saw it, points_map.bin's until a wave writes
+    snapshot: HashMap<Uuid, Map<String, Value>>,
+    conflicts: Vec<KeepConflict>,
 }
 
-impl Deref for Stage11GenshinQdrantClient {
-    type Target = GenShinQdrantClient;
-
-    fn deref(&self) -> &Self::Target {
-        &self.client
+async fn set_reset_point_task<'a, S: PointStore>(
+    scheduler: &QdrantWriteScheduler<S>,
+    tasks: &[ReSetPointTask<'a>],
+    check: &mut PayloadCheck,
+) -> anyhow::Result<(Vec<FailedReSetPointTask<'a>>, Option<StallError>)> {
+    let (ops, owners) = write_ops(tasks);
+    let guarded = match guard_writes(
+        scheduler.store(),
+        &ops,
+        &check.snapshot,
+        check.policy,
+        check.batch_size,
+    )
+    .await
+    {
+        Ok(guarded) => guarded,
+        Err(e) => {
+            tracing::error!("Failed to read the payload of the kept points: {}", e);
+            let failed = tasks
+                .iter()
+                .map(|task| FailedReSetPointTask {
+                    task: task.clone(),
+                    reason: FailureReason::PayloadCheckFailed,
+                    error: e.to_string(),
+                })
+                .collect();
+            return Ok((failed, None));
+        }
+    };
+    for (conflict, op) in guarded.conflicts.into_iter().zip(guarded.conflict_owners) {
+        check.conflicts.push(KeepConflict {
+            entry: tasks[owners[op]].entry,
+            conflict,
+        });
     }
-}
-
-impl Stage11GenshinQdrantClient {
-    pub fn new(
-        collection_name: &str,
-        dry_run: bool,
-        worker_num: usize,
-        url_prefix: &str,
-    ) -> anyhow::Result<Self> {
-        let client = GenShinQdrantClient::new()?;
-        Ok(Self {
-            client,
-            collection_name: collection_name.to_owned(),
-            dry_run,
-            worker_num,
-            url_prefix: url_prefix.to_owned(),
+    let owners: Vec<usize> = guarded.owners.iter().map(|&op| owners[op]).collect();
+    let ops = guarded.ops;
+    let pb = ProgressBar::new(ops.len() as u64);
+    let style = ProgressStyle::default_bar()
+        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
+    pb.set_style(style);
+    pb.set_message("Overwriting Qdrant payload...");
+    let (outcomes, stalled) = scheduler
+        .run(&ops, |done, total| {
+            pb.set_length(total as u64);
+            pb.set_position(done as u64);
         })
-    }
-
-    async fn set_reset_point_task<'a>(
-        self: Arc<Self>,
-        tasks: &'a [ReSetPointTask<'a>],
-    ) -> anyhow::Result<Option<Vec<FailedReSetPointTask<'a>>>> {
-        let pb = ProgressBar::new(tasks.len() as u64);
-        let style = ProgressStyle::default_bar()
-            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
-        pb.set_style(style);
-        pb.set_message("Overwriting Qdrant payload...");
-        let mut stream = futures::stream::iter(tasks.into_iter().map(|op| {
-            let client = self.clone();
-            let pb = pb.clone();
-            async move {
-                let triage = client.set_reset_point_task_atomic(op).await;
-                pb.inc(1);
-                (op, triage)
-            }
-        }))
-        .buffer_unordered(self.worker_num);
-        let mut failed_tasks = Vec::new();
-        while let Some((tasks, res)) = stream.next().await {
-            match res {
-                Some(res) => {
-                    res.into_iter().for_each(|result| match result {
-                        Ok(_) => {}
-                        Err(e) => {
-                            tracing::error!("Failed to overwrite task: {}", e);
-                            failed_tasks.push(FailedReSetPointTask {
-                                task: tasks.clone(),
-                                error: e.to_string(),
-                            });
-                        }
-                    });
-                }
-                _ => {}
-            }
-        }
-        pb.finish_with_message("Done");
-        if failed_tasks.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(failed_tasks))
+        .await;
+    pb.finish_with_message("Done");
+    // what later waves find there is ours, not a conflict
+    for outcome in outcomes.iter() {
+        let (WriteStatus::Written, WriteOp::SetPayload { points, payload }) =
+            (&outcome.status, &ops[outcome.op])
+        else {
+            continue;
+        };
+        for id in points {
+            let snapshot = check.snapshot.entry(*id).or_default();
+            snapshot.extend(payload.clone());
         }
     }
-
-    async fn set_reset_point_task_atomic<'a>(
-        self: Arc<Self>,
-        task: &'a ReSetPointTask<'a>,
-    ) -> Option<Vec<Result<PointsOperationResponse, QdrantError>>> {
-        let keep_point_ids: Vec<&Uuid> = task.keep_point_list.iter().cloned().collect();
-        let delete_point_ids: Vec<&Uuid> = task.discard_point_list.iter().cloned().collect();
-        let payload = task
-            .keep_point_list
-            .iter()
-            .zip(task.transfer_tag_list.iter())
-            .map(|(_, tags)| {
-                Payload::try_from(json!({
-                    "categories": tags,
-                }))
-                .expect("Failed to create payload")
-            })
-            .collect::<Vec<_>>();
-        if self.dry_run {
-            tracing::info!(
-                "Dry run: would overwrite points {:?} with Payload: {:?}",
-                task.keep_point_list,
-                payload
-            );
-            return None;
-        }
-        let add_ops = join_all(
-            keep_point_ids
-                .into_iter()
-                .zip(payload)
-                .map(|(id, payload)| {
-                    self.client.set_payload(
-                        SetPayloadPointsBuilder::new(&self.collection_name, payload)
-                            .points_selector(PointsIdsList {
-                                ids: vec![id.to_string().into()],
-                            })
-                            .wait(true),
-                    )
-                }),
-        );
-        let del_ops = join_all(delete_point_ids.into_iter().map(|id| {
-            self.client.delete_points(
-                DeletePointsBuilder::new(&self.collection_name)
-                    .points(PointsIdsList {
-                        ids: vec![id.to_string().into()],
-                    })
-                    .wait(true),
-            )
-        }));
-        let res = join!(add_ops, del_ops);
-        let res = res
-            .0
-            .into_iter()
-            .chain(res.1.into_iter())
-            .collect::<Vec<_>>();
-        Some(res)
+    let failed = failed_tasks(tasks, &owners, outcomes);
+    for failed in failed.iter() {
+        tracing::error!("Failed to overwrite task: {}", failed.error);
     }
+    Ok((failed, stalled))
 }
 
-fn into_keep_tags<'a>(
-    uuid: &'a Uuid,
-    tags_sets: &mut Vec<HashSet<&'a str>>,
-    metadata: &'a HashMap<Uuid, NekoPoint>,
-) {
-    if let Some(categories) = metadata.get(uuid).and_then(|p| p.categories.as_ref()) {
-        let mut tags = HashSet::with_capacity(categories.len());
-        categories.iter().for_each(|tag| {
-            tags.insert(tag.as_str());
-        });
-        tags_sets.push(tags);
+/// Runs `tasks` in waves, one after the other, no two tasks of a wave writing the same point
+///
+/// A single wave unless tasks conflict, see [`partition_waves`]. After a stall abort the tasks of
+/// the later waves fail as not attempted.
+async fn run_in_waves<'a, S: PointStore>(
+    scheduler: &QdrantWriteScheduler<S>,
+    tasks: &[ReSetPointTask<'a>],
+    check: &mut PayloadCheck,
+) -> anyhow::Result<(Vec<FailedReSetPointTask<'a>>, Option<StallError>)> {
+    let waves = partition_waves(tasks.len(), &find_conflicts(tasks).groups());
+    let mut failed = Vec::new();
+    for (n, wave) in waves.iter().enumerate() {
+        let wave_tasks: Vec<ReSetPointTask<'a>> = wave.iter().map(|&i| tasks[i].clone()).collect();
+        if waves.len() > 1 {
+            tracing::info!("Wave {}/{}: {} tasks", n + 1, waves.len(), wave_tasks.len());
+        }
+        let (wave_failed, stalled) = set_reset_point_task(scheduler, &wave_tasks, check).await?;
+        failed.extend(wave_failed);
+        if stalled.is_some() {
+            failed.extend(
+                waves[n + 1..]
+                    .iter()
+                    .flatten()
+                    .map(|&i| FailedReSetPointTask {
+                        task: tasks[i].clone(),
+                        reason: FailureReason::NotAttempted,
+                        error: "not attempted, run aborted".to_owned(),
+                    }),
+            );
+            return Ok((failed, stalled));
+        }
     }
+    Ok((failed, None))
 }
 
-fn into_duplicate_tags<'a>(
-    uuid: &'a Uuid,
-    tags_set: &mut HashSet<&'a str>,
-    metadata: &'a HashMap<Uuid, NekoPoint>,
-) {
-    if let Some(categories) = metadata.get(uuid).and_then(|p| p.categories.as_ref()) {
-        categories.iter().for_each(|tag| {
-            tags_set.insert(tag.as_str());
-        });
-    }
+/// Asks for the collection name on stdin before anything is written
+fn confirm(collection: &str) -> anyhow::Result<()> {
+    print!("Type the collection name to modify {}: ", collection);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    anyhow::ensure!(
+        answer.trim() == collection,
+        "Not confirmed, nothing was written"
+    );
+    Ok(())
 }
 
 #[derive(Parser, Debug)]
@@ -203,122 +178,418 @@ fn into_duplicate_tags<'a>(
 struct Cli {
     #[arg(long, default_value = "false")]
     dry_run: bool,
+    /// Report of every point the run would touch, with its current and proposed payload, the
+    /// payloads are fetched from the collection first
+    #[arg(long, requires = "dry_run")]
+    plan_report: Option<PathBuf>,
+    #[arg(long, value_enum, default_value = "json")]
+    plan_format: PlanFormat,
     #[arg(long, default_value = "16")]
     worker_num: usize,
-    #[arg(long, default_value = "http://127.0.0.1:10000/nekoimg/NekoImage")]
-    url_prefix: String,
+    /// Most points deleted per request
+    #[arg(long, default_value = "256")]
+    batch_size: usize,
+    /// Retries of a request failing with a transient error
+    #[arg(long, default_value = "3")]
+    max_retries: u32,
+    /// Requests started per second, unlimited by default
+    #[arg(long)]
+    max_requests_per_sec: Option<f64>,
     #[arg(long, default_value = "qdrant_point_reset_errors")]
     save_result_prefix: String,
+    #[command(flatten)]
+    stall: StallArgs,
+    /// Prometheus text dump of this run's metrics
+    #[arg(long, default_value = "stage11_metrics.prom")]
+    metrics_file: PathBuf,
+    /// Key prefix in the S3 bucket, every discarded point is uploaded to `<prefix>/<uuid>.json`
+    /// first and a task whose upload fails is not written at all
+    #[arg(long)]
+    archive_payloads: Option<String>,
+    /// Archive the vectors too, making the archive a `restore-points` export
+    #[arg(long, requires = "archive_payloads")]
+    archive_vectors: bool,
+    /// Tasks archived concurrently
+    #[arg(long, default_value = "16")]
+    archive_concurrency: usize,
+    /// Referenced points fetched to check the collection is the one the classification was made
+    /// from
+    #[arg(long, default_value = "500")]
+    interlock_sample: usize,
+    /// Least fraction of the sampled points that has to exist in the collection
+    #[arg(long, default_value = "0.95")]
+    interlock_min_present: f64,
+    /// Least fraction of the present sampled points whose dimensions match points_map.bin
+    #[arg(long, default_value = "0.9")]
+    interlock_min_matching: f64,
+    /// Run even if the collection fails the interlock check
+    #[arg(long)]
+    force: bool,
+    /// Write without asking for confirmation
+    #[arg(long)]
+    yes: bool,
+    #[command(flatten)]
+    lock: LockArgs,
+    /// Collection to write to instead of `QDRANT_COLLECTION_NAME`, e.g. a shadow collection
+    #[arg(long)]
+    collection: Option<String>,
+    /// Export written by `shadow-collection`, once done the collection is compared with what
+    /// final_classification.json expects of the exported points
+    #[arg(long, requires = "collection")]
+    shadow_baseline: Option<PathBuf>,
+    /// Where the shadow report goes, `<save_result_prefix>_shadow_report_<time>.json` by default,
+    /// the run fails when the collection differs from what is expected
+    #[arg(long, requires = "shadow_baseline")]
+    shadow_report: Option<PathBuf>,
+    /// Run tasks writing the same points in consecutive waves, in their order in
+    /// final_classification.json, instead of refusing to run
+    #[arg(long)]
+    serialize_conflicts: bool,
+    /// What to do with a kept point whose categories changed since points_map.bin: `merge` them
+    /// with ours, `skip` it for manual review or `overwrite` them
+    #[arg(long, default_value = "merge")]
+    on_conflict: OnConflict,
+    /// Writes and deletes already applied, per collection, skipped when re-run unchanged
+    #[arg(long, default_value = "stage11_applied_ops.jsonl")]
+    ledger: PathBuf,
+    /// Snapshot of the collection taken before the first write, `restore-snapshot` brings it back
+    #[arg(long)]
+    backup_snapshot: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new("info"));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage11.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new("info"));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let _logging = Logging::new("stage11").init()?;
+    let _lock = cli.lock.acquire("stage11")?;
     let file = fs::read("final_classification.json")?;
     let res: Vec<FinalClassification> = serde_json::from_slice(&*file)?;
-    let points_metadata = fs::read(r"points_map.bin")?;
-    let points_metadata_ex: HashMap<Uuid, NekoPoint> =
-        bincode::serde::decode_from_slice(&points_metadata, bincode::config::standard())?.0;
-    let all_tasks: Vec<ReSetPointTask<'_>> = res
-        .iter()
-        .map(|item| {
-            let mut keep_point_list = Vec::new();
-            let mut discard_point_list = Vec::new();
-            let mut keep_point_tags_set_list = Vec::new();
-            let mut discard_point_tags_set = HashSet::new();
-            item.kept_text_anomalies_group.as_ref().map(|uuids| {
-                keep_point_list.extend(uuids);
-                uuids.iter().for_each(|uuid| {
-                    into_keep_tags(uuid, &mut keep_point_tags_set_list, &points_metadata_ex)
-                })
-            });
-            item.triaged_gif_and_invalid_group.as_ref().map(|uuids| {
-                discard_point_list.extend(uuids.0.iter());
-                uuids.0.iter().for_each(|uuid| {
-                    into_duplicate_tags(uuid, &mut discard_point_tags_set, &points_metadata_ex);
-                });
-            });
-            item.triaged_gif_and_discard_same_frame_group
-                .as_ref()
-                .map(|uuids| {
-                    discard_point_list.extend(uuids.iter());
-                    uuids.iter().for_each(|uuid| {
-                        into_duplicate_tags(uuid, &mut discard_point_tags_set, &points_metadata_ex);
-                    });
-                });
-            item.triaged_gif_and_then_will_keep_group
-                .as_ref()
-                .map(|uuids| {
-                    keep_point_list.extend(uuids.iter());
-                    uuids.iter().for_each(|uuid| {
-                        into_keep_tags(uuid, &mut keep_point_tags_set_list, &points_metadata_ex);
-                    });
-                });
-            item.triaged_gif_and_then_will_delete_group
-                .as_ref()
-                .map(|uuids| {
-                    discard_point_list.extend(uuids.iter());
-                    uuids.iter().for_each(|uuid| {
-                        into_duplicate_tags(uuid, &mut discard_point_tags_set, &points_metadata_ex);
-                    });
-                });
-            item.kept_non_gif.as_ref().map(|uuid| {
-                keep_point_list.push(uuid);
-                into_keep_tags(uuid, &mut keep_point_tags_set_list, &points_metadata_ex);
-            });
-            item.other_need_delete_group.as_ref().map(|uuids| {
-                discard_point_list.extend(uuids.iter());
-                uuids.iter().for_each(|uuid| {
-                    into_duplicate_tags(uuid, &mut discard_point_tags_set, &points_metadata_ex);
-                });
-            });
-            let transfer_tag_list: Vec<Vec<&str>> = keep_point_tags_set_list
-                .into_iter()
-                .map(|mut km| {
-                    km.extend(discard_point_tags_set.iter());
-                    km.into_iter().collect::<Vec<&str>>()
-                })
-                .collect::<Vec<Vec<&str>>>();
-            assert_eq!(transfer_tag_list.len(), keep_point_list.len());
-            ReSetPointTask {
-                keep_point_list,
-                discard_point_list,
-                transfer_tag_list,
+    let sampled = res.iter().filter(|fc| fc.is_sample()).count();
+    anyhow::ensure!(
+        sampled == 0,
+        "final_classification.json comes from a sampled stage9 run ({} of {} entries are a sample), \
+         it only serves estimates",
+        sampled,
+        res.len()
+    );
+    let points_metadata_ex: HashMap<Uuid, NekoPoint> = load_neko_points(r"points_map.bin")?;
+    IdKindCounts::count(points_metadata_ex.values().map(PointRef::of))
+        .warn_if_mixed("points_map.bin");
+    let (all_tasks, build_report) = build_tasks(&res, &points_metadata_ex);
+    let stats = TaskStats::collect(&all_tasks);
+    tracing::info!(
+        "Tasks: {}, keeps: {}, discards: {}, skipped empty: {}, skipped invalid: {}",
+        stats.tasks,
+        stats.total_keeps,
+        stats.total_discards,
+        build_report.skipped_empty,
+        build_report.invalid.len()
+    );
+    tracing::info!(
+        "Tag cardinality (tags -> kept points): {:?}",
+        stats.tag_cardinality
+    );
+    if !build_report.invalid.is_empty() {
+        let filename = format!(
+            "{}_invalid_entries_{}.json",
+            cli.save_result_prefix,
+            chrono::Local::now().format("%Y%m%d_%H%M%S")
+        );
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &build_report.invalid)?)
+        })?;
+        tracing::warn!(
+            "{} invalid entries skipped, details saved to {}",
+            build_report.invalid.len(),
+            &filename
+        );
+    }
+    let conflicts = find_conflicts(&all_tasks);
+    if !conflicts.is_empty() {
+        let filename = format!(
+            "{}_conflicts_{}.json",
+            cli.save_result_prefix,
+            chrono::Local::now().format("%Y%m%d_%H%M%S")
+        );
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &conflicts)?)
+        })?;
+        tracing::warn!(
+            "{} points written by more than one of {} tasks, report saved to {}",
+            conflicts.conflicts.len(),
+            conflicts.tasks,
+            &filename
+        );
+        if conflicts.keep_vs_delete > 0 {
+            tracing::error!(
+                "{} points are kept by one task and deleted by another, the classification \
+                 contradicts itself",
+                conflicts.keep_vs_delete
+            );
+        }
+        anyhow::ensure!(
+            cli.serialize_conflicts,
+            "Tasks share points, concurrent writes would race, pass --serialize-conflicts to run \
+             them in waves"
+        );
+    }
+    let collection = match &cli.collection {
+        Some(collection) => collection.clone(),
+        None => env::var("QDRANT_COLLECTION_NAME")?,
+    };
+    let expected = match &cli.shadow_baseline {
+        Some(path) => {
+            anyhow::ensure!(
+                env::var("QDRANT_COLLECTION_NAME").ok().as_ref() != Some(&collection),
+                "{} is QDRANT_COLLECTION_NAME, a shadow run needs a shadow collection",
+                collection
+            );
+            let baseline = read_baseline(path)?;
+            tracing::info!("Shadow run, {} points in the baseline", baseline.len());
+            Some(expected_points(&baseline, &write_ops(&all_tasks).0))
+        }
+        None => None,
+    };
+    let store = QdrantPointStore::new(GenShinQdrantClient::new()?, &collection)
+        .with_num_ids(points_metadata_ex.values().map(PointRef::of));
+    let referenced = referenced_points(&res);
+    tracing::info!(
+        "Collection: {}, points in collection: {}, points referenced by final_classification.json: {}",
+        collection,
+        store.count().await?,
+        referenced.len()
+    );
+    let interlock = InterlockConfig {
+        sample_size: cli.interlock_sample,
+        min_present: cli.interlock_min_present,
+        min_matching: cli.interlock_min_matching,
+        seed: rand::random(),
+    };
+    let report = verify_sample(
+        &store,
+        &collection,
+        &referenced,
+        &points_metadata_ex,
+        &interlock,
+    )
+    .await?;
+    tracing::info!(
+        "Interlock sample (seed {}): {} of {} points present, {} not comparable, {} with other dimensions",
+        interlock.seed,
+        report.present(),
+        report.sampled,
+        report.not_comparable,
+        report.mismatched.len()
+    );
+    if !report.passed() {
+        let filename = format!(
+            "{}_interlock_{}.json",
+            cli.save_result_prefix,
+            chrono::Local::now().format("%Y%m%d_%H%M%S")
+        );
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report)?)
+        })?;
+        for failure in &report.failures {
+            tracing::error!("Interlock: {}", failure);
+        }
+        anyhow::ensure!(
+            cli.force,
+            "{} does not look like the collection final_classification.json was made from, \
+             mismatch report saved to {}, pass --force to run anyway",
+            collection,
+            &filename
+        );
+        tracing::warn!("Interlock failed, continuing because of --force");
+    }
+    if !cli.dry_run && !cli.yes {
+        confirm(&collection)?;
+    }
+    if let Some(path) = &cli.backup_snapshot {
+        match cli.dry_run {
+            true => tracing::info!(
+                "Dry run: would snapshot {} to {}",
+                collection,
+                path.display()
+            ),
+            false => {
+                GenShinQdrantClient::new()?
+                    .export_snapshot(&collection, path)
+                    .await?;
             }
-        })
-        .collect();
-    let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
-    let client = Arc::new(Stage11GenshinQdrantClient::new(
-        &collection_name,
-        cli.dry_run,
-        cli.worker_num,
-        &cli.url_prefix,
-    )?);
-    let res = client.set_reset_point_task(&all_tasks).await?;
-    if let Some(failed_tasks) = res {
+        }
+    }
+    let ledger = OpLedger::open(&cli.ledger, &collection)?;
+    if ledger.recovered_bytes() > 0 {
+        tracing::warn!(
+            "Dropped a torn last entry of {} bytes from ledger {}",
+            ledger.recovered_bytes(),
+            cli.ledger.display()
+        );
+    }
+    let scheduler = QdrantWriteScheduler::new(
+        store,
+        WriteSchedulerConfig {
+            batch_size: cli.batch_size,
+            concurrency: cli.worker_num,
+            max_retries: cli.max_retries,
+            max_requests_per_sec: cli.max_requests_per_sec,
+            dry_run: cli.dry_run,
+            stall: StallConfig::from(&cli.stall),
+            ..Default::default()
+        },
+    )
+    .with_ledger(ledger);
+    if let Some(path) = &cli.plan_report {
+        let plan = plan_tasks(
+            scheduler.store(),
+            &all_tasks,
+            &snapshot_payloads(&all_tasks, &points_metadata_ex),
+            cli.on_conflict,
+            cli.batch_size,
+        )
+        .await?;
+        atomic_write_with(path, |w| write_plan(w, &plan, cli.plan_format))?;
+        let summary = PlanSummary::of(&plan);
+        tracing::info!(
+            "Dry run plan: {} keeps ({} changed), {} deletes, {} points already gone, {} \
+             conflicts ({}), saved to {}",
+            summary.keeps,
+            summary.changed,
+            summary.deletes,
+            summary.missing,
+            summary.conflicts,
+            cli.on_conflict,
+            path.display()
+        );
+    }
+    let stall = StallConfig::from(&cli.stall);
+    let (tasks, mut failed_tasks, archive_stalled) = match &cli.archive_payloads {
+        Some(prefix) if cli.dry_run => {
+            tracing::info!("Dry run: would archive discards to {}", prefix);
+            (all_tasks, Vec::new(), None)
+        }
+        Some(prefix) => {
+            let archive = PayloadArchive::new(GenShinOperator::new()?, prefix, cli.archive_vectors);
+            let outcome = archive
+                .archive_tasks(
+                    scheduler.store(),
+                    &all_tasks,
+                    cli.archive_concurrency,
+                    &stall,
+                )
+                .await;
+            tracing::info!(
+                "Archived {} points, {} bytes to {}, {} tasks failed to archive",
+                outcome.summary.points,
+                outcome.summary.bytes,
+                prefix,
+                outcome.failed.len()
+            );
+            (outcome.ready, outcome.failed, outcome.stalled)
+        }
+        None => (all_tasks, Vec::new(), None),
+    };
+    let mut check = PayloadCheck {
+        policy: cli.on_conflict,
+        batch_size: cli.batch_size,
+        snapshot: snapshot_payloads(&tasks, &points_metadata_ex),
+        conflicts: Vec::new(),
+    };
+    let (write_failed, stalled) = run_in_waves(&scheduler, &tasks, &mut check).await?;
+    failed_tasks.extend(write_failed);
+    if !check.conflicts.is_empty() {
+        let filename = format!(
+            "{}_payload_conflicts_{}.json",
+            cli.save_result_prefix,
+            chrono::Local::now().format("%Y%m%d_%H%M%S")
+        );
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &check.conflicts)?)
+        })?;
+        let count = |action| {
+            check
+                .conflicts
+                .iter()
+                .filter(|c| c.conflict.action == action)
+                .count()
+        };
+        tracing::warn!(
+            "{} kept points changed since points_map.bin ({}): {} merged, {} skipped, {} \
+             overwritten, {} gone, report saved to {}",
+            check.conflicts.len(),
+            cli.on_conflict,
+            count(ConflictAction::Merged),
+            count(ConflictAction::Skipped),
+            count(ConflictAction::Overwritten),
+            count(ConflictAction::Missing),
+            &filename
+        );
+    }
+    let stalled = archive_stalled.or(stalled);
+    let mut shadow_differs = None;
+    if let Some(expected) = &expected {
+        if cli.dry_run {
+            tracing::warn!("Dry run, every expected change shows up in the shadow report");
+        }
+        let report = compare_shadow(scheduler.store(), expected, cli.batch_size).await?;
+        let filename = match &cli.shadow_report {
+            Some(path) => path.display().to_string(),
+            None => format!(
+                "{}_shadow_report_{}.json",
+                cli.save_result_prefix,
+                chrono::Local::now().format("%Y%m%d_%H%M%S")
+            ),
+        };
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report)?)
+        })?;
+        let summary = format!(
+            "{} points present, {} deleted, {} unexpectedly present, {} unexpectedly missing, \
+             {} payload diffs, report saved to {}",
+            report.present.len(),
+            report.deleted,
+            report.unexpectedly_present.len(),
+            report.unexpectedly_missing.len(),
+            report.payload_diffs.len(),
+            &filename
+        );
+        match report.matches() {
+            true => tracing::info!("Shadow collection as expected: {}", summary),
+            false => {
+                tracing::error!("Shadow collection differs: {}", summary);
+                shadow_differs = Some(filename);
+            }
+        }
+    }
+    if !failed_tasks.is_empty() {
         let filename = format!(
             "{}_{}.json",
             cli.save_result_prefix,
             chrono::Local::now().format("%Y%m%d_%H%M%S")
         );
-        let failed_file = File::create(&filename)?;
-        serde_json::to_writer_pretty(failed_file, &failed_tasks)?;
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &failed_tasks)?)
+        })?;
         tracing::error!(
             "Some tasks failed, details saved to {}. Total failed tasks: {}",
             &filename,
             failed_tasks.len()
         );
-    } else {
+    } else if stalled.is_none() {
         tracing::info!("All tasks completed successfully.");
     }
+    shared::metrics::dump_prometheus_to(&cli.metrics_file)?;
+    tracing::info!("Metrics saved to {}", cli.metrics_file.display());
+    if let Some(e) = stalled {
+        tracing::error!("Run aborted, the saved failures are partial");
+        return Err(e.into());
+    }
+    if let Some(filename) = shadow_differs {
+        anyhow::bail!(
+            "Shadow collection differs from what is expected, see {}",
+            filename
+        );
+    }
     Ok(())
 }
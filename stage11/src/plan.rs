@@ -0,0 +1,345 @@
+//! What a run would do to every point it touches, written by `--dry-run` for review
+
+use crate::task::ReSetPointTask;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use shared::qdrant::{
+    ConflictAction, GuardDecision, OnConflict, PointStore, decide_write, fetch_payloads,
+};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use uuid::Uuid;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum PlanFormat {
+    /// One array of every planned point
+    #[default]
+    Json,
+    /// One row per planned point, payloads as JSON in their cells
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    /// Kept, its categories overwritten
+    Keep,
+    Delete,
+}
+
+impl PlanAction {
+    #[inline]
+    fn as_str(self) -> &'static str {
+        match self {
+            PlanAction::Keep => "keep",
+            PlanAction::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedPoint {
+    pub id: Uuid,
+    /// Position of the final_classification.json entry of the task touching it
+    pub entry: usize,
+    pub action: PlanAction,
+    /// `None` when the point is gone from the collection
+    pub current: Option<Map<String, Value>>,
+    /// Payload after the run, `None` when the point is deleted or left alone because it is gone
+    pub proposed: Option<Map<String, Value>>,
+    /// Fields of a kept point the run changes
+    pub changed: Vec<String>,
+    /// What `--on-conflict` makes of a kept point changed since points_map.bin
+    pub conflict: Option<ConflictAction>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PlanSummary {
+    pub keeps: usize,
+    /// Kept points whose payload the run changes
+    pub changed: usize,
+    pub deletes: usize,
+    /// Points already gone from the collection
+    pub missing: usize,
+    pub conflicts: usize,
+}
+
+impl PlanSummary {
+    pub fn of(plan: &[PlannedPoint]) -> Self {
+        let mut summary = Self::default();
+        for point in plan {
+            match point.action {
+                PlanAction::Keep => summary.keeps += 1,
+                PlanAction::Delete => summary.deletes += 1,
+            }
+            summary.changed +=
+                (point.action == PlanAction::Keep && !point.changed.is_empty()) as usize;
+            summary.missing += point.current.is_none() as usize;
+            summary.conflicts += point.conflict.is_some() as usize;
+        }
+        summary
+    }
+}
+
+/// `current` with `written` merged in, as Qdrant's set payload does, and the fields that changed
+fn merged(
+    current: &Map<String, Value>,
+    written: &Map<String, Value>,
+) -> (Map<String, Value>, Vec<String>) {
+    let changed = written
+        .iter()
+        .filter(|(field, value)| current.get(*field) != Some(*value))
+        .map(|(field, _)| field.clone())
+        .collect();
+    let mut proposed = current.clone();
+    proposed.extend(written.clone());
+    (proposed, changed)
+}
+
+/// Fetches the current payload of every point `tasks` touch, `batch_size` at a time, and plans
+/// the writes the way a real run would guard them against `snapshot`, see
+/// [`guard_writes`](shared::qdrant::guard_writes)
+///
+/// One row per point and task, kept points first within a task.
+pub async fn plan_tasks<S: PointStore>(
+    store: &S,
+    tasks: &[ReSetPointTask<'_>],
+    snapshot: &HashMap<Uuid, Map<String, Value>>,
+    policy: OnConflict,
+    batch_size: usize,
+) -> anyhow::Result<Vec<PlannedPoint>> {
+    let ids: Vec<Uuid> = tasks
+        .iter()
+        .flat_map(|task| task.keep_point_list.iter().chain(&task.discard_point_list))
+        .map(|id| **id)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let current = fetch_payloads(store, &ids, batch_size).await?;
+    let empty = Map::new();
+    let mut plan = Vec::with_capacity(ids.len());
+    for task in tasks {
+        for (id, tags) in task
+            .keep_point_list
+            .iter()
+            .zip(task.transfer_tag_list.iter())
+        {
+            let intended = Map::from_iter([("categories".to_owned(), Value::from(tags.clone()))]);
+            let before = snapshot.get(*id).unwrap_or(&empty);
+            let now = current.get(*id);
+            let (written, conflict) = match decide_write(&intended, before, now, policy) {
+                GuardDecision::Write(payload) => (Some(payload), None),
+                GuardDecision::Resolved { payload, .. } => {
+                    let action = match policy {
+                        OnConflict::Overwrite => ConflictAction::Overwritten,
+                        _ => ConflictAction::Merged,
+                    };
+                    (Some(payload), Some(action))
+                }
+                GuardDecision::Skip(_) => (None, Some(ConflictAction::Skipped)),
+                GuardDecision::Missing => (None, Some(ConflictAction::Missing)),
+            };
+            let (proposed, changed) = match (now, written) {
+                (Some(now), Some(written)) => {
+                    let (proposed, changed) = merged(now, &written);
+                    (Some(proposed), changed)
+                }
+                (Some(now), None) => (Some(now.clone()), Vec::new()),
+                // overwriting a gone point recreates nothing, set payload ignores it
+                (None, _) => (None, Vec::new()),
+            };
+            plan.push(PlannedPoint {
+                id: **id,
+                entry: task.entry,
+                action: PlanAction::Keep,
+                current: now.cloned(),
+                proposed,
+                changed,
+                conflict,
+            });
+        }
+        for id in task.discard_point_list.iter() {
+            let now = current.get(*id);
+            plan.push(PlannedPoint {
+                id: **id,
+                entry: task.entry,
+                action: PlanAction::Delete,
+                current: now.cloned(),
+                proposed: None,
+                changed: Vec::new(),
+                conflict: None,
+            });
+        }
+    }
+    Ok(plan)
+}
+
+/// Quotes `field` if it holds a separator, a quote or a line break
+fn csv_field(field: &str) -> Cow<'_, str> {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => Cow::Owned(format!("\"{}\"", field.replace('"', "\"\""))),
+        false => Cow::Borrowed(field),
+    }
+}
+
+/// Writes `plan` as `format`
+pub fn write_plan<W: Write>(
+    mut w: W,
+    plan: &[PlannedPoint],
+    format: PlanFormat,
+) -> anyhow::Result<()> {
+    match format {
+        PlanFormat::Json => serde_json::to_writer_pretty(&mut w, plan)?,
+        PlanFormat::Csv => write_csv(&mut w, plan)?,
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn write_csv<W: Write>(w: &mut W, plan: &[PlannedPoint]) -> io::Result<()> {
+    let payload = |p: &Option<Map<String, Value>>| {
+        p.as_ref()
+            .map(|p| Value::Object(p.clone()).to_string())
+            .unwrap_or_default()
+    };
+    writeln!(w, "id,entry,action,conflict,changed,current,proposed")?;
+    for point in plan {
+        let conflict = match point.conflict {
+            Some(ConflictAction::Merged) => "merged",
+            Some(ConflictAction::Skipped) => "skipped",
+            Some(ConflictAction::Overwritten) => "overwritten",
+            Some(ConflictAction::Missing) => "missing",
+            None => "",
+        };
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{}",
+            point.id,
+            point.entry,
+            point.action.as_str(),
+            conflict,
+            csv_field(&point.changed.join(";")),
+            csv_field(&payload(&point.current)),
+            csv_field(&payload(&point.proposed)),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use shared::test_util::MemoryPointStore;
+
+    fn payload(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn test_plan_tasks() {
+        let id: Vec<Uuid> = (0..5).map(Uuid::from_u128).collect();
+        let store = MemoryPointStore::with_payloads(HashMap::from([
+            (id[0], payload(json!({"categories": ["a"], "size": 1}))),
+            // changed since the snapshot
+            (id[1], payload(json!({"categories": ["x"]}))),
+            (id[2], payload(json!({"categories": ["b"]}))),
+        ]));
+        let snapshot = HashMap::from([
+            (id[0], payload(json!({"categories": ["a"]}))),
+            (id[1], payload(json!({"categories": ["c"]}))),
+            (id[4], payload(json!({"categories": ["d"]}))),
+        ]);
+        let tasks = vec![
+            ReSetPointTask {
+                entry: 0,
+                keep_point_list: vec![&id[0]],
+                discard_point_list: vec![&id[2], &id[3]],
+                transfer_tag_list: vec![vec!["a", "b"]],
+            },
+            ReSetPointTask {
+                entry: 3,
+                keep_point_list: vec![&id[1], &id[4]],
+                discard_point_list: vec![],
+                transfer_tag_list: vec![vec!["c"], vec!["d"]],
+            },
+        ];
+        let plan = plan_tasks(&store, &tasks, &snapshot, OnConflict::Skip, 2)
+            .await
+            .unwrap();
+        let summary: Vec<_> = plan
+            .iter()
+            .map(|p| (p.id, p.entry, p.action, p.changed.clone(), p.conflict))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    id[0],
+                    0,
+                    PlanAction::Keep,
+                    vec!["categories".to_owned()],
+                    None
+                ),
+                (id[2], 0, PlanAction::Delete, vec![], None),
+                (id[3], 0, PlanAction::Delete, vec![], None),
+                (
+                    id[1],
+                    3,
+                    PlanAction::Keep,
+                    vec![],
+                    Some(ConflictAction::Skipped)
+                ),
+                (
+                    id[4],
+                    3,
+                    PlanAction::Keep,
+                    vec![],
+                    Some(ConflictAction::Missing)
+                ),
+            ]
+        );
+        assert_eq!(
+            plan[0].proposed,
+            Some(payload(json!({"categories": ["a", "b"], "size": 1})))
+        );
+        assert_eq!(plan[3].proposed, plan[3].current);
+        assert_eq!((plan[3].current.is_some(), plan[4].current), (true, None));
+        assert_eq!(
+            PlanSummary::of(&plan),
+            PlanSummary {
+                keeps: 3,
+                changed: 1,
+                deletes: 2,
+                missing: 2,
+                conflicts: 2,
+            }
+        );
+
+        let mut csv = Vec::new();
+        write_plan(&mut csv, &plan[..2], PlanFormat::Csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "id,entry,action,conflict,changed,current,proposed"
+        );
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},0,keep,,categories,\"{{\"\"categories\"\":[\"\"a\"\"],\"\"size\"\":1}}\",\
+                 \"{{\"\"categories\"\":[\"\"a\"\",\"\"b\"\"],\"\"size\"\":1}}\"",
+                id[0]
+            )
+        );
+        assert_eq!(
+            lines[2],
+            format!(
+                "{},0,delete,,,\"{{\"\"categories\"\":[\"\"b\"\"]}}\",",
+                id[2]
+            )
+        );
+    }
+}
@@ -0,0 +1,21 @@
+use shared::qdrant::PointRecord;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Points copied into a shadow collection, the JSON lines export `shadow-collection` writes
+pub fn read_baseline(path: impl AsRef<Path>) -> anyhow::Result<Vec<PointRecord>> {
+    let mut records = Vec::new();
+    for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(
+            serde_json::from_str(&line).map_err(|e| {
+                anyhow::anyhow!("Invalid baseline record at line {}: {}", idx + 1, e)
+            })?,
+        );
+    }
+    Ok(records)
+}
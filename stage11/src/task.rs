@@ -0,0 +1,455 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use shared::qdrant::{WriteOp, WriteOutcome, WriteStatus};
+use shared::structure::{FinalClassification, NekoPoint};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReSetPointTask<'a> {
+    /// Position of the final_classification.json entry the task was built from
+    pub entry: usize,
+    pub keep_point_list: Vec<&'a Uuid>,
+    pub discard_point_list: Vec<&'a Uuid>,
+    pub transfer_tag_list: Vec<Vec<&'a str>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    WriteFailed,
+    /// The run aborted before the task's writes finished
+    NotAttempted,
+    /// The discards could not be archived, nothing of the task was written
+    ArchiveFailed,
+    /// The current payload of its kept points could not be read, nothing of the task was written
+    PayloadCheckFailed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailedReSetPointTask<'a> {
+    #[serde(flatten)]
+    pub task: ReSetPointTask<'a>,
+    pub reason: FailureReason,
+    pub error: String,
+}
+
+/// A FinalClassification entry whose kept points and tag lists do not line up
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct InvalidTaskEntry {
+    pub index: usize,
+    pub keep_count: usize,
+    pub tag_list_count: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TaskBuildReport {
+    pub skipped_empty: usize,
+    pub invalid: Vec<InvalidTaskEntry>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TaskStats {
+    pub tasks: usize,
+    pub total_keeps: usize,
+    pub total_discards: usize,
+    /// tag count of a kept point -> number of kept points
+    pub tag_cardinality: BTreeMap<usize, usize>,
+}
+
+impl TaskStats {
+    pub fn collect(tasks: &[ReSetPointTask<'_>]) -> Self {
+        let mut stats = Self {
+            tasks: tasks.len(),
+            ..Default::default()
+        };
+        for task in tasks {
+            stats.total_keeps += task.keep_point_list.len();
+            stats.total_discards += task.discard_point_list.len();
+            for tags in task.transfer_tag_list.iter() {
+                *stats.tag_cardinality.entry(tags.len()).or_default() += 1;
+            }
+        }
+        stats
+    }
+}
+
+fn into_keep_tags<'a>(
+    uuid: &'a Uuid,
+    tags_sets: &mut Vec<HashSet<&'a str>>,
+    metadata: &'a HashMap<Uuid, NekoPoint>,
+) {
+    if let Some(categories) = metadata.get(uuid).and_then(|p| p.categories.as_ref()) {
+        let mut tags = HashSet::with_capacity(categories.len());
+        categories.iter().for_each(|tag| {
+            tags.insert(tag.as_str());
+        });
+        tags_sets.push(tags);
+    }
+}
+
+fn into_duplicate_tags<'a>(
+    uuid: &'a Uuid,
+    tags_set: &mut HashSet<&'a str>,
+    metadata: &'a HashMap<Uuid, NekoPoint>,
+) {
+    if let Some(categories) = metadata.get(uuid).and_then(|p| p.categories.as_ref()) {
+        categories.iter().for_each(|tag| {
+            tags_set.insert(tag.as_str());
+        });
+    }
+}
+
+/// Builds one task per entry, skipping no-op entries and reporting malformed ones
+pub fn build_tasks<'a>(
+    res: &'a [FinalClassification],
+    points_metadata: &'a HashMap<Uuid, NekoPoint>,
+) -> (Vec<ReSetPointTask<'a>>, TaskBuildReport) {
+    let mut report = TaskBuildReport::default();
+    let mut tasks = Vec::with_capacity(res.len());
+    for (index, item) in res.iter().enumerate() {
+        let mut keep_point_list = Vec::new();
+        let mut discard_point_list = Vec::new();
+        let mut keep_point_tags_set_list = Vec::new();
+        let mut discard_point_tags_set = HashSet::new();
+        if let Some(uuids) = item.kept_text_anomalies_group.as_ref() {
+            keep_point_list.extend(uuids);
+            uuids.iter().for_each(|uuid| {
+                into_keep_tags(uuid, &mut keep_point_tags_set_list, points_metadata)
+            });
+        }
+        if let Some(uuids) = item.triaged_gif_and_invalid_group.as_ref() {
+            discard_point_list.extend(uuids.0.iter());
+            uuids.0.iter().for_each(|uuid| {
+                into_duplicate_tags(uuid, &mut discard_point_tags_set, points_metadata);
+            });
+        }
+        if let Some(uuids) = item.triaged_gif_and_discard_same_frame_group.as_ref() {
+            discard_point_list.extend(uuids.iter());
+            uuids.iter().for_each(|uuid| {
+                into_duplicate_tags(uuid, &mut discard_point_tags_set, points_metadata);
+            });
+        }
+        if let Some(uuids) = item.triaged_gif_and_then_will_keep_group.as_ref() {
+            keep_point_list.extend(uuids.iter());
+            uuids.iter().for_each(|uuid| {
+                into_keep_tags(uuid, &mut keep_point_tags_set_list, points_metadata);
+            });
+        }
+        if let Some(uuids) = item.triaged_gif_and_then_will_delete_group.as_ref() {
+            discard_point_list.extend(uuids.iter());
+            uuids.iter().for_each(|uuid| {
+                into_duplicate_tags(uuid, &mut discard_point_tags_set, points_metadata);
+            });
+        }
+        if let Some(uuids) = item.reviewed_keep_group.as_ref() {
+            keep_point_list.extend(uuids.iter());
+            uuids.iter().for_each(|uuid| {
+                into_keep_tags(uuid, &mut keep_point_tags_set_list, points_metadata);
+            });
+        }
+        if let Some(uuid) = item.kept_non_gif.as_ref() {
+            keep_point_list.push(uuid);
+            into_keep_tags(uuid, &mut keep_point_tags_set_list, points_metadata);
+        }
+        if let Some(uuids) = item.other_need_delete_group.as_ref() {
+            discard_point_list.extend(uuids.iter());
+            uuids.iter().for_each(|uuid| {
+                into_duplicate_tags(uuid, &mut discard_point_tags_set, points_metadata);
+            });
+        }
+        if keep_point_list.is_empty() && discard_point_list.is_empty() {
+            report.skipped_empty += 1;
+            continue;
+        }
+        let transfer_tag_list: Vec<Vec<&str>> = keep_point_tags_set_list
+            .into_iter()
+            .map(|mut km| {
+                km.extend(discard_point_tags_set.iter());
+                km.into_iter().collect::<Vec<&str>>()
+            })
+            .collect::<Vec<Vec<&str>>>();
+        if transfer_tag_list.len() != keep_point_list.len() {
+            tracing::warn!(
+                "Entry {} has {} kept points but {} tag lists, skipped",
+                index,
+                keep_point_list.len(),
+                transfer_tag_list.len()
+            );
+            report.invalid.push(InvalidTaskEntry {
+                index,
+                keep_count: keep_point_list.len(),
+                tag_list_count: transfer_tag_list.len(),
+            });
+            continue;
+        }
+        tasks.push(ReSetPointTask {
+            entry: index,
+            keep_point_list,
+            discard_point_list,
+            transfer_tag_list,
+        });
+    }
+    (tasks, report)
+}
+
+/// Scheduler operations for `tasks`, with the index of the task every operation belongs to
+///
+/// Each kept point gets its transferred tags as `categories`, the discards of a task are deleted
+/// in one operation.
+pub fn write_ops(tasks: &[ReSetPointTask<'_>]) -> (Vec<WriteOp>, Vec<usize>) {
+    let mut ops = Vec::new();
+    let mut owners = Vec::new();
+    for (idx, task) in tasks.iter().enumerate() {
+        for (keep, tags) in task
+            .keep_point_list
+            .iter()
+            .zip(task.transfer_tag_list.iter())
+        {
+            let payload = Map::from_iter([("categories".to_owned(), Value::from(tags.clone()))]);
+            ops.push(WriteOp::SetPayload {
+                points: vec![**keep],
+                payload,
+            });
+            owners.push(idx);
+        }
+        if !task.discard_point_list.is_empty() {
+            ops.push(WriteOp::DeletePoints {
+                points: task.discard_point_list.iter().map(|id| **id).collect(),
+            });
+            owners.push(idx);
+        }
+    }
+    (ops, owners)
+}
+
+/// Payload fields [`write_ops`] overwrites as points_map.bin has them, for every kept point
+pub fn snapshot_payloads(
+    tasks: &[ReSetPointTask<'_>],
+    metadata: &HashMap<Uuid, NekoPoint>,
+) -> HashMap<Uuid, Map<String, Value>> {
+    tasks
+        .iter()
+        .flat_map(|task| task.keep_point_list.iter())
+        .map(|id| {
+            let categories = metadata
+                .get(id)
+                .and_then(|p| p.categories.clone())
+                .map_or(Value::Null, Value::from);
+            (
+                **id,
+                Map::from_iter([("categories".to_owned(), categories)]),
+            )
+        })
+        .collect()
+}
+
+/// One entry per failed or, after a stall abort, unfinished operation
+pub fn failed_tasks<'a>(
+    tasks: &[ReSetPointTask<'a>],
+    owners: &[usize],
+    outcomes: Vec<WriteOutcome>,
+) -> Vec<FailedReSetPointTask<'a>> {
+    outcomes
+        .into_iter()
+        .filter_map(|outcome| {
+            let (reason, error) = match outcome.status {
+                WriteStatus::Failed { error, .. } => (FailureReason::WriteFailed, error),
+                WriteStatus::NotAttempted => (
+                    FailureReason::NotAttempted,
+                    "not attempted, run aborted".to_owned(),
+                ),
+                WriteStatus::Written | WriteStatus::DryRun => return None,
+            };
+            Some(FailedReSetPointTask {
+                task: tasks[owners[outcome.op]].clone(),
+                reason,
+                error,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(id: u128, tags: Option<&[&str]>) -> (Uuid, NekoPoint) {
+        let id = Uuid::from_u128(id);
+        let pt = NekoPoint {
+            id,
+            height: 1,
+            width: 1,
+            size: None,
+            categories: tags.map(|t| t.iter().map(|s| s.to_string()).collect()),
+            text_info: None,
+            qdrant_num_id: None,
+            extra: None,
+        };
+        (id, pt)
+    }
+
+    #[test]
+    fn test_build_tasks() {
+        let metadata: HashMap<Uuid, NekoPoint> = HashMap::from([
+            point(1, Some(&["a"])),
+            point(2, Some(&["b", "c"])),
+            point(3, Some(&["a", "d"])),
+            point(4, None),
+        ]);
+        let fixtures = vec![
+            // empty
+            FinalClassification::default(),
+            // keep 1, discard 2 and 3
+            FinalClassification {
+                kept_non_gif: Some(Uuid::from_u128(1)),
+                other_need_delete_group: Some(vec![Uuid::from_u128(2)]),
+                triaged_gif_and_invalid_group: Some((
+                    vec![Uuid::from_u128(3)],
+                    vec!["broken".to_string()],
+                )),
+                ..Default::default()
+            },
+            // malformed: kept point without categories
+            FinalClassification {
+                kept_non_gif: Some(Uuid::from_u128(4)),
+                other_need_delete_group: Some(vec![Uuid::from_u128(2)]),
+                ..Default::default()
+            },
+            // empty groups are still no-ops
+            FinalClassification {
+                other_need_delete_group: Some(vec![]),
+                ..Default::default()
+            },
+            // discard only
+            FinalClassification {
+                triaged_gif_and_discard_same_frame_group: Some(vec![Uuid::from_u128(3)]),
+                ..Default::default()
+            },
+        ];
+        let (tasks, report) = build_tasks(&fixtures, &metadata);
+        assert_eq!(tasks.len(), 2);
+        assert_eq!((tasks[0].entry, tasks[1].entry), (1, 4));
+        assert_eq!(report.skipped_empty, 2);
+        assert_eq!(
+            report.invalid,
+            vec![InvalidTaskEntry {
+                index: 2,
+                keep_count: 1,
+                tag_list_count: 0,
+            }]
+        );
+        let mut tags = tasks[0].transfer_tag_list[0].clone();
+        tags.sort_unstable();
+        assert_eq!(tags, vec!["a", "b", "c", "d"]);
+        assert_eq!(tasks[0].discard_point_list.len(), 2);
+        assert!(tasks[1].keep_point_list.is_empty());
+
+        let stats = TaskStats::collect(&tasks);
+        assert_eq!(
+            stats,
+            TaskStats {
+                tasks: 2,
+                total_keeps: 1,
+                total_discards: 3,
+                tag_cardinality: BTreeMap::from([(4, 1)]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_write_ops() {
+        let metadata: HashMap<Uuid, NekoPoint> = [
+            point(1, Some(&["a"])),
+            point(2, Some(&["b"])),
+            point(3, Some(&["c"])),
+            point(4, Some(&["d"])),
+        ]
+        .into();
+        let res = vec![
+            FinalClassification {
+                kept_text_anomalies_group: Some(vec![Uuid::from_u128(1)]),
+                other_need_delete_group: Some(vec![Uuid::from_u128(2), Uuid::from_u128(3)]),
+                ..Default::default()
+            },
+            FinalClassification {
+                kept_non_gif: Some(Uuid::from_u128(4)),
+                ..Default::default()
+            },
+        ];
+        let (tasks, _) = build_tasks(&res, &metadata);
+        let (ops, owners) = write_ops(&tasks);
+        assert_eq!(owners, vec![0, 0, 1]);
+        match &ops[0] {
+            WriteOp::SetPayload { points, payload } => {
+                assert_eq!(points, &vec![Uuid::from_u128(1)]);
+                let mut tags: Vec<&str> = payload["categories"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|t| t.as_str().unwrap())
+                    .collect();
+                tags.sort();
+                assert_eq!(tags, vec!["a", "b", "c"]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            ops[1],
+            WriteOp::DeletePoints {
+                points: vec![Uuid::from_u128(2), Uuid::from_u128(3)]
+            }
+        );
+        assert_eq!(ops[2].points(), &[Uuid::from_u128(4)]);
+        let snapshot = snapshot_payloads(&tasks, &metadata);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(
+            snapshot[&Uuid::from_u128(4)]["categories"],
+            Value::from(vec!["d"])
+        );
+
+        let outcomes = vec![
+            WriteOutcome {
+                op: 0,
+                status: WriteStatus::Written,
+                attempts: 1,
+                skipped: 0,
+            },
+            WriteOutcome {
+                op: 1,
+                status: WriteStatus::Failed {
+                    error: "rejected".to_owned(),
+                    points: vec![Uuid::from_u128(3)],
+                },
+                attempts: 4,
+                skipped: 0,
+            },
+            WriteOutcome {
+                op: 2,
+                status: WriteStatus::NotAttempted,
+                attempts: 0,
+                skipped: 0,
+            },
+        ];
+        let failed = failed_tasks(&tasks, &owners, outcomes);
+        let summary: Vec<(Vec<&Uuid>, FailureReason, &str)> = failed
+            .iter()
+            .map(|f| (f.task.keep_point_list.clone(), f.reason, f.error.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    vec![&Uuid::from_u128(1)],
+                    FailureReason::WriteFailed,
+                    "rejected"
+                ),
+                (
+                    vec![&Uuid::from_u128(4)],
+                    FailureReason::NotAttempted,
+                    "not attempted, run aborted"
+                )
+            ]
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! Partitions tasks into waves run one after the other, no two tasks of a wave sharing a point
+//!
+//! A coloring of the conflict graph, tasks being the nodes and every group of tasks sharing a
+//! point a clique. A task goes to the wave after the latest wave of the earlier tasks it
+//! conflicts with, so conflicting tasks run in their order in the task list and the waves end
+//! up with what running every task on its own, in order, would leave.
+
+/// Wave of every task, `groups` being the tasks sharing a point, e.g.
+/// [`ConflictReport::groups`](crate::conflict::ConflictReport::groups)
+pub fn assign_waves(task_count: usize, groups: &[Vec<usize>]) -> Vec<usize> {
+    let mut memberships = vec![Vec::new(); task_count];
+    for (group, tasks) in groups.iter().enumerate() {
+        for &task in tasks {
+            memberships[task].push(group);
+        }
+    }
+    // wave of the latest task of every group assigned so far
+    let mut latest: Vec<Option<usize>> = vec![None; groups.len()];
+    let mut waves = Vec::with_capacity(task_count);
+    for groups in memberships {
+        let wave = groups
+            .iter()
+            .filter_map(|&g| latest[g].map(|w| w + 1))
+            .max()
+            .unwrap_or(0);
+        for g in groups {
+            latest[g] = Some(latest[g].map_or(wave, |w| w.max(wave)));
+        }
+        waves.push(wave);
+    }
+    waves
+}
+
+/// Task indices of every wave, in order, each wave listing its tasks in their order
+pub fn partition_waves(task_count: usize, groups: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let waves = assign_waves(task_count, groups);
+    let mut partition = vec![Vec::new(); waves.iter().max().map_or(0, |w| w + 1)];
+    for (task, wave) in waves.into_iter().enumerate() {
+        partition[wave].push(task);
+    }
+    partition
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// No wave holds two tasks of a group, and the tasks of a group run in their order
+    fn check(task_count: usize, groups: &[Vec<usize>]) -> Vec<usize> {
+        let waves = assign_waves(task_count, groups);
+        for group in groups {
+            for pair in group.windows(2) {
+                assert!(waves[pair[0]] < waves[pair[1]], "{:?} in {:?}", pair, waves);
+            }
+        }
+        waves
+    }
+
+    #[test]
+    fn test_no_conflicts() {
+        assert_eq!(partition_waves(3, &[]), vec![vec![0, 1, 2]]);
+        assert!(partition_waves(0, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_independent_pairs_share_waves() {
+        let groups = vec![vec![0, 1], vec![2, 3], vec![4, 5]];
+        assert_eq!(check(6, &groups), vec![0, 1, 0, 1, 0, 1]);
+        assert_eq!(
+            partition_waves(6, &groups),
+            vec![vec![0, 2, 4], vec![1, 3, 5]]
+        );
+    }
+
+    #[test]
+    fn test_chain() {
+        // 0-1, 1-2, 2-3: only neighbours conflict, the order still holds along the chain
+        let groups = vec![vec![0, 1], vec![1, 2], vec![2, 3]];
+        assert_eq!(check(4, &groups), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_clique_and_bystanders() {
+        // 1, 3 and 4 share one point, 0 and 2 touch nothing
+        assert_eq!(check(5, &[vec![1, 3, 4]]), vec![0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_later_task_waits_for_latest_conflict() {
+        // 2 conflicts with 0 and 1, which are a wave apart
+        let groups = vec![vec![0, 1], vec![0, 2], vec![1, 2]];
+        assert_eq!(check(3, &groups), vec![0, 1, 2]);
+        let groups = vec![vec![0, 1], vec![2, 3], vec![1, 4], vec![3, 4]];
+        assert_eq!(
+            partition_waves(5, &groups),
+            vec![vec![0, 2], vec![1, 3], vec![4]]
+        );
+    }
+}
@@ -1,36 +1,17 @@
 use chrono::Local;
 use ndarray::Array2;
 use pacmap::fit_transform;
+use shared::logging::Logging;
 use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
+use std::fs;
 use std::io::Write;
-use std::{env, fs};
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::prelude::*;
-use tracing_subscriber::{EnvFilter, Layer};
 
 fn main() -> anyhow::Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new(
-        env::var("STDOUT_LOG_LEVEL").unwrap_or_else(|_| "debug".to_string()),
-    ));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage12.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new(
-            env::var("FILE_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-        ));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let _logging = Logging::new("stage12").stdout_level("debug").init()?;
     let points: PointExplorer<f32, 768> = PointExplorerBuilder::new()
         .path("qdrant_point_explorer_250611.pkl")
         .build()?;
-    let n = points.len();
-    let mut points_vec = Vec::with_capacity(n * 768);
-    for (_, vector) in points.iter() {
-        points_vec.extend_from_slice(vector);
-    }
-    let arr2: Array2<f32> = Array2::from_shape_vec((n, 768), points_vec)?;
+    let arr2: Array2<f32> = points.to_array2();
     tracing::info!(
         "Loaded {} points with shape {:?}",
         points.len(),
@@ -0,0 +1,169 @@
+mod metrics;
+mod strategy;
+
+use crate::metrics::{Evaluation, MergeExample, evaluate};
+use crate::strategy::{Strategy, StrategyParams};
+use clap::Parser;
+use serde::Serialize;
+use shared::atomic_write::atomic_write_with;
+use shared::config::Thresholds;
+use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "cluster-eval",
+    version,
+    about = "Score a clustering strategy against manually labeled duplicate groups"
+)]
+struct Cli {
+    /// JSON array of uuid groups, each group is one true cluster
+    #[arg(long)]
+    ground_truth: PathBuf,
+    #[arg(long, default_value = "qdrant_point_explorer_250611.pkl")]
+    point_explorer: String,
+    #[arg(long, value_enum)]
+    strategy: Strategy,
+    /// Cosine similarity threshold shared by every strategy, the configured `image_sim` when unset
+    #[arg(long)]
+    threshold: Option<f32>,
+    /// greedy: points clustered per chunk before merging, stage1 uses 20000
+    #[arg(long, default_value = "20000")]
+    chunk_size: usize,
+    /// optics: Euclidean radius on normalized vectors, defaults to the one matching --threshold
+    #[arg(long)]
+    eps: Option<f32>,
+    /// optics: neighbors needed for a core point
+    #[arg(long, default_value = "2")]
+    min_samples: usize,
+    /// hnsw-components: neighbors searched per point
+    #[arg(long, default_value = "10")]
+    k: usize,
+    /// hnsw-components: search breadth
+    #[arg(long, default_value = "64")]
+    ef: usize,
+    /// Over- and under-merge examples kept in the report
+    #[arg(long, default_value = "20")]
+    examples: usize,
+    #[arg(long, default_value = "cluster_eval")]
+    save_result_prefix: String,
+}
+
+#[derive(Serialize)]
+struct EvalReport {
+    strategy: String,
+    threshold: f32,
+    missing_points: Vec<Uuid>,
+    #[serde(flatten)]
+    evaluation: Evaluation,
+}
+
+fn print_examples(title: &str, examples: &[MergeExample], limit: usize) {
+    println!("\n{} ({} total)", title, examples.len());
+    for example in examples.iter().take(limit) {
+        let sizes: Vec<String> = example.pieces.iter().map(|p| p.len().to_string()).collect();
+        println!(
+            "  - {} wrong pairs, pieces of {} e.g. {}",
+            example.wrong_pairs,
+            sizes.join(" + "),
+            example
+                .pieces
+                .iter()
+                .map(|p| p[0].to_string())
+                .collect::<Vec<_>>()
+                .join(" / ")
+        );
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let threshold = match cli.threshold {
+        Some(threshold) => threshold,
+        None => Thresholds::init()?.image_sim,
+    };
+    let truth: Vec<Vec<Uuid>> = serde_json::from_slice(&fs::read(&cli.ground_truth)?)?;
+    let mut seen = HashSet::new();
+    for id in truth.iter().flatten() {
+        if !seen.insert(*id) {
+            anyhow::bail!("{} appears in more than one ground truth group", id);
+        }
+    }
+    let pe: PointExplorer<f32, 768> = PointExplorerBuilder::new()
+        .path(&cli.point_explorer)
+        .build()?;
+    let missing_points: Vec<Uuid> = truth
+        .iter()
+        .flatten()
+        .filter(|id| !pe.contains(id))
+        .copied()
+        .collect();
+    let truth: Vec<Vec<Uuid>> = truth
+        .into_iter()
+        .map(|group| group.into_iter().filter(|id| pe.contains(id)).collect())
+        .filter(|group: &Vec<Uuid>| !group.is_empty())
+        .collect();
+    // explorer order, the greedy strategy depends on it just like stage1
+    let mut ids: Vec<Uuid> = truth.iter().flatten().copied().collect();
+    ids.sort_by_key(|id| pe.uuid2index(id));
+    let vectors: Vec<&[f32]> = ids
+        .iter()
+        .map(|id| pe.get_vector(id).expect("checked above").as_slice())
+        .collect();
+    println!(
+        "Evaluating {:?} on {} points in {} groups, {} ground truth points not in the explorer",
+        cli.strategy,
+        ids.len(),
+        truth.len(),
+        missing_points.len()
+    );
+
+    let params = StrategyParams {
+        threshold,
+        chunk_size: cli.chunk_size,
+        eps: cli.eps,
+        min_samples: cli.min_samples,
+        k: cli.k,
+        ef: cli.ef,
+    };
+    let predicted = strategy::run(cli.strategy, &params, &ids, &vectors);
+    let mut evaluation = evaluate(&truth, &predicted);
+
+    println!("\n--- {:?} @ {} ---", cli.strategy, threshold);
+    println!(
+        "Clusters: {} predicted, {} true",
+        evaluation.predicted_clusters, evaluation.true_clusters
+    );
+    println!(
+        "Pairs: {} predicted, {} true, {} correct",
+        evaluation.predicted_pairs, evaluation.true_pairs, evaluation.correct_pairs
+    );
+    println!(
+        "Precision {:.4}  Recall {:.4}  F1 {:.4}  ARI {:.4}",
+        evaluation.precision, evaluation.recall, evaluation.f1, evaluation.adjusted_rand_index
+    );
+    print_examples("Over-merged clusters", &evaluation.over_merged, 5);
+    print_examples("Under-merged groups", &evaluation.under_merged, 5);
+
+    evaluation.over_merged.truncate(cli.examples);
+    evaluation.under_merged.truncate(cli.examples);
+    let report = EvalReport {
+        strategy: format!("{:?}", cli.strategy),
+        threshold,
+        missing_points,
+        evaluation,
+    };
+    let filename = format!(
+        "{}_{}.json",
+        cli.save_result_prefix,
+        chrono::Local::now().format("%Y%m%d_%H%M%S")
+    );
+    atomic_write_with(&filename, |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report)?)
+    })?;
+    println!("\nReport saved to {}", &filename);
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// A cluster on one side that spans several clusters on the other
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MergeExample {
+    /// The members split by the cluster they belong to on the other side, largest piece first
+    pub pieces: Vec<Vec<Uuid>>,
+    /// Pairs inside the cluster whose members sit in different pieces
+    pub wrong_pairs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Evaluation {
+    pub points: usize,
+    pub true_clusters: usize,
+    pub predicted_clusters: usize,
+    pub true_pairs: u64,
+    pub predicted_pairs: u64,
+    pub correct_pairs: u64,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+    pub adjusted_rand_index: f64,
+    /// Predicted clusters joining several ground truth groups, worst first
+    pub over_merged: Vec<MergeExample>,
+    /// Ground truth groups split across several predicted clusters, worst first
+    pub under_merged: Vec<MergeExample>,
+}
+
+#[inline]
+fn pairs(n: u64) -> u64 {
+    n * n.saturating_sub(1) / 2
+}
+
+fn merge_example(pieces: &HashMap<usize, Vec<Uuid>>) -> Option<MergeExample> {
+    if pieces.len() < 2 {
+        return None;
+    }
+    let mut pieces: Vec<Vec<Uuid>> = pieces.values().cloned().collect();
+    for piece in pieces.iter_mut() {
+        piece.sort();
+    }
+    pieces.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    let total = pieces.iter().map(|p| p.len() as u64).sum();
+    let within: u64 = pieces.iter().map(|p| pairs(p.len() as u64)).sum();
+    Some(MergeExample {
+        pieces,
+        wrong_pairs: pairs(total) - within,
+    })
+}
+
+fn worst_first(mut examples: Vec<MergeExample>) -> Vec<MergeExample> {
+    examples.sort_by(|a, b| {
+        b.wrong_pairs
+            .cmp(&a.wrong_pairs)
+            .then_with(|| a.pieces.cmp(&b.pieces))
+    });
+    examples
+}
+
+/// Compares `predicted` against the disjoint ground truth groups
+///
+/// Only ground truth points are scored, predicted members outside it are ignored and ground truth
+/// points missing from `predicted` count as singletons. Precision and recall are taken over the
+/// pairs of points put in the same cluster, an empty side scores 1.
+pub fn evaluate(truth: &[Vec<Uuid>], predicted: &[Vec<Uuid>]) -> Evaluation {
+    let truth_label: HashMap<Uuid, usize> = truth
+        .iter()
+        .enumerate()
+        .flat_map(|(label, group)| group.iter().map(move |id| (*id, label)))
+        .collect();
+    let mut predicted_label: HashMap<Uuid, usize> = predicted
+        .iter()
+        .enumerate()
+        .flat_map(|(label, cluster)| cluster.iter().map(move |id| (*id, label)))
+        .filter(|(id, _)| truth_label.contains_key(id))
+        .collect();
+    let mut next_label = predicted.len();
+    for id in truth_label.keys() {
+        predicted_label.entry(*id).or_insert_with(|| {
+            next_label += 1;
+            next_label - 1
+        });
+    }
+
+    // contingency table, ground truth group -> predicted cluster -> members
+    let mut by_truth: HashMap<usize, HashMap<usize, Vec<Uuid>>> = HashMap::new();
+    let mut by_predicted: HashMap<usize, HashMap<usize, Vec<Uuid>>> = HashMap::new();
+    for (id, &t) in truth_label.iter() {
+        let p = predicted_label[id];
+        by_truth
+            .entry(t)
+            .or_default()
+            .entry(p)
+            .or_default()
+            .push(*id);
+        by_predicted
+            .entry(p)
+            .or_default()
+            .entry(t)
+            .or_default()
+            .push(*id);
+    }
+    let cluster_pairs = |table: &HashMap<usize, HashMap<usize, Vec<Uuid>>>| -> u64 {
+        table
+            .values()
+            .map(|row| pairs(row.values().map(|c| c.len() as u64).sum()))
+            .sum()
+    };
+    let true_pairs = cluster_pairs(&by_truth);
+    let predicted_pairs = cluster_pairs(&by_predicted);
+    let correct_pairs: u64 = by_truth
+        .values()
+        .flat_map(|row| row.values())
+        .map(|cell| pairs(cell.len() as u64))
+        .sum();
+
+    let ratio = |num: u64, den: u64| match den {
+        0 => 1.0,
+        _ => num as f64 / den as f64,
+    };
+    let precision = ratio(correct_pairs, predicted_pairs);
+    let recall = ratio(correct_pairs, true_pairs);
+    let f1 = if precision + recall > 0.0 {
+        2.0 * precision * recall / (precision + recall)
+    } else {
+        0.0
+    };
+
+    let points = truth_label.len();
+    let expected = true_pairs as f64 * predicted_pairs as f64 / pairs(points as u64).max(1) as f64;
+    let max_index = (true_pairs + predicted_pairs) as f64 / 2.0;
+    // both sides all singletons or one cluster each, the partitions agree
+    let adjusted_rand_index = if max_index > expected {
+        (correct_pairs as f64 - expected) / (max_index - expected)
+    } else {
+        1.0
+    };
+
+    Evaluation {
+        points,
+        true_clusters: by_truth.len(),
+        predicted_clusters: by_predicted.len(),
+        true_pairs,
+        predicted_pairs,
+        correct_pairs,
+        precision,
+        recall,
+        f1,
+        adjusted_rand_index,
+        over_merged: worst_first(by_predicted.values().filter_map(merge_example).collect()),
+        under_merged: worst_first(by_truth.values().filter_map(merge_example).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ns: &[u128]) -> Vec<Uuid> {
+        ns.iter().copied().map(Uuid::from_u128).collect()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_hand_computed() {
+        // truth {1 2 3} {4 5}, predicted {1 2} {3 4 5}
+        // same-cluster pairs: truth 3 + 1, predicted 1 + 3, shared (1,2) and (4,5)
+        let truth = vec![ids(&[1, 2, 3]), ids(&[4, 5])];
+        let predicted = vec![ids(&[1, 2]), ids(&[3, 4, 5])];
+        let eval = evaluate(&truth, &predicted);
+        assert_eq!(eval.points, 5);
+        assert_eq!((eval.true_pairs, eval.predicted_pairs), (4, 4));
+        assert_eq!(eval.correct_pairs, 2);
+        assert_close(eval.precision, 0.5);
+        assert_close(eval.recall, 0.5);
+        assert_close(eval.f1, 0.5);
+        // expected index 4 * 4 / 10 = 1.6, max index 4, ARI (2 - 1.6) / (4 - 1.6)
+        assert_close(eval.adjusted_rand_index, 1.0 / 6.0);
+        assert_eq!(
+            eval.over_merged,
+            vec![MergeExample {
+                pieces: vec![ids(&[4, 5]), ids(&[3])],
+                wrong_pairs: 2,
+            }]
+        );
+        assert_eq!(
+            eval.under_merged,
+            vec![MergeExample {
+                pieces: vec![ids(&[1, 2]), ids(&[3])],
+                wrong_pairs: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_hand_computed_unbalanced() {
+        // truth {1 2} {3 4} {5 6}, predicted {1 2 3 4} {5} {6}
+        // truth pairs 3, predicted pairs 6, shared (1,2) and (3,4)
+        let truth = vec![ids(&[1, 2]), ids(&[3, 4]), ids(&[5, 6])];
+        let predicted = vec![ids(&[1, 2, 3, 4]), ids(&[5]), ids(&[6])];
+        let eval = evaluate(&truth, &predicted);
+        assert_close(eval.precision, 2.0 / 6.0);
+        assert_close(eval.recall, 2.0 / 3.0);
+        assert_close(eval.f1, 4.0 / 9.0);
+        // expected index 3 * 6 / 15 = 1.2, max index 4.5, ARI 0.8 / 3.3
+        assert_close(eval.adjusted_rand_index, 0.8 / 3.3);
+        assert_eq!(eval.over_merged.len(), 1);
+        assert_eq!(eval.over_merged[0].wrong_pairs, 4);
+        assert_eq!(eval.under_merged[0].pieces, vec![ids(&[5]), ids(&[6])]);
+    }
+
+    #[test]
+    fn test_perfect_and_degenerate() {
+        let truth = vec![ids(&[1, 2]), ids(&[3])];
+        let eval = evaluate(&truth, &[ids(&[3]), ids(&[2, 1])]);
+        assert_close(eval.f1, 1.0);
+        assert_close(eval.adjusted_rand_index, 1.0);
+        assert!(eval.over_merged.is_empty() && eval.under_merged.is_empty());
+        // nothing predicted, unknown points ignored: every truth point is a singleton
+        let eval = evaluate(&truth, &[ids(&[7, 8])]);
+        assert_eq!(eval.predicted_clusters, 3);
+        assert_close(eval.precision, 1.0);
+        assert_close(eval.recall, 0.0);
+        assert_close(eval.f1, 0.0);
+        assert_close(eval.adjusted_rand_index, 0.0);
+    }
+}
@@ -0,0 +1,117 @@
+use clap::ValueEnum;
+use hnsw_rs::prelude::*;
+use ndarray::Array2;
+use petal_clustering::{Fit, Optics};
+use petal_neighbors::distance::Euclidean;
+use shared::cluster::{connected_components, greedy_cluster_chunked, union_find_cluster};
+use shared::cosine_sim::cosine_sim;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// stage1, greedy complete-linkage in chunks, similarity strictly above the threshold
+    Greedy,
+    /// stage14, single-linkage over all pairs at or above the threshold
+    UnionFind,
+    /// OPTICS on L2-normalized vectors
+    Optics,
+    /// Connected components of the HNSW kNN graph, edges at or above the threshold
+    HnswComponents,
+}
+
+#[derive(Debug, Clone)]
+pub struct StrategyParams {
+    pub threshold: f32,
+    pub chunk_size: usize,
+    /// OPTICS radius in Euclidean distance, derived from `threshold` when unset
+    pub eps: Option<f32>,
+    pub min_samples: usize,
+    pub k: usize,
+    pub ef: usize,
+}
+
+impl StrategyParams {
+    /// For unit vectors `|a - b|^2 = 2 (1 - cos)`
+    pub fn optics_eps(&self) -> f32 {
+        self.eps
+            .unwrap_or_else(|| (2.0 * (1.0 - self.threshold)).max(0.0).sqrt())
+    }
+}
+
+fn normalized(v: &[f32]) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter().map(|x| x / norm).collect()
+    } else {
+        v.to_vec()
+    }
+}
+
+fn optics(vectors: &[&[f32]], params: &StrategyParams) -> Vec<Vec<usize>> {
+    let dim = vectors.first().map_or(0, |v| v.len());
+    let data: Vec<f32> = vectors.iter().flat_map(|v| normalized(v)).collect();
+    let vecs: Array2<f32> = Array2::from_shape_vec((vectors.len(), dim), data)
+        .expect("Failed to create Array2 from data");
+    let mut opt = Optics::new(
+        params.optics_eps(),
+        params.min_samples,
+        Euclidean::default(),
+    );
+    let (clusters_map, noises) = opt.fit(&vecs, None);
+    let mut clusters: Vec<Vec<usize>> = clusters_map.into_values().collect();
+    clusters.extend(noises.into_iter().map(|idx| vec![idx]));
+    clusters
+}
+
+fn hnsw_components(vectors: &[&[f32]], params: &StrategyParams) -> Vec<Vec<usize>> {
+    let all_vecs: Vec<Vec<f32>> = vectors.iter().map(|v| v.to_vec()).collect();
+    let data: Vec<(&Vec<f32>, usize)> = all_vecs
+        .iter()
+        .enumerate()
+        .map(|(idx, v)| (v, idx))
+        .collect();
+    let mut hnsw = Hnsw::<f32, DistCosine>::new(48, data.len(), 16, 600, DistCosine);
+    hnsw.parallel_insert(&data);
+    hnsw.set_searching_mode(true);
+    let max_distance = 1.0 - params.threshold;
+    let edges = all_vecs.iter().enumerate().flat_map(|(idx, v)| {
+        hnsw.search(v, params.k + 1, params.ef.max(params.k + 1))
+            .into_iter()
+            .filter(move |n| n.d_id != idx && n.distance <= max_distance)
+            .map(move |n| (idx, n.d_id))
+    });
+    connected_components(vectors.len(), edges)
+}
+
+/// Clusters `ids` with the chosen strategy, `vectors[i]` belongs to `ids[i]`
+pub fn run(
+    strategy: Strategy,
+    params: &StrategyParams,
+    ids: &[Uuid],
+    vectors: &[&[f32]],
+) -> Vec<Vec<Uuid>> {
+    let to_ids = |clusters: Vec<Vec<usize>>| -> Vec<Vec<Uuid>> {
+        clusters
+            .into_iter()
+            .map(|members| members.into_iter().map(|i| ids[i]).collect())
+            .collect()
+    };
+    match strategy {
+        Strategy::Greedy => {
+            let index: HashMap<&Uuid, usize> =
+                ids.iter().enumerate().map(|(i, id)| (id, i)).collect();
+            greedy_cluster_chunked(ids, params.chunk_size, |a, b| {
+                cosine_sim(vectors[index[a]], vectors[index[b]]) > params.threshold
+            })
+            .into_iter()
+            .map(|cluster| cluster.into_iter().collect())
+            .collect()
+        }
+        Strategy::UnionFind => to_ids(union_find_cluster(ids.len(), |i, j| {
+            cosine_sim(vectors[i], vectors[j]) >= params.threshold
+        })),
+        Strategy::Optics => to_ids(optics(vectors, params)),
+        Strategy::HnswComponents => to_ids(hnsw_components(vectors, params)),
+    }
+}
@@ -0,0 +1,329 @@
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Quantiles {
+    pub min: f32,
+    pub p01: f32,
+    pub p05: f32,
+    pub p25: f32,
+    pub p50: f32,
+    pub p75: f32,
+    pub p95: f32,
+    pub p99: f32,
+    pub max: f32,
+    pub mean: f32,
+}
+
+/// Linearly interpolated quantile of ascending `sorted`
+fn quantile(sorted: &[f32], q: f64) -> f32 {
+    let pos = q * (sorted.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    let frac = (pos - lo as f64) as f32;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * frac
+}
+
+/// `None` without values, NaNs are left out
+pub fn quantiles(values: &[f32]) -> Option<Quantiles> {
+    let mut sorted: Vec<f32> = values.iter().copied().filter(|v| !v.is_nan()).collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_unstable_by(f32::total_cmp);
+    let q = |q: f64| quantile(&sorted, q);
+    let mean = sorted.iter().map(|&v| v as f64).sum::<f64>() / sorted.len() as f64;
+    Some(Quantiles {
+        min: sorted[0],
+        p01: q(0.01),
+        p05: q(0.05),
+        p25: q(0.25),
+        p50: q(0.5),
+        p75: q(0.75),
+        p95: q(0.95),
+        p99: q(0.99),
+        max: sorted[sorted.len() - 1],
+        mean: mean as f32,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Histogram {
+    /// `counts.len() + 1` ascending bin edges
+    pub edges: Vec<f32>,
+    pub counts: Vec<usize>,
+}
+
+/// Equal width bins over `[lower, upper]`, values outside land in the first or last bin
+pub fn histogram(values: &[f32], lower: f32, upper: f32, bins: usize) -> Histogram {
+    let bins = if upper > lower { bins.max(1) } else { 1 };
+    let width = (upper - lower) / bins as f32;
+    let edges = (0..=bins).map(|i| lower + width * i as f32).collect();
+    let mut counts = vec![0; bins];
+    for &v in values.iter().filter(|v| !v.is_nan()) {
+        let bin = match width > 0.0 {
+            true => ((v - lower) / width).floor().max(0.0) as usize,
+            false => 0,
+        };
+        counts[bin.min(bins - 1)] += 1;
+    }
+    Histogram { edges, counts }
+}
+
+/// `count` distinct unordered pairs of `0..n`, every pair if there are no more than that
+pub fn sample_pairs<R: Rng>(n: usize, count: usize, rng: &mut R) -> Vec<(usize, usize)> {
+    let total = n * n.saturating_sub(1) / 2;
+    if count >= total {
+        return (0..n)
+            .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+            .collect();
+    }
+    let mut seen = HashSet::with_capacity(count);
+    let mut pairs = Vec::with_capacity(count);
+    while pairs.len() < count {
+        let (a, b) = (rng.random_range(0..n), rng.random_range(0..n));
+        if a == b {
+            continue;
+        }
+        let pair = (a.min(b), a.max(b));
+        if seen.insert(pair) {
+            pairs.push(pair);
+        }
+    }
+    pairs
+}
+
+/// The `k` most similar members of `pool` for every anchor, as distinct unordered pairs
+///
+/// Random pairs are almost never near a duplicate threshold, these cover the high end.
+pub fn nearest_pairs<F>(anchors: &[usize], pool: &[usize], k: usize, sim: F) -> Vec<(usize, usize)>
+where
+    F: Fn(usize, usize) -> f32,
+{
+    let mut seen = HashSet::new();
+    let mut pairs = Vec::new();
+    for &anchor in anchors {
+        let mut scored: Vec<(f32, usize)> = pool
+            .iter()
+            .filter(|&&other| other != anchor)
+            .map(|&other| (sim(anchor, other), other))
+            .collect();
+        scored.sort_unstable_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        for &(_, other) in scored.iter().take(k) {
+            let pair = (anchor.min(other), anchor.max(other));
+            if seen.insert(pair) {
+                pairs.push(pair);
+            }
+        }
+    }
+    pairs
+}
+
+/// Least squares `new = slope * old + intercept`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinearFit {
+    pub slope: f64,
+    pub intercept: f64,
+    pub r2: f64,
+}
+
+impl LinearFit {
+    #[inline]
+    pub fn apply(&self, old: f32) -> f32 {
+        (self.slope * old as f64 + self.intercept) as f32
+    }
+}
+
+/// `None` with fewer than two pairs or no spread in old similarity
+pub fn fit_linear(pairs: &[(f32, f32)]) -> Option<LinearFit> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|p| p.0 as f64).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|p| p.1 as f64).sum::<f64>() / n;
+    let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+    for &(x, y) in pairs {
+        let (dx, dy) = (x as f64 - mean_x, y as f64 - mean_y);
+        sxx += dx * dx;
+        sxy += dx * dy;
+        syy += dy * dy;
+    }
+    if sxx == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    let r2 = match syy {
+        0.0 => 1.0,
+        _ => sxy * sxy / (sxx * syy),
+    };
+    Some(LinearFit {
+        slope,
+        intercept: mean_y - slope * mean_x,
+        r2,
+    })
+}
+
+/// Pairs whose old similarity falls in `[old_lower, old_upper)`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MappingBin {
+    pub old_lower: f32,
+    pub old_upper: f32,
+    pub pairs: usize,
+    pub new_median: Option<f32>,
+}
+
+/// Median new similarity per equal width bin of old similarity, the last bin includes `upper`
+pub fn mapping_bins(pairs: &[(f32, f32)], lower: f32, upper: f32, bins: usize) -> Vec<MappingBin> {
+    let hist = histogram(&[], lower, upper, bins);
+    let bins = hist.counts.len();
+    let mut members: Vec<Vec<f32>> = vec![Vec::new(); bins];
+    let width = (upper - lower) / bins as f32;
+    for &(old, new) in pairs {
+        if old < lower || old > upper {
+            continue;
+        }
+        let bin = match width > 0.0 {
+            true => ((old - lower) / width) as usize,
+            false => 0,
+        };
+        members[bin.min(bins - 1)].push(new);
+    }
+    hist.edges
+        .windows(2)
+        .zip(members)
+        .map(|(edge, values)| MappingBin {
+            old_lower: edge[0],
+            old_upper: edge[1],
+            pairs: values.len(),
+            new_median: quantiles(&values).map(|q| q.p50),
+        })
+        .collect()
+}
+
+/// An old similarity threshold carried over to the new space
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Translation {
+    pub old: f32,
+    /// Through the linear fit
+    pub linear: Option<f32>,
+    /// The new threshold keeping as many sampled pairs above it as the old one did
+    pub rank_matched: Option<f32>,
+    /// Sampled pairs at or above `old`, the rank match is only as good as this count
+    pub pairs_above: usize,
+}
+
+pub fn translate(pairs: &[(f32, f32)], threshold: f32, fit: Option<&LinearFit>) -> Translation {
+    let pairs_above = pairs.iter().filter(|p| p.0 >= threshold).count();
+    let rank_matched = match pairs_above {
+        0 => None,
+        k => {
+            let mut new: Vec<f32> = pairs.iter().map(|p| p.1).collect();
+            new.sort_unstable_by(|a, b| b.total_cmp(a));
+            Some(new[k - 1])
+        }
+    };
+    Translation {
+        old: threshold,
+        linear: fit.map(|f| f.apply(threshold)),
+        rank_matched,
+        pairs_above,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let values: Vec<f32> = (0..=100).rev().map(|v| v as f32 / 100.0).collect();
+        let q = quantiles(&values).unwrap();
+        assert_close(q.min, 0.0);
+        assert_close(q.p05, 0.05);
+        assert_close(q.p50, 0.5);
+        assert_close(q.p99, 0.99);
+        assert_close(q.max, 1.0);
+        assert_close(q.mean, 0.5);
+        // interpolated between the two middle values
+        assert_close(quantiles(&[1.0, 0.0, f32::NAN]).unwrap().p50, 0.5);
+        assert!(quantiles(&[]).is_none());
+    }
+
+    #[test]
+    fn test_histogram() {
+        let hist = histogram(&[0.0, 0.1, 0.49, 0.5, 1.0, 1.5, -2.0], 0.0, 1.0, 2);
+        assert_eq!(hist.edges, vec![0.0, 0.5, 1.0]);
+        assert_eq!(hist.counts, vec![4, 3]);
+        // every drift exactly 1
+        let hist = histogram(&[1.0, 1.0], 1.0, 1.0, 20);
+        assert_eq!(hist.counts, vec![2]);
+    }
+
+    #[test]
+    fn test_sample_pairs() {
+        let mut rng = Pcg64::seed_from_u64(7);
+        let pairs = sample_pairs(100, 500, &mut rng);
+        assert_eq!(pairs.len(), 500);
+        assert!(pairs.iter().all(|&(a, b)| a < b && b < 100));
+        assert_eq!(pairs.iter().collect::<HashSet<_>>().len(), 500);
+        // deterministic by seed
+        assert_eq!(sample_pairs(100, 500, &mut Pcg64::seed_from_u64(7)), pairs);
+        // fewer pairs than asked for
+        assert_eq!(sample_pairs(3, 10, &mut rng), vec![(0, 1), (0, 2), (1, 2)]);
+        assert!(sample_pairs(1, 10, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_nearest_pairs() {
+        let points = [0.0f32, 0.1, 0.15, 5.0, 5.2, 9.0];
+        let sim = |a: usize, b: usize| -(points[a] - points[b]).abs();
+        let pool: Vec<usize> = (0..points.len()).collect();
+        let pairs = nearest_pairs(&[0, 3, 1], &pool, 1, sim);
+        // (1, 2) is the closest to 1, (0, 1) already seen from 0
+        assert_eq!(pairs, vec![(0, 1), (3, 4), (1, 2)]);
+        assert_eq!(nearest_pairs(&[5], &pool, 2, sim), vec![(4, 5), (3, 5)]);
+    }
+
+    #[test]
+    fn test_fit_and_translate() {
+        // the new model compresses similarities towards 1
+        let pairs: Vec<(f32, f32)> = (0..=20)
+            .map(|i| {
+                let old = i as f32 / 20.0;
+                (old, 0.5 + old / 2.0)
+            })
+            .collect();
+        let fit = fit_linear(&pairs).unwrap();
+        assert!((fit.slope - 0.5).abs() < 1e-6);
+        assert!((fit.intercept - 0.5).abs() < 1e-6);
+        assert!((fit.r2 - 1.0).abs() < 1e-6);
+        let t = translate(&pairs, 0.9, Some(&fit));
+        assert_eq!(t.pairs_above, 3);
+        assert_close(t.linear.unwrap(), 0.95);
+        assert_close(t.rank_matched.unwrap(), 0.95);
+        let t = translate(&pairs, 1.5, None);
+        assert_eq!((t.pairs_above, t.linear, t.rank_matched), (0, None, None));
+        assert!(fit_linear(&[(0.5, 0.1), (0.5, 0.9)]).is_none());
+        assert!(fit_linear(&pairs[..1]).is_none());
+    }
+
+    #[test]
+    fn test_mapping_bins() {
+        let pairs = [(0.1, 0.3), (0.2, 0.5), (0.9, 0.95), (1.0, 1.0), (-0.5, 0.0)];
+        let bins = mapping_bins(&pairs, 0.0, 1.0, 2);
+        assert_eq!(bins.len(), 2);
+        assert_eq!(bins[0].pairs, 2);
+        assert_close(bins[0].new_median.unwrap(), 0.4);
+        assert_eq!(bins[1].pairs, 2);
+        assert_close(bins[1].new_median.unwrap(), 0.975);
+        let bins = mapping_bins(&[], 0.0, 1.0, 4);
+        assert!(bins.iter().all(|b| b.pairs == 0 && b.new_median.is_none()));
+    }
+}
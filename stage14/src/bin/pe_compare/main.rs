@@ -0,0 +1,260 @@
+mod drift;
+
+use crate::drift::{
+    Histogram, LinearFit, MappingBin, Quantiles, Translation, fit_linear, histogram, mapping_bins,
+    nearest_pairs, quantiles, sample_pairs, translate,
+};
+use clap::Parser;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use serde::Serialize;
+use shared::atomic_write::atomic_write_with;
+use shared::config::Thresholds;
+use shared::cosine_sim::cosine_sim;
+use shared::point_explorer::{DynPointExplorer, PointExplorerBuilder};
+use shared::structure::NekoPoint;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "pe-compare",
+    version,
+    about = "Measure how far vectors moved between two point explorer snapshots of a collection"
+)]
+struct Cli {
+    /// Explorer embedded with the old model
+    #[arg(long)]
+    old: String,
+    /// Explorer embedded with the new model
+    #[arg(long)]
+    new: String,
+    #[arg(long, default_value = "768")]
+    old_dim: usize,
+    /// Defaults to --old-dim, per-point drift needs both to match
+    #[arg(long)]
+    new_dim: Option<usize>,
+    /// Pickled point map, resolves the metadata of the points that moved the most
+    #[arg(long)]
+    points_map: Option<String>,
+    /// Points that moved the most listed in the report
+    #[arg(long, default_value = "50")]
+    top: usize,
+    #[arg(long, default_value = "20")]
+    histogram_bins: usize,
+    /// Random pairs for the threshold mapping
+    #[arg(long, default_value = "20000")]
+    random_pairs: usize,
+    /// Points searched exhaustively for near pairs, which random pairs hardly ever are
+    #[arg(long, default_value = "5000")]
+    pool: usize,
+    /// Points of the pool whose nearest neighbors are paired
+    #[arg(long, default_value = "200")]
+    anchors: usize,
+    /// Nearest neighbors paired per anchor
+    #[arg(long, default_value = "10")]
+    k: usize,
+    /// Old similarity thresholds to carry over to the new space, the configured `image_sim` when
+    /// none given
+    #[arg(long, value_delimiter = ',')]
+    thresholds: Vec<f32>,
+    #[arg(long, default_value = "20")]
+    mapping_bins: usize,
+    #[arg(long, default_value = "7")]
+    seed: u64,
+    #[arg(long, default_value = "pe_compare")]
+    save_result_prefix: String,
+}
+
+#[derive(Serialize)]
+struct MovedPoint {
+    id: Uuid,
+    similarity: f32,
+    metadata: Option<NekoPoint>,
+}
+
+#[derive(Serialize)]
+struct Drift {
+    quantiles: Option<Quantiles>,
+    histogram: Histogram,
+    /// Lowest old-to-new similarity first
+    most_moved: Vec<MovedPoint>,
+}
+
+#[derive(Serialize)]
+struct ThresholdMapping {
+    random_pairs: usize,
+    near_pairs: usize,
+    fit: Option<LinearFit>,
+    bins: Vec<MappingBin>,
+    translations: Vec<Translation>,
+}
+
+#[derive(Serialize)]
+struct CompareReport {
+    old: String,
+    new: String,
+    old_dim: usize,
+    new_dim: usize,
+    common_points: usize,
+    only_old: usize,
+    only_new: usize,
+    /// Absent when the dimensions differ
+    drift: Option<Drift>,
+    mapping: ThresholdMapping,
+}
+
+fn load(path: &str, dim: usize, points_map: Option<&str>) -> anyhow::Result<DynPointExplorer<f32>> {
+    let mut builder = PointExplorerBuilder::new().path(path);
+    if let Some(points_map) = points_map {
+        builder = builder.metadata_path(points_map);
+    }
+    Ok(builder.build_dyn(dim)?)
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut cli = Cli::parse();
+    if cli.thresholds.is_empty() {
+        cli.thresholds.push(Thresholds::init()?.image_sim);
+    }
+    let new_dim = cli.new_dim.unwrap_or(cli.old_dim);
+    let old = load(&cli.old, cli.old_dim, cli.points_map.as_deref())?;
+    let new = load(&cli.new, new_dim, None)?;
+    // old explorer order
+    let common: Vec<(Uuid, &[f32], &[f32])> = old
+        .iter()
+        .filter_map(|(id, v)| Some((*id, v.as_slice(), new.get_vector(id)?)))
+        .collect();
+    let only_old = old.len() - common.len();
+    let only_new = new.len() - common.len();
+    println!(
+        "{} common points, {} only in {}, {} only in {}",
+        common.len(),
+        only_old,
+        cli.old,
+        only_new,
+        cli.new
+    );
+
+    let drift = match cli.old_dim == new_dim {
+        true => {
+            let mut moved: Vec<(Uuid, f32)> = common
+                .iter()
+                .map(|&(id, a, b)| (id, cosine_sim(a, b)))
+                .collect();
+            let sims: Vec<f32> = moved.iter().map(|m| m.1).collect();
+            let quantiles = quantiles(&sims);
+            let lower = quantiles.as_ref().map_or(0.0, |q| q.min.min(1.0));
+            let histogram = histogram(&sims, lower, 1.0, cli.histogram_bins);
+            moved.sort_unstable_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+            let most_moved = moved
+                .into_iter()
+                .take(cli.top)
+                .map(|(id, similarity)| MovedPoint {
+                    id,
+                    similarity,
+                    metadata: old.get_point_metadata(&id).cloned(),
+                })
+                .collect();
+            Some(Drift {
+                quantiles,
+                histogram,
+                most_moved,
+            })
+        }
+        false => {
+            println!(
+                "Dimensions differ ({} vs {}), skipping per-point drift",
+                cli.old_dim, new_dim
+            );
+            None
+        }
+    };
+    if let Some(q) = drift.as_ref().and_then(|d| d.quantiles.as_ref()) {
+        println!("\n--- Old to new similarity ---");
+        println!(
+            "min {:.4}  p01 {:.4}  p05 {:.4}  p25 {:.4}  p50 {:.4}  p75 {:.4}  p95 {:.4}  max {:.4}  mean {:.4}",
+            q.min, q.p01, q.p05, q.p25, q.p50, q.p75, q.p95, q.max, q.mean
+        );
+    }
+
+    let mut rng = Pcg64::seed_from_u64(cli.seed);
+    let random = sample_pairs(common.len(), cli.random_pairs, &mut rng);
+    let pool_size = cli.pool.min(common.len());
+    let pool = rand::seq::index::sample(&mut rng, common.len(), pool_size).into_vec();
+    let anchors = &pool[..cli.anchors.min(pool.len())];
+    let near = nearest_pairs(anchors, &pool, cli.k, |a, b| {
+        cosine_sim(common[a].1, common[b].1)
+    });
+    let seen: HashSet<(usize, usize)> = random.iter().copied().collect();
+    let near: Vec<(usize, usize)> = near.into_iter().filter(|p| !seen.contains(p)).collect();
+    let pairs: Vec<(f32, f32)> = random
+        .iter()
+        .chain(&near)
+        .map(|&(a, b)| {
+            (
+                cosine_sim(common[a].1, common[b].1),
+                cosine_sim(common[a].2, common[b].2),
+            )
+        })
+        .collect();
+    let fit = fit_linear(&pairs);
+    let lower = pairs.iter().map(|p| p.0).fold(1.0f32, f32::min);
+    let bins = mapping_bins(&pairs, lower, 1.0, cli.mapping_bins);
+    let translations: Vec<Translation> = cli
+        .thresholds
+        .iter()
+        .map(|&t| translate(&pairs, t, fit.as_ref()))
+        .collect();
+
+    println!(
+        "\n--- Threshold mapping over {} random and {} near pairs ---",
+        random.len(),
+        near.len()
+    );
+    match &fit {
+        Some(fit) => println!(
+            "new = {:.4} * old + {:.4}  (r2 {:.4})",
+            fit.slope, fit.intercept, fit.r2
+        ),
+        None => println!("Too few pairs to fit"),
+    }
+    for t in &translations {
+        let show = |v: Option<f32>| v.map_or("n/a".to_string(), |v| format!("{:.4}", v));
+        println!(
+            "{:.4} -> linear {}  rank matched {}  ({} pairs above)",
+            t.old,
+            show(t.linear),
+            show(t.rank_matched),
+            t.pairs_above
+        );
+    }
+
+    let report = CompareReport {
+        old: cli.old,
+        new: cli.new,
+        old_dim: cli.old_dim,
+        new_dim,
+        common_points: common.len(),
+        only_old,
+        only_new,
+        drift,
+        mapping: ThresholdMapping {
+            random_pairs: random.len(),
+            near_pairs: near.len(),
+            fit,
+            bins,
+            translations,
+        },
+    };
+    let filename = format!(
+        "{}_{}.json",
+        cli.save_result_prefix,
+        chrono::Local::now().format("%Y%m%d_%H%M%S")
+    );
+    atomic_write_with(&filename, |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report)?)
+    })?;
+    println!("\nReport saved to {}", &filename);
+    Ok(())
+}
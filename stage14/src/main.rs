@@ -1,12 +1,48 @@
+use clap::{Parser, ValueEnum};
 use indicatif::{ProgressBar, ProgressStyle};
-use petgraph::unionfind::UnionFind;
-use shared::cosine_sim::cosine_sim;
+use shared::atomic_write::atomic_write_with;
+use shared::cluster::union_find_cluster;
+use shared::cluster_file::{LegacyFormat, read_clusters, write_clusters};
+use shared::config::Thresholds;
+use shared::cosine_sim::{cosine_sim, simd_capabilities};
+use shared::distance::{SignBits, SignBitsFilter};
+use shared::exact_dup::ExactDupReduction;
 use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
-use shared::structure::IMAGE_SIM_THRESHOLD;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
+use std::path::PathBuf;
 use uuid::Uuid;
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Prefilter {
+    /// Exact cosine for every pair
+    None,
+    /// Skip pairs whose sign bits provably rule out the threshold, see `shared::distance`
+    Signbits,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "stage14",
+    version,
+    about = "Single-linkage clustering over all pairs"
+)]
+struct Cli {
+    #[arg(long, value_enum, default_value = "none")]
+    prefilter: Prefilter,
+    /// stage23 exact duplicate groups, clustered through one representative each
+    #[arg(long)]
+    exact_clusters: Option<PathBuf>,
+    /// Cosine similarity linking two points, the configured `image_sim` when unset
+    #[arg(long)]
+    threshold: Option<f32>,
+}
+
 fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let threshold = match cli.threshold {
+        Some(threshold) => threshold,
+        None => Thresholds::init()?.image_sim,
+    };
     let pe: PointExplorer<f32, 768> = PointExplorerBuilder::new()
         .path("qdrant_point_explorer_250611.pkl")
         .build()?;
@@ -16,8 +52,22 @@ fn main() -> anyhow::Result<()> {
         return Ok(());
     }
     println!("Successfully loaded {} points.", n);
-    let mut uf = UnionFind::<usize>::new(n);
-    let vectors: Vec<_> = pe.iter().map(|(_, v)| v).collect();
+    let ids: Vec<Uuid> = pe.iter().map(|(id, _)| *id).collect();
+    let (ids, exact_dups) = match &cli.exact_clusters {
+        Some(path) => {
+            let groups = read_clusters(path, LegacyFormat::Pickle)?;
+            ExactDupReduction::reduce(&ids, &groups)
+        }
+        None => (ids, ExactDupReduction::default()),
+    };
+    if exact_dups.dropped() > 0 {
+        println!("Left out {} exact duplicates.", exact_dups.dropped());
+    }
+    let n = ids.len();
+    let vectors: Vec<_> = ids
+        .iter()
+        .map(|id| pe.get_vector(id).expect("listed id"))
+        .collect();
     let total_pairs = if n > 1 { (n * (n - 1)) / 2 } else { 0 };
 
     let pb = ProgressBar::new(total_pairs as u64);
@@ -27,31 +77,47 @@ fn main() -> anyhow::Result<()> {
             .progress_chars("#>-"),
     );
     println!(
-        "\nStarting clustering of {} pairs with threshold {}...",
-        total_pairs, IMAGE_SIM_THRESHOLD
+        "\nStarting clustering of {} pairs with threshold {}, {} cosine kernels...",
+        total_pairs,
+        threshold,
+        simd_capabilities()
     );
 
-    for i in 0..n {
-        for j in (i + 1)..n {
-            let vector_i = vectors[i];
-            let vector_j = vectors[j];
-            let similarity = cosine_sim(vector_i, vector_j);
-            if similarity >= IMAGE_SIM_THRESHOLD {
-                uf.union(i, j);
-            }
-            pb.inc(1);
+    let signatures: Option<Vec<SignBits>> = match cli.prefilter {
+        Prefilter::None => None,
+        Prefilter::Signbits => {
+            let filter = SignBitsFilter::new(threshold);
+            Some(
+                vectors
+                    .iter()
+                    .map(|v| filter.signature(v.as_slice()))
+                    .collect(),
+            )
         }
-    }
+    };
+    let mut exact_evaluations = 0u64;
+    let components = union_find_cluster(n, |i, j| {
+        pb.inc(1);
+        if signatures.as_ref().is_some_and(|s| !s[i].may_reach(&s[j])) {
+            return false;
+        }
+        exact_evaluations += 1;
+        cosine_sim(vectors[i], vectors[j]) >= threshold
+    });
     pb.finish_with_message("Clustering complete!");
+    println!(
+        "Exact cosine evaluations: {} of {} pairs ({:.2}%)",
+        exact_evaluations,
+        total_pairs,
+        exact_evaluations as f64 * 100.0 / total_pairs.max(1) as f64
+    );
 
     println!("\nExtracting cluster results...");
-    let mut clusters: HashMap<usize, HashSet<Uuid>> = HashMap::new();
-    for i in 0..n {
-        let root = uf.find_mut(i);
-        let uuid = pe.index2uuid(i).expect("Index should be valid");
-        clusters.entry(root).or_default().insert(*uuid);
-    }
-    let result_clusters: Vec<HashSet<Uuid>> = clusters.into_values().collect();
+    let result_clusters: Vec<HashSet<Uuid>> = components
+        .into_iter()
+        .map(|members| members.into_iter().map(|i| ids[i]).collect())
+        .collect();
+    let result_clusters = exact_dups.expand(result_clusters);
 
     println!("\n--- Clustering Finished ---");
     println!("Found {} clusters.", result_clusters.len());
@@ -66,9 +132,7 @@ fn main() -> anyhow::Result<()> {
             println!("  - ... and {} more members.", cluster.len() - 5);
         }
     }
-    let r = bincode::serde::encode_to_vec(result_clusters, bincode::config::standard())
-        .map_err(|e| anyhow::anyhow!("Failed to serialize clusters: {}", e))?;
-    std::fs::write("clusters.bin", r)
+    atomic_write_with("clusters.bin", |w| write_clusters(w, &result_clusters))
         .map_err(|e| anyhow::anyhow!("Failed to write clusters to file: {}", e))?;
     Ok(())
 }
@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+/// Error rate over the last `window` files, trips once it exceeds `threshold_percent`
+///
+/// Nothing trips before the window is full, so a few early failures do not end the run.
+#[derive(Debug, Clone)]
+pub struct ErrorWindow {
+    window: usize,
+    threshold_percent: f64,
+    outcomes: VecDeque<bool>,
+    failed: usize,
+}
+
+impl ErrorWindow {
+    pub fn new(window: usize, threshold_percent: f64) -> Self {
+        assert!(window > 0, "window must hold at least one file");
+        Self {
+            window,
+            threshold_percent,
+            outcomes: VecDeque::with_capacity(window),
+            failed: 0,
+        }
+    }
+
+    /// Records one file, `true` if the error rate of the full window is over the threshold
+    pub fn record(&mut self, failed: bool) -> bool {
+        if self.outcomes.len() == self.window && self.outcomes.pop_front() == Some(true) {
+            self.failed -= 1;
+        }
+        self.outcomes.push_back(failed);
+        self.failed += failed as usize;
+        self.outcomes.len() == self.window && self.rate_percent() > self.threshold_percent
+    }
+
+    /// Failed share of the files in the window, in percent
+    pub fn rate_percent(&self) -> f64 {
+        match self.outcomes.len() {
+            0 => 0.0,
+            len => self.failed as f64 * 100.0 / len as f64,
+        }
+    }
+
+    #[inline]
+    pub fn window(&self) -> usize {
+        self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trips_only_when_full() {
+        let mut window = ErrorWindow::new(4, 50.0);
+        assert!(!window.record(true));
+        assert!(!window.record(true));
+        assert!(!window.record(true));
+        assert_eq!(window.rate_percent(), 100.0);
+        assert!(window.record(true));
+    }
+
+    #[test]
+    fn test_threshold_is_exclusive() {
+        let mut window = ErrorWindow::new(4, 50.0);
+        for failed in [true, false, true, false] {
+            assert!(!window.record(failed));
+        }
+        assert_eq!(window.rate_percent(), 50.0);
+        // [false, true, false, true] is still 50%
+        assert!(!window.record(true));
+        // [true, false, true, true]
+        assert!(window.record(true));
+    }
+
+    #[test]
+    fn test_old_failures_slide_out() {
+        let mut window = ErrorWindow::new(3, 30.0);
+        assert!(!window.record(true));
+        assert!(!window.record(false));
+        assert!(window.record(false));
+        for _ in 0..3 {
+            assert!(!window.record(false));
+        }
+        assert_eq!(window.rate_percent(), 0.0);
+        assert!(window.record(true));
+    }
+}
@@ -1,17 +1,27 @@
+mod error_window;
+mod manifest;
+
+use crate::error_window::ErrorWindow;
+use crate::manifest::{ManifestEntry, Outcome, moved_files, read_manifest, write_manifest};
 use clap::{ArgAction, ArgGroup, Parser};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use shared::atomic_write::atomic_write;
+use shared::logging::Logging;
 use shared::neko_uuid::NekoUuid;
+use shared::stage_lock::LockArgs;
 use shared::structure::WrongExtFile;
 use std::cmp::min;
-use std::io::Write;
-use std::path::PathBuf;
-use std::{env, fs};
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use uuid::Uuid;
 use walkdir::WalkDir;
 
 #[derive(Debug, Copy, Clone, Default)]
@@ -22,7 +32,7 @@ enum Op {
 }
 
 #[derive(Debug, Parser)]
-#[command(group(ArgGroup::new("Op").args(&["copy", "r#move"]).multiple(false)))]
+#[command(group(ArgGroup::new("Op").args(&["copy", "move"]).multiple(false)))]
 struct Args {
     #[arg(long, value_delimiter = ',')]
     #[arg(value_parser = clap::value_parser!(PathBuf))]
@@ -33,10 +43,31 @@ struct Args {
     copy: bool,
     #[arg(long = "move", group = "Op", action = ArgAction::SetTrue, help = "Move files")]
     r#move: bool,
-    #[arg(long, default_value = "false", help = "Overwrite existing files")]
+    /// Overwrite destinations that exist with other content, identical ones are always skipped
+    #[arg(long, default_value = "false")]
     overwrite: bool,
     #[arg(long, default_value = "true")]
     check_ext: bool,
+    #[command(flatten)]
+    lock: LockArgs,
+    /// Log and write to the manifest where every file would go, without touching any
+    #[arg(long)]
+    dry_run: bool,
+    /// Files between two progress summaries
+    #[arg(long, default_value = "10000", value_parser = clap::value_parser!(u64).range(1..))]
+    progress_every: u64,
+    /// Abort once more than this percent of the last `--fail-fast-window` files failed
+    #[arg(long)]
+    fail_fast_threshold: Option<f64>,
+    #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    fail_fast_window: u64,
+    /// `stage15_manifest_<timestamp>.jsonl` by default
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    /// Manifests of earlier move runs, their sources that are gone are done if still at their
+    /// destination
+    #[arg(long, value_delimiter = ',')]
+    previous_manifests: Vec<PathBuf>,
 }
 
 #[derive(Debug, thiserror::Error, Serialize, Deserialize)]
@@ -51,28 +82,138 @@ enum Stage15Error {
 
 type Stage15Result<T> = Result<T, Stage15Error>;
 
-fn main() -> anyhow::Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new(
-        env::var("STDOUT_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-    ));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage15.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new(
-            env::var("FILE_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-        ));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
-    let args = Args::parse();
-    let op = if args.r#move { Op::Move } else { Op::Copy };
+/// Bytes compared at each end of a destination that already exists
+const COMPARE_EDGE: usize = 64 * 1024;
+
+/// Extension of the source file, `""` for extensionless names and trailing dots (`foo.`)
+fn source_ext(path: &Path) -> &str {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+}
+
+/// `dst_dir/<uuid>.<ext>`, built by joining a complete file name instead of
+/// `set_extension` so dotted destination directories and empty extensions stay intact
+fn build_dst_path(dst_dir: &Path, file_id: &Uuid, ext: &str) -> PathBuf {
+    let filename = match ext {
+        "" => file_id.to_string(),
+        ext => format!("{}.{}", file_id, ext),
+    };
+    dst_dir.join(filename)
+}
+
+/// Whether `dst` holds `contents`, judged from its length and first and last [`COMPARE_EDGE`]
+/// bytes, the destination name being a hash of the content this only catches partial and
+/// foreign files
+fn same_content(dst: &Path, contents: &[u8]) -> io::Result<bool> {
+    let mut file = File::open(dst)?;
+    if file.metadata()?.len() != contents.len() as u64 {
+        return Ok(false);
+    }
+    let edge = COMPARE_EDGE.min(contents.len());
+    let mut buf = vec![0u8; edge];
+    file.read_exact(&mut buf)?;
+    if buf != contents[..edge] {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::End(-(edge as i64)))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf == contents[contents.len() - edge..])
+}
+
+/// Entry of a source gone since an earlier run moved it, if the destination is still there with
+/// the recorded length, or at all for manifests without lengths
+fn already_moved(
+    src_path: &Path,
+    moved: &HashMap<PathBuf, ManifestEntry>,
+) -> Option<ManifestEntry> {
+    let earlier = moved.get(src_path)?;
+    let dst = earlier.dst.as_ref()?;
+    let len = fs::metadata(dst).ok()?.len();
+    earlier.len.is_none_or(|l| l == len).then(|| ManifestEntry {
+        src: src_path.to_path_buf(),
+        dst: Some(dst.clone()),
+        renamed_ext: earlier.renamed_ext.clone(),
+        outcome: Outcome::SkippedIdentical,
+        error: None,
+        len: Some(len),
+    })
+}
+
+/// Running counts of the outcomes, shared by the workers
+#[derive(Debug, Default)]
+struct Progress {
+    processed: AtomicUsize,
+    copied: AtomicUsize,
+    moved: AtomicUsize,
+    skipped: AtomicUsize,
+    conflicts: AtomicUsize,
+    planned: AtomicUsize,
+    wrong_ext: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl Progress {
+    /// Counts one file, returns the files processed so far
+    fn record(&self, entry: &ManifestEntry) -> usize {
+        let counter = match entry.outcome {
+            Outcome::Copied => &self.copied,
+            Outcome::Moved => &self.moved,
+            Outcome::SkippedExisting | Outcome::SkippedIdentical => &self.skipped,
+            Outcome::DestinationConflict => &self.conflicts,
+            Outcome::WouldCopy | Outcome::WouldMove => &self.planned,
+            Outcome::Failed => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if entry.renamed_ext.is_some() {
+            self.wrong_ext.fetch_add(1, Ordering::Relaxed);
+        }
+        self.processed.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let get = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "processed {}, copied {}, moved {}, skipped identical {}, conflicts {}, planned {}, \
+             wrong ext {}, failed {}",
+            get(&self.processed),
+            get(&self.copied),
+            get(&self.moved),
+            get(&self.skipped),
+            get(&self.conflicts),
+            get(&self.planned),
+            get(&self.wrong_ext),
+            get(&self.failed)
+        )
+    }
+}
+
+/// Decides the destination of one file and, unless `--dry-run`, copies or moves it there
+///
+/// `moved` are the sources earlier runs moved, see [`already_moved`].
+/// Whether `a` and `b` are the same file, through links and relative paths
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(fs::canonicalize(a)? == fs::canonicalize(b)?)
+}
+
+/// Files of the source paths, the destination tree left out as it holds earlier outputs
+fn list_files(args: &Args, op: Op, moved: &HashMap<PathBuf, ManifestEntry>) -> Vec<PathBuf> {
+    let dst_root = fs::canonicalize(&args.dst_path).ok();
+    let outside_dst = |path: &Path| {
+        dst_root
+            .as_ref()
+            .is_none_or(|root| fs::canonicalize(path).map_or(true, |path| path != *root))
+    };
     let mut all_files = Vec::new();
     for src in &args.src_paths {
         match src {
             src if src.is_dir() => {
                 for entry in WalkDir::new(src)
                     .into_iter()
+                    .filter_entry(|e| !e.file_type().is_dir() || outside_dst(e.path()))
                     .filter_map(Result::ok)
                     .filter(|e| e.file_type().is_file())
                 {
@@ -82,6 +223,10 @@ fn main() -> anyhow::Result<()> {
             src if src.is_file() => {
                 all_files.push(src.clone());
             }
+            // listed file an earlier run moved, process_file checks it is still at its destination
+            src if matches!(op, Op::Move) && moved.contains_key(src) => {
+                all_files.push(src.clone());
+            }
             _ => {
                 tracing::error!(
                     "Source path {} is neither a file nor a directory",
@@ -90,6 +235,134 @@ fn main() -> anyhow::Result<()> {
             }
         }
     }
+    all_files
+}
+
+fn process_file(
+    src_path: &Path,
+    args: &Args,
+    op: Op,
+    neko_uuid: &NekoUuid,
+    moved: &HashMap<PathBuf, ManifestEntry>,
+) -> Stage15Result<ManifestEntry> {
+    let src_path_ext = source_ext(src_path);
+    let file_contents = match fs::read(src_path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            let done = match (op, e.kind()) {
+                (Op::Move, io::ErrorKind::NotFound) => already_moved(src_path, moved),
+                _ => None,
+            };
+            return done.ok_or_else(|| {
+                Stage15Error::IOError(src_path.to_path_buf(), PathBuf::new(), e.to_string())
+            });
+        }
+    };
+    let target_filename = neko_uuid.generate(file_contents.as_slice());
+    let mut dst_path = build_dst_path(&args.dst_path, &target_filename, src_path_ext);
+    let mut renamed_ext = None;
+    if args.check_ext {
+        let file_infer_ext = match infer::get(&file_contents[0..min(file_contents.len(), 8192 + 1)])
+        {
+            Some(typ) => typ.extension(),
+            _ => return Err(Stage15Error::InferError(src_path.to_path_buf())),
+        };
+        if src_path_ext != file_infer_ext {
+            tracing::debug!(
+                "File {} has extension {}, but inferred as {}",
+                src_path.display(),
+                src_path_ext,
+                file_infer_ext
+            );
+            dst_path = build_dst_path(&args.dst_path, &target_filename, file_infer_ext);
+            renamed_ext = Some(file_infer_ext.to_string());
+        }
+    }
+    let io_error = |e: std::io::Error| {
+        Stage15Error::IOError(src_path.to_path_buf(), dst_path.clone(), e.to_string())
+    };
+    let identical = match dst_path.exists() {
+        true => Some(same_content(&dst_path, &file_contents).map_err(io_error)?),
+        false => None,
+    };
+    // the source is its own destination, removing it would lose the only copy
+    let in_place = identical == Some(true) && same_file(src_path, &dst_path).map_err(io_error)?;
+    let outcome = match (identical, op, args.dry_run) {
+        // already in place, a move still takes the source away
+        (Some(true), Op::Move, false) if !in_place => {
+            fs::remove_file(src_path).map_err(io_error)?;
+            Outcome::SkippedIdentical
+        }
+        (Some(true), _, _) => Outcome::SkippedIdentical,
+        (Some(false), _, _) if !args.overwrite => Outcome::DestinationConflict,
+        (_, Op::Copy, true) => Outcome::WouldCopy,
+        (_, Op::Move, true) => Outcome::WouldMove,
+        (_, Op::Copy, false) => {
+            fs::copy(src_path, &dst_path).map_err(io_error)?;
+            Outcome::Copied
+        }
+        (_, Op::Move, false) => {
+            fs::rename(src_path, &dst_path).map_err(io_error)?;
+            Outcome::Moved
+        }
+    };
+    let error = (outcome == Outcome::DestinationConflict).then(|| {
+        tracing::warn!(
+            "{} exists with other content than {}, left as is",
+            dst_path.display(),
+            src_path.display()
+        );
+        "destination exists with other content".to_string()
+    });
+    if args.dry_run {
+        tracing::info!(
+            "{} -> {} ({:?}{})",
+            src_path.display(),
+            dst_path.display(),
+            outcome,
+            renamed_ext
+                .as_ref()
+                .map(|ext| format!(", extension renamed to {}", ext))
+                .unwrap_or_default()
+        );
+    }
+    Ok(ManifestEntry {
+        src: src_path.to_path_buf(),
+        dst: Some(dst_path),
+        renamed_ext,
+        outcome,
+        error,
+        len: Some(file_contents.len() as u64),
+    })
+}
+
+fn main() -> anyhow::Result<()> {
+    let _logging = Logging::new("stage15").init()?;
+    let args = Args::parse();
+    let _lock = args.lock.acquire("stage15")?;
+    let op = if args.r#move { Op::Move } else { Op::Copy };
+    let mut moved = HashMap::new();
+    for path in &args.previous_manifests {
+        moved.extend(moved_files(read_manifest(path)?));
+    }
+    if !moved.is_empty() {
+        tracing::info!(
+            "{} files moved by earlier runs in {} manifests",
+            moved.len(),
+            args.previous_manifests.len()
+        );
+    }
+    let mut all_files = list_files(&args, op, &moved);
+    // overlapping source paths list files more than once
+    let listed = all_files.len();
+    all_files.sort_unstable();
+    all_files.dedup();
+    if all_files.len() < listed {
+        tracing::warn!(
+            "{} files listed more than once by overlapping source paths",
+            listed - all_files.len()
+        );
+    }
     tracing::info!(
         "Found {} files in {} directories",
         all_files.len(),
@@ -99,73 +372,73 @@ fn main() -> anyhow::Result<()> {
     let neko_uuid = NekoUuid::new();
     let pb = ProgressBar::new(files_len as u64);
     let style = ProgressStyle::default_bar()
-        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
+        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")?;
     pb.set_style(style);
     pb.set_message("Working...");
-    let res: Vec<Stage15Result<Option<WrongExtFile>>> = all_files
+    if args.dry_run {
+        tracing::info!("Dry run, no file is copied or moved");
+    }
+    let progress = Progress::default();
+    let error_window = args
+        .fail_fast_threshold
+        .map(|threshold| Mutex::new(ErrorWindow::new(args.fail_fast_window as usize, threshold)));
+    let aborted = AtomicBool::new(false);
+    let res: Vec<(ManifestEntry, Option<Stage15Error>)> = all_files
         .into_par_iter()
-        .map(|file| {
+        .filter_map(|file| {
+            if aborted.load(Ordering::Relaxed) {
+                return None;
+            }
+            let (entry, error) = match process_file(&file, &args, op, &neko_uuid, &moved) {
+                Ok(entry) => (entry, None),
+                Err(e) => (ManifestEntry::failed(file, &e), Some(e)),
+            };
             pb.inc(1);
-            let src_path = file;
-            let src_path_ext = src_path
-                .extension()
-                .and_then(|ext| ext.to_str())
-                .unwrap_or_default();
-            let file_contents = fs::read(&src_path).map_err(|e| {
-                Stage15Error::IOError(src_path.clone(), PathBuf::new(), e.to_string())
-            })?;
-            let target_filename = neko_uuid.generate(file_contents.as_slice());
-            let mut dst_path = args.dst_path.join(target_filename.to_string());
-            dst_path.set_extension(src_path_ext);
-            let mut maybe_wrong_ext: Option<WrongExtFile> = None;
-            if args.check_ext {
-                let file_infer_ext =
-                    match infer::get(&file_contents[0..min(file_contents.len(), 8192 + 1)]) {
-                        Some(typ) => typ.extension(),
-                        _ => return Err(Stage15Error::InferError(src_path)),
-                    };
-                if src_path_ext != file_infer_ext {
-                    tracing::debug!(
-                        "File {} has extension {}, but inferred as {}",
-                        src_path.display(),
-                        src_path_ext,
-                        file_infer_ext
-                    );
-                    dst_path.set_extension(file_infer_ext);
-                    maybe_wrong_ext = Some(WrongExtFile {
-                        path: dst_path.to_string_lossy().to_string(), // stage8 need it
-                        expected_ext: file_infer_ext.to_string(),
-                    });
-                }
+            let processed = progress.record(&entry);
+            if (processed as u64).is_multiple_of(args.progress_every) {
+                pb.set_message(progress.to_string());
+                tracing::info!("{}", progress);
             }
-            match &op {
-                Op::Copy => {
-                    if dst_path.exists() && !args.overwrite {
-                        return Ok(maybe_wrong_ext);
-                    }
-                    fs::copy(&src_path, &dst_path).map_err(|e| {
-                        Stage15Error::IOError(src_path.clone(), dst_path.clone(), e.to_string())
-                    })?;
-                    return Ok(maybe_wrong_ext);
+            if let Some(window) = &error_window {
+                let mut window = window.lock().unwrap();
+                if window.record(error.is_some()) && !aborted.swap(true, Ordering::Relaxed) {
+                    tracing::error!(
+                        "{:.1}% of the last {} files failed, aborting",
+                        window.rate_percent(),
+                        window.window()
+                    );
                 }
-                Op::Move => fs::rename(&src_path, &dst_path).map_err(|e| {
-                    Stage15Error::IOError(src_path.clone(), dst_path.clone(), e.to_string())
-                })?,
             }
-            Ok(maybe_wrong_ext)
+            Some((entry, error))
         })
         .collect();
     pb.finish_with_message("Done!");
-    let (wrong_ext_files, failed_res): (Vec<WrongExtFile>, Vec<Stage15Error>) = res
-        .into_iter()
-        .fold((Vec::new(), Vec::new()), |(mut wrong, mut error), r| {
-            if let Ok(Some(w)) = r {
-                wrong.push(w);
-            } else if let Err(e) = r {
-                error.push(e);
-            }
-            (wrong, error)
-        });
+    tracing::info!("{}", progress);
+    let (manifest, failed_res): (Vec<ManifestEntry>, Vec<Stage15Error>) = res.into_iter().fold(
+        (Vec::new(), Vec::new()),
+        |(mut entries, mut error), (entry, e)| {
+            entries.push(entry);
+            error.extend(e);
+            (entries, error)
+        },
+    );
+    let wrong_ext_files: Vec<WrongExtFile> = match args.dry_run {
+        true => Vec::new(),
+        false => manifest
+            .iter()
+            .filter_map(ManifestEntry::wrong_ext)
+            .collect(),
+    };
+    let manifest_path = args.manifest.clone().unwrap_or_else(|| {
+        let timestamp = chrono::Utc::now().format("%Y%m%d%H%M%S").to_string();
+        PathBuf::from(format!("stage15_manifest_{}.jsonl", timestamp))
+    });
+    write_manifest(&manifest_path, &manifest)?;
+    tracing::info!(
+        "Manifest of {} files saved to {}",
+        manifest.len(),
+        manifest_path.display()
+    );
     if !failed_res.is_empty() {
         let timestamp = chrono::Utc::now().format("%Y%m%d%H%M%S").to_string();
         let name = format!("stage15_failed_files_{}.json", timestamp);
@@ -174,9 +447,7 @@ fn main() -> anyhow::Result<()> {
             failed_res.len(),
             &name
         );
-        let f = serde_json::to_string(&failed_res)?;
-        let mut file = fs::File::create(&name)?;
-        file.write_all(f.as_bytes())?;
+        atomic_write(&name, serde_json::to_string(&failed_res)?)?;
     }
     if !wrong_ext_files.is_empty() {
         let timestamp = chrono::Utc::now().format("%Y%m%d%H%M%S").to_string();
@@ -186,9 +457,15 @@ fn main() -> anyhow::Result<()> {
             wrong_ext_files.len(),
             &name
         );
-        let f = serde_json::to_string(&wrong_ext_files)?;
-        let mut file = fs::File::create(&name)?;
-        file.write_all(f.as_bytes())?;
+        atomic_write(&name, serde_json::to_string(&wrong_ext_files)?)?;
+    }
+    if aborted.load(Ordering::Relaxed) {
+        anyhow::bail!(
+            "Aborted after {} of {} files, the error rate exceeded {}%",
+            manifest.len(),
+            files_len,
+            args.fail_fast_threshold.unwrap_or_default()
+        );
     }
     tracing::info!(
         "Successfully processed {} files, which errors: {}",
@@ -197,3 +474,177 @@ fn main() -> anyhow::Result<()> {
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_ext() {
+        assert_eq!(source_ext(Path::new("a/b.png")), "png");
+        assert_eq!(source_ext(Path::new("a/b")), "");
+        assert_eq!(source_ext(Path::new("a/b.")), "");
+        assert_eq!(source_ext(Path::new("a.v2/b")), "");
+        assert_eq!(source_ext(Path::new(".hidden")), "");
+    }
+
+    #[test]
+    fn test_build_dst_path() {
+        let id = Uuid::nil();
+        assert_eq!(
+            build_dst_path(Path::new("out.v2"), &id, "png"),
+            Path::new("out.v2").join(format!("{}.png", id))
+        );
+        assert_eq!(
+            build_dst_path(Path::new("out.v2/"), &id, ""),
+            Path::new("out.v2").join(id.to_string())
+        );
+    }
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    fn args(src: &Path, dst: &Path, extra: &[&str]) -> Args {
+        let mut argv = vec![
+            "stage15".to_string(),
+            format!("--src-paths={}", src.display()),
+            format!("--dst-path={}", dst.display()),
+        ];
+        argv.extend(extra.iter().map(|arg| arg.to_string()));
+        Args::parse_from(argv)
+    }
+
+    #[test]
+    fn test_process_file_dry_run() {
+        let dir = std::env::temp_dir().join(format!("stage15_{}", Uuid::new_v4()));
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        let file = src.join("a.jpg");
+        fs::write(&file, PNG).unwrap();
+        let neko_uuid = NekoUuid::new();
+        let id = neko_uuid.generate(PNG);
+        let expected_dst = build_dst_path(&dst, &id, "png");
+
+        let dry = args(&src, &dst, &["--move", "--dry-run"]);
+        let entry = process_file(&file, &dry, Op::Move, &neko_uuid, &HashMap::new()).unwrap();
+        assert_eq!(entry.outcome, Outcome::WouldMove);
+        assert_eq!(entry.dst.as_deref(), Some(expected_dst.as_path()));
+        assert_eq!(entry.renamed_ext.as_deref(), Some("png"));
+        assert!(file.exists() && !expected_dst.exists());
+
+        // the real run makes the decision the dry run announced
+        let entry = process_file(
+            &file,
+            &args(&src, &dst, &[]),
+            Op::Copy,
+            &neko_uuid,
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(entry.outcome, Outcome::Copied);
+        assert_eq!(fs::read(&expected_dst).unwrap(), PNG);
+        let wrong_ext = entry.wrong_ext().unwrap();
+        assert_eq!(
+            (wrong_ext.path.as_str(), wrong_ext.expected_ext.as_str()),
+            (expected_dst.to_str().unwrap(), "png")
+        );
+        let entry = process_file(&file, &dry, Op::Copy, &neko_uuid, &HashMap::new()).unwrap();
+        assert_eq!(entry.outcome, Outcome::SkippedIdentical);
+
+        let missing = src.join("missing.png");
+        let err = process_file(&missing, &dry, Op::Copy, &neko_uuid, &HashMap::new()).unwrap_err();
+        let failed = ManifestEntry::failed(missing, &err);
+        let manifest = dir.join("manifest.jsonl");
+        write_manifest(&manifest, &[entry.clone(), failed.clone()]).unwrap();
+        let lines: Vec<ManifestEntry> = fs::read_to_string(&manifest)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, [entry, failed]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_same_content() {
+        let dir = std::env::temp_dir().join(format!("stage15_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..3 * COMPARE_EDGE).map(|i| (i % 251) as u8).collect();
+        let dst = dir.join("dst");
+        fs::write(&dst, &contents).unwrap();
+        assert!(same_content(&dst, &contents).unwrap());
+        assert!(!same_content(&dst, &contents[1..]).unwrap());
+        let mut tail = contents.clone();
+        *tail.last_mut().unwrap() ^= 1;
+        assert!(!same_content(&dst, &tail).unwrap());
+        fs::write(&dst, PNG).unwrap();
+        assert!(same_content(&dst, PNG).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_process_file_rerun() {
+        let dir = std::env::temp_dir().join(format!("stage15_{}", Uuid::new_v4()));
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        let file = src.join("a.png");
+        fs::write(&file, PNG).unwrap();
+        let neko_uuid = NekoUuid::new();
+        let expected_dst = build_dst_path(&dst, &neko_uuid.generate(PNG), "png");
+        let no_moves = HashMap::new();
+
+        // output of an earlier run, a copy leaves the source in place and a move removes it
+        fs::write(&expected_dst, PNG).unwrap();
+        for (op, extra, kept) in [
+            (Op::Copy, &[][..], true),
+            (Op::Move, &["--move", "--dry-run"][..], true),
+            (Op::Move, &["--move"][..], false),
+        ] {
+            let entry =
+                process_file(&file, &args(&src, &dst, extra), op, &neko_uuid, &no_moves).unwrap();
+            assert_eq!(entry.outcome, Outcome::SkippedIdentical);
+            assert_eq!(file.exists(), kept);
+        }
+        assert_eq!(fs::read(&expected_dst).unwrap(), PNG);
+        fs::write(&file, PNG).unwrap();
+
+        // something else under the name, neither side is touched unless overwriting
+        fs::write(&expected_dst, b"partial").unwrap();
+        let moving = args(&src, &dst, &["--move"]);
+        let entry = process_file(&file, &moving, Op::Move, &neko_uuid, &no_moves).unwrap();
+        assert_eq!(entry.outcome, Outcome::DestinationConflict);
+        assert!(entry.error.is_some() && entry.wrong_ext().is_none());
+        assert_eq!(fs::read(&expected_dst).unwrap(), b"partial");
+        assert!(file.exists());
+
+        let overwriting = args(&src, &dst, &["--move", "--overwrite"]);
+        let entry = process_file(&file, &overwriting, Op::Move, &neko_uuid, &no_moves).unwrap();
+        assert_eq!(
+            (entry.outcome, entry.len),
+            (Outcome::Moved, Some(PNG.len() as u64))
+        );
+        assert_eq!(fs::read(&expected_dst).unwrap(), PNG);
+        assert!(!file.exists());
+
+        // moved onto itself, e.g. the destination listed among the sources
+        let in_place = args(&dst, &dst, &["--move"]);
+        assert!(list_files(&in_place, Op::Move, &no_moves).is_empty());
+        let kept = process_file(&expected_dst, &in_place, Op::Move, &neko_uuid, &no_moves).unwrap();
+        assert_eq!(kept.outcome, Outcome::SkippedIdentical);
+        assert_eq!(fs::read(&expected_dst).unwrap(), PNG);
+        let nested = args(&dir, &dst, &["--move"]);
+        assert!(!list_files(&nested, Op::Move, &no_moves).contains(&expected_dst));
+
+        // the source is gone, the earlier manifest says where to
+        assert!(process_file(&file, &moving, Op::Move, &neko_uuid, &no_moves).is_err());
+        let moved = moved_files(vec![entry]);
+        let entry = process_file(&file, &moving, Op::Move, &neko_uuid, &moved).unwrap();
+        assert_eq!(entry.outcome, Outcome::SkippedIdentical);
+        assert_eq!(entry.dst.as_deref(), Some(expected_dst.as_path()));
+        assert!(process_file(&file, &args(&src, &dst, &[]), Op::Copy, &neko_uuid, &moved).is_err());
+        fs::write(&expected_dst, b"partial").unwrap();
+        assert!(process_file(&file, &moving, Op::Move, &neko_uuid, &moved).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+//! `stage15_manifest_<timestamp>.jsonl`, one line per source file with where it went or would go
+
+use serde::{Deserialize, Serialize};
+use shared::atomic_write::atomic_write_with;
+use shared::structure::WrongExtFile;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Copied,
+    Moved,
+    /// Copy mode without `--overwrite` and the destination exists, written by runs that did not
+    /// compare the destination yet
+    SkippedExisting,
+    /// The destination already holds the content, or a source gone in move mode is at the
+    /// destination an earlier run moved it to
+    SkippedIdentical,
+    /// The destination exists with other content and was left as is, see `--overwrite`
+    DestinationConflict,
+    /// `--dry-run`, nothing was touched
+    WouldCopy,
+    WouldMove,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub src: PathBuf,
+    /// `None` when the file failed before its destination was known
+    pub dst: Option<PathBuf>,
+    /// Inferred extension the destination got instead of the source one
+    pub renamed_ext: Option<String>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    /// Length of the source, lets a later run check a moved file is still at its destination
+    #[serde(default)]
+    pub len: Option<u64>,
+}
+
+impl ManifestEntry {
+    pub fn failed(src: PathBuf, error: &impl ToString) -> Self {
+        Self {
+            src,
+            dst: None,
+            renamed_ext: None,
+            outcome: Outcome::Failed,
+            error: Some(error.to_string()),
+            len: None,
+        }
+    }
+
+    /// Entry of the wrong-ext list stage8 reads, for renamed destinations holding the source
+    pub fn wrong_ext(&self) -> Option<WrongExtFile> {
+        if matches!(self.outcome, Outcome::DestinationConflict | Outcome::Failed) {
+            return None;
+        }
+        Some(WrongExtFile {
+            path: self.dst.as_ref()?.to_string_lossy().to_string(),
+            expected_ext: self.renamed_ext.clone()?,
+        })
+    }
+}
+
+pub fn read_manifest<P: AsRef<Path>>(path: P) -> io::Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Sources the `entries` of earlier runs moved, by source path
+pub fn moved_files(entries: Vec<ManifestEntry>) -> HashMap<PathBuf, ManifestEntry> {
+    entries
+        .into_iter()
+        .filter(|entry| entry.outcome == Outcome::Moved && entry.dst.is_some())
+        .map(|entry| (entry.src.clone(), entry))
+        .collect()
+}
+
+pub fn write_manifest<P: AsRef<Path>>(path: P, entries: &[ManifestEntry]) -> io::Result<()> {
+    atomic_write_with(path, |w| {
+        for entry in entries {
+            serde_json::to_writer(&mut *w, entry)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    })
+}
@@ -1,21 +1,24 @@
 use clap::Parser;
+use dashmap::DashMap;
+use image::DynamicImage;
 use image::imageops::FilterType;
-use image_hasher::HasherConfig;
+use image_hasher::{Hasher, HasherConfig, ImageHash};
 use indicatif::{ProgressBar, ProgressStyle};
 use mimalloc::MiMalloc;
 use rayon::iter::Either;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use shared::atomic_write::atomic_write_with;
+use shared::cluster_file::write_clusters;
+use shared::image_ext::open_image;
+use shared::logging::Logging;
 use shared::point_explorer::{PointExplorerBuilder, PointExplorerError};
 use shared::structure::{NekoPointExt, NekoPointExtResource};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use shared::uuid_set::UuidSet;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{env, fs};
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
 use uuid::Uuid;
 
 #[global_allocator]
@@ -25,6 +28,24 @@ static GLOBAL: MiMalloc = MiMalloc;
 struct Args {
     #[arg(short, long)]
     src_dir: PathBuf,
+    /// Hash pixels as stored, ignoring the EXIF orientation tag
+    #[arg(long, default_value = "false")]
+    no_exif_orientation: bool,
+    /// Store the smallest hash among the 0/90/180/270 rotations so rotated duplicates collide.
+    /// Costs 4x hashing time and lets unrelated images that only match after rotation cluster
+    #[arg(long, default_value = "false")]
+    rotation_invariant: bool,
+    /// Only files with these extensions (case-insensitive) are hashed, the rest are counted and skipped
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "jpg,jpeg,png,gif,webp,bmp,tif,tiff"
+    )]
+    extensions: Vec<String>,
+    /// Also write the points of every exact-hash group but its first, for stage17 --exclude-set:
+    /// they need no approximate search, the first one still finds the group's near duplicates
+    #[arg(long, default_value = "false")]
+    exact_hash_exclude: bool,
 }
 
 #[derive(Debug, thiserror::Error, Serialize, Deserialize)]
@@ -35,71 +56,170 @@ enum Stage16Error {
     ImageError(String),
     #[error("UUID Parse Error: {0}")]
     UUidError(String),
+    #[error("UUID {id} is the stem of several files: {paths:?}")]
+    StemCollision { id: Uuid, paths: Vec<String> },
     #[error("Point Explorer Error: {0}")]
     #[serde(skip)]
     PointExplorerError(#[from] PointExplorerError),
 }
 
-fn main() -> anyhow::Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new(
-        env::var("STDOUT_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-    ));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage16.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new(
-            env::var("FILE_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-        ));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
-    let args = Args::parse();
-    let all_files: Vec<PathBuf> = walkdir::WalkDir::new(&args.src_dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .map(|e| e.into_path())
-        .collect();
-    let hasher = HasherConfig::new()
+/// Lexicographically smallest hash over the four right-angle rotations
+fn canonical_rotation_hash(hasher: &Hasher, img: &DynamicImage) -> ImageHash {
+    [
+        hasher.hash_image(img),
+        hasher.hash_image(&img.rotate90()),
+        hasher.hash_image(&img.rotate180()),
+        hasher.hash_image(&img.rotate270()),
+    ]
+    .into_iter()
+    .min_by(|a, b| a.as_bytes().cmp(b.as_bytes()))
+    .unwrap()
+}
+
+fn hash_file(
+    hasher: &Hasher,
+    path: &Path,
+    apply_orientation: bool,
+    rotation_invariant: bool,
+) -> Result<ImageHash, Stage16Error> {
+    let img =
+        open_image(path, apply_orientation).map_err(|e| Stage16Error::ImageError(e.to_string()))?;
+    Ok(match rotation_invariant {
+        true => canonical_rotation_hash(hasher, &img),
+        false => hasher.hash_image(&img),
+    })
+}
+
+fn build_hasher() -> Hasher {
+    HasherConfig::new()
         .hash_alg(image_hasher::HashAlg::Median)
         .resize_filter(FilterType::Lanczos3)
         .preproc_dct()
         .hash_size(16, 16)
-        .to_hasher();
+        .to_hasher()
+}
+
+/// (id, hash bytes, source)
+type ScannedPoint = (Uuid, Vec<u8>, NekoPointExt);
+
+#[derive(Debug)]
+struct ScanOutcome {
+    points: Vec<ScannedPoint>,
+    errors: Vec<Stage16Error>,
+    skipped: usize,
+}
+
+fn normalize_extensions(extensions: &[String]) -> HashSet<String> {
+    extensions
+        .iter()
+        .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+fn list_files(src_dir: &Path) -> Vec<PathBuf> {
+    walkdir::WalkDir::new(src_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .collect()
+}
+
+/// Hashes every allowlisted file, points whose uuid stem shows up more than once are all dropped
+fn scan_files<F>(
+    files: Vec<PathBuf>,
+    allowed_exts: &HashSet<String>,
+    pb: &ProgressBar,
+    hash: F,
+) -> ScanOutcome
+where
+    F: Fn(&Path) -> Result<Vec<u8>, Stage16Error> + Sync,
+{
+    let (files, skipped): (Vec<PathBuf>, Vec<PathBuf>) = files.into_iter().partition(|file| {
+        file.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| allowed_exts.contains(&ext.to_ascii_lowercase()))
+    });
+    pb.inc(skipped.len() as u64);
+    let seen: DashMap<Uuid, Vec<String>> = DashMap::with_capacity(files.len());
+    let (points, mut errors): (Vec<ScannedPoint>, Vec<Stage16Error>) = files
+        .into_par_iter()
+        .map(|file| {
+            pb.inc(1);
+            let file_path = file
+                .to_str()
+                .ok_or_else(|| Stage16Error::IoError("Invalid file path".to_string()))?;
+            let file_id = file
+                .file_stem()
+                .and_then(|os| os.to_str())
+                .ok_or_else(|| Stage16Error::IoError("Invalid file stem".to_string()))?;
+            let file_id = Uuid::from_str(file_id)
+                .map_err(|_| Stage16Error::UUidError(file_id.to_string()))?;
+            seen.entry(file_id)
+                .or_default()
+                .push(String::from(file_path));
+            let hash = hash(&file)?;
+            let ext = NekoPointExt {
+                source: Some(NekoPointExtResource::Local(String::from(file_path))),
+            };
+            Ok((file_id, hash, ext))
+        })
+        .partition_map(|res: Result<ScannedPoint, Stage16Error>| match res {
+            Ok(v) => Either::Left(v),
+            Err(err) => Either::Right(err),
+        });
+    let collided: HashMap<Uuid, Vec<String>> = seen
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(id, mut paths)| {
+            paths.sort_unstable();
+            (id, paths)
+        })
+        .collect();
+    for (&id, paths) in collided.iter() {
+        tracing::warn!("UUID {} collides across {:?}, none of them kept", id, paths);
+    }
+    let points = points
+        .into_iter()
+        .filter(|(id, _, _)| !collided.contains_key(id))
+        .collect();
+    errors.extend(
+        collided
+            .into_iter()
+            .map(|(id, paths)| Stage16Error::StemCollision { id, paths }),
+    );
+    ScanOutcome {
+        points,
+        errors,
+        skipped: skipped.len(),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let _logging = Logging::new("stage16").init()?;
+    let args = Args::parse();
+    let all_files = list_files(&args.src_dir);
+    let allowed_exts = normalize_extensions(&args.extensions);
+    let hasher = build_hasher();
     let pb = ProgressBar::new(all_files.len() as u64);
     let style = ProgressStyle::default_bar()
         .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
     pb.set_style(style);
     pb.set_message("Working...");
-    // HashMap<Uuid, NekoPointExt>
-    let (final_res_ok, final_res_err): (Vec<(Uuid, Vec<u8>, NekoPointExt)>, Vec<Stage16Error>) =
-        all_files
-            .into_par_iter()
-            .map(|file| {
-                pb.inc(1);
-                let file_path = file
-                    .to_str()
-                    .ok_or_else(|| Stage16Error::IoError("Invalid file path".to_string()))?;
-                let file_id = file
-                    .file_stem()
-                    .and_then(|os| os.to_str())
-                    .ok_or_else(|| Stage16Error::IoError("Invalid file stem".to_string()))?;
-                let file_id = Uuid::from_str(file_id)
-                    .map_err(|_| Stage16Error::UUidError(file_id.to_string()))?;
-                let img =
-                    image::open(&file).map_err(|e| Stage16Error::ImageError(e.to_string()))?;
-                let hash = hasher.hash_image(&img);
-                let ext = NekoPointExt {
-                    source: Some(NekoPointExtResource::Local(String::from(file_path))),
-                };
-                Ok((file_id, hash.as_bytes().to_vec(), ext))
-            })
-            .partition_map(
-                |res: Result<(Uuid, Vec<u8>, NekoPointExt), Stage16Error>| match res {
-                    Ok(v) => Either::Left(v),
-                    Err(err) => Either::Right(err),
-                },
-            );
+    let ScanOutcome {
+        points: final_res_ok,
+        errors: final_res_err,
+        skipped,
+    } = scan_files(all_files, &allowed_exts, &pb, |file| {
+        hash_file(
+            &hasher,
+            file,
+            !args.no_exif_orientation,
+            args.rotation_invariant,
+        )
+        .map(|hash| hash.as_bytes().to_vec())
+    });
     let (final_res_size, final_err_size) = (final_res_ok.len(), final_res_err.len());
     let mut point_explorer = PointExplorerBuilder::new()
         .capacity(final_res_ok.len())
@@ -112,9 +232,10 @@ fn main() -> anyhow::Result<()> {
     point_explorer.extend(point_pairs);
     pb.finish();
     tracing::info!(
-        "Processed {} files successfully, {} errors encountered",
+        "Processed {} files successfully, {} errors encountered, {} non-image files skipped",
         final_res_size,
-        final_err_size
+        final_err_size,
+        skipped
     );
     let timestamp = chrono::Utc::now().format("%Y%m%d%H%M%S").to_string();
     // serde ext_pairs to HashMap<Uuid, NekoPointExt>
@@ -133,6 +254,34 @@ fn main() -> anyhow::Result<()> {
             .map_err(|e| Stage16Error::IoError(e.to_string()))?;
         fs::write(&err_name, f.as_bytes()).map_err(|e| Stage16Error::IoError(e.to_string()))?;
     }
+    // points sharing the very same hash, no KNN needed to pair them
+    let exact_groups = point_explorer.exact_duplicate_groups();
+    tracing::info!(
+        "{} exact-hash duplicate groups covering {} points",
+        exact_groups.len(),
+        exact_groups.iter().map(Vec::len).sum::<usize>()
+    );
+    if !exact_groups.is_empty() {
+        let clusters: Vec<HashSet<Uuid>> = exact_groups
+            .iter()
+            .map(|ids| ids.iter().copied().collect())
+            .collect();
+        let clusters_name = format!("stage16_exact_hash_clusters_{}.pkl", timestamp);
+        atomic_write_with(&clusters_name, |w| write_clusters(w, &clusters))
+            .map_err(|e| Stage16Error::IoError(e.to_string()))?;
+    }
+    if args.exact_hash_exclude {
+        let exclude: UuidSet = exact_groups.iter().flat_map(|ids| &ids[1..]).collect();
+        let exclude_name = format!("stage16_exact_hash_exclude_{}.bin", timestamp);
+        exclude
+            .save(&exclude_name)
+            .map_err(|e| Stage16Error::IoError(e.to_string()))?;
+        tracing::info!(
+            "{} points to exclude written to {}",
+            exclude.len(),
+            exclude_name
+        );
+    }
     // final
     let pe_name = format!("stage16_point_explorer_{}.bin", timestamp);
     point_explorer
@@ -140,3 +289,98 @@ fn main() -> anyhow::Result<()> {
         .map_err(|e| Stage16Error::PointExplorerError(e))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::metadata::Orientation;
+    use image::{Rgb, RgbImage};
+    use shared::image_ext::set_jpeg_orientation;
+
+    fn encode(img: &RgbImage) -> Vec<u8> {
+        let mut out = Vec::new();
+        JpegEncoder::new_with_quality(&mut out, 95)
+            .encode_image(img)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_exif_orientation_hash() {
+        let img = RgbImage::from_fn(128, 64, |x, y| {
+            Rgb([(x * 2) as u8, ((x + y) % 64 * 4) as u8, (y * 4) as u8])
+        });
+        let rotated = DynamicImage::ImageRgb8(img.clone()).rotate90().to_rgb8();
+        let dir = std::env::temp_dir();
+        let tagged_path = dir.join(format!("{}.jpg", Uuid::new_v4()));
+        let rotated_path = dir.join(format!("{}.jpg", Uuid::new_v4()));
+        fs::write(
+            &tagged_path,
+            set_jpeg_orientation(&encode(&img), Orientation::Rotate90).unwrap(),
+        )
+        .unwrap();
+        fs::write(&rotated_path, encode(&rotated)).unwrap();
+        let hasher = build_hasher();
+        let upright = hash_file(&hasher, &rotated_path, true, false).unwrap();
+        let corrected = hash_file(&hasher, &tagged_path, true, false).unwrap();
+        let raw = hash_file(&hasher, &tagged_path, false, false).unwrap();
+        assert!(upright.dist(&corrected) <= 16);
+        assert!(upright.dist(&raw) > 48);
+        fs::remove_file(tagged_path).unwrap();
+        fs::remove_file(rotated_path).unwrap();
+    }
+
+    #[test]
+    fn test_scan_files_collisions_and_junk() {
+        let dir = std::env::temp_dir().join(format!("stage16_{}", Uuid::new_v4()));
+        let (dup, unique) = (Uuid::new_v4(), Uuid::new_v4());
+        for sub in ["a", "b"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let dup_a = dir.join("a").join(format!("{}.png", dup));
+        let dup_b = dir.join("b").join(format!("{}.JPG", dup));
+        fs::write(&dup_a, b"a").unwrap();
+        fs::write(&dup_b, b"b").unwrap();
+        fs::write(dir.join("a").join(format!("{}.webp", unique)), b"c").unwrap();
+        fs::write(dir.join("b").join("notes.txt"), b"junk").unwrap();
+        fs::write(dir.join(format!("{}.json", unique)), b"{}").unwrap();
+
+        let files = list_files(&dir);
+        assert_eq!(files.len(), 5);
+        let allowed =
+            normalize_extensions(&["png".to_string(), ".jpg".to_string(), "webp".to_string()]);
+        let outcome = scan_files(files, &allowed, &ProgressBar::hidden(), |file| {
+            fs::read(file).map_err(|e| Stage16Error::IoError(e.to_string()))
+        });
+        assert_eq!(outcome.skipped, 2);
+        assert_eq!(outcome.points.len(), 1);
+        assert_eq!(outcome.points[0].0, unique);
+        assert_eq!(outcome.errors.len(), 1);
+        match &outcome.errors[0] {
+            Stage16Error::StemCollision { id, paths } => {
+                assert_eq!(*id, dup);
+                let mut expected = vec![
+                    dup_a.to_str().unwrap().to_string(),
+                    dup_b.to_str().unwrap().to_string(),
+                ];
+                expected.sort_unstable();
+                assert_eq!(paths, &expected);
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_canonical_rotation_hash() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([(x * 4) as u8, (y * 5) as u8, ((x * y) % 256) as u8])
+        }));
+        let hasher = build_hasher();
+        let canonical = canonical_rotation_hash(&hasher, &img);
+        for rotated in [img.rotate90(), img.rotate180(), img.rotate270()] {
+            assert_eq!(canonical_rotation_hash(&hasher, &rotated), canonical);
+        }
+    }
+}
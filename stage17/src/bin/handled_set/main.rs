@@ -0,0 +1,42 @@
+use anyhow::Result;
+use clap::Parser;
+use shared::atomic_write::atomic_write;
+use shared::structure::FinalClassification;
+use shared::uuid_set::UuidSet;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(about = "Collect the points earlier stage9 rounds decided on, for stage17 --exclude-set")]
+struct Args {
+    /// final_classification.json files of the previous rounds
+    #[arg(required = true)]
+    classifications: Vec<PathBuf>,
+    #[arg(short, long, default_value = "handled_set.bin")]
+    output: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut handled: HashSet<uuid::Uuid> = HashSet::new();
+    for path in &args.classifications {
+        let entries: Vec<FinalClassification> = serde_json::from_slice(&fs::read(path)?)?;
+        let before = handled.len();
+        handled.extend(entries.iter().flat_map(FinalClassification::handled));
+        println!(
+            "{}: {} entries, {} points not in an earlier file",
+            path.display(),
+            entries.len(),
+            handled.len() - before
+        );
+    }
+    let set: UuidSet = handled.into_iter().collect();
+    atomic_write(&args.output, set.as_bytes())?;
+    println!(
+        "Saved {} handled points to {}",
+        set.len(),
+        args.output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use chrono::{DateTime, Utc};
+use hnsw_rs::prelude::*;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use shared::hnsw::{HnswIdMap, adaptive_search_live};
+use shared::opendal::{Entry, EntryMode};
+use shared::point_explorer::PointExplorer;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use uuid::Uuid;
+
+/// Most points have fewer than 5 neighbors under the cap
+pub const KNN_INITIAL_K: usize = 16;
+/// Dense meme clusters hold hundreds of near-identical members
+pub const KNN_MAX_K: usize = 3200;
+pub const KNN_EF_FACTOR: f32 = 2.5;
+
+/// Points whose object was modified after `since`, objects without a modification time are not new
+pub fn new_since(entries: &[Entry], since: DateTime<Utc>) -> HashSet<Uuid> {
+    entries
+        .iter()
+        .filter(|e| e.metadata.mode == EntryMode::FILE)
+        .filter(|e| e.metadata.last_modified.is_some_and(|t| t > since))
+        .filter_map(|e| Uuid::parse_str(e.to_point()).ok())
+        .collect()
+}
+
+/// Points worth a search: not excluded and, given `only`, listed there
+pub fn query_ids<'a>(
+    point_explorer: &'a PointExplorer<u8, 32>,
+    exclude: &HashSet<Uuid>,
+    only: Option<&HashSet<Uuid>>,
+) -> Vec<&'a Uuid> {
+    point_explorer
+        .iter()
+        .map(|(id, _)| id)
+        .filter(|id| !exclude.contains(id))
+        .filter(|id| only.is_none_or(|only| only.contains(id)))
+        .collect()
+}
+
+/// Every query with a neighbor under `max_distance` that is not an `excluded` point id, together
+/// with those neighbors, and the number of queries still saturated at [`KNN_MAX_K`]
+pub fn knn_candidates<'a>(
+    hnsw: &Hnsw<u8, DistHamming>,
+    point_explorer: &PointExplorer<u8, 32>,
+    ids: &'a HnswIdMap,
+    queries: &[&Uuid],
+    excluded: &HashSet<usize>,
+    max_distance: f32,
+    pb: &ProgressBar,
+) -> (HashSet<&'a Uuid>, usize) {
+    let truncated = AtomicUsize::new(0);
+    let points_knn_set = queries
+        .par_iter()
+        .flat_map(|id| {
+            pb.inc(1);
+            let id_index = ids.point_id(id).expect("point not indexed");
+            let vec = point_explorer.get_vector(id).expect("point not found");
+            let res = adaptive_search_live(
+                hnsw,
+                vec,
+                max_distance,
+                KNN_INITIAL_K,
+                KNN_MAX_K,
+                KNN_EF_FACTOR,
+                ids,
+            );
+            if res.max_k_hit {
+                truncated.fetch_add(1, Ordering::Relaxed);
+            }
+            let mut found: Vec<&Uuid> = res
+                .neighbors
+                .iter()
+                .map(|n| n.point_id())
+                .filter(|&idx| idx != id_index && !excluded.contains(&idx))
+                .map(|idx| ids.uuid(idx).unwrap())
+                .collect();
+            // a new point is as much a candidate as what it is near
+            if !found.is_empty() {
+                found.push(ids.uuid(id_index).unwrap());
+            }
+            found
+        })
+        .collect::<HashSet<&Uuid>>();
+    (points_knn_set, truncated.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::config::DEFAULT_HAMMING_CUTOFF;
+    use shared::opendal::Metadata;
+
+    /// Two tight clusters, 1..=4 and 5..=7, and the isolated 8
+    fn explorer() -> PointExplorer<u8, 32> {
+        let point = |id: u128, base: u8, variant: u8| {
+            let mut v = [base; 32];
+            v[0] = variant;
+            (Uuid::from_u128(id), v)
+        };
+        let points = [
+            point(1, 10, 0),
+            point(2, 10, 1),
+            point(3, 10, 2),
+            point(4, 10, 3),
+            point(5, 20, 0),
+            point(6, 20, 1),
+            point(7, 20, 2),
+            point(8, 30, 0),
+        ];
+        let mut explorer = PointExplorer::default();
+        explorer.extend(points.iter().map(|(id, v)| (id, v)));
+        explorer
+    }
+
+    fn index(explorer: &PointExplorer<u8, 32>) -> Hnsw<'static, u8, DistHamming> {
+        let vecs: Vec<Vec<u8>> = explorer.iter().map(|(_, v)| v.to_vec()).collect();
+        let data: Vec<(&Vec<u8>, usize)> = vecs.iter().zip(0..).collect();
+        let mut hnsw = Hnsw::new(16, data.len(), 16, 200, DistHamming);
+        hnsw.parallel_insert(&data);
+        hnsw.set_searching_mode(true);
+        hnsw
+    }
+
+    fn set(ids: &[u128]) -> HashSet<Uuid> {
+        ids.iter().copied().map(Uuid::from_u128).collect()
+    }
+
+    fn found(
+        hnsw: &Hnsw<u8, DistHamming>,
+        explorer: &PointExplorer<u8, 32>,
+        exclude: &HashSet<Uuid>,
+        only: Option<&HashSet<Uuid>>,
+    ) -> HashSet<Uuid> {
+        let ids = HnswIdMap::from_ids(explorer.iter().map(|(id, _)| id));
+        let queries = query_ids(explorer, exclude, only);
+        let excluded: HashSet<usize> = exclude.iter().filter_map(|id| ids.point_id(id)).collect();
+        let (res, truncated) = knn_candidates(
+            hnsw,
+            explorer,
+            &ids,
+            &queries,
+            &excluded,
+            DEFAULT_HAMMING_CUTOFF,
+            &ProgressBar::hidden(),
+        );
+        assert_eq!(truncated, 0);
+        res.into_iter().copied().collect()
+    }
+
+    #[test]
+    fn test_query_ids() {
+        let explorer = explorer();
+        let ids = |q: Vec<&Uuid>| q.into_iter().copied().collect::<HashSet<_>>();
+        let exclude = set(&[1, 2, 3, 4, 5]);
+        assert_eq!(ids(query_ids(&explorer, &exclude, None)), set(&[6, 7, 8]));
+        let only = set(&[5, 6, 9]);
+        assert_eq!(ids(query_ids(&explorer, &exclude, Some(&only))), set(&[6]));
+        assert_eq!(
+            ids(query_ids(&explorer, &HashSet::new(), None)),
+            set(&[1, 2, 3, 4, 5, 6, 7, 8])
+        );
+    }
+
+    #[test]
+    fn test_knn_candidates_exclusion() {
+        let explorer = explorer();
+        let hnsw = index(&explorer);
+        let none = HashSet::new();
+        assert_eq!(
+            found(&hnsw, &explorer, &none, None),
+            set(&[1, 2, 3, 4, 5, 6, 7])
+        );
+        // the first cluster was handled entirely, 5 of the second one
+        let exclude = set(&[1, 2, 3, 4, 5]);
+        assert_eq!(found(&hnsw, &explorer, &exclude, None), set(&[6, 7]));
+        assert_eq!(
+            found(&hnsw, &explorer, &exclude, Some(&set(&[6]))),
+            set(&[6, 7])
+        );
+        assert_eq!(found(&hnsw, &explorer, &exclude, Some(&set(&[8]))), none);
+        // a new point next to handled ones only finds what is left
+        assert_eq!(
+            found(&hnsw, &explorer, &set(&[2, 3, 4]), Some(&set(&[1]))),
+            none
+        );
+    }
+
+    #[test]
+    fn test_new_since() {
+        let since: DateTime<Utc> = "2025-06-11T00:00:00Z".parse().unwrap();
+        let entry = |id: u128, mode: EntryMode, last_modified: Option<&str>| Entry {
+            path: format!("img/{}.png", Uuid::from_u128(id)),
+            metadata: Metadata {
+                mode,
+                is_current: None,
+                is_deleted: false,
+                cache_control: None,
+                content_disposition: None,
+                content_length: Some(1),
+                content_md5: None,
+                content_range: None,
+                content_type: None,
+                content_encoding: None,
+                etag: None,
+                last_modified: last_modified.map(|t| t.parse().unwrap()),
+                version: None,
+                user_metadata: None,
+            },
+        };
+        let entries = [
+            entry(1, EntryMode::FILE, Some("2025-06-12T08:00:00Z")),
+            entry(2, EntryMode::FILE, Some("2025-06-10T08:00:00Z")),
+            entry(3, EntryMode::FILE, Some("2025-06-11T00:00:00Z")),
+            entry(4, EntryMode::FILE, None),
+            entry(5, EntryMode::DIR, Some("2025-06-12T08:00:00Z")),
+        ];
+        assert_eq!(new_since(&entries, since), set(&[1]));
+    }
+}
@@ -1,21 +1,132 @@
+mod candidates;
+mod threshold;
+
+use crate::candidates::{KNN_MAX_K, knn_candidates, new_since, query_ids};
+use crate::threshold::{
+    DistanceStats, ThresholdPoint, label_pairs, precision_recall, sample_indices, suggest_threshold,
+};
+use chrono::{DateTime, Utc};
+use clap::Parser;
 use hnsw_rs::prelude::*;
 use indicatif::{ProgressBar, ProgressStyle};
 use mimalloc::MiMalloc;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use shared::atomic_write::atomic_write_with;
+use shared::config::Thresholds;
+use shared::hnsw::{HnswIdMap, HnswIndex, HnswMetaError, HnswStorage, search_live};
+use shared::knn_dump::KnnDumpWriter;
+use shared::logging::Logging;
+use shared::opendal::load_entry_list;
 use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
+use shared::uuid_set::UuidSet;
 use std::collections::HashSet;
 use std::env;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::EnvFilter;
-use tracing_subscriber::prelude::*;
 use uuid::Uuid;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+#[derive(Parser)]
+#[command(name = "stage17", version)]
+struct Cli {
+    /// Points already handled, never queried nor reported as neighbors: a `UuidSet` or a pickled
+    /// `HashSet<Uuid>`, see the `handled-set` bin and stage16 `--exact-hash-exclude`. Repeatable,
+    /// the sets are merged
+    #[arg(long, value_delimiter = ',')]
+    exclude_set: Vec<PathBuf>,
+    /// stage5 listing, only points whose object was modified after `--since` are queried
+    #[arg(long, requires = "since")]
+    only_new_since: Option<PathBuf>,
+    /// RFC 3339, e.g. 2025-06-11T00:00:00Z
+    #[arg(long, requires = "only_new_since")]
+    since: Option<DateTime<Utc>>,
+    /// stage0 float point explorer, also builds a cosine index over it for text search (see the
+    /// stage9 `search` bin), kept if `--float-index-basename` already exists in `--output-dir`
+    #[arg(long)]
+    float_point_map: Option<String>,
+    #[arg(long, default_value = "768")]
+    float_dim: usize,
+    #[arg(long, default_value = "stage17_hnsw_f32")]
+    float_index_basename: String,
+    /// Directory the float index is saved to, and looked for in
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
+    /// threshold mode: points sampled as queries
+    #[arg(long, default_value = "2000")]
+    sample_size: usize,
+    #[arg(long, default_value = "42")]
+    sample_seed: u64,
+    /// threshold mode: neighbors per query, the nearest is reported apart from the others
+    #[arg(long, default_value = "10")]
+    sample_k: usize,
+    /// threshold mode: JSON array of uuid groups, each group is one true cluster (see the stage14
+    /// `cluster-eval` bin), enables the precision/recall curve
+    #[arg(long)]
+    ground_truth: Option<PathBuf>,
+    /// threshold mode: precision the suggested threshold must reach on the labeled pairs
+    #[arg(long, default_value = "0.95")]
+    target_precision: f32,
+    /// Bring the index at `STAGE17_HNSW_BASENAME` up to date with the point map instead of
+    /// refusing one that differs: the added points are inserted, the removed ones tombstoned, and
+    /// the result saved back under the same basename. Indices dumped without an id map are
+    /// rebuilt once they no longer match the point map
+    #[arg(long)]
+    maintain: bool,
+    /// Insert the points of the point map the index at `STAGE17_HNSW_BASENAME` misses and save it
+    /// back under the same basename, for nightly runs. Removed points are left live until the
+    /// next `--maintain`, indices dumped without an id map are refused
+    #[arg(long, conflicts_with = "maintain")]
+    append: bool,
+    /// tune mode: points sampled as queries, each is also searched exhaustively
+    #[arg(long, default_value = "200")]
+    tune_sample_size: usize,
+    /// tune mode: ef values swept
+    #[arg(long, value_delimiter = ',', default_value = "50,100,200,500,1000")]
+    tune_ef: Vec<usize>,
+    /// tune mode: k values swept
+    #[arg(long, value_delimiter = ',', default_value = "10,50,200")]
+    tune_k: Vec<usize>,
+    /// Extra neighbors searched per query to make up for tombstones
+    #[arg(long, default_value = "16")]
+    over_fetch: usize,
+}
+
+/// Which points the knn mode queries and reports
+#[derive(Default)]
+struct KnnFilter {
+    exclude: HashSet<Uuid>,
+    only: Option<HashSet<Uuid>>,
+}
+
+impl KnnFilter {
+    fn from_cli(cli: &Cli) -> anyhow::Result<Self> {
+        let mut exclude = HashSet::new();
+        for path in &cli.exclude_set {
+            let set = UuidSet::load_any(path)?;
+            tracing::info!("{} points excluded by {}", set.len(), path.display());
+            exclude.extend(set.iter());
+        }
+        let only = match (&cli.only_new_since, cli.since) {
+            (Some(listing), Some(since)) => {
+                let (entries, _) = load_entry_list(listing)?;
+                let new = new_since(&entries, since);
+                tracing::info!(
+                    "{} of {} listed objects are newer than {}",
+                    new.len(),
+                    entries.len(),
+                    since
+                );
+                Some(new)
+            }
+            _ => None,
+        };
+        Ok(KnnFilter { exclude, only })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct SearchResult {
     uri: String,
@@ -25,7 +136,9 @@ struct SearchResult {
 fn hnsw_query(
     hnsw: &Hnsw<u8, DistHamming>,
     point_explorer: &PointExplorer<u8, 32>,
+    ids: &HnswIdMap,
     query_ids: &[&str],
+    over_fetch: usize,
 ) -> Vec<Vec<SearchResult>> {
     let queries: Vec<(&str, [u8; 32])> = query_ids
         .iter()
@@ -39,13 +152,13 @@ fn hnsw_query(
     for (id_str, query_vec) in queries {
         tracing::debug!("Querying for point id = {}", id_str);
         let mut result = Vec::new();
-        let neighbors = hnsw.search(&query_vec, 200, 500);
+        let neighbors = search_live(hnsw, &query_vec, 200, 500, ids, over_fetch);
         for n in neighbors {
-            let id = point_explorer.index2uuid(n.d_id).unwrap();
+            let id = ids.uuid(n.point_id()).unwrap();
             let uri = point_explorer.get_point_uri("url", id).unwrap_or_default();
             let res = SearchResult {
                 uri,
-                distance: n.distance,
+                distance: n.distance(),
             };
             tracing::debug!("Success query for id {}, res: {:?}", id_str, res);
             result.push(res);
@@ -58,11 +171,14 @@ fn hnsw_query(
 fn query(
     hnsw: &Hnsw<u8, DistHamming>,
     point_explorer: &PointExplorer<u8, 32>,
+    ids: &HnswIdMap,
+    over_fetch: usize,
 ) -> anyhow::Result<()> {
     // query sample
     let res = hnsw_query(
         &hnsw,
         &point_explorer,
+        ids,
         &[
             "fd1faa7e-d9e2-5712-913d-bb72ba7447cd", // you
             "b43f2ec7-b950-5259-9b19-e1656ce60213", // 24
@@ -71,6 +187,7 @@ fn query(
             "00008799-ad39-5f58-959b-12ae973421f7",
             "00068fdf-7c5a-57d9-b5c2-9a9845271f9d", // very simple
         ],
+        over_fetch,
     );
     // save res
     let res = serde_pickle::to_vec(&res, serde_pickle::SerOptions::default())?;
@@ -78,29 +195,50 @@ fn query(
     Ok(())
 }
 
-fn knn(hnsw: &Hnsw<u8, DistHamming>, point_explorer: &PointExplorer<u8, 32>) -> anyhow::Result<()> {
-    let all_ids: Vec<&Uuid> = point_explorer.iter().map(|(id, _)| id).collect();
-    let pb = ProgressBar::new(all_ids.len() as u64);
+fn knn(
+    hnsw: &Hnsw<u8, DistHamming>,
+    point_explorer: &PointExplorer<u8, 32>,
+    ids: &HnswIdMap,
+    filter: &KnnFilter,
+) -> anyhow::Result<()> {
+    // skipped before searching, the handled points are most of the collection after a few rounds
+    let queries = query_ids(point_explorer, &filter.exclude, filter.only.as_ref());
+    let excluded: HashSet<usize> = filter
+        .exclude
+        .iter()
+        .filter_map(|id| ids.point_id(id))
+        .collect();
+    tracing::info!(
+        "Querying {} of {} points, {} excluded",
+        queries.len(),
+        point_explorer.len(),
+        excluded.len()
+    );
+    let pb = ProgressBar::new(queries.len() as u64);
     let style = ProgressStyle::default_bar()
         .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
     pb.set_style(style);
     pb.set_message("Working...");
-    let points_knn_set = all_ids
-        .into_par_iter()
-        .flat_map(|id| {
-            pb.inc(1);
-            let id_index = point_explorer.uuid2index(id).expect("point not found");
-            let vec = point_explorer.get_vector(id).expect("point not found");
-            let neighbors = hnsw.search(vec, 200, 500);
-            neighbors
-                .iter()
-                .filter(|n| n.distance <= 0.625 && n.d_id != id_index) // filter by distance threshold
-                .map(|n| point_explorer.index2uuid(n.d_id).unwrap())
-                .collect::<Vec<_>>()
-        })
-        .collect::<HashSet<&Uuid>>();
+    let max_distance = Thresholds::init()?.hamming_cutoff;
+    let (points_knn_set, truncated) = knn_candidates(
+        hnsw,
+        point_explorer,
+        ids,
+        &queries,
+        &excluded,
+        max_distance,
+        &pb,
+    );
     pb.finish_with_message("KNN search completed");
     tracing::info!("Found {} unique points in KNN search", points_knn_set.len());
+    if truncated > 0 {
+        tracing::warn!(
+            "{} points still had every neighbor within {} at k = {}",
+            truncated,
+            max_distance,
+            KNN_MAX_K
+        );
+    }
     // save knn set
     let knn_set_path = PathBuf::from(format!(
         "stage17_knn_set_{}.pkl",
@@ -111,20 +249,240 @@ fn knn(hnsw: &Hnsw<u8, DistHamming>, point_explorer: &PointExplorer<u8, 32>) ->
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new(
-        env::var("STDOUT_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
+#[derive(Serialize)]
+struct ThresholdReport {
+    seed: u64,
+    queries: usize,
+    k: usize,
+    cutoff: f32,
+    nearest: DistanceStats,
+    /// 2nd to k-th neighbors
+    others: DistanceStats,
+    labeled_pairs: Option<usize>,
+    curve: Option<Vec<ThresholdPoint>>,
+    target_precision: f32,
+    suggested: Option<ThresholdPoint>,
+}
+
+/// Distance distributions of the neighbors of sampled points, and a cutoff reaching the target
+/// precision on the labeled pairs among them
+fn threshold(
+    hnsw: &Hnsw<u8, DistHamming>,
+    point_explorer: &PointExplorer<u8, 32>,
+    ids: &HnswIdMap,
+    cli: &Cli,
+) -> anyhow::Result<PathBuf> {
+    // tombstones drawn are skipped
+    let sample: Vec<(usize, Uuid)> = sample_indices(ids.len(), cli.sample_size, cli.sample_seed)
+        .into_iter()
+        .filter_map(|idx| Some((idx, *ids.uuid(idx)?)))
+        .collect();
+    let k = cli.sample_k.max(1);
+    let cutoff = Thresholds::init()?.hamming_cutoff;
+    tracing::info!(
+        "Searching {} neighbors of {} sampled points",
+        k,
+        sample.len()
+    );
+    let pairs: Vec<(Uuid, Uuid, f32, usize)> = sample
+        .par_iter()
+        .flat_map_iter(|&(idx, id)| {
+            let vec = point_explorer.get_vector(&id).expect("point not found");
+            search_live(hnsw, vec.as_slice(), k + 1, 500, ids, cli.over_fetch)
+                .into_iter()
+                .filter(move |n| n.point_id() != idx)
+                .take(k)
+                .enumerate()
+                .map(move |(rank, n)| (id, *ids.uuid(n.point_id()).unwrap(), n.distance(), rank))
+        })
+        .collect();
+    let (nearest, others): (Vec<_>, Vec<_>) = pairs.iter().partition(|p| p.3 == 0);
+    let distances = |pairs: Vec<&(Uuid, Uuid, f32, usize)>| -> Vec<f32> {
+        pairs.into_iter().map(|p| p.2).collect()
+    };
+    let mut report = ThresholdReport {
+        seed: cli.sample_seed,
+        queries: sample.len(),
+        k,
+        cutoff,
+        nearest: DistanceStats::new(&distances(nearest), cutoff),
+        others: DistanceStats::new(&distances(others), cutoff),
+        labeled_pairs: None,
+        curve: None,
+        target_precision: cli.target_precision,
+        suggested: None,
+    };
+    println!(
+        "Nearest neighbor median distance {:.4}, {:.1}% of nearest and {:.1}% of 2nd-{}th \
+         neighbors within the current cutoff {}",
+        report.nearest.quantiles.get(3).map_or(f32::NAN, |q| q.1),
+        report.nearest.under_cutoff * 100.0,
+        report.others.under_cutoff * 100.0,
+        k,
+        cutoff
+    );
+    if let Some(path) = &cli.ground_truth {
+        let groups: Vec<Vec<Uuid>> = serde_json::from_slice(&std::fs::read(path)?)?;
+        let pairs: Vec<(Uuid, Uuid, f32)> = pairs.iter().map(|p| (p.0, p.1, p.2)).collect();
+        let labeled = label_pairs(&groups, &pairs);
+        let curve = precision_recall(&labeled);
+        report.suggested = suggest_threshold(&curve, cli.target_precision).copied();
+        match &report.suggested {
+            Some(point) => println!(
+                "Recommended cutoff {:.4}: precision {:.3}, recall {:.3} on {} labeled pairs \
+                 (target precision {})",
+                point.threshold,
+                point.precision,
+                point.recall,
+                labeled.len(),
+                cli.target_precision
+            ),
+            None => println!(
+                "No cutoff reaches precision {} on {} labeled pairs",
+                cli.target_precision,
+                labeled.len()
+            ),
+        }
+        report.labeled_pairs = Some(labeled.len());
+        report.curve = Some(curve);
+    }
+    let path = PathBuf::from(format!(
+        "stage17_thresholds_{}.json",
+        chrono::Utc::now().timestamp()
     ));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage17.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new(
-            env::var("FILE_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-        ));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    atomic_write_with(&path, |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report)?)
+    })?;
+    Ok(path)
+}
+
+/// Recall and latency of the searches over `--tune-ef` x `--tune-k` against an exhaustive search
+/// from sampled live points
+fn tune(
+    index: &mut HnswIndex<u8, DistHamming>,
+    point_explorer: &PointExplorer<u8, 32>,
+    ids: &HnswIdMap,
+    cli: &Cli,
+) -> anyhow::Result<PathBuf> {
+    let live: Vec<(Vec<u8>, usize)> = (0..ids.len())
+        .filter_map(|idx| Some((point_explorer.get_vector(ids.uuid(idx)?)?.to_vec(), idx)))
+        .collect();
+    let data: Vec<(&Vec<u8>, usize)> = live.iter().map(|(v, idx)| (v, *idx)).collect();
+    let queries = sample_indices(data.len(), cli.tune_sample_size, cli.sample_seed);
+    tracing::info!(
+        "Sweeping ef {:?} and k {:?} from {} of {} live points",
+        cli.tune_ef,
+        cli.tune_k,
+        queries.len(),
+        data.len()
+    );
+    let report = index.tune(&data, &queries, &cli.tune_ef, &cli.tune_k);
+    for p in report.grid.iter() {
+        println!(
+            "k {:>4} ef {:>5}: recall {:.4}, {:.3} ms mean, {:.3} ms p99, k-th distance {:.4}",
+            p.k, p.ef_search, p.recall, p.latency_mean_ms, p.latency_p99_ms, p.kth_distance
+        );
+    }
+    let path = PathBuf::from(format!(
+        "stage17_tune_{}.json",
+        chrono::Utc::now().timestamp()
+    ));
+    atomic_write_with(&path, |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report)?)
+    })?;
+    Ok(path)
+}
+
+/// Builds a cosine index over the float explorer at `point_map` and saves it with its id map as
+/// `basename` in `dir`
+fn float_index(point_map: &str, dim: usize, dir: &Path, basename: &str) -> anyhow::Result<()> {
+    let graph = dir.join(format!("{}.hnsw.graph", basename));
+    let data = dir.join(format!("{}.hnsw.data", basename));
+    if graph.exists() && data.exists() {
+        tracing::info!(
+            "Float HNSW index {} already exists in {}",
+            basename,
+            dir.display()
+        );
+        return Ok(());
+    }
+    let point_explorer = PointExplorerBuilder::new()
+        .path(point_map)
+        .build_dyn::<f32>(dim)?;
+    let all_vecs: Vec<Vec<f32>> = point_explorer.iter().map(|(_, v)| v.clone()).collect();
+    let data: Vec<(&Vec<f32>, usize)> = all_vecs.iter().zip(0..).collect();
+    let mut index = HnswIndex::new(48, data.len(), 16, 600, DistCosine);
+    tracing::info!("Building float HNSW index with {} points", data.len());
+    index.insert(&data);
+    let ids = HnswIdMap::from_ids(point_explorer.iter().map(|(id, _)| id));
+    std::fs::create_dir_all(dir)?;
+    index.save(dir, basename, &ids)?;
+    tracing::info!("Saved float HNSW index to {}", dir.join(basename).display());
+    Ok(())
+}
+
+/// Streams the top-k neighbors (self excluded) of every point, in point id order, tombstones get
+/// empty records
+fn all_knn(
+    hnsw: &Hnsw<u8, DistHamming>,
+    point_explorer: &PointExplorer<u8, 32>,
+    ids: &HnswIdMap,
+    k: usize,
+    ef: usize,
+    over_fetch: usize,
+) -> anyhow::Result<PathBuf> {
+    const CHUNK_SIZE: usize = 4096;
+    let dump_path = PathBuf::from(format!(
+        "stage17_all_knn_{}.bin",
+        chrono::Utc::now().timestamp()
+    ));
+    let mut writer = KnnDumpWriter::create(&dump_path, k as u32, ids.ids().iter().copied())?;
+    let all_vecs: Vec<Option<&[u8; 32]>> = (0..ids.len())
+        .map(|idx| point_explorer.get_vector(ids.uuid(idx)?))
+        .collect();
+    let pb = ProgressBar::new(all_vecs.len() as u64);
+    let style = ProgressStyle::default_bar()
+        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
+    pb.set_style(style);
+    pb.set_message("Dumping all-KNN records...");
+    for (chunk_idx, chunk) in all_vecs.chunks(CHUNK_SIZE).enumerate() {
+        let base = chunk_idx * CHUNK_SIZE;
+        let records: Vec<Vec<(u32, f32)>> = chunk
+            .par_iter()
+            .enumerate()
+            .map(|(offset, vec)| match vec {
+                Some(vec) => search_live(hnsw, vec.as_slice(), k + 1, ef, ids, over_fetch)
+                    .into_iter()
+                    .filter(|n| n.point_id() != base + offset)
+                    .take(k)
+                    .map(|n| (n.point_id() as u32, n.distance()))
+                    .collect(),
+                None => Vec::new(),
+            })
+            .collect();
+        for (offset, neighbors) in records.iter().enumerate() {
+            writer.write_record((base + offset) as u32, neighbors)?;
+            pb.inc(1);
+        }
+    }
+    writer.finish()?;
+    pb.finish_with_message("All-KNN dump completed");
+    Ok(dump_path)
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let _logging = Logging::new("stage17").init()?;
+    let thresholds = Thresholds::init()?;
+    tracing::info!("Similarity thresholds {:?}", thresholds);
+    if let Some(point_map) = &cli.float_point_map {
+        float_index(
+            point_map,
+            cli.float_dim,
+            &cli.output_dir,
+            &cli.float_index_basename,
+        )?;
+    }
     // stage16_point_explorer_20250611083440.pkl
     let point_explorer: PointExplorer<u8, 32> = PointExplorerBuilder::new()
         .path(env::var("STAGE17_POINT_MAP")?)
@@ -145,32 +503,142 @@ fn main() -> anyhow::Result<()> {
     let hnsw_data = PathBuf::from(&hnsw_base).with_extension("hnsw.data");
     let hnsw_graph = PathBuf::from(&hnsw_base).with_extension("hnsw.graph");
     let hnsw_exists = hnsw_data.exists() && hnsw_graph.exists();
-    let mut maybe_hnsw_io = if hnsw_exists {
+    let explorer_ids = || point_explorer.iter().map(|(id, _)| id);
+    let mut maybe_storage = if hnsw_exists {
         tracing::info!("Loading existing HNSW index from {}", hnsw_base);
-        Some(HnswIo::new(Path::new("."), &hnsw_base))
+        Some(HnswStorage::open(".", &hnsw_base))
     } else {
         tracing::info!("{} not found, Creating new HNSW index", hnsw_base);
         None
     };
-    let mut hnsw = match maybe_hnsw_io {
-        Some(ref mut hnsw_io) => hnsw_io.load_hnsw()?,
-        None => {
-            let mut hnsw = Hnsw::<u8, DistHamming>::new(48, data.len(), 16, 600, DistHamming);
-            hnsw.set_extend_candidates(false);
-            tracing::info!("Building HNSW index with {} points", data.len());
-            hnsw.parallel_insert(&data);
-            tracing::info!("Successfully built HNSW index with {} points", data.len());
-            hnsw
-        }
+    let build = || {
+        let mut index = HnswIndex::new(48, data.len(), 16, 600, DistHamming);
+        tracing::info!("Building HNSW index with {} points", data.len());
+        index.insert(&data);
+        tracing::info!("Successfully built HNSW index with {} points", data.len());
+        (index, HnswIdMap::from_ids(explorer_ids()))
+    };
+    let mut updated = false;
+    let (mut index, ids) = match maybe_storage {
+        Some(ref mut storage) => match storage.ids() {
+            Ok(mut ids) => {
+                let (mut index, meta) = HnswIndex::load_with_meta(storage, ids.ids())?;
+                tracing::info!(
+                    "HNSW index built {} with {:?}, {} tombstones",
+                    meta.built_at,
+                    meta.params,
+                    meta.tombstones
+                );
+                let diff = ids.diff(explorer_ids());
+                if cli.append {
+                    if !diff.removed.is_empty() {
+                        tracing::warn!(
+                            "{} points of the HNSW index are gone from the point map, run with \
+                             --maintain to tombstone them",
+                            diff.removed.len()
+                        );
+                    }
+                    if !diff.added.is_empty() {
+                        let points = point_explorer.iter().map(|(id, v)| (id, v.as_slice()));
+                        let merge = index.add_points(&mut ids, points);
+                        tracing::info!(
+                            "Appended {} points, {} already present",
+                            merge.added.len(),
+                            merge.present.len()
+                        );
+                        index.save(".", &hnsw_base, &ids)?;
+                        tracing::info!("Saved HNSW index in place as {}", hnsw_base);
+                    }
+                } else if !diff.is_empty() && !cli.maintain {
+                    anyhow::bail!(
+                        "HNSW index {} misses {} points of the point map and holds {} removed \
+                         ones, run with --maintain to update it",
+                        hnsw_base,
+                        diff.added.len(),
+                        diff.removed.len()
+                    );
+                } else if !diff.is_empty() {
+                    let points = point_explorer.iter().map(|(id, v)| (id, v.as_slice()));
+                    let diff = index.update(&mut ids, points);
+                    tracing::info!(
+                        "Inserted {} points, tombstoned {}, {} tombstones in total",
+                        diff.added.len(),
+                        diff.removed.len(),
+                        ids.tombstones().len()
+                    );
+                    updated = true;
+                }
+                (index, ids)
+            }
+            // the points it holds are unknown, appending could duplicate or skip some
+            Err(HnswMetaError::MissingIds(path)) if cli.append => anyhow::bail!(
+                "HNSW index {} has no id map at {}, run once with --maintain to record or \
+                 rebuild it before appending",
+                hnsw_base,
+                path.display()
+            ),
+            // dumped before id maps were kept, the point ids are the explorer positions
+            Err(HnswMetaError::MissingIds(path)) => {
+                match HnswIndex::load_with_meta(storage, explorer_ids()) {
+                    Ok((index, meta)) => {
+                        tracing::info!(
+                            "HNSW index built {} with {:?} matches the point map",
+                            meta.built_at,
+                            meta.params
+                        );
+                        let ids = HnswIdMap::from_ids(explorer_ids());
+                        if cli.maintain {
+                            ids.save(&path)?;
+                            tracing::info!("Saved its id map to {}", path.display());
+                        }
+                        (index, ids)
+                    }
+                    // without an id map the points it holds are unknown, nothing to update
+                    Err(e @ HnswMetaError::Mismatch { .. }) if cli.maintain => {
+                        tracing::warn!("{}, rebuilding it", e);
+                        updated = true;
+                        build()
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        },
+        None => build(),
     };
-    // debug
-    hnsw.dump_layer_info();
-    hnsw.set_searching_mode(true);
     // save hnsw
-    if !hnsw_exists {
+    if updated {
+        index.save(".", &hnsw_base, &ids)?;
+        tracing::info!("Saved HNSW index in place as {}", hnsw_base);
+    } else if !hnsw_exists {
         tracing::info!("Saving HNSW index to {}", hnsw_base);
         let file_name = format!("stage17_hnsw_{}", chrono::Utc::now().timestamp());
-        hnsw.file_dump(Path::new("."), &file_name)?;
+        let dumped = index.dump_with_ids(".", &file_name, &ids)?;
+        tracing::info!("Saved HNSW index as {}", dumped);
+    }
+    // the sweep times searches on the index itself, before it is handed out
+    if let Ok("tune") = env::var("STAGE17_MODE").as_deref() {
+        let path = tune(&mut index, &point_explorer, &ids, &cli)?;
+        tracing::info!("Saved tune report to {}", path.display());
+        return Ok(());
+    }
+    let hnsw = index.searcher();
+    // debug
+    hnsw.dump_layer_info();
+    match env::var("STAGE17_MODE").as_deref() {
+        Ok("query") => query(hnsw, &point_explorer, &ids, cli.over_fetch)?,
+        Ok("knn") => knn(hnsw, &point_explorer, &ids, &KnnFilter::from_cli(&cli)?)?,
+        Ok("all-knn") => {
+            let k = env::var("STAGE17_ALL_KNN_K").map_or(Ok(200), |s| s.parse())?;
+            let ef = env::var("STAGE17_ALL_KNN_EF").map_or(Ok(500), |s| s.parse())?;
+            let path = all_knn(hnsw, &point_explorer, &ids, k, ef, cli.over_fetch)?;
+            tracing::info!("Saved all-KNN dump to {}", path.display());
+        }
+        Ok("threshold") => {
+            let path = threshold(hnsw, &point_explorer, &ids, &cli)?;
+            tracing::info!("Saved threshold report to {}", path.display());
+        }
+        _ => {}
     }
     Ok(())
 }
@@ -0,0 +1,243 @@
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use serde::Serialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Bins of [`DistanceStats::histogram`] over the normalized distance range `[0, 1]`
+pub const HISTOGRAM_BINS: usize = 64;
+
+/// `amount` distinct indices below `len`, the same for the same seed
+pub fn sample_indices(len: usize, amount: usize, seed: u64) -> Vec<usize> {
+    let mut rng = Pcg64::seed_from_u64(seed);
+    let mut sample = rand::seq::index::sample(&mut rng, len, amount.min(len)).into_vec();
+    sample.sort_unstable();
+    sample
+}
+
+/// Distribution of normalized Hamming distances
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DistanceStats {
+    pub count: usize,
+    pub mean: f32,
+    /// `(quantile, distance)` at 1, 5, 25, 50, 75 and 95%
+    pub quantiles: Vec<(f32, f32)>,
+    /// Fraction at most `cutoff`
+    pub under_cutoff: f32,
+    /// Counts of [`HISTOGRAM_BINS`] equal bins over `[0, 1]`, 1 itself in the last
+    pub histogram: Vec<u64>,
+}
+
+impl DistanceStats {
+    pub fn new(distances: &[f32], cutoff: f32) -> Self {
+        let mut sorted = distances.to_vec();
+        sorted.sort_unstable_by(f32::total_cmp);
+        let count = sorted.len();
+        let quantiles = [0.01, 0.05, 0.25, 0.5, 0.75, 0.95]
+            .into_iter()
+            .filter(|_| count > 0)
+            .map(|q| (q, sorted[((count - 1) as f32 * q).round() as usize]))
+            .collect();
+        let mut histogram = vec![0u64; HISTOGRAM_BINS];
+        for d in &sorted {
+            let bin = (d.clamp(0.0, 1.0) * HISTOGRAM_BINS as f32) as usize;
+            histogram[bin.min(HISTOGRAM_BINS - 1)] += 1;
+        }
+        let ratio = |n: usize| match count {
+            0 => 0.0,
+            _ => n as f32 / count as f32,
+        };
+        Self {
+            count,
+            mean: sorted.iter().sum::<f32>() / count.max(1) as f32,
+            quantiles,
+            under_cutoff: ratio(sorted.partition_point(|&d| d <= cutoff)),
+            histogram,
+        }
+    }
+}
+
+/// A sampled neighbor pair both of whose points are labeled
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LabeledPair {
+    pub distance: f32,
+    /// In the same ground-truth group
+    pub duplicate: bool,
+}
+
+/// Labels the `(query, neighbor, distance)` pairs by `groups`, each group one true cluster
+///
+/// Pairs with a point in no group are left out, nothing is known about them.
+pub fn label_pairs(groups: &[Vec<Uuid>], pairs: &[(Uuid, Uuid, f32)]) -> Vec<LabeledPair> {
+    let group_of: HashMap<&Uuid, usize> = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, group)| group.iter().map(move |id| (id, idx)))
+        .collect();
+    pairs
+        .iter()
+        .filter_map(|(a, b, distance)| {
+            let (a, b) = (group_of.get(a)?, group_of.get(b)?);
+            Some(LabeledPair {
+                distance: *distance,
+                duplicate: a == b,
+            })
+        })
+        .collect()
+}
+
+/// Pairs at most `threshold` apart taken as duplicates
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct ThresholdPoint {
+    pub threshold: f32,
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub false_negatives: usize,
+    pub precision: f32,
+    /// 0 when there are no duplicates
+    pub recall: f32,
+}
+
+/// One point per distinct distance of `pairs`, ascending
+pub fn precision_recall(pairs: &[LabeledPair]) -> Vec<ThresholdPoint> {
+    let mut sorted = pairs.to_vec();
+    sorted.sort_unstable_by(|a, b| a.distance.total_cmp(&b.distance));
+    let positives = sorted.iter().filter(|p| p.duplicate).count();
+    let (mut tp, mut fp) = (0usize, 0usize);
+    let mut curve: Vec<ThresholdPoint> = Vec::new();
+    for (idx, pair) in sorted.iter().enumerate() {
+        match pair.duplicate {
+            true => tp += 1,
+            false => fp += 1,
+        }
+        // pairs at the same distance fall on the same side of any threshold
+        if sorted
+            .get(idx + 1)
+            .is_some_and(|next| next.distance == pair.distance)
+        {
+            continue;
+        }
+        curve.push(ThresholdPoint {
+            threshold: pair.distance,
+            true_positives: tp,
+            false_positives: fp,
+            false_negatives: positives - tp,
+            precision: tp as f32 / (tp + fp) as f32,
+            recall: match positives {
+                0 => 0.0,
+                n => tp as f32 / n as f32,
+            },
+        });
+    }
+    curve
+}
+
+/// The highest threshold still reaching `target` precision, i.e. the best recall at it
+pub fn suggest_threshold(curve: &[ThresholdPoint], target: f32) -> Option<&ThresholdPoint> {
+    curve
+        .iter()
+        .rev()
+        .find(|point| point.precision >= target && point.true_positives > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(distance: f32, duplicate: bool) -> LabeledPair {
+        LabeledPair {
+            distance,
+            duplicate,
+        }
+    }
+
+    #[test]
+    fn test_sample_indices() {
+        let a = sample_indices(1000, 10, 7);
+        assert_eq!(a, sample_indices(1000, 10, 7));
+        assert_ne!(a, sample_indices(1000, 10, 8));
+        assert_eq!(a.len(), 10);
+        assert!(a.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(sample_indices(3, 10, 7), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_distance_stats() {
+        let distances: Vec<f32> = (0..=100).map(|i| i as f32 / 100.0).collect();
+        let stats = DistanceStats::new(&distances, 0.625);
+        assert_eq!(stats.count, 101);
+        assert!((stats.mean - 0.5).abs() < 1e-5);
+        assert_eq!(stats.quantiles[3], (0.5, 0.5));
+        assert_eq!(stats.quantiles[0], (0.01, 0.01));
+        // 0.00 ..= 0.62
+        assert!((stats.under_cutoff - 63.0 / 101.0).abs() < 1e-6);
+        assert_eq!(stats.histogram.iter().sum::<u64>(), 101);
+        assert_eq!(stats.histogram[HISTOGRAM_BINS - 1], 2);
+
+        let empty = DistanceStats::new(&[], 0.625);
+        assert_eq!((empty.count, empty.mean, empty.under_cutoff), (0, 0.0, 0.0));
+        assert!(empty.quantiles.is_empty());
+    }
+
+    #[test]
+    fn test_label_pairs() {
+        let id = Uuid::from_u128;
+        let groups = vec![vec![id(1), id(2)], vec![id(3)]];
+        let pairs = [
+            (id(1), id(2), 0.1),
+            (id(1), id(3), 0.2),
+            (id(1), id(9), 0.3),
+        ];
+        assert_eq!(
+            label_pairs(&groups, &pairs),
+            vec![pair(0.1, true), pair(0.2, false)]
+        );
+    }
+
+    #[test]
+    fn test_precision_recall() {
+        let pairs = [
+            pair(0.3, false),
+            pair(0.1, true),
+            pair(0.2, true),
+            pair(0.2, false),
+            pair(0.4, true),
+            pair(0.6, false),
+        ];
+        let curve = precision_recall(&pairs);
+        let thresholds: Vec<f32> = curve.iter().map(|p| p.threshold).collect();
+        assert_eq!(thresholds, vec![0.1, 0.2, 0.3, 0.4, 0.6]);
+        let counts: Vec<(usize, usize, usize)> = curve
+            .iter()
+            .map(|p| (p.true_positives, p.false_positives, p.false_negatives))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![(1, 0, 2), (2, 1, 1), (2, 2, 1), (3, 2, 0), (3, 3, 0)]
+        );
+        assert_eq!(curve[1].precision, 2.0 / 3.0);
+        assert_eq!(curve[3].recall, 1.0);
+        assert!(precision_recall(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_suggest_threshold() {
+        let pairs = [
+            pair(0.1, true),
+            pair(0.2, true),
+            pair(0.3, true),
+            pair(0.35, false),
+            pair(0.4, true),
+            pair(0.5, false),
+            pair(0.6, false),
+        ];
+        let curve = precision_recall(&pairs);
+        // precision dips below 0.8 at 0.35 and recovers to 4 of 5 at 0.4, the last point
+        // reaching the target wins
+        assert_eq!(suggest_threshold(&curve, 0.8).unwrap().threshold, 0.4);
+        assert_eq!(suggest_threshold(&curve, 0.9).unwrap().threshold, 0.3);
+        assert_eq!(suggest_threshold(&curve, 0.5).unwrap().threshold, 0.6);
+        let negatives = precision_recall(&[pair(0.1, false)]);
+        assert!(suggest_threshold(&negatives, 0.5).is_none());
+    }
+}
@@ -1,35 +1,51 @@
+use clap::Parser;
 use mimalloc::MiMalloc;
 use ndarray::Array2;
-use petal_clustering::{Fit, Optics};
-use petal_neighbors::distance::Hamming;
 use rand::prelude::*;
 use rand::rng;
+use shared::logging::Logging;
+use shared::optics::{OpticsClusters, OpticsMetric, OpticsParams, fit, sweep};
 use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
-use std::collections::{HashMap, HashSet};
+use shared::uuid_set::UuidSet;
+use std::collections::HashMap;
 use std::env;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
 use uuid::Uuid;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+#[derive(Parser)]
+#[command(about = "OPTICS over the hand picked ids plus 200 random points")]
+struct Args {
+    #[arg(long, default_value_t = OpticsParams::default().eps)]
+    eps: f32,
+    #[arg(long, default_value_t = OpticsParams::default().min_samples)]
+    min_samples: usize,
+    /// hamming or euclidean
+    #[arg(long, default_value_t = OpticsParams::default().metric)]
+    metric: OpticsMetric,
+    /// Comma separated eps values fitted one after another instead of `--eps`, one result file
+    /// per value plus a JSON summary
+    #[arg(long, value_delimiter = ',', num_args = 1..)]
+    sweep: Option<Vec<f32>>,
+}
+
+fn uuid_clusters<'a>(
+    (clusters_map, _): &OpticsClusters,
+    row_uuids: &'a [Uuid],
+) -> HashMap<usize, Vec<&'a Uuid>> {
+    clusters_map
+        .iter()
+        .map(|(&cluster_id, indices)| {
+            let uuids = indices.iter().map(|&idx| &row_uuids[idx]).collect();
+            (cluster_id, uuids)
+        })
+        .collect()
+}
+
 fn main() -> anyhow::Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new(
-        env::var("STDOUT_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-    ));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage18.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new(
-            env::var("FILE_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-        ));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let args = Args::parse();
+    let _logging = Logging::new("stage18").init()?;
     let point_explorer: PointExplorer<u8, 32> = PointExplorerBuilder::new()
         .path(env::var("STAGE18_POINT_MAP")?)
         .metadata_ext_path(env::var("STAGE18_POINT_EXT")?)
@@ -86,7 +102,7 @@ fn main() -> anyhow::Result<()> {
         .chain(hm_list_2.iter())
         .map(|s| Uuid::parse_str(s).expect("invalid UUID in hm_list"))
         .collect();
-    let exclude: HashSet<Uuid> = first_batch.iter().cloned().collect();
+    let exclude: UuidSet = first_batch.iter().collect();
     let mut remaining: Vec<&Uuid> = point_explorer_keys
         .iter()
         .filter_map(|&id| {
@@ -100,26 +116,50 @@ fn main() -> anyhow::Result<()> {
     let mut thread_rng = rng();
     remaining.shuffle(&mut thread_rng);
     let sample_200: Vec<&Uuid> = remaining.into_iter().take(200).collect();
-    let combined_uuids: Vec<&Uuid> = first_batch
+    let combined_uuids: Vec<Uuid> = first_batch
         .iter()
         .chain(sample_200.iter().map(|&uuid| uuid))
+        .copied()
         .collect();
-    let data: Vec<f32> = combined_uuids
-        .iter()
-        .filter_map(|uuid| point_explorer.get_vector(uuid))
-        .flat_map(|point_data| point_data.iter().map(|&byte| byte as f32))
-        .collect();
-    let vecs: Array2<f32> = Array2::from_shape_vec((combined_uuids.len(), 32), data)
-        .expect("Failed to create Array2 from data");
-    let mut opt = Optics::new(10.0, 2, Hamming::default());
-    let (clusters_map, noises) = opt.fit(&vecs, None);
-    let uuid_clusters: HashMap<usize, Vec<&Uuid>> = clusters_map
-        .into_iter()
-        .map(|(cluster_id, indices)| {
-            let uuids = indices.into_iter().map(|idx| combined_uuids[idx]).collect();
-            (cluster_id, uuids)
-        })
-        .collect();
-    tracing::info!("Optics clustering result: {:?}", uuid_clusters);
+    // ids missing from the explorer are dropped, `row_uuids` keeps rows and ids aligned
+    let (vecs, row_uuids): (Array2<f32>, Vec<Uuid>) =
+        point_explorer.select_to_array2_f32(&combined_uuids);
+    let params = OpticsParams {
+        eps: args.eps,
+        min_samples: args.min_samples,
+        metric: args.metric,
+    };
+    let Some(eps) = args.sweep else {
+        tracing::info!("Running Optics with {:?}", params);
+        let res = fit(&vecs, &params);
+        tracing::info!(
+            "Optics clustering result: {:?}",
+            uuid_clusters(&res, &row_uuids)
+        );
+        return Ok(());
+    };
+    tracing::info!("Sweeping eps over {:?} with {:?}", eps, params);
+    let ts = chrono::Utc::now().timestamp();
+    let summaries = sweep(&vecs, &params, &eps, |params, res| {
+        let file_name = format!("stage18_optics_results{}_eps{}.pkl", ts, params.eps);
+        let clusters = uuid_clusters(res, &row_uuids);
+        let noise: Vec<&Uuid> = res.1.iter().map(|&idx| &row_uuids[idx]).collect();
+        let data = serde_pickle::to_vec(&(clusters, noise), serde_pickle::SerOptions::default())?;
+        std::fs::write(&file_name, data)?;
+        tracing::info!("Saved clustering results to {}", file_name);
+        anyhow::Ok(())
+    })?;
+    for summary in summaries.iter() {
+        tracing::info!(
+            "eps {}: {} clusters, {} noise, largest cluster {}",
+            summary.params.eps,
+            summary.cluster_count,
+            summary.noise_count,
+            summary.largest_cluster
+        );
+    }
+    let file_name = format!("stage18_optics_sweep{}.json", ts);
+    std::fs::write(&file_name, serde_json::to_vec_pretty(&summaries)?)?;
+    tracing::info!("Saved sweep summary to {}", file_name);
     Ok(())
 }
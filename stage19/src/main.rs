@@ -1,68 +1,88 @@
+use clap::Parser;
 use mimalloc::MiMalloc;
 use ndarray::Array2;
-use petal_clustering::{Fit, Optics};
-use petal_neighbors::distance::Hamming;
-use rand::prelude::*;
-use rand::rng;
+use shared::logging::Logging;
+use shared::optics::{OpticsClusters, OpticsMetric, OpticsParams, fit, sweep};
 use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
-use std::collections::HashSet;
+use shared::uuid_set::UuidSet;
 use std::env;
-use std::path::PathBuf;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
 use uuid::Uuid;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+#[derive(Parser)]
+#[command(about = "OPTICS over the stage19_POINT_KNN points")]
+struct Args {
+    #[arg(long, default_value_t = OpticsParams::default().eps)]
+    eps: f32,
+    #[arg(long, default_value_t = OpticsParams::default().min_samples)]
+    min_samples: usize,
+    /// hamming or euclidean
+    #[arg(long, default_value_t = OpticsParams::default().metric)]
+    metric: OpticsMetric,
+    /// Comma separated eps values fitted one after another instead of `--eps`, one result file
+    /// per value plus a JSON summary
+    #[arg(long, value_delimiter = ',', num_args = 1..)]
+    sweep: Option<Vec<f32>>,
+}
+
+fn save_result(res: &OpticsClusters, file_name: &str) -> anyhow::Result<()> {
+    let res = serde_pickle::to_vec(res, serde_pickle::SerOptions::default())?;
+    std::fs::write(file_name, res)?;
+    tracing::info!("Saved clustering results to {}", file_name);
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new(
-        env::var("STDOUT_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-    ));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage19.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new(
-            env::var("FILE_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-        ));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let args = Args::parse();
+    let _logging = Logging::new("stage19").init()?;
     let point_explorer: PointExplorer<u8, 32> = PointExplorerBuilder::new()
         .path(env::var("stage19_POINT_MAP")?)
         .metadata_ext_path(env::var("stage19_POINT_EXT")?)
         .point_url_prefix(env::var("stage19_POINT_URL_PREFIX")?)
         .build()?;
-    let pre_knn: HashSet<Uuid> = serde_pickle::from_slice(
-        &std::fs::read(env::var("stage19_POINT_KNN")?)?,
-        serde_pickle::DeOptions::default(),
-    )?;
-    let pre_knn_vecs = pre_knn
-        .iter()
-        .map(|id| {
-            point_explorer
-                .get_vector(id)
-                .expect("Failed to get point from PointExplorer")
-                .map(|v| v as f32)
-        })
-        .collect::<Vec<_>>();
-    let vecs = Array2::from_shape_vec(
-        (pre_knn_vecs.len(), 32),
-        pre_knn_vecs.into_iter().flatten().collect(),
-    )?;
-    let mut opt = Optics::new(10.0, 2, Hamming::default());
-    let res = opt.fit(&vecs, None);
-    tracing::info!("Optics clustering result: {:?}", res);
-    // save res
-    let res = serde_pickle::to_vec(&res, serde_pickle::SerOptions::default())?;
-    let file_name = format!(
-        "stage19_optics_results{}.pkl",
-        chrono::Utc::now().timestamp()
+    // accepts both the legacy pickled HashSet and the binary UuidSet
+    let pre_knn = UuidSet::load_any(env::var("stage19_POINT_KNN")?)?;
+    let pre_knn_ids: Vec<Uuid> = pre_knn.iter().collect();
+    let (vecs, row_ids): (Array2<f32>, Vec<Uuid>) =
+        point_explorer.select_to_array2_f32(&pre_knn_ids);
+    anyhow::ensure!(
+        row_ids.len() == pre_knn_ids.len(),
+        "{} of {} points are missing from the PointExplorer",
+        pre_knn_ids.len() - row_ids.len(),
+        pre_knn_ids.len()
     );
-    std::fs::write(&file_name, res)?;
-    tracing::info!("Saved clustering results to {}", file_name);
+    let params = OpticsParams {
+        eps: args.eps,
+        min_samples: args.min_samples,
+        metric: args.metric,
+    };
+    let ts = chrono::Utc::now().timestamp();
+    let Some(eps) = args.sweep else {
+        tracing::info!("Running Optics with {:?}", params);
+        let res = fit(&vecs, &params);
+        tracing::info!("Optics clustering result: {:?}", res);
+        return save_result(&res, &format!("stage19_optics_results{}.pkl", ts));
+    };
+    tracing::info!("Sweeping eps over {:?} with {:?}", eps, params);
+    let summaries = sweep(&vecs, &params, &eps, |params, res| {
+        save_result(
+            res,
+            &format!("stage19_optics_results{}_eps{}.pkl", ts, params.eps),
+        )
+    })?;
+    for summary in summaries.iter() {
+        tracing::info!(
+            "eps {}: {} clusters, {} noise, largest cluster {}",
+            summary.params.eps,
+            summary.cluster_count,
+            summary.noise_count,
+            summary.largest_cluster
+        );
+    }
+    let file_name = format!("stage19_optics_sweep{}.json", ts);
+    std::fs::write(&file_name, serde_json::to_vec_pretty(&summaries)?)?;
+    tracing::info!("Saved sweep summary to {}", file_name);
     Ok(())
 }
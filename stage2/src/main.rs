@@ -1,72 +1,82 @@
+use clap::Parser;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use prost::Message;
 use qdrant_client::qdrant::vectors_output::VectorsOptions as VectorsOptionsOutput;
-use qdrant_client::qdrant::with_payload_selector::SelectorOptions as SelectorOptionsPayload;
 use qdrant_client::qdrant::with_vectors_selector::SelectorOptions;
 use qdrant_client::qdrant::{GetPointsBuilder, GetResponse, PointId, VectorsSelector};
-use qdrant_client::qdrant::{point_id, value};
-use shared::qdrant::GenShinQdrantClient;
+use shared::atomic_write::atomic_write;
+use shared::cluster_file::{LegacyFormat, open_clusters};
+use shared::migrations::encode_neko_points;
+use shared::qdrant::{
+    GenShinQdrantClient, IdKindCounts, PayloadAnomaly, PayloadReader, PointRef, payload_selector,
+};
 use shared::structure::{NekoPoint, NekoPointText};
-use std::collections::HashMap;
 use std::collections::HashSet;
-use std::io::{Read, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
 use uuid::Uuid;
 
-fn extract_point(pb: ProgressBar, points: GetResponse) -> HashMap<Uuid, NekoPoint> {
+/// Points fetched with their full payload to measure what the projection saves
+const PAYLOAD_SAMPLE: usize = 100;
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// Payload keys fetched on top of the extracted ones, kept as JSON in `NekoPoint::extra`
+    #[arg(long, value_delimiter = ',')]
+    payload_keys: Vec<String>,
+}
+
+/// Points keyed by id, with the payload anomalies of every point that had some
+fn extract_point(
+    pb: ProgressBar,
+    points: GetResponse,
+    extra_keys: &[String],
+) -> (
+    HashMap<Uuid, NekoPoint>,
+    BTreeMap<Uuid, Vec<PayloadAnomaly>>,
+) {
     let mut points_map: HashMap<Uuid, NekoPoint> = HashMap::new();
+    let mut anomalies: BTreeMap<Uuid, Vec<PayloadAnomaly>> = BTreeMap::new();
     for raw in points.result.into_iter() {
-        let id = raw
-            .id
-            .and_then(|pid| pid.point_id_options)
-            .map(|opt| match opt {
-                point_id::PointIdOptions::Uuid(s) => Uuid::parse_str(&s).unwrap(),
-                point_id::PointIdOptions::Num(n) => Uuid::from_u128(n as u128),
-            })
-            .unwrap();
-        let height = raw.payload.get("height").unwrap().as_integer().unwrap() as usize;
-        let weight = raw.payload.get("width").unwrap().as_integer().unwrap() as usize;
-        let categories = match raw.payload.get("categories").and_then(|v| v.kind.clone()) {
-            Some(value::Kind::ListValue(list)) => Some(
-                list.values
-                    .iter()
-                    .filter_map(|item| {
-                        if let Some(value::Kind::StringValue(s)) = item.kind.clone() {
-                            Some(s)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect(),
-            ),
-            _ => None,
+        let point_ref = PointRef::try_from(raw.id.unwrap()).unwrap();
+        let qdrant_num_id = match point_ref {
+            PointRef::Num(num) => Some(num),
+            PointRef::Uuid(_) => None,
         };
+        let mut payload = PayloadReader::new(&raw.payload);
+        let fields = payload.neko_fields();
+        let extra = payload.extra(extra_keys);
+        let ocr_text = fields.ocr_text;
         let text_info = raw.vectors.and_then(|vectors| {
             if let Some(VectorsOptionsOutput::Vectors(named)) = vectors.vectors_options {
                 named.vectors.get("text_contain_vector").and_then(|v| {
-                    raw.payload
-                        .get("ocr_text")
-                        .and_then(|t| t.as_str().map(|s| s.to_string()))
-                        .map(|txt| NekoPointText {
-                            text: txt,
-                            text_vector: v.data.clone(),
-                        })
+                    ocr_text.map(|txt| NekoPointText {
+                        text: txt,
+                        text_vector: v.data.clone(),
+                    })
                 })
             } else {
                 None
             }
         });
         let pt = NekoPoint {
-            id,
-            height,
-            weight,
-            categories,
+            id: point_ref.key(),
+            height: fields.height,
+            width: fields.width,
+            categories: fields.categories,
             text_info,
             size: None,
+            qdrant_num_id,
+            extra,
         };
+        let point_anomalies = payload.into_anomalies();
+        if !point_anomalies.is_empty() {
+            anomalies.insert(pt.id, point_anomalies);
+        }
         points_map.insert(pt.id, pt);
         pb.inc(1);
     }
-    points_map
+    (points_map, anomalies)
 }
 
 // TODO:
@@ -89,20 +99,36 @@ fn extract_point(pb: ProgressBar, points: GetResponse) -> HashMap<Uuid, NekoPoin
 //     Ok(())
 // }
 
+/// Encoded size of the full and of the projected payloads of the first [`PAYLOAD_SAMPLE`] points
+async fn sample_payload_sizes(
+    client: &GenShinQdrantClient,
+    point_list: &[PointId],
+    extra_keys: &[String],
+) -> anyhow::Result<(usize, usize)> {
+    let sample = &point_list[..point_list.len().min(PAYLOAD_SAMPLE)];
+    let full = client
+        .get_points(GetPointsBuilder::new("nekoimg", sample.to_vec()).with_payload(true))
+        .await?;
+    let projected = client
+        .get_points(
+            GetPointsBuilder::new("nekoimg", sample.to_vec())
+                .with_payload(payload_selector(extra_keys)),
+        )
+        .await?;
+    Ok((full.encoded_len(), projected.encoded_len()))
+}
+
 #[tokio::main]
 pub async fn main() {
-    let global_clusters = std::fs::read(r"global_clusters.pkl").unwrap();
-    let global_clusters: Vec<HashSet<Uuid>> =
-        serde_pickle::from_slice(&global_clusters, Default::default()).unwrap();
-    let point_set: HashSet<String> = global_clusters
-        .iter()
-        .flat_map(|c| c.iter())
-        .map(|uuid| uuid.to_string())
-        .collect();
-    let point_list: Vec<PointId> = point_set
-        .into_iter()
-        .map(|s: String| PointId::from(s))
-        .collect();
+    let args = Args::parse();
+    let global_clusters = open_clusters(r"global_clusters.pkl", LegacyFormat::Pickle).unwrap();
+    // cluster files key numeric points by `num_id_key`, they are fetched by number
+    let mut point_set: HashSet<PointRef> = HashSet::new();
+    for cluster in global_clusters {
+        let (_, cluster) = cluster.unwrap();
+        point_set.extend(cluster.into_iter().map(PointRef::from_key));
+    }
+    let point_list: Vec<PointId> = point_set.into_iter().map(PointId::from).collect();
     println!("Got point_list, len={:?}", point_list.len());
     let points;
     /// Desperate
@@ -118,6 +144,16 @@ pub async fn main() {
         Err(_) => {
             println!("File not found, fetching...");
             let client = GenShinQdrantClient::new().unwrap();
+            match sample_payload_sizes(&client, &point_list, &args.payload_keys).await {
+                Ok((full, projected)) => println!(
+                    "Payload projection: {} bytes instead of {} for a sample of {} points ({:.1}% saved)",
+                    projected,
+                    full,
+                    point_list.len().min(PAYLOAD_SAMPLE),
+                    100.0 * (1.0 - projected as f64 / full.max(1) as f64)
+                ),
+                Err(e) => println!("Warning: failed to measure the payload projection: {}", e),
+            }
             points = client
                 .get_points(
                     GetPointsBuilder::new("nekoimg", point_list)
@@ -125,14 +161,18 @@ pub async fn main() {
                         .with_vectors(SelectorOptions::Include(VectorsSelector::from(vec![
                             "text_contain_vector".to_string(),
                         ])))
-                        .with_payload(SelectorOptionsPayload::Enable(true))
+                        .with_payload(payload_selector(&args.payload_keys))
                         .build(),
                 )
                 .await
                 .unwrap();
         }
     }
-    println!("Got points, {:?}", points.result.len());
+    println!(
+        "Got points, {:?} ({} bytes)",
+        points.result.len(),
+        points.encoded_len()
+    );
     let m = MultiProgress::new();
     let pb_local = m.add(ProgressBar::new(points.result.len() as u64));
     let style = ProgressStyle::default_bar()
@@ -141,10 +181,23 @@ pub async fn main() {
         .progress_chars("#>-");
     pb_local.set_style(style.clone());
     pb_local.set_message("extract_point");
-    let points_map = extract_point(pb_local, points);
+    let (points_map, anomalies) = extract_point(pb_local, points, &args.payload_keys);
     println!("Got points, {:?}", points_map.len());
-    let mut saved_file = std::fs::File::create(r"points_map.bin").unwrap();
-    let serialized =
-        bincode::serde::encode_to_vec(&points_map, bincode::config::standard()).unwrap();
-    saved_file.write_all(&serialized).unwrap();
+    if !anomalies.is_empty() {
+        println!(
+            "Warning: {} points have payload fields in an unexpected shape, see payload_anomalies.json",
+            anomalies.len()
+        );
+    }
+    let report = serde_json::to_vec_pretty(&anomalies).unwrap();
+    atomic_write(r"payload_anomalies.json", &report).unwrap();
+    let kinds = IdKindCounts::count(points_map.values().map(PointRef::of));
+    if kinds.is_mixed() {
+        println!(
+            "Warning: the collection mixes point id types, {} uuid and {} numeric",
+            kinds.uuid, kinds.num
+        );
+    }
+    let serialized = encode_neko_points(&points_map).unwrap();
+    atomic_write(r"points_map.bin", &serialized).unwrap();
 }
@@ -0,0 +1,461 @@
+use clap::Parser;
+use indicatif::{ProgressBar, ProgressStyle};
+use mimalloc::MiMalloc;
+use qdrant_client::qdrant::vectors_config::Config as VectorsConfigOptions;
+use qdrant_client::qdrant::with_payload_selector::SelectorOptions as SelectorOptionsPayload;
+use qdrant_client::qdrant::{PayloadIncludeSelector, PointId, ScrollPointsBuilder, point_id};
+use serde::Serialize;
+use shared::atomic_write::atomic_write_with;
+use shared::cluster_file::{LegacyFormat, read_clusters};
+use shared::logging::Logging;
+use shared::migrations::load_neko_points;
+use shared::qdrant::{GenShinQdrantClient, QdrantResult};
+use shared::structure::NekoPoint;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[global_allocator]
+static GLOBAL: MiMalloc = MiMalloc;
+
+const SIZE_QUANTILES: [f64; 7] = [0.0, 0.1, 0.25, 0.5, 0.75, 0.9, 1.0];
+
+#[derive(Debug, Default, Serialize)]
+struct PointsSummary {
+    source: String,
+    point_count: usize,
+    /// Named vector -> how many points carry it (Qdrant: collection config, points_map: text_info)
+    named_vectors: BTreeMap<String, Option<usize>>,
+    format_distribution: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct SizeQuantile {
+    quantile: f64,
+    bytes: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ListingSummary {
+    entries: usize,
+    joined_entries: usize,
+    orphan_entries: usize,
+    points_without_entry: usize,
+    /// Joined entries listed without a content length, left out of the size figures
+    unknown_size_entries: usize,
+    total_bytes: u64,
+    size_quantiles: Vec<SizeQuantile>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct DuplicateSummary {
+    clusters: usize,
+    multi_member_clusters: usize,
+    points_in_multi_member_clusters: usize,
+    /// Fraction of all points sitting in a cluster of size > 1
+    duplicate_rate: f64,
+    /// Points that could go away if every multi-member cluster kept one representative
+    removable_points: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct CollectionStats {
+    generated_at: String,
+    points: PointsSummary,
+    listing: Option<ListingSummary>,
+    duplicates: Option<DuplicateSummary>,
+}
+
+/// Nearest-rank quantiles over an ascending slice, empty input yields no rows
+fn quantiles(sorted: &[u64], qs: &[f64]) -> Vec<SizeQuantile> {
+    if sorted.is_empty() {
+        return Vec::new();
+    }
+    qs.iter()
+        .map(|&q| {
+            let q = q.clamp(0.0, 1.0);
+            let rank = (q * sorted.len() as f64).ceil() as usize;
+            let idx = rank.saturating_sub(1).min(sorted.len() - 1);
+            SizeQuantile {
+                quantile: q,
+                bytes: sorted[idx],
+            }
+        })
+        .collect()
+}
+
+/// Lowercased extension/format histogram, missing values are bucketed as `<none>`
+fn format_distribution<'a, I>(formats: I) -> BTreeMap<String, usize>
+where
+    I: IntoIterator<Item = Option<&'a str>>,
+{
+    formats.into_iter().fold(BTreeMap::new(), |mut acc, fmt| {
+        let key = match fmt {
+            Some(f) if !f.is_empty() => f.to_ascii_lowercase(),
+            _ => "<none>".to_string(),
+        };
+        *acc.entry(key).or_insert(0) += 1;
+        acc
+    })
+}
+
+fn duplicate_summary(clusters: &[HashSet<Uuid>], point_count: usize) -> DuplicateSummary {
+    let multi: Vec<usize> = clusters
+        .iter()
+        .map(HashSet::len)
+        .filter(|&len| len > 1)
+        .collect();
+    let points_in_multi: usize = multi.iter().sum();
+    DuplicateSummary {
+        clusters: clusters.len(),
+        multi_member_clusters: multi.len(),
+        points_in_multi_member_clusters: points_in_multi,
+        duplicate_rate: match point_count {
+            0 => 0.0,
+            n => points_in_multi as f64 / n as f64,
+        },
+        removable_points: points_in_multi - multi.len(),
+    }
+}
+
+fn listing_summary(
+    entries: &[shared::opendal::Entry],
+    point_ids: Option<&HashSet<String>>,
+) -> (ListingSummary, BTreeMap<String, usize>) {
+    let joined: Vec<&shared::opendal::Entry> = entries
+        .iter()
+        .filter(|e| point_ids.is_none_or(|ids| ids.contains(e.to_point())))
+        .collect();
+    let mut sizes: Vec<u64> = joined
+        .iter()
+        .filter_map(|e| e.metadata.content_length)
+        .collect();
+    sizes.sort_unstable();
+    let seen: HashSet<&str> = joined.iter().map(|e| e.to_point()).collect();
+    let formats = format_distribution(
+        joined
+            .iter()
+            .map(|e| Path::new(&e.path).extension().and_then(|ext| ext.to_str())),
+    );
+    let summary = ListingSummary {
+        entries: entries.len(),
+        joined_entries: joined.len(),
+        orphan_entries: entries.len() - joined.len(),
+        points_without_entry: point_ids.map_or(0, |ids| {
+            ids.iter().filter(|id| !seen.contains(id.as_str())).count()
+        }),
+        unknown_size_entries: joined.len() - sizes.len(),
+        total_bytes: sizes.iter().sum(),
+        size_quantiles: quantiles(&sizes, &SIZE_QUANTILES),
+    };
+    (summary, formats)
+}
+
+struct Stage20GenshinQdrantClient {
+    client: GenShinQdrantClient,
+    collection_name: String,
+}
+
+impl Stage20GenshinQdrantClient {
+    pub fn new(collection_name: &str) -> anyhow::Result<Self> {
+        let client = GenShinQdrantClient::new()?;
+        Ok(Self {
+            client,
+            collection_name: collection_name.to_owned(),
+        })
+    }
+
+    /// (points_count, named vectors from the collection config)
+    async fn fetch_collection_info(&self) -> QdrantResult<(u64, Vec<String>)> {
+        let info = self.client.collection_info(&self.collection_name).await?;
+        let info = info.result.unwrap_or_default();
+        let named_vectors = info
+            .config
+            .and_then(|c| c.params)
+            .and_then(|p| p.vectors_config)
+            .and_then(|v| v.config)
+            .map(|cfg| match cfg {
+                VectorsConfigOptions::Params(_) => vec!["<default>".to_string()],
+                VectorsConfigOptions::ParamsMap(m) => m.map.into_keys().collect(),
+            })
+            .unwrap_or_default();
+        Ok((info.points_count.unwrap_or_default(), named_vectors))
+    }
+
+    /// Point id -> payload `format`, vectors are never fetched
+    async fn fetch_formats(&self, pre_num: u64) -> QdrantResult<HashMap<String, Option<String>>> {
+        let pb = ProgressBar::new(pre_num);
+        let style = ProgressStyle::default_bar()
+            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
+            .unwrap();
+        pb.set_style(style);
+        pb.set_message("Scrolling Qdrant payload...");
+        let mut offset: Option<PointId> = None;
+        let mut out = HashMap::with_capacity(pre_num as usize);
+        loop {
+            let mut sc = ScrollPointsBuilder::new(&self.collection_name)
+                .limit(1000)
+                .with_payload(SelectorOptionsPayload::Include(PayloadIncludeSelector {
+                    fields: vec!["format".to_string()],
+                }))
+                .with_vectors(false);
+            if let Some(ov) = offset {
+                sc = sc.offset(ov);
+            }
+            let resp = self.client.scroll(sc).await?;
+            pb.inc(resp.result.len() as u64);
+            offset = resp.next_page_offset.to_owned();
+            out.extend(resp.result.into_iter().filter_map(|p| {
+                let id = match p.id?.point_id_options? {
+                    point_id::PointIdOptions::Uuid(s) => s,
+                    point_id::PointIdOptions::Num(n) => n.to_string(),
+                };
+                let format = p
+                    .payload
+                    .get("format")
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string());
+                Some((id, format))
+            }));
+            if offset.is_none() {
+                break;
+            }
+        }
+        pb.finish_with_message("Scroll done");
+        Ok(out)
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "Stage20",
+    version,
+    about = "Collection statistics: duplicate rate, format distribution and size quantiles"
+)]
+struct Cli {
+    /// Read points from Qdrant (QDRANT_COLLECTION_NAME) instead of a points_map.bin
+    #[arg(long, default_value = "false")]
+    from_qdrant: bool,
+    #[arg(long, default_value = "points_map.bin")]
+    points_map: PathBuf,
+    /// opendal listing checkpoint produced by stage5
+    #[arg(long)]
+    s3_listing: Option<PathBuf>,
+    /// Clusters file, pickled or in the shared cluster format
+    #[arg(long)]
+    clusters: Option<PathBuf>,
+    #[arg(long, default_value = "stage20_stats")]
+    save_result_prefix: String,
+}
+
+fn print_table(stats: &CollectionStats) {
+    let row = |k: &str, v: String| println!("  {:<36} {:>16}", k, v);
+    println!("Collection statistics ({})", stats.points.source);
+    row("points", stats.points.point_count.to_string());
+    for (name, count) in stats.points.named_vectors.iter() {
+        let count = count.map_or_else(|| "configured".to_string(), |c| c.to_string());
+        row(&format!("vector[{}]", name), count);
+    }
+    for (fmt, count) in stats.points.format_distribution.iter() {
+        row(&format!("format[{}]", fmt), count.to_string());
+    }
+    if let Some(listing) = stats.listing.as_ref() {
+        row("listing entries", listing.entries.to_string());
+        row("listing entries joined", listing.joined_entries.to_string());
+        row("listing orphan entries", listing.orphan_entries.to_string());
+        row(
+            "points without entry",
+            listing.points_without_entry.to_string(),
+        );
+        row(
+            "entries without size",
+            listing.unknown_size_entries.to_string(),
+        );
+        row("total bytes", listing.total_bytes.to_string());
+        for q in listing.size_quantiles.iter() {
+            row(
+                &format!("size p{:.0}", q.quantile * 100.0),
+                q.bytes.to_string(),
+            );
+        }
+    }
+    if let Some(dup) = stats.duplicates.as_ref() {
+        row("clusters", dup.clusters.to_string());
+        row("clusters (size > 1)", dup.multi_member_clusters.to_string());
+        row(
+            "points in clusters (size > 1)",
+            dup.points_in_multi_member_clusters.to_string(),
+        );
+        row("duplicate rate", format!("{:.4}", dup.duplicate_rate));
+        row("removable points", dup.removable_points.to_string());
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let _logging = Logging::new("stage20").init()?;
+    let (mut points, point_ids) = if cli.from_qdrant {
+        let collection_name = std::env::var("QDRANT_COLLECTION_NAME")?;
+        let client = Stage20GenshinQdrantClient::new(&collection_name)?;
+        let (points_count, named_vectors) = client.fetch_collection_info().await?;
+        let formats = client.fetch_formats(points_count).await?;
+        let summary = PointsSummary {
+            source: format!("qdrant:{}", collection_name),
+            point_count: formats.len(),
+            named_vectors: named_vectors.into_iter().map(|n| (n, None)).collect(),
+            format_distribution: format_distribution(formats.values().map(|f| f.as_deref())),
+        };
+        (summary, formats.into_keys().collect::<HashSet<String>>())
+    } else {
+        let points_map: HashMap<Uuid, NekoPoint> = load_neko_points(&cli.points_map)?;
+        let text_vectors = points_map
+            .values()
+            .filter(|p| p.text_info.is_some())
+            .count();
+        let summary = PointsSummary {
+            source: cli.points_map.display().to_string(),
+            point_count: points_map.len(),
+            named_vectors: BTreeMap::from([(
+                "text_contain_vector".to_string(),
+                Some(text_vectors),
+            )]),
+            format_distribution: BTreeMap::new(),
+        };
+        let ids = points_map.keys().map(|id| id.to_string()).collect();
+        (summary, ids)
+    };
+    tracing::info!(
+        "Loaded {} points from {}",
+        points.point_count,
+        points.source
+    );
+    let listing = match cli.s3_listing.as_ref() {
+        Some(path) => {
+            let data = fs::read(path)?;
+            let (entries, _) = shared::opendal::decode_entry_list(&data)?;
+            tracing::info!("Loaded {} listing entries", entries.len());
+            let (summary, formats) = listing_summary(&entries, Some(&point_ids));
+            // points_map has no format field, the listing extension is the next best thing
+            if points.format_distribution.is_empty() {
+                points.format_distribution = formats;
+            }
+            Some(summary)
+        }
+        None => None,
+    };
+    let duplicates = match cli.clusters.as_ref() {
+        Some(path) => {
+            let clusters = read_clusters(path, LegacyFormat::Pickle)?;
+            tracing::info!("Loaded {} clusters", clusters.len());
+            Some(duplicate_summary(&clusters, points.point_count))
+        }
+        None => None,
+    };
+    let stats = CollectionStats {
+        generated_at: chrono::Local::now().to_rfc3339(),
+        points,
+        listing,
+        duplicates,
+    };
+    print_table(&stats);
+    let filename = format!(
+        "{}_{}.json",
+        cli.save_result_prefix,
+        chrono::Local::now().format("%Y%m%d_%H%M%S")
+    );
+    atomic_write_with(&filename, |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &stats)?)
+    })?;
+    tracing::info!("Saved statistics to {}", filename);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, len: Option<u64>) -> shared::opendal::Entry {
+        shared::opendal::Entry {
+            path: path.to_string(),
+            metadata: shared::opendal::Metadata {
+                mode: shared::opendal::EntryMode::FILE,
+                is_current: None,
+                is_deleted: false,
+                cache_control: None,
+                content_disposition: None,
+                content_length: len,
+                content_md5: None,
+                content_range: None,
+                content_type: None,
+                content_encoding: None,
+                etag: None,
+                last_modified: None,
+                version: None,
+                user_metadata: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_quantiles() {
+        assert!(quantiles(&[], &SIZE_QUANTILES).is_empty());
+        let sorted: Vec<u64> = (1..=10).collect();
+        let res = quantiles(&sorted, &[0.0, 0.5, 0.9, 1.0]);
+        let bytes: Vec<u64> = res.iter().map(|q| q.bytes).collect();
+        assert_eq!(bytes, vec![1, 5, 9, 10]);
+        let single = quantiles(&[42], &SIZE_QUANTILES);
+        assert!(single.iter().all(|q| q.bytes == 42));
+    }
+
+    #[test]
+    fn test_format_distribution() {
+        let dist = format_distribution([Some("PNG"), Some("png"), Some("gif"), None, Some("")]);
+        assert_eq!(dist.get("png"), Some(&2));
+        assert_eq!(dist.get("gif"), Some(&1));
+        assert_eq!(dist.get("<none>"), Some(&2));
+    }
+
+    #[test]
+    fn test_duplicate_summary() {
+        let clusters: Vec<HashSet<Uuid>> = vec![
+            (0..3).map(Uuid::from_u128).collect(),
+            HashSet::from([Uuid::from_u128(10)]),
+            (20..22).map(Uuid::from_u128).collect(),
+        ];
+        let dup = duplicate_summary(&clusters, 10);
+        assert_eq!(dup.clusters, 3);
+        assert_eq!(dup.multi_member_clusters, 2);
+        assert_eq!(dup.points_in_multi_member_clusters, 5);
+        assert_eq!(dup.removable_points, 3);
+        assert!((dup.duplicate_rate - 0.5).abs() < f64::EPSILON);
+        assert_eq!(duplicate_summary(&[], 0).duplicate_rate, 0.0);
+    }
+
+    #[test]
+    fn test_listing_summary_join() {
+        let a = Uuid::from_u128(1).to_string();
+        let b = Uuid::from_u128(2).to_string();
+        let c = Uuid::from_u128(3).to_string();
+        let entries = vec![
+            entry(&format!("NekoImage/{}.png", a), Some(100)),
+            entry(&format!("NekoImage/{}.gif", b), None),
+            entry("NekoImage/orphan.jpg", Some(7)),
+        ];
+        let ids = HashSet::from([a, b, c]);
+        let (summary, formats) = listing_summary(&entries, Some(&ids));
+        assert_eq!(summary.entries, 3);
+        assert_eq!(summary.joined_entries, 2);
+        assert_eq!(summary.orphan_entries, 1);
+        assert_eq!(summary.points_without_entry, 1);
+        assert_eq!(summary.unknown_size_entries, 1);
+        assert_eq!(summary.total_bytes, 100);
+        // the entry without a size does not drag the quantiles to 0
+        assert!(summary.size_quantiles.iter().all(|q| q.bytes == 100));
+        assert_eq!(formats.get("png"), Some(&1));
+        assert_eq!(formats.get("jpg"), None);
+        let (unjoined, _) = listing_summary(&entries, None);
+        assert_eq!(unjoined.joined_entries, 3);
+    }
+}
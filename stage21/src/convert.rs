@@ -0,0 +1,188 @@
+use clap::ValueEnum;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat, ImageResult};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum TargetFormat {
+    Jpg,
+    Png,
+    /// Lossless, the image crate has no lossy WebP encoder
+    Webp,
+}
+
+impl TargetFormat {
+    #[inline]
+    pub fn ext(self) -> &'static str {
+        match self {
+            TargetFormat::Jpg => "jpg",
+            TargetFormat::Png => "png",
+            TargetFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Objects whose extension is in `source_exts` are converted to `target`, everything else is left
+/// alone
+#[derive(Debug, Clone)]
+pub struct ConvertPolicy {
+    source_exts: HashSet<String>,
+    pub target: TargetFormat,
+    /// JPEG quality, ignored by the lossless targets
+    pub quality: u8,
+}
+
+impl ConvertPolicy {
+    pub fn new<I, S>(source_exts: I, target: TargetFormat, quality: u8) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut source_exts: HashSet<String> = source_exts
+            .into_iter()
+            .map(|ext| ext.as_ref().trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+        // a converted object never qualifies again
+        source_exts.remove(target.ext());
+        if target == TargetFormat::Jpg {
+            source_exts.remove("jpeg");
+        }
+        Self {
+            source_exts,
+            target,
+            quality: quality.clamp(1, 100),
+        }
+    }
+
+    /// Extensionless keys are left alone, their format is unknown without downloading them
+    pub fn needs_conversion(&self, path: &str) -> bool {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => self.source_exts.contains(&ext.to_ascii_lowercase()),
+            None => false,
+        }
+    }
+
+    /// Decodes `src` by its content and encodes it in the target format
+    pub fn convert(&self, src: &[u8]) -> ImageResult<Vec<u8>> {
+        let img = image::load_from_memory(src)?;
+        let mut out = Cursor::new(Vec::new());
+        match self.target {
+            TargetFormat::Jpg => {
+                let encoder = JpegEncoder::new_with_quality(&mut out, self.quality);
+                DynamicImage::ImageRgb8(img.to_rgb8()).write_with_encoder(encoder)?;
+            }
+            TargetFormat::Png => img.write_to(&mut out, ImageFormat::Png)?,
+            TargetFormat::Webp => {
+                DynamicImage::ImageRgba8(img.to_rgba8()).write_to(&mut out, ImageFormat::WebP)?
+            }
+        }
+        Ok(out.into_inner())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct SizeSummary {
+    pub files: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl SizeSummary {
+    pub fn add(&mut self, before: usize, after: usize) {
+        self.files += 1;
+        self.bytes_before += before as u64;
+        self.bytes_after += after as u64;
+    }
+
+    /// Positive when the converted objects are larger
+    #[inline]
+    pub fn delta(&self) -> i64 {
+        self.bytes_after as i64 - self.bytes_before as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+    use std::fs;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        fs::read(format!("../assets/test_images/{}", name)).unwrap()
+    }
+
+    fn max_channel_diff(a: &DynamicImage, b: &DynamicImage) -> u8 {
+        a.to_rgb8()
+            .as_raw()
+            .iter()
+            .zip(b.to_rgb8().as_raw())
+            .map(|(x, y)| x.abs_diff(*y))
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_needs_conversion() {
+        let policy = ConvertPolicy::new(["bmp", ".TIFF", "heic", "webp"], TargetFormat::Webp, 90);
+        assert!(policy.needs_conversion("a/b.bmp"));
+        assert!(policy.needs_conversion("a/b.tiff"));
+        assert!(policy.needs_conversion("a.b/c.HEIC"));
+        // unknown extensions are left alone
+        assert!(!policy.needs_conversion("a/b.psd"));
+        assert!(!policy.needs_conversion("a/b.png"));
+        assert!(!policy.needs_conversion("a/b.webp"));
+        assert!(!policy.needs_conversion("a/b"));
+        let policy = ConvertPolicy::new(["bmp", "jpeg"], TargetFormat::Jpg, 90);
+        assert!(!policy.needs_conversion("a/b.jpeg"));
+    }
+
+    #[test]
+    fn test_convert_lossless() {
+        for name in ["convert_0.bmp", "convert_1.tiff"] {
+            let src = fixture(name);
+            let original = image::load_from_memory(&src).unwrap();
+            for target in [TargetFormat::Png, TargetFormat::Webp] {
+                let policy = ConvertPolicy::new(["bmp", "tiff"], target, 90);
+                let converted = policy.convert(&src).unwrap();
+                assert_eq!(
+                    image::guess_format(&converted).unwrap(),
+                    ImageFormat::from_extension(target.ext()).unwrap()
+                );
+                let decoded = image::load_from_memory(&converted).unwrap();
+                assert_eq!(decoded.dimensions(), original.dimensions());
+                assert_eq!(max_channel_diff(&decoded, &original), 0, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_convert_jpg() {
+        let src = fixture("convert_0.bmp");
+        let original = image::load_from_memory(&src).unwrap();
+        let converted = ConvertPolicy::new(["bmp"], TargetFormat::Jpg, 95)
+            .convert(&src)
+            .unwrap();
+        assert_eq!(image::guess_format(&converted).unwrap(), ImageFormat::Jpeg);
+        let decoded = image::load_from_memory(&converted).unwrap();
+        assert_eq!(decoded.dimensions(), original.dimensions());
+        assert!(max_channel_diff(&decoded, &original) < 48);
+    }
+
+    #[test]
+    fn test_convert_garbage() {
+        let policy = ConvertPolicy::new(["bmp"], TargetFormat::Webp, 90);
+        assert!(policy.convert(b"definitely not an image").is_err());
+    }
+
+    #[test]
+    fn test_size_summary() {
+        let mut summary = SizeSummary::default();
+        summary.add(100, 40);
+        summary.add(10, 30);
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.delta(), -40);
+    }
+}
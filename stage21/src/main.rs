@@ -0,0 +1,266 @@
+mod convert;
+
+use crate::convert::{ConvertPolicy, SizeSummary, TargetFormat};
+use anyhow::Result;
+use clap::{ArgGroup, Parser};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use shared::atomic_write::atomic_write_with;
+use shared::logging::Logging;
+use shared::opendal::{GenShinOperator, decode_entry_list};
+use shared::stage_lock::LockArgs;
+use shared::stall::{StallArgs, StallConfig, StallError, for_each_watched};
+use shared::structure::{FailedExtFile, WrongExtFile};
+use std::fs;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+enum ConvertStatus {
+    /// `WrongExtFile` so stage8 can point the Qdrant payload at the new object
+    Converted {
+        file: WrongExtFile,
+        before: usize,
+        after: usize,
+    },
+    Failed(FailedExtFile),
+}
+
+#[derive(Debug, Default)]
+struct ConvertOutcome {
+    converted: Vec<WrongExtFile>,
+    failed: Vec<FailedExtFile>,
+    summary: SizeSummary,
+}
+
+#[derive(Serialize)]
+struct ConvertReport {
+    target_ext: &'static str,
+    dry_run: bool,
+    #[serde(flatten)]
+    summary: SizeSummary,
+    delta: i64,
+    failed: usize,
+}
+
+pub struct Stage21Operator {
+    op: GenShinOperator,
+    policy: ConvertPolicy,
+    dry_run: bool,
+    worker_num: usize,
+}
+
+impl Deref for Stage21Operator {
+    type Target = GenShinOperator;
+
+    fn deref(&self) -> &Self::Target {
+        &self.op
+    }
+}
+
+impl Stage21Operator {
+    pub fn new(policy: ConvertPolicy, dry_run: bool, worker_num: usize) -> Result<Self> {
+        let op = GenShinOperator::new()?;
+        Ok(Self {
+            op,
+            policy,
+            dry_run,
+            worker_num,
+        })
+    }
+
+    async fn convert_task(
+        self: Arc<Self>,
+        paths: Vec<String>,
+        stall: &StallConfig,
+    ) -> Result<(ConvertOutcome, Option<StallError>)> {
+        let pb = ProgressBar::new(paths.len() as u64);
+        let style = ProgressStyle::default_bar()
+            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
+        pb.set_style(style);
+        pb.set_message("Converting images...");
+        let tasks = paths.into_iter().map(|path| {
+            let op = self.clone();
+            let pb = pb.clone();
+            (path.clone(), async move {
+                let status = op.convert_single_task(path).await;
+                pb.inc(1);
+                status
+            })
+        });
+        let mut outcome = ConvertOutcome::default();
+        let watched = for_each_watched(tasks, self.worker_num, stall, |status| match status {
+            ConvertStatus::Converted {
+                file,
+                before,
+                after,
+            } => {
+                outcome.summary.add(before, after);
+                outcome.converted.push(file);
+            }
+            ConvertStatus::Failed(failed) => outcome.failed.push(failed),
+        })
+        .await;
+        pb.finish_with_message("Done");
+        Ok((outcome, watched.err()))
+    }
+
+    async fn convert_single_task(self: Arc<Self>, path: String) -> ConvertStatus {
+        let fail = |path: String, error: String| {
+            tracing::error!("Failed to convert {}: {}", path, error);
+            ConvertStatus::Failed(FailedExtFile { path, error })
+        };
+        let src = match self.op.read(&path).await {
+            Ok(buf) => buf.to_vec(),
+            Err(e) => return fail(path, format!("read error: {}", e)),
+        };
+        let before = src.len();
+        let this = self.clone();
+        let converted = match tokio::task::spawn_blocking(move || this.policy.convert(&src)).await {
+            Ok(Ok(converted)) => converted,
+            Ok(Err(e)) => return fail(path, format!("convert error: {}", e)),
+            Err(e) => return fail(path, format!("convert task error: {}", e)),
+        };
+        let after = converted.len();
+        let file = WrongExtFile {
+            path,
+            expected_ext: self.policy.target.ext().to_owned(),
+        };
+        let dst = file.renamed_path();
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: {} -> {} ({} -> {} bytes)",
+                &file.path,
+                &dst,
+                before,
+                after
+            );
+        } else {
+            // the original stays, its Qdrant payload points at it until stage8 ran
+            if let Err(e) = self.op.write(&dst, converted).await {
+                return fail(file.path, format!("write error: {}", e));
+            }
+            tracing::debug!("Converted {} to {}", &file.path, &dst);
+        }
+        ConvertStatus::Converted {
+            file,
+            before,
+            after,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "Stage21", version)]
+#[command(group(ArgGroup::new("input").args(&["filelist_checkpoint_path", "file_list"]).required(true)))]
+struct Cli {
+    /// stage5 listing checkpoint, candidates are picked by extension
+    #[arg(long)]
+    filelist_checkpoint_path: Option<PathBuf>,
+    /// JSON array of object keys, still filtered by `--source-exts`
+    #[arg(long)]
+    file_list: Option<PathBuf>,
+    /// Extensions converted, every other object is left untouched, the target's never qualifies
+    #[arg(long, value_delimiter = ',', default_value = "bmp,tif,tiff,heic")]
+    source_exts: Vec<String>,
+    #[arg(long, value_enum, default_value = "webp")]
+    target: TargetFormat,
+    /// JPEG quality, lossless targets ignore it
+    #[arg(long, default_value = "90")]
+    quality: u8,
+    #[arg(long, default_value = "false")]
+    dry_run: bool,
+    #[arg(long, default_value = "16")]
+    worker_num: usize,
+    #[arg(long, default_value = "ext_files_convert")]
+    save_result_prefix: String,
+    #[command(flatten)]
+    stall: StallArgs,
+    #[command(flatten)]
+    lock: LockArgs,
+    /// Prometheus text dump of this run's metrics
+    #[arg(long, default_value = "stage21_metrics.prom")]
+    metrics_file: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let _logging = Logging::new("stage21").init()?;
+    let cli = Cli::parse();
+    let _lock = cli.lock.acquire("stage21")?;
+    let policy = ConvertPolicy::new(&cli.source_exts, cli.target, cli.quality);
+    let paths: Vec<String> = match (&cli.filelist_checkpoint_path, &cli.file_list) {
+        (Some(checkpoint), _) => {
+            let (entries, _) = decode_entry_list(&fs::read(checkpoint)?)?;
+            entries.into_iter().map(|entry| entry.path).collect()
+        }
+        (None, Some(list)) => serde_json::from_slice(&fs::read(list)?)?,
+        (None, None) => unreachable!("clap requires one input"),
+    };
+    let total = paths.len();
+    let paths: Vec<String> = paths
+        .into_iter()
+        .filter(|path| policy.needs_conversion(path))
+        .collect();
+    tracing::info!(
+        "{} of {} objects need conversion to {}",
+        paths.len(),
+        total,
+        cli.target.ext()
+    );
+    let op = Stage21Operator::new(policy, cli.dry_run, cli.worker_num)?;
+    let stall = StallConfig::from(&cli.stall);
+    let (outcome, stalled) = Arc::new(op).convert_task(paths, &stall).await?;
+    let summary = outcome.summary;
+    tracing::info!(
+        "Converted {} objects, {} -> {} bytes ({:+} bytes), {} failed",
+        summary.files,
+        summary.bytes_before,
+        summary.bytes_after,
+        summary.delta(),
+        outcome.failed.len()
+    );
+    let report = ConvertReport {
+        target_ext: cli.target.ext(),
+        dry_run: cli.dry_run,
+        summary,
+        delta: summary.delta(),
+        failed: outcome.failed.len(),
+    };
+    atomic_write_with(format!("{}_summary.json", cli.save_result_prefix), |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report)?)
+    })?;
+    if !cli.dry_run {
+        let save_path = format!("{}_converted.json", cli.save_result_prefix);
+        atomic_write_with(&save_path, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer(w, &outcome.converted)?)
+        })?;
+        tracing::info!(
+            "Saved converted list to {}, feed it to stage8 --wrong-ext-file-list, the originals \
+             can go once it ran",
+            &save_path
+        );
+    }
+    if !outcome.failed.is_empty() {
+        let filename = format!(
+            "{}_failed_{}.json",
+            cli.save_result_prefix,
+            chrono::Local::now().format("%Y%m%d_%H%M%S")
+        );
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &outcome.failed)?)
+        })?;
+        tracing::error!(
+            "Some conversions failed, details saved to {}. Total failed: {}",
+            &filename,
+            outcome.failed.len()
+        );
+    }
+    shared::metrics::dump_prometheus_to(&cli.metrics_file)?;
+    tracing::info!("Metrics saved to {}", cli.metrics_file.display());
+    if let Some(e) = stalled {
+        tracing::error!("Results above are partial");
+        return Err(e.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,195 @@
+mod subtract;
+
+use crate::subtract::{image_cluster_index, subtract_co_clustered};
+use anyhow::Result;
+use clap::Parser;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use mimalloc::MiMalloc;
+use rayon::prelude::*;
+use shared::atomic_write::atomic_write_with;
+use shared::cluster::{
+    bucketed_merge, centroid_signature, greedy_cluster, sampled_linkage_violations,
+};
+use shared::cluster_file::{LegacyFormat, read_clusters, write_clusters};
+use shared::config::Thresholds;
+use shared::logging::Logging;
+use shared::migrations::load_neko_points;
+use shared::point_explorer::PointExplorer;
+use shared::text::{DEFAULT_GARBAGE_THRESHOLD, DEFAULT_MAX_GRAPHEMES, garbage_score, sanitize};
+use shared::validation::{TEXT_VECTOR_DIM, validate_neko_points_dim};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[global_allocator]
+static GLOBAL: MiMalloc = MiMalloc;
+
+/// 4096 merge buckets, as in stage1
+const MERGE_BITS: u32 = 12;
+const VERIFY_SAMPLES: usize = 2000;
+const TEXT_DIM: usize = TEXT_VECTOR_DIM;
+
+#[derive(Parser)]
+#[command(about = "Cluster points by OCR text vector, keeping clusters the image clusters miss")]
+struct Args {
+    #[arg(short = 'm', long, default_value = "points_map.bin")]
+    points_map: PathBuf,
+    /// Clusters written by stage1
+    #[arg(long, default_value = "global_clusters.pkl")]
+    image_clusters: PathBuf,
+    /// Cosine similarity of two texts in a cluster, the configured `text_sim` when unset
+    #[arg(long)]
+    threshold: Option<f32>,
+    /// Points per local clustering chunk
+    #[arg(long, default_value_t = 20000)]
+    chunk_size: usize,
+    /// Cluster file, the format stage3 reads
+    #[arg(short, long, default_value = "text_clusters.pkl")]
+    output: PathBuf,
+    /// Only warn when the points map fails validation, text vectors of the wrong dimension are
+    /// then left out
+    #[arg(long)]
+    lenient: bool,
+    /// Garbage score from which an OCR text is left out, the vectors of noise read off an image
+    /// land close to each other whatever the image
+    #[arg(long, default_value_t = DEFAULT_GARBAGE_THRESHOLD)]
+    garbage_threshold: f32,
+}
+
+fn similar(
+    explorer: &PointExplorer<f32, TEXT_DIM>,
+    threshold: f32,
+) -> impl Fn(&Uuid, &Uuid) -> bool + Sync + '_ {
+    move |a, b| explorer.get_cosine_sim((a, b)).unwrap() > threshold
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let threshold = match args.threshold {
+        Some(threshold) => threshold,
+        None => Thresholds::init()?.text_sim,
+    };
+    let _logging = Logging::new("stage22").init()?;
+
+    let points = load_neko_points(&args.points_map)?;
+    let validation = validate_neko_points_dim(&points, TEXT_DIM);
+    if !validation.is_clean() {
+        anyhow::ensure!(
+            args.lenient,
+            "{} is invalid, pass --lenient to run anyway: {}",
+            args.points_map.display(),
+            validation
+        );
+        tracing::warn!(
+            "{} is invalid, continuing because of --lenient: {}",
+            args.points_map.display(),
+            validation
+        );
+    }
+    let mut garbage = 0;
+    let mut texts: Vec<(Uuid, &[f32])> = points
+        .iter()
+        .filter_map(|(id, point)| {
+            let text_info = point.text_info.as_ref()?;
+            if garbage_score(&text_info.text).is_garbage(args.garbage_threshold) {
+                tracing::debug!(
+                    "Garbage text of {}: {}",
+                    id,
+                    sanitize(&text_info.text, DEFAULT_MAX_GRAPHEMES)
+                );
+                garbage += 1;
+                return None;
+            }
+            Some((*id, &text_info.text_vector[..]))
+        })
+        .collect();
+    if garbage > 0 {
+        tracing::warn!(
+            "Skipped {} texts scoring {} or more as garbage",
+            garbage,
+            args.garbage_threshold
+        );
+    }
+    let total_texts = texts.len();
+    texts.retain(|(_, vector)| vector.len() == TEXT_DIM);
+    if texts.len() < total_texts {
+        tracing::warn!(
+            "Skipped {} text vectors that are not {} long",
+            total_texts - texts.len(),
+            TEXT_DIM
+        );
+    }
+    // the points map is a HashMap, sorting keeps the greedy passes reproducible across runs
+    texts.sort_unstable_by_key(|(id, _)| *id);
+    let mut explorer: PointExplorer<f32, TEXT_DIM> = PointExplorer::default();
+    explorer.extend(texts.iter().copied());
+    tracing::info!(
+        "{} of {} points have a text vector",
+        explorer.len(),
+        points.len()
+    );
+    drop(points);
+
+    let all_ids: Vec<Uuid> = explorer.iter().map(|(id, _)| *id).collect();
+    let chunks: Vec<&[Uuid]> = all_ids.chunks(args.chunk_size.max(1)).collect();
+    let m = MultiProgress::new();
+    let style = ProgressStyle::default_bar()
+        .template("{spinner:.green} {msg} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?
+        .progress_chars("#>-");
+    let pb_local = m.add(ProgressBar::new(chunks.len() as u64));
+    pb_local.set_style(style.clone());
+    pb_local.set_message("Local clustering");
+    let local_vec: Vec<Vec<HashSet<Uuid>>> = chunks
+        .par_iter()
+        .map(|&chunk| {
+            let res = greedy_cluster(chunk, similar(&explorer, threshold));
+            pb_local.inc(1);
+            res
+        })
+        .collect();
+    pb_local.finish_with_message("Local clustering done");
+
+    let local_count = local_vec.iter().map(Vec::len).sum::<usize>();
+    let pb_merge = m.add(ProgressBar::new(local_count as u64));
+    pb_merge.set_style(style);
+    pb_merge.set_message("Global merging");
+    let signature = |cluster: &HashSet<Uuid>| {
+        let vectors = cluster
+            .iter()
+            .map(|id| &explorer.get_vector(id).expect("clustered id")[..]);
+        centroid_signature(vectors, MERGE_BITS)
+    };
+    let text_clusters = bucketed_merge(
+        local_vec,
+        MERGE_BITS,
+        signature,
+        similar(&explorer, threshold),
+        |n| pb_merge.inc(n as u64),
+    );
+    pb_merge.finish_with_message("Global merging done");
+    let violations = sampled_linkage_violations(
+        &text_clusters,
+        VERIFY_SAMPLES,
+        similar(&explorer, threshold),
+    );
+    anyhow::ensure!(
+        violations.is_empty(),
+        "merged text clusters hold dissimilar pairs: {:?}",
+        violations
+    );
+    tracing::info!("{} text clusters", text_clusters.len());
+
+    let image_clusters = read_clusters(&args.image_clusters, LegacyFormat::Pickle)?;
+    let image_index = image_cluster_index(&image_clusters);
+    let (text_clusters, summary) = subtract_co_clustered(text_clusters, &image_index);
+    tracing::info!(
+        "Kept {} text clusters with {} pairs not in any image cluster, dropped {}",
+        summary.kept,
+        summary.new_pairs,
+        summary.dropped
+    );
+
+    atomic_write_with(&args.output, |w| write_clusters(w, &text_clusters))?;
+    tracing::info!("Saved text clusters to {}", args.output.display());
+    Ok(())
+}
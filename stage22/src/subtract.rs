@@ -0,0 +1,112 @@
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SubtractSummary {
+    pub kept: usize,
+    /// Text clusters whose every pair already shares an image cluster, singletons included
+    pub dropped: usize,
+    /// Pairs of the kept clusters that no image cluster holds
+    pub new_pairs: usize,
+}
+
+/// Point -> position of its image cluster in `global_clusters.pkl`
+pub fn image_cluster_index(image_clusters: &[HashSet<Uuid>]) -> HashMap<Uuid, usize> {
+    image_clusters
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, cluster)| cluster.iter().map(move |id| (*id, idx)))
+        .collect()
+}
+
+fn pairs(n: usize) -> usize {
+    n * n.saturating_sub(1) / 2
+}
+
+/// Pairs of `cluster` whose points are not in the same image cluster
+///
+/// Points missing from `image_index` count as their own image cluster.
+pub fn new_pairs(cluster: &HashSet<Uuid>, image_index: &HashMap<Uuid, usize>) -> usize {
+    let mut groups: HashMap<usize, usize> = HashMap::new();
+    for idx in cluster.iter().filter_map(|id| image_index.get(id)) {
+        *groups.entry(*idx).or_default() += 1;
+    }
+    pairs(cluster.len()) - groups.values().copied().map(pairs).sum::<usize>()
+}
+
+/// Keeps the text clusters holding at least one pair the image clusters do not
+///
+/// A kept cluster stays whole: each of its points pairs with some point of another image cluster,
+/// and reviewers need the already co-clustered members to judge the new pairs.
+pub fn subtract_co_clustered(
+    text_clusters: Vec<HashSet<Uuid>>,
+    image_index: &HashMap<Uuid, usize>,
+) -> (Vec<HashSet<Uuid>>, SubtractSummary) {
+    let mut summary = SubtractSummary::default();
+    let kept = text_clusters
+        .into_iter()
+        .filter(|cluster| match new_pairs(cluster, image_index) {
+            0 => {
+                summary.dropped += 1;
+                false
+            }
+            n => {
+                summary.kept += 1;
+                summary.new_pairs += n;
+                true
+            }
+        })
+        .collect();
+    (kept, summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(ids: &[u128]) -> HashSet<Uuid> {
+        ids.iter().copied().map(Uuid::from_u128).collect()
+    }
+
+    #[test]
+    fn test_new_pairs() {
+        let index = image_cluster_index(&[set(&[1, 2, 3]), set(&[4, 5])]);
+        assert_eq!(index.len(), 5);
+        assert_eq!(new_pairs(&set(&[1, 2, 3]), &index), 0);
+        // 1-4, 1-5, 2-4, 2-5
+        assert_eq!(new_pairs(&set(&[1, 2, 4, 5]), &index), 4);
+        // 9 has no image cluster, it pairs newly with everyone
+        assert_eq!(new_pairs(&set(&[1, 2, 9]), &index), 2);
+        assert_eq!(new_pairs(&set(&[8, 9]), &index), 1);
+        assert_eq!(new_pairs(&set(&[9]), &index), 0);
+        assert_eq!(new_pairs(&HashSet::new(), &index), 0);
+    }
+
+    #[test]
+    fn test_subtract_co_clustered() {
+        let image_clusters = vec![set(&[1, 2, 3]), set(&[4, 5]), set(&[6, 7])];
+        let index = image_cluster_index(&image_clusters);
+        let text_clusters = vec![
+            // inside one image cluster, nothing new
+            set(&[1, 2]),
+            // spans two image clusters
+            set(&[3, 4, 5]),
+            // equal to an image cluster
+            set(&[6, 7]),
+            // one image cluster plus an unclustered point
+            set(&[6, 10]),
+            set(&[11]),
+            set(&[12, 13]),
+        ];
+        let (kept, summary) = subtract_co_clustered(text_clusters, &index);
+        assert_eq!(kept, vec![set(&[3, 4, 5]), set(&[6, 10]), set(&[12, 13])]);
+        assert_eq!(
+            summary,
+            SubtractSummary {
+                kept: 3,
+                dropped: 3,
+                new_pairs: 2 + 1 + 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use indicatif::ProgressBar;
+use shared::exact_dup::{ContentId, listed_content_id};
+use shared::neko_uuid::NekoUuid;
+use shared::opendal::{Entry, EntryMode, GenShinOperator};
+use shared::stall::{StallConfig, for_each_watched};
+use uuid::Uuid;
+
+#[derive(Debug, Default)]
+pub struct ContentIds {
+    pub ids: Vec<(Uuid, ContentId)>,
+    /// Files hashed after download for lack of an md5
+    pub downloaded: usize,
+    /// Paths whose download failed, left out of `ids`
+    pub failed: Vec<String>,
+}
+
+/// Listed files by the point their file stem names, with the number of files named otherwise
+pub fn listed_points(entries: &[Entry]) -> (Vec<(Uuid, &Entry)>, usize) {
+    let mut skipped = 0;
+    let points = entries
+        .iter()
+        .filter(|e| e.metadata.mode == EntryMode::FILE)
+        .filter_map(|e| match Uuid::parse_str(e.to_point()) {
+            Ok(id) => Some((id, e)),
+            Err(_) => {
+                skipped += 1;
+                None
+            }
+        })
+        .collect();
+    (points, skipped)
+}
+
+/// Content identity of every file, downloading at most `concurrency` of those without an md5 at
+/// a time
+pub async fn content_ids(
+    op: &GenShinOperator,
+    files: &[(Uuid, &Entry)],
+    concurrency: usize,
+    pb: &ProgressBar,
+) -> anyhow::Result<ContentIds> {
+    let neko = NekoUuid::new();
+    let mut out = ContentIds::default();
+    let mut pending = Vec::new();
+    for &(id, entry) in files {
+        match listed_content_id(entry) {
+            Some(content) => out.ids.push((id, content)),
+            None => pending.push((id, entry.path.as_str())),
+        }
+    }
+    pb.set_length(pending.len() as u64);
+    let neko = &neko;
+    let jobs = pending.iter().map(|&(id, path)| {
+        let job = async move {
+            let res = op.read(path).await.map(|buf| neko.generate(&buf.to_vec()));
+            (id, path, res)
+        };
+        (path, job)
+    });
+    for_each_watched(
+        jobs,
+        concurrency.max(1),
+        &StallConfig::default(),
+        |(id, path, res)| {
+            match res {
+                Ok(hash) => {
+                    out.ids.push((id, ContentId::Neko(hash)));
+                    out.downloaded += 1;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to download {}: {}", path, e);
+                    out.failed.push(path.to_string());
+                }
+            }
+            pb.inc(1);
+        },
+    )
+    .await?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opendal::Operator;
+    use opendal::services::Memory;
+    use shared::exact_dup::group_by_content;
+    use shared::opendal::Metadata;
+    use std::collections::HashSet;
+
+    fn file(path: &str, etag: Option<&str>) -> Entry {
+        Entry {
+            path: path.to_string(),
+            metadata: Metadata {
+                mode: EntryMode::FILE,
+                is_current: None,
+                is_deleted: false,
+                cache_control: None,
+                content_disposition: None,
+                content_length: Some(1),
+                content_md5: None,
+                content_range: None,
+                content_type: None,
+                content_encoding: None,
+                etag: etag.map(String::from),
+                last_modified: None,
+                version: None,
+                user_metadata: None,
+            },
+        }
+    }
+
+    fn path(n: u128) -> String {
+        format!("img/{}.png", Uuid::from_u128(n))
+    }
+
+    #[tokio::test]
+    async fn test_content_ids() {
+        let op = Operator::new(Memory::default()).unwrap().finish();
+        op.write(&path(1), b"same".to_vec()).await.unwrap();
+        op.write(&path(2), b"same".to_vec()).await.unwrap();
+        op.write(&path(3), b"other".to_vec()).await.unwrap();
+        let md5 = "\"0123456789abcdef0123456789abcdef\"";
+        let entries = vec![
+            file(&path(1), None),
+            file(&path(2), Some("\"0123456789abcdef0123456789abcdef-2\"")),
+            file(&path(3), None),
+            // listed but gone since
+            file(&path(4), None),
+            file(&path(5), Some(md5)),
+            file(&path(6), Some(md5)),
+            file("img/thumbnail.png", Some(md5)),
+        ];
+        let (files, skipped) = listed_points(&entries);
+        assert_eq!(files.len(), 6);
+        assert_eq!(skipped, 1);
+
+        let op = GenShinOperator::from(op);
+        let ids = content_ids(&op, &files, 2, &ProgressBar::hidden())
+            .await
+            .unwrap();
+        assert_eq!(ids.downloaded, 3);
+        assert_eq!(ids.failed, vec![path(4)]);
+        let groups = group_by_content(ids.ids);
+        let set =
+            |ids: &[u128]| -> HashSet<Uuid> { ids.iter().map(|&n| Uuid::from_u128(n)).collect() };
+        assert_eq!(groups, vec![set(&[1, 2]), set(&[5, 6])]);
+    }
+}
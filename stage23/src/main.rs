@@ -0,0 +1,91 @@
+mod hash;
+
+use crate::hash::{content_ids, listed_points};
+use anyhow::Result;
+use clap::Parser;
+use indicatif::{ProgressBar, ProgressStyle};
+use shared::atomic_write::{atomic_write, atomic_write_with};
+use shared::cluster_file::write_clusters;
+use shared::exact_dup::{ExactDupReduction, group_by_content};
+use shared::logging::Logging;
+use shared::opendal::{GenShinOperator, load_entry_list};
+use shared::uuid_set::UuidSet;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(about = "Group byte-identical objects of the S3 listing ahead of similarity clustering")]
+struct Args {
+    /// Listing saved by stage5
+    #[arg(short, long, default_value = "opendal_list_file.bin")]
+    listing: PathBuf,
+    /// Downloads in flight for objects without an md5
+    #[arg(short, long, default_value_t = 64)]
+    concurrency: usize,
+    /// Cluster file of the exact duplicate groups, stage1 and stage14 reduce by it
+    #[arg(short, long, default_value = "exact_clusters.pkl")]
+    output: PathBuf,
+    /// `UuidSet` of the listed points with one representative per exact duplicate group
+    #[arg(long, default_value = "exact_reduced.bin")]
+    reduced: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let _logging = Logging::new("stage23").init()?;
+
+    let (entries, _) = load_entry_list(&args.listing)?;
+    let (files, skipped) = listed_points(&entries);
+    if skipped > 0 {
+        tracing::warn!("Skipped {} files not named by a point uuid", skipped);
+    }
+    tracing::info!("{} files of {} listed entries", files.len(), entries.len());
+
+    let op = GenShinOperator::new()?;
+    let pb = ProgressBar::new(0);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} {msg} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?
+            .progress_chars("#>-"),
+    );
+    pb.set_message("Hashing objects without an md5");
+    let ids = content_ids(&op, &files, args.concurrency, &pb).await?;
+    pb.finish_with_message("Hashing done");
+    tracing::info!(
+        "{} files by listed md5, {} hashed after download, {} failed",
+        ids.ids.len() - ids.downloaded,
+        ids.downloaded,
+        ids.failed.len()
+    );
+
+    let groups = group_by_content(ids.ids);
+    let mut points: Vec<Uuid> = files
+        .iter()
+        .map(|(id, _)| *id)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    points.sort_unstable();
+    let (reduced, reduction) = ExactDupReduction::reduce(&points, &groups);
+    tracing::info!(
+        "{} exact duplicate groups, {} of {} points left to cluster",
+        groups.len(),
+        reduced.len(),
+        points.len()
+    );
+    debug_assert_eq!(reduced.len() + reduction.dropped(), points.len());
+
+    atomic_write_with(&args.output, |w| write_clusters(w, &groups))?;
+    tracing::info!("Saved exact duplicate groups to {}", args.output.display());
+    let reduced: UuidSet = reduced.into_iter().collect();
+    atomic_write(&args.reduced, reduced.as_bytes())?;
+    tracing::info!("Saved reduced point set to {}", args.reduced.display());
+    anyhow::ensure!(
+        ids.failed.is_empty(),
+        "{} objects could not be downloaded, their duplicates are not grouped",
+        ids.failed.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,343 @@
+use serde::Serialize;
+use shared::structure::FinalClassification;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use uuid::Uuid;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Group {
+    KeptTextAnomaly,
+    TriagedInvalid,
+    TriagedSameFrame,
+    TriagedKeep,
+    TriagedDelete,
+    KeptNonGif,
+    OtherDelete,
+    ReviewedKeep,
+}
+
+impl Group {
+    #[inline]
+    pub fn is_kept(self) -> bool {
+        matches!(
+            self,
+            Group::KeptTextAnomaly | Group::TriagedKeep | Group::KeptNonGif | Group::ReviewedKeep
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionKind {
+    KeptToDeleted,
+    DeletedToKept,
+    GroupChanged,
+    OnlyInOld,
+    OnlyInNew,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UuidTransition {
+    pub id: Uuid,
+    pub kind: TransitionKind,
+    pub old: Option<Group>,
+    pub new: Option<Group>,
+    pub old_entry: Option<usize>,
+    pub new_entry: Option<usize>,
+}
+
+/// Best counterpart of an entry on the other side
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClusterMatch {
+    pub old: usize,
+    pub new: usize,
+    pub jaccard: f64,
+}
+
+/// One entry whose members spread over several entries on the other side
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClusterSpread {
+    pub entry: usize,
+    pub counterparts: Vec<usize>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ClassificationDiff {
+    pub old_entries: usize,
+    pub new_entries: usize,
+    pub unchanged_uuids: usize,
+    pub counts: BTreeMap<TransitionKind, usize>,
+    pub transitions: Vec<UuidTransition>,
+    /// Best match by Jaccard for every old entry sharing at least one uuid with the new file
+    pub matches: Vec<ClusterMatch>,
+    /// Old entries split over several new entries
+    pub splits: Vec<ClusterSpread>,
+    /// New entries merged from several old entries
+    pub merges: Vec<ClusterSpread>,
+    pub only_in_old: Vec<usize>,
+    pub only_in_new: Vec<usize>,
+}
+
+pub fn entry_members(entry: &FinalClassification) -> Vec<(Uuid, Group)> {
+    fn push(out: &mut Vec<(Uuid, Group)>, ids: Option<&Vec<Uuid>>, group: Group) {
+        out.extend(ids.into_iter().flatten().map(|&id| (id, group)));
+    }
+    let mut out = Vec::new();
+    push(
+        &mut out,
+        entry.kept_text_anomalies_group.as_ref(),
+        Group::KeptTextAnomaly,
+    );
+    push(
+        &mut out,
+        entry
+            .triaged_gif_and_invalid_group
+            .as_ref()
+            .map(|(ids, _)| ids),
+        Group::TriagedInvalid,
+    );
+    push(
+        &mut out,
+        entry.triaged_gif_and_discard_same_frame_group.as_ref(),
+        Group::TriagedSameFrame,
+    );
+    push(
+        &mut out,
+        entry.triaged_gif_and_then_will_keep_group.as_ref(),
+        Group::TriagedKeep,
+    );
+    push(
+        &mut out,
+        entry.triaged_gif_and_then_will_delete_group.as_ref(),
+        Group::TriagedDelete,
+    );
+    out.extend(entry.kept_non_gif.map(|id| (id, Group::KeptNonGif)));
+    push(
+        &mut out,
+        entry.other_need_delete_group.as_ref(),
+        Group::OtherDelete,
+    );
+    push(
+        &mut out,
+        entry.reviewed_keep_group.as_ref(),
+        Group::ReviewedKeep,
+    );
+    out
+}
+
+/// uuid -> (entry index, group) plus the member count of every entry
+struct ClassificationIndex {
+    by_uuid: HashMap<Uuid, (usize, Group)>,
+    sizes: Vec<usize>,
+}
+
+impl ClassificationIndex {
+    fn new(entries: &[FinalClassification]) -> Self {
+        let mut by_uuid = HashMap::new();
+        let mut sizes = Vec::with_capacity(entries.len());
+        for (idx, entry) in entries.iter().enumerate() {
+            let members = entry_members(entry);
+            sizes.push(members.len());
+            for (id, group) in members {
+                by_uuid.insert(id, (idx, group));
+            }
+        }
+        Self { by_uuid, sizes }
+    }
+
+    /// entry -> {counterpart entry -> shared uuid count}
+    fn overlaps(&self, other: &Self) -> Vec<BTreeMap<usize, usize>> {
+        let mut overlaps = vec![BTreeMap::new(); self.sizes.len()];
+        for (id, &(idx, _)) in self.by_uuid.iter() {
+            if let Some(&(other_idx, _)) = other.by_uuid.get(id) {
+                *overlaps[idx].entry(other_idx).or_default() += 1;
+            }
+        }
+        overlaps
+    }
+}
+
+#[inline]
+fn jaccard(shared: usize, a: usize, b: usize) -> f64 {
+    shared as f64 / (a + b - shared) as f64
+}
+
+/// Highest Jaccard first, lowest counterpart index on ties
+fn best_match(
+    overlap: &BTreeMap<usize, usize>,
+    size: usize,
+    sizes: &[usize],
+) -> Option<(usize, f64)> {
+    overlap
+        .iter()
+        .map(|(&idx, &shared)| (idx, jaccard(shared, size, sizes[idx])))
+        .fold(None, |best, (idx, score)| match best {
+            Some((_, best_score)) if best_score >= score => best,
+            _ => Some((idx, score)),
+        })
+}
+
+fn spreads(overlaps: &[BTreeMap<usize, usize>]) -> Vec<ClusterSpread> {
+    overlaps
+        .iter()
+        .enumerate()
+        .filter(|(_, overlap)| overlap.len() > 1)
+        .map(|(entry, overlap)| ClusterSpread {
+            entry,
+            counterparts: overlap.keys().copied().collect(),
+        })
+        .collect()
+}
+
+pub fn diff(old: &[FinalClassification], new: &[FinalClassification]) -> ClassificationDiff {
+    let old_index = ClassificationIndex::new(old);
+    let new_index = ClassificationIndex::new(new);
+    let old_overlaps = old_index.overlaps(&new_index);
+    let new_overlaps = new_index.overlaps(&old_index);
+
+    let mut diff = ClassificationDiff {
+        old_entries: old.len(),
+        new_entries: new.len(),
+        ..Default::default()
+    };
+    for (idx, overlap) in old_overlaps.iter().enumerate() {
+        match best_match(overlap, old_index.sizes[idx], &new_index.sizes) {
+            Some((new_idx, score)) => diff.matches.push(ClusterMatch {
+                old: idx,
+                new: new_idx,
+                jaccard: score,
+            }),
+            None if old_index.sizes[idx] > 0 => diff.only_in_old.push(idx),
+            None => {}
+        }
+    }
+    diff.only_in_new = new_overlaps
+        .iter()
+        .enumerate()
+        .filter(|&(idx, overlap)| overlap.is_empty() && new_index.sizes[idx] > 0)
+        .map(|(idx, _)| idx)
+        .collect();
+    diff.splits = spreads(&old_overlaps);
+    diff.merges = spreads(&new_overlaps);
+
+    let ids: HashSet<&Uuid> = old_index
+        .by_uuid
+        .keys()
+        .chain(new_index.by_uuid.keys())
+        .collect();
+    for &id in ids.iter() {
+        let before = old_index.by_uuid.get(id).copied();
+        let after = new_index.by_uuid.get(id).copied();
+        let kind = match (before.map(|(_, g)| g), after.map(|(_, g)| g)) {
+            (Some(a), Some(b)) if a == b => {
+                diff.unchanged_uuids += 1;
+                continue;
+            }
+            (Some(a), Some(b)) => match (a.is_kept(), b.is_kept()) {
+                (true, false) => TransitionKind::KeptToDeleted,
+                (false, true) => TransitionKind::DeletedToKept,
+                _ => TransitionKind::GroupChanged,
+            },
+            (Some(_), None) => TransitionKind::OnlyInOld,
+            (None, Some(_)) => TransitionKind::OnlyInNew,
+            (None, None) => unreachable!(),
+        };
+        *diff.counts.entry(kind).or_default() += 1;
+        diff.transitions.push(UuidTransition {
+            id: *id,
+            kind,
+            old: before.map(|(_, g)| g),
+            new: after.map(|(_, g)| g),
+            old_entry: before.map(|(idx, _)| idx),
+            new_entry: after.map(|(idx, _)| idx),
+        });
+    }
+    diff.transitions.sort_unstable_by_key(|t| (t.kind, t.id));
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::test_util::{classification as entry, id};
+
+    #[test]
+    fn test_reordered_entries_align() {
+        let old = vec![entry(1, &[2, 3]), entry(10, &[11])];
+        let new = vec![entry(10, &[11]), entry(1, &[2, 3])];
+        let diff = diff(&old, &new);
+        assert!(diff.transitions.is_empty());
+        assert_eq!(diff.unchanged_uuids, 5);
+        assert_eq!(
+            diff.matches,
+            vec![
+                ClusterMatch {
+                    old: 0,
+                    new: 1,
+                    jaccard: 1.0
+                },
+                ClusterMatch {
+                    old: 1,
+                    new: 0,
+                    jaccard: 1.0
+                },
+            ]
+        );
+        assert!(diff.splits.is_empty() && diff.merges.is_empty());
+    }
+
+    #[test]
+    fn test_split() {
+        // {1, 2, 3, 4, 5} becomes {1, 2, 3} + {4, 5}, 4 now survives
+        let old = vec![entry(1, &[2, 3, 4, 5])];
+        let new = vec![entry(4, &[5]), entry(1, &[2, 3])];
+        let diff = diff(&old, &new);
+        assert_eq!(
+            diff.splits,
+            vec![ClusterSpread {
+                entry: 0,
+                counterparts: vec![0, 1],
+            }]
+        );
+        assert!(diff.merges.is_empty());
+        assert_eq!(diff.matches[0].new, 1);
+        assert!((diff.matches[0].jaccard - 0.6).abs() < 1e-9);
+        assert_eq!(diff.counts.get(&TransitionKind::DeletedToKept), Some(&1));
+        assert_eq!(diff.transitions[0].id, id(4));
+        assert_eq!(diff.transitions[0].new, Some(Group::KeptNonGif));
+        assert_eq!(diff.transitions[0].new_entry, Some(0));
+    }
+
+    #[test]
+    fn test_merge_and_one_sided() {
+        // {1, 2} + {3, 4} merge into {1, 2, 3, 4, 5}, {7, 8} disappears, {9} is new
+        let old = vec![entry(1, &[2]), entry(3, &[4]), entry(7, &[8])];
+        let new = vec![entry(1, &[2, 3, 4, 5]), entry(9, &[])];
+        let diff = diff(&old, &new);
+        assert_eq!(
+            diff.merges,
+            vec![ClusterSpread {
+                entry: 0,
+                counterparts: vec![0, 1],
+            }]
+        );
+        assert!(diff.splits.is_empty());
+        assert_eq!(diff.only_in_old, vec![2]);
+        assert_eq!(diff.only_in_new, vec![1]);
+        assert_eq!(diff.counts.get(&TransitionKind::KeptToDeleted), Some(&1));
+        assert_eq!(diff.counts.get(&TransitionKind::OnlyInOld), Some(&2));
+        assert_eq!(diff.counts.get(&TransitionKind::OnlyInNew), Some(&2));
+        assert_eq!(diff.unchanged_uuids, 3);
+    }
+
+    #[test]
+    fn test_group_changed() {
+        let mut new = entry(1, &[]);
+        new.other_need_delete_group = None;
+        new.triaged_gif_and_then_will_delete_group = Some(vec![id(2)]);
+        let diff = diff(&[entry(1, &[2])], &[new]);
+        assert_eq!(diff.counts.get(&TransitionKind::GroupChanged), Some(&1));
+        assert_eq!(diff.transitions[0].old, Some(Group::OtherDelete));
+        assert_eq!(diff.transitions[0].new, Some(Group::TriagedDelete));
+    }
+}
@@ -0,0 +1,95 @@
+mod diff;
+
+use crate::diff::{ClassificationDiff, TransitionKind};
+use anyhow::Result;
+use clap::Parser;
+use shared::structure::FinalClassification;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(about = "Diff two stage9 final_classification.json files by cluster membership")]
+struct Args {
+    old: PathBuf,
+    new: PathBuf,
+    /// Print the full diff as JSON instead of the human readable report
+    #[arg(long, default_value = "false")]
+    json: bool,
+    /// Max transitions listed per kind in the human readable report
+    #[arg(long, default_value_t = 50)]
+    limit: usize,
+}
+
+fn load(path: &Path) -> Result<Vec<FinalClassification>> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+fn print_report(diff: &ClassificationDiff, limit: usize) {
+    println!(
+        "Entries: old = {}, new = {}",
+        diff.old_entries, diff.new_entries
+    );
+    println!("Unchanged uuids: {}", diff.unchanged_uuids);
+    for (kind, count) in diff.counts.iter() {
+        println!("  {:?}: {}", kind, count);
+    }
+    println!(
+        "Clusters: {} matched ({} identical), {} split, {} merged, {} only in old, {} only in new",
+        diff.matches.len(),
+        diff.matches.iter().filter(|m| m.jaccard == 1.0).count(),
+        diff.splits.len(),
+        diff.merges.len(),
+        diff.only_in_old.len(),
+        diff.only_in_new.len()
+    );
+    for kind in [
+        TransitionKind::KeptToDeleted,
+        TransitionKind::DeletedToKept,
+        TransitionKind::GroupChanged,
+    ] {
+        let transitions: Vec<_> = diff.transitions.iter().filter(|t| t.kind == kind).collect();
+        if transitions.is_empty() {
+            continue;
+        }
+        println!("{:?}:", kind);
+        for t in transitions.iter().take(limit) {
+            println!(
+                "  {} {:?} (entry {:?}) -> {:?} (entry {:?})",
+                t.id, t.old, t.old_entry, t.new, t.new_entry
+            );
+        }
+        if transitions.len() > limit {
+            println!("  ... {} more", transitions.len() - limit);
+        }
+    }
+    for split in diff.splits.iter().take(limit) {
+        println!(
+            "Split: old entry {} -> new entries {:?}",
+            split.entry, split.counterparts
+        );
+    }
+    for merge in diff.merges.iter().take(limit) {
+        println!(
+            "Merge: old entries {:?} -> new entry {}",
+            merge.counterparts, merge.entry
+        );
+    }
+    if !diff.only_in_old.is_empty() {
+        println!("Only in old: {:?}", diff.only_in_old);
+    }
+    if !diff.only_in_new.is_empty() {
+        println!("Only in new: {:?}", diff.only_in_new);
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let old = load(&args.old)?;
+    let new = load(&args.new)?;
+    let diff = diff::diff(&old, &new);
+    match args.json {
+        true => println!("{}", serde_json::to_string_pretty(&diff)?),
+        false => print_report(&diff, args.limit),
+    }
+    Ok(())
+}
@@ -0,0 +1,319 @@
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use shared::report::{Html, page};
+use shared::structure::NekoPoint;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Component, Path};
+use uuid::Uuid;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SizeBucket {
+    Pairs,
+    Small,
+    Medium,
+    Large,
+}
+
+impl SizeBucket {
+    pub const ALL: [SizeBucket; 4] = [
+        SizeBucket::Pairs,
+        SizeBucket::Small,
+        SizeBucket::Medium,
+        SizeBucket::Large,
+    ];
+
+    /// `None` for singletons, there is nothing to compare
+    pub fn of(len: usize) -> Option<Self> {
+        match len {
+            0 | 1 => None,
+            2 => Some(SizeBucket::Pairs),
+            3..=5 => Some(SizeBucket::Small),
+            6..=20 => Some(SizeBucket::Medium),
+            _ => Some(SizeBucket::Large),
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SizeBucket::Pairs => "pairs",
+            SizeBucket::Small => "3-5 members",
+            SizeBucket::Medium => "6-20 members",
+            SizeBucket::Large => "21+ members",
+        }
+    }
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            SizeBucket::Pairs => "pairs.html",
+            SizeBucket::Small => "small.html",
+            SizeBucket::Medium => "medium.html",
+            SizeBucket::Large => "large.html",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampledCluster {
+    /// Position in the clusters file, the same cluster keeps it across runs of one file
+    pub index: usize,
+    /// Sorted so pages compare line by line across runs
+    pub members: Vec<Uuid>,
+}
+
+/// Up to `per_bucket` clusters of every bucket, in file order
+///
+/// Every bucket draws from its own generator derived from `seed`, so one bucket's sample does not
+/// move when another bucket grows.
+pub fn sample_clusters(
+    clusters: &[HashSet<Uuid>],
+    per_bucket: usize,
+    seed: u64,
+) -> BTreeMap<SizeBucket, Vec<SampledCluster>> {
+    let mut candidates: BTreeMap<SizeBucket, Vec<usize>> = BTreeMap::new();
+    for (index, cluster) in clusters.iter().enumerate() {
+        if let Some(bucket) = SizeBucket::of(cluster.len()) {
+            candidates.entry(bucket).or_default().push(index);
+        }
+    }
+    candidates
+        .into_iter()
+        .map(|(bucket, indices)| {
+            let mut rng = Pcg64::seed_from_u64(seed.wrapping_add(bucket as u64));
+            let amount = per_bucket.min(indices.len());
+            let mut picked = rand::seq::index::sample(&mut rng, indices.len(), amount).into_vec();
+            picked.sort_unstable();
+            let sampled = picked
+                .into_iter()
+                .map(|i| {
+                    let index = indices[i];
+                    let mut members: Vec<Uuid> = clusters[index].iter().copied().collect();
+                    members.sort();
+                    SampledCluster { index, members }
+                })
+                .collect();
+            (bucket, sampled)
+        })
+        .collect()
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:1em}\
+section{border-top:1px solid #ccc;padding:.5em 0}\
+.members{display:flex;flex-wrap:wrap;gap:8px}\
+figure{margin:0;width:256px}\
+img{max-width:256px;max-height:256px;display:block}\
+.missing{width:256px;height:128px;background:#eee;display:flex;align-items:center;justify-content:center}\
+code{user-select:all;font-size:11px}\
+textarea{width:40em;font-family:monospace}";
+
+fn caption(point: Option<&NekoPoint>) -> String {
+    match point {
+        Some(point) => {
+            let size = point
+                .size
+                .map_or("size unknown".to_string(), |size| format!("{} bytes", size));
+            format!("{}x{}, {}", point.width, point.height, size)
+        }
+        None => "not in points map".to_string(),
+    }
+}
+
+/// One page holding the sampled clusters of `bucket` side by side
+pub fn render_bucket<'a>(
+    bucket: SizeBucket,
+    sampled: &[SampledCluster],
+    seed: u64,
+    src: impl Fn(&Uuid) -> Option<&'a str>,
+    points: &HashMap<Uuid, NekoPoint>,
+) -> String {
+    let title = format!("Clusters, {}", bucket.label());
+    let mut body = Html::new();
+    body.element("h1", &[], &title).element(
+        "p",
+        &[],
+        &format!("{} sampled clusters, seed {}", sampled.len(), seed),
+    );
+    for cluster in sampled {
+        let anchor = format!("cluster-{}", cluster.index);
+        let ids: Vec<String> = cluster.members.iter().map(Uuid::to_string).collect();
+        let rows = ids.len().min(8).to_string();
+        body.open("section", &[("id", &anchor)])
+            .element(
+                "h2",
+                &[],
+                &format!("Cluster {}, {} members", cluster.index, ids.len()),
+            )
+            .element(
+                "textarea",
+                &[("readonly", "readonly"), ("rows", &rows)],
+                &ids.join("\n"),
+            )
+            .open("div", &[("class", "members")]);
+        for (id, text) in cluster.members.iter().zip(ids.iter()) {
+            body.open("figure", &[]);
+            match src(id) {
+                Some(url) => body.void("img", &[("src", url), ("alt", text), ("loading", "lazy")]),
+                None => body.element("div", &[("class", "missing")], "no image"),
+            };
+            body.open("figcaption", &[])
+                .element("code", &[], text)
+                .void("br", &[])
+                .text(&caption(points.get(id)))
+                .close("figcaption")
+                .close("figure");
+        }
+        body.close("div").close("section");
+    }
+    page(&title, STYLE, &body)
+}
+
+/// Links to every bucket page with its sample size
+pub fn render_index(sampled: &BTreeMap<SizeBucket, Vec<SampledCluster>>, seed: u64) -> String {
+    let mut body = Html::new();
+    body.element("h1", &[], "Cluster gallery")
+        .element("p", &[], &format!("seed {}", seed))
+        .open("ul", &[]);
+    for bucket in SizeBucket::ALL {
+        let count = sampled.get(&bucket).map_or(0, Vec::len);
+        body.open("li", &[]);
+        match count {
+            0 => body.text(&format!("{}: no clusters", bucket.label())),
+            _ => body
+                .element("a", &[("href", bucket.file_name())], bucket.label())
+                .text(&format!(": {} sampled", count)),
+        };
+        body.close("li");
+    }
+    body.close("ul");
+    page("Cluster gallery", STYLE, &body)
+}
+
+/// `src` of the image at `path` for a page in `base`, both absolute, so the gallery opens from
+/// any working directory
+pub fn relative_src(path: &Path, base: &Path) -> String {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let up = base.components().skip(common).map(|_| "..".to_string());
+    let down = path.components().skip(common).map(|c| match c {
+        Component::ParentDir => "..".to_string(),
+        c => c.as_os_str().to_string_lossy().into_owned(),
+    });
+    up.chain(down).collect::<Vec<_>>().join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(start: u128, len: u128) -> HashSet<Uuid> {
+        (start..start + len).map(Uuid::from_u128).collect()
+    }
+
+    fn clusters() -> Vec<HashSet<Uuid>> {
+        let mut out = vec![cluster(0, 1)];
+        out.extend((0..10).map(|i| cluster(100 + i * 2, 2)));
+        out.extend((0..3).map(|i| cluster(1000 + i * 10, 4)));
+        out.push(cluster(5000, 20));
+        out.push(cluster(6000, 21));
+        out
+    }
+
+    #[test]
+    fn test_bucket_bounds() {
+        let buckets: Vec<_> = [1, 2, 3, 5, 6, 20, 21].map(SizeBucket::of).into();
+        assert_eq!(
+            buckets,
+            vec![
+                None,
+                Some(SizeBucket::Pairs),
+                Some(SizeBucket::Small),
+                Some(SizeBucket::Small),
+                Some(SizeBucket::Medium),
+                Some(SizeBucket::Medium),
+                Some(SizeBucket::Large),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sample_reproducible() {
+        let clusters = clusters();
+        let a = sample_clusters(&clusters, 4, 7);
+        assert_eq!(a, sample_clusters(&clusters, 4, 7));
+        assert_eq!(a[&SizeBucket::Pairs].len(), 4);
+        assert!(a[&SizeBucket::Pairs].iter().all(|c| c.members.len() == 2));
+        assert!(
+            a[&SizeBucket::Pairs]
+                .windows(2)
+                .all(|w| w[0].index < w[1].index)
+        );
+        // fewer candidates than asked for, all taken
+        assert_eq!(a[&SizeBucket::Small].len(), 3);
+        assert_eq!(a[&SizeBucket::Medium][0].index, 14);
+        assert_eq!(a[&SizeBucket::Large][0].index, 15);
+        let members = &a[&SizeBucket::Large][0].members;
+        assert!(members.windows(2).all(|w| w[0] < w[1]));
+        let other_seeds: Vec<_> = (0..8)
+            .map(|seed| sample_clusters(&clusters, 4, seed)[&SizeBucket::Pairs].clone())
+            .collect();
+        assert!(other_seeds.iter().any(|s| *s != a[&SizeBucket::Pairs]));
+    }
+
+    #[test]
+    fn test_render() {
+        let clusters = clusters();
+        let sampled = sample_clusters(&clusters, 10, 1);
+        let pairs = &sampled[&SizeBucket::Pairs];
+        let first = pairs[0].members[0];
+        let points = HashMap::from([(
+            first,
+            NekoPoint {
+                id: first,
+                height: 20,
+                width: 10,
+                size: Some(300),
+                categories: None,
+                text_info: None,
+                qdrant_num_id: None,
+                extra: None,
+            },
+        )]);
+        let urls = HashMap::from([(first, "https://cdn/a.png?x=1&y=2".to_string())]);
+        let html = render_bucket(
+            SizeBucket::Pairs,
+            pairs,
+            1,
+            |id| urls.get(id).map(String::as_str),
+            &points,
+        );
+        assert!(html.contains(r#"src="https://cdn/a.png?x=1&amp;y=2""#));
+        assert!(html.contains("10x20, 300 bytes"));
+        assert!(html.contains("no image"));
+        assert_eq!(html.matches("<section").count(), 10);
+        for cluster in pairs {
+            assert!(html.contains(&format!("id=\"cluster-{}\"", cluster.index)));
+            for id in cluster.members.iter() {
+                assert!(html.contains(&format!("<code>{}</code>", id)));
+            }
+        }
+        let index = render_index(&sampled, 1);
+        assert!(index.contains(r#"<a href="pairs.html">pairs</a>: 10 sampled"#));
+        assert!(index.contains("21+ members</a>: 1 sampled"));
+    }
+
+    #[test]
+    fn test_relative_src() {
+        let src = |path: &str, base: &str| relative_src(Path::new(path), Path::new(base));
+        assert_eq!(
+            src("/data/thumbs/a.png", "/data/gallery"),
+            "../thumbs/a.png"
+        );
+        assert_eq!(
+            src("/data/gallery/thumbs/a.png", "/data/gallery"),
+            "thumbs/a.png"
+        );
+        assert_eq!(src("/a.png", "/data/gallery"), "../../a.png");
+    }
+}
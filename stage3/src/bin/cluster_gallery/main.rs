@@ -0,0 +1,131 @@
+mod gallery;
+
+use crate::gallery::{SizeBucket, relative_src, render_bucket, render_index, sample_clusters};
+use anyhow::Result;
+use clap::Parser;
+use shared::atomic_write::atomic_write;
+use shared::cluster_file::{LegacyFormat, read_clusters};
+use shared::migrations::load_neko_points;
+use shared::opendal::{EntryMode, decode_entry_list};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(about = "Write HTML pages of randomly sampled clusters, one page per size bucket")]
+struct Args {
+    /// Cluster file, or a legacy bincode or (with a `.pkl` extension) pickled one
+    #[arg(short, long, default_value = "clusters.bin")]
+    clusters: PathBuf,
+    #[arg(short = 'm', long, default_value = "points_map.bin")]
+    points_map: PathBuf,
+    /// Images are shown from `<url_prefix>/<key>`, keys taken from `--file-list`
+    #[arg(long, requires = "file_list", conflicts_with = "thumbnail_dir")]
+    url_prefix: Option<String>,
+    /// S3 listing as written by stage5
+    #[arg(long)]
+    file_list: Option<PathBuf>,
+    /// Local images named `<uuid>.<ext>`, used instead of `--url-prefix`
+    #[arg(long)]
+    thumbnail_dir: Option<PathBuf>,
+    /// Clusters sampled per size bucket
+    #[arg(long, default_value_t = 12)]
+    per_bucket: usize,
+    /// Same seed and clusters file give the same sample, random and printed when unset
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(short, long, default_value = "cluster_gallery")]
+    output_dir: PathBuf,
+}
+
+/// Legacy files without the cluster file header are pickles if named `.pkl`, bincode otherwise
+fn load_clusters(path: &Path) -> Result<Vec<HashSet<Uuid>>> {
+    let legacy = match path.extension().is_some_and(|ext| ext == "pkl") {
+        true => LegacyFormat::Pickle,
+        false => LegacyFormat::Bincode,
+    };
+    Ok(read_clusters(path, legacy)?)
+}
+
+fn listing_urls(file_list: &Path, prefix: &str) -> Result<HashMap<Uuid, String>> {
+    let (entries, _) = decode_entry_list(&fs::read(file_list)?)?;
+    Ok(entries
+        .iter()
+        .filter(|entry| entry.metadata.mode == EntryMode::FILE)
+        .filter_map(|entry| {
+            let id = Uuid::parse_str(entry.to_point()).ok()?;
+            let url = format!(
+                "{}/{}",
+                prefix.trim_end_matches('/'),
+                entry.path.trim_start_matches('/')
+            );
+            Some((id, url))
+        })
+        .collect())
+}
+
+/// Images of `dir` by point, relative to the pages in `output_dir`
+fn thumbnail_paths(dir: &Path, output_dir: &Path) -> Result<HashMap<Uuid, String>> {
+    let dir = fs::canonicalize(dir)?;
+    let output_dir = fs::canonicalize(output_dir)?;
+    let mut paths = HashMap::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| Uuid::parse_str(stem).ok());
+        if let Some(id) = id {
+            paths.insert(id, relative_src(&path, &output_dir));
+        }
+    }
+    Ok(paths)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let clusters = load_clusters(&args.clusters)?;
+    let points = load_neko_points(&args.points_map)?;
+    println!(
+        "Loaded {} clusters and {} points",
+        clusters.len(),
+        points.len()
+    );
+    fs::create_dir_all(&args.output_dir)?;
+    let sources = match (&args.url_prefix, &args.file_list, &args.thumbnail_dir) {
+        (Some(prefix), Some(file_list), _) => listing_urls(file_list, prefix)?,
+        (_, _, Some(dir)) => thumbnail_paths(dir, &args.output_dir)?,
+        _ => {
+            println!("Neither --url-prefix nor --thumbnail-dir given, pages have no images");
+            HashMap::new()
+        }
+    };
+    let seed = args.seed.unwrap_or_else(rand::random);
+    println!("Sampling with --seed {}", seed);
+    let sampled = sample_clusters(&clusters, args.per_bucket, seed);
+    for bucket in SizeBucket::ALL {
+        let Some(clusters) = sampled.get(&bucket) else {
+            continue;
+        };
+        let html = render_bucket(
+            bucket,
+            clusters,
+            seed,
+            |id| sources.get(id).map(String::as_str),
+            &points,
+        );
+        let path = args.output_dir.join(bucket.file_name());
+        atomic_write(&path, html)?;
+        println!(
+            "{}: {} clusters -> {}",
+            bucket.label(),
+            clusters.len(),
+            path.display()
+        );
+    }
+    let index = args.output_dir.join("index.html");
+    atomic_write(&index, render_index(&sampled, seed))?;
+    println!("Gallery index at {}", index.display());
+    Ok(())
+}
@@ -0,0 +1,332 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum EditError {
+    #[error("Point {0} is in no cluster")]
+    UnknownPoint(Uuid),
+    #[error("Point {id} is in clusters {first} and {second}, clusters must be disjoint")]
+    NotDisjoint {
+        id: Uuid,
+        first: usize,
+        second: usize,
+    },
+    #[error("The points are all in cluster {0}, nothing to merge")]
+    NothingToMerge(usize),
+    #[error("Point {id} is not in cluster {cluster} of {cluster_of}")]
+    NotInCluster {
+        id: Uuid,
+        cluster: usize,
+        cluster_of: Uuid,
+    },
+    #[error("Splitting every member off cluster {0} would leave it empty")]
+    WholeCluster(usize),
+    #[error("No points given")]
+    NoPoints,
+}
+
+pub type EditResult<T> = Result<T, EditError>;
+
+/// One curation step, as recorded in the edit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Edit {
+    /// Merges the clusters containing `ids` into the first of them
+    Merge { ids: Vec<Uuid> },
+    /// Moves `members` of the cluster containing `cluster_of` into a new last cluster
+    Split {
+        cluster_of: Uuid,
+        members: Vec<Uuid>,
+    },
+    /// Drops `id`, and its cluster once empty
+    RemovePoint { id: Uuid },
+}
+
+/// Cluster of every point, failing on a point found in two clusters
+pub fn cluster_index(clusters: &[HashSet<Uuid>]) -> EditResult<HashMap<Uuid, usize>> {
+    let mut index = HashMap::with_capacity(clusters.iter().map(HashSet::len).sum());
+    for (idx, cluster) in clusters.iter().enumerate() {
+        for &id in cluster {
+            if let Some(first) = index.insert(id, idx) {
+                return Err(EditError::NotDisjoint {
+                    id,
+                    first,
+                    second: idx,
+                });
+            }
+        }
+    }
+    Ok(index)
+}
+
+fn cluster_of(index: &HashMap<Uuid, usize>, id: &Uuid) -> EditResult<usize> {
+    index.get(id).copied().ok_or(EditError::UnknownPoint(*id))
+}
+
+/// The clusters after `edit`, the input is checked for disjointness first
+pub fn apply(mut clusters: Vec<HashSet<Uuid>>, edit: &Edit) -> EditResult<Vec<HashSet<Uuid>>> {
+    let index = cluster_index(&clusters)?;
+    match edit {
+        Edit::Merge { ids } => {
+            let mut targets: Vec<usize> = ids
+                .iter()
+                .map(|id| cluster_of(&index, id))
+                .collect::<EditResult<_>>()?;
+            targets.sort_unstable();
+            targets.dedup();
+            let [into, rest @ ..] = &targets[..] else {
+                return Err(EditError::NoPoints);
+            };
+            if rest.is_empty() {
+                return Err(EditError::NothingToMerge(*into));
+            }
+            // from the back so the indices stay valid
+            for &idx in rest.iter().rev() {
+                let merged = clusters.remove(idx);
+                clusters[*into].extend(merged);
+            }
+        }
+        Edit::Split {
+            cluster_of: of,
+            members,
+        } => {
+            let idx = cluster_of(&index, of)?;
+            if members.is_empty() {
+                return Err(EditError::NoPoints);
+            }
+            for id in members {
+                if cluster_of(&index, id)? != idx {
+                    return Err(EditError::NotInCluster {
+                        id: *id,
+                        cluster: idx,
+                        cluster_of: *of,
+                    });
+                }
+            }
+            let split: HashSet<Uuid> = members.iter().copied().collect();
+            if split.len() == clusters[idx].len() {
+                return Err(EditError::WholeCluster(idx));
+            }
+            clusters[idx].retain(|id| !split.contains(id));
+            clusters.push(split);
+        }
+        Edit::RemovePoint { id } => {
+            let idx = cluster_of(&index, id)?;
+            clusters[idx].remove(id);
+            if clusters[idx].is_empty() {
+                clusters.remove(idx);
+            }
+        }
+    }
+    Ok(clusters)
+}
+
+/// `name.v<n+1>.ext` for `name.v<n>.ext` and `name.v1.ext` for `name.ext`, skipping the
+/// versions `exists` reports taken
+pub fn next_version(path: &Path, exists: impl Fn(&Path) -> bool) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (base, mut version) = match stem.rsplit_once(".v") {
+        Some((base, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+            (base.to_string(), n.parse::<u64>().unwrap_or(0))
+        }
+        _ => (stem, 0),
+    };
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    loop {
+        version += 1;
+        let candidate = path.with_file_name(format!("{}.v{}{}", base, version, ext));
+        if !exists(&candidate) {
+            return candidate;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::test_util::id;
+
+    fn clusters(spec: &[&[u128]]) -> Vec<HashSet<Uuid>> {
+        spec.iter()
+            .map(|c| c.iter().copied().map(id).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let before = clusters(&[&[1, 2], &[3], &[4, 5], &[6]]);
+        let edit = Edit::Merge {
+            ids: vec![id(4), id(1), id(5)],
+        };
+        assert_eq!(
+            apply(before.clone(), &edit).unwrap(),
+            clusters(&[&[1, 2, 4, 5], &[3], &[6]])
+        );
+        let three = Edit::Merge {
+            ids: vec![id(6), id(3), id(2)],
+        };
+        assert_eq!(
+            apply(before, &three).unwrap(),
+            clusters(&[&[1, 2, 3, 6], &[4, 5]])
+        );
+    }
+
+    #[test]
+    fn test_merge_rejected() {
+        let before = clusters(&[&[1, 2], &[3]]);
+        let merge = |ids: &[u128]| Edit::Merge {
+            ids: ids.iter().copied().map(id).collect(),
+        };
+        assert_eq!(
+            apply(before.clone(), &merge(&[1, 9])),
+            Err(EditError::UnknownPoint(id(9)))
+        );
+        assert_eq!(
+            apply(before.clone(), &merge(&[1, 2])),
+            Err(EditError::NothingToMerge(0))
+        );
+        assert_eq!(apply(before, &merge(&[])), Err(EditError::NoPoints));
+    }
+
+    #[test]
+    fn test_split() {
+        let before = clusters(&[&[1, 2, 3, 4], &[5, 6]]);
+        let edit = Edit::Split {
+            cluster_of: id(1),
+            members: vec![id(3), id(4), id(3)],
+        };
+        assert_eq!(
+            apply(before, &edit).unwrap(),
+            clusters(&[&[1, 2], &[5, 6], &[3, 4]])
+        );
+    }
+
+    #[test]
+    fn test_split_rejected() {
+        let before = clusters(&[&[1, 2], &[3, 4]]);
+        let split = |of: u128, members: &[u128]| Edit::Split {
+            cluster_of: id(of),
+            members: members.iter().copied().map(id).collect(),
+        };
+        assert_eq!(
+            apply(before.clone(), &split(1, &[3])),
+            Err(EditError::NotInCluster {
+                id: id(3),
+                cluster: 0,
+                cluster_of: id(1)
+            })
+        );
+        assert_eq!(
+            apply(before.clone(), &split(1, &[9])),
+            Err(EditError::UnknownPoint(id(9)))
+        );
+        assert_eq!(
+            apply(before.clone(), &split(9, &[1])),
+            Err(EditError::UnknownPoint(id(9)))
+        );
+        assert_eq!(
+            apply(before.clone(), &split(1, &[1, 2])),
+            Err(EditError::WholeCluster(0))
+        );
+        assert_eq!(apply(before, &split(1, &[])), Err(EditError::NoPoints));
+    }
+
+    #[test]
+    fn test_remove_point() {
+        let before = clusters(&[&[1, 2], &[3], &[4]]);
+        let remove = |n| Edit::RemovePoint { id: id(n) };
+        assert_eq!(
+            apply(before.clone(), &remove(2)).unwrap(),
+            clusters(&[&[1], &[3], &[4]])
+        );
+        // an emptied cluster is dropped
+        assert_eq!(
+            apply(before.clone(), &remove(3)).unwrap(),
+            clusters(&[&[1, 2], &[4]])
+        );
+        assert_eq!(
+            apply(before, &remove(9)),
+            Err(EditError::UnknownPoint(id(9)))
+        );
+    }
+
+    #[test]
+    fn test_overlapping_input_rejected() {
+        let before = clusters(&[&[1, 2], &[3], &[2, 4]]);
+        let edit = Edit::RemovePoint { id: id(3) };
+        assert_eq!(
+            apply(before, &edit),
+            Err(EditError::NotDisjoint {
+                id: id(2),
+                first: 0,
+                second: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_edits_keep_disjointness() {
+        let mut current = clusters(&[&[1, 2, 3], &[4, 5], &[6], &[7, 8]]);
+        let edits = [
+            Edit::Merge {
+                ids: vec![id(6), id(4)],
+            },
+            Edit::Split {
+                cluster_of: id(1),
+                members: vec![id(2)],
+            },
+            Edit::RemovePoint { id: id(7) },
+            Edit::Merge {
+                ids: vec![id(2), id(8)],
+            },
+        ];
+        for edit in &edits {
+            current = apply(current, edit).unwrap();
+            let index = cluster_index(&current).unwrap();
+            assert_eq!(index.len(), current.iter().map(HashSet::len).sum::<usize>());
+            assert!(current.iter().all(|c| !c.is_empty()));
+        }
+        assert_eq!(current, clusters(&[&[1, 3], &[4, 5, 6], &[2, 8]]));
+    }
+
+    #[test]
+    fn test_edit_log_shape() {
+        let edit = Edit::Split {
+            cluster_of: id(1),
+            members: vec![id(2)],
+        };
+        let value = serde_json::to_value(&edit).unwrap();
+        assert_eq!(value["op"], "split");
+        assert_eq!(serde_json::from_value::<Edit>(value).unwrap(), edit);
+    }
+
+    #[test]
+    fn test_next_version() {
+        let none = |_: &Path| false;
+        assert_eq!(
+            next_version(Path::new("out/global_clusters.pkl"), none),
+            PathBuf::from("out/global_clusters.v1.pkl")
+        );
+        assert_eq!(
+            next_version(Path::new("global_clusters.v9.pkl"), none),
+            PathBuf::from("global_clusters.v10.pkl")
+        );
+        assert_eq!(
+            next_version(Path::new("clusters.vendor.pkl"), none),
+            PathBuf::from("clusters.vendor.v1.pkl")
+        );
+        let taken = |p: &Path| p == Path::new("global_clusters.v2.pkl");
+        assert_eq!(
+            next_version(Path::new("global_clusters.v1.pkl"), taken),
+            PathBuf::from("global_clusters.v3.pkl")
+        );
+    }
+}
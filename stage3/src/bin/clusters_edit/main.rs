@@ -0,0 +1,143 @@
+mod edit;
+
+use crate::edit::{Edit, apply, cluster_index, next_version};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use shared::atomic_write::atomic_write_with;
+use shared::cluster_file::{LegacyFormat, read_clusters, write_clusters};
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(about = "Merge, split and prune clusters by hand before triage")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+    /// JSON lines log every edit is appended to
+    #[arg(long, global = true, default_value = "clusters_edit_log.jsonl")]
+    log: PathBuf,
+}
+
+/// Each edit writes `<name>.v<n>.pkl` next to `<file>`, the input is never touched
+#[derive(Subcommand)]
+enum Command {
+    /// Merge the clusters containing these points
+    Merge {
+        file: PathBuf,
+        #[arg(long, value_delimiter = ',', required = true)]
+        ids: Vec<Uuid>,
+    },
+    /// Move the listed members of a cluster into a new cluster
+    Split {
+        file: PathBuf,
+        /// Any member of the cluster to split
+        #[arg(long)]
+        cluster_of: Uuid,
+        /// Members moved together into the new cluster
+        #[arg(long, num_args = 1.., required = true)]
+        keep_with: Vec<Uuid>,
+    },
+    /// Drop a point, and its cluster once empty
+    RemovePoint {
+        file: PathBuf,
+        #[arg(long)]
+        id: Uuid,
+    },
+    /// Print the cluster of a point
+    Show {
+        file: PathBuf,
+        #[arg(long)]
+        id: Uuid,
+    },
+}
+
+#[derive(Serialize)]
+struct EditLogEntry<'a> {
+    at: DateTime<Utc>,
+    input: &'a Path,
+    output: &'a Path,
+    #[serde(flatten)]
+    edit: &'a Edit,
+    clusters_before: usize,
+    clusters_after: usize,
+}
+
+/// Cluster file as written by stage1, legacy pickles included
+fn load_clusters(path: &Path) -> Result<Vec<HashSet<Uuid>>> {
+    Ok(read_clusters(path, LegacyFormat::Pickle)?)
+}
+
+fn save_clusters(path: &Path, clusters: &[HashSet<Uuid>]) -> Result<()> {
+    Ok(atomic_write_with(path, |w| write_clusters(w, clusters))?)
+}
+
+fn append_log(path: &Path, entry: &EditLogEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut log = OpenOptions::new().create(true).append(true).open(path)?;
+    log.write_all(&line)?;
+    Ok(log.sync_data()?)
+}
+
+fn show(file: &Path, id: &Uuid) -> Result<()> {
+    let clusters = load_clusters(file)?;
+    let index = cluster_index(&clusters)?;
+    let Some(&idx) = index.get(id) else {
+        anyhow::bail!("Point {} is in no cluster of {}", id, file.display());
+    };
+    let mut members: Vec<&Uuid> = clusters[idx].iter().collect();
+    members.sort_unstable();
+    println!("Cluster {} ({} members):", idx, members.len());
+    for member in members {
+        println!("  {}", member);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let (file, edit) = match args.command {
+        Command::Show { file, id } => return show(&file, &id),
+        Command::Merge { file, ids } => (file, Edit::Merge { ids }),
+        Command::Split {
+            file,
+            cluster_of,
+            keep_with,
+        } => (
+            file,
+            Edit::Split {
+                cluster_of,
+                members: keep_with,
+            },
+        ),
+        Command::RemovePoint { file, id } => (file, Edit::RemovePoint { id }),
+    };
+    let clusters = load_clusters(&file)?;
+    let clusters_before = clusters.len();
+    let edited = apply(clusters, &edit)?;
+    let output = next_version(&file, Path::exists);
+    save_clusters(&output, &edited)?;
+    append_log(
+        &args.log,
+        &EditLogEntry {
+            at: Utc::now(),
+            input: &file,
+            output: &output,
+            edit: &edit,
+            clusters_before,
+            clusters_after: edited.len(),
+        },
+    )?;
+    println!(
+        "{} clusters -> {} clusters, written to {}",
+        clusters_before,
+        edited.len(),
+        output.display()
+    );
+    Ok(())
+}
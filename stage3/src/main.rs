@@ -1,6 +1,7 @@
 use clap::Parser;
 use plotters::prelude::*;
-use std::collections::{HashMap, HashSet};
+use shared::cluster_file::{LegacyFormat, open_clusters};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use uuid::Uuid;
 
@@ -19,18 +20,21 @@ struct Args {
 
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    // Load clusters
-    let cluster_data = std::fs::read(&args.clusters)?;
-    let global_clusters: Vec<HashSet<Uuid>> =
-        bincode::serde::decode_from_slice(&cluster_data, bincode::config::standard())?.0;
-    println!("Loaded global clusters, count = {}", global_clusters.len());
+    // Stream clusters, only their sizes are kept
+    let global_clusters = open_clusters(&args.clusters, LegacyFormat::Bincode)?;
+    println!(
+        "Opened global clusters, count = {}",
+        global_clusters.total()
+    );
 
     // Compute sizes of clusters with more than one member
-    let mut sizes: Vec<usize> = global_clusters
-        .iter()
-        .map(HashSet::len)
-        .filter(|&len| len > 1)
-        .collect();
+    let mut sizes: Vec<usize> = Vec::new();
+    for cluster in global_clusters {
+        let (_, cluster) = cluster?;
+        if cluster.len() > 1 {
+            sizes.push(cluster.len());
+        }
+    }
     sizes.sort_unstable();
 
     let count = sizes.len();
@@ -0,0 +1,98 @@
+mod verify;
+
+use crate::verify::{ObjectList, ReclaimReport};
+use anyhow::Result;
+use clap::Parser;
+use shared::atomic_write::atomic_write_with;
+use shared::logging::Logging;
+use shared::opendal::{GenShinOperator, load_entry_list, save_entry_list};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+/// Keys listed per category on the console, the report holds all of them
+const CONSOLE_LIMIT: usize = 20;
+
+#[derive(Parser)]
+#[command(about = "Check a cleanup campaign against fresh S3 listings from before and after it")]
+struct Args {
+    /// Listing taken by stage5 before the campaign
+    #[arg(long, default_value = "opendal_list_file.bin")]
+    before: PathBuf,
+    /// Where the fresh listing is saved, or read from with `--skip-fresh-listing`
+    #[arg(long, default_value = "opendal_list_file_after.bin")]
+    after: PathBuf,
+    /// Reuse `--after` instead of listing the bucket again
+    #[arg(long, default_value = "false")]
+    skip_fresh_listing: bool,
+    /// Must match the path and recursion the `--before` listing was taken with
+    #[arg(long, default_value = "/")]
+    list_path: String,
+    #[arg(short, long, default_value = "false")]
+    recursive: bool,
+    /// JSON array of the object keys the campaign deletes
+    #[arg(short, long)]
+    deletion_manifest: PathBuf,
+    #[arg(short, long, default_value = "reclaim_report.json")]
+    output: PathBuf,
+}
+
+fn print_list(name: &str, list: &ObjectList) {
+    if list.count == 0 {
+        return;
+    }
+    println!("{}: {} objects, {} bytes", name, list.count, list.bytes);
+    for key in list.keys.iter().take(CONSOLE_LIMIT) {
+        println!("  {}", key);
+    }
+    if list.count > CONSOLE_LIMIT {
+        println!("  ... {} more", list.count - CONSOLE_LIMIT);
+    }
+}
+
+fn print_report(report: &ReclaimReport) {
+    println!(
+        "Files: before = {} ({} bytes), after = {} ({} bytes)",
+        report.files_before, report.bytes_before, report.files_after, report.bytes_after
+    );
+    println!(
+        "Reclaimed {} bytes from {} deleted objects",
+        report.reclaimed_bytes(),
+        report.deleted.count
+    );
+    print_list("Still present", &report.still_present);
+    print_list("Unexpectedly missing", &report.unexpectedly_missing);
+    print_list("In the manifest but never listed", &report.unknown);
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let _logging = Logging::new("reclaim_verify").init()?;
+
+    let args = Args::parse();
+    let expected: HashSet<String> = serde_json::from_slice(&fs::read(&args.deletion_manifest)?)?;
+    let (before, _) = load_entry_list(&args.before)?;
+    let after = match args.skip_fresh_listing {
+        true => load_entry_list(&args.after)?.0,
+        false => {
+            let op = GenShinOperator::new()?;
+            let after = op.list_all(&args.list_path, args.recursive).await?;
+            save_entry_list(&args.after, &after)?;
+            tracing::info!("Saved {} entries to {}", after.len(), args.after.display());
+            after
+        }
+    };
+
+    let report = verify::verify(&before, &after, &expected);
+    atomic_write_with(&args.output, |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &report)?)
+    })?;
+    print_report(&report);
+    tracing::info!("Saved report to {}", args.output.display());
+    anyhow::ensure!(
+        report.unexpectedly_missing.count == 0,
+        "{} objects outside the deletion manifest are gone",
+        report.unexpectedly_missing.count
+    );
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use serde::Serialize;
+use shared::opendal::{Entry, EntryMode};
+use std::collections::{HashMap, HashSet};
+
+/// Object key as the deletion manifest spells it, listings may carry a leading `/`
+pub fn normalize(key: &str) -> &str {
+    key.trim_start_matches('/')
+}
+
+/// Key -> size of every file in a listing, directories carry no bytes of their own
+fn files(listing: &[Entry]) -> HashMap<&str, u64> {
+    listing
+        .iter()
+        .filter(|e| e.metadata.mode == EntryMode::FILE)
+        .map(|e| (normalize(&e.path), e.metadata.content_length.unwrap_or(0)))
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ObjectList {
+    pub count: usize,
+    pub bytes: u64,
+    pub keys: Vec<String>,
+}
+
+impl FromIterator<(String, u64)> for ObjectList {
+    fn from_iter<I: IntoIterator<Item = (String, u64)>>(iter: I) -> Self {
+        let mut list = ObjectList::default();
+        for (key, bytes) in iter {
+            list.bytes += bytes;
+            list.keys.push(key);
+        }
+        list.count = list.keys.len();
+        list.keys.sort_unstable();
+        list
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ReclaimReport {
+    pub files_before: usize,
+    pub bytes_before: u64,
+    pub files_after: usize,
+    pub bytes_after: u64,
+    /// Expected deletions gone from the bucket, sized by the listing before the campaign
+    pub deleted: ObjectList,
+    /// Expected deletions still listed, the campaign has to be resumed for them
+    pub still_present: ObjectList,
+    /// Gone without being in the manifest, nothing should have touched them
+    pub unexpectedly_missing: ObjectList,
+    /// Expected deletions neither listing holds, usually a manifest built from another prefix
+    pub unknown: ObjectList,
+}
+
+impl ReclaimReport {
+    #[inline]
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.deleted.bytes
+    }
+}
+
+/// Reconciles the listings around a cleanup campaign with the keys it was meant to delete
+///
+/// Only files are compared, both listings must cover the prefix the manifest was built from.
+pub fn verify(before: &[Entry], after: &[Entry], expected: &HashSet<String>) -> ReclaimReport {
+    let before = files(before);
+    let after = files(after);
+    let expected: HashSet<&str> = expected.iter().map(|k| normalize(k)).collect();
+
+    let deleted = expected
+        .iter()
+        .filter(|k| !after.contains_key(*k))
+        .filter_map(|k| Some((k.to_string(), *before.get(k)?)))
+        .collect();
+    let still_present = expected
+        .iter()
+        .filter_map(|k| Some((k.to_string(), *after.get(k)?)))
+        .collect();
+    let unknown = expected
+        .iter()
+        .filter(|k| !before.contains_key(*k) && !after.contains_key(*k))
+        .map(|k| (k.to_string(), 0))
+        .collect();
+    let unexpectedly_missing = before
+        .iter()
+        .filter(|(k, _)| !after.contains_key(*k) && !expected.contains(*k))
+        .map(|(k, bytes)| (k.to_string(), *bytes))
+        .collect();
+    ReclaimReport {
+        files_before: before.len(),
+        bytes_before: before.values().sum(),
+        files_after: after.len(),
+        bytes_after: after.values().sum(),
+        deleted,
+        still_present,
+        unexpectedly_missing,
+        unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::opendal::Metadata;
+
+    fn entry(path: &str, mode: EntryMode, len: u64) -> Entry {
+        Entry {
+            path: path.to_string(),
+            metadata: Metadata {
+                mode,
+                is_current: None,
+                is_deleted: false,
+                cache_control: None,
+                content_disposition: None,
+                content_length: Some(len),
+                content_md5: None,
+                content_range: None,
+                content_type: None,
+                content_encoding: None,
+                etag: None,
+                last_modified: None,
+                version: None,
+                user_metadata: None,
+            },
+        }
+    }
+
+    fn file(path: &str, len: u64) -> Entry {
+        entry(path, EntryMode::FILE, len)
+    }
+
+    fn keys(list: &ObjectList) -> Vec<&str> {
+        list.keys.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn test_verify() {
+        let before = vec![
+            entry("img/", EntryMode::DIR, 0),
+            file("img/a.png", 100),
+            file("img/b.png", 200),
+            file("img/c.png", 300),
+            file("img/d.png", 400),
+            file("img/e.png", 500),
+        ];
+        let after = vec![
+            entry("img/", EntryMode::DIR, 0),
+            // resized since the first listing
+            file("img/c.png", 350),
+            file("img/e.png", 500),
+        ];
+        let expected: HashSet<String> = ["/img/a.png", "img/b.png", "img/c.png", "img/z.png"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let report = verify(&before, &after, &expected);
+
+        assert_eq!((report.files_before, report.bytes_before), (5, 1500));
+        assert_eq!((report.files_after, report.bytes_after), (2, 850));
+        assert_eq!(keys(&report.deleted), ["img/a.png", "img/b.png"]);
+        assert_eq!(report.reclaimed_bytes(), 300);
+        assert_eq!(keys(&report.still_present), ["img/c.png"]);
+        assert_eq!(report.still_present.bytes, 350);
+        assert_eq!(keys(&report.unexpectedly_missing), ["img/d.png"]);
+        assert_eq!(report.unexpectedly_missing.bytes, 400);
+        assert_eq!(keys(&report.unknown), ["img/z.png"]);
+        assert_eq!(report.unknown.count, 1);
+    }
+
+    #[test]
+    fn test_verify_clean_campaign() {
+        let before = vec![file("/a", 1), file("/b", 2)];
+        let after = vec![file("/b", 2)];
+        let expected = HashSet::from(["a".to_string()]);
+        let report = verify(&before, &after, &expected);
+        assert_eq!(report.deleted.count, 1);
+        assert_eq!(report.reclaimed_bytes(), 1);
+        assert_eq!(report.still_present, ObjectList::default());
+        assert_eq!(report.unexpectedly_missing, ObjectList::default());
+        assert_eq!(report.unknown, ObjectList::default());
+    }
+}
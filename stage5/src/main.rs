@@ -1,10 +1,9 @@
 use anyhow::Result;
 use clap::Parser;
-use shared::opendal::GenShinOperator;
+use shared::logging::Logging;
+use shared::opendal::{EntryListLayout, GenShinOperator, load_entry_list, save_entry_list};
 use std::ops::Deref;
 use std::path::Path;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::{EnvFilter, prelude::*};
 
 pub struct Stage5Operator(GenShinOperator);
 
@@ -22,10 +21,8 @@ impl Stage5Operator {
         list_path: &str,
         is_recursive: bool,
     ) -> Result<Vec<shared::opendal::Entry>> {
-        let res = self.op.list_with(list_path).recursive(is_recursive).await?;
+        let res = self.list_all(list_path, is_recursive).await?;
         tracing::info!("Fetched result from s3, len = {:?}", &res.len());
-        let res: Vec<shared::opendal::Entry> =
-            res.into_iter().map(shared::opendal::Entry::from).collect();
         Ok(res)
     }
 }
@@ -45,20 +42,20 @@ struct Cli {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new("debug"));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage5.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new("info"));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let _logging = Logging::new("stage5").stdout_level("debug").init()?;
 
     let cli = Cli::parse();
     let checkpoint = Path::new(&cli.filelist_checkpoint_path);
     if checkpoint.exists() && !cli.overwrite {
         tracing::warn!("Checkpoint exists, skipping.");
+        let (entries, layout) = load_entry_list(checkpoint)?;
+        if layout != EntryListLayout::Current {
+            tracing::info!(
+                "Upgraded {:?} checkpoint of {} entries to the current layout",
+                layout,
+                entries.len()
+            );
+        }
         return Ok(());
     }
     if checkpoint.exists() {
@@ -76,14 +73,6 @@ async fn main() -> Result<()> {
         entries.len(),
         cli.filelist_checkpoint_path
     );
-    let res: Vec<shared::opendal::Entry> = entries
-        .into_iter()
-        .map(shared::opendal::Entry::from)
-        .collect();
-    let serialized = bincode::serde::encode_to_vec::<
-        &Vec<shared::opendal::Entry>,
-        bincode::config::Configuration,
-    >(&res.clone().into(), bincode::config::standard())?;
-    std::fs::write(&cli.filelist_checkpoint_path, &serialized)?;
+    save_entry_list(checkpoint, &entries)?;
     Ok(())
 }
@@ -1,26 +1,32 @@
+mod suggest;
+
+use crate::suggest::{SuggestRules, count_ext_pairs};
 use anyhow::Result;
-use bytes::Buf;
 use clap::Parser;
-use futures::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use serde::Deserialize;
-use shared::opendal::GenShinOperator;
-use shared::structure::{FailedExtFile, TriageFile, WrongExtFile};
+use shared::atomic_write::atomic_write_with;
+use shared::image_ext::{format_has_ext, sniff_image_format};
+use shared::logging::Logging;
+use shared::opendal::{EntryListLayout, GenShinOperator, load_entry_list};
+use shared::stage_lock::LockArgs;
+use shared::stall::{StallArgs, StallConfig, StallError, for_each_watched};
+use shared::structure::{AmbiguousExtFile, FailedExtFile, TriageFile, WrongExtFile};
 use std::cmp::min;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
+
+/// Bytes read from the start of every object, enough for infer and the image header
+const HEADER_LEN: u64 = 8192 + 1;
+/// Failure of objects holding nothing, as opposed to `read error: ...` for unreadable ones
+const EMPTY_OBJECT_ERROR: &str = "empty object";
 
 pub struct Stage6Operator {
     op: GenShinOperator,
     worker_num: usize,
+    deep_verify: bool,
 }
 
 impl Deref for Stage6Operator {
@@ -32,48 +38,68 @@ impl Deref for Stage6Operator {
 }
 
 impl Stage6Operator {
-    pub fn new(worker_num: usize) -> Result<Self> {
-        let op = GenShinOperator::new()?;
-        Ok(Self { op, worker_num })
+    pub fn new(worker_num: usize, deep_verify: bool) -> Result<Self> {
+        Ok(Self::with_operator(
+            GenShinOperator::new()?,
+            worker_num,
+            deep_verify,
+        ))
+    }
+
+    pub fn with_operator(op: GenShinOperator, worker_num: usize, deep_verify: bool) -> Self {
+        Self {
+            op,
+            worker_num,
+            deep_verify,
+        }
     }
 
     pub async fn verify(
         self: Arc<Self>,
         entries: Vec<shared::opendal::Entry>,
         worker_num: usize,
-    ) -> Result<(Vec<WrongExtFile>, Vec<FailedExtFile>)> {
+        stall: &StallConfig,
+    ) -> Result<(
+        Vec<WrongExtFile>,
+        Vec<FailedExtFile>,
+        Vec<AmbiguousExtFile>,
+        Option<StallError>,
+    )> {
         let pb = ProgressBar::new(entries.len() as u64);
         let style = ProgressStyle::default_bar()
             .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
         pb.set_style(style);
         pb.set_message("Validating extensions...");
-        let mut stream = futures::stream::iter(entries.into_iter().map(|entry| {
+        let tasks = entries.into_iter().map(|entry| {
             let op = self.clone();
             let pb = pb.clone();
-            async move {
+            (entry.path.clone(), async move {
                 let triage = op.verify_single_ext(entry).await?;
                 pb.inc(1);
                 Ok::<_, anyhow::Error>(triage)
-            }
-        }))
-        .buffer_unordered(worker_num);
+            })
+        });
         let mut all_wrong = Vec::new();
         let mut all_failed = Vec::new();
-        while let Some(res) = stream.next().await {
+        let mut all_ambiguous = Vec::new();
+        let watched = for_each_watched(tasks, worker_num, stall, |res| {
             if let Ok(Some(triage)) = res {
                 match triage {
                     TriageFile::Wrong(w) => all_wrong.push(w),
                     TriageFile::Failed(f) => all_failed.push(f),
+                    TriageFile::Ambiguous(a) => all_ambiguous.push(a),
                 }
             }
-        }
+        })
+        .await;
         pb.finish_with_message("Validation complete");
         tracing::info!(
-            "Validation complete：wrong_ext = {}, failed = {}",
+            "Validation complete：wrong_ext = {}, failed = {}, ambiguous = {}",
             all_wrong.len(),
-            all_failed.len()
+            all_failed.len(),
+            all_ambiguous.len()
         );
-        Ok((all_wrong, all_failed))
+        Ok((all_wrong, all_failed, all_ambiguous, watched.err()))
     }
 
     pub async fn verify_single_ext(
@@ -81,37 +107,68 @@ impl Stage6Operator {
         file: shared::opendal::Entry,
     ) -> Result<Option<TriageFile>> {
         let path = file.path;
-        let len = file.metadata.content_length.unwrap_or_default();
-        match self.op.read_with(&path).range(0..min(len, 8192 + 1)).await {
-            Ok(buf) => match infer::get(buf.chunk()) {
-                Some(kind) => {
-                    let inferred_ext = kind.extension();
-                    let ori_ext = path.split('.').last().unwrap_or_default();
-                    if inferred_ext != ori_ext {
+        let len = match file.metadata.content_length {
+            Some(len) if len > 0 => Some(len),
+            // listings through some proxies miss the length of multipart uploads
+            _ => self.stat_length(&path).await,
+        };
+        let range = 0..len.map_or(HEADER_LEN, |len| min(len, HEADER_LEN));
+        match self.op.read_with(&path).range(range).await {
+            Ok(buf) => {
+                let header = buf.to_bytes();
+                if header.is_empty() {
+                    tracing::debug!("verify_single_ext: {:?} is empty", path);
+                    return Ok(Some(TriageFile::Failed(FailedExtFile {
+                        path: path.clone(),
+                        error: EMPTY_OBJECT_ERROR.into(),
+                    })));
+                }
+                let kind = infer::get(&header);
+                if self.deep_verify {
+                    let truncated = match len {
+                        Some(len) => len > header.len() as u64,
+                        None => header.len() as u64 == HEADER_LEN,
+                    };
+                    if let Some(ambiguous) = deep_verify(&path, kind, &header, truncated) {
                         tracing::debug!(
-                            "verify_single_ext: File {:?} has wrong ext: {}, expected: {}",
+                            "verify_single_ext: infer and image disagree on {:?}: {:?} vs {:?}",
                             path,
-                            inferred_ext,
-                            ori_ext
+                            ambiguous.infer_ext,
+                            ambiguous.image_ext
                         );
-                        return Ok(Some(TriageFile::Wrong(WrongExtFile {
-                            path: path.clone(),
-                            expected_ext: inferred_ext.to_string(),
-                        })));
+                        return Ok(Some(TriageFile::Ambiguous(ambiguous)));
                     }
-                    Ok(None)
                 }
-                None => {
-                    tracing::debug!(
-                        "verify_single_ext: Failed to infer file type for: {:?}",
-                        path
-                    );
-                    Ok(Some(TriageFile::Failed(FailedExtFile {
-                        path: path.clone(),
-                        error: "infer::get returned None".into(),
-                    })))
+                match kind {
+                    Some(kind) => {
+                        let inferred_ext = kind.extension();
+                        let ori_ext = path.split('.').last().unwrap_or_default();
+                        if inferred_ext != ori_ext {
+                            tracing::debug!(
+                                "verify_single_ext: File {:?} has wrong ext: {}, expected: {}",
+                                path,
+                                inferred_ext,
+                                ori_ext
+                            );
+                            return Ok(Some(TriageFile::Wrong(WrongExtFile {
+                                path: path.clone(),
+                                expected_ext: inferred_ext.to_string(),
+                            })));
+                        }
+                        Ok(None)
+                    }
+                    None => {
+                        tracing::debug!(
+                            "verify_single_ext: Failed to infer file type for: {:?}",
+                            path
+                        );
+                        Ok(Some(TriageFile::Failed(FailedExtFile {
+                            path: path.clone(),
+                            error: "infer::get returned None".into(),
+                        })))
+                    }
                 }
-            },
+            }
             Err(e) => {
                 tracing::debug!("verify_single_ext: Error reading {:?}: {}", path, e);
                 Ok(Some(TriageFile::Failed(FailedExtFile {
@@ -121,6 +178,39 @@ impl Stage6Operator {
             }
         }
     }
+
+    /// Length from the object itself, `None` when it is unknown there too
+    async fn stat_length(&self, path: &str) -> Option<u64> {
+        match self.op.stat(path).await {
+            Ok(meta) => Some(meta.content_length()).filter(|&len| len > 0),
+            Err(e) => {
+                tracing::debug!("verify_single_ext: Error stating {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+}
+
+/// `None` when infer and the image crate agree on what `header` is
+fn deep_verify(
+    path: &str,
+    kind: Option<infer::Type>,
+    header: &[u8],
+    truncated: bool,
+) -> Option<AmbiguousExtFile> {
+    let format = sniff_image_format(header, truncated);
+    let agree = match (kind, format) {
+        (Some(kind), Some(format)) => format_has_ext(format, kind.extension()),
+        // fine as long as infer does not claim an image either
+        (Some(kind), None) => kind.matcher_type() != infer::MatcherType::Image,
+        (None, Some(_)) => false,
+        (None, None) => true,
+    };
+    (!agree).then(|| AmbiguousExtFile {
+        path: path.to_string(),
+        infer_ext: kind.map(|kind| kind.extension().to_string()),
+        image_ext: format.map(|format| format.extensions_str()[0].to_string()),
+    })
 }
 
 #[derive(Parser, Debug)]
@@ -138,6 +228,21 @@ struct Cli {
     exclude_files: Option<Vec<String>>,
     #[arg(short, long, default_value = "ext_files")]
     save_result_prefix: String,
+    #[command(flatten)]
+    stall: StallArgs,
+    /// Also parse the header with the image crate, disagreements with infer go to
+    /// `<prefix>_ambiguous.json` instead of the rename input
+    #[arg(long)]
+    deep_verify: bool,
+    #[command(flatten)]
+    lock: LockArgs,
+    /// Also write `<prefix>_skip_suggestions.json`, extension pairs of the wrong files to pass to
+    /// stage7 `--skip-config`, each with the rule and count that suggested it
+    #[arg(long)]
+    suggest_skips: bool,
+    /// JSON array of suggestion rules replacing the built-in ones
+    #[arg(long, requires = "suggest_skips")]
+    suggest_rules: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Default)]
@@ -148,21 +253,15 @@ struct FilterConfig {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new("info"));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage6.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new("info"));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let _logging = Logging::new("stage6").init()?;
 
     let cli = Cli::parse();
-    let op = Stage6Operator::new(cli.worker_num)?;
-    let checkpoint = fs::read(cli.filelist_checkpoint_path)?;
-    let entries: Vec<shared::opendal::Entry> =
-        bincode::serde::decode_from_slice(&checkpoint, bincode::config::standard())?.0;
+    let _lock = cli.lock.acquire("stage6")?;
+    let op = Stage6Operator::new(cli.worker_num, cli.deep_verify)?;
+    let (entries, layout) = load_entry_list(&cli.filelist_checkpoint_path)?;
+    if layout != EntryListLayout::Current {
+        tracing::info!("Upgraded {:?} checkpoint to the current layout", layout);
+    }
     let mut cfg = if let Some(path) = cli.include_exclude_file.as_ref() {
         let file = fs::read(path)?;
         serde_json::from_slice(&file)?
@@ -193,22 +292,142 @@ async fn main() -> Result<()> {
     };
     tracing::info!("Loaded {} entries from checkpoint", entries.len());
 
-    let (wrong_ext_files, failed_ext_files) = Arc::new(op).verify(entries, cli.worker_num).await?;
+    let stall = StallConfig::from(&cli.stall);
+    let (wrong_ext_files, failed_ext_files, ambiguous_ext_files, stalled) =
+        Arc::new(op).verify(entries, cli.worker_num, &stall).await?;
     tracing::info!(
         "Verification complete! wrong_ext_files: {}, failed_ext_files: {}",
         wrong_ext_files.len(),
         failed_ext_files.len()
     );
-    let mut file = File::create(format!("{}_wrong.json", &cli.save_result_prefix))?;
-    let serialized = serde_json::to_string_pretty(&wrong_ext_files)?;
-    file.write_all(serialized.as_bytes())?;
-    let mut file = File::create(format!("{}_failed.json", &cli.save_result_prefix))?;
-    let serialized = serde_json::to_string_pretty(&failed_ext_files)?;
-    file.write_all(serialized.as_bytes())?;
+    atomic_write_with(format!("{}_wrong.json", &cli.save_result_prefix), |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &wrong_ext_files)?)
+    })?;
+    atomic_write_with(format!("{}_failed.json", &cli.save_result_prefix), |w| {
+        Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &failed_ext_files)?)
+    })?;
+    if cli.suggest_skips {
+        let rules = match cli.suggest_rules.as_ref() {
+            Some(path) => SuggestRules::load(path)?,
+            None => SuggestRules::default(),
+        };
+        let suggestions = rules.suggest(&count_ext_pairs(&wrong_ext_files));
+        for suggestion in suggestions.iter() {
+            tracing::info!(
+                "Suggest skipping {} -> {} ({}, {} files)",
+                suggestion.from,
+                suggestion.to,
+                suggestion.rule,
+                suggestion.count
+            );
+        }
+        atomic_write_with(
+            format!("{}_skip_suggestions.json", &cli.save_result_prefix),
+            |w| Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &suggestions)?),
+        )?;
+        tracing::info!(
+            "Saved {} skip suggestions to {}_skip_suggestions.json",
+            suggestions.len(),
+            &cli.save_result_prefix
+        );
+    }
+    if cli.deep_verify {
+        atomic_write_with(format!("{}_ambiguous.json", &cli.save_result_prefix), |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &ambiguous_ext_files)?)
+        })?;
+        tracing::info!(
+            "Saved {} ambiguous files to {}_ambiguous.json",
+            ambiguous_ext_files.len(),
+            &cli.save_result_prefix
+        );
+    }
     tracing::info!(
         "Saved results to {}_wrong.json and {}_failed.json",
         &cli.save_result_prefix,
         &cli.save_result_prefix
     );
+    if let Some(e) = stalled {
+        tracing::error!("Results above are partial");
+        return Err(e.into());
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::opendal::EntryMode;
+    use uuid::Uuid;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[tokio::test]
+    async fn test_verify_on_fs_backend() {
+        let root = std::env::temp_dir().join(format!("stage6_fs_{}", Uuid::new_v4()));
+        let op = GenShinOperator::fs(root.to_str().unwrap()).unwrap();
+        op.write("img/wrong.jpg", PNG.to_vec()).await.unwrap();
+        op.write("img/right.png", PNG.to_vec()).await.unwrap();
+        op.write("img/garbage.png", b"hello".to_vec())
+            .await
+            .unwrap();
+        let entries: Vec<shared::opendal::Entry> = op
+            .list_all("img/", true)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.metadata.mode == EntryMode::FILE)
+            .collect();
+        assert_eq!(entries.len(), 3);
+
+        let op = Arc::new(Stage6Operator::with_operator(op, 4, false));
+        let (wrong, failed, ambiguous, stalled) = op
+            .verify(entries, 4, &StallConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(wrong.len(), 1);
+        assert_eq!(wrong[0].path, "img/wrong.jpg");
+        assert_eq!(wrong[0].expected_ext, "png");
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].path, "img/garbage.png");
+        assert!(ambiguous.is_empty());
+        assert!(stalled.is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_without_content_length() {
+        let op = opendal::Operator::new(opendal::services::Memory::default())
+            .unwrap()
+            .finish();
+        let op = GenShinOperator::from(op);
+        op.write("img/wrong.jpg", PNG.to_vec()).await.unwrap();
+        op.write("img/right.png", PNG.to_vec()).await.unwrap();
+        op.write("img/empty.png", Vec::<u8>::new()).await.unwrap();
+        let mut entries: Vec<shared::opendal::Entry> = op
+            .list_all("img/", true)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.metadata.mode == EntryMode::FILE)
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(entries.len(), 3);
+        // as listed through the proxy, missing or zero
+        entries[0].metadata.content_length = None;
+        entries[1].metadata.content_length = Some(0);
+        entries[2].metadata.content_length = None;
+
+        let op = Arc::new(Stage6Operator::with_operator(op, 4, false));
+        let (wrong, failed, ambiguous, _) = op
+            .verify(entries, 4, &StallConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(wrong.len(), 1);
+        assert_eq!(wrong[0].path, "img/wrong.jpg");
+        assert_eq!(wrong[0].expected_ext, "png");
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].path, "img/empty.png");
+        assert_eq!(failed[0].error, EMPTY_OBJECT_ERROR);
+        assert!(ambiguous.is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+//! Skip suggestions for stage7 from the `(claimed_ext, inferred_ext)` pairs of the wrong files
+
+use serde::{Deserialize, Serialize};
+use shared::structure::WrongExtFile;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Files per `(claimed_ext, inferred_ext)` pair, claimed being the extension the object has
+pub type ExtPairCounts = BTreeMap<(String, String), usize>;
+
+pub fn count_ext_pairs(wrong: &[WrongExtFile]) -> ExtPairCounts {
+    let mut counts = ExtPairCounts::new();
+    for file in wrong {
+        let pair = (file.current_ext().to_string(), file.expected_ext.clone());
+        *counts.entry(pair).or_default() += 1;
+    }
+    counts
+}
+
+/// One rule of a [`SuggestRules`] set, tagged by `rule` in the rules file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum SuggestRule {
+    /// Names of one format, renaming between any two of them changes nothing
+    Equivalent { exts: Vec<String> },
+    /// Formats `container` legitimately holds, infer only sees the container
+    Container {
+        container: String,
+        members: Vec<String>,
+    },
+    /// Pairs seen fewer than `min_count` times, more likely misdetections than misnamed files
+    BelowCount { min_count: usize },
+}
+
+impl SuggestRule {
+    fn name(&self) -> &'static str {
+        match self {
+            SuggestRule::Equivalent { .. } => "equivalent",
+            SuggestRule::Container { .. } => "container",
+            SuggestRule::BelowCount { .. } => "below_count",
+        }
+    }
+
+    fn matches(&self, claimed: &str, inferred: &str, count: usize) -> bool {
+        let has = |exts: &[String], ext: &str| exts.iter().any(|e| e.eq_ignore_ascii_case(ext));
+        match self {
+            SuggestRule::Equivalent { exts } => has(exts, claimed) && has(exts, inferred),
+            SuggestRule::Container { container, members } => {
+                container.eq_ignore_ascii_case(inferred) && has(members, claimed)
+            }
+            SuggestRule::BelowCount { min_count } => count < *min_count,
+        }
+    }
+}
+
+/// Ordered rules, a pair is suggested by the first one matching it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SuggestRules(pub Vec<SuggestRule>);
+
+impl Default for SuggestRules {
+    fn default() -> Self {
+        let exts = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        Self(vec![
+            SuggestRule::Equivalent {
+                exts: exts(&["jpg", "jpeg", "jpe", "jfif"]),
+            },
+            SuggestRule::Equivalent {
+                exts: exts(&["tif", "tiff"]),
+            },
+            SuggestRule::Equivalent {
+                exts: exts(&["heic", "heif"]),
+            },
+            SuggestRule::Equivalent {
+                exts: exts(&["mpg", "mpeg"]),
+            },
+            SuggestRule::Container {
+                container: "zip".to_string(),
+                members: exts(&["cbz", "epub", "apk", "jar", "docx", "xlsx", "pptx"]),
+            },
+            SuggestRule::Container {
+                container: "mp4".to_string(),
+                members: exts(&["m4a", "m4v"]),
+            },
+            SuggestRule::Container {
+                container: "xml".to_string(),
+                members: exts(&["svg"]),
+            },
+            SuggestRule::BelowCount { min_count: 3 },
+        ])
+    }
+}
+
+/// Entry of the suggestions file, readable as a stage7 `--skip-config` rule
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkipSuggestion {
+    pub from: String,
+    pub to: String,
+    /// Name of the rule that suggested the pair
+    pub rule: String,
+    pub count: usize,
+}
+
+impl SuggestRules {
+    /// JSON array of rules, replacing the built-in set
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn suggest(&self, counts: &ExtPairCounts) -> Vec<SkipSuggestion> {
+        counts
+            .iter()
+            .filter_map(|((claimed, inferred), &count)| {
+                let rule = self
+                    .0
+                    .iter()
+                    .find(|rule| rule.matches(claimed, inferred, count))?;
+                Some(SkipSuggestion {
+                    from: claimed.clone(),
+                    to: inferred.clone(),
+                    rule: rule.name().to_string(),
+                    count,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stage7::skip::{SkipRule, SkipRules};
+
+    fn counts(pairs: &[(&str, &str, usize)]) -> ExtPairCounts {
+        pairs
+            .iter()
+            .map(|&(from, to, n)| ((from.to_string(), to.to_string()), n))
+            .collect()
+    }
+
+    fn suggested(rules: &SuggestRules, pairs: &[(&str, &str, usize)]) -> Vec<(String, String)> {
+        rules
+            .suggest(&counts(pairs))
+            .into_iter()
+            .map(|s| (s.from, s.rule))
+            .collect()
+    }
+
+    #[test]
+    fn test_count_ext_pairs() {
+        let wrong = |path: &str, ext: &str| WrongExtFile {
+            path: path.to_string(),
+            expected_ext: ext.to_string(),
+        };
+        let pairs = count_ext_pairs(&[
+            wrong("a/1.jpeg", "jpg"),
+            wrong("a/2.jpeg", "jpg"),
+            wrong("a/3.png", "jpg"),
+            wrong("a/4", "png"),
+        ]);
+        assert_eq!(
+            pairs,
+            counts(&[("", "png", 1), ("jpeg", "jpg", 2), ("png", "jpg", 1)])
+        );
+    }
+
+    #[test]
+    fn test_equivalent() {
+        let rules = SuggestRules(vec![SuggestRule::Equivalent {
+            exts: vec!["jpg".into(), "jpeg".into()],
+        }]);
+        assert_eq!(
+            suggested(
+                &rules,
+                &[("JPEG", "jpg", 40), ("png", "jpg", 40), ("jpg", "png", 40)]
+            ),
+            vec![("JPEG".to_string(), "equivalent".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_container() {
+        let rules = SuggestRules(vec![SuggestRule::Container {
+            container: "zip".into(),
+            members: vec!["cbz".into()],
+        }]);
+        // only the member claimed and the container inferred, not the way around
+        assert_eq!(
+            suggested(
+                &rules,
+                &[("cbz", "zip", 9), ("zip", "cbz", 9), ("rar", "zip", 9)]
+            ),
+            vec![("cbz".to_string(), "container".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_below_count() {
+        let rules = SuggestRules(vec![SuggestRule::BelowCount { min_count: 3 }]);
+        assert_eq!(
+            suggested(&rules, &[("gif", "png", 2), ("webp", "png", 3)]),
+            vec![("gif".to_string(), "below_count".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_first_rule_wins() {
+        let suggestions = SuggestRules::default().suggest(&counts(&[
+            ("jpeg", "jpg", 1),
+            ("png", "jpg", 500),
+            ("svg", "xml", 7),
+        ]));
+        let rules: Vec<(&str, &str)> = suggestions
+            .iter()
+            .map(|s| (s.from.as_str(), s.rule.as_str()))
+            .collect();
+        assert_eq!(rules, vec![("jpeg", "equivalent"), ("svg", "container")]);
+        assert_eq!(suggestions[0].count, 1);
+    }
+
+    #[test]
+    fn test_rules_file_format() {
+        let rules: SuggestRules = serde_json::from_str(
+            r#"[{"rule": "equivalent", "exts": ["tif", "tiff"]},
+                {"rule": "container", "container": "zip", "members": ["cbz"]},
+                {"rule": "below_count", "min_count": 5}]"#,
+        )
+        .unwrap();
+        assert_eq!(rules.0.len(), 3);
+        assert_eq!(rules.0[2], SuggestRule::BelowCount { min_count: 5 });
+        let default = SuggestRules::default();
+        let round_trip: SuggestRules =
+            serde_json::from_str(&serde_json::to_string(&default).unwrap()).unwrap();
+        assert_eq!(round_trip, default);
+    }
+
+    #[test]
+    fn test_readable_as_stage7_skip_config() {
+        let suggestions = SuggestRules::default().suggest(&counts(&[("jpeg", "jpg", 12)]));
+        let json = serde_json::to_string(&suggestions).unwrap();
+        let skip: Vec<SkipRule> = serde_json::from_str(&json).unwrap();
+        assert!(SkipRules::new(skip).matching("jpeg", "jpg").is_some());
+    }
+}
@@ -0,0 +1,533 @@
+use serde::{Deserialize, Serialize};
+use shared::atomic_write::atomic_write_with;
+use shared::structure::RenamedFile;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Last step of a rename the journal saw
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenameState {
+    /// Recorded before the copy, `dst` may be missing or partial
+    Copying,
+    /// `dst` is complete, `src` may still exist
+    Copied,
+    /// `src` is deleted
+    Done,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalRecord {
+    #[serde(flatten)]
+    pub file: RenamedFile,
+    pub state: RenameState,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JournalError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Journal line {line} is corrupt: {source}")]
+    Corrupt {
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+/// Renames of the journal at `path` that never reached [`RenameState::Done`], in journal order
+///
+/// A torn last line, left by a crash mid-append, is dropped.
+pub fn read_pending<P: AsRef<Path>>(path: P) -> Result<Vec<JournalRecord>, JournalError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let lines: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()?;
+    let mut order: Vec<String> = Vec::new();
+    let mut last: HashMap<String, JournalRecord> = HashMap::new();
+    for (idx, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: JournalRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) if idx + 1 == lines.len() => {
+                tracing::warn!("Dropping torn last journal line {}: {}", idx + 1, e);
+                break;
+            }
+            Err(source) => {
+                return Err(JournalError::Corrupt {
+                    line: idx + 1,
+                    source,
+                });
+            }
+        };
+        if !last.contains_key(&record.file.old_key) {
+            order.push(record.file.old_key.clone());
+        }
+        last.insert(record.file.old_key.clone(), record);
+    }
+    Ok(order
+        .into_iter()
+        .filter_map(|key| last.remove(&key))
+        .filter(|record| record.state != RenameState::Done)
+        .collect())
+}
+
+/// Append only JSON lines journal of the renames in flight, every record is synced before the
+/// step it announces
+pub struct RenameJournal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl RenameJournal {
+    /// Opens the journal at `path` and returns its pending renames, see [`read_pending`]
+    ///
+    /// The finished renames are compacted away first.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<JournalRecord>), JournalError> {
+        let path = path.as_ref().to_path_buf();
+        let pending = read_pending(&path)?;
+        atomic_write_with(&path, |w| {
+            for record in &pending {
+                serde_json::to_writer(&mut *w, record).map_err(io::Error::from)?;
+                w.write_all(b"\n")?;
+            }
+            Ok::<_, io::Error>(())
+        })?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        let journal = RenameJournal {
+            path,
+            file: Mutex::new(file),
+        };
+        Ok((journal, pending))
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, file: &RenamedFile, state: RenameState) -> io::Result<()> {
+        let record = JournalRecord {
+            file: file.clone(),
+            state,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        let mut journal = self.file.lock().unwrap();
+        journal.write_all(&line)?;
+        journal.sync_data()
+    }
+}
+
+/// What a journaled rename needs from the bucket
+///
+/// Only ever called with concrete stores, whose futures keep their `Send`.
+#[allow(async_fn_in_trait)]
+pub trait RenameStore {
+    async fn copy(&self, src: &str, dst: &str) -> anyhow::Result<()>;
+
+    async fn delete(&self, path: &str) -> anyhow::Result<()>;
+
+    /// Content length, `None` if `path` does not exist
+    async fn size(&self, path: &str) -> anyhow::Result<Option<u64>>;
+
+    /// Last `len` bytes of `path`, all of it if shorter
+    async fn tail(&self, path: &str, len: u64) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Bytes at the end of `src` and `dst` compared before a rename is taken as copied
+pub const TAIL_LEN: u64 = 4096;
+
+/// Copies `old_key` to `new_key` and deletes `old_key`, journaling every step
+pub async fn journaled_rename<S: RenameStore>(
+    store: &S,
+    journal: &RenameJournal,
+    file: &RenamedFile,
+) -> anyhow::Result<()> {
+    journal.record(file, RenameState::Copying)?;
+    store.copy(&file.old_key, &file.new_key).await?;
+    journal.record(file, RenameState::Copied)?;
+    store.delete(&file.old_key).await?;
+    journal.record(file, RenameState::Done)?;
+    Ok(())
+}
+
+/// How a pending rename is finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recovery {
+    /// `dst` matches `src`, only the delete was missing
+    DeleteSource,
+    /// `dst` is missing or partial, the rename runs again
+    Redo,
+    /// Both steps had happened, only the journal lagged behind
+    Complete,
+    /// Neither object exists, left in the journal
+    Lost,
+}
+
+impl Display for Recovery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Recovery::DeleteSource => write!(f, "source deleted"),
+            Recovery::Redo => write!(f, "redone"),
+            Recovery::Complete => write!(f, "already complete"),
+            Recovery::Lost => write!(f, "lost"),
+        }
+    }
+}
+
+/// Decides from the sizes of `src` and `dst`, `None` for a missing object
+///
+/// Equal sizes are only a candidate, [`replay`] redoes the rename when the tails differ too. The
+/// journal state does not matter: a `copying` rename whose `dst` is complete is as good as
+/// `copied`, and a `copied` one whose `dst` went missing has to be redone all the same.
+pub fn recover(src: Option<u64>, dst: Option<u64>) -> Recovery {
+    match (src, dst) {
+        (Some(src), Some(dst)) if src == dst => Recovery::DeleteSource,
+        (Some(_), _) => Recovery::Redo,
+        (None, Some(_)) => Recovery::Complete,
+        (None, None) => Recovery::Lost,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayOutcome {
+    /// Renames the replay finished, belong in the manifest
+    pub renamed: Vec<RenamedFile>,
+    /// Number of finished and lost renames per recovery
+    pub counts: BTreeMap<Recovery, usize>,
+    pub lost: Vec<RenamedFile>,
+    /// Still pending, retried by the next run
+    pub failed: Vec<RenamedFile>,
+}
+
+async fn same_tail<S: RenameStore>(store: &S, file: &RenamedFile) -> anyhow::Result<bool> {
+    let src = store.tail(&file.old_key, TAIL_LEN).await?;
+    Ok(src == store.tail(&file.new_key, TAIL_LEN).await?)
+}
+
+async fn replay_one<S: RenameStore>(
+    store: &S,
+    journal: &RenameJournal,
+    file: &RenamedFile,
+) -> anyhow::Result<Recovery> {
+    let mut recovery = recover(
+        store.size(&file.old_key).await?,
+        store.size(&file.new_key).await?,
+    );
+    // a torn copy can have the full size, e.g. preallocated or zero filled by the backend
+    if recovery == Recovery::DeleteSource && !same_tail(store, file).await? {
+        recovery = Recovery::Redo;
+    }
+    match recovery {
+        Recovery::DeleteSource => {
+            journal.record(file, RenameState::Copied)?;
+            store.delete(&file.old_key).await?;
+            journal.record(file, RenameState::Done)?;
+        }
+        Recovery::Redo => journaled_rename(store, journal, file).await?,
+        Recovery::Complete => journal.record(file, RenameState::Done)?,
+        Recovery::Lost => {}
+    }
+    Ok(recovery)
+}
+
+/// Finishes the `pending` renames of a previous run, one at a time
+pub async fn replay<S: RenameStore>(
+    store: &S,
+    journal: &RenameJournal,
+    pending: Vec<JournalRecord>,
+) -> ReplayOutcome {
+    let mut outcome = ReplayOutcome::default();
+    for JournalRecord { file, state } in pending {
+        match replay_one(store, journal, &file).await {
+            Ok(recovery) => {
+                tracing::info!(
+                    "Replayed {:?} rename {} -> {}: {}",
+                    state,
+                    file.old_key,
+                    file.new_key,
+                    recovery
+                );
+                *outcome.counts.entry(recovery).or_default() += 1;
+                match recovery {
+                    Recovery::Lost => {
+                        tracing::error!("Neither {} nor {} exists", file.old_key, file.new_key);
+                        outcome.lost.push(file);
+                    }
+                    _ => outcome.renamed.push(file),
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to replay rename of {}: {}", file.old_key, e);
+                outcome.failed.push(file);
+            }
+        }
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// Where [`FakeStore`] fails like a killed process would, once
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum CrashPoint {
+        /// Half of `dst` written
+        MidCopy,
+        AfterCopy,
+        BeforeDelete,
+        AfterDelete,
+    }
+
+    #[derive(Default)]
+    struct FakeStore {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+        crash: Mutex<Option<CrashPoint>>,
+    }
+
+    impl FakeStore {
+        fn with(objects: &[(&str, &[u8])]) -> Self {
+            let objects = objects
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_vec()))
+                .collect();
+            FakeStore {
+                objects: Mutex::new(objects),
+                ..Default::default()
+            }
+        }
+
+        fn crash_at(&self, point: CrashPoint) {
+            *self.crash.lock().unwrap() = Some(point);
+        }
+
+        fn crashes(&self, point: CrashPoint) -> anyhow::Result<()> {
+            let mut crash = self.crash.lock().unwrap();
+            match *crash == Some(point) {
+                true => {
+                    *crash = None;
+                    anyhow::bail!("crashed {:?}", point)
+                }
+                false => Ok(()),
+            }
+        }
+
+        fn get(&self, path: &str) -> Option<Vec<u8>> {
+            self.objects.lock().unwrap().get(path).cloned()
+        }
+    }
+
+    impl RenameStore for FakeStore {
+        async fn copy(&self, src: &str, dst: &str) -> anyhow::Result<()> {
+            let data = self
+                .get(src)
+                .ok_or_else(|| anyhow::anyhow!("{} not found", src))?;
+            if *self.crash.lock().unwrap() == Some(CrashPoint::MidCopy) {
+                let partial = data[..data.len() / 2].to_vec();
+                self.objects
+                    .lock()
+                    .unwrap()
+                    .insert(dst.to_string(), partial);
+            }
+            self.crashes(CrashPoint::MidCopy)?;
+            self.objects.lock().unwrap().insert(dst.to_string(), data);
+            self.crashes(CrashPoint::AfterCopy)
+        }
+
+        async fn delete(&self, path: &str) -> anyhow::Result<()> {
+            self.crashes(CrashPoint::BeforeDelete)?;
+            self.objects.lock().unwrap().remove(path);
+            self.crashes(CrashPoint::AfterDelete)
+        }
+
+        async fn size(&self, path: &str) -> anyhow::Result<Option<u64>> {
+            Ok(self.get(path).map(|data| data.len() as u64))
+        }
+
+        async fn tail(&self, path: &str, len: u64) -> anyhow::Result<Vec<u8>> {
+            let data = self
+                .get(path)
+                .ok_or_else(|| anyhow::anyhow!("{} not found", path))?;
+            let start = data.len().saturating_sub(len as usize);
+            Ok(data[start..].to_vec())
+        }
+    }
+
+    const DATA: &[u8] = b"\x89PNG not really a jpeg";
+
+    fn journal_path() -> PathBuf {
+        std::env::temp_dir().join(format!("stage7_journal_{}.jsonl", Uuid::new_v4()))
+    }
+
+    fn file(n: u128) -> RenamedFile {
+        RenamedFile {
+            point_id: Uuid::from_u128(n),
+            old_key: format!("{n}.jpg"),
+            new_key: format!("{n}.png"),
+            new_ext: "png".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_recover() {
+        assert_eq!(recover(Some(10), Some(10)), Recovery::DeleteSource);
+        assert_eq!(recover(Some(10), Some(5)), Recovery::Redo);
+        assert_eq!(recover(Some(10), None), Recovery::Redo);
+        assert_eq!(recover(None, Some(10)), Recovery::Complete);
+        assert_eq!(recover(None, None), Recovery::Lost);
+    }
+
+    #[tokio::test]
+    async fn test_replay_crash_points() {
+        let cases = [
+            (CrashPoint::MidCopy, RenameState::Copying, Recovery::Redo),
+            (
+                CrashPoint::AfterCopy,
+                RenameState::Copying,
+                Recovery::DeleteSource,
+            ),
+            (
+                CrashPoint::BeforeDelete,
+                RenameState::Copied,
+                Recovery::DeleteSource,
+            ),
+            (
+                CrashPoint::AfterDelete,
+                RenameState::Copied,
+                Recovery::Complete,
+            ),
+        ];
+        for (point, state, recovery) in cases {
+            let path = journal_path();
+            let f = file(1);
+            let store = FakeStore::with(&[(f.old_key.as_str(), DATA)]);
+            store.crash_at(point);
+            let (journal, pending) = RenameJournal::open(&path).unwrap();
+            assert!(pending.is_empty());
+            assert!(journaled_rename(&store, &journal, &f).await.is_err());
+            drop(journal);
+
+            // the next run
+            let (journal, pending) = RenameJournal::open(&path).unwrap();
+            assert_eq!(
+                pending,
+                vec![JournalRecord {
+                    file: f.clone(),
+                    state
+                }],
+                "{point:?}"
+            );
+            let outcome = replay(&store, &journal, pending).await;
+            assert_eq!(outcome.counts, BTreeMap::from([(recovery, 1)]), "{point:?}");
+            assert_eq!(outcome.renamed, vec![f.clone()]);
+            assert!(outcome.failed.is_empty() && outcome.lost.is_empty());
+            assert_eq!(store.get(&f.old_key), None, "{point:?}");
+            assert_eq!(store.get(&f.new_key).as_deref(), Some(DATA), "{point:?}");
+            drop(journal);
+            assert!(read_pending(&path).unwrap().is_empty());
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_same_size_torn() {
+        let path = journal_path();
+        let f = file(1);
+        let mut torn = DATA[..DATA.len() / 2].to_vec();
+        torn.resize(DATA.len(), 0);
+        let store = FakeStore::with(&[(f.old_key.as_str(), DATA), (f.new_key.as_str(), &torn)]);
+        let (journal, _) = RenameJournal::open(&path).unwrap();
+        journal.record(&f, RenameState::Copying).unwrap();
+        drop(journal);
+
+        let (journal, pending) = RenameJournal::open(&path).unwrap();
+        let outcome = replay(&store, &journal, pending).await;
+        assert_eq!(outcome.counts, BTreeMap::from([(Recovery::Redo, 1)]));
+        assert_eq!(store.get(&f.old_key), None);
+        assert_eq!(store.get(&f.new_key).as_deref(), Some(DATA));
+        drop(journal);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_lost_and_failed() {
+        let path = journal_path();
+        let (journal, _) = RenameJournal::open(&path).unwrap();
+        let (lost, failing) = (file(1), file(2));
+        journal.record(&lost, RenameState::Copied).unwrap();
+        journal.record(&failing, RenameState::Copying).unwrap();
+        drop(journal);
+
+        let store = FakeStore::with(&[(failing.old_key.as_str(), DATA)]);
+        store.crash_at(CrashPoint::MidCopy);
+        let (journal, pending) = RenameJournal::open(&path).unwrap();
+        let outcome = replay(&store, &journal, pending).await;
+        assert_eq!(outcome.lost, vec![lost.clone()]);
+        assert_eq!(outcome.failed, vec![failing.clone()]);
+        assert!(outcome.renamed.is_empty());
+        drop(journal);
+        // both stay pending
+        let pending: Vec<RenamedFile> = read_pending(&path)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.file)
+            .collect();
+        assert_eq!(pending, vec![lost, failing]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_pending() {
+        let path = journal_path();
+        assert!(read_pending(&path).unwrap().is_empty());
+        let (journal, _) = RenameJournal::open(&path).unwrap();
+        for n in 1..=3 {
+            journal.record(&file(n), RenameState::Copying).unwrap();
+        }
+        journal.record(&file(2), RenameState::Copied).unwrap();
+        journal.record(&file(1), RenameState::Copied).unwrap();
+        journal.record(&file(1), RenameState::Done).unwrap();
+        drop(journal);
+        // a crash mid-append
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(br#"{"point_id":"00000000-0000-0000-0000-00000"#)
+            .unwrap();
+        drop(f);
+        let pending = read_pending(&path).unwrap();
+        let states: Vec<(String, RenameState)> = pending
+            .iter()
+            .map(|r| (r.file.old_key.clone(), r.state))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("2.jpg".to_string(), RenameState::Copied),
+                ("3.jpg".to_string(), RenameState::Copying),
+            ]
+        );
+
+        // compacted on open, the torn line included
+        let (journal, reopened) = RenameJournal::open(&path).unwrap();
+        assert_eq!(reopened, pending);
+        drop(journal);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        std::fs::write(&path, "not json\n{}\n").unwrap();
+        assert!(matches!(
+            read_pending(&path),
+            Err(JournalError::Corrupt { line: 1, .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
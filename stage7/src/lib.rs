@@ -0,0 +1,3 @@
+pub mod journal;
+pub mod rename;
+pub mod skip;
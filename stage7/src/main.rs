@@ -1,159 +1,17 @@
 use anyhow::Result;
 use clap::Parser;
-use futures::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
-use serde::{Deserialize, Serialize};
-use shared::opendal::GenShinOperator;
+use shared::atomic_write::atomic_write_with;
+use shared::logging::Logging;
+use shared::stage_lock::LockArgs;
+use shared::stall::{StallArgs, StallConfig};
 use shared::structure::WrongExtFile;
-use std::borrow::Cow;
+use stage7::journal::{RenameJournal, ReplayOutcome, read_pending};
+use stage7::rename::{ExtPairs, Stage7Operator};
+use stage7::skip::SkipRules;
 use std::collections::HashSet;
 use std::fs;
-use std::ops::Deref;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
-
-#[derive(Serialize, Deserialize)]
-#[serde(transparent)]
-struct RenameFailedTask(WrongExtFile);
-
-pub struct Stage7Operator {
-    op: GenShinOperator,
-    dry_run: bool,
-    worker_num: usize,
-    need_skip: bool,
-    skip_ext_pairs: HashSet<(Cow<'static, str>, Cow<'static, str>)>,
-    need_include: bool,
-    include_ext_pairs: HashSet<(Cow<'static, str>, Cow<'static, str>)>,
-}
-
-impl Deref for Stage7Operator {
-    type Target = GenShinOperator;
-
-    fn deref(&self) -> &Self::Target {
-        &self.op
-    }
-}
-
-impl Stage7Operator {
-    fn new(
-        dry_run: bool,
-        worker_num: usize,
-        skip_ext_pairs: HashSet<(Cow<'static, str>, Cow<'static, str>)>,
-        include_ext_pairs: HashSet<(Cow<'static, str>, Cow<'static, str>)>,
-    ) -> Result<Self> {
-        let op = GenShinOperator::new()?;
-        Ok(Self {
-            op,
-            dry_run,
-            worker_num,
-            need_skip: !skip_ext_pairs.is_empty(),
-            need_include: !include_ext_pairs.is_empty(),
-            skip_ext_pairs,
-            include_ext_pairs,
-        })
-    }
-
-    async fn rename_task(
-        self: Arc<Self>,
-        files: Vec<WrongExtFile>,
-    ) -> Result<Option<Vec<RenameFailedTask>>> {
-        let pb = ProgressBar::new(files.len() as u64);
-        let style = ProgressStyle::default_bar()
-            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
-        pb.set_style(style);
-        pb.set_message("Renaming extensions...");
-        let mut stream = futures::stream::iter(files.into_iter().map(|file| {
-            let op = self.clone();
-            let pb = pb.clone();
-            async move {
-                let triage = op.rename_single_task(file).await?;
-                pb.inc(1);
-                Ok::<_, anyhow::Error>(triage)
-            }
-        }))
-        .buffer_unordered(self.worker_num);
-        let mut failed_tasks = Vec::new();
-        while let Some(res) = stream.next().await {
-            match res {
-                Ok(Some(task)) => failed_tasks.push(task),
-                Ok(None) => {}
-                Err(e) => {
-                    tracing::error!("Error: {}", e);
-                }
-            }
-        }
-        pb.finish_with_message("Done");
-        if failed_tasks.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(failed_tasks))
-        }
-    }
-
-    async fn rename_single_task(
-        self: Arc<Self>,
-        file: WrongExtFile,
-    ) -> Result<Option<RenameFailedTask>> {
-        let wrong_ext = file.path.split('.').last().unwrap();
-        let right_ext = &file.expected_ext;
-        let wrong_file_path = &file.path;
-        let right_file_path = format!(
-            "{}.{}",
-            file.path.split('.').next().unwrap(),
-            file.expected_ext.as_str()
-        );
-        if self.need_include
-            && !self
-                .include_ext_pairs
-                .contains(&(wrong_ext.into(), right_ext.into()))
-        {
-            tracing::warn!(
-                "Skipping rename from {} to {} due to include_ext_pairs",
-                wrong_file_path,
-                right_file_path
-            );
-            return Ok::<_, anyhow::Error>(None);
-        }
-        if self.need_skip
-            && self
-                .skip_ext_pairs
-                .contains(&(Cow::Borrowed(wrong_ext), Cow::Borrowed(right_ext)))
-        {
-            tracing::warn!(
-                "Skipping rename from {} to {} due to skip_ext_pairs",
-                wrong_file_path,
-                right_file_path
-            );
-            return Ok::<_, anyhow::Error>(None);
-        }
-        if self.dry_run {
-            tracing::info!("Dry run: {} -> {}", wrong_file_path, right_file_path);
-            return Ok(None);
-        }
-        match self
-            .rename_atomic_task(&wrong_file_path, &right_file_path)
-            .await
-        {
-            Ok(_) => {
-                tracing::debug!("Renamed {} to {}", wrong_file_path, right_file_path);
-                Ok(None)
-            }
-            Err(e) => {
-                tracing::error!("Failed to rename {}: {}", wrong_file_path, e);
-                Ok(Some(RenameFailedTask(file)))
-            }
-        }
-    }
-
-    async fn rename_atomic_task(self: Arc<Self>, src: &str, dst: &str) -> Result<()> {
-        self.op.copy(src, dst).await?;
-        self.op.delete(src).await?;
-        Ok(())
-    }
-}
 
 #[derive(Parser, Debug)]
 #[command(name = "Stage7", version)]
@@ -166,6 +24,17 @@ struct Cli {
     dry_run: bool,
     #[arg(long, default_value = "ext_files_rename")]
     save_result_prefix: String,
+    /// Successfully renamed files, consumed by `stage8 --from-manifest`
+    #[arg(long, default_value = "renamed_manifest.json")]
+    manifest_file: String,
+    /// Write-ahead journal of the renames in flight, the renames a crashed run left unfinished
+    /// are replayed from it on startup
+    #[arg(long, default_value = "rename_journal.jsonl")]
+    journal_file: PathBuf,
+    #[arg(long, default_value = "false")]
+    no_journal: bool,
+    #[command(flatten)]
+    stall: StallArgs,
     /// Skip renaming for these extensions
     /// Example: --skip-ext-pair jpeg jpg --skip-ext-pair png jpg
     #[arg(long,
@@ -173,6 +42,18 @@ struct Cli {
           value_names = &["FROM","TO"],
           action = clap::ArgAction::Append)]
     skip_ext_pair: Option<Vec<String>>,
+    /// Skip every rename to this extension, whatever the source
+    /// Example: --skip-to-ext webp
+    #[arg(long, action = clap::ArgAction::Append)]
+    skip_to_ext: Option<Vec<String>>,
+    /// Skip every rename of this extension, whatever the target
+    /// Example: --skip-from-ext gif
+    #[arg(long, action = clap::ArgAction::Append)]
+    skip_from_ext: Option<Vec<String>>,
+    /// JSON array of `{"from": "jpeg", "to": "jpg"}` skip rules, a missing side matches any
+    /// extension, merged with the skip flags
+    #[arg(long)]
+    skip_config: Option<PathBuf>,
     /// Include renaming for these extensions
     /// Example: --include-ext-pair jpeg jpg --include-ext-pair png jpg
     #[arg(long,
@@ -180,61 +61,125 @@ struct Cli {
           value_names = &["FROM","TO"],
           action = clap::ArgAction::Append)]
     include_ext_pair: Option<Vec<String>>,
+    /// Prometheus text dump of this run's metrics
+    #[arg(long, default_value = "stage7_metrics.prom")]
+    metrics_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new("info"));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage7.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new("info"));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let _logging = Logging::new("stage7").init()?;
     let cli = Cli::parse();
-    let skip_ext_pairs: HashSet<(Cow<'static, str>, Cow<'static, str>)> = cli
-        .skip_ext_pair
-        .unwrap_or_default()
-        .chunks(2)
-        .filter_map(|chunk| {
-            if chunk.len() == 2 {
-                Some((Cow::Owned(chunk[0].clone()), Cow::Owned(chunk[1].clone())))
-            } else {
-                None
-            }
-        })
-        .collect();
-    let include_ext_pairs: HashSet<(Cow<'static, str>, Cow<'static, str>)> = cli
+    let _lock = cli.lock.acquire("stage7")?;
+    let mut skip_rules = SkipRules::from_args(
+        &cli.skip_ext_pair.unwrap_or_default(),
+        &cli.skip_to_ext.unwrap_or_default(),
+        &cli.skip_from_ext.unwrap_or_default(),
+    )?;
+    if let Some(path) = cli.skip_config.as_ref() {
+        skip_rules.extend(SkipRules::load(path)?);
+    }
+    for rule in skip_rules.rules() {
+        tracing::info!("Skip rule: {}", rule);
+    }
+    let include_ext_pairs: ExtPairs = cli
         .include_ext_pair
         .unwrap_or_default()
-        .chunks(2)
-        .filter_map(|chunk| {
-            if chunk.len() == 2 {
-                Some((Cow::Owned(chunk[0].clone()), Cow::Owned(chunk[1].clone())))
-            } else {
-                None
-            }
-        })
+        .chunks_exact(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
         .collect();
-    let op = Stage7Operator::new(
-        cli.dry_run,
-        cli.worker_num,
-        skip_ext_pairs,
-        include_ext_pairs,
-    )?;
+    let mut op = Stage7Operator::new(cli.dry_run, cli.worker_num, skip_rules, include_ext_pairs)?;
+    let mut replayed = ReplayOutcome::default();
+    let mut journaled: HashSet<String> = HashSet::new();
+    if cli.dry_run {
+        let pending = read_pending(&cli.journal_file)?;
+        if !pending.is_empty() {
+            tracing::warn!(
+                "{} unfinished renames in {}, not replayed in a dry run",
+                pending.len(),
+                cli.journal_file.display()
+            );
+        }
+    } else if !cli.no_journal {
+        let (journal, pending) = RenameJournal::open(&cli.journal_file)?;
+        op = op.journal(journal);
+        if !pending.is_empty() {
+            tracing::info!("Replaying {} unfinished renames", pending.len());
+            journaled.extend(pending.iter().map(|r| r.file.old_key.clone()));
+            replayed = op.replay_journal(pending).await.unwrap_or_default();
+        }
+    }
     let file = fs::read(cli.wrong_file)?;
-    let files: Vec<WrongExtFile> = serde_json::from_slice(&file)?;
+    let mut files: Vec<WrongExtFile> = serde_json::from_slice(&file)?;
     tracing::info!("Loaded {} files", files.len());
-    let failed_tasks = Arc::new(op).rename_task(files).await?;
-    if let Some(tasks) = failed_tasks {
+    // already handled by the replay, or left for the next one
+    files.retain(|f| !journaled.contains(&f.path));
+    if !journaled.is_empty() {
+        tracing::info!("{} files left after the replayed ones", files.len());
+    }
+    let stall = StallConfig::from(&cli.stall);
+    let mut outcome = Arc::new(op).rename_task(files, &stall).await?;
+    if !journaled.is_empty() {
+        tracing::info!(
+            "Replayed {}, lost {}, still pending {}",
+            replayed.renamed.len(),
+            replayed.lost.len(),
+            replayed.failed.len()
+        );
+        for (recovery, count) in replayed.counts.iter() {
+            tracing::info!("  replay {}: {}", recovery, count);
+        }
+    }
+    tracing::info!(
+        "Renamed {}, skipped {}, failed {}",
+        outcome.renamed.len(),
+        outcome.skipped.len(),
+        outcome.failed.len()
+    );
+    for (reason, count) in outcome.skip_counts.iter() {
+        tracing::info!("  skipped {}: {}", reason, count);
+    }
+    if !cli.dry_run {
+        outcome.renamed.splice(0..0, replayed.renamed);
+        atomic_write_with(&cli.manifest_file, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer(w, &outcome.renamed)?)
+        })?;
+        tracing::info!("Saved rename manifest to {}", &cli.manifest_file);
+    }
+    if !outcome.skipped.is_empty() {
+        let save_path = format!("{}_skipped.json", cli.save_result_prefix);
+        tracing::info!("Saved skipped tasks to {}", &save_path);
+        atomic_write_with(save_path, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer(w, &outcome.skipped)?)
+        })?;
+    }
+    if !replayed.lost.is_empty() {
+        let save_path = format!("{}_lost.json", cli.save_result_prefix);
+        tracing::error!(
+            "{} journaled renames lost both objects, saved to {}",
+            replayed.lost.len(),
+            &save_path
+        );
+        atomic_write_with(save_path, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer(w, &replayed.lost)?)
+        })?;
+    }
+    if !outcome.failed.is_empty() {
         let save_path = format!("{}_failed.json", cli.save_result_prefix);
         tracing::info!("Saved failed tasks to {}", &save_path);
-        let file = fs::File::create(save_path)?;
-        serde_json::to_writer(file, &tasks)?;
-    } else {
+        atomic_write_with(save_path, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer(w, &outcome.failed)?)
+        })?;
+    } else if outcome.stalled.is_none() {
         tracing::info!("All tasks succeeded");
     }
+    shared::metrics::dump_prometheus_to(&cli.metrics_file)?;
+    tracing::info!("Metrics saved to {}", cli.metrics_file.display());
+    if let Some(e) = outcome.stalled {
+        tracing::error!("Results above are partial");
+        return Err(e.into());
+    }
     Ok(())
 }
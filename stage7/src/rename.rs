@@ -0,0 +1,327 @@
+use crate::journal::{
+    JournalRecord, RenameJournal, RenameStore, ReplayOutcome, journaled_rename, replay,
+};
+use crate::skip::{SkipRule, SkipRules};
+use anyhow::Result;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use shared::opendal::GenShinOperator;
+use shared::stall::{StallConfig, StallError, for_each_watched};
+use shared::structure::{RenamedFile, WrongExtFile};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// `(from, to)` extension pairs
+pub type ExtPairs = HashSet<(String, String)>;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RenameFailedTask(pub WrongExtFile);
+
+/// Why a file was left alone
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SkipReason {
+    /// Include pairs were given and none is this rename
+    NotIncluded,
+    Rule(SkipRule),
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::NotIncluded => write!(f, "not in include pairs"),
+            SkipReason::Rule(rule) => write!(f, "skip rule {}", rule),
+        }
+    }
+}
+
+enum RenameStatus {
+    Renamed(RenamedFile),
+    Skipped(WrongExtFile, SkipReason),
+    DryRun,
+    Failed(RenameFailedTask),
+}
+
+/// Per-file result of [`Stage7Operator::rename_task`], dry-run files are in none of the lists
+#[derive(Debug, Default)]
+pub struct RenameOutcome {
+    pub renamed: Vec<RenamedFile>,
+    pub skipped: Vec<WrongExtFile>,
+    /// Number of `skipped` per reason
+    pub skip_counts: BTreeMap<SkipReason, usize>,
+    pub failed: Vec<RenameFailedTask>,
+    /// Set when the run was aborted, the lists above are partial
+    pub stalled: Option<StallError>,
+}
+
+pub struct Stage7Operator {
+    op: GenShinOperator,
+    dry_run: bool,
+    worker_num: usize,
+    skip_rules: SkipRules,
+    include_ext_pairs: ExtPairs,
+    journal: Option<RenameJournal>,
+}
+
+impl Deref for Stage7Operator {
+    type Target = GenShinOperator;
+
+    fn deref(&self) -> &Self::Target {
+        &self.op
+    }
+}
+
+impl Stage7Operator {
+    pub fn new(
+        dry_run: bool,
+        worker_num: usize,
+        skip_rules: SkipRules,
+        include_ext_pairs: ExtPairs,
+    ) -> Result<Self> {
+        let op = GenShinOperator::new()?;
+        Ok(Self::with_operator(
+            op,
+            dry_run,
+            worker_num,
+            skip_rules,
+            include_ext_pairs,
+        ))
+    }
+
+    pub fn with_operator(
+        op: GenShinOperator,
+        dry_run: bool,
+        worker_num: usize,
+        skip_rules: SkipRules,
+        include_ext_pairs: ExtPairs,
+    ) -> Self {
+        Self {
+            op,
+            dry_run,
+            worker_num,
+            skip_rules,
+            include_ext_pairs,
+            journal: None,
+        }
+    }
+
+    /// Journals every rename so a crash between copy and delete can be replayed
+    pub fn journal(mut self, journal: RenameJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Finishes the renames a previous run left in the journal, `None` without a journal
+    pub async fn replay_journal(&self, pending: Vec<JournalRecord>) -> Option<ReplayOutcome> {
+        let journal = self.journal.as_ref()?;
+        Some(replay(self, journal, pending).await)
+    }
+
+    pub async fn rename_task(
+        self: Arc<Self>,
+        files: Vec<WrongExtFile>,
+        stall: &StallConfig,
+    ) -> Result<RenameOutcome> {
+        let pb = ProgressBar::new(files.len() as u64);
+        let style = ProgressStyle::default_bar()
+            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
+        pb.set_style(style);
+        pb.set_message("Renaming extensions...");
+        let tasks = files.into_iter().map(|file| {
+            let op = self.clone();
+            let pb = pb.clone();
+            (file.path.clone(), async move {
+                let triage = op.rename_single_task(file).await?;
+                pb.inc(1);
+                Ok::<_, anyhow::Error>(triage)
+            })
+        });
+        let mut outcome = RenameOutcome::default();
+        let watched = for_each_watched(tasks, self.worker_num, stall, |res| match res {
+            Ok(RenameStatus::Renamed(file)) => outcome.renamed.push(file),
+            Ok(RenameStatus::Skipped(file, reason)) => {
+                *outcome.skip_counts.entry(reason).or_default() += 1;
+                outcome.skipped.push(file);
+            }
+            Ok(RenameStatus::Failed(task)) => outcome.failed.push(task),
+            Ok(RenameStatus::DryRun) => {}
+            Err(e) => {
+                tracing::error!("Error: {}", e);
+            }
+        })
+        .await;
+        outcome.stalled = watched.err();
+        pb.finish_with_message("Done");
+        Ok(outcome)
+    }
+
+    /// Why `from -> to` is not renamed, `None` if it is
+    fn skip_reason(&self, from: &str, to: &str) -> Option<SkipReason> {
+        if !self.include_ext_pairs.is_empty()
+            && !self
+                .include_ext_pairs
+                .contains(&(from.to_owned(), to.to_owned()))
+        {
+            return Some(SkipReason::NotIncluded);
+        }
+        self.skip_rules
+            .matching(from, to)
+            .map(|rule| SkipReason::Rule(rule.clone()))
+    }
+
+    #[tracing::instrument(name = "stage7.rename", skip_all, fields(key = %file.path, uuid))]
+    async fn rename_single_task(self: Arc<Self>, file: WrongExtFile) -> Result<RenameStatus> {
+        let wrong_file_path = &file.path;
+        let right_file_path = file.renamed_path();
+        if let Some(reason) = self.skip_reason(file.current_ext(), &file.expected_ext) {
+            tracing::warn!(
+                "Skipping rename from {} to {}, {}",
+                wrong_file_path,
+                right_file_path,
+                reason
+            );
+            return Ok(RenameStatus::Skipped(file, reason));
+        }
+        let Some(point_id) = file.point_id() else {
+            // Without a point id the rename could never reach Qdrant, leave the object alone
+            tracing::error!("No point id in file stem of {}", wrong_file_path);
+            return Ok(RenameStatus::Failed(RenameFailedTask(file)));
+        };
+        tracing::Span::current().record("uuid", tracing::field::display(point_id));
+        if self.dry_run {
+            tracing::info!("Dry run: {} -> {}", wrong_file_path, right_file_path);
+            return Ok(RenameStatus::DryRun);
+        }
+        let renamed = RenamedFile {
+            point_id,
+            old_key: file.path.clone(),
+            new_key: right_file_path,
+            new_ext: file.expected_ext.clone(),
+        };
+        match self.rename_atomic_task(&renamed).await {
+            Ok(_) => {
+                tracing::debug!("Renamed {} to {}", renamed.old_key, renamed.new_key);
+                Ok(RenameStatus::Renamed(renamed))
+            }
+            Err(e) => {
+                tracing::error!("Failed to rename {}: {}", wrong_file_path, e);
+                Ok(RenameStatus::Failed(RenameFailedTask(file)))
+            }
+        }
+    }
+
+    async fn rename_atomic_task(&self, file: &RenamedFile) -> Result<()> {
+        match self.journal.as_ref() {
+            Some(journal) => journaled_rename(self, journal, file).await,
+            None => {
+                RenameStore::copy(self, &file.old_key, &file.new_key).await?;
+                RenameStore::delete(self, &file.old_key).await
+            }
+        }
+    }
+}
+
+impl RenameStore for Stage7Operator {
+    async fn copy(&self, src: &str, dst: &str) -> Result<()> {
+        if self.op.info().full_capability().copy {
+            self.op.copy(src, dst).await?;
+        } else {
+            // e.g. the memory service, fall back to a full read and write
+            let buf = self.op.read(src).await?;
+            self.op.write(dst, buf).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        Ok(self.op.delete(path).await?)
+    }
+
+    async fn size(&self, path: &str) -> Result<Option<u64>> {
+        match self.op.exists(path).await? {
+            true => Ok(Some(self.op.stat(path).await?.content_length())),
+            false => Ok(None),
+        }
+    }
+
+    async fn tail(&self, path: &str, len: u64) -> Result<Vec<u8>> {
+        let size = self.op.stat(path).await?.content_length();
+        let range = size.saturating_sub(len)..size;
+        Ok(self.op.read_with(path).range(range).await?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::read_pending;
+    use std::fs;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_rename_on_fs_backend() {
+        let root = std::env::temp_dir().join(format!("stage7_fs_{}", Uuid::new_v4()));
+        let op = GenShinOperator::fs(root.to_str().unwrap()).unwrap();
+        let (renamed_id, skipped_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let files = vec![
+            WrongExtFile {
+                path: format!("img/{}.png", renamed_id),
+                expected_ext: "jpg".to_string(),
+            },
+            WrongExtFile {
+                path: format!("img/{}.gif", skipped_id),
+                expected_ext: "webp".to_string(),
+            },
+            WrongExtFile {
+                path: "img/not-a-point.png".to_string(),
+                expected_ext: "jpg".to_string(),
+            },
+        ];
+        for file in &files {
+            op.write(&file.path, file.path.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+        let journal_path = root.with_extension("jsonl");
+        let (journal, pending) = RenameJournal::open(&journal_path).unwrap();
+        assert!(pending.is_empty());
+        let skip_rules = SkipRules::from_args(&[], &["webp".to_string()], &[]).unwrap();
+        let op = Arc::new(
+            Stage7Operator::with_operator(op, false, 4, skip_rules, ExtPairs::new())
+                .journal(journal),
+        );
+        let outcome = op
+            .clone()
+            .rename_task(files.clone(), &StallConfig::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            outcome.renamed,
+            vec![RenamedFile {
+                point_id: renamed_id,
+                old_key: files[0].path.clone(),
+                new_key: format!("img/{}.jpg", renamed_id),
+                new_ext: "jpg".to_string(),
+            }]
+        );
+        assert_eq!(outcome.skipped.len(), 1);
+        assert_eq!(outcome.failed.len(), 1);
+        assert!(outcome.stalled.is_none());
+        assert!(!root.join(&files[0].path).exists());
+        assert_eq!(
+            fs::read(root.join(&outcome.renamed[0].new_key)).unwrap(),
+            files[0].path.as_bytes()
+        );
+        // left alone
+        assert!(root.join(&files[1].path).exists());
+        assert!(root.join(&files[2].path).exists());
+        assert!(read_pending(&journal_path).unwrap().is_empty());
+
+        drop(op);
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_file(&journal_path).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// A rename `from -> to` extension pattern that is left alone
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "SkipRuleSpec", into = "SkipRuleSpec")]
+pub enum SkipRule {
+    /// `from -> to`
+    ExactPair(String, String),
+    /// Any source renamed to this extension
+    FromAny(String),
+    /// This extension renamed to anything
+    ToAny(String),
+}
+
+/// Config file form of [`SkipRule`], a missing side matches any extension
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SkipRuleSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+}
+
+impl TryFrom<SkipRuleSpec> for SkipRule {
+    type Error = String;
+
+    fn try_from(spec: SkipRuleSpec) -> Result<Self, Self::Error> {
+        match (spec.from, spec.to) {
+            (Some(from), Some(to)) => Ok(SkipRule::ExactPair(from, to)),
+            (None, Some(to)) => Ok(SkipRule::FromAny(to)),
+            (Some(from), None) => Ok(SkipRule::ToAny(from)),
+            (None, None) => Err("skip rule needs `from`, `to` or both".to_string()),
+        }
+    }
+}
+
+impl From<SkipRule> for SkipRuleSpec {
+    fn from(rule: SkipRule) -> Self {
+        match rule {
+            SkipRule::ExactPair(from, to) => SkipRuleSpec {
+                from: Some(from),
+                to: Some(to),
+            },
+            SkipRule::FromAny(to) => SkipRuleSpec {
+                from: None,
+                to: Some(to),
+            },
+            SkipRule::ToAny(from) => SkipRuleSpec {
+                from: Some(from),
+                to: None,
+            },
+        }
+    }
+}
+
+impl SkipRule {
+    pub fn matches(&self, from: &str, to: &str) -> bool {
+        match self {
+            SkipRule::ExactPair(f, t) => f == from && t == to,
+            SkipRule::FromAny(t) => t == to,
+            SkipRule::ToAny(f) => f == from,
+        }
+    }
+
+    /// Exact pairs win over wildcards when several rules match, so counts name the narrowest one
+    fn specificity(&self) -> u8 {
+        match self {
+            SkipRule::ExactPair(..) => 1,
+            SkipRule::FromAny(_) | SkipRule::ToAny(_) => 0,
+        }
+    }
+}
+
+impl Display for SkipRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipRule::ExactPair(from, to) => write!(f, "{} -> {}", from, to),
+            SkipRule::FromAny(to) => write!(f, "* -> {}", to),
+            SkipRule::ToAny(from) => write!(f, "{} -> *", from),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SkipRules(Vec<SkipRule>);
+
+impl SkipRules {
+    pub fn new(rules: impl IntoIterator<Item = SkipRule>) -> Self {
+        let mut rules: Vec<SkipRule> = rules.into_iter().collect();
+        rules.sort();
+        rules.dedup();
+        Self(rules)
+    }
+
+    /// From the CLI's flat `FROM TO` pair list and single extension lists, a `FROM` without its
+    /// `TO` is an error
+    pub fn from_args(
+        pairs: &[String],
+        to_exts: &[String],
+        from_exts: &[String],
+    ) -> anyhow::Result<Self> {
+        if let Some(last) = pairs.last().filter(|_| pairs.len() % 2 == 1) {
+            anyhow::bail!("--skip-ext-pair {} has no target extension", last);
+        }
+        let pairs = pairs
+            .chunks_exact(2)
+            .map(|pair| SkipRule::ExactPair(pair[0].clone(), pair[1].clone()));
+        let to = to_exts.iter().cloned().map(SkipRule::FromAny);
+        let from = from_exts.iter().cloned().map(SkipRule::ToAny);
+        Ok(Self::new(pairs.chain(to).chain(from)))
+    }
+
+    /// JSON array of `{"from": "jpeg", "to": "jpg"}`, either side may be left out
+    pub fn load(path: &Path) -> anyhow::Result<Vec<SkipRule>> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn extend(&mut self, rules: impl IntoIterator<Item = SkipRule>) {
+        *self = Self::new(self.0.drain(..).chain(rules));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn rules(&self) -> &[SkipRule] {
+        &self.0
+    }
+
+    /// The most specific rule skipping `from -> to`, if any
+    pub fn matching(&self, from: &str, to: &str) -> Option<&SkipRule> {
+        self.0
+            .iter()
+            .filter(|rule| rule.matches(from, to))
+            .max_by_key(|rule| rule.specificity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let rules = SkipRules::from_args(
+            &s(&["jpeg", "jpg", "png", "jpg"]),
+            &s(&["webp"]),
+            &s(&["gif"]),
+        )
+        .unwrap();
+        assert_eq!(
+            rules.rules(),
+            &[
+                SkipRule::ExactPair("jpeg".into(), "jpg".into()),
+                SkipRule::ExactPair("png".into(), "jpg".into()),
+                SkipRule::FromAny("webp".into()),
+                SkipRule::ToAny("gif".into()),
+            ]
+        );
+        assert!(SkipRules::from_args(&[], &[], &[]).unwrap().is_empty());
+        let odd = SkipRules::from_args(&s(&["jpeg", "jpg", "odd"]), &[], &[]).unwrap_err();
+        assert!(odd.to_string().contains("odd"), "{}", odd);
+    }
+
+    #[test]
+    fn test_config_file_format() {
+        let rules: Vec<SkipRule> = serde_json::from_str(
+            r#"[{"from": "jpeg", "to": "jpg"}, {"to": "webp"}, {"from": "gif"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                SkipRule::ExactPair("jpeg".into(), "jpg".into()),
+                SkipRule::FromAny("webp".into()),
+                SkipRule::ToAny("gif".into()),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&rules).unwrap(),
+            r#"[{"from":"jpeg","to":"jpg"},{"to":"webp"},{"from":"gif"}]"#
+        );
+        assert!(serde_json::from_str::<Vec<SkipRule>>(r#"[{}]"#).is_err());
+    }
+
+    #[test]
+    fn test_matching_precedence() {
+        let rules = SkipRules::new([
+            SkipRule::FromAny("jpg".into()),
+            SkipRule::ToAny("jpeg".into()),
+            SkipRule::ExactPair("jpeg".into(), "jpg".into()),
+        ]);
+        assert_eq!(
+            rules.matching("jpeg", "jpg"),
+            Some(&SkipRule::ExactPair("jpeg".into(), "jpg".into()))
+        );
+        assert_eq!(
+            rules.matching("png", "jpg"),
+            Some(&SkipRule::FromAny("jpg".into()))
+        );
+        assert_eq!(
+            rules.matching("jpeg", "png"),
+            Some(&SkipRule::ToAny("jpeg".into()))
+        );
+        assert_eq!(rules.matching("png", "webp"), None);
+        // direction matters
+        assert_eq!(rules.matching("jpg", "jpeg"), None);
+    }
+
+    #[test]
+    fn test_owned_and_borrowed_lookup() {
+        // rules hold owned strings from the CLI, lookups borrow from each file's path
+        let rules = SkipRules::from_args(&s(&["jpeg", "jpg"]), &[], &[]).unwrap();
+        let path = String::from("img/a.jpeg");
+        let from = &path[path.len() - 4..];
+        let to = String::from("jpg");
+        assert!(rules.matching(from, &to).is_some());
+        assert!(rules.matching(&from.to_uppercase(), &to).is_none());
+    }
+}
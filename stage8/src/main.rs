@@ -1,22 +1,22 @@
-use clap::Parser;
-use futures::StreamExt;
+use clap::{ArgGroup, Parser};
 use indicatif::{ProgressBar, ProgressStyle};
-use qdrant_client::Payload;
-use qdrant_client::QdrantError;
-use qdrant_client::qdrant::{PointsIdsList, PointsOperationResponse, SetPayloadPointsBuilder};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use shared::qdrant::GenShinQdrantClient;
-use shared::structure::WrongExtFile;
-use std::fs::File;
-use std::ops::Deref;
-use std::path::PathBuf;
-use std::sync::Arc;
+use serde_json::{Map, Value};
+use shared::atomic_write::atomic_write_with;
+use shared::logging::Logging;
+use shared::op_ledger::OpLedger;
+use shared::qdrant::{
+    ConflictAction, GenShinQdrantClient, IdKindCounts, OnConflict, PayloadConflict, PointRef,
+    PointStore, QdrantPointStore, QdrantWriteScheduler, WriteOp, WriteSchedulerConfig, WriteStatus,
+    guard_writes,
+};
+use shared::stage_lock::LockArgs;
+use shared::stall::{StallArgs, StallConfig, StallError};
+use shared::structure::{RenamedFile, WrongExtFile};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::{env, fs};
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct RenameOp {
@@ -33,181 +33,506 @@ struct FailedRenameOp {
     error: String,
 }
 
-struct Stage8GenshinQdrantClient {
-    client: GenShinQdrantClient,
-    collection_name: String,
-    dry_run: bool,
-    worker_num: usize,
-    url_prefix: String,
+/// A renamed point whose `format` or `url` changed since the rename was planned
+#[derive(Debug, Serialize)]
+struct RenameConflict {
+    #[serde(flatten)]
+    op: RenameOp,
+    #[serde(flatten)]
+    conflict: PayloadConflict,
 }
 
-impl Deref for Stage8GenshinQdrantClient {
-    type Target = GenShinQdrantClient;
-
-    fn deref(&self) -> &Self::Target {
-        &self.client
-    }
+/// Payload update for the renamed point, fails if the point id is neither a UUID nor a number
+fn write_op(op: &RenameOp, url_prefix: &str) -> Result<WriteOp, String> {
+    let point: PointRef = op.point_id.parse()?;
+    let url = format!("{}/{}.{}", url_prefix, &op.point_id, &op.target_ext);
+    let payload = Map::from_iter([
+        ("format".to_owned(), Value::from(op.target_ext.clone())),
+        ("url".to_owned(), Value::from(url)),
+    ]);
+    Ok(WriteOp::SetPayload {
+        points: vec![point.key()],
+        payload,
+    })
 }
 
-impl Stage8GenshinQdrantClient {
-    pub fn new(
-        collection_name: &str,
-        dry_run: bool,
-        worker_num: usize,
-        url_prefix: &str,
-    ) -> anyhow::Result<Self> {
-        let client = GenShinQdrantClient::new()?;
-        Ok(Self {
-            client,
-            collection_name: collection_name.to_owned(),
-            dry_run,
-            worker_num,
-            url_prefix: url_prefix.to_owned(),
-        })
-    }
+/// `format` and `url` the point is expected to have before the rename, those of its source key
+fn snapshot_payload(op: &RenameOp, url_prefix: &str) -> Map<String, Value> {
+    let Some(ext) = Path::new(&op.src).extension().and_then(|e| e.to_str()) else {
+        return Map::new();
+    };
+    let url = format!("{}/{}.{}", url_prefix, &op.point_id, ext);
+    Map::from_iter([
+        ("format".to_owned(), Value::from(ext)),
+        ("url".to_owned(), Value::from(url)),
+    ])
+}
 
-    async fn set_payload_task(
-        self: Arc<Self>,
-        ops: &[RenameOp],
-    ) -> anyhow::Result<Option<Vec<FailedRenameOp>>> {
-        let pb = ProgressBar::new(ops.len() as u64);
-        let style = ProgressStyle::default_bar()
-            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
-        pb.set_style(style);
-        pb.set_message("Overwriting Qdrant payload...");
-        let mut stream = futures::stream::iter(ops.into_iter().map(|op| {
-            let client = self.clone();
-            let pb = pb.clone();
-            async move {
-                let triage = client.set_payload_atomic(op).await;
-                pb.inc(1);
-                (op, triage)
+async fn set_payload_task<S: PointStore>(
+    scheduler: &QdrantWriteScheduler<S>,
+    ops: &[RenameOp],
+    url_prefix: &str,
+    on_conflict: OnConflict,
+) -> anyhow::Result<(Vec<FailedRenameOp>, Vec<RenameConflict>, Option<StallError>)> {
+    let mut failed_tasks = Vec::new();
+    let mut writes = Vec::with_capacity(ops.len());
+    let mut owners = Vec::with_capacity(ops.len());
+    let mut snapshot = HashMap::with_capacity(ops.len());
+    for op in ops {
+        match write_op(op, url_prefix) {
+            Ok(write) => {
+                snapshot.insert(write.points()[0], snapshot_payload(op, url_prefix));
+                writes.push(write);
+                owners.push(op);
             }
-        }))
-        .buffer_unordered(self.worker_num);
-        let mut failed_tasks = Vec::new();
-        while let Some((op, res)) = stream.next().await {
-            match res {
-                Ok(Some(res)) => {
-                    tracing::debug!("Point {} overwritten successfully: {:?}", op.point_id, res);
-                }
-                Err(e) => {
-                    tracing::error!("Failed to overwrite point {}: {}", op.point_id, e);
-                    failed_tasks.push(FailedRenameOp {
-                        op: op.clone(),
-                        error: e.to_string(),
-                    });
-                }
-                _ => {} // already handled
-            }
-        }
-        pb.finish_with_message("Done");
-        if failed_tasks.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(failed_tasks))
+            Err(error) => failed_tasks.push(FailedRenameOp {
+                op: op.clone(),
+                error,
+            }),
         }
     }
-
-    async fn set_payload_atomic(
-        self: Arc<Self>,
-        op: &RenameOp,
-    ) -> Result<Option<PointsOperationResponse>, QdrantError> {
-        let url = format!("{}/{}.{}", &self.url_prefix, &op.point_id, &op.target_ext);
-        let payload = Payload::try_from(json!({
-            "format": op.target_ext.to_owned(),
-            "url": url,
-        }))?;
-        if self.dry_run {
-            tracing::info!(
-                "Dry run: would overwrite point {} with URL {}, Payload: {:?}",
-                &op.point_id,
-                &url,
-                &payload
-            );
-            return Ok(None);
+    let batch_size = scheduler.config().batch_size;
+    let guarded = match guard_writes(
+        scheduler.store(),
+        &writes,
+        &snapshot,
+        on_conflict,
+        batch_size,
+    )
+    .await
+    {
+        Ok(guarded) => guarded,
+        Err(e) => {
+            tracing::error!("Failed to read the payload of the renamed points: {}", e);
+            failed_tasks.extend(owners.into_iter().map(|op| FailedRenameOp {
+                op: op.clone(),
+                error: format!("payload check failed: {}", e),
+            }));
+            return Ok((failed_tasks, Vec::new(), None));
         }
-        self.client
-            .set_payload(
-                SetPayloadPointsBuilder::new(&self.collection_name, payload)
-                    .points_selector(PointsIdsList {
-                        ids: vec![op.point_id.to_owned().into()],
-                    })
-                    .wait(true),
-            )
-            .await
-            .map(Some)
+    };
+    let conflicts = guarded
+        .conflicts
+        .into_iter()
+        .zip(guarded.conflict_owners)
+        .map(|(conflict, op)| RenameConflict {
+            op: owners[op].clone(),
+            conflict,
+        })
+        .collect();
+    let owners: Vec<&RenameOp> = guarded.owners.iter().map(|&op| owners[op]).collect();
+    let writes = guarded.ops;
+    let pb = ProgressBar::new(writes.len() as u64);
+    let style = ProgressStyle::default_bar()
+        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
+    pb.set_style(style);
+    pb.set_message("Overwriting Qdrant payload...");
+    let (outcomes, stalled) = scheduler
+        .run(&writes, |done, total| {
+            pb.set_length(total as u64);
+            pb.set_position(done as u64);
+        })
+        .await;
+    pb.finish_with_message("Done");
+    for outcome in outcomes {
+        let error = match outcome.status {
+            WriteStatus::Failed { error, .. } => error,
+            WriteStatus::NotAttempted => "not attempted, run aborted".to_owned(),
+            WriteStatus::Written | WriteStatus::DryRun => continue,
+        };
+        let op = owners[outcome.op];
+        tracing::error!("Failed to overwrite point {}: {}", op.point_id, error);
+        failed_tasks.push(FailedRenameOp {
+            op: op.clone(),
+            error,
+        });
     }
+    Ok((failed_tasks, conflicts, stalled))
+}
+
+/// Legacy input, trusts that every file in stage6's list was renamed
+fn rename_ops_from_wrong_ext(files: Vec<WrongExtFile>) -> Vec<RenameOp> {
+    files
+        .into_iter()
+        .filter_map(|file| {
+            let src = PathBuf::from(&file.path);
+            let mut dst = PathBuf::new();
+            let point_id = src.file_stem()?.to_str()?;
+            dst.push(point_id);
+            dst.set_extension(&file.expected_ext);
+            Some(RenameOp {
+                point_id: point_id.to_owned(),
+                dst: dst.to_string_lossy().to_string(),
+                src: file.path,
+                target_ext: file.expected_ext,
+            })
+        })
+        .collect()
+}
+
+/// Only files stage7 actually renamed
+fn rename_ops_from_manifest(manifest: Vec<RenamedFile>) -> Vec<RenameOp> {
+    manifest
+        .into_iter()
+        .map(|file| RenameOp {
+            point_id: file.point_id.to_string(),
+            src: file.old_key,
+            dst: file.new_key,
+            target_ext: file.new_ext,
+        })
+        .collect()
 }
 
 #[derive(Parser, Debug)]
 #[command(name = "Stage8", version)]
+#[command(group(ArgGroup::new("input").args(&["wrong_ext_file_list", "from_manifest"]).required(true)))]
 struct Cli {
     #[arg(long)]
-    wrong_ext_file_list: PathBuf,
+    wrong_ext_file_list: Option<PathBuf>,
+    /// stage7 `renamed_manifest.json`
+    #[arg(long)]
+    from_manifest: Option<PathBuf>,
     #[arg(long, default_value = "false")]
     dry_run: bool,
     #[arg(long, default_value = "16")]
     worker_num: usize,
+    /// Retries of a request failing with a transient error
+    #[arg(long, default_value = "3")]
+    max_retries: u32,
+    /// Requests started per second, unlimited by default
+    #[arg(long)]
+    max_requests_per_sec: Option<f64>,
     #[arg(long, default_value = "qdrant_point_rename_errors")]
     save_result_prefix: String,
     #[arg(long, default_value = "http://127.0.0.1:10000/nekoimg/NekoImage")]
     url_prefix: String,
+    #[command(flatten)]
+    stall: StallArgs,
+    /// Prometheus text dump of this run's metrics
+    #[arg(long, default_value = "stage8_metrics.prom")]
+    metrics_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    /// What to do with a point whose `format` or `url` changed since its file was renamed: `merge`
+    /// keeps theirs, `skip` leaves it for manual review, `overwrite` writes ours
+    #[arg(long, default_value = "merge")]
+    on_conflict: OnConflict,
+    /// Payload writes already applied, skipped when re-run with the same payload. Delete it to
+    /// write everything again
+    #[arg(long, default_value = "stage8_applied_ops.jsonl")]
+    ledger: PathBuf,
+    /// Snapshot of the collection taken before the first write, `restore-snapshot` brings it back
+    #[arg(long)]
+    backup_snapshot: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new("info"));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage8.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new("info"));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
+    let _logging = Logging::new("stage8").init()?;
     let cli = Cli::parse();
-    let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
-    let client = Arc::new(Stage8GenshinQdrantClient::new(
-        &collection_name,
-        cli.dry_run,
-        cli.worker_num,
-        &cli.url_prefix,
-    )?);
-    let need_rename_filelist = fs::read(&cli.wrong_ext_file_list)?;
-    let need_rename_filelist: Vec<WrongExtFile> = serde_json::from_slice(&need_rename_filelist)?;
-    let rename_ops = need_rename_filelist
-        .into_iter()
-        .filter_map(|file| {
-            let src = PathBuf::from(&file.path);
-            let mut dst = PathBuf::new();
-            let point_id = src.file_stem()?.to_str()?;
-            dst.push(point_id);
-            dst.set_extension(&file.expected_ext);
-            Some(RenameOp {
-                point_id: point_id.to_owned(),
-                dst: dst.to_string_lossy().to_string(),
-                src: file.path,
-                target_ext: file.expected_ext,
-            })
-        })
-        .collect::<Vec<_>>();
-    let res = client.set_payload_task(&rename_ops).await?;
-    if let Some(failed_tasks) = res {
+    let _lock = cli.lock.acquire("stage8")?;
+    let rename_ops = match (&cli.from_manifest, &cli.wrong_ext_file_list) {
+        (Some(manifest), _) => {
+            let manifest: Vec<RenamedFile> = serde_json::from_slice(&fs::read(manifest)?)?;
+            rename_ops_from_manifest(manifest)
+        }
+        (None, Some(list)) => {
+            let files: Vec<WrongExtFile> = serde_json::from_slice(&fs::read(list)?)?;
+            rename_ops_from_wrong_ext(files)
+        }
+        (None, None) => unreachable!("clap requires one input"),
+    };
+    tracing::info!("Loaded {} rename ops", rename_ops.len());
+    let points: Vec<PointRef> = rename_ops
+        .iter()
+        .filter_map(|op| op.point_id.parse().ok())
+        .collect();
+    IdKindCounts::count(points.iter().copied()).warn_if_mixed("rename ops");
+    let collection = env::var("QDRANT_COLLECTION_NAME")?;
+    let store =
+        QdrantPointStore::new(GenShinQdrantClient::new()?, &collection).with_num_ids(points);
+    if let Some(path) = &cli.backup_snapshot {
+        match cli.dry_run {
+            true => tracing::info!(
+                "Dry run: would snapshot {} to {}",
+                collection,
+                path.display()
+            ),
+            false => {
+                GenShinQdrantClient::new()?
+                    .export_snapshot(&collection, path)
+                    .await?;
+            }
+        }
+    }
+    let ledger = OpLedger::open(&cli.ledger, &collection)?;
+    if ledger.recovered_bytes() > 0 {
+        tracing::warn!(
+            "Dropped a torn last entry of {} bytes from ledger {}",
+            ledger.recovered_bytes(),
+            cli.ledger.display()
+        );
+    }
+    let scheduler = QdrantWriteScheduler::new(
+        store,
+        WriteSchedulerConfig {
+            concurrency: cli.worker_num,
+            max_retries: cli.max_retries,
+            max_requests_per_sec: cli.max_requests_per_sec,
+            dry_run: cli.dry_run,
+            stall: StallConfig::from(&cli.stall),
+            ..Default::default()
+        },
+    )
+    .with_ledger(ledger);
+    let (failed_tasks, conflicts, stalled) =
+        set_payload_task(&scheduler, &rename_ops, &cli.url_prefix, cli.on_conflict).await?;
+    if !conflicts.is_empty() {
+        let filename = format!(
+            "{}_payload_conflicts_{}.json",
+            cli.save_result_prefix,
+            chrono::Local::now().format("%Y%m%d_%H%M%S")
+        );
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &conflicts)?)
+        })?;
+        let count = |action| {
+            conflicts
+                .iter()
+                .filter(|c| c.conflict.action == action)
+                .count()
+        };
+        tracing::warn!(
+            "{} renamed points changed since the rename ({}): {} kept theirs, {} skipped, {} \
+             overwritten, {} gone, report saved to {}",
+            conflicts.len(),
+            cli.on_conflict,
+            count(ConflictAction::Merged),
+            count(ConflictAction::Skipped),
+            count(ConflictAction::Overwritten),
+            count(ConflictAction::Missing),
+            &filename
+        );
+    }
+    if !failed_tasks.is_empty() {
         let filename = format!(
             "{}_{}.json",
             cli.save_result_prefix,
             chrono::Local::now().format("%Y%m%d_%H%M%S")
         );
-        let failed_file = File::create(&filename)?;
-        serde_json::to_writer_pretty(failed_file, &failed_tasks)?;
+        atomic_write_with(&filename, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &failed_tasks)?)
+        })?;
         tracing::error!(
             "Some tasks failed, details saved to {}. Total failed tasks: {}",
             &filename,
             failed_tasks.len()
         );
-    } else {
+    } else if stalled.is_none() {
         tracing::info!("All tasks completed successfully.");
     }
+    shared::metrics::dump_prometheus_to(&cli.metrics_file)?;
+    tracing::info!("Metrics saved to {}", cli.metrics_file.display());
+    if let Some(e) = stalled {
+        tracing::error!("Run aborted, the saved failures are partial");
+        return Err(e.into());
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opendal::Operator;
+    use opendal::services::Memory;
+    use shared::opendal::GenShinOperator;
+    use shared::test_util::MemoryPointStore;
+    use stage7::rename::{SkipReason, Stage7Operator};
+    use stage7::skip::{SkipRule, SkipRules};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    fn rename(point_id: &str, ext: &str) -> RenameOp {
+        RenameOp {
+            point_id: point_id.to_owned(),
+            src: format!("{point_id}.png"),
+            dst: format!("{point_id}.{ext}"),
+            target_ext: ext.to_owned(),
+        }
+    }
+
+    /// Store holding `ops`' points with the payload they had before the rename
+    fn store_before(ops: &[RenameOp], url_prefix: &str) -> MemoryPointStore {
+        MemoryPointStore::with_payloads(ops.iter().filter_map(|op| {
+            let point: PointRef = op.point_id.parse().ok()?;
+            Some((point.key(), snapshot_payload(op, url_prefix)))
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_set_payload_failures() {
+        let (ok, broken) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let ops = vec![
+            rename(&ok.to_string(), "jpg"),
+            rename("not-a-point", "jpg"),
+            rename(&broken.to_string(), "gif"),
+            // numeric point, stored under its key
+            rename("42", "webp"),
+        ];
+        let store = MemoryPointStore {
+            broken: HashSet::from([broken]),
+            ..store_before(&ops, "http://host/img")
+        };
+        let scheduler = QdrantWriteScheduler::new(store, WriteSchedulerConfig::default());
+        let (failed, conflicts, stalled) =
+            set_payload_task(&scheduler, &ops, "http://host/img", OnConflict::Merge)
+                .await
+                .unwrap();
+        assert!(stalled.is_none());
+        assert!(conflicts.is_empty());
+        let failed: Vec<(&str, &str)> = failed
+            .iter()
+            .map(|f| (f.op.point_id.as_str(), f.error.as_str()))
+            .collect();
+        assert_eq!(failed.len(), 2);
+        assert!(failed[0].0 == "not-a-point" && failed[0].1.starts_with("invalid point id"));
+        assert_eq!(failed[1], (broken.to_string().as_str(), "rejected"));
+        let payloads = scheduler.store().payloads();
+        assert_eq!(payloads.len(), 3);
+        assert_eq!(
+            payloads[&broken]["url"],
+            format!("http://host/img/{broken}.png")
+        );
+        assert_eq!(payloads[&ok]["url"], format!("http://host/img/{ok}.jpg"));
+        assert_eq!(payloads[&ok]["format"], "jpg");
+        assert_eq!(
+            payloads[&Uuid::from_u128(42)]["url"],
+            "http://host/img/42.webp"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_payload_conflicts() {
+        let (moved, gone, same) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let ops = vec![
+            rename(&moved.to_string(), "jpg"),
+            rename(&gone.to_string(), "jpg"),
+            rename(&same.to_string(), "gif"),
+        ];
+        let moved_url = format!("http://cdn/{moved}.png");
+        for (policy, action, url) in [
+            (OnConflict::Merge, ConflictAction::Merged, moved_url.clone()),
+            (OnConflict::Skip, ConflictAction::Skipped, moved_url.clone()),
+            (
+                OnConflict::Overwrite,
+                ConflictAction::Overwritten,
+                format!("http://host/img/{moved}.jpg"),
+            ),
+        ] {
+            let store = store_before(&ops, "http://host/img");
+            {
+                let mut points = store.points.lock().unwrap();
+                points.remove(&gone);
+                points.get_mut(&moved).unwrap().payload["url"] = Value::from(moved_url.clone());
+            }
+            let scheduler = QdrantWriteScheduler::new(store, WriteSchedulerConfig::default());
+            let (failed, conflicts, _) =
+                set_payload_task(&scheduler, &ops, "http://host/img", policy)
+                    .await
+                    .unwrap();
+            let payloads = scheduler.store().payloads();
+            assert_eq!(payloads[&moved]["url"], url, "{}", policy);
+            assert_eq!(
+                payloads[&moved]["format"],
+                match policy {
+                    OnConflict::Overwrite => "jpg",
+                    _ => "png",
+                }
+            );
+            assert_eq!(payloads[&same]["format"], "gif");
+            let summary: Vec<(&str, ConflictAction)> = conflicts
+                .iter()
+                .map(|c| (c.op.point_id.as_str(), c.conflict.action))
+                .collect();
+            match policy {
+                // written blindly, Qdrant would reject the write to the gone point
+                OnConflict::Overwrite => {
+                    assert_eq!(summary, vec![(moved.to_string().as_str(), action)]);
+                    assert!(payloads.contains_key(&gone));
+                }
+                _ => {
+                    assert!(failed.is_empty());
+                    assert_eq!(
+                        summary,
+                        vec![
+                            (moved.to_string().as_str(), action),
+                            (gone.to_string().as_str(), ConflictAction::Missing),
+                        ]
+                    );
+                    assert!(!payloads.contains_key(&gone));
+                }
+            }
+            assert_eq!(conflicts[0].conflict.fields[0].field, "url");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_manifest_ops_match_renamed_points() {
+        let op = Operator::new(Memory::default()).unwrap().finish();
+        let renamed = Uuid::from_u128(1);
+        let skipped = Uuid::from_u128(2);
+        let missing = Uuid::from_u128(3);
+        for key in [
+            format!("img/{renamed}.png"),
+            format!("img/{skipped}.jpeg"),
+            "img/not-a-point.png".to_owned(),
+        ] {
+            op.write(&key, vec![0u8; 4]).await.unwrap();
+        }
+        let files = vec![
+            WrongExtFile {
+                path: format!("img/{renamed}.png"),
+                expected_ext: "jpg".to_owned(),
+            },
+            WrongExtFile {
+                path: format!("img/{skipped}.jpeg"),
+                expected_ext: "jpg".to_owned(),
+            },
+            WrongExtFile {
+                path: format!("img/{missing}.webp"),
+                expected_ext: "gif".to_owned(),
+            },
+            WrongExtFile {
+                path: "img/not-a-point.png".to_owned(),
+                expected_ext: "jpg".to_owned(),
+            },
+        ];
+        let stage7 = Arc::new(Stage7Operator::with_operator(
+            GenShinOperator::from(op.clone()),
+            false,
+            4,
+            SkipRules::new([SkipRule::ExactPair("jpeg".into(), "jpg".into())]),
+            HashSet::new(),
+        ));
+        let outcome = stage7
+            .rename_task(files, &StallConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(outcome.skipped.len(), 1);
+        let rule = SkipRule::ExactPair("jpeg".into(), "jpg".into());
+        assert_eq!(outcome.skip_counts[&SkipReason::Rule(rule)], 1);
+        assert_eq!(outcome.failed.len(), 2);
+        assert!(op.exists(&format!("img/{renamed}.jpg")).await.unwrap());
+        assert!(!op.exists(&format!("img/{renamed}.png")).await.unwrap());
+
+        // round trip through the manifest file format
+        let manifest: Vec<RenamedFile> =
+            serde_json::from_slice(&serde_json::to_vec(&outcome.renamed).unwrap()).unwrap();
+        let ops = rename_ops_from_manifest(manifest);
+        let ids: Vec<&str> = ops.iter().map(|op| op.point_id.as_str()).collect();
+        assert_eq!(ids, vec![renamed.to_string()]);
+        assert_eq!(ops[0].src, format!("img/{renamed}.png"));
+        assert_eq!(ops[0].dst, format!("img/{renamed}.jpg"));
+        assert_eq!(ops[0].target_ext, "jpg");
+    }
+}
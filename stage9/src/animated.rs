@@ -0,0 +1,139 @@
+//! Decoding of the animated formats triage handles, GIF, animated WebP and APNG, through the
+//! image crate's animation decoders
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::error::ImageFormatHint;
+use image::{
+    AnimationDecoder, DynamicImage, Frame, Frames, ImageDecoder, ImageError, ImageFormat,
+    ImageResult,
+};
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+/// Extensions of the formats [`open_animation`] decodes, APNG usually comes as `.png`
+pub const ANIMATED_EXTS: [&str; 4] = ["gif", "webp", "png", "apng"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnimatedFormat {
+    Gif,
+    WebP,
+    /// PNG, animated or not
+    Png,
+}
+
+/// An opened file whose frames are decoded one at a time as they are pulled
+pub struct Animation {
+    pub format: AnimatedFormat,
+    /// Canvas every frame is composited onto
+    pub dimensions: (u32, u32),
+    pub frames: Frames<'static>,
+}
+
+/// A still WebP or PNG as an animation of its only frame
+fn still<D: ImageDecoder>(format: AnimatedFormat, decoder: D) -> ImageResult<Animation> {
+    let dimensions = decoder.dimensions();
+    let frame = Frame::new(DynamicImage::from_decoder(decoder)?.into_rgba8());
+    Ok(Animation {
+        format,
+        dimensions,
+        frames: Frames::new(Box::new(std::iter::once(Ok(frame)))),
+    })
+}
+
+/// Opens `path` by its content, the extension of a download may not match it
+pub fn open_animation(path: &str) -> ImageResult<Animation> {
+    let mut head = [0u8; 16];
+    let read = File::open(path)?.read(&mut head)?;
+    let format = image::guess_format(&head[..read])?;
+    let reader = BufReader::new(File::open(path)?);
+    match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(reader)?;
+            Ok(Animation {
+                format: AnimatedFormat::Gif,
+                dimensions: decoder.dimensions(),
+                frames: decoder.into_frames(),
+            })
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return still(AnimatedFormat::WebP, decoder);
+            }
+            Ok(Animation {
+                format: AnimatedFormat::WebP,
+                dimensions: decoder.dimensions(),
+                frames: decoder.into_frames(),
+            })
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(reader)?;
+            if !decoder.is_apng()? {
+                return still(AnimatedFormat::Png, decoder);
+            }
+            let dimensions = decoder.dimensions();
+            Ok(Animation {
+                format: AnimatedFormat::Png,
+                dimensions,
+                frames: decoder.apng()?.into_frames(),
+            })
+        }
+        other => Err(ImageError::Unsupported(
+            ImageFormatHint::Exact(other).into(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use shared::fixtures::{GifMotion, GifSpec, gif_bytes};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_open_animation() {
+        let dir = std::env::temp_dir().join(format!("animated_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gif = dir.join("a.webp");
+        let spec = GifSpec {
+            frames: 4,
+            motion: GifMotion::Moving,
+        };
+        // named after the point's extension, sniffed by content
+        std::fs::write(&gif, gif_bytes(32, 0, spec).unwrap()).unwrap();
+        let animation = open_animation(gif.to_str().unwrap()).unwrap();
+        assert_eq!(
+            (animation.format, animation.dimensions),
+            (AnimatedFormat::Gif, (32, 32))
+        );
+        assert_eq!(animation.frames.collect_frames().unwrap().len(), 4);
+
+        let image = RgbaImage::from_pixel(8, 6, Rgba([1, 2, 3, 255]));
+        for (name, format) in [
+            ("b.webp", AnimatedFormat::WebP),
+            ("c.png", AnimatedFormat::Png),
+        ] {
+            let path = dir.join(name);
+            image.save(&path).unwrap();
+            let animation = open_animation(path.to_str().unwrap()).unwrap();
+            assert_eq!((animation.format, animation.dimensions), (format, (8, 6)));
+            let frames = animation.frames.collect_frames().unwrap();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].buffer(), &image);
+        }
+
+        let jpg = dir.join("d.jpg");
+        DynamicImage::ImageRgba8(image)
+            .to_rgb8()
+            .save(&jpg)
+            .unwrap();
+        assert!(matches!(
+            open_animation(jpg.to_str().unwrap()),
+            Err(ImageError::Unsupported(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+//! Representative frames of the triage candidates, whichever of the formats of
+//! [`crate::animated`] they come in
+
+use crate::animated::open_animation;
+use anyhow::Result;
+use image::error::{ParameterError, ParameterErrorKind};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageBuffer, ImageError, Rgba};
+use image_hasher::{Hasher, HasherConfig, ImageHash};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use shared::metrics;
+use shared::structure::{
+    GifFrames, GifMeta, TriageGif, TriageGifClip, TriageGifGroupsGifStagePair,
+    TriageGifGroupsGifStageRes, TriageGifPair,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error)]
+enum AnimatedWorkerError {
+    #[error("Animation frames are too poor: {0}, expected at least 5 frames")]
+    PoorFrames(usize),
+    #[error(transparent)]
+    InternalImageError(#[from] ImageError),
+    #[error(transparent)]
+    InternalIOError(#[from] std::io::Error),
+    #[error(transparent)]
+    InternalHashError(#[from] anyhow::Error),
+}
+
+impl AnimatedWorkerError {
+    /// `reason` label of `gif_decode_failures_total`
+    fn reason(&self) -> &'static str {
+        match self {
+            AnimatedWorkerError::PoorFrames(_) => "poor_frames",
+            AnimatedWorkerError::InternalImageError(_) => "image",
+            AnimatedWorkerError::InternalIOError(_) => "io",
+            AnimatedWorkerError::InternalHashError(_) => "hash",
+        }
+    }
+}
+
+/// Decodes GIF, animated WebP and APNG alike, a still WebP or PNG counts as a single frame
+pub struct AnimatedMediaWorker {
+    hasher: Hasher,
+    extract_hw: u32,
+    /// Original canvas of the GIFs stored downscaled
+    downscaled: HashMap<Uuid, (u32, u32)>,
+}
+
+impl AnimatedMediaWorker {
+    pub fn new(extract_hw: u32) -> Self {
+        let hasher = HasherConfig::new()
+            .hash_alg(image_hasher::HashAlg::Gradient)
+            .resize_filter(FilterType::Lanczos3)
+            .hash_size(32, 32)
+            .to_hasher();
+        Self {
+            extract_hw,
+            hasher,
+            downscaled: HashMap::new(),
+        }
+    }
+
+    /// Records GIFs stored downscaled, their metadata then carries the original canvas
+    pub fn note_downscaled<I: IntoIterator<Item = (Uuid, (u32, u32))>>(&mut self, gifs: I) {
+        self.downscaled.extend(gifs);
+    }
+
+    pub fn process<'a>(
+        &self,
+        gifs: &'a [Option<TriageGifPair>],
+    ) -> Result<TriageGifGroupsGifStageRes<'a>> {
+        let pb = ProgressBar::new(gifs.len() as u64);
+        let style = ProgressStyle::default_bar()
+            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?;
+        pb.set_style(style);
+        pb.set_message("Extracting GIF frames...");
+        // `par_bridge` pulls groups in sequence, so a prioritized request is handled front first
+        let mut results: Vec<(usize, Option<TriageGifGroupsGifStagePair<'a>>)> = gifs
+            .iter()
+            .enumerate()
+            .par_bridge()
+            .map(|(idx, gif_pair)| {
+                pb.inc(1);
+                // Encapsulate the internal errors of GIFs within process_pair
+                (idx, gif_pair.as_ref().map(|p| self.process_pair(p)))
+            })
+            .collect();
+        results.sort_unstable_by_key(|&(idx, _)| idx);
+        pb.finish_with_message("All GIFs processed");
+        Ok(results.into_iter().map(|(_, res)| res).collect())
+    }
+
+    /// Determining whether all frames of a GIF image are identical
+    fn judge_gif_frame(&self, path: &str) -> Result<bool, AnimatedWorkerError> {
+        let animation = open_animation(path)?;
+        tracing::debug!("Judging {:?} frames: {}", animation.format, path);
+        let (width, height) = animation.dimensions;
+        let frames = animation.frames.collect_frames()?;
+        if frames.len() <= 1 {
+            return Ok(true);
+        }
+        let hashes: Vec<ImageHash> = frames
+            .into_iter()
+            .map(|frame| -> Result<ImageHash, AnimatedWorkerError> {
+                let raw: Vec<u8> = frame.buffer().to_vec();
+                let img_buf: ImageBuffer<Rgba<u8>, Vec<u8>> =
+                    ImageBuffer::from_raw(width, height, raw).ok_or_else(|| {
+                        ImageError::Parameter(ParameterError::from_kind(
+                            ParameterErrorKind::DimensionMismatch,
+                        ))
+                    })?;
+                let dyn_img = DynamicImage::ImageRgba8(img_buf);
+                let hash = self.hasher.hash_image(&dyn_img);
+                Ok(hash)
+            })
+            .collect::<Result<Vec<_>, AnimatedWorkerError>>()?;
+        match hashes.split_first() {
+            None => panic!("Cannot happen at all!"),
+            Some((first_hash, rest_hashes)) => {
+                let is_all_same = rest_hashes.iter().enumerate().all(|(i, h)| {
+                    let original_idx = i + 1;
+                    let score = first_hash.dist(h);
+                    tracing::debug!(
+                        "Comparing image {}'s idx=0 vs idx={}, score = {}",
+                        path,
+                        original_idx,
+                        score
+                    );
+                    score < 5
+                });
+                if is_all_same {
+                    tracing::debug!("All frames in GIF {} are identical", path);
+                }
+                Ok(is_all_same)
+            }
+        }
+    }
+
+    fn process_pair<'a>(&self, gifs: &'a TriageGifPair<'a>) -> TriageGifGroupsGifStagePair<'a> {
+        type InvalidGifIdT<'a> = Option<Vec<(&'a Uuid, &'a str, usize, String)>>;
+        /// id, path, size, frame_len
+        type DiscardFrameGifT<'a> = Option<Vec<(&'a Uuid, &'a str, usize, Option<usize>)>>;
+        type PrepareClipGifT<'a> = Option<Vec<(&'a Uuid, &'a str, usize, GifFrames, GifMeta)>>;
+
+        let mut invalid_gif_id: InvalidGifIdT<'a> = None;
+        let mut discard_same_frame_gif_id: DiscardFrameGifT<'a> = None;
+        let mut prepare_clip_gif_id: PrepareClipGifT<'a> = None;
+
+        let try_add_invalid = |opt: &mut InvalidGifIdT<'a>,
+                               id: &'a Uuid,
+                               path: &'a str,
+                               size: usize,
+                               reason: &str| {
+            match opt {
+                Some(vec) => vec.push((id, path, size, reason.to_string())),
+                None => *opt = Some(vec![(id, path, size, reason.to_string())]),
+            }
+        };
+        let try_add_prepare_clip = |opt: &mut PrepareClipGifT<'a>,
+                                    id: &'a Uuid,
+                                    path: &'a str,
+                                    size: usize,
+                                    frame: Vec<Vec<u8>>,
+                                    meta: GifMeta| {
+            match opt {
+                Some(vec) => vec.push((id, path, size, frame, meta)),
+                None => *opt = Some(vec![(id, path, size, frame, meta)]),
+            }
+        };
+
+        // preprocess
+        let gifs = gifs
+            .iter()
+            .filter(|gif| {
+                let res = self.judge_gif_frame(gif.path).unwrap_or(false);
+                if res {
+                    match discard_same_frame_gif_id {
+                        Some(ref mut vec) => vec.push((gif.uuid, gif.path, gif.size, None)),
+                        None => {
+                            discard_same_frame_gif_id =
+                                Some(vec![(gif.uuid, gif.path, gif.size, None)])
+                        }
+                    }
+                }
+                !res
+            })
+            .collect::<Vec<_>>();
+
+        for &TriageGif {
+            uuid: id,
+            path,
+            size,
+        } in gifs
+        {
+            match self.process_single(path, true) {
+                Ok((frames, meta)) => {
+                    let meta = GifMeta {
+                        downscaled_from: self.downscaled.get(id).copied(),
+                        ..meta
+                    };
+                    try_add_prepare_clip(&mut prepare_clip_gif_id, id, path, size, frames, meta)
+                }
+                Err(
+                    e @ AnimatedWorkerError::InternalImageError(_)
+                    | e @ AnimatedWorkerError::InternalIOError(_),
+                ) => {
+                    tracing::error!("Error processing GIF {}: {}", id, e);
+                    metrics::counter("gif_decode_failures_total", &[("reason", e.reason())]).inc();
+                    try_add_invalid(&mut invalid_gif_id, id, path, size, &e.to_string());
+                }
+                _ => {} // cannot exist
+            }
+        }
+
+        let invalid_group = invalid_gif_id.map(|entries| {
+            let (ids, reasons): (Vec<&Uuid>, Vec<String>) = entries
+                .into_iter()
+                .map(|(id, _, _, reason)| (id, reason))
+                .unzip();
+            (ids, reasons)
+        });
+
+        let discard_same_frame_group = discard_same_frame_gif_id
+            .map(|entries| entries.into_iter().map(|(id, _, _, _)| id).collect());
+
+        let gif_metadata = prepare_clip_gif_id.as_ref().map(|entries| {
+            entries
+                .iter()
+                .map(|&(id, _, _, _, meta)| (id, meta))
+                .collect::<HashMap<_, _>>()
+        });
+
+        let prepare_group = prepare_clip_gif_id.map(|entries| {
+            entries
+                .into_iter()
+                .map(|(id, path, size, frame, _)| TriageGifClip {
+                    id,
+                    path,
+                    size,
+                    frame,
+                })
+                .collect()
+        });
+
+        TriageGifGroupsGifStagePair {
+            invalid_gif_id: invalid_group,
+            discard_same_frame_gif_id: discard_same_frame_group,
+            prepare_clip_gif_pair: prepare_group,
+            gif_metadata,
+        }
+    }
+
+    fn process_single(
+        &self,
+        gif_path: &str,
+        allow_poor_frame: bool,
+    ) -> Result<(GifFrames, GifMeta), AnimatedWorkerError> {
+        let animation =
+            open_animation(gif_path).map_err(AnimatedWorkerError::InternalImageError)?;
+        let (w, h) = animation.dimensions;
+        let frames = animation
+            .frames
+            .collect_frames()
+            .map_err(AnimatedWorkerError::InternalImageError)?;
+        let total = frames.len();
+        let duration: Duration = frames
+            .iter()
+            .map(|frame| Duration::from(frame.delay()))
+            .sum();
+        let meta = GifMeta {
+            frame_count: total,
+            duration_ms: duration.as_millis() as u64,
+            width: w,
+            height: h,
+            downscaled_from: None,
+        };
+        // TODO: d63f2ed8-a3ed-54ba-8624-34d1a049735b vs 42fdd210-3755-5613-a922-5a8d10622024 (?)
+        let selected_idxs = match total {
+            n if n < 5 && !allow_poor_frame => Err(AnimatedWorkerError::PoorFrames(n)),
+            n if n < 5 && allow_poor_frame => Ok((0..n).collect::<Vec<_>>()),
+            _ => Ok(Vec::from([
+                0,
+                total / 4,
+                total / 2,
+                total * 3 / 4,
+                total - 1,
+            ])),
+        }?;
+        let picked = frames
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, frame)| {
+                if selected_idxs.contains(&i) {
+                    Some(frame)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let frames_bytes = picked
+            .iter()
+            .map(|frame| {
+                let buf: Vec<u8> = frame.buffer().to_vec();
+                let img: ImageBuffer<Rgba<u8>, _> =
+                    ImageBuffer::from_raw(w, h, buf).ok_or_else(|| {
+                        ImageError::Parameter(ParameterError::from_kind(
+                            ParameterErrorKind::DimensionMismatch,
+                        ))
+                    })?;
+                let img = DynamicImage::ImageRgba8(img);
+                Ok::<Vec<u8>, ImageError>(
+                    img.resize_to_fill(self.extract_hw, self.extract_hw, FilterType::Triangle)
+                        .to_rgb8()
+                        .into_raw(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((frames_bytes, meta))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::fixtures::{GifMotion, GifSpec, gif_bytes};
+    use std::path::PathBuf;
+
+    const GIFS: [&str; 4] = [
+        "../assets/test_images/mcat_0.gif",
+        "../assets/test_images/mcat_1.gif",
+        "../assets/test_images/bq_0.gif",
+        "../assets/test_images/bq_1.gif",
+    ];
+
+    #[test]
+    fn test_process_single_meta() {
+        let worker = AnimatedMediaWorker::new(32);
+        for path in GIFS {
+            let (frames, meta) = worker.process_single(path, true).unwrap();
+            assert!(meta.frame_count > 1, "{}: {:?}", path, meta);
+            assert!(meta.duration_ms > 0, "{}: {:?}", path, meta);
+            assert!(meta.width > 0 && meta.height > 0, "{}: {:?}", path, meta);
+            assert_eq!(frames.len(), meta.frame_count.min(5));
+        }
+    }
+
+    #[test]
+    fn test_process_pair_gif_metadata() {
+        let worker = AnimatedMediaWorker::new(32);
+        let uuids: Vec<Uuid> = (1..=GIFS.len() as u128).map(Uuid::from_u128).collect();
+        let pair: TriageGifPair = uuids
+            .iter()
+            .zip(GIFS)
+            .map(|(uuid, path)| TriageGif {
+                uuid,
+                path,
+                size: 0,
+            })
+            .collect();
+        let res = worker.process_pair(&pair);
+        let clips = res.prepare_clip_gif_pair.unwrap();
+        let metadata = res.gif_metadata.unwrap();
+        assert_eq!(metadata.len(), clips.len());
+        for clip in clips {
+            assert!(metadata[clip.id].frame_count > 1);
+        }
+    }
+
+    /// Writes a generated GIF into a fresh temp dir
+    fn fixture_gif(frames: usize, motion: GifMotion) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("animated_worker_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fixture.gif");
+        std::fs::write(&path, gif_bytes(64, 0, GifSpec { frames, motion }).unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_judge_gif_frame_edge_cases() {
+        let worker = AnimatedMediaWorker::new(32);
+        let still = fixture_gif(6, GifMotion::Still);
+        let moving = fixture_gif(6, GifMotion::Moving);
+        let single = fixture_gif(1, GifMotion::Moving);
+        assert!(worker.judge_gif_frame(still.to_str().unwrap()).unwrap());
+        assert!(!worker.judge_gif_frame(moving.to_str().unwrap()).unwrap());
+        // a single frame counts as identical frames
+        assert!(worker.judge_gif_frame(single.to_str().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_process_single_poor_frames() {
+        let worker = AnimatedMediaWorker::new(32);
+        let poor = fixture_gif(3, GifMotion::Moving);
+        let poor = poor.to_str().unwrap();
+        assert!(matches!(
+            worker.process_single(poor, false),
+            Err(AnimatedWorkerError::PoorFrames(3))
+        ));
+        let (frames, meta) = worker.process_single(poor, true).unwrap();
+        assert_eq!((frames.len(), meta.frame_count), (3, 3));
+        assert_eq!(meta.duration_ms, 300);
+        let long = fixture_gif(9, GifMotion::Moving);
+        let (frames, meta) = worker
+            .process_single(long.to_str().unwrap(), false)
+            .unwrap();
+        assert_eq!((frames.len(), meta.frame_count), (5, 9));
+        assert!(frames.iter().all(|f| f.len() == 32 * 32 * 3));
+    }
+
+    #[test]
+    fn test_process_pair_discards_still() {
+        let worker = AnimatedMediaWorker::new(32);
+        let paths = [
+            fixture_gif(6, GifMotion::Still),
+            fixture_gif(6, GifMotion::Moving),
+            fixture_gif(2, GifMotion::Moving),
+        ];
+        let paths: Vec<&str> = paths.iter().map(|p| p.to_str().unwrap()).collect();
+        let uuids: Vec<Uuid> = (1..=3).map(Uuid::from_u128).collect();
+        let pair: TriageGifPair = uuids
+            .iter()
+            .zip(paths.iter().copied())
+            .map(|(uuid, path)| TriageGif {
+                uuid,
+                path,
+                size: 0,
+            })
+            .collect();
+        let res = worker.process_pair(&pair);
+        assert_eq!(res.discard_same_frame_gif_id, Some(vec![&uuids[0]]));
+        assert!(res.invalid_gif_id.is_none());
+        // poor GIFs still go to CLIP with every frame they have
+        let clips = res.prepare_clip_gif_pair.unwrap();
+        let ids: Vec<&Uuid> = clips.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![&uuids[1], &uuids[2]]);
+        assert_eq!(clips[1].frame.len(), 2);
+    }
+
+    #[test]
+    fn test_process_pair_webp() {
+        let worker = AnimatedMediaWorker::new(32);
+        let dir = std::env::temp_dir().join(format!("animated_worker_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let still = dir.join("still.webp");
+        image::RgbaImage::from_pixel(16, 16, Rgba([9, 9, 9, 255]))
+            .save(&still)
+            .unwrap();
+        let broken = dir.join("broken.webp");
+        std::fs::write(&broken, b"RIFF\0\0\0\0WEBPVP8 ").unwrap();
+        let uuids: Vec<Uuid> = (1..=2).map(Uuid::from_u128).collect();
+        let paths = [still.to_str().unwrap(), broken.to_str().unwrap()];
+        let pair: TriageGifPair = uuids
+            .iter()
+            .zip(paths)
+            .map(|(uuid, path)| TriageGif {
+                uuid,
+                path,
+                size: 0,
+            })
+            .collect();
+        let res = worker.process_pair(&pair);
+        // a still WebP is a single frame, like a single-frame GIF
+        assert_eq!(res.discard_same_frame_gif_id, Some(vec![&uuids[0]]));
+        assert_eq!(res.invalid_gif_id.unwrap().0, vec![&uuids[1]]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+mod sources;
+
+use crate::sources::{Comparison, Placement, Probe, Sources};
+use candle_core::DType;
+use candle_transformers::models::clip::ClipConfig;
+use clap::Parser;
+use shared::cluster_file::{LegacyFormat, read_clusters};
+use shared::cosine_sim::cosine_sim;
+use shared::migrations::load_neko_points;
+use shared::point_explorer::PointExplorerBuilder;
+use shared::structure::FinalClassification;
+use stage9::clip_worker::ClipWorker;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "compare",
+    version,
+    about = "Explain why the pipeline did or did not merge two points, from whatever artifacts are at hand"
+)]
+struct Cli {
+    a: Uuid,
+    b: Uuid,
+    /// f32 image vector explorer
+    #[arg(long)]
+    image_explorer: Option<String>,
+    #[arg(long, default_value = "768")]
+    image_dim: usize,
+    /// Versioned bincode points map, holds the text vectors
+    #[arg(long)]
+    points_map: Option<PathBuf>,
+    /// u8 perceptual hash explorer
+    #[arg(long)]
+    hash_explorer: Option<String>,
+    #[arg(long, default_value = "32")]
+    hash_dim: usize,
+    /// Pickled global clusters
+    #[arg(long)]
+    clusters: Option<PathBuf>,
+    /// stage9 final_classification.json
+    #[arg(long)]
+    final_classification: Option<PathBuf>,
+    /// Embed both points afresh from --image-dir with --clip-model
+    #[arg(long)]
+    with_clip: bool,
+    #[arg(long)]
+    clip_model: Option<PathBuf>,
+    /// Directory of files named after their point id, as stage9 downloads them
+    #[arg(long, default_value = "nekoimg_stage9_gifs")]
+    image_dir: PathBuf,
+    #[arg(long)]
+    use_gpu: bool,
+    /// Also write the comparison as JSON
+    #[arg(long)]
+    json: Option<PathBuf>,
+}
+
+/// Loads `path` with `load`, a missing flag or a failed load makes the source unavailable
+fn load<P, T, F>(path: Option<P>, flag: &str, load: F) -> Probe<T>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&Path) -> anyhow::Result<T>,
+{
+    let Some(path) = path else {
+        return Probe::Unavailable(format!("no --{}", flag));
+    };
+    let path = path.as_ref();
+    load(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+        .into()
+}
+
+fn local_file(dir: &Path, id: &Uuid) -> Result<String, String> {
+    let stem = id.to_string();
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| path.file_stem().is_some_and(|s| s == stem.as_str()))
+        .and_then(|path| path.to_str().map(str::to_string))
+        .ok_or_else(|| format!("no file of {} in {}", id, dir.display()))
+}
+
+fn clip_cosine(cli: &Cli) -> Result<f32, String> {
+    if !cli.with_clip {
+        return Err("no --with-clip".to_string());
+    }
+    let model = cli.clip_model.as_ref().ok_or("no --clip-model")?;
+    let model = model.to_str().ok_or("--clip-model is not valid UTF-8")?;
+    let files = [
+        local_file(&cli.image_dir, &cli.a)?,
+        local_file(&cli.image_dir, &cli.b)?,
+    ];
+    let tensor_type = match cli.use_gpu {
+        true => DType::BF16,
+        false => DType::F32,
+    };
+    let embed = || -> anyhow::Result<Vec<Vec<f32>>> {
+        let worker = ClipWorker::new(
+            model,
+            ClipConfig::baai_bge_vl_large(),
+            tensor_type,
+            cli.use_gpu,
+        )?;
+        let paths: Vec<&str> = files.iter().map(String::as_str).collect();
+        let embeddings = worker.get_images_embedding_batched(&paths)?;
+        Ok(embeddings.to_dtype(DType::F32)?.to_vec2()?)
+    };
+    let embeddings = embed().map_err(|e| e.to_string())?;
+    Ok(cosine_sim(&embeddings[0], &embeddings[1]))
+}
+
+fn print_placements(id: &Uuid, placements: &Probe<Vec<Placement>>) {
+    match placements {
+        Probe::Available(p) if p.is_empty() => println!("  {}: in no group", id),
+        Probe::Available(p) => {
+            for placement in p {
+                println!("  {}: {}", id, placement);
+            }
+        }
+        Probe::Unavailable(reason) => println!("  {}: unavailable ({})", id, reason),
+    }
+}
+
+fn print(cmp: &Comparison) {
+    let row = |name: &str, value: &dyn Display| println!("{:<22}{}", name, value);
+    println!("{} vs {}", cmp.a, cmp.b);
+    row("image cosine", &cmp.image_cosine);
+    row("text cosine", &cmp.text_cosine);
+    row("hash hamming", &cmp.hash_hamming);
+    row("fresh CLIP cosine", &cmp.clip_cosine);
+    match &cmp.clusters {
+        Probe::Available(clusters) => {
+            let shared = clusters.shared();
+            match shared.is_empty() {
+                true => row(
+                    "shared cluster",
+                    &format!("no (a in {:?}, b in {:?})", clusters.a, clusters.b),
+                ),
+                false => row("shared cluster", &format!("yes {:?}", shared)),
+            }
+        }
+        Probe::Unavailable(reason) => row("shared cluster", &format!("unavailable ({})", reason)),
+    }
+    println!("final classification");
+    print_placements(&cmp.a, &cmp.placements_a);
+    print_placements(&cmp.b, &cmp.placements_b);
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let sources = Sources {
+        images: load(cli.image_explorer.as_ref(), "image-explorer", |path| {
+            let path = path.to_str().unwrap_or_default();
+            Ok(PointExplorerBuilder::new()
+                .path(path)
+                .build_dyn(cli.image_dim)?)
+        }),
+        points: load(cli.points_map.as_ref(), "points-map", |path| {
+            Ok(load_neko_points(path)?)
+        }),
+        hashes: load(cli.hash_explorer.as_ref(), "hash-explorer", |path| {
+            let path = path.to_str().unwrap_or_default();
+            Ok(PointExplorerBuilder::new()
+                .path(path)
+                .build_dyn(cli.hash_dim)?)
+        }),
+        clusters: load(cli.clusters.as_ref(), "clusters", |path| {
+            Ok(read_clusters(path, LegacyFormat::Pickle)?)
+        }),
+        classifications: load(
+            cli.final_classification.as_ref(),
+            "final-classification",
+            |path| {
+                let fcs: Vec<FinalClassification> = serde_json::from_slice(&fs::read(path)?)?;
+                Ok(fcs)
+            },
+        ),
+    };
+    let cmp = sources.compare(cli.a, cli.b, clip_cosine(&cli).into());
+    print(&cmp);
+    if let Some(path) = &cli.json {
+        shared::atomic_write::atomic_write_with(path, |w| {
+            Ok::<_, anyhow::Error>(serde_json::to_writer_pretty(w, &cmp)?)
+        })?;
+        println!("\nSaved to {}", path.display());
+    }
+    Ok(())
+}
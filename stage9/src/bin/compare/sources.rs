@@ -0,0 +1,378 @@
+use serde::Serialize;
+use shared::cosine_sim::cosine_sim;
+use shared::hamming::hamming;
+use shared::point_explorer::DynPointExplorer;
+use shared::structure::{FinalClassification, NekoPoint};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use uuid::Uuid;
+
+/// A value, or why it could not be had
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Probe<T> {
+    Available(T),
+    Unavailable(String),
+}
+
+impl<T> Probe<T> {
+    pub fn get(&self) -> Result<&T, String> {
+        match self {
+            Probe::Available(v) => Ok(v),
+            Probe::Unavailable(reason) => Err(reason.clone()),
+        }
+    }
+}
+
+impl<T> From<Result<T, String>> for Probe<T> {
+    fn from(res: Result<T, String>) -> Self {
+        match res {
+            Ok(v) => Probe::Available(v),
+            Err(reason) => Probe::Unavailable(reason),
+        }
+    }
+}
+
+impl<T: Display> Display for Probe<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::Available(v) => write!(f, "{}", v),
+            Probe::Unavailable(reason) => write!(f, "unavailable ({})", reason),
+        }
+    }
+}
+
+/// Artifacts a comparison reads, each one optional
+pub struct Sources {
+    /// f32 image vectors
+    pub images: Probe<DynPointExplorer<f32>>,
+    /// Text vectors
+    pub points: Probe<HashMap<Uuid, NekoPoint>>,
+    /// u8 perceptual hashes
+    pub hashes: Probe<DynPointExplorer<u8>>,
+    pub clusters: Probe<Vec<HashSet<Uuid>>>,
+    pub classifications: Probe<Vec<FinalClassification>>,
+}
+
+/// Global clusters holding each point
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Clusters {
+    pub a: Vec<usize>,
+    pub b: Vec<usize>,
+}
+
+impl Clusters {
+    pub fn shared(&self) -> Vec<usize> {
+        self.a
+            .iter()
+            .filter(|idx| self.b.contains(idx))
+            .copied()
+            .collect()
+    }
+}
+
+/// Group of a [`FinalClassification`] entry a point landed in
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Placement {
+    /// Index into `final_classification.json`
+    pub entry: usize,
+    pub cluster_index: Option<usize>,
+    pub group: &'static str,
+}
+
+impl Display for Placement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of entry {}", self.group, self.entry)?;
+        if let Some(idx) = self.cluster_index {
+            write!(f, " (cluster {})", idx)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub a: Uuid,
+    pub b: Uuid,
+    pub image_cosine: Probe<f32>,
+    pub text_cosine: Probe<f32>,
+    /// Differing bits of the perceptual hashes
+    pub hash_hamming: Probe<u32>,
+    pub clusters: Probe<Clusters>,
+    pub placements_a: Probe<Vec<Placement>>,
+    pub placements_b: Probe<Vec<Placement>>,
+    /// Freshly embedded from the local files
+    pub clip_cosine: Probe<f32>,
+}
+
+fn vectors<'s, T>(
+    explorer: &'s Probe<DynPointExplorer<T>>,
+    a: &Uuid,
+    b: &Uuid,
+) -> Result<(&'s [T], &'s [T]), String>
+where
+    T: Copy + std::fmt::Debug + Default + Serialize + serde::de::DeserializeOwned,
+{
+    let explorer = explorer.get()?;
+    let get = |id: &Uuid| {
+        explorer
+            .get_vector(id)
+            .ok_or_else(|| format!("{} not in the explorer", id))
+    };
+    Ok((get(a)?, get(b)?))
+}
+
+fn text_vector<'s>(points: &'s HashMap<Uuid, NekoPoint>, id: &Uuid) -> Result<&'s [f32], String> {
+    let point = points
+        .get(id)
+        .ok_or_else(|| format!("{} not in the points map", id))?;
+    let text = point
+        .text_info
+        .as_ref()
+        .ok_or_else(|| format!("{} has no text", id))?;
+    Ok(&text.text_vector)
+}
+
+/// Groups of `fc` holding `id`
+pub fn groups_of(fc: &FinalClassification, id: &Uuid) -> Vec<&'static str> {
+    let groups: [(&'static str, Option<&Vec<Uuid>>); 7] = [
+        (
+            "kept_text_anomalies_group",
+            fc.kept_text_anomalies_group.as_ref(),
+        ),
+        (
+            "triaged_gif_and_invalid_group",
+            fc.triaged_gif_and_invalid_group
+                .as_ref()
+                .map(|(ids, _)| ids),
+        ),
+        (
+            "triaged_gif_and_discard_same_frame_group",
+            fc.triaged_gif_and_discard_same_frame_group.as_ref(),
+        ),
+        (
+            "triaged_gif_and_then_will_keep_group",
+            fc.triaged_gif_and_then_will_keep_group.as_ref(),
+        ),
+        (
+            "triaged_gif_and_then_will_delete_group",
+            fc.triaged_gif_and_then_will_delete_group.as_ref(),
+        ),
+        (
+            "other_need_delete_group",
+            fc.other_need_delete_group.as_ref(),
+        ),
+        ("reviewed_keep_group", fc.reviewed_keep_group.as_ref()),
+    ];
+    let mut found: Vec<&'static str> = groups
+        .into_iter()
+        .filter(|(_, ids)| ids.is_some_and(|ids| ids.contains(id)))
+        .map(|(name, _)| name)
+        .collect();
+    if fc.kept_non_gif.as_ref() == Some(id) {
+        found.push("kept_non_gif");
+    }
+    found
+}
+
+fn placements(classifications: &[FinalClassification], id: &Uuid) -> Vec<Placement> {
+    classifications
+        .iter()
+        .enumerate()
+        .flat_map(|(entry, fc)| {
+            groups_of(fc, id).into_iter().map(move |group| Placement {
+                entry,
+                cluster_index: fc.cluster_index,
+                group,
+            })
+        })
+        .collect()
+}
+
+impl Sources {
+    /// Everything the sources at hand tell about `a` and `b`
+    pub fn compare(&self, a: Uuid, b: Uuid, clip_cosine: Probe<f32>) -> Comparison {
+        let image_cosine = vectors(&self.images, &a, &b).map(|(va, vb)| cosine_sim(va, vb));
+        let text_cosine = self.points.get().and_then(|points| {
+            let (va, vb) = (text_vector(points, &a)?, text_vector(points, &b)?);
+            match va.len() == vb.len() {
+                true => Ok(cosine_sim(va, vb)),
+                false => Err(format!(
+                    "text vectors of {} and {} dims",
+                    va.len(),
+                    vb.len()
+                )),
+            }
+        });
+        let hash_hamming = vectors(&self.hashes, &a, &b).map(|(va, vb)| hamming(va, vb));
+        let clusters = self.clusters.get().map(|clusters| {
+            let holding = |id: &Uuid| -> Vec<usize> {
+                clusters
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.contains(id))
+                    .map(|(idx, _)| idx)
+                    .collect()
+            };
+            Clusters {
+                a: holding(&a),
+                b: holding(&b),
+            }
+        });
+        let placements_a = self.classifications.get().map(|fcs| placements(fcs, &a));
+        let placements_b = self.classifications.get().map(|fcs| placements(fcs, &b));
+        Comparison {
+            a,
+            b,
+            image_cosine: image_cosine.into(),
+            text_cosine: text_cosine.into(),
+            hash_hamming: hash_hamming.into(),
+            clusters: clusters.into(),
+            placements_a: placements_a.into(),
+            placements_b: placements_b.into(),
+            clip_cosine,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::structure::NekoPointText;
+    use shared::test_util::{self, id};
+
+    fn missing(reason: &str) -> Probe<f32> {
+        Probe::Unavailable(reason.to_string())
+    }
+
+    fn unavailable<T>() -> Probe<T> {
+        Probe::Unavailable("not given".to_string())
+    }
+
+    fn point(n: u128, text_vector: Option<Vec<f32>>) -> NekoPoint {
+        NekoPoint {
+            id: id(n),
+            height: 1,
+            width: 1,
+            size: None,
+            categories: None,
+            text_info: text_vector.map(|text_vector| NekoPointText {
+                text: String::new(),
+                text_vector,
+            }),
+            qdrant_num_id: None,
+            extra: None,
+        }
+    }
+
+    fn classification(kept: u128, deleted: &[u128], cluster_index: usize) -> FinalClassification {
+        FinalClassification {
+            cluster_index: Some(cluster_index),
+            ..test_util::classification(kept, deleted)
+        }
+    }
+
+    fn full() -> Sources {
+        let mut images = DynPointExplorer::new(2);
+        images.insert(id(1), [1.0f32, 0.0]).unwrap();
+        images.insert(id(2), [1.0f32, 1.0]).unwrap();
+        let mut hashes = DynPointExplorer::new(2);
+        hashes.insert(id(1), [0b1111u8, 0]).unwrap();
+        hashes.insert(id(2), [0b0011u8, 1]).unwrap();
+        let points = HashMap::from([
+            (id(1), point(1, Some(vec![0.0, 1.0]))),
+            (id(2), point(2, Some(vec![0.0, 2.0]))),
+        ]);
+        Sources {
+            images: Probe::Available(images),
+            points: Probe::Available(points),
+            hashes: Probe::Available(hashes),
+            clusters: Probe::Available(vec![
+                HashSet::from([id(3), id(4)]),
+                HashSet::from([id(1), id(2)]),
+            ]),
+            classifications: Probe::Available(vec![classification(2, &[1], 1)]),
+        }
+    }
+
+    #[test]
+    fn test_compare_all_sources() {
+        let cmp = full().compare(id(1), id(2), missing("--with-clip not given"));
+        let image = cmp.image_cosine.get().unwrap();
+        assert!((image - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert!((cmp.text_cosine.get().unwrap() - 1.0).abs() < 1e-6);
+        assert_eq!(cmp.hash_hamming, Probe::Available(3));
+        let clusters = cmp.clusters.get().unwrap();
+        assert_eq!(clusters.shared(), vec![1]);
+        assert_eq!(
+            cmp.placements_a,
+            Probe::Available(vec![Placement {
+                entry: 0,
+                cluster_index: Some(1),
+                group: "other_need_delete_group",
+            }])
+        );
+        assert_eq!(cmp.placements_b.get().unwrap()[0].group, "kept_non_gif");
+        assert_eq!(
+            cmp.clip_cosine.to_string(),
+            "unavailable (--with-clip not given)"
+        );
+    }
+
+    #[test]
+    fn test_compare_partial_sources() {
+        let sources = Sources {
+            images: unavailable(),
+            points: Probe::Available(HashMap::from([
+                (id(1), point(1, Some(vec![1.0, 0.0]))),
+                (id(2), point(2, None)),
+            ])),
+            hashes: unavailable(),
+            clusters: Probe::Available(vec![HashSet::from([id(1)]), HashSet::from([id(2)])]),
+            classifications: unavailable(),
+        };
+        let cmp = sources.compare(id(1), id(2), Probe::Available(0.5));
+        assert_eq!(cmp.image_cosine, missing("not given"));
+        assert_eq!(
+            cmp.text_cosine,
+            missing("00000000-0000-0000-0000-000000000002 has no text")
+        );
+        assert_eq!(cmp.hash_hamming, unavailable());
+        assert!(cmp.clusters.get().unwrap().shared().is_empty());
+        assert_eq!(cmp.placements_a, unavailable());
+        assert_eq!(cmp.placements_b, unavailable());
+        assert_eq!(cmp.clip_cosine, Probe::Available(0.5));
+    }
+
+    #[test]
+    fn test_compare_unknown_point() {
+        let cmp = full().compare(id(1), id(9), missing("no local file"));
+        let not_in = |what: &str| missing(&format!("{} not in {}", id(9), what));
+        assert_eq!(cmp.image_cosine, not_in("the explorer"));
+        assert_eq!(cmp.text_cosine, not_in("the points map"));
+        assert_eq!(
+            cmp.hash_hamming,
+            Probe::Unavailable(format!("{} not in the explorer", id(9)))
+        );
+        assert_eq!(
+            cmp.clusters,
+            Probe::Available(Clusters {
+                a: vec![1],
+                b: vec![]
+            })
+        );
+        assert_eq!(cmp.placements_b, Probe::Available(vec![]));
+    }
+
+    #[test]
+    fn test_groups_of() {
+        let mut fc = classification(1, &[2, 3], 0);
+        fc.reviewed_keep_group = Some(vec![id(2)]);
+        assert_eq!(groups_of(&fc, &id(1)), vec!["kept_non_gif"]);
+        assert_eq!(
+            groups_of(&fc, &id(2)),
+            vec!["other_need_delete_group", "reviewed_keep_group"]
+        );
+        assert!(groups_of(&fc, &id(4)).is_empty());
+    }
+}
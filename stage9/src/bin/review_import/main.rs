@@ -0,0 +1,43 @@
+use clap::Parser;
+use shared::atomic_write::atomic_write;
+use shared::structure::FinalClassification;
+use stage9::review::{ReviewItem, merge_reviews};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "review-import",
+    version,
+    about = "Merge reviewed decisions from review_queue.json into final_classification.json"
+)]
+struct Cli {
+    /// stage9 `review_queue.json` with a `decision` set on the reviewed clusters
+    #[arg(long)]
+    reviewed: PathBuf,
+    #[arg(long, default_value = "final_classification.json")]
+    final_classification: PathBuf,
+    /// Defaults to overwriting --final-classification
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let reviews: Vec<ReviewItem> = serde_json::from_slice(&fs::read(&cli.reviewed)?)?;
+    let mut classifications: Vec<FinalClassification> =
+        serde_json::from_slice(&fs::read(&cli.final_classification)?)?;
+    let report = merge_reviews(&mut classifications, &reviews)?;
+    let output = cli.output.unwrap_or(cli.final_classification);
+    atomic_write(&output, serde_json::to_string(&classifications)?)?;
+    println!(
+        "Merged {} reviews into {}: {} approved, {} kept entirely, {} custom, {} undecided",
+        reviews.len(),
+        output.display(),
+        report.approved,
+        report.kept_all,
+        report.custom,
+        report.undecided
+    );
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use candle_core::DType;
+use stage9::clip_worker::ClipWorker;
+use std::path::Path;
+
+pub trait TextEmbedder {
+    fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>>;
+}
+
+/// [`ClipWorker`] text tower behind the CLIP tokenizer
+pub struct ClipTextEmbedder {
+    worker: ClipWorker,
+}
+
+impl ClipTextEmbedder {
+    pub fn new<P: AsRef<Path>>(worker: ClipWorker, tokenizer_path: P) -> anyhow::Result<Self> {
+        let worker = worker.with_tokenizer(Some(tokenizer_path.as_ref()))?;
+        Ok(Self { worker })
+    }
+}
+
+impl TextEmbedder for ClipTextEmbedder {
+    fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let embedding = self.worker.get_texts_embedding_batched(&[text])?;
+        Ok(embedding.squeeze(0)?.to_dtype(DType::F32)?.to_vec1()?)
+    }
+}
+
+/// Scales `v` to unit length, an all zero `v` is left as is and returns false
+pub fn l2_normalize(v: &mut [f32]) -> bool {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 || !norm.is_finite() {
+        return false;
+    }
+    v.iter_mut().for_each(|x| *x /= norm);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_l2_normalize() {
+        let mut v = vec![3.0, 0.0, -4.0];
+        assert!(l2_normalize(&mut v));
+        assert_eq!(v, vec![0.6, 0.0, -0.8]);
+        // already unit length
+        assert!(l2_normalize(&mut v));
+        assert!((v.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-6);
+        let mut zeros = vec![0.0; 4];
+        assert!(!l2_normalize(&mut zeros));
+        assert_eq!(zeros, vec![0.0; 4]);
+        assert!(!l2_normalize(&mut []));
+    }
+}
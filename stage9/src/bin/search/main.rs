@@ -0,0 +1,116 @@
+mod embed;
+mod query;
+
+use crate::embed::ClipTextEmbedder;
+use crate::query::search_text;
+use candle_core::DType;
+use candle_transformers::models::clip::ClipConfig;
+use clap::Parser;
+use hnsw_rs::prelude::DistCosine;
+use shared::hnsw::{HnswIdMap, HnswIndex, HnswMetaError, HnswStorage};
+use shared::point_explorer::PointExplorerBuilder;
+use stage9::clip_worker::ClipWorker;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "search",
+    version,
+    about = "Search the float image index with free text queries embedded by the CLIP text tower"
+)]
+struct Cli {
+    #[arg(required = true)]
+    queries: Vec<String>,
+    /// CLIP safetensors the collection was embedded with
+    #[arg(long)]
+    model: PathBuf,
+    /// Defaults to tokenizer.json next to --model
+    #[arg(long)]
+    tokenizer: Option<PathBuf>,
+    /// stage0 point explorer the index was built from
+    #[arg(long)]
+    point_map: String,
+    #[arg(long, default_value = "768")]
+    dim: usize,
+    /// Pickled point extensions, with --url-prefix resolves the URLs of the hits
+    #[arg(long, requires = "url_prefix")]
+    point_ext: Option<String>,
+    #[arg(long, requires = "point_ext")]
+    url_prefix: Option<String>,
+    /// Where the index is, stage17's `--output-dir`
+    #[arg(long, default_value = ".")]
+    index_dir: PathBuf,
+    /// Basename of the index `stage17 --float-point-map` dumps
+    #[arg(long, default_value = "stage17_hnsw_f32")]
+    index: String,
+    #[arg(short, default_value = "20")]
+    k: usize,
+    #[arg(long, default_value = "200")]
+    ef: usize,
+    #[arg(long)]
+    use_gpu: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let mut builder = PointExplorerBuilder::new().path(cli.point_map.as_str());
+    if let (Some(ext), Some(prefix)) = (&cli.point_ext, &cli.url_prefix) {
+        builder = builder
+            .metadata_ext_path(ext.as_str())
+            .point_url_prefix("url", prefix.as_str());
+    }
+    let explorer = builder.build_dyn::<f32>(cli.dim)?;
+    let graph = cli.index_dir.join(format!("{}.hnsw.graph", cli.index));
+    let data = cli.index_dir.join(format!("{}.hnsw.data", cli.index));
+    if !graph.exists() || !data.exists() {
+        anyhow::bail!(
+            "{} not found in {}, build it with `stage17 --float-point-map {}`",
+            cli.index,
+            cli.index_dir.display(),
+            cli.point_map
+        );
+    }
+    let mut storage = HnswStorage::open(&cli.index_dir, &cli.index);
+    let ids = match storage.ids() {
+        Ok(ids) => ids,
+        // dumped before id maps were kept, the index ids are the explorer positions
+        Err(HnswMetaError::MissingIds(_)) => HnswIdMap::from_ids(explorer.iter().map(|(id, _)| id)),
+        Err(e) => return Err(e.into()),
+    };
+    let mut index: HnswIndex<f32, DistCosine> = HnswIndex::new_from_storage(&mut storage);
+
+    let model = cli
+        .model
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Non UTF-8 model path {}", cli.model.display()))?;
+    let tokenizer = cli
+        .tokenizer
+        .clone()
+        .unwrap_or_else(|| cli.model.with_file_name("tokenizer.json"));
+    let tensor_type = match cli.use_gpu {
+        true => DType::BF16,
+        false => DType::F32,
+    };
+    let worker = ClipWorker::new(
+        model,
+        ClipConfig::baai_bge_vl_large(),
+        tensor_type,
+        cli.use_gpu,
+    )?;
+    let embedder = ClipTextEmbedder::new(worker, &tokenizer)?;
+
+    for text in &cli.queries {
+        let hits = search_text(&embedder, &mut index, &ids, &explorer, text, cli.k, cli.ef)?;
+        println!("\n--- {:?} ---", text);
+        for (rank, hit) in hits.iter().enumerate() {
+            println!(
+                "{:>3}  {:.4}  {}  {}",
+                rank + 1,
+                hit.similarity,
+                hit.id,
+                hit.url.as_deref().unwrap_or("-")
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use crate::embed::{TextEmbedder, l2_normalize};
+use hnsw_rs::prelude::DistCosine;
+use serde::Serialize;
+use shared::hnsw::{HnswIdMap, HnswIndex};
+use shared::point_explorer::DynPointExplorer;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+pub struct Hit {
+    pub id: Uuid,
+    pub similarity: f32,
+    pub url: Option<String>,
+}
+
+/// Embeds `text` and returns the `k` points of `index` closest to it, most similar first
+///
+/// `ids` maps the index ids to points, `explorer` resolves their URLs.
+pub fn search_text<E: TextEmbedder>(
+    embedder: &E,
+    index: &mut HnswIndex<'_, f32, DistCosine>,
+    ids: &HnswIdMap,
+    explorer: &DynPointExplorer<f32>,
+    text: &str,
+    k: usize,
+    ef: usize,
+) -> anyhow::Result<Vec<Hit>> {
+    let mut query = embedder.embed(text)?;
+    if query.len() != explorer.dim() {
+        anyhow::bail!(
+            "Query embedded to {} dimensions, the explorer holds {}",
+            query.len(),
+            explorer.dim()
+        );
+    }
+    if !l2_normalize(&mut query) {
+        anyhow::bail!("Query `{}` embedded to a zero vector", text);
+    }
+    let hits = index
+        .search(&query, k, ef.max(k))
+        .into_iter()
+        .filter_map(|n| {
+            let id = *ids.uuid(n.point_id())?;
+            Some(Hit {
+                id,
+                similarity: 1.0 - n.distance(),
+                url: explorer.get_point_uri("url", &id),
+            })
+        })
+        .collect();
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::hnsw::HnswStorage;
+
+    const DIM: usize = 16;
+    const CAPTIONS: [&str; 6] = [
+        "a cat on a sofa",
+        "two dogs in the snow",
+        "anime girl with a sword",
+        "screenshot of a chat",
+        "meme with impact font",
+        "sunset over the sea",
+    ];
+
+    /// Deterministic bag of bytes, not normalized
+    struct FakeEmbedder;
+
+    impl TextEmbedder for FakeEmbedder {
+        fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+            let mut v = vec![0.0; DIM];
+            for (i, b) in text.bytes().enumerate() {
+                v[(b as usize * 7 + i) % DIM] += 1.0;
+            }
+            Ok(v)
+        }
+    }
+
+    fn explorer() -> DynPointExplorer<f32> {
+        let mut explorer = DynPointExplorer::new(DIM);
+        for (i, caption) in CAPTIONS.iter().enumerate() {
+            // image vectors are not unit length either
+            let v: Vec<f32> = FakeEmbedder
+                .embed(caption)
+                .unwrap()
+                .iter()
+                .map(|x| x * (i + 1) as f32)
+                .collect();
+            explorer.insert(Uuid::from_u128(i as u128), v).unwrap();
+        }
+        explorer
+    }
+
+    #[test]
+    fn test_search_text_round_trip() {
+        let explorer = explorer();
+        let vectors: Vec<Vec<f32>> = explorer.iter().map(|(_, v)| v.clone()).collect();
+        let points: Vec<(&Vec<f32>, usize)> = vectors.iter().zip(0..).collect();
+        let mut index = HnswIndex::new(16, points.len(), 16, 200, DistCosine);
+        index.insert(&points);
+        let ids = HnswIdMap::from_ids(explorer.iter().map(|(id, _)| id));
+        let dir = std::env::temp_dir().join(format!("search_round_trip_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        index.save(&dir, "float", &ids).unwrap();
+        drop(index);
+
+        let mut storage = HnswStorage::open(&dir, "float");
+        let ids = storage.ids().unwrap();
+        let mut index = HnswIndex::new_from_storage(&mut storage);
+        for (i, caption) in CAPTIONS.iter().enumerate() {
+            let hits =
+                search_text(&FakeEmbedder, &mut index, &ids, &explorer, caption, 3, 50).unwrap();
+            assert_eq!(hits.len(), 3);
+            assert_eq!(hits[0].id, Uuid::from_u128(i as u128), "{caption}");
+            assert!((hits[0].similarity - 1.0).abs() < 1e-5);
+            assert!(hits.windows(2).all(|w| w[0].similarity >= w[1].similarity));
+            assert!(hits.iter().all(|h| h.url.is_none()));
+        }
+        drop(index);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_search_text_rejects() {
+        let explorer = explorer();
+        let mut index = HnswIndex::new(16, 1, 16, 200, DistCosine);
+        let ids = HnswIdMap::from_ids(explorer.iter().map(|(id, _)| id));
+        // nothing to embed
+        assert!(search_text(&FakeEmbedder, &mut index, &ids, &explorer, "", 3, 50).is_err());
+        let other = DynPointExplorer::<f32>::new(DIM + 1);
+        assert!(search_text(&FakeEmbedder, &mut index, &ids, &other, "a cat", 3, 50).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum DurationError {
+    #[error("Duration is empty")]
+    Empty,
+    #[error("`{0}` is not a duration such as 6h, 90m, 1h30m or 45s")]
+    Invalid(String),
+}
+
+/// Parses `6h`, `90m`, `1h30m` or `45s`, a bare number is seconds
+pub fn parse_duration(s: &str) -> Result<Duration, DurationError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(DurationError::Empty);
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let invalid = || DurationError::Invalid(s.to_string());
+    let mut total = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let unit = match rest[digits..].chars().next() {
+            Some('h') => 3600,
+            Some('m') => 60,
+            Some('s') => 1,
+            _ => return Err(invalid()),
+        };
+        total = value
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// Wall clock a run may take, counted from [`TimeBudget::start`]
+#[derive(Debug, Clone, Copy)]
+pub struct TimeBudget {
+    start: Instant,
+    budget: Duration,
+}
+
+impl TimeBudget {
+    pub fn start(budget: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            budget,
+        }
+    }
+
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Whether work expected to take `estimate` still ends within the budget
+    pub fn admits(&self, estimate: Duration) -> bool {
+        self.elapsed().saturating_add(estimate) <= self.budget
+    }
+}
+
+/// Runs `process` on consecutive ranges of `len` entries, `batch` at a time, returns how many
+/// entries were processed
+///
+/// With a budget no batch is started unless one as slow as the slowest so far still fits, the
+/// batch in flight always finishes.
+pub fn run_batches<F>(
+    len: usize,
+    batch: usize,
+    budget: Option<&TimeBudget>,
+    mut process: F,
+) -> anyhow::Result<usize>
+where
+    F: FnMut(Range<usize>) -> anyhow::Result<()>,
+{
+    let batch = batch.max(1);
+    let mut slowest = Duration::ZERO;
+    let mut done = 0;
+    while done < len {
+        if budget.is_some_and(|b| !b.admits(slowest)) {
+            break;
+        }
+        let end = (done + batch).min(len);
+        let started = Instant::now();
+        process(done..end)?;
+        slowest = slowest.max(started.elapsed());
+        done = end;
+    }
+    Ok(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::Schedule;
+    use shared::structure::FinalClassification;
+    use std::collections::HashSet;
+    use std::thread;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("6h"), Ok(Duration::from_secs(6 * 3600)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration(" 120 "), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration(""), Err(DurationError::Empty));
+        for bad in ["h", "6d", "1h30", "-5m", "1.5h"] {
+            assert_eq!(
+                parse_duration(bad),
+                Err(DurationError::Invalid(bad.to_string())),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_batches_unbounded() {
+        let mut ranges = Vec::new();
+        let done = run_batches(7, 3, None, |r| {
+            ranges.push(r);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(done, 7);
+        assert_eq!(ranges, vec![0..3, 3..6, 6..7]);
+        assert_eq!(run_batches(0, 0, None, |_| Ok(())).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_run_batches_budget() {
+        // nothing fits into an exhausted budget
+        let budget = TimeBudget::start(Duration::ZERO);
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(run_batches(5, 2, Some(&budget), |_| Ok(())).unwrap(), 0);
+        // a second batch as slow as the first one would overrun
+        let budget = TimeBudget::start(Duration::from_millis(50));
+        let done = run_batches(5, 2, Some(&budget), |_| {
+            thread::sleep(Duration::from_millis(30));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(done, 2);
+        let budget = TimeBudget::start(Duration::from_secs(3600));
+        assert_eq!(run_batches(5, 2, Some(&budget), |_| Ok(())).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_partial_run_partitions_clusters() {
+        let clusters: Vec<HashSet<Uuid>> = (0..6u128)
+            .map(|c| (0..=c).map(|m| Uuid::from_u128(c * 100 + m)).collect())
+            .collect();
+        let schedule = Schedule::by_priority_desc(&[3u64, 9, 1, 7, 5, 0]);
+        let budget = TimeBudget::start(Duration::from_millis(50));
+        let mut results = Vec::new();
+        let processed = run_batches(clusters.len(), 2, Some(&budget), |range| {
+            thread::sleep(Duration::from_millis(30));
+            results.extend(schedule.order()[range].iter().map(|&idx| {
+                let mut members: Vec<Uuid> = clusters[idx].iter().copied().collect();
+                members.sort();
+                let kept = members.remove(0);
+                FinalClassification {
+                    kept_non_gif: Some(kept),
+                    other_need_delete_group: Some(members).filter(|m| !m.is_empty()),
+                    cluster_index: Some(idx),
+                    ..Default::default()
+                }
+            }));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(processed, 2);
+        let final_classification: Vec<FinalClassification> = schedule
+            .restore_prefix(results)
+            .into_iter()
+            .map(|(_, fc)| fc)
+            .collect();
+        let remaining: Vec<HashSet<Uuid>> = schedule
+            .remaining(processed)
+            .into_iter()
+            .map(|idx| clusters[idx].clone())
+            .collect();
+        // the two clusters with the biggest savings, in original order
+        let indices: Vec<usize> = final_classification
+            .iter()
+            .filter_map(|fc| fc.cluster_index)
+            .collect();
+        assert_eq!(indices, vec![1, 3]);
+        assert_eq!(remaining.len(), 4);
+
+        let mut covered: Vec<HashSet<Uuid>> = final_classification
+            .iter()
+            .map(|fc| fc.handled().into_iter().collect())
+            .chain(remaining)
+            .collect();
+        covered.sort_by_key(|c| c.iter().min().copied());
+        assert_eq!(covered, clusters);
+    }
+}
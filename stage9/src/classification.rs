@@ -0,0 +1,400 @@
+//! Owned assembly of [`FinalClassification`] from the borrowed per-stage results
+//!
+//! How the stage results map onto an entry:
+//! - the text stage keeps one point per text anomaly cluster, the rest of the cluster without
+//!   GIFs keeps its biggest non-GIF and deletes everything else
+//! - a cluster with GIFs keeps no non-GIF, its GIFs are decided by the GIF and CLIP stages
+//! - the GIF stage deletes GIFs that fail to decode or show a single frame and records the
+//!   metadata of the ones sent on to the CLIP stage
+//! - the CLIP stage keeps the biggest GIF of each similar group and deletes the others; `None`
+//!   (no GIFs in the cluster) and `Some(None)` (no GIF survived the GIF stage) both add nothing
+//! - a cluster whose GIFs all fail the GIF stage keeps nothing but its text anomalies
+
+use shared::structure::{
+    FinalClassification, GifMeta, SampleInfo, TriageGif, TriageGifGroupsClipStagePair,
+    TriageGifGroupsGifStagePair,
+};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Result of the cluster split for one cluster: kept text anomalies, GIFs to triage, kept
+/// non-GIF and the other points to delete
+pub type ExtractedCluster<'a> = (
+    Option<Vec<&'a Uuid>>,
+    Option<Vec<&'a Uuid>>,
+    Option<&'a Uuid>,
+    Option<Vec<&'a Uuid>>,
+);
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ClassificationError {
+    #[error("{id} is both kept and deleted in cluster {cluster_index:?}")]
+    KeptAndDeleted {
+        id: Uuid,
+        cluster_index: Option<usize>,
+    },
+}
+
+/// Every setter replaces what was set before, empty groups are left out of the entry
+#[derive(Debug, Clone, Default)]
+pub struct FinalClassificationBuilder {
+    text_anomalies: Vec<Uuid>,
+    gif_invalid: Vec<(Uuid, String)>,
+    gif_same_frame: Vec<Uuid>,
+    gif_keep: Vec<Uuid>,
+    gif_delete: Vec<Uuid>,
+    non_gif: Option<Uuid>,
+    others_delete: Vec<Uuid>,
+    gif_metadata: Option<HashMap<Uuid, GifMeta>>,
+    cluster_index: Option<usize>,
+    sample: Option<SampleInfo>,
+}
+
+fn non_empty<T>(v: Vec<T>) -> Option<Vec<T>> {
+    (!v.is_empty()).then_some(v)
+}
+
+fn owned(ids: Option<&Vec<&Uuid>>) -> Vec<Uuid> {
+    ids.into_iter().flatten().map(|&&id| id).collect()
+}
+
+impl FinalClassificationBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn keep_text_anomalies(mut self, ids: Vec<Uuid>) -> Self {
+        self.text_anomalies = ids;
+        self
+    }
+
+    /// GIFs that failed to decode, with the reason
+    pub fn gif_invalid(mut self, gifs: Vec<(Uuid, String)>) -> Self {
+        self.gif_invalid = gifs;
+        self
+    }
+
+    pub fn gif_same_frame(mut self, ids: Vec<Uuid>) -> Self {
+        self.gif_same_frame = ids;
+        self
+    }
+
+    pub fn gif_keep(mut self, ids: Vec<Uuid>) -> Self {
+        self.gif_keep = ids;
+        self
+    }
+
+    pub fn gif_delete(mut self, ids: Vec<Uuid>) -> Self {
+        self.gif_delete = ids;
+        self
+    }
+
+    pub fn keep_non_gif(mut self, id: Option<Uuid>) -> Self {
+        self.non_gif = id;
+        self
+    }
+
+    pub fn delete_others(mut self, ids: Vec<Uuid>) -> Self {
+        self.others_delete = ids;
+        self
+    }
+
+    pub fn gif_metadata(mut self, metadata: Option<HashMap<Uuid, GifMeta>>) -> Self {
+        self.gif_metadata = metadata;
+        self
+    }
+
+    pub fn cluster_index(mut self, idx: usize) -> Self {
+        self.cluster_index = Some(idx);
+        self
+    }
+
+    /// Marks the entry as coming from a sampled run
+    pub fn sample(mut self, sample: Option<SampleInfo>) -> Self {
+        self.sample = sample;
+        self
+    }
+
+    /// Invalid, single frame and metadata of the triaged GIFs, `None` if the cluster had none
+    pub fn gif_stage(self, pair: Option<&TriageGifGroupsGifStagePair>) -> Self {
+        let invalid = pair
+            .and_then(|p| p.invalid_gif_id.as_ref())
+            .map(|(ids, reasons)| {
+                debug_assert_eq!(ids.len(), reasons.len());
+                ids.iter()
+                    .map(|&&id| id)
+                    .zip(reasons.iter().cloned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let metadata = pair
+            .and_then(|p| p.gif_metadata.as_ref())
+            .map(|metadata| metadata.iter().map(|(&&id, &meta)| (id, meta)).collect());
+        self.gif_invalid(invalid)
+            .gif_same_frame(owned(
+                pair.and_then(|p| p.discard_same_frame_gif_id.as_ref()),
+            ))
+            .gif_metadata(metadata)
+    }
+
+    /// Kept and duplicate GIFs, see the module docs for the two `None` levels
+    pub fn clip_stage(self, pair: Option<&Option<TriageGifGroupsClipStagePair>>) -> Self {
+        let pair = pair.and_then(Option::as_ref);
+        let ids = |gifs: Option<&Vec<TriageGif>>| -> Vec<Uuid> {
+            gifs.into_iter().flatten().map(|gif| *gif.uuid).collect()
+        };
+        let keep = ids(pair.and_then(|p| p.kept_gifs.as_ref()));
+        let delete = ids(pair.and_then(|p| p.discard_duplicate_gifs.as_ref()));
+        self.gif_keep(keep).gif_delete(delete)
+    }
+
+    /// Fails if a point is both kept and deleted
+    pub fn build(self) -> Result<FinalClassification, ClassificationError> {
+        let (invalid_ids, reasons): (Vec<Uuid>, Vec<String>) = self.gif_invalid.into_iter().unzip();
+        let fc = FinalClassification {
+            kept_text_anomalies_group: non_empty(self.text_anomalies),
+            triaged_gif_and_invalid_group: non_empty(invalid_ids).map(|ids| (ids, reasons)),
+            triaged_gif_and_discard_same_frame_group: non_empty(self.gif_same_frame),
+            triaged_gif_and_then_will_keep_group: non_empty(self.gif_keep),
+            triaged_gif_and_then_will_delete_group: non_empty(self.gif_delete),
+            kept_non_gif: self.non_gif,
+            other_need_delete_group: non_empty(self.others_delete),
+            reviewed_keep_group: None,
+            gif_metadata: self.gif_metadata,
+            cluster_index: self.cluster_index,
+            sample: self.sample,
+        };
+        let kept: HashSet<Uuid> = fc.kept().into_iter().collect();
+        match fc.discarded().into_iter().find(|id| kept.contains(id)) {
+            Some(id) => Err(ClassificationError::KeptAndDeleted {
+                id,
+                cluster_index: fc.cluster_index,
+            }),
+            None => Ok(fc),
+        }
+    }
+}
+
+/// The GIFs to triage are left to [`FinalClassificationBuilder::gif_stage`] and
+/// [`FinalClassificationBuilder::clip_stage`]
+impl From<&ExtractedCluster<'_>> for FinalClassificationBuilder {
+    fn from((text_anomalies, _, non_gif, others): &ExtractedCluster<'_>) -> Self {
+        FinalClassificationBuilder::new()
+            .keep_text_anomalies(owned(text_anomalies.as_ref()))
+            .keep_non_gif(non_gif.copied())
+            .delete_others(owned(others.as_ref()))
+    }
+}
+
+impl TryFrom<FinalClassificationBuilder> for FinalClassification {
+    type Error = ClassificationError;
+
+    fn try_from(builder: FinalClassificationBuilder) -> Result<Self, Self::Error> {
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::test_util::id;
+
+    const META: GifMeta = GifMeta {
+        frame_count: 4,
+        duration_ms: 400,
+        width: 8,
+        height: 8,
+        downscaled_from: None,
+    };
+
+    fn gif(uuid: &Uuid) -> TriageGif<'_> {
+        TriageGif {
+            uuid,
+            path: "",
+            size: 1,
+        }
+    }
+
+    fn gif_pair(ids: &[Uuid]) -> TriageGifGroupsGifStagePair<'_> {
+        // 0 fails to decode, 1 is a single frame, the rest goes on to the clip stage
+        TriageGifGroupsGifStagePair {
+            invalid_gif_id: Some((vec![&ids[0]], vec!["broken".to_string()])),
+            discard_same_frame_gif_id: Some(vec![&ids[1]]),
+            prepare_clip_gif_pair: None,
+            gif_metadata: Some(ids[2..].iter().map(|id| (id, META)).collect()),
+        }
+    }
+
+    fn clip_pair(ids: &[Uuid]) -> TriageGifGroupsClipStagePair<'_> {
+        TriageGifGroupsClipStagePair {
+            kept_gifs: Some(vec![gif(&ids[2])]),
+            discard_duplicate_gifs: Some(ids[3..].iter().map(gif).collect()),
+            unmerged_gifs: None,
+        }
+    }
+
+    #[test]
+    fn test_extracted_cluster_without_gifs() {
+        let (text, non_gif, other) = (id(1), id(2), id(3));
+        let extracted: ExtractedCluster =
+            (Some(vec![&text]), None, Some(&non_gif), Some(vec![&other]));
+        let fc = FinalClassificationBuilder::from(&extracted)
+            .gif_stage(None)
+            .clip_stage(None)
+            .cluster_index(5)
+            .build()
+            .unwrap();
+        assert_eq!(fc.kept_text_anomalies_group, Some(vec![text]));
+        assert_eq!(fc.kept_non_gif, Some(non_gif));
+        assert_eq!(fc.other_need_delete_group, Some(vec![other]));
+        assert_eq!(fc.cluster_index, Some(5));
+        assert!(fc.triaged_gif_and_invalid_group.is_none());
+        assert!(fc.triaged_gif_and_discard_same_frame_group.is_none());
+        assert!(fc.triaged_gif_and_then_will_keep_group.is_none());
+        assert!(fc.triaged_gif_and_then_will_delete_group.is_none());
+        assert!(fc.gif_metadata.is_none());
+        assert!(fc.reviewed_keep_group.is_none());
+
+        // a text only cluster
+        let extracted: ExtractedCluster = (Some(vec![&text]), None, None, Some(vec![&other]));
+        let fc = FinalClassificationBuilder::from(&extracted)
+            .build()
+            .unwrap();
+        assert_eq!(fc.kept(), vec![text]);
+        assert_eq!(fc.discarded(), vec![other]);
+    }
+
+    #[test]
+    fn test_stage_combinations() {
+        let gifs: Vec<Uuid> = (10..15).map(id).collect();
+        let other = id(1);
+        let extracted: ExtractedCluster =
+            (None, Some(gifs.iter().collect()), None, Some(vec![&other]));
+        let gif_stage = gif_pair(&gifs);
+        let clip_stages = [None, Some(None), Some(Some(clip_pair(&gifs)))];
+        for gif_stage in [None, Some(&gif_stage)] {
+            for clip_stage in &clip_stages {
+                let case = format!(
+                    "gif stage {}, clip stage {:?}",
+                    gif_stage.is_some(),
+                    clip_stage.as_ref().map(Option::is_some)
+                );
+                let fc = FinalClassificationBuilder::from(&extracted)
+                    .gif_stage(gif_stage)
+                    .clip_stage(clip_stage.as_ref())
+                    .build()
+                    .unwrap();
+                // the triaged GIFs never come from the cluster split
+                assert!(fc.kept_non_gif.is_none(), "{case}");
+                assert_eq!(fc.other_need_delete_group, Some(vec![other]), "{case}");
+                match gif_stage {
+                    Some(_) => {
+                        assert_eq!(
+                            fc.triaged_gif_and_invalid_group,
+                            Some((vec![gifs[0]], vec!["broken".to_string()])),
+                            "{case}"
+                        );
+                        assert_eq!(
+                            fc.triaged_gif_and_discard_same_frame_group,
+                            Some(vec![gifs[1]]),
+                            "{case}"
+                        );
+                        assert_eq!(
+                            fc.gif_metadata.as_ref().map(HashMap::len),
+                            Some(3),
+                            "{case}"
+                        );
+                    }
+                    None => {
+                        assert!(fc.triaged_gif_and_invalid_group.is_none(), "{case}");
+                        assert!(
+                            fc.triaged_gif_and_discard_same_frame_group.is_none(),
+                            "{case}"
+                        );
+                        assert!(fc.gif_metadata.is_none(), "{case}");
+                    }
+                }
+                match clip_stage {
+                    Some(Some(_)) => {
+                        assert_eq!(
+                            fc.triaged_gif_and_then_will_keep_group,
+                            Some(vec![gifs[2]]),
+                            "{case}"
+                        );
+                        assert_eq!(
+                            fc.triaged_gif_and_then_will_delete_group,
+                            Some(gifs[3..].to_vec()),
+                            "{case}"
+                        );
+                    }
+                    // no GIFs in the cluster and none past the GIF stage look the same
+                    Some(None) | None => {
+                        assert!(fc.triaged_gif_and_then_will_keep_group.is_none(), "{case}");
+                        assert!(
+                            fc.triaged_gif_and_then_will_delete_group.is_none(),
+                            "{case}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_gifs_failed_keeps_nothing() {
+        let gifs = [id(10), id(11)];
+        let other = id(1);
+        let extracted: ExtractedCluster =
+            (None, Some(gifs.iter().collect()), None, Some(vec![&other]));
+        let gif_stage = TriageGifGroupsGifStagePair {
+            invalid_gif_id: Some((vec![&gifs[0]], vec!["broken".to_string()])),
+            discard_same_frame_gif_id: Some(vec![&gifs[1]]),
+            prepare_clip_gif_pair: None,
+            gif_metadata: None,
+        };
+        let fc = FinalClassificationBuilder::from(&extracted)
+            .gif_stage(Some(&gif_stage))
+            .clip_stage(Some(&None))
+            .build()
+            .unwrap();
+        assert!(fc.kept().is_empty());
+        let mut discarded = fc.discarded();
+        discarded.sort();
+        assert_eq!(discarded, vec![other, gifs[0], gifs[1]]);
+    }
+
+    #[test]
+    fn test_build_rejects_kept_and_deleted() {
+        let err = FinalClassificationBuilder::new()
+            .keep_non_gif(Some(id(1)))
+            .delete_others(vec![id(2), id(1)])
+            .cluster_index(3)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ClassificationError::KeptAndDeleted {
+                id: id(1),
+                cluster_index: Some(3)
+            }
+        );
+        let builder = FinalClassificationBuilder::new()
+            .gif_keep(vec![id(4)])
+            .gif_invalid(vec![(id(4), "broken".to_string())]);
+        assert!(FinalClassification::try_from(builder).is_err());
+    }
+
+    #[test]
+    fn test_empty_groups_left_out() {
+        let fc = FinalClassificationBuilder::new()
+            .keep_text_anomalies(Vec::new())
+            .gif_invalid(Vec::new())
+            .delete_others(Vec::new())
+            .build()
+            .unwrap();
+        assert!(fc.kept_text_anomalies_group.is_none());
+        assert!(fc.triaged_gif_and_invalid_group.is_none());
+        assert!(fc.other_need_delete_group.is_none());
+        assert!(fc.handled().is_empty());
+    }
+}
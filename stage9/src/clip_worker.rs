@@ -1,36 +1,157 @@
+use crate::frame_check::FrameCheck;
+#[cfg(feature = "remote-image")]
+use crate::remote_image::{FetchConfig, RemoteImage};
 use candle_core::{D, DType, Device, Error as CandleError, Result, Tensor, WithDType};
 use candle_nn::VarBuilder;
 use candle_transformers::models::clip::{ClipConfig, ClipModel};
-use image::{ImageReader, imageops};
+use image::metadata::Orientation;
+use image::{DynamicImage, imageops};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use shared::config::Thresholds;
 use shared::cosine_sim::{Cosine, cosine_sim};
+use shared::image_ext::open_image;
+use shared::metrics;
 use shared::structure::{
-    IMAGE_SIM_THRESHOLD, TriageGif, TriageGifClip, TriageGifGroupsClipStagePair,
-    TriageGifGroupsClipStageReq, TriageGifGroupsClipStageRes,
+    TriageGif, TriageGifClip, TriageGifGroupsClipStagePair, TriageGifGroupsClipStageReq,
+    TriageGifGroupsClipStageRes, UnmergedGif,
 };
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tokenizers::Tokenizer;
+
+/// Images or texts per forward pass of the towers unless configured otherwise
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+/// Inputs per forward pass of the towers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BatchSize {
+    Fixed(usize),
+    /// Starts at `max` and halves on every out of memory error, down to one input, the size that
+    /// fits is kept for the batches after
+    Auto {
+        max: usize,
+    },
+}
+
+impl Default for BatchSize {
+    fn default() -> Self {
+        BatchSize::Fixed(DEFAULT_BATCH_SIZE)
+    }
+}
+
+/// Whether `e` is the device running out of memory, worth a retry with a smaller batch
+pub(crate) fn is_out_of_memory(e: &CandleError) -> bool {
+    let msg = e.to_string().to_ascii_lowercase();
+    msg.contains("out of memory") || msg.contains("out_of_memory") || msg.contains("outofmemory")
+}
+
+/// Runs `forward` over `items` in batches of `size` inputs, halving `size` and retrying the batch
+/// on out of memory if `auto`
+pub(crate) fn run_batched<T, F>(
+    items: &[T],
+    size: &AtomicUsize,
+    auto: bool,
+    mut forward: F,
+) -> Result<Vec<Tensor>>
+where
+    F: FnMut(&[T]) -> Result<Tensor>,
+{
+    let mut batches = Vec::new();
+    let mut rest = items;
+    while !rest.is_empty() {
+        let batch = size.load(Ordering::Relaxed).clamp(1, rest.len());
+        match forward(&rest[..batch]) {
+            Ok(features) => {
+                batches.push(features);
+                rest = &rest[batch..];
+            }
+            Err(e) if auto && batch > 1 && is_out_of_memory(&e) => {
+                tracing::warn!(
+                    "Out of memory with {} inputs per batch, retrying with {}",
+                    batch,
+                    batch / 2
+                );
+                metrics::counter("clip_batch_oom_total", &[]).inc();
+                size.store(batch / 2, Ordering::Relaxed);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(batches)
+}
+
+/// `ids` padded with `pad_id` to a common length, each cut to `max_len` keeping its last token,
+/// the end of text token the text tower pools at
+pub(crate) fn pad_token_ids(ids: &[&[u32]], max_len: usize, pad_id: u32) -> (Vec<u32>, usize) {
+    let len = ids
+        .iter()
+        .map(|ids| ids.len().min(max_len))
+        .max()
+        .unwrap_or(0);
+    let mut padded = Vec::with_capacity(ids.len() * len);
+    for (row, ids) in ids.iter().enumerate() {
+        match ids.split_last() {
+            Some((last, rest)) if ids.len() > max_len => {
+                padded.extend_from_slice(&rest[..max_len - 1]);
+                padded.push(*last);
+            }
+            _ => padded.extend_from_slice(ids),
+        }
+        padded.resize((row + 1) * len, pad_id);
+    }
+    (padded, len)
+}
 
 pub trait ClipWorkerInput: Sync + Sized {
-    fn to_raw(&self, size: usize) -> anyhow::Result<Vec<u8>>;
+    fn to_raw(&self, size: usize, apply_orientation: bool) -> anyhow::Result<Vec<u8>>;
+}
+
+pub(crate) fn resize_to_raw(img: &DynamicImage, size: usize) -> Vec<u8> {
+    let (height, width) = (size, size);
+    let img = img.resize_to_fill(width as u32, height as u32, imageops::FilterType::Triangle);
+    img.to_rgb8().into_raw()
 }
 
 impl ClipWorkerInput for &str {
-    fn to_raw(&self, size: usize) -> anyhow::Result<Vec<u8>> {
-        let img = ImageReader::open(self)?
-            .decode()
+    fn to_raw(&self, size: usize, apply_orientation: bool) -> anyhow::Result<Vec<u8>> {
+        let img = open_image(self, apply_orientation)
             .map_err(|e| CandleError::Msg(format!("Failed to decode image: {}", e).into()).bt())?;
-        let (height, width) = (size, size);
-        let img = img.resize_to_fill(width as u32, height as u32, imageops::FilterType::Triangle);
-        let img = img.to_rgb8();
-        let img = img.into_raw();
-        Ok(img)
+        Ok(resize_to_raw(&img, size))
+    }
+}
+
+/// Already decoded, orientation (if any) is the caller's business
+impl ClipWorkerInput for DynamicImage {
+    fn to_raw(&self, size: usize, _: bool) -> anyhow::Result<Vec<u8>> {
+        Ok(resize_to_raw(self, size))
+    }
+}
+
+/// Decoded image together with the EXIF orientation read from its decoder
+pub struct OrientedImage {
+    pub image: DynamicImage,
+    pub orientation: Orientation,
+}
+
+impl ClipWorkerInput for OrientedImage {
+    fn to_raw(&self, size: usize, apply_orientation: bool) -> anyhow::Result<Vec<u8>> {
+        match apply_orientation && self.orientation != Orientation::NoTransforms {
+            true => {
+                let mut img = self.image.clone();
+                img.apply_orientation(self.orientation);
+                Ok(resize_to_raw(&img, size))
+            }
+            false => Ok(resize_to_raw(&self.image, size)),
+        }
     }
 }
 
 impl<'a> ClipWorkerInput for &'a [u8] {
-    fn to_raw(&self, _: usize) -> anyhow::Result<Vec<u8>> {
+    fn to_raw(&self, _: usize, _: bool) -> anyhow::Result<Vec<u8>> {
         Ok((*self).to_vec())
     }
 }
@@ -39,8 +160,8 @@ impl<'a, U> ClipWorkerInput for &'a U
 where
     U: ClipWorkerInput + Sync,
 {
-    fn to_raw(&self, size: usize) -> anyhow::Result<Vec<u8>> {
-        (*self).to_raw(size)
+    fn to_raw(&self, size: usize, apply_orientation: bool) -> anyhow::Result<Vec<u8>> {
+        (*self).to_raw(size, apply_orientation)
     }
 }
 
@@ -49,6 +170,16 @@ pub struct ClipWorker {
     device: Device,
     model: ClipModel,
     tensor_type: DType,
+    apply_exif_orientation: bool,
+    frame_check: Option<FrameCheck>,
+    #[cfg(feature = "remote-image")]
+    remote_fetch: FetchConfig,
+    model_path: PathBuf,
+    /// Loaded by [`Self::with_tokenizer`], needed by the text tower
+    tokenizer: Option<Tokenizer>,
+    /// Inputs per forward pass, lowered on out of memory if `auto_batch`
+    batch_size: AtomicUsize,
+    auto_batch: bool,
 }
 
 impl ClipWorker {
@@ -78,9 +209,65 @@ impl ClipWorker {
             model,
             tensor_type,
             config: clip_config,
+            apply_exif_orientation: true,
+            frame_check: None,
+            #[cfg(feature = "remote-image")]
+            remote_fetch: FetchConfig::default(),
+            model_path: PathBuf::from(model_filepath),
+            tokenizer: None,
+            batch_size: AtomicUsize::new(DEFAULT_BATCH_SIZE),
+            auto_batch: false,
         })
     }
 
+    /// Inputs per forward pass, [`DEFAULT_BATCH_SIZE`] fixed by default
+    pub fn batch_size(mut self, size: BatchSize) -> Self {
+        let (size, auto) = match size {
+            BatchSize::Fixed(size) => (size, false),
+            BatchSize::Auto { max } => (max, true),
+        };
+        self.batch_size = AtomicUsize::new(size.max(1));
+        self.auto_batch = auto;
+        self
+    }
+
+    /// Inputs per forward pass now, lower than configured once auto batching hit out of memory
+    pub fn current_batch_size(&self) -> usize {
+        self.batch_size.load(Ordering::Relaxed)
+    }
+
+    /// Loads the text tower's tokenizer from `path`, `tokenizer.json` next to the model by
+    /// default
+    pub fn with_tokenizer(mut self, path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => self.model_path.with_file_name("tokenizer.json"),
+        };
+        let tokenizer = Tokenizer::from_file(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to load tokenizer {}: {}", path.display(), e))?;
+        self.tokenizer = Some(tokenizer);
+        Ok(self)
+    }
+
+    /// EXIF orientation correction for path / [`OrientedImage`] inputs, on by default
+    pub fn exif_orientation(mut self, enabled: bool) -> Self {
+        self.apply_exif_orientation = enabled;
+        self
+    }
+
+    /// Frame level check of the GIFs merged into a cluster, off by default
+    pub fn frame_check(mut self, check: FrameCheck) -> Self {
+        self.frame_check = Some(check);
+        self
+    }
+
+    /// Timeout and size cap of [`Self::get_images_embedding_from_urls`]
+    #[cfg(feature = "remote-image")]
+    pub fn remote_fetch(mut self, fetch: FetchConfig) -> Self {
+        self.remote_fetch = fetch;
+        self
+    }
+
     fn div_l2_norm(&self, v: &Tensor) -> Result<Tensor> {
         let l2_norm = v.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
         v.broadcast_div(&l2_norm)
@@ -91,7 +278,7 @@ impl ClipWorker {
         T: ClipWorkerInput,
     {
         let img = image
-            .to_raw(image_size)
+            .to_raw(image_size, self.apply_exif_orientation)
             .map_err(|e| CandleError::Msg(e.to_string()))?;
         let img = Tensor::from_vec(img, (image_size, image_size, 3), &self.device)?
             .permute((2, 0, 1))?
@@ -129,18 +316,115 @@ impl ClipWorker {
     where
         T: ClipWorkerInput,
     {
-        const BATCH_SIZE: usize = 32;
-        let batches: Vec<Tensor> = images
-            .chunks(BATCH_SIZE)
-            .map(|chunk| {
-                let imgs = self
-                    .load_images(chunk, self.config.image_size)
-                    .map_err(|e| {
-                        CandleError::Msg(format!("Failed to load image batch: {}", e).into()).bt()
-                    })?;
-                self.model.get_image_features(&imgs)
+        let batches = run_batched(images, &self.batch_size, self.auto_batch, |chunk| {
+            let start = Instant::now();
+            let imgs = self
+                .load_images(chunk, self.config.image_size)
+                .map_err(|e| {
+                    CandleError::Msg(format!("Failed to load image batch: {}", e).into()).bt()
+                })?;
+            let features = self.model.get_image_features(&imgs)?;
+            metrics::histogram("clip_batch_seconds", &[]).observe_since(start);
+            metrics::counter("clip_images_total", &[]).add(chunk.len() as u64);
+            Ok(features)
+        })?;
+        let features = match batches.as_slice() {
+            [single] => single.clone(),
+            _ => Tensor::cat(&batches, 0)?,
+        };
+        self.div_l2_norm(&features)
+    }
+
+    /// Embeddings of the images behind `urls`, fetched in parallel, each URL with its own
+    /// result so that one unreachable image does not fail the others
+    #[cfg(feature = "remote-image")]
+    pub fn get_images_embedding_from_urls(&self, urls: &[&str]) -> Vec<anyhow::Result<Vec<f32>>> {
+        let fetch = self.remote_fetch;
+        let agent = fetch.agent();
+        let raws: Vec<anyhow::Result<Vec<u8>>> = urls
+            .par_iter()
+            .map(|url| {
+                let remote = RemoteImage::new(*url).with_fetch(fetch);
+                let start = Instant::now();
+                let raw =
+                    remote.fetch_raw(&agent, self.config.image_size, self.apply_exif_orientation);
+                metrics::histogram("clip_fetch_seconds", &[]).observe_since(start);
+                Ok(raw?)
             })
-            .collect::<Result<_>>()?;
+            .collect();
+        let fetched: Vec<&[u8]> = raws
+            .iter()
+            .filter_map(|raw| raw.as_ref().ok().map(Vec::as_slice))
+            .collect();
+        let embeddings = match fetched.is_empty() {
+            true => Ok(Vec::new()),
+            false => self
+                .get_images_embedding_batched(&fetched)
+                .and_then(|t| t.to_dtype(DType::F32)?.to_vec2::<f32>()),
+        };
+        let mut embeddings = match embeddings {
+            Ok(embeddings) => embeddings.into_iter(),
+            Err(e) => {
+                let msg = e.to_string();
+                return raws
+                    .into_iter()
+                    .zip(urls)
+                    .map(|(raw, url)| {
+                        raw?;
+                        anyhow::bail!("Failed to embed {}: {}", url, msg)
+                    })
+                    .collect();
+            }
+        };
+        raws.into_iter()
+            .map(|raw| {
+                raw?;
+                Ok(embeddings.next().expect("one embedding per fetched image"))
+            })
+            .collect()
+    }
+
+    /// L2 normalized text tower embedding of one tokenized query, shape `(1, dim)`
+    pub fn get_text_embedding(&self, input_ids: &[u32]) -> Result<Tensor> {
+        let max_len = self.config.text_config.max_position_embeddings;
+        if input_ids.len() > max_len {
+            return Err(CandleError::Msg(format!(
+                "Query is {} tokens long, the text tower takes at most {}",
+                input_ids.len(),
+                max_len
+            )));
+        }
+        let ids = Tensor::new(input_ids, &self.device)?.unsqueeze(0)?;
+        let text_features = self.model.get_text_features(&ids)?;
+        self.div_l2_norm(&text_features)
+    }
+
+    /// L2 normalized text tower embeddings of `texts`, shape `(texts.len(), dim)`
+    ///
+    /// Tokenized with the tokenizer of [`Self::with_tokenizer`], a text longer than the text tower
+    /// takes is cut to fit.
+    pub fn get_texts_embedding_batched(&self, texts: &[&str]) -> Result<Tensor> {
+        let tokenizer = self.tokenizer.as_ref().ok_or_else(|| {
+            CandleError::Msg("No tokenizer loaded, see ClipWorker::with_tokenizer".to_owned())
+        })?;
+        let max_len = self.config.text_config.max_position_embeddings;
+        let pad_id = tokenizer
+            .token_to_id("<|endoftext|>")
+            .or_else(|| tokenizer.get_padding().map(|p| p.pad_id))
+            .unwrap_or(0);
+        let batches = run_batched(texts, &self.batch_size, self.auto_batch, |chunk| {
+            let start = Instant::now();
+            let encodings = tokenizer
+                .encode_batch(chunk.to_vec(), true)
+                .map_err(|e| CandleError::Msg(format!("Failed to tokenize texts: {}", e)))?;
+            let ids: Vec<&[u32]> = encodings.iter().map(|e| e.get_ids()).collect();
+            let (padded, len) = pad_token_ids(&ids, max_len, pad_id);
+            let ids = Tensor::from_vec(padded, (chunk.len(), len), &self.device)?;
+            let features = self.model.get_text_features(&ids)?;
+            metrics::histogram("clip_text_batch_seconds", &[]).observe_since(start);
+            metrics::counter("clip_texts_total", &[]).add(chunk.len() as u64);
+            Ok(features)
+        })?;
         let features = match batches.as_slice() {
             [single] => single.clone(),
             _ => Tensor::cat(&batches, 0)?,
@@ -151,10 +435,11 @@ impl ClipWorker {
     fn find_gif_embedding_clusters<'a, 'b, T>(
         &self,
         items: &'b [(TriageGifClip<'a>, Vec<T>)],
-    ) -> Vec<Vec<&'b TriageGifClip<'a>>>
+    ) -> anyhow::Result<Vec<Vec<&'b TriageGifClip<'a>>>>
     where
         T: WithDType + Cosine + Debug,
     {
+        let threshold = Thresholds::init()?.image_sim;
         let mut id_map = HashMap::with_capacity(items.len());
         for it in items {
             id_map.insert(it.0.id, it);
@@ -165,7 +450,7 @@ impl ClipWorker {
             for cl in clusters.iter_mut() {
                 let ok = cl.iter().all(|c| {
                     let vec_j = &id_map.get(&c.id).unwrap().1;
-                    cosine_sim(vec_i, vec_j) > IMAGE_SIM_THRESHOLD
+                    cosine_sim(vec_i, vec_j) > threshold
                 });
                 if ok {
                     cl.push(&it);
@@ -177,7 +462,53 @@ impl ClipWorker {
                 clusters.push(vec![&it]);
             }
         }
-        clusters
+        Ok(clusters)
+    }
+
+    /// Splits the members whose frames the frame check does not find in `kept` off the cluster,
+    /// with their coverage
+    ///
+    /// A GIF that fails to decode keeps the pooled decision.
+    fn frame_checked<'a, 'b>(
+        &self,
+        kept: &TriageGifClip<'a>,
+        members: Vec<&'b TriageGifClip<'a>>,
+    ) -> (
+        Vec<&'b TriageGifClip<'a>>,
+        Vec<(&'b TriageGifClip<'a>, f32)>,
+    ) {
+        let Some(check) = self.frame_check.as_ref() else {
+            return (members, Vec::new());
+        };
+        if members.is_empty() {
+            return (members, Vec::new());
+        }
+        let kept_hashes = match check.frame_hashes(kept.path) {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                tracing::warn!("Frame check skipped for the cluster of {}: {}", kept.id, e);
+                return (members, Vec::new());
+            }
+        };
+        let mut merged = Vec::with_capacity(members.len());
+        let mut unmerged = Vec::new();
+        for clip in members {
+            let hashes = match check.frame_hashes(clip.path) {
+                Ok(hashes) => hashes,
+                Err(e) => {
+                    tracing::warn!("Frame check skipped for {}: {}", clip.id, e);
+                    merged.push(clip);
+                    continue;
+                }
+            };
+            let coverage = check.align(&hashes, &kept_hashes).coverage();
+            tracing::debug!("Frame coverage of {} in {}: {}", clip.id, kept.id, coverage);
+            match coverage < check.min_coverage {
+                true => unmerged.push((clip, coverage)),
+                false => merged.push(clip),
+            }
+        }
+        (merged, unmerged)
     }
 
     pub fn get_images_embedding_adapted<'a, T>(
@@ -222,32 +553,45 @@ impl ClipWorker {
                     tracing::debug!("Items: {}", items.len());
                     // FIXME:
                     let clusters: Vec<Vec<&TriageGifClip<'a>>> =
-                        self.find_gif_embedding_clusters(&items);
+                        self.find_gif_embedding_clusters(&items)?;
                     tracing::debug!("Clusters: {}", clusters.len());
                     let mut max_clips = Vec::with_capacity(clusters.len());
                     let mut other_clips = Vec::with_capacity(items.len() - clusters.len());
+                    let mut unmerged = Vec::new();
+                    let to_gif = |clip: &TriageGifClip<'a>| TriageGif {
+                        uuid: clip.id,
+                        path: clip.path,
+                        size: clip.size,
+                    };
                     for cluster in clusters.iter() {
                         let (max_idx, &tgc) = cluster
                             .iter()
                             .enumerate()
                             .max_by_key(|&(_, clip)| clip.size)
                             .unwrap();
-                        max_clips.push(TriageGif {
-                            uuid: tgc.id,
-                            path: tgc.path,
-                            size: tgc.size,
-                        });
-                        other_clips.extend(
-                            cluster
-                                .iter()
-                                .take(max_idx)
-                                .chain(cluster.iter().skip(max_idx + 1))
-                                .map(|&clip| TriageGif {
-                                    uuid: clip.id,
-                                    path: clip.path,
-                                    size: clip.size,
-                                }),
-                        );
+                        max_clips.push(to_gif(tgc));
+                        let members = cluster
+                            .iter()
+                            .take(max_idx)
+                            .chain(cluster.iter().skip(max_idx + 1))
+                            .copied()
+                            .collect();
+                        let (merged, split) = self.frame_checked(tgc, members);
+                        for (clip, coverage) in split {
+                            tracing::info!(
+                                "Frame check keeps {} next to {}, coverage {:.2}",
+                                clip.id,
+                                tgc.id,
+                                coverage
+                            );
+                            max_clips.push(to_gif(clip));
+                            unmerged.push(UnmergedGif {
+                                gif: clip.id,
+                                kept: tgc.id,
+                                coverage,
+                            });
+                        }
+                        other_clips.extend(merged.into_iter().map(to_gif));
                     }
                     match kept {
                         Some(ref mut v) => v.extend(max_clips),
@@ -279,6 +623,7 @@ impl ClipWorker {
                     let res = TriageGifGroupsClipStagePair {
                         kept_gifs: kept,
                         discard_duplicate_gifs: discarded,
+                        unmerged_gifs: Some(unmerged).filter(|v| !v.is_empty()),
                     };
                     final_res.push(Some(Some(res)));
                 }
@@ -295,7 +640,7 @@ impl ClipWorker {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::gif_worker::GifWorker;
+    use crate::animated_worker::AnimatedMediaWorker;
     use anyhow::Result;
     use half::bf16;
     use shared::cosine_sim::cosine_sim;
@@ -336,7 +681,7 @@ mod tests {
         tracing_subscriber::registry().with(stdout).init();
         tracing::info!("Starting adapted worker test...");
         let clip_config = ClipConfig::baai_bge_vl_large();
-        let gif_worker = GifWorker::new(clip_config.image_size as u32);
+        let gif_worker = AnimatedMediaWorker::new(clip_config.image_size as u32);
         let model_path = PathBuf::from(env::var("CLIP_MODEL_PATH")?);
         let clip_worker = ClipWorker::new(
             model_path.to_str().unwrap(),
@@ -390,4 +735,123 @@ mod tests {
         println!("{:?}", clip_res);
         Ok(())
     }
+
+    #[test]
+    fn test_pad_token_ids() {
+        let (padded, len) = pad_token_ids(&[&[1, 2, 9], &[1, 9], &[1, 2, 3, 4, 5, 9]], 4, 0);
+        assert_eq!(len, 4);
+        assert_eq!(padded, vec![1, 2, 9, 0, 1, 9, 0, 0, 1, 2, 3, 9]);
+        assert_eq!(pad_token_ids(&[], 4, 0), (vec![], 0));
+    }
+
+    #[test]
+    fn test_run_batched_halves_on_oom() {
+        let items: Vec<u32> = (0..13).collect();
+        let mut seen = Vec::new();
+        let size = AtomicUsize::new(8);
+        let batches = run_batched(&items, &size, true, |chunk| {
+            seen.push(chunk.len());
+            match chunk.len() > 3 {
+                true => Err(CandleError::Msg("CUDA_ERROR_OUT_OF_MEMORY".to_owned())),
+                false => Tensor::zeros((chunk.len(), 2), DType::F32, &Device::Cpu),
+            }
+        })
+        .unwrap();
+        // 8 and 4 fail, 2 is kept for the rest
+        assert_eq!(seen, vec![8, 4, 2, 2, 2, 2, 2, 2, 1]);
+        assert_eq!(size.load(Ordering::Relaxed), 2);
+        let rows: usize = batches.iter().map(|b| b.dims()[0]).sum();
+        assert_eq!(rows, 13);
+
+        // fixed batches and other errors are passed through
+        let fixed = AtomicUsize::new(8);
+        let res = run_batched(&items, &fixed, false, |_| {
+            Err::<Tensor, _>(CandleError::Msg("out of memory".to_owned()))
+        });
+        assert!(res.is_err());
+        assert_eq!(fixed.load(Ordering::Relaxed), 8);
+        let res = run_batched(&items, &size, true, |_| {
+            Err::<Tensor, _>(CandleError::Msg("shape mismatch".to_owned()))
+        });
+        assert!(res.is_err());
+        assert_eq!(size.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_texts_embedding() -> Result<()> {
+        let model_path = PathBuf::from(env::var("CLIP_MODEL_PATH")?);
+        let worker = ClipWorker::new(
+            model_path.to_str().unwrap(),
+            ClipConfig::baai_bge_vl_large(),
+            DType::F32,
+            false,
+        )?
+        .with_tokenizer(None)?;
+        let long = "cat ".repeat(200);
+        let texts = ["a cat", "a photo of a cat", long.as_str()];
+        let embeddings = worker
+            .get_texts_embedding_batched(&texts)?
+            .to_vec2::<f32>()?;
+        assert_eq!(embeddings.len(), 3);
+        let single = worker.get_texts_embedding_batched(&texts[1..2])?;
+        let single = single.get(0)?.to_vec1::<f32>()?;
+        // padding to the batch's longest text does not change an embedding
+        assert!(cosine_sim(&embeddings[1], &single) > 0.999);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exif_orientation_to_raw() -> Result<()> {
+        use image::codecs::jpeg::JpegEncoder;
+        use image::{Rgb, RgbImage};
+        use shared::image_ext::set_jpeg_orientation;
+
+        let img = RgbImage::from_fn(96, 48, |x, y| Rgb([(x * 2) as u8, (y * 5) as u8, 64]));
+        let mut plain = Vec::new();
+        JpegEncoder::new_with_quality(&mut plain, 95).encode_image(&img)?;
+        let tagged = set_jpeg_orientation(&plain, Orientation::Rotate90).unwrap();
+        let dir = env::temp_dir();
+        let plain_path = dir.join(format!("{}.jpg", Uuid::new_v4()));
+        let tagged_path = dir.join(format!("{}.jpg", Uuid::new_v4()));
+        std::fs::write(&plain_path, &plain)?;
+        std::fs::write(&tagged_path, &tagged)?;
+        let tagged_path_str = tagged_path.to_str().unwrap();
+        // physically rotated counterpart
+        let upright = open_image(&plain_path, false)?.rotate90();
+        let expected = upright.to_raw(224, true)?;
+        assert_eq!(tagged_path_str.to_raw(224, true)?, expected);
+        assert_ne!(tagged_path_str.to_raw(224, false)?, expected);
+        let oriented = OrientedImage {
+            image: open_image(&tagged_path, false)?,
+            orientation: Orientation::Rotate90,
+        };
+        assert_eq!(oriented.to_raw(224, true)?, expected);
+        assert_ne!(oriented.to_raw(224, false)?, expected);
+        std::fs::remove_file(plain_path)?;
+        std::fs::remove_file(tagged_path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "remote-image")]
+    #[test]
+    fn test_embedding_from_urls() -> Result<()> {
+        use crate::remote_image::tests::{FIXTURE, serve};
+        let model_path = PathBuf::from(env::var("CLIP_MODEL_PATH")?);
+        let worker = ClipWorker::new(
+            model_path.to_str().unwrap(),
+            ClipConfig::baai_bge_vl_large(),
+            DType::F32,
+            false,
+        )?;
+        let addr = serve();
+        let fixture_url = format!("http://{}/bsn_0.jpg", addr);
+        let missing_url = format!("http://{}/missing.jpg", addr);
+        let res = worker.get_images_embedding_from_urls(&[&missing_url, &fixture_url]);
+        let err = res[0].as_ref().unwrap_err().to_string();
+        assert!(err.contains(&missing_url), "{}", err);
+        let local = worker.get_images_embedding_batched(&[FIXTURE])?;
+        let local = local.get(0)?.to_vec1::<f32>()?;
+        assert!(cosine_sim(res[1].as_ref().unwrap(), &local) > 0.999);
+        Ok(())
+    }
 }
@@ -0,0 +1,259 @@
+use serde::{Deserialize, Serialize};
+use shared::structure::NekoPoint;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use uuid::Uuid;
+
+/// What a point most likely shows, judged from its metadata alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentKind {
+    Screenshot,
+    Photo,
+    Unknown,
+}
+
+/// Thresholds of [`classify`], each matching feature votes for one kind
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContentKindConfig {
+    /// Portrait long side over short side from which a point votes screenshot, phone screens
+    /// and captured threads are far taller than any camera format
+    pub screenshot_min_aspect: f32,
+    /// Flat UI compresses well, at most this many bytes per pixel votes screenshot
+    pub screenshot_max_bytes_per_pixel: f32,
+    /// At least this many bytes per pixel votes photo
+    pub photo_min_bytes_per_pixel: f32,
+    /// At least this many pixels votes photo
+    pub photo_min_pixels: usize,
+    /// Long side over short side of camera formats, within `photo_aspect_tolerance` and without
+    /// text votes photo
+    pub photo_aspects: Vec<f32>,
+    pub photo_aspect_tolerance: f32,
+    /// Votes a kind needs, and more than the other kind, not to be unknown
+    pub min_votes: usize,
+}
+
+impl Default for ContentKindConfig {
+    fn default() -> Self {
+        Self {
+            screenshot_min_aspect: 1.9,
+            screenshot_max_bytes_per_pixel: 0.15,
+            photo_min_bytes_per_pixel: 0.35,
+            photo_min_pixels: 2_000_000,
+            photo_aspects: vec![4.0 / 3.0, 3.0 / 2.0, 16.0 / 9.0],
+            photo_aspect_tolerance: 0.02,
+            min_votes: 2,
+        }
+    }
+}
+
+impl ContentKindConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+}
+
+/// Pure function of the point, `size` is only used when known
+pub fn classify(point: &NekoPoint, config: &ContentKindConfig) -> ContentKind {
+    let Some(aspect) = point.aspect_ratio() else {
+        return ContentKind::Unknown;
+    };
+    let (width, height) = point.resolution();
+    let portrait = height > width;
+    let pixels = point.pixel_count();
+    let bytes_per_pixel = point.size.map(|size| size as f32 / pixels as f32);
+    let has_text = point.text_info.is_some();
+
+    let screenshot = [
+        has_text,
+        portrait && aspect >= config.screenshot_min_aspect,
+        bytes_per_pixel.is_some_and(|bpp| bpp <= config.screenshot_max_bytes_per_pixel),
+    ];
+    let photo = [
+        bytes_per_pixel.is_some_and(|bpp| bpp >= config.photo_min_bytes_per_pixel),
+        pixels >= config.photo_min_pixels,
+        !has_text
+            && config
+                .photo_aspects
+                .iter()
+                .any(|a| (aspect - a).abs() <= config.photo_aspect_tolerance),
+    ];
+    let votes = |features: &[bool]| features.iter().filter(|&&f| f).count();
+    let (screenshot, photo) = (votes(&screenshot), votes(&photo));
+    if screenshot >= config.min_votes && screenshot > photo {
+        ContentKind::Screenshot
+    } else if photo >= config.min_votes && photo > screenshot {
+        ContentKind::Photo
+    } else {
+        ContentKind::Unknown
+    }
+}
+
+/// One group per kind if the cluster holds both screenshots and photos, unknown points then
+/// form a group of their own, `None` if the cluster is not mixed
+pub fn split_by_kind<F>(cluster: &HashSet<Uuid>, kind_of: F) -> Option<Vec<HashSet<Uuid>>>
+where
+    F: Fn(&Uuid) -> ContentKind,
+{
+    let mut groups: BTreeMap<ContentKind, HashSet<Uuid>> = BTreeMap::new();
+    for id in cluster {
+        groups.entry(kind_of(id)).or_default().insert(*id);
+    }
+    let mixed =
+        groups.contains_key(&ContentKind::Screenshot) && groups.contains_key(&ContentKind::Photo);
+    mixed.then(|| groups.into_values().collect())
+}
+
+/// Replaces every mixed cluster with its [`split_by_kind`] groups, so the keep-one policy keeps
+/// the best point of each kind, also returns the index of the cluster each one came from
+pub fn split_mixed_clusters<I, F>(clusters: I, kind_of: F) -> (Vec<HashSet<Uuid>>, Vec<usize>)
+where
+    I: IntoIterator<Item = HashSet<Uuid>>,
+    F: Fn(&Uuid) -> ContentKind,
+{
+    let clusters = clusters.into_iter();
+    let mut split = Vec::with_capacity(clusters.size_hint().0);
+    let mut origin = Vec::with_capacity(clusters.size_hint().0);
+    for (idx, cluster) in clusters.into_iter().enumerate() {
+        match split_by_kind(&cluster, &kind_of) {
+            Some(groups) => {
+                origin.extend(std::iter::repeat_n(idx, groups.len()));
+                split.extend(groups);
+            }
+            None => {
+                split.push(cluster);
+                origin.push(idx);
+            }
+        }
+    }
+    (split, origin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::structure::NekoPointText;
+    use std::collections::HashMap;
+
+    fn point(width: usize, height: usize, size: Option<usize>, text: bool) -> NekoPoint {
+        NekoPoint {
+            id: Uuid::nil(),
+            height,
+            width,
+            size,
+            categories: None,
+            text_info: text.then(|| NekoPointText {
+                text: "@someone: look at this".to_string(),
+                text_vector: vec![0.0; 4],
+            }),
+            qdrant_num_id: None,
+            extra: None,
+        }
+    }
+
+    #[test]
+    fn test_classify_samples() {
+        let config = ContentKindConfig::default();
+        let samples = [
+            // phone screenshot of a tweet, PNG of flat UI
+            (
+                point(1170, 2532, Some(350_000), true),
+                ContentKind::Screenshot,
+            ),
+            // same without OCR text, still tall and flat
+            (
+                point(1080, 2400, Some(300_000), false),
+                ContentKind::Screenshot,
+            ),
+            // desktop capture with text but a camera-like size
+            (
+                point(1920, 1080, Some(200_000), true),
+                ContentKind::Screenshot,
+            ),
+            // 12MP camera JPEG
+            (
+                point(4032, 3024, Some(3_500_000), false),
+                ContentKind::Photo,
+            ),
+            // 3:2 photo of unknown size
+            (point(3000, 2000, None, false), ContentKind::Photo),
+            // small square thumbnail, no vote either way
+            (point(512, 512, Some(60_000), false), ContentKind::Unknown),
+            // a photo with a caption, one vote each
+            (point(1600, 1200, Some(700_000), true), ContentKind::Unknown),
+            (point(0, 100, Some(10), false), ContentKind::Unknown),
+        ];
+        for (i, (point, kind)) in samples.iter().enumerate() {
+            assert_eq!(classify(point, &config), *kind, "sample {i}");
+        }
+        let strict = ContentKindConfig {
+            min_votes: 3,
+            ..ContentKindConfig::default()
+        };
+        assert_eq!(classify(&samples[0].0, &strict), ContentKind::Screenshot);
+        assert_eq!(classify(&samples[1].0, &strict), ContentKind::Unknown);
+    }
+
+    #[test]
+    fn test_config_partial_json() {
+        let config: ContentKindConfig = serde_json::from_str(r#"{"min_votes": 3}"#).unwrap();
+        assert_eq!(config.min_votes, 3);
+        assert_eq!(config.photo_min_pixels, 2_000_000);
+    }
+
+    fn kinds(entries: &[(u128, ContentKind)]) -> HashMap<Uuid, ContentKind> {
+        entries
+            .iter()
+            .map(|&(id, kind)| (Uuid::from_u128(id), kind))
+            .collect()
+    }
+
+    fn set(ids: &[u128]) -> HashSet<Uuid> {
+        ids.iter().copied().map(Uuid::from_u128).collect()
+    }
+
+    #[test]
+    fn test_split_by_kind() {
+        use ContentKind::*;
+        let kind = kinds(&[
+            (1, Screenshot),
+            (2, Photo),
+            (3, Photo),
+            (4, Unknown),
+            (5, Screenshot),
+        ]);
+        let kind_of = |id: &Uuid| kind[id];
+        assert_eq!(
+            split_by_kind(&set(&[1, 2, 3, 4]), kind_of),
+            Some(vec![set(&[1]), set(&[2, 3]), set(&[4])])
+        );
+        assert_eq!(
+            split_by_kind(&set(&[1, 5, 2]), kind_of),
+            Some(vec![set(&[1, 5]), set(&[2])])
+        );
+        // unknown points alone do not make a cluster mixed
+        assert_eq!(split_by_kind(&set(&[2, 3, 4]), kind_of), None);
+        assert_eq!(split_by_kind(&set(&[1, 4, 5]), kind_of), None);
+        assert_eq!(split_by_kind(&set(&[]), kind_of), None);
+    }
+
+    #[test]
+    fn test_split_mixed_clusters() {
+        use ContentKind::*;
+        let kind = kinds(&[
+            (1, Screenshot),
+            (2, Photo),
+            (3, Photo),
+            (4, Photo),
+            (5, Unknown),
+        ]);
+        let clusters = vec![set(&[3, 4]), set(&[1, 2, 5]), set(&[4, 5])];
+        let (split, origin) = split_mixed_clusters(clusters, |id| kind[id]);
+        assert_eq!(
+            split,
+            vec![set(&[3, 4]), set(&[1]), set(&[2]), set(&[5]), set(&[4, 5])]
+        );
+        assert_eq!(origin, vec![0, 1, 1, 1, 2]);
+    }
+}
@@ -0,0 +1,303 @@
+//! Accounting of every input point to exactly one terminal bucket, `unaccounted_points.json`
+//!
+//! Stages register the points they saw and the ones they set aside in a [`CoverageSink`], the
+//! final classification supplies the kept and deleted ones. A point in no bucket fell through
+//! the cracks somewhere after the last stage that saw it.
+
+use serde::{Deserialize, Serialize};
+use shared::structure::{
+    FinalClassification, TriageGifGroupsClipStagePair, TriageGifGroupsGifStagePair,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Pipeline order, a later stage seeing a point supersedes an earlier one
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Load,
+    Extract,
+    Schedule,
+    Download,
+    Refine,
+    Group,
+    Classify,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Terminal {
+    Kept,
+    Deleted,
+    /// Left for the next run, e.g. out of time budget
+    Deferred,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetAside {
+    pub stage: Stage,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+struct Ledger {
+    last_seen: HashMap<Uuid, Stage>,
+    deferred: HashMap<Uuid, SetAside>,
+    failed: HashMap<Uuid, SetAside>,
+}
+
+/// Shared by the stages, every method takes `&self`
+#[derive(Debug, Default)]
+pub struct CoverageSink {
+    ledger: Mutex<Ledger>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unaccounted {
+    pub id: Uuid,
+    /// `None` if no stage registered the point
+    pub last_stage: Option<Stage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflicting {
+    pub id: Uuid,
+    pub buckets: Vec<Terminal>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageReport {
+    pub input_points: usize,
+    /// Points of the input in each bucket, a conflicting point counts in all of its buckets
+    pub buckets: BTreeMap<Terminal, usize>,
+    pub unaccounted: Vec<Unaccounted>,
+    /// Points in more than one bucket
+    pub conflicting: Vec<Conflicting>,
+    /// Set aside points with their stage and reason
+    pub set_aside: BTreeMap<Uuid, SetAside>,
+}
+
+impl CoverageSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn saw<'a>(&self, stage: Stage, ids: impl IntoIterator<Item = &'a Uuid>) {
+        let mut ledger = self.ledger.lock().unwrap();
+        for id in ids {
+            let last = ledger.last_seen.entry(*id).or_insert(stage);
+            *last = (*last).max(stage);
+        }
+    }
+
+    pub fn defer<'a>(&self, stage: Stage, ids: impl IntoIterator<Item = &'a Uuid>, reason: &str) {
+        self.set_aside(stage, ids, reason, Terminal::Deferred)
+    }
+
+    pub fn fail<'a>(&self, stage: Stage, ids: impl IntoIterator<Item = &'a Uuid>, reason: &str) {
+        self.set_aside(stage, ids, reason, Terminal::Failed)
+    }
+
+    fn set_aside<'a>(
+        &self,
+        stage: Stage,
+        ids: impl IntoIterator<Item = &'a Uuid>,
+        reason: &str,
+        terminal: Terminal,
+    ) {
+        let mut ledger = self.ledger.lock().unwrap();
+        for id in ids {
+            let last = ledger.last_seen.entry(*id).or_insert(stage);
+            *last = (*last).max(stage);
+            let entry = SetAside {
+                stage,
+                reason: reason.to_string(),
+            };
+            match terminal {
+                Terminal::Deferred => ledger.deferred.insert(*id, entry),
+                _ => ledger.failed.insert(*id, entry),
+            };
+        }
+    }
+
+    /// Buckets of every member of `input`, from the sink and from `classified`
+    pub fn reconcile(
+        &self,
+        input: &[HashSet<Uuid>],
+        classified: &[FinalClassification],
+    ) -> CoverageReport {
+        let ledger = self.ledger.lock().unwrap();
+        let mut buckets_of: HashMap<Uuid, Vec<Terminal>> = HashMap::new();
+        for fc in classified {
+            for id in fc.kept() {
+                buckets_of.entry(id).or_default().push(Terminal::Kept);
+            }
+            for id in fc.discarded() {
+                buckets_of.entry(id).or_default().push(Terminal::Deleted);
+            }
+        }
+        for (ids, terminal) in [
+            (&ledger.deferred, Terminal::Deferred),
+            (&ledger.failed, Terminal::Failed),
+        ] {
+            for id in ids.keys() {
+                buckets_of.entry(*id).or_default().push(terminal);
+            }
+        }
+        let members: HashSet<&Uuid> = input.iter().flatten().collect();
+        let mut members: Vec<&Uuid> = members.into_iter().collect();
+        members.sort_unstable();
+        let mut report = CoverageReport {
+            input_points: members.len(),
+            buckets: BTreeMap::new(),
+            unaccounted: Vec::new(),
+            conflicting: Vec::new(),
+            set_aside: BTreeMap::new(),
+        };
+        for id in members {
+            let mut buckets = buckets_of.remove(id).unwrap_or_default();
+            buckets.sort_unstable();
+            for bucket in &buckets {
+                *report.buckets.entry(*bucket).or_default() += 1;
+            }
+            match buckets.len() {
+                0 => report.unaccounted.push(Unaccounted {
+                    id: *id,
+                    last_stage: ledger.last_seen.get(id).copied(),
+                }),
+                1 => {}
+                _ => report.conflicting.push(Conflicting { id: *id, buckets }),
+            }
+            if let Some(entry) = ledger.failed.get(id).or_else(|| ledger.deferred.get(id)) {
+                report.set_aside.insert(*id, entry.clone());
+            }
+        }
+        report
+    }
+}
+
+/// GIFs the GIF stage decided on or sent on to the CLIP stage
+pub fn refined<'a>(pair: &TriageGifGroupsGifStagePair<'a>) -> Vec<&'a Uuid> {
+    let invalid = pair.invalid_gif_id.iter().flat_map(|(ids, _)| ids);
+    let same_frame = pair.discard_same_frame_gif_id.iter().flatten();
+    let to_clip = pair.gif_metadata.iter().flat_map(|m| m.keys());
+    invalid.chain(same_frame).chain(to_clip).copied().collect()
+}
+
+/// GIFs the CLIP stage kept or found duplicate
+pub fn grouped<'a>(pair: &TriageGifGroupsClipStagePair<'a>) -> Vec<&'a Uuid> {
+    pair.kept_gifs
+        .iter()
+        .chain(&pair.discard_duplicate_gifs)
+        .flatten()
+        .map(|gif| gif.uuid)
+        .collect()
+}
+
+impl CoverageReport {
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.unaccounted.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::test_util::id;
+
+    fn classification(kept: &[u128], deleted: &[u128]) -> FinalClassification {
+        let ids = |ns: &[u128]| (!ns.is_empty()).then(|| ns.iter().copied().map(id).collect());
+        FinalClassification {
+            kept_text_anomalies_group: ids(kept),
+            other_need_delete_group: ids(deleted),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_full_coverage() {
+        let input: Vec<HashSet<Uuid>> = vec![[id(1), id(2)].into(), [id(3), id(4)].into()];
+        let sink = CoverageSink::new();
+        sink.saw(Stage::Extract, &[id(1), id(2), id(3), id(4)]);
+        sink.defer(Stage::Schedule, &[id(3)], "time budget");
+        sink.fail(Stage::Download, &[id(4)], "timeout");
+        let report = sink.reconcile(&input, &[classification(&[1], &[2])]);
+        assert!(report.is_complete() && report.conflicting.is_empty());
+        assert_eq!(report.input_points, 4);
+        assert_eq!(
+            report.buckets,
+            BTreeMap::from([
+                (Terminal::Kept, 1),
+                (Terminal::Deleted, 1),
+                (Terminal::Deferred, 1),
+                (Terminal::Failed, 1),
+            ])
+        );
+        assert_eq!(report.set_aside[&id(4)].reason, "timeout");
+    }
+
+    #[test]
+    fn test_drop_after_each_stage() {
+        let input: Vec<HashSet<Uuid>> = vec![[id(1), id(2)].into()];
+        let stages = [
+            Stage::Load,
+            Stage::Extract,
+            Stage::Schedule,
+            Stage::Download,
+            Stage::Refine,
+            Stage::Group,
+            Stage::Classify,
+        ];
+        for (i, &dropped_at) in stages.iter().enumerate() {
+            let sink = CoverageSink::new();
+            for &stage in &stages[..=i] {
+                sink.saw(stage, &[id(1), id(2)]);
+            }
+            // point 2 is seen up to `dropped_at`, then no stage and no entry reports it
+            for &stage in &stages[i + 1..] {
+                sink.saw(stage, &[id(1)]);
+            }
+            let report = sink.reconcile(&input, &[classification(&[1], &[])]);
+            assert_eq!(
+                report.unaccounted,
+                vec![Unaccounted {
+                    id: id(2),
+                    last_stage: Some(dropped_at),
+                }],
+                "dropped after {:?}",
+                dropped_at
+            );
+        }
+        let never_seen = CoverageSink::new().reconcile(&input, &[classification(&[1], &[])]);
+        assert_eq!(never_seen.unaccounted[0].last_stage, None);
+    }
+
+    #[test]
+    fn test_conflicting_buckets() {
+        let input: Vec<HashSet<Uuid>> = vec![[id(1), id(2)].into()];
+        let sink = CoverageSink::new();
+        sink.fail(Stage::Download, &[id(2)], "not found");
+        // a failed download the GIF stage then deletes as invalid, and a point two entries hold
+        let report = sink.reconcile(
+            &input,
+            &[classification(&[1], &[2]), classification(&[], &[1])],
+        );
+        assert!(report.is_complete());
+        assert_eq!(
+            report.conflicting,
+            vec![
+                Conflicting {
+                    id: id(1),
+                    buckets: vec![Terminal::Kept, Terminal::Deleted],
+                },
+                Conflicting {
+                    id: id(2),
+                    buckets: vec![Terminal::Deleted, Terminal::Failed],
+                },
+            ]
+        );
+    }
+}
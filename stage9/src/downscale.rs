@@ -0,0 +1,176 @@
+//! Downscaling of oversized GIFs as they are downloaded, triage only needs frames at CLIP's input
+//! size so the full-size original never has to reach the disk
+
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::imageops::FilterType;
+use image::{AnimationDecoder, Frame, ImageDecoder, ImageResult};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use uuid::Uuid;
+
+/// NeuQuant speed of the re-encoding, 1 is the best quality and 30 the fastest
+const ENCODE_SPEED: i32 = 10;
+
+/// What the downloader stored for one GIF, the audit trail of `--max-download-dimension`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredGif {
+    pub id: Uuid,
+    pub original_bytes: u64,
+    pub stored_bytes: u64,
+    /// Canvas of the download, `None` if it is no readable GIF
+    pub original_dimensions: Option<(u32, u32)>,
+    /// Canvas of the stored copy if it was downscaled
+    pub downscaled_to: Option<(u32, u32)>,
+}
+
+/// Canvas size read from the header alone
+pub fn gif_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    GifDecoder::new(Cursor::new(bytes))
+        .ok()
+        .map(|d| d.dimensions())
+}
+
+/// `(width, height)` scaled to fit `max` on both sides, aspect ratio kept and never below 1px
+pub fn fit_within((width, height): (u32, u32), max: u32) -> (u32, u32) {
+    if width <= max && height <= max {
+        return (width, height);
+    }
+    let scale = max as f64 / width.max(height) as f64;
+    let fit = |side: u32| ((side as f64 * scale).round() as u32).clamp(1, max);
+    (fit(width), fit(height))
+}
+
+/// Re-encodes `bytes` with its canvas fit within `max_dimension`, frame by frame so only one
+/// frame is decoded at a time, keeping every frame and its delay
+///
+/// `Ok(None)` when the GIF already fits.
+pub fn downscale_gif(
+    bytes: &[u8],
+    max_dimension: u32,
+) -> ImageResult<Option<(Vec<u8>, (u32, u32))>> {
+    let decoder = GifDecoder::new(Cursor::new(bytes))?;
+    let original = decoder.dimensions();
+    let (width, height) = fit_within(original, max_dimension);
+    if (width, height) == original {
+        return Ok(None);
+    }
+    let mut out = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut out, ENCODE_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+        for frame in decoder.into_frames() {
+            let frame = frame?;
+            let delay = frame.delay();
+            // frames come composited onto the full canvas, offsets no longer apply
+            let resized =
+                image::imageops::resize(frame.buffer(), width, height, FilterType::Triangle);
+            encoder.encode_frame(Frame::from_parts(resized, 0, 0, delay))?;
+        }
+    }
+    Ok(Some((out, (width, height))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animated_worker::AnimatedMediaWorker;
+    use shared::fixtures::{GifMotion, GifSpec, gif_bytes};
+    use shared::structure::{TriageGif, TriageGifPair};
+    use std::path::{Path, PathBuf};
+
+    fn frames(bytes: &[u8]) -> Vec<Frame> {
+        GifDecoder::new(Cursor::new(bytes))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap()
+    }
+
+    fn write(dir: &Path, name: &str, bytes: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_fit_within() {
+        assert_eq!(fit_within((300, 150), 100), (100, 50));
+        assert_eq!(fit_within((150, 300), 100), (50, 100));
+        assert_eq!(fit_within((80, 60), 100), (80, 60));
+        assert_eq!(fit_within((5000, 2), 100), (100, 1));
+    }
+
+    #[test]
+    fn test_fitting_gif_untouched() {
+        let spec = GifSpec {
+            frames: 3,
+            motion: GifMotion::Moving,
+        };
+        let bytes = gif_bytes(64, 0, spec).unwrap();
+        assert_eq!(gif_dimensions(&bytes), Some((64, 64)));
+        assert!(downscale_gif(&bytes, 64).unwrap().is_none());
+        assert!(downscale_gif(b"not a gif", 64).is_err());
+    }
+
+    #[test]
+    fn test_downscaled_classifies_like_original() {
+        let dir = std::env::temp_dir().join(format!("downscale_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let worker = AnimatedMediaWorker::new(32);
+        let ids: Vec<Uuid> = (1..=2).map(Uuid::from_u128).collect();
+        let mut originals = Vec::new();
+        let mut stored = Vec::new();
+        for (idx, motion) in [GifMotion::Moving, GifMotion::Still]
+            .into_iter()
+            .enumerate()
+        {
+            let original = gif_bytes(512, idx, GifSpec { frames: 8, motion }).unwrap();
+            let (small, canvas) = downscale_gif(&original, 128).unwrap().unwrap();
+            assert_eq!(canvas, (128, 128));
+            assert_eq!(gif_dimensions(&small), Some(canvas));
+            assert!(small.len() < original.len(), "{} bytes", small.len());
+            let delays =
+                |bytes: &[u8]| -> Vec<_> { frames(bytes).iter().map(|f| f.delay()).collect() };
+            assert_eq!(delays(&small), delays(&original));
+            originals.push(write(&dir, &format!("{}_original.gif", idx), &original));
+            stored.push(write(&dir, &format!("{}_stored.gif", idx), &small));
+        }
+        let classify = |paths: &[PathBuf], ids: &[Uuid]| {
+            let paths: Vec<&str> = paths.iter().map(|p| p.to_str().unwrap()).collect();
+            let pair: TriageGifPair = ids
+                .iter()
+                .zip(paths)
+                .map(|(uuid, path)| TriageGif {
+                    uuid,
+                    path,
+                    size: 0,
+                })
+                .collect();
+            let gifs = [Some(pair)];
+            let res = worker.process(&gifs).unwrap().remove(0).unwrap();
+            let clip: Vec<Uuid> = res
+                .prepare_clip_gif_pair
+                .unwrap_or_default()
+                .iter()
+                .map(|c| *c.id)
+                .collect();
+            let still: Vec<Uuid> = res
+                .discard_same_frame_gif_id
+                .unwrap_or_default()
+                .into_iter()
+                .copied()
+                .collect();
+            let frame_counts: Vec<usize> = res
+                .gif_metadata
+                .unwrap_or_default()
+                .values()
+                .map(|m| m.frame_count)
+                .collect();
+            (clip, still, frame_counts)
+        };
+        let (clip, still, counts) = classify(&originals, &ids);
+        assert_eq!((clip, still, counts), (vec![ids[0]], vec![ids[1]], vec![8]));
+        assert_eq!(classify(&originals, &ids), classify(&stored, &ids));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,342 @@
+//! Quick mode: triage a random sample of the clusters and extrapolate to all of them
+//!
+//! Clusters are stratified by size bucket. Every bucket draws from its own generator derived
+//! from the seed, so one bucket's sample does not move when another bucket grows. Totals are the
+//! bucket's sample mean times its cluster count, their intervals the normal approximation with the
+//! finite population correction.
+
+use crate::savings::GB;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use serde::Serialize;
+use shared::structure::SampleInfo;
+use std::collections::BTreeMap;
+
+/// z of a two-sided 95% interval
+const Z_95: f64 = 1.959_964;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SizeBucket {
+    Pairs,
+    Small,
+    Medium,
+    Large,
+}
+
+impl SizeBucket {
+    pub fn of(len: usize) -> Self {
+        match len {
+            0..=2 => SizeBucket::Pairs,
+            3..=5 => SizeBucket::Small,
+            6..=20 => SizeBucket::Medium,
+            _ => SizeBucket::Large,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stratum {
+    pub bucket: SizeBucket,
+    /// Clusters of the bucket
+    pub clusters: usize,
+    /// Indices of the sampled ones, sorted
+    pub sampled: Vec<usize>,
+}
+
+/// Samples about `fraction` of the clusters, given by their sizes
+///
+/// The sample is shared out between buckets by their clusters or, `by_size`, by their points,
+/// which gives the few large clusters a bigger part. A bucket gets two clusters at least, so that
+/// its spread can be estimated.
+pub fn sample_clusters(sizes: &[usize], sample: &SampleInfo) -> Vec<Stratum> {
+    let mut buckets: BTreeMap<SizeBucket, Vec<usize>> = BTreeMap::new();
+    for (idx, &len) in sizes.iter().enumerate() {
+        buckets.entry(SizeBucket::of(len)).or_default().push(idx);
+    }
+    let weight = |indices: &[usize]| -> f64 {
+        match sample.by_size {
+            true => indices.iter().map(|&idx| sizes[idx]).sum::<usize>() as f64,
+            false => indices.len() as f64,
+        }
+    };
+    let total_weight: f64 = buckets.values().map(|indices| weight(indices)).sum();
+    let target = (sample.fraction.clamp(0.0, 1.0) * sizes.len() as f64).ceil();
+    buckets
+        .into_iter()
+        .map(|(bucket, indices)| {
+            let share = match total_weight > 0.0 {
+                true => (target * weight(&indices) / total_weight).round() as usize,
+                false => 0,
+            };
+            let amount = share.max(2).min(indices.len());
+            let mut rng = Pcg64::seed_from_u64(sample.seed.wrapping_add(bucket as u64));
+            let mut picked = rand::seq::index::sample(&mut rng, indices.len(), amount).into_vec();
+            picked.sort_unstable();
+            Stratum {
+                bucket,
+                clusters: indices.len(),
+                sampled: picked.into_iter().map(|i| indices[i]).collect(),
+            }
+        })
+        .collect()
+}
+
+/// A total extrapolated from a sample, with its 95% interval
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct Estimate {
+    /// Sum over the sampled clusters, the total is at least that
+    pub sampled: f64,
+    pub total: f64,
+    pub std_error: f64,
+    pub low: f64,
+    pub high: f64,
+}
+
+impl Estimate {
+    fn new(sampled: f64, total: f64, std_error: f64) -> Self {
+        Self {
+            sampled,
+            total,
+            std_error,
+            low: (total - Z_95 * std_error).max(sampled),
+            high: total + Z_95 * std_error,
+        }
+    }
+
+    /// Total of one bucket of `clusters` clusters from the values of its sampled ones
+    ///
+    /// A single sampled cluster has no spread, its bucket gets a zero-width interval.
+    pub fn stratum(clusters: usize, values: &[f64]) -> Self {
+        let n = values.len();
+        if n == 0 {
+            return Self::default();
+        }
+        let sampled: f64 = values.iter().sum();
+        let mean = sampled / n as f64;
+        let variance = match n {
+            1 => 0.0,
+            _ => values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64,
+        };
+        let fpc = (1.0 - n as f64 / clusters as f64).max(0.0);
+        let std_error = clusters as f64 * (fpc * variance / n as f64).sqrt();
+        Self::new(sampled, mean * clusters as f64, std_error)
+    }
+
+    /// Total over independent buckets
+    pub fn combine<'a>(estimates: impl IntoIterator<Item = &'a Estimate>) -> Self {
+        let (sampled, total, variance) =
+            estimates
+                .into_iter()
+                .fold((0.0, 0.0, 0.0), |(sampled, total, variance), e| {
+                    (
+                        sampled + e.sampled,
+                        total + e.total,
+                        variance + e.std_error.powi(2),
+                    )
+                });
+        Self::new(sampled, total, variance.sqrt())
+    }
+
+    fn scaled(self, by: f64) -> Self {
+        Self {
+            sampled: self.sampled * by,
+            total: self.total * by,
+            std_error: self.std_error * by,
+            low: self.low * by,
+            high: self.high * by,
+        }
+    }
+}
+
+/// What the triage of one cluster produced
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ClusterOutcome {
+    pub deletions: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StratumEstimate {
+    pub bucket: SizeBucket,
+    pub clusters: usize,
+    pub sampled: usize,
+    pub deletions: Estimate,
+    pub bytes: Estimate,
+}
+
+/// `estimate_report.json`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EstimateReport {
+    pub sample: SampleInfo,
+    pub confidence: f64,
+    pub clusters: usize,
+    pub sampled_clusters: usize,
+    pub deletions: Estimate,
+    pub bytes: Estimate,
+    pub gb: Estimate,
+    pub strata: Vec<StratumEstimate>,
+}
+
+impl EstimateReport {
+    /// `outcome(idx)` is the triage result of sampled cluster `idx`
+    pub fn new<F>(sample: SampleInfo, strata: &[Stratum], outcome: F) -> Self
+    where
+        F: Fn(usize) -> ClusterOutcome,
+    {
+        let strata: Vec<StratumEstimate> = strata
+            .iter()
+            .map(|stratum| {
+                let outcomes: Vec<ClusterOutcome> =
+                    stratum.sampled.iter().map(|&idx| outcome(idx)).collect();
+                let values = |f: fn(&ClusterOutcome) -> u64| -> Vec<f64> {
+                    outcomes.iter().map(|o| f(o) as f64).collect()
+                };
+                StratumEstimate {
+                    bucket: stratum.bucket,
+                    clusters: stratum.clusters,
+                    sampled: stratum.sampled.len(),
+                    deletions: Estimate::stratum(stratum.clusters, &values(|o| o.deletions)),
+                    bytes: Estimate::stratum(stratum.clusters, &values(|o| o.bytes)),
+                }
+            })
+            .collect();
+        let bytes = Estimate::combine(strata.iter().map(|s| &s.bytes));
+        Self {
+            sample,
+            confidence: 0.95,
+            clusters: strata.iter().map(|s| s.clusters).sum(),
+            sampled_clusters: strata.iter().map(|s| s.sampled).sum(),
+            deletions: Estimate::combine(strata.iter().map(|s| &s.deletions)),
+            bytes,
+            gb: bytes.scaled(1.0 / GB),
+            strata,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(fraction: f64, seed: u64, by_size: bool) -> SampleInfo {
+        SampleInfo {
+            fraction,
+            seed,
+            by_size,
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_sample_clusters() {
+        // 900 pairs, 90 of 3-5, 10 of 50
+        let sizes: Vec<usize> = [(900, 2), (90, 4), (10, 50)]
+            .into_iter()
+            .flat_map(|(n, len)| std::iter::repeat_n(len, n))
+            .collect();
+        let strata = sample_clusters(&sizes, &sample(0.1, 7, false));
+        let amounts: Vec<(SizeBucket, usize, usize)> = strata
+            .iter()
+            .map(|s| (s.bucket, s.clusters, s.sampled.len()))
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                (SizeBucket::Pairs, 900, 90),
+                (SizeBucket::Small, 90, 9),
+                (SizeBucket::Large, 10, 2)
+            ]
+        );
+        for stratum in &strata {
+            assert!(stratum.sampled.is_sorted());
+            assert!(
+                stratum
+                    .sampled
+                    .iter()
+                    .all(|&idx| SizeBucket::of(sizes[idx]) == stratum.bucket)
+            );
+        }
+        assert_eq!(strata, sample_clusters(&sizes, &sample(0.1, 7, false)));
+        assert_ne!(strata, sample_clusters(&sizes, &sample(0.1, 8, false)));
+
+        // by points: 1800, 360 and 500 of 2660
+        let by_size = sample_clusters(&sizes, &sample(0.1, 7, true));
+        let amounts: Vec<usize> = by_size.iter().map(|s| s.sampled.len()).collect();
+        assert_eq!(amounts, vec![68, 14, 10]);
+    }
+
+    #[test]
+    fn test_sample_small_buckets() {
+        let strata = sample_clusters(&[2, 2, 2, 9], &sample(0.01, 1, false));
+        let amounts: Vec<usize> = strata.iter().map(|s| s.sampled.len()).collect();
+        assert_eq!(amounts, vec![2, 1]);
+        assert!(sample_clusters(&[], &sample(0.5, 1, true)).is_empty());
+    }
+
+    #[test]
+    fn test_stratum_estimate() {
+        let estimate = Estimate::stratum(100, &[1.0, 3.0, 2.0, 6.0]);
+        assert_close(estimate.sampled, 12.0);
+        assert_close(estimate.total, 300.0);
+        // s² = 14/3, se = 100 * sqrt(0.96 * s² / 4)
+        assert_close(
+            estimate.std_error,
+            100.0 * (0.96f64 * 14.0 / 3.0 / 4.0).sqrt(),
+        );
+        assert_close(estimate.high - estimate.total, Z_95 * estimate.std_error);
+        assert_close(estimate.total - estimate.low, Z_95 * estimate.std_error);
+
+        // fully enumerated, no uncertainty left
+        let full = Estimate::stratum(3, &[1.0, 2.0, 4.0]);
+        assert_eq!(
+            (full.total, full.std_error, full.low, full.high),
+            (7.0, 0.0, 7.0, 7.0)
+        );
+
+        // never below what the sample itself holds
+        let skewed = Estimate::stratum(10, &[0.0, 0.0, 100.0]);
+        assert_close(skewed.low, 100.0);
+        assert_eq!(Estimate::stratum(10, &[]), Estimate::default());
+    }
+
+    #[test]
+    fn test_combine() {
+        let a = Estimate::stratum(100, &[1.0, 3.0]);
+        let b = Estimate::stratum(10, &[5.0, 5.0, 5.0]);
+        let total = Estimate::combine([&a, &b]);
+        assert_close(total.total, 200.0 + 50.0);
+        assert_close(total.sampled, 19.0);
+        assert_close(total.std_error, a.std_error);
+        assert_eq!(Estimate::combine([]), Estimate::default());
+    }
+
+    #[test]
+    fn test_report() {
+        let strata = vec![
+            Stratum {
+                bucket: SizeBucket::Pairs,
+                clusters: 10,
+                sampled: vec![0, 1],
+            },
+            Stratum {
+                bucket: SizeBucket::Large,
+                clusters: 2,
+                sampled: vec![10, 11],
+            },
+        ];
+        let report = EstimateReport::new(sample(0.2, 1, false), &strata, |idx| ClusterOutcome {
+            deletions: if idx < 10 { 1 } else { 30 },
+            bytes: if idx < 10 { GB as u64 } else { 0 },
+        });
+        assert_eq!((report.clusters, report.sampled_clusters), (12, 4));
+        assert_close(report.deletions.total, 10.0 + 60.0);
+        assert_close(report.deletions.std_error, 0.0);
+        assert_close(report.gb.total, 10.0);
+        assert_close(report.bytes.total, 10.0 * GB);
+        assert_eq!(report.strata[1].deletions.sampled, 60.0);
+    }
+}
@@ -0,0 +1,628 @@
+//! `explanations.jsonl`, every signal behind the decision on a cluster in one line per cluster
+//!
+//! The schema is versioned by [`EXPLANATION_SCHEMA_VERSION`], bump it with any change to the
+//! serialized shape, the golden test of `main.rs` fails on unintended ones.
+
+use crate::text_cluster::{TextClusters, TextStrategy};
+use serde::{Deserialize, Serialize};
+use shared::atomic_write::atomic_write_with;
+use shared::cosine_sim::cosine_sim;
+use shared::structure::{FinalClassification, GifMeta, NekoPoint, NekoPointExt};
+use shared::text::is_garbage;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+pub const EXPLANATION_SCHEMA_VERSION: u32 = 3;
+
+/// Candidates listed after each representative
+pub const RUNNERS_UP: usize = 3;
+
+/// Final group of a member, named after the `final_classification.json` region it is in
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Assignment {
+    KeptTextAnomaly,
+    KeptGif,
+    KeptNonGif,
+    KeptByReview,
+    DeletedInvalidGif,
+    DeletedSameFrameGif,
+    DeletedDuplicateGif,
+    DeletedOther,
+    /// In no region, e.g. a GIF whose cluster ran out of time budget
+    Undecided,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExplainedMember {
+    pub id: Uuid,
+    pub size: Option<usize>,
+    pub assignment: Assignment,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PairSimilarity {
+    pub a: Uuid,
+    pub b: Uuid,
+    pub similarity: f32,
+}
+
+/// Members whose texts are all above the `text_sim` threshold of each other
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextSubcluster {
+    pub members: Vec<Uuid>,
+    pub similarities: Vec<PairSimilarity>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GifVerdict {
+    Invalid,
+    SameFrame,
+    Kept,
+    Duplicate,
+}
+
+/// Kept GIF the frame check split this one off from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Unmerged {
+    pub kept: Uuid,
+    pub coverage: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GifOutcome {
+    pub id: Uuid,
+    pub verdict: GifVerdict,
+    /// Decode error of an invalid GIF
+    pub reason: Option<String>,
+    pub meta: Option<GifMeta>,
+    pub unmerged: Option<Unmerged>,
+}
+
+/// Closest kept member of a deleted one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeptSimilarity {
+    pub deleted: Uuid,
+    pub kept: Uuid,
+    pub similarity: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepresentativeReason {
+    TextAnomaly,
+    NonGif,
+    Gif,
+    Reviewed,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepresentativePolicy {
+    LargestSize,
+    /// Largest of the points whose OCR text is not garbage, of all of them if there are none
+    ReadableTextThenLargestSize,
+    Manual,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub id: Uuid,
+    pub size: Option<usize>,
+    /// Its OCR text scores as garbage, only set under
+    /// [`RepresentativePolicy::ReadableTextThenLargestSize`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub garbage_text: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Representative {
+    pub id: Uuid,
+    pub reason: RepresentativeReason,
+    pub policy: RepresentativePolicy,
+    /// Next candidates of the policy, in its order
+    pub runners_up: Vec<Candidate>,
+}
+
+/// One line of `explanations.jsonl`, every list sorted so reruns give the same bytes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterExplanation {
+    pub schema_version: u32,
+    /// Index into `final_classification.json`
+    pub index: usize,
+    pub cluster_index: Option<usize>,
+    pub members: Vec<ExplainedMember>,
+    /// How the text subclusters were found, `None` without text points
+    pub text_strategy: Option<TextStrategy>,
+    pub text_subclusters: Vec<TextSubcluster>,
+    pub gifs: Vec<GifOutcome>,
+    /// Empty without a point explorer
+    pub similarities: Vec<KeptSimilarity>,
+    pub representatives: Vec<Representative>,
+}
+
+/// Similarities are rounded so the file does not change with the float summation order
+fn round(similarity: f32) -> f32 {
+    (similarity * 1e4).round() / 1e4
+}
+
+fn assignments(classification: &FinalClassification) -> HashMap<Uuid, Assignment> {
+    let groups = [
+        (
+            classification.kept_text_anomalies_group.as_ref(),
+            Assignment::KeptTextAnomaly,
+        ),
+        (
+            classification.triaged_gif_and_then_will_keep_group.as_ref(),
+            Assignment::KeptGif,
+        ),
+        (
+            classification.reviewed_keep_group.as_ref(),
+            Assignment::KeptByReview,
+        ),
+        (
+            classification
+                .triaged_gif_and_invalid_group
+                .as_ref()
+                .map(|(ids, _)| ids),
+            Assignment::DeletedInvalidGif,
+        ),
+        (
+            classification
+                .triaged_gif_and_discard_same_frame_group
+                .as_ref(),
+            Assignment::DeletedSameFrameGif,
+        ),
+        (
+            classification
+                .triaged_gif_and_then_will_delete_group
+                .as_ref(),
+            Assignment::DeletedDuplicateGif,
+        ),
+        (
+            classification.other_need_delete_group.as_ref(),
+            Assignment::DeletedOther,
+        ),
+    ];
+    groups
+        .into_iter()
+        .flat_map(|(ids, assignment)| ids.into_iter().flatten().map(move |&id| (id, assignment)))
+        .chain(
+            classification
+                .kept_non_gif
+                .map(|id| (id, Assignment::KeptNonGif)),
+        )
+        .collect()
+}
+
+/// Explains entry `index` of `final_classification.json`
+///
+/// `text` are the text anomaly clusters of the text stage, `unmerged` the
+/// `(gif, kept, coverage)` splits of the frame check.
+pub fn explain_cluster<S>(
+    index: usize,
+    members: &HashSet<Uuid>,
+    classification: &FinalClassification,
+    text: &TextClusters,
+    unmerged: &[(Uuid, Uuid, f32)],
+    points_metadata: &HashMap<Uuid, (NekoPoint, NekoPointExt)>,
+    similarity: &S,
+) -> ClusterExplanation
+where
+    S: Fn(&Uuid, &Uuid) -> Option<f32>,
+{
+    let size_of = |id: &Uuid| points_metadata.get(id).and_then(|(pt, _)| pt.size);
+    let assignments = assignments(classification);
+    let assignment = |id: &Uuid| {
+        assignments
+            .get(id)
+            .copied()
+            .unwrap_or(Assignment::Undecided)
+    };
+    let mut member_ids: Vec<Uuid> = members.iter().copied().collect();
+    member_ids.sort_unstable();
+    let explained_members = member_ids
+        .iter()
+        .map(|&id| ExplainedMember {
+            id,
+            size: size_of(&id),
+            assignment: assignment(&id),
+        })
+        .collect();
+
+    let text_vector = |id: &Uuid| {
+        points_metadata
+            .get(id)
+            .and_then(|(pt, _)| pt.text_info.as_ref())
+            .map(|t| t.text_vector.as_slice())
+    };
+    let mut subclusters: Vec<TextSubcluster> = text
+        .clusters
+        .iter()
+        .map(|cluster| {
+            let mut members: Vec<Uuid> = cluster.iter().copied().copied().collect();
+            members.sort_unstable();
+            let similarities = members
+                .iter()
+                .enumerate()
+                .flat_map(|(i, a)| members[i + 1..].iter().map(move |b| (a, b)))
+                .filter_map(|(a, b)| {
+                    let sim = cosine_sim(text_vector(a)?, text_vector(b)?);
+                    Some(PairSimilarity {
+                        a: *a,
+                        b: *b,
+                        similarity: round(sim),
+                    })
+                })
+                .collect();
+            TextSubcluster {
+                members,
+                similarities,
+            }
+        })
+        .collect();
+    subclusters.sort_unstable_by(|a, b| a.members.cmp(&b.members));
+
+    let invalid_reasons: HashMap<Uuid, &String> = classification
+        .triaged_gif_and_invalid_group
+        .as_ref()
+        .map(|(ids, reasons)| ids.iter().copied().zip(reasons).collect())
+        .unwrap_or_default();
+    let unmerged: HashMap<Uuid, Unmerged> = unmerged
+        .iter()
+        .map(|&(gif, kept, coverage)| {
+            (
+                gif,
+                Unmerged {
+                    kept,
+                    coverage: round(coverage),
+                },
+            )
+        })
+        .collect();
+    let gif_verdict = |assignment: Assignment| match assignment {
+        Assignment::DeletedInvalidGif => Some(GifVerdict::Invalid),
+        Assignment::DeletedSameFrameGif => Some(GifVerdict::SameFrame),
+        Assignment::KeptGif => Some(GifVerdict::Kept),
+        Assignment::DeletedDuplicateGif => Some(GifVerdict::Duplicate),
+        _ => None,
+    };
+    let gifs: Vec<GifOutcome> = member_ids
+        .iter()
+        .filter_map(|id| {
+            Some(GifOutcome {
+                id: *id,
+                verdict: gif_verdict(assignment(id))?,
+                reason: invalid_reasons.get(id).map(|r| r.to_string()),
+                meta: classification
+                    .gif_metadata
+                    .as_ref()
+                    .and_then(|m| m.get(id))
+                    .copied(),
+                unmerged: unmerged.get(id).cloned(),
+            })
+        })
+        .collect();
+
+    let mut kept = classification.kept();
+    kept.sort_unstable();
+    let mut deleted = classification.discarded();
+    deleted.sort_unstable();
+    let similarities = deleted
+        .iter()
+        .filter_map(|d| {
+            kept.iter()
+                .filter_map(|k| similarity(k, d).map(|s| (k, s)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(k, s)| KeptSimilarity {
+                    deleted: *d,
+                    kept: *k,
+                    similarity: round(s),
+                })
+        })
+        .collect();
+
+    let garbage_text = |id: &Uuid| {
+        points_metadata
+            .get(id)
+            .and_then(|(pt, _)| pt.text_info.as_ref())
+            .is_some_and(|t| is_garbage(&t.text))
+    };
+    let runners_up = |kept: &Uuid, policy, pool: &mut dyn Iterator<Item = &Uuid>| {
+        let mut candidates: Vec<Candidate> = pool
+            .filter(|&id| id != kept)
+            .map(|&id| Candidate {
+                id,
+                size: size_of(&id),
+                garbage_text: policy == RepresentativePolicy::ReadableTextThenLargestSize
+                    && garbage_text(&id),
+            })
+            .collect();
+        candidates.sort_unstable_by(|a, b| {
+            a.garbage_text
+                .cmp(&b.garbage_text)
+                .then(b.size.cmp(&a.size))
+                .then(a.id.cmp(&b.id))
+        });
+        candidates.truncate(RUNNERS_UP);
+        candidates
+    };
+    let representative = |id: Uuid, reason, pool: &mut dyn Iterator<Item = &Uuid>| {
+        let policy = match reason {
+            RepresentativeReason::Reviewed => RepresentativePolicy::Manual,
+            RepresentativeReason::TextAnomaly => RepresentativePolicy::ReadableTextThenLargestSize,
+            _ => RepresentativePolicy::LargestSize,
+        };
+        Representative {
+            id,
+            reason,
+            policy,
+            runners_up: runners_up(&id, policy, pool),
+        }
+    };
+    let mut representatives = Vec::new();
+    for &id in classification.kept_text_anomalies_group.iter().flatten() {
+        let subcluster = subclusters.iter().find(|c| c.members.contains(&id));
+        let mut pool = subcluster.into_iter().flat_map(|c| c.members.iter());
+        representatives.push(representative(
+            id,
+            RepresentativeReason::TextAnomaly,
+            &mut pool,
+        ));
+    }
+    if let Some(id) = classification.kept_non_gif {
+        // the stage picks it among everything but the text anomalies and the GIFs
+        let mut pool = member_ids.iter().filter(|id| {
+            matches!(
+                assignment(id),
+                Assignment::KeptNonGif | Assignment::DeletedOther
+            )
+        });
+        representatives.push(representative(id, RepresentativeReason::NonGif, &mut pool));
+    }
+    for &id in classification
+        .triaged_gif_and_then_will_keep_group
+        .iter()
+        .flatten()
+    {
+        // the CLIP groups are not recorded, every duplicate GIF of the cluster competes
+        let mut pool = member_ids
+            .iter()
+            .filter(|id| assignment(id) == Assignment::DeletedDuplicateGif);
+        representatives.push(representative(id, RepresentativeReason::Gif, &mut pool));
+    }
+    for &id in classification.reviewed_keep_group.iter().flatten() {
+        representatives.push(representative(
+            id,
+            RepresentativeReason::Reviewed,
+            &mut std::iter::empty(),
+        ));
+    }
+    representatives.sort_unstable_by_key(|r| r.id);
+
+    ClusterExplanation {
+        schema_version: EXPLANATION_SCHEMA_VERSION,
+        index,
+        cluster_index: classification.cluster_index,
+        members: explained_members,
+        text_strategy: (!subclusters.is_empty()).then_some(text.strategy),
+        text_subclusters: subclusters,
+        gifs,
+        similarities,
+        representatives,
+    }
+}
+
+/// Streams one explanation per line into `path`, atomically
+pub fn write_explanations<P: AsRef<Path>>(
+    path: P,
+    explanations: &[ClusterExplanation],
+) -> io::Result<()> {
+    atomic_write_with(path, |w| {
+        for explanation in explanations {
+            serde_json::to_writer(&mut *w, explanation)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classification::FinalClassificationBuilder;
+    use serde_json::json;
+    use shared::structure::NekoPointText;
+    use shared::test_util::id;
+
+    #[test]
+    fn test_schema() {
+        let explanation = ClusterExplanation {
+            schema_version: EXPLANATION_SCHEMA_VERSION,
+            index: 0,
+            cluster_index: Some(4),
+            members: vec![ExplainedMember {
+                id: id(1),
+                size: Some(10),
+                assignment: Assignment::KeptGif,
+            }],
+            text_strategy: Some(TextStrategy::UnionFind),
+            text_subclusters: vec![TextSubcluster {
+                members: vec![id(2), id(3)],
+                similarities: vec![PairSimilarity {
+                    a: id(2),
+                    b: id(3),
+                    similarity: 0.75,
+                }],
+            }],
+            gifs: vec![GifOutcome {
+                id: id(1),
+                verdict: GifVerdict::Kept,
+                reason: None,
+                meta: None,
+                unmerged: Some(Unmerged {
+                    kept: id(5),
+                    coverage: 0.5,
+                }),
+            }],
+            similarities: vec![KeptSimilarity {
+                deleted: id(3),
+                kept: id(1),
+                similarity: 0.25,
+            }],
+            representatives: vec![Representative {
+                id: id(1),
+                reason: RepresentativeReason::Gif,
+                policy: RepresentativePolicy::LargestSize,
+                runners_up: vec![Candidate {
+                    id: id(6),
+                    size: None,
+                    garbage_text: false,
+                }],
+            }],
+        };
+        let value = serde_json::to_value(&explanation).unwrap();
+        let uuid = |n| id(n).to_string();
+        assert_eq!(
+            value,
+            json!({
+                "schema_version": 3,
+                "index": 0,
+                "cluster_index": 4,
+                "members": [{"id": uuid(1), "size": 10, "assignment": "kept_gif"}],
+                "text_strategy": "union_find",
+                "text_subclusters": [{
+                    "members": [uuid(2), uuid(3)],
+                    "similarities": [{"a": uuid(2), "b": uuid(3), "similarity": 0.75}],
+                }],
+                "gifs": [{
+                    "id": uuid(1),
+                    "verdict": "kept",
+                    "reason": null,
+                    "meta": null,
+                    "unmerged": {"kept": uuid(5), "coverage": 0.5},
+                }],
+                "similarities": [{"deleted": uuid(3), "kept": uuid(1), "similarity": 0.25}],
+                "representatives": [{
+                    "id": uuid(1),
+                    "reason": "gif",
+                    "policy": "largest_size",
+                    "runners_up": [{"id": uuid(6), "size": null}],
+                }],
+            })
+        );
+        let line = serde_json::to_string(&explanation).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ClusterExplanation>(&line).unwrap(),
+            explanation
+        );
+    }
+
+    #[test]
+    fn test_text_anomaly_readable_first() {
+        let point = |n: u128, size: usize, text: &str| {
+            let pt = NekoPoint {
+                id: id(n),
+                height: 1,
+                width: 1,
+                size: Some(size),
+                categories: None,
+                text_info: Some(NekoPointText {
+                    text: text.to_string(),
+                    text_vector: vec![1.0, 0.0],
+                }),
+                qdrant_num_id: None,
+                extra: None,
+            };
+            (id(n), (pt, NekoPointExt { source: None }))
+        };
+        let points_metadata = HashMap::from([
+            point(1, 300, "when the build is green"),
+            point(2, 900, "|||:;.,~~-- ==__"),
+            point(3, 100, "when the build is green!"),
+        ]);
+        let classification = FinalClassificationBuilder::new()
+            .keep_text_anomalies(vec![id(1)])
+            .delete_others(vec![id(2), id(3)])
+            .build()
+            .unwrap();
+        let members: HashSet<Uuid> = [id(1), id(2), id(3)].into();
+        let (a, b, c) = (id(1), id(2), id(3));
+        let explanation = explain_cluster(
+            0,
+            &members,
+            &classification,
+            &TextClusters {
+                strategy: TextStrategy::Greedy,
+                clusters: vec![vec![&a, &b, &c]],
+            },
+            &[],
+            &points_metadata,
+            &|_, _| Some(0.5),
+        );
+        let representative = &explanation.representatives[0];
+        assert_eq!(
+            representative.policy,
+            RepresentativePolicy::ReadableTextThenLargestSize
+        );
+        let runners_up: Vec<(Uuid, bool)> = representative
+            .runners_up
+            .iter()
+            .map(|c| (c.id, c.garbage_text))
+            .collect();
+        assert_eq!(runners_up, vec![(id(3), false), (id(2), true)]);
+    }
+
+    #[test]
+    fn test_write_explanations() {
+        let path = std::env::temp_dir().join(format!("explanations_{}.jsonl", Uuid::new_v4()));
+        let classification = FinalClassificationBuilder::new()
+            .keep_non_gif(Some(id(1)))
+            .delete_others(vec![id(2)])
+            .build()
+            .unwrap();
+        let members: HashSet<Uuid> = [id(1), id(2), id(3)].into();
+        let explanations: Vec<ClusterExplanation> = (0..2)
+            .map(|index| {
+                explain_cluster(
+                    index,
+                    &members,
+                    &classification,
+                    &TextClusters {
+                        strategy: TextStrategy::Greedy,
+                        clusters: Vec::new(),
+                    },
+                    &[],
+                    &HashMap::new(),
+                    &|_, _| Some(0.5),
+                )
+            })
+            .collect();
+        let assignments: Vec<Assignment> = explanations[0]
+            .members
+            .iter()
+            .map(|m| m.assignment)
+            .collect();
+        assert_eq!(
+            assignments,
+            [
+                Assignment::KeptNonGif,
+                Assignment::DeletedOther,
+                Assignment::Undecided
+            ]
+        );
+        assert_eq!(explanations[0].representatives[0].runners_up[0].id, id(2));
+        assert_eq!(explanations[0].text_strategy, None);
+        write_explanations(&path, &explanations).unwrap();
+        let lines: Vec<ClusterExplanation> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, explanations);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use crate::animated::open_animation;
+use image::DynamicImage;
+use image::imageops::FilterType;
+use image_hasher::{HashAlg, Hasher, HasherConfig, ImageHash};
+
+/// Frames of the shorter sequence matched into the longer one, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameAlignment {
+    /// `(index in a, index in b)` of every matched frame, both ascending
+    pub pairs: Vec<(usize, usize)>,
+    pub shorter: usize,
+    pub longer: usize,
+}
+
+impl FrameAlignment {
+    /// Fraction of the shorter sequence that found a match, 0 if it has no frames
+    pub fn coverage(&self) -> f32 {
+        match self.shorter {
+            0 => 0.0,
+            n => self.pairs.len() as f32 / n as f32,
+        }
+    }
+}
+
+/// Longest in-order matching of the shorter sequence into the longer one
+///
+/// A frame of the longer sequence may absorb a run of shorter frames, a GIF re-encoded at a lower
+/// frame rate or with repeated frames still aligns completely.
+pub fn align_frames<T, F>(a: &[T], b: &[T], matches: F) -> FrameAlignment
+where
+    F: Fn(&T, &T) -> bool,
+{
+    let swapped = a.len() > b.len();
+    let (short, long) = if swapped { (b, a) } else { (a, b) };
+    let (n, m) = (short.len(), long.len());
+    let is_match = |i: usize, j: usize| matches(&short[i], &long[j]);
+    // best[i * (m + 1) + j]: matched frames among short[..i] into long[..j]
+    let width = m + 1;
+    let mut best = vec![0u32; (n + 1) * width];
+    for i in 1..=n {
+        for j in 1..=m {
+            let skip_long = best[i * width + j - 1];
+            let take = best[(i - 1) * width + j] + is_match(i - 1, j - 1) as u32;
+            best[i * width + j] = skip_long.max(take);
+        }
+    }
+    let mut pairs = Vec::with_capacity(best[n * width + m] as usize);
+    let (mut i, mut j) = (n, m);
+    while i > 0 && j > 0 {
+        let here = best[i * width + j];
+        if is_match(i - 1, j - 1) && here == best[(i - 1) * width + j] + 1 {
+            pairs.push((i - 1, j - 1));
+            i -= 1;
+        } else if here == best[i * width + j - 1] {
+            j -= 1;
+        } else {
+            i -= 1;
+        }
+    }
+    pairs.reverse();
+    if swapped {
+        pairs.iter_mut().for_each(|p| *p = (p.1, p.0));
+    }
+    FrameAlignment {
+        pairs,
+        shorter: n,
+        longer: m,
+    }
+}
+
+/// Second opinion on GIFs the pooled CLIP embedding calls duplicates
+///
+/// Pooling hides order and length, a GIF sharing only its opening with another looks the same.
+pub struct FrameCheck {
+    hasher: Hasher,
+    /// Least coverage for the pair to stay merged
+    pub min_coverage: f32,
+    /// Most hash distance of two frames considered the same
+    pub max_distance: u32,
+}
+
+impl FrameCheck {
+    pub fn new(min_coverage: f32, max_distance: u32) -> Self {
+        let hasher = HasherConfig::new()
+            .hash_alg(HashAlg::Gradient)
+            .resize_filter(FilterType::Triangle)
+            .hash_size(16, 16)
+            .to_hasher();
+        Self {
+            hasher,
+            min_coverage,
+            max_distance,
+        }
+    }
+
+    /// Hash of every frame, decoded one at a time
+    pub fn frame_hashes(&self, path: &str) -> anyhow::Result<Vec<ImageHash>> {
+        open_animation(path)?
+            .frames
+            .map(|frame| {
+                let img = DynamicImage::ImageRgba8(frame?.into_buffer());
+                Ok(self.hasher.hash_image(&img))
+            })
+            .collect()
+    }
+
+    pub fn align(&self, a: &[ImageHash], b: &[ImageHash]) -> FrameAlignment {
+        align_frames(a, b, |x, y| x.dist(y) <= self.max_distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same(a: &u32, b: &u32) -> bool {
+        a == b
+    }
+
+    #[test]
+    fn test_align_subset() {
+        // the first half of a GIF
+        let long = [1, 2, 3, 4, 5, 6];
+        let res = align_frames(&long[..3], &long, same);
+        assert_eq!(res.pairs, vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(res.coverage(), 1.0);
+        // arguments in either order
+        let res = align_frames(&long, &long[..3], same);
+        assert_eq!(res.pairs, vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!((res.shorter, res.longer), (3, 6));
+    }
+
+    #[test]
+    fn test_align_same_opening() {
+        // a different joke with the same opening
+        let res = align_frames(&[1, 2, 7, 8, 9], &[1, 2, 3, 4, 5, 6], same);
+        assert_eq!(res.pairs, vec![(0, 0), (1, 1)]);
+        assert_eq!(res.coverage(), 0.4);
+    }
+
+    #[test]
+    fn test_align_frame_rate() {
+        // every other frame dropped
+        let res = align_frames(&[1, 3, 5], &[1, 2, 3, 4, 5, 6], same);
+        assert_eq!(res.pairs, vec![(0, 0), (1, 2), (2, 4)]);
+        // frames held twice as long map onto one frame each
+        let res = align_frames(&[1, 1, 2, 2, 3, 3], &[1, 2, 3, 4, 5, 6, 7], same);
+        assert_eq!(res.coverage(), 1.0);
+        assert_eq!(
+            res.pairs,
+            vec![(0, 0), (1, 0), (2, 1), (3, 1), (4, 2), (5, 2)]
+        );
+    }
+
+    #[test]
+    fn test_align_order_and_noise() {
+        // reversed playback only keeps one frame in order
+        let res = align_frames(&[3, 2, 1], &[1, 2, 3], same);
+        assert_eq!(res.pairs.len(), 1);
+        // hashes within a distance match
+        let near = |a: &u32, b: &u32| a.abs_diff(*b) <= 1;
+        let res = align_frames(&[10, 21, 30], &[11, 20, 29, 40], near);
+        assert_eq!(res.pairs, vec![(0, 0), (1, 1), (2, 2)]);
+        let res = align_frames::<u32, _>(&[], &[1, 2], same);
+        assert_eq!(res.coverage(), 0.0);
+    }
+
+    #[test]
+    fn test_frame_hashes() {
+        let check = FrameCheck::new(0.8, 20);
+        let a = check
+            .frame_hashes("../assets/test_images/mcat_0.gif")
+            .unwrap();
+        assert!(a.len() > 1);
+        assert_eq!(check.align(&a, &a).coverage(), 1.0);
+    }
+}
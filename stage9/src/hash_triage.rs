@@ -0,0 +1,156 @@
+use crate::frame_check::FrameCheck;
+use clap::ValueEnum;
+use image_hasher::ImageHash;
+use serde::Serialize;
+use shared::structure::{
+    TriageGif, TriageGifClip, TriageGifGroupsClipStagePair, TriageGifGroupsClipStageReq,
+    TriageGifGroupsClipStageRes,
+};
+
+/// How GIF duplicates are grouped after the GIF stage
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriageMode {
+    /// Pooled CLIP embeddings, needs the model
+    #[default]
+    Clip,
+    /// Frame hashes only, cruder but runs without the model or a GPU
+    HashOnly,
+}
+
+/// Groups GIFs by their frame hashes in place of the clip stage
+///
+/// Largest first, a GIF joins the first group whose largest GIF covers at least `min_coverage`
+/// of the shorter frame sequence, in order. Its answer has the shape of the clip stage's.
+pub struct HashTriage {
+    check: FrameCheck,
+}
+
+impl HashTriage {
+    pub const DEFAULT_MIN_COVERAGE: f32 = 0.8;
+
+    pub fn new(min_coverage: f32, max_distance: u32) -> Self {
+        Self {
+            check: FrameCheck::new(min_coverage, max_distance),
+        }
+    }
+
+    /// Groups of indices into `hashes`, each led by its largest member, `None` hashes (GIFs that
+    /// failed to decode) stay alone
+    fn group(&self, sizes: &[usize], hashes: &[Option<Vec<ImageHash>>]) -> Vec<Vec<usize>> {
+        let mut order: Vec<usize> = (0..sizes.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(sizes[i]));
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for i in order {
+            let joined = hashes[i].as_ref().and_then(|own| {
+                groups.iter_mut().find(|group| {
+                    hashes[group[0]].as_ref().is_some_and(|lead| {
+                        self.check.align(own, lead).coverage() >= self.check.min_coverage
+                    })
+                })
+            });
+            match joined {
+                Some(group) => group.push(i),
+                None => groups.push(vec![i]),
+            }
+        }
+        groups
+    }
+
+    pub fn triage<'a>(
+        &self,
+        req: TriageGifGroupsClipStageReq<'a>,
+    ) -> TriageGifGroupsClipStageRes<'a> {
+        req.into_iter()
+            .map(|outer| outer.map(|inner| inner.map(|grp| self.triage_group(grp))))
+            .collect()
+    }
+
+    fn triage_group<'a>(&self, grp: Vec<TriageGifClip<'a>>) -> TriageGifGroupsClipStagePair<'a> {
+        let hashes: Vec<Option<Vec<ImageHash>>> = grp
+            .iter()
+            .map(|clip| match self.check.frame_hashes(clip.path) {
+                Ok(hashes) => Some(hashes),
+                Err(e) => {
+                    tracing::warn!("Frame hashes of {} failed, kept alone: {}", clip.id, e);
+                    None
+                }
+            })
+            .collect();
+        let sizes: Vec<usize> = grp.iter().map(|clip| clip.size).collect();
+        let to_gif = |i: usize| TriageGif {
+            uuid: grp[i].id,
+            path: grp[i].path,
+            size: grp[i].size,
+        };
+        let mut kept = Vec::new();
+        let mut discarded = Vec::new();
+        for group in self.group(&sizes, &hashes) {
+            kept.push(to_gif(group[0]));
+            discarded.extend(group[1..].iter().map(|&i| to_gif(i)));
+        }
+        TriageGifGroupsClipStagePair {
+            kept_gifs: Some(kept),
+            discard_duplicate_gifs: Some(discarded),
+            unmerged_gifs: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const GIFS: [&str; 4] = [
+        "../assets/test_images/mcat_0.gif",
+        "../assets/test_images/mcat_1.gif",
+        "../assets/test_images/bq_0.gif",
+        "../assets/test_images/bq_1.gif",
+    ];
+
+    fn clips<'a>(ids: &'a [Uuid], paths: &[&'a str]) -> Vec<TriageGifClip<'a>> {
+        ids.iter()
+            .zip(paths)
+            .map(|(id, &path)| TriageGifClip {
+                id,
+                path,
+                size: std::fs::metadata(path).map_or(0, |m| m.len() as usize),
+                frame: Vec::new(),
+            })
+            .collect()
+    }
+
+    fn ids(gifs: &Option<Vec<TriageGif>>) -> Vec<Uuid> {
+        gifs.iter().flatten().map(|gif| *gif.uuid).collect()
+    }
+
+    #[test]
+    fn test_hash_only_assets() {
+        let uuids: Vec<Uuid> = (0..5).map(Uuid::from_u128).collect();
+        let triage = HashTriage::new(HashTriage::DEFAULT_MIN_COVERAGE, 24);
+        let req = vec![
+            Some(Some(clips(
+                &uuids[..3],
+                &[GIFS[0], GIFS[1], "../assets/test_images/missing.gif"],
+            ))),
+            None,
+            Some(Some(clips(&uuids[3..], &GIFS[2..]))),
+            Some(None),
+        ];
+        let res = triage.triage(req);
+        assert_eq!(res.len(), 4);
+        assert!(res[1].is_none());
+        assert!(matches!(res[3], Some(None)));
+        // the mcat GIFs share the cat but not a single frame, both kept, as is the GIF that does
+        // not decode
+        let mcat = res[0].as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(ids(&mcat.kept_gifs), uuids[..3].to_vec());
+        assert!(ids(&mcat.discard_duplicate_gifs).is_empty());
+        // bq_1 is a longer, smaller encoding of bq_0, every frame of bq_0 is found in it
+        let bq = res[2].as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(ids(&bq.kept_gifs), vec![uuids[3]]);
+        assert_eq!(ids(&bq.discard_duplicate_gifs), vec![uuids[4]]);
+        assert!(bq.unmerged_gifs.is_none());
+    }
+}
@@ -0,0 +1,433 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use shared::cluster_file::{CLUSTERS_MAGIC, ClusterReader, LegacyFormat};
+use shared::migrations::{read_schema_version, v1};
+use shared::opendal::probe_entry_list;
+use shared::structure::{NEKO_POINT_SCHEMA_VERSION, NekoPoint};
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Every file and directory stage9 touches, checked by [`validate_inputs`] before any work
+#[derive(Debug, Clone)]
+pub struct Stage9Inputs {
+    /// Cluster records, or a pickled `Vec<HashSet<Uuid>>`, see `shared::cluster_file`
+    pub clusters: PathBuf,
+    /// Versioned bincode `HashMap<Uuid, NekoPoint>`, see `shared::migrations`
+    pub points_map: PathBuf,
+    /// Versioned bincode `Vec<Entry>` listing taken after the stage8 renames, see `shared::opendal`
+    pub file_list: PathBuf,
+    /// CLIP safetensors, `None` in the hash-only triage mode
+    pub clip_model: Option<PathBuf>,
+    pub animated_overrides: Option<PathBuf>,
+    /// Image vectors for the review queue
+    pub point_explorer: Option<PathBuf>,
+    /// Downloaded triage candidates
+    pub triage_dir: PathBuf,
+    pub output_dir: PathBuf,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InputIssue {
+    #[error("{0}: file not found")]
+    Missing(PathBuf),
+    #[error("{path}: unreadable: {source}")]
+    Unreadable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{path}: malformed: {reason}")]
+    Malformed { path: PathBuf, reason: String },
+    #[error("{path}: directory not writable: {reason}")]
+    NotWritable { path: PathBuf, reason: String },
+}
+
+/// All problems found by [`validate_inputs`], one per line
+#[derive(Debug)]
+pub struct InvalidInputs(pub Vec<InputIssue>);
+
+impl Display for InvalidInputs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} invalid stage9 input(s):", self.0.len())?;
+        for issue in self.0.iter() {
+            write!(f, "\n  - {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidInputs {}
+
+fn open(path: &Path) -> Result<File, InputIssue> {
+    if !path.is_file() {
+        return Err(InputIssue::Missing(path.to_path_buf()));
+    }
+    File::open(path).map_err(|source| InputIssue::Unreadable {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn malformed(path: &Path, reason: impl Display) -> InputIssue {
+    InputIssue::Malformed {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Pickle protocol 2+ starts with `PROTO <version>` and ends with `STOP`
+fn check_pickle(path: &Path) -> Result<(), InputIssue> {
+    let mut file = open(path)?;
+    let mut head = [0u8; 2];
+    let mut tail = [0u8; 1];
+    file.read_exact(&mut head)
+        .and_then(|_| file.seek(SeekFrom::End(-1)))
+        .and_then(|_| file.read_exact(&mut tail))
+        .map_err(|_| malformed(path, "too short for a pickle"))?;
+    match (head, tail) {
+        ([0x80, 2..=5], [b'.']) => Ok(()),
+        ([0x80, 2..=5], _) => Err(malformed(path, "truncated pickle, no STOP opcode")),
+        _ => Err(malformed(path, "not a pickle (protocol 2-5) file")),
+    }
+}
+
+/// Record files are checked up to their first cluster, anything else must be a pickle
+fn check_clusters(path: &Path) -> Result<(), InputIssue> {
+    let mut reader = BufReader::new(open(path)?);
+    let is_records = reader
+        .fill_buf()
+        .map_err(|source| InputIssue::Unreadable {
+            path: path.to_path_buf(),
+            source,
+        })?
+        .starts_with(CLUSTERS_MAGIC);
+    if !is_records {
+        return check_pickle(path);
+    }
+    match ClusterReader::new(reader, LegacyFormat::Pickle)
+        .map(|mut clusters| clusters.next().transpose())
+    {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) | Err(e) => Err(malformed(path, format!("bad first cluster: {}", e))),
+    }
+}
+
+/// Decodes the length and the first element of a bincode (standard config) sequence or map
+fn check_bincode_seq_from<T: DeserializeOwned>(
+    path: &Path,
+    mut reader: impl Read,
+) -> Result<(), InputIssue> {
+    let config = bincode::config::standard();
+    let len: u64 = bincode::decode_from_std_read(&mut reader, config)
+        .map_err(|e| malformed(path, format!("bad length header: {}", e)))?;
+    if len > 0 {
+        bincode::serde::decode_from_std_read::<T, _, _>(&mut reader, config)
+            .map_err(|e| malformed(path, format!("bad first record: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// Accepts every schema version this build migrates, v0 records share the v1 layout
+fn check_points_map(path: &Path) -> Result<(), InputIssue> {
+    let mut reader = BufReader::new(open(path)?);
+    let version = read_schema_version(&mut reader)
+        .map_err(|e| malformed(path, format!("bad schema header: {}", e)))?;
+    if version > NEKO_POINT_SCHEMA_VERSION {
+        return Err(malformed(
+            path,
+            format!(
+                "schema version {} is newer than the supported {}",
+                version, NEKO_POINT_SCHEMA_VERSION
+            ),
+        ));
+    }
+    match version {
+        0 | 1 => check_bincode_seq_from::<(Uuid, v1::NekoPoint)>(path, reader),
+        _ => check_bincode_seq_from::<(Uuid, NekoPoint)>(path, reader),
+    }
+}
+
+/// Accepts every listing layout `shared::opendal` decodes, probed from the first MiB which holds
+/// the first record whole
+fn check_entry_list(path: &Path) -> Result<(), InputIssue> {
+    let mut head = Vec::new();
+    open(path)?
+        .take(1 << 20)
+        .read_to_end(&mut head)
+        .map_err(|e| malformed(path, format!("unreadable listing: {}", e)))?;
+    probe_entry_list(&head)
+        .map(|_| ())
+        .map_err(|e| malformed(path, e))
+}
+
+/// Reads the JSON header of a safetensors file and checks the tensors it lists fit in the file
+fn check_safetensors(path: &Path) -> Result<(), InputIssue> {
+    let mut file = open(path)?;
+    let file_len = file
+        .metadata()
+        .map_err(|source| InputIssue::Unreadable {
+            path: path.to_path_buf(),
+            source,
+        })?
+        .len();
+    let mut len_buf = [0u8; 8];
+    file.read_exact(&mut len_buf)
+        .map_err(|_| malformed(path, "shorter than the safetensors header"))?;
+    let header_len = u64::from_le_bytes(len_buf);
+    if header_len > file_len.saturating_sub(8) {
+        return Err(malformed(
+            path,
+            format!("header length {} exceeds file", header_len),
+        ));
+    }
+    let mut header = vec![0u8; header_len as usize];
+    file.read_exact(&mut header)
+        .map_err(|e| malformed(path, format!("unreadable header: {}", e)))?;
+    let header: Map<String, Value> = serde_json::from_slice(&header)
+        .map_err(|e| malformed(path, format!("invalid header JSON: {}", e)))?;
+    let data_len = file_len - 8 - header_len;
+    let mut tensors = 0;
+    for (name, info) in header.iter().filter(|(name, _)| *name != "__metadata__") {
+        let end = info
+            .get("data_offsets")
+            .and_then(|offsets| offsets.get(1))
+            .and_then(Value::as_u64)
+            .ok_or_else(|| malformed(path, format!("tensor {} has no data_offsets", name)))?;
+        if end > data_len {
+            return Err(malformed(
+                path,
+                format!("truncated, tensor {} ends past the file", name),
+            ));
+        }
+        tensors += 1;
+    }
+    for prefix in ["vision_model.", "text_model."] {
+        if !header.keys().any(|name| name.starts_with(prefix)) {
+            return Err(malformed(
+                path,
+                format!("no {}* tensors among {}, not a CLIP model", prefix, tensors),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Creates `dir` if needed and proves it writable with a probe file
+fn check_writable_dir(dir: &Path) -> Result<(), InputIssue> {
+    let not_writable = |reason: String| InputIssue::NotWritable {
+        path: dir.to_path_buf(),
+        reason,
+    };
+    fs::create_dir_all(dir).map_err(|e| not_writable(e.to_string()))?;
+    let probe = dir.join(format!(".stage9_probe_{}", Uuid::new_v4()));
+    fs::write(&probe, b"").map_err(|e| not_writable(e.to_string()))?;
+    fs::remove_file(&probe).map_err(|e| not_writable(e.to_string()))
+}
+
+/// Checks every input up front so a bad path fails the run before the S3 download, not hours in
+pub fn validate_inputs(inputs: &Stage9Inputs) -> Result<(), InvalidInputs> {
+    let mut checks = vec![
+        check_clusters(&inputs.clusters),
+        check_points_map(&inputs.points_map),
+        check_entry_list(&inputs.file_list),
+    ];
+    if let Some(path) = inputs.clip_model.as_deref() {
+        checks.push(check_safetensors(path));
+    }
+    checks.push(check_writable_dir(&inputs.triage_dir));
+    checks.push(check_writable_dir(&inputs.output_dir));
+    if let Some(path) = inputs.animated_overrides.as_deref() {
+        checks.push(match path.is_file() {
+            true => crate::triage_candidate::AnimatedCandidate::load_overrides(path)
+                .map(|_| ())
+                .map_err(|e| malformed(path, e)),
+            false => Err(InputIssue::Missing(path.to_path_buf())),
+        });
+    }
+    if let Some(path) = inputs.point_explorer.as_deref() {
+        checks.push(open(path).map(|_| ()));
+    }
+    let issues: Vec<InputIssue> = checks.into_iter().filter_map(Result::err).collect();
+    match issues.is_empty() {
+        true => Ok(()),
+        false => Err(InvalidInputs(issues)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::cluster_file::write_clusters;
+    use std::collections::{HashMap, HashSet};
+
+    /// `pickle.dumps([], protocol=3)`
+    const EMPTY_LIST_PICKLE: &[u8] = b"\x80\x03]q\x00.";
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("stage9_inputs_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn safetensors(names: &[&str], truncate: usize) -> Vec<u8> {
+        let header: Map<String, Value> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let info = serde_json::json!({"dtype": "F32", "shape": [1], "data_offsets": [i * 4, i * 4 + 4]});
+                (name.to_string(), info)
+            })
+            .collect();
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut out = (header.len() as u64).to_le_bytes().to_vec();
+        out.extend(header);
+        out.extend(vec![0u8; names.len() * 4 - truncate]);
+        out
+    }
+
+    fn valid_inputs(dir: &Path) -> Stage9Inputs {
+        fs::write(dir.join("clusters.pkl"), EMPTY_LIST_PICKLE).unwrap();
+        let points: HashMap<Uuid, NekoPoint> = HashMap::new();
+        let encoded = shared::migrations::encode_neko_points(&points).unwrap();
+        fs::write(dir.join("points_map.bin"), encoded).unwrap();
+        shared::opendal::save_entry_list(dir.join("file_list.bin"), &[]).unwrap();
+        let model = safetensors(&["vision_model.a", "text_model.b"], 0);
+        fs::write(dir.join("model.safetensors"), model).unwrap();
+        Stage9Inputs {
+            clusters: dir.join("clusters.pkl"),
+            points_map: dir.join("points_map.bin"),
+            file_list: dir.join("file_list.bin"),
+            clip_model: Some(dir.join("model.safetensors")),
+            animated_overrides: None,
+            point_explorer: None,
+            triage_dir: dir.join("gifs"),
+            output_dir: dir.join("out"),
+        }
+    }
+
+    #[test]
+    fn test_valid_inputs() {
+        let dir = scratch_dir();
+        let inputs = valid_inputs(&dir);
+        validate_inputs(&inputs).unwrap();
+        assert!(inputs.triage_dir.is_dir() && inputs.output_dir.is_dir());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_every_issue_reported() {
+        let dir = scratch_dir();
+        let mut inputs = valid_inputs(&dir);
+        inputs.clusters = dir.join("nope.pkl");
+        fs::write(&inputs.points_map, b"\x05garbage").unwrap();
+        fs::write(&inputs.file_list, b"").unwrap();
+        fs::write(
+            inputs.clip_model.as_ref().unwrap(),
+            safetensors(&["vision_model.a", "text_model.b"], 2),
+        )
+        .unwrap();
+        fs::write(dir.join("blocker"), b"").unwrap();
+        inputs.output_dir = dir.join("blocker").join("out");
+        inputs.animated_overrides = Some(dir.join("overrides.json"));
+        let err = validate_inputs(&inputs).unwrap_err();
+        let messages: Vec<String> = err.0.iter().map(ToString::to_string).collect();
+        assert_eq!(messages.len(), 6, "{:#?}", messages);
+        assert!(matches!(&err.0[0], InputIssue::Missing(p) if p.ends_with("nope.pkl")));
+        assert!(messages[1].contains("points_map.bin") && messages[1].contains("first record"));
+        assert!(messages[2].contains("file_list.bin") && messages[2].contains("no known layout"));
+        assert!(messages[3].contains("model.safetensors") && messages[3].contains("truncated"));
+        assert!(matches!(&err.0[4], InputIssue::NotWritable { .. }));
+        assert!(matches!(&err.0[5], InputIssue::Missing(p) if p.ends_with("overrides.json")));
+        assert!(err.to_string().starts_with("6 invalid stage9 input(s):"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_model_and_pickle_headers() {
+        let dir = scratch_dir();
+        let path = dir.join("m.safetensors");
+        fs::write(&path, safetensors(&["vision_model.a"], 0)).unwrap();
+        assert!(
+            check_safetensors(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("text_model.")
+        );
+        fs::write(&path, b"{}").unwrap();
+        assert!(check_safetensors(&path).is_err());
+        let path = dir.join("c.pkl");
+        fs::write(&path, &EMPTY_LIST_PICKLE[..EMPTY_LIST_PICKLE.len() - 1]).unwrap();
+        assert!(
+            check_pickle(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("truncated")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cluster_records() {
+        let dir = scratch_dir();
+        let path = dir.join("global_clusters.clusters");
+        let clusters: Vec<HashSet<Uuid>> = vec![(1..4).map(Uuid::from_u128).collect()];
+        let mut records = Vec::new();
+        write_clusters(&mut records, &clusters).unwrap();
+        fs::write(&path, &records).unwrap();
+        check_clusters(&path).unwrap();
+        fs::write(&path, &records[..records.len() - 1]).unwrap();
+        assert!(
+            check_clusters(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("bad first cluster")
+        );
+        fs::write(&path, EMPTY_LIST_PICKLE).unwrap();
+        check_clusters(&path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_points_map_versions() {
+        let dir = scratch_dir();
+        let path = dir.join("points_map.bin");
+        // unversioned maps from before the header are still accepted
+        let points: HashMap<Uuid, NekoPoint> = HashMap::new();
+        let encoded = bincode::serde::encode_to_vec(&points, bincode::config::standard()).unwrap();
+        fs::write(&path, encoded).unwrap();
+        check_points_map(&path).unwrap();
+        // version 1 records lack `qdrant_num_id`
+        let id = Uuid::from_u128(1);
+        let v1_points = HashMap::from([(
+            id,
+            v1::NekoPoint {
+                id,
+                height: 1,
+                width: 1,
+                size: None,
+                categories: None,
+                text_info: None,
+            },
+        )]);
+        let mut v1_map = shared::migrations::NEKO_POINTS_MAGIC.to_vec();
+        v1_map.extend(1u32.to_le_bytes());
+        v1_map.extend(
+            bincode::serde::encode_to_vec(&v1_points, bincode::config::standard()).unwrap(),
+        );
+        fs::write(&path, v1_map).unwrap();
+        check_points_map(&path).unwrap();
+        let mut newer = shared::migrations::NEKO_POINTS_MAGIC.to_vec();
+        newer.extend((NEKO_POINT_SCHEMA_VERSION + 1).to_le_bytes());
+        newer.push(0);
+        fs::write(&path, newer).unwrap();
+        assert!(
+            check_points_map(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("newer than the supported")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,3 +1,17 @@
+pub mod animated;
+mod animated_worker;
+pub mod classification;
 pub mod clip_worker;
-mod gif_worker;
+pub mod content_kind;
+pub mod coverage;
+pub mod downscale;
+pub mod explain;
+pub mod frame_check;
+pub mod hash_triage;
+#[cfg(feature = "remote-image")]
+pub mod remote_image;
+pub mod review;
 mod s3_downloader;
+pub mod shared_text;
+pub mod text_cluster;
+pub mod triage_candidate;
@@ -1,29 +1,78 @@
+mod animated;
+mod animated_worker;
+mod budget;
+mod classification;
 mod clip_worker;
-mod gif_worker;
+mod content_kind;
+mod coverage;
+mod downscale;
+mod estimate;
+mod explain;
+mod frame_check;
+mod hash_triage;
+mod inputs;
+#[cfg(feature = "remote-image")]
+mod remote_image;
+mod review;
 mod s3_downloader;
+mod savings;
+mod schedule;
+mod shared_text;
+mod text_cluster;
+mod triage_candidate;
 
-use crate::clip_worker::ClipWorker;
-use crate::gif_worker::GifWorker;
-use crate::s3_downloader::S3Downloader;
+use crate::animated::ANIMATED_EXTS;
+use crate::animated_worker::AnimatedMediaWorker;
+use crate::budget::{TimeBudget, run_batches};
+use crate::classification::{ExtractedCluster, FinalClassificationBuilder};
+use crate::clip_worker::{BatchSize, ClipWorker, DEFAULT_BATCH_SIZE};
+use crate::content_kind::{ContentKind, ContentKindConfig, classify, split_mixed_clusters};
+use crate::coverage::{CoverageSink, Stage};
+use crate::downscale::StoredGif;
+use crate::estimate::{ClusterOutcome, EstimateReport, Stratum};
+use crate::explain::{ClusterExplanation, explain_cluster, write_explanations};
+use crate::frame_check::FrameCheck;
+use crate::hash_triage::{HashTriage, TriageMode};
+use crate::inputs::{Stage9Inputs, validate_inputs};
+use crate::review::{ReviewRules, build_review_queue};
+use crate::s3_downloader::{
+    DEFAULT_CHUNK_CONCURRENCY, DEFAULT_CHUNK_SIZE, DownloadError, S3Downloader,
+};
+use crate::savings::SavingsSummary;
+use crate::schedule::Schedule;
+use crate::shared_text::{
+    DEFAULT_SHARED_TEXT_MIN_CLUSTERS, SharedTextConfig, TextIndex, shared_texts,
+};
+use crate::text_cluster::{
+    DEFAULT_TEXT_CLUSTER_CAP, TextClusters, TextStrategy, union_find_clusters,
+};
+use crate::triage_candidate::{AnimatedCandidate, DEFAULT_ANIMATED_EXTS};
 use anyhow::Result;
 use candle_core::DType;
 use candle_transformers::models::clip::ClipConfig;
+use clap::Parser;
 use half::bf16;
 use mimalloc::MiMalloc;
 use rayon::prelude::*;
+use shared::atomic_write::{atomic_write, atomic_write_with};
+use shared::cluster_file::{LegacyFormat, open_clusters, write_clusters};
+use shared::config::Thresholds;
 use shared::cosine_sim::cosine_sim;
+use shared::logging::Logging;
+use shared::migrations::load_neko_points;
+use shared::point_explorer::{PointExplorer, PointExplorerBuilder};
 use shared::structure::{
-    FinalClassification, TEXT_SIM_THRESHOLD, TriageGif, TriageGifGroupsClipStageReq,
-    TriageGifGroupsGifStageReq,
+    FinalClassification, SampleInfo, TriageGif, TriageGifGroupsClipStageReq,
+    TriageGifGroupsClipStageRes, TriageGifGroupsGifStageReq, TriageGifGroupsGifStageRes,
 };
 use shared::structure::{NekoPoint, NekoPointExt, NekoPointExtResource};
+use shared::text::{DEFAULT_MAX_GRAPHEMES, is_garbage};
+use shared::validation::{TEXT_VECTOR_DIM, validate_neko_points};
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
-use std::{env, fs};
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use uuid::Uuid;
 
 #[global_allocator]
@@ -33,7 +82,9 @@ static GLOBAL: MiMalloc = MiMalloc;
 fn find_text_anomalies_clusters<'a>(
     text_points: &[&'a Uuid],
     points_metadata: &HashMap<Uuid, (NekoPoint, NekoPointExt)>,
-) -> Vec<Vec<&'a Uuid>> {
+    cap: usize,
+    threshold: f32,
+) -> TextClusters<'a> {
     let mut id_vec_pairs = Vec::with_capacity(text_points.len());
     for &id in text_points {
         if let Some((pt, _)) = points_metadata.get(id) {
@@ -42,6 +93,18 @@ fn find_text_anomalies_clusters<'a>(
             }
         }
     }
+    let strategy = TextStrategy::for_points(id_vec_pairs.len(), cap);
+    if strategy == TextStrategy::UnionFind {
+        tracing::debug!(
+            "{} text points over the cap of {}, clustering them by union-find",
+            id_vec_pairs.len(),
+            cap
+        );
+        return TextClusters {
+            strategy,
+            clusters: union_find_clusters(&id_vec_pairs, threshold),
+        };
+    }
     let mut vec_map: HashMap<&Uuid, &[f32]> = HashMap::with_capacity(id_vec_pairs.len());
     for &(ref id, vec_i) in &id_vec_pairs {
         vec_map.insert(id, vec_i);
@@ -52,7 +115,7 @@ fn find_text_anomalies_clusters<'a>(
         for cl in clusters.iter_mut() {
             let ok = cl.iter().all(|&other_id| {
                 let vec_j = vec_map.get(&other_id).unwrap();
-                cosine_sim(vec_i, vec_j) > TEXT_SIM_THRESHOLD
+                cosine_sim(vec_i, vec_j) > threshold
             });
             if ok {
                 cl.push(id);
@@ -64,21 +127,38 @@ fn find_text_anomalies_clusters<'a>(
             clusters.push(vec![id]);
         }
     }
-    clusters
+    TextClusters { strategy, clusters }
+}
+
+/// Text anomaly clusters of one cluster, as the text stage of [`extract_clusters`] finds them
+fn text_subclusters<'a>(
+    cluster: &'a HashSet<Uuid>,
+    points_metadata: &HashMap<Uuid, (NekoPoint, NekoPointExt)>,
+    cap: usize,
+    text_sim: f32,
+) -> TextClusters<'a> {
+    let text_points: Vec<&Uuid> = cluster
+        .iter()
+        .filter(|id| {
+            points_metadata
+                .get(id)
+                .is_some_and(|(pt, _)| pt.text_info.is_some())
+        })
+        .collect();
+    find_text_anomalies_clusters(&text_points, points_metadata, cap, text_sim)
 }
 
+/// One entry per cluster of `points_clusters`, in its order
 fn extract_clusters<'a>(
-    points_clusters: &'a [HashSet<Uuid>],
+    points_clusters: impl IndexedParallelIterator<Item = (usize, &'a HashSet<Uuid>)>,
     points_metadata: &'a HashMap<Uuid, (NekoPoint, NekoPointExt)>,
-) -> Vec<(
-    Option<Vec<&'a Uuid>>, // Option<Vec<KeptTextAnomaliesPic>>
-    Option<Vec<&'a Uuid>>, // Option<Vec<NeedTriageGifs>>
-    Option<&'a Uuid>,      // Option<KeptNonGif>
-    Option<Vec<&'a Uuid>>, // Option<Vec<OtherNeedDeletePics>>
-)> {
+    candidate: &AnimatedCandidate,
+    text_cluster_cap: usize,
+    text_sim: f32,
+) -> Vec<ExtractedCluster<'a>> {
     points_clusters
-        .par_iter()
-        .map(|cursor| {
+        .map(|(idx, cursor)| {
+            let _cluster = tracing::debug_span!("cluster", idx).entered();
             let cursor_ref: HashSet<&Uuid> = cursor.iter().collect();
             // stage1
             let only_text_uuids: Vec<&Uuid> = cursor
@@ -92,23 +172,28 @@ fn extract_clusters<'a>(
                 .collect();
             let text_points = (!only_text_uuids.is_empty()).then_some(only_text_uuids);
             let text_points_size = text_points.as_ref().map_or(0, |v| v.len());
-            let text_anomalies_clusters = text_points
-                .as_ref()
-                .map(|tp| find_text_anomalies_clusters(tp, points_metadata));
+            let text_anomalies_clusters = text_points.as_ref().map(|tp| {
+                find_text_anomalies_clusters(tp, points_metadata, text_cluster_cap, text_sim)
+                    .clusters
+            });
             let mut text_anomalies: Option<Vec<&Uuid>> = None;
             let mut text_non_anomalies: Option<Vec<&Uuid>> = None; // TODO: keep it...?
             if let Some(clusters) = text_anomalies_clusters {
                 text_anomalies = Some(Vec::with_capacity(clusters.len()));
-                text_non_anomalies = Some(Vec::with_capacity(text_points_size - clusters.len()));
+                text_non_anomalies = Some(Vec::with_capacity(
+                    text_points_size.saturating_sub(clusters.len()),
+                ));
                 for cluster in clusters.iter() {
+                    // garbage OCR text only represents its subcluster if all of it is garbage
                     let (max_idx, &max_uuid) = cluster
                         .iter()
                         .enumerate()
                         .max_by_key(|&(_, &id)| {
-                            points_metadata
-                                .get(id)
-                                .and_then(|(pt, _)| pt.size)
-                                .unwrap_or(0)
+                            points_metadata.get(id).map_or((false, 0), |(pt, _)| {
+                                let readable =
+                                    pt.text_info.as_ref().is_some_and(|t| !is_garbage(&t.text));
+                                (readable, pt.size.unwrap_or(0))
+                            })
                         })
                         .unwrap();
                     text_anomalies.as_mut().unwrap().push(max_uuid);
@@ -147,7 +232,7 @@ fn extract_clusters<'a>(
             for &id in non_text_anomalies_set.iter() {
                 let is_gif = points_metadata
                     .get(id)
-                    .map(|(_, ex)| ex.ext() == "gif")
+                    .map(|(_, ex)| candidate.is_candidate(id, ex.ext()))
                     .unwrap_or(false);
                 match is_gif {
                     true => {
@@ -171,7 +256,7 @@ fn extract_clusters<'a>(
                     (Some(gif), _) => (Some(gif), None),
                     // We no longer make this judgment because the GIF group may contain
                     // invalid GIFs (such as single frames). This part of the logic can be
-                    // completely left to `gif_worker` to judge.
+                    // completely left to `animated_worker` to judge.
                     (None, non_gif) => {
                         let maybe_biggest_non_gif = non_gif.and_then(|hs| {
                             hs.iter()
@@ -213,28 +298,267 @@ fn extract_clusters<'a>(
         .collect()
 }
 
+/// Groups the duplicate GIFs left after the GIF stage, see [`TriageMode`]
+enum GifGrouper {
+    Clip(Box<ClipWorker>),
+    Hash(HashTriage),
+}
+
+impl GifGrouper {
+    fn group<'a>(
+        &self,
+        req: TriageGifGroupsClipStageReq<'a>,
+    ) -> Result<TriageGifGroupsClipStageRes<'a>> {
+        match self {
+            GifGrouper::Clip(worker) => {
+                let res = worker.get_images_embedding_adapted::<bf16>(req)?;
+                tracing::debug!("CLIP batch size now {}", worker.current_batch_size());
+                Ok(res)
+            }
+            GifGrouper::Hash(triage) => Ok(triage.triage(req)),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "Stage9", version)]
+struct Cli {
+    /// Pickled duplicate clusters from stage1
+    #[arg(long, default_value = "global_clusters.pkl")]
+    clusters: PathBuf,
+    #[arg(long, default_value = "points_map.bin")]
+    points_map: PathBuf,
+    /// S3 listing taken after the renames
+    #[arg(long, default_value = "opendal_list_file_after_rename_simplify.bin")]
+    file_list: PathBuf,
+    /// CLIP safetensors, BAAI/BGE-VL-large, unused in the hash-only triage mode
+    #[arg(long)]
+    clip_model_path: Option<PathBuf>,
+    /// How duplicate GIFs are grouped, `hash-only` compares frame hashes and needs no model
+    #[arg(long, value_enum, default_value_t = TriageMode::Clip)]
+    triage_mode: TriageMode,
+    /// Extensions treated as animated triage candidates, GIF, WebP and PNG (APNG) are decoded,
+    /// a still WebP or PNG counts as a single-frame animation
+    #[arg(long, value_delimiter = ',', default_values_t = DEFAULT_ANIMATED_EXTS.map(str::to_string))]
+    animated_exts: Vec<String>,
+    /// JSON map of point id to `animated` / `static` overriding the extension check
+    #[arg(long)]
+    animated_overrides: Option<PathBuf>,
+    /// Where triage candidates are downloaded
+    #[arg(long, default_value = "nekoimg_stage9_gifs")]
+    triage_dir: PathBuf,
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
+    #[arg(long, default_value = "20")]
+    download_worker_num: usize,
+    /// Store triage GIFs whose canvas exceeds this many pixels on a side downscaled to fit it,
+    /// frames are only needed at CLIP's input size. Original and stored sizes go to
+    /// `downloaded_gifs.json`
+    #[arg(long)]
+    max_download_dimension: Option<u32>,
+    /// Bytes per ranged request of a triage GIF download, written to disk as they arrive
+    #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE)]
+    download_chunk_size: u64,
+    /// Ranges of one triage GIF in flight at a time
+    #[arg(long, default_value_t = DEFAULT_CHUNK_CONCURRENCY)]
+    download_chunk_concurrency: usize,
+    /// Bytes per second all triage GIF downloads share, unlimited by default
+    #[arg(long)]
+    max_download_bytes_per_sec: Option<u64>,
+    /// Fetch the triage GIFs of the next batch while the current one is refined and grouped,
+    /// keeping up to this many bytes of them in memory. Splits the run in batches of
+    /// --budget-batch-clusters even without a time budget
+    #[arg(long, conflicts_with = "max_download_bytes_per_sec")]
+    prefetch_bytes: Option<usize>,
+    /// Prometheus text dump of this run's metrics, relative to `--output-dir`
+    #[arg(long, default_value = "stage9_metrics.prom")]
+    metrics_file: PathBuf,
+    /// Image vectors for the review queue similarities, the uncertain rule is off without them
+    #[arg(long)]
+    point_explorer: Option<PathBuf>,
+    /// Review a cluster when a deleted member's closest kept member is below this similarity
+    #[arg(long, default_value = "0.99")]
+    review_uncertain_below: f32,
+    /// Review a cluster whose members disagree on any of these tags
+    #[arg(long, value_delimiter = ',')]
+    review_protected_tags: Vec<String>,
+    /// Review a cluster when a deleted member is over this many times bigger than every kept one
+    #[arg(long, default_value = "2.0")]
+    review_size_ratio: f64,
+    /// Prefix joined with the S3 key into a browsable url in the review queue
+    #[arg(long)]
+    review_url_prefix: Option<String>,
+    /// Compare the frames of GIFs CLIP merges and keep both when less than this fraction of the
+    /// shorter one is found in order in the other, off when unset. In the hash-only triage mode
+    /// the least coverage to group two GIFs, 0.8 when unset
+    #[arg(long)]
+    gif_frame_min_coverage: Option<f32>,
+    /// Most gradient hash distance (of 256 bits) of two frames considered the same, the
+    /// configured `frame_hash_distance` when unset
+    #[arg(long)]
+    gif_frame_max_distance: Option<u32>,
+    /// Frames per CLIP forward pass
+    #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+    clip_batch_size: usize,
+    /// Halve the CLIP batch size on GPU out of memory errors instead of failing, starting from
+    /// --clip-batch-size
+    #[arg(long)]
+    clip_auto_batch: bool,
+    /// Stop scheduling clusters once this is nearly used up (e.g. 6h, 90m), the untouched ones
+    /// are saved to `remaining_clusters.pkl` for the next run
+    #[arg(long, value_parser = budget::parse_duration)]
+    time_budget: Option<Duration>,
+    /// Clusters per batch under a time budget or with --prefetch-bytes, the budget is checked
+    /// between batches
+    #[arg(long, default_value = "1000")]
+    budget_batch_clusters: usize,
+    /// Split clusters mixing screenshots and photos by kind, keeping the best point of each
+    #[arg(long)]
+    split_content_kinds: bool,
+    /// JSON thresholds of the screenshot/photo classifier, defaults for the ones left out
+    #[arg(long, requires = "split_content_kinds")]
+    content_kind_config: Option<PathBuf>,
+    /// Text points of one cluster above which the all-members text clustering gives way to
+    /// union-find over the similar pairs, `explanations.jsonl` records which one ran
+    #[arg(long, default_value_t = DEFAULT_TEXT_CLUSTER_CAP)]
+    text_cluster_cap: usize,
+    /// Fail the run when an input point ends up in none of kept, deleted, deferred or failed,
+    /// otherwise they are only reported in `unaccounted_points.json`
+    #[arg(long)]
+    strict_coverage: bool,
+    /// Clusters an OCR text has to be seen in to be listed in `shared_text_groups.json`, points
+    /// in no cluster count as a cluster each
+    #[arg(long, default_value_t = DEFAULT_SHARED_TEXT_MIN_CLUSTERS)]
+    shared_text_min_clusters: usize,
+    /// Also merge OCR texts whose simhashes differ in at most this many bits, only identical
+    /// texts after normalization when unset
+    #[arg(long)]
+    shared_text_simhash_distance: Option<u32>,
+    /// Most duplicated OCR texts listed in `shared_text_groups.json`
+    #[arg(long, default_value = "100")]
+    shared_text_top: usize,
+    /// OCR texts in `shared_text_groups.json` and `review_queue.json` are cut to this many
+    /// characters, as displayed, and end in an ellipsis when cut
+    #[arg(long, default_value_t = DEFAULT_MAX_GRAPHEMES)]
+    report_text_max_graphemes: usize,
+    /// Only warn when the points map fails validation, e.g. holds text vectors of the wrong
+    /// dimension
+    #[arg(long)]
+    lenient: bool,
+    /// Quick mode: triage this fraction of the clusters, stratified by size, and extrapolate the
+    /// deletions and bytes freed to all of them in `estimate_report.json`. The entries of
+    /// `final_classification.json` are marked as a sample, stage11 refuses them
+    #[arg(long, conflicts_with = "time_budget")]
+    sample_fraction: Option<f64>,
+    /// Seed of the sample, random when unset, the report records it
+    #[arg(long, requires = "sample_fraction")]
+    sample_seed: Option<u64>,
+    /// Share the sample out between size buckets by their points rather than their clusters
+    #[arg(long, requires = "sample_fraction")]
+    sample_by_size: bool,
+}
+
 fn main() -> Result<()> {
-    let stdout = tracing_subscriber::fmt::layer().with_filter(EnvFilter::new(
-        env::var("STDOUT_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-    ));
-    let file_appender = RollingFileAppender::new(Rotation::HOURLY, "logs", "stage9.log");
-    let file = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_filter(EnvFilter::new(
-            env::var("FILE_LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-        ));
-    tracing_subscriber::registry()
-        .with(stdout)
-        .with(file)
-        .init();
-    let points_clusters: Vec<HashSet<Uuid>> =
-        serde_pickle::from_slice(&fs::read(r"global_clusters.pkl")?, Default::default())?;
-    let points_metadata = fs::read(r"points_map.bin")?;
-    let points_metadata_ex: HashMap<Uuid, NekoPoint> =
-        bincode::serde::decode_from_slice(&points_metadata, bincode::config::standard())?.0;
-    let s3_file_data = fs::read(r"opendal_list_file_after_rename_simplify.bin")?;
-    let s3_file_data: Vec<shared::opendal::Entry> =
-        bincode::serde::decode_from_slice(&s3_file_data, bincode::config::standard())?.0;
+    let _logging = Logging::new("stage9").init()?;
+    let cli = Cli::parse();
+    let thresholds = Thresholds::init()?;
+    tracing::info!("Similarity thresholds {:?}", thresholds);
+    let frame_distance = cli
+        .gif_frame_max_distance
+        .unwrap_or(thresholds.frame_hash_distance);
+    let budget = cli.time_budget.map(TimeBudget::start);
+    if let Some(fraction) = cli.sample_fraction {
+        anyhow::ensure!(
+            fraction > 0.0 && fraction <= 1.0,
+            "--sample-fraction {} is not in (0, 1]",
+            fraction
+        );
+    }
+    let sample = cli.sample_fraction.map(|fraction| SampleInfo {
+        fraction,
+        seed: cli.sample_seed.unwrap_or_else(rand::random),
+        by_size: cli.sample_by_size,
+    });
+    let inputs = Stage9Inputs {
+        clusters: cli.clusters,
+        points_map: cli.points_map,
+        file_list: cli.file_list,
+        clip_model: cli.clip_model_path,
+        animated_overrides: cli.animated_overrides,
+        point_explorer: cli.point_explorer,
+        triage_dir: cli.triage_dir,
+        output_dir: cli.output_dir,
+    };
+    validate_inputs(&inputs)?;
+    // the model is needed last, load it before hours of downloading and GIF decoding
+    let clip_config = ClipConfig::baai_bge_vl_large();
+    let grouper = match (cli.triage_mode, inputs.clip_model.as_deref()) {
+        (TriageMode::Clip, Some(path)) => {
+            let clip_model_path = path
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("CLIP model path is not valid UTF-8"))?;
+            let batch_size = match cli.clip_auto_batch {
+                true => BatchSize::Auto {
+                    max: cli.clip_batch_size,
+                },
+                false => BatchSize::Fixed(cli.clip_batch_size),
+            };
+            let mut worker =
+                ClipWorker::new(clip_model_path, clip_config.clone(), DType::BF16, true)?
+                    .batch_size(batch_size);
+            if let Some(min_coverage) = cli.gif_frame_min_coverage {
+                worker = worker.frame_check(FrameCheck::new(min_coverage, frame_distance));
+            }
+            GifGrouper::Clip(Box::new(worker))
+        }
+        (TriageMode::Clip, None) => anyhow::bail!("The clip triage mode needs --clip-model-path"),
+        (TriageMode::HashOnly, _) => {
+            tracing::warn!("Hash-only triage mode, duplicate GIFs are grouped without CLIP");
+            GifGrouper::Hash(HashTriage::new(
+                cli.gif_frame_min_coverage
+                    .unwrap_or(HashTriage::DEFAULT_MIN_COVERAGE),
+                frame_distance,
+            ))
+        }
+    };
+    let output = |name: &str| inputs.output_dir.join(name);
+    let phase = tracing::info_span!("stage9.load").entered();
+    let mut points_metadata_ex: HashMap<Uuid, NekoPoint> = load_neko_points(&inputs.points_map)?;
+    let validation = validate_neko_points(&points_metadata_ex);
+    if !validation.is_clean() {
+        anyhow::ensure!(
+            cli.lenient,
+            "{} is invalid, pass --lenient to run anyway: {}",
+            inputs.points_map.display(),
+            validation
+        );
+        tracing::warn!(
+            "{} is invalid, continuing because of --lenient: {}",
+            inputs.points_map.display(),
+            validation
+        );
+    }
+    // as in stage22, text vectors of the wrong dimension are left out instead of compared
+    let mut wrong_dim = 0;
+    for point in points_metadata_ex.values_mut() {
+        if point
+            .text_info
+            .as_ref()
+            .is_some_and(|t| t.text_vector.len() != TEXT_VECTOR_DIM)
+        {
+            point.text_info = None;
+            wrong_dim += 1;
+        }
+    }
+    if wrong_dim > 0 {
+        tracing::warn!(
+            "Skipped {} text vectors that are not {} long",
+            wrong_dim,
+            TEXT_VECTOR_DIM
+        );
+    }
+    let s3_file_data = fs::read(&inputs.file_list)?;
+    let (s3_file_data, _) = shared::opendal::decode_entry_list(&s3_file_data)?;
     tracing::info!("Successfully loaded data from files.");
     let s3_pre_map: HashMap<String, shared::opendal::Entry> = s3_file_data
         .into_iter()
@@ -258,8 +582,139 @@ fn main() -> Result<()> {
         })
         .collect();
     tracing::info!("S3 metadata: {:?}", points_metadata.len());
+    let coverage = CoverageSink::new();
+    let clusters_reader = open_clusters(&inputs.clusters, LegacyFormat::Pickle)?;
+    let loaded = clusters_reader.total();
+    if !clusters_reader.is_streaming() {
+        tracing::warn!(
+            "{} is a legacy pickle and was decoded whole, `clusters-convert` makes it streamable",
+            inputs.clusters.display()
+        );
+    }
+    // Clusters are consumed as they are read, only the (possibly split) ones below are kept
+    let mut read_error = None;
+    let mut without_metadata: Vec<Uuid> = Vec::new();
+    let clusters = clusters_reader.map_while(|cluster| match cluster {
+        Ok((_, cluster)) => {
+            let (with, without): (Vec<&Uuid>, Vec<&Uuid>) = cluster
+                .iter()
+                .partition(|id| points_metadata.contains_key(id));
+            coverage.saw(Stage::Load, with);
+            without_metadata.extend(without);
+            Some(cluster)
+        }
+        Err(e) => {
+            read_error = Some(e);
+            None
+        }
+    });
+    // Index in the loaded clusters of every cluster below
+    let (points_clusters, cluster_origin) = match cli.split_content_kinds {
+        true => {
+            let config = match cli.content_kind_config.as_deref() {
+                Some(path) => ContentKindConfig::load(path)?,
+                None => ContentKindConfig::default(),
+            };
+            let (clusters, origin) = split_mixed_clusters(clusters, |id| {
+                points_metadata
+                    .get(id)
+                    .map_or(ContentKind::Unknown, |(pt, _)| classify(pt, &config))
+            });
+            tracing::info!(
+                "Split clusters mixing screenshots and photos, {} clusters from {}",
+                clusters.len(),
+                loaded
+            );
+            (clusters, origin)
+        }
+        false => {
+            let clusters: Vec<HashSet<Uuid>> = clusters.collect();
+            let origin = (0..clusters.len()).collect::<Vec<usize>>();
+            (clusters, origin)
+        }
+    };
+    if let Some(e) = read_error {
+        return Err(e.into());
+    }
+    if !without_metadata.is_empty() {
+        tracing::warn!(
+            "{} clustered points are missing from the points map",
+            without_metadata.len()
+        );
+        coverage.fail(
+            Stage::Load,
+            &without_metadata,
+            "missing from the points map",
+        );
+    }
+    drop(phase);
+    let phase = tracing::info_span!("stage9.extract").entered();
+    let mut candidate = AnimatedCandidate::new(cli.animated_exts);
+    if let Some(path) = inputs.animated_overrides.as_deref() {
+        let overrides = AnimatedCandidate::load_overrides(path)?;
+        tracing::info!(
+            "Loaded {} animated overrides from {}",
+            overrides.len(),
+            path.display()
+        );
+        candidate = candidate.with_overrides(overrides);
+    }
+    let undecodable: Vec<&String> = candidate
+        .extensions()
+        .iter()
+        .filter(|ext| !ANIMATED_EXTS.contains(&ext.as_str()))
+        .collect();
+    if !undecodable.is_empty() {
+        tracing::warn!(
+            "AnimatedMediaWorker decodes {:?}, other candidates ({:?}) will be reported as invalid",
+            ANIMATED_EXTS,
+            undecodable
+        );
+    }
+    // Strata over the clusters above and the index there of every sampled cluster below
+    let mut sampled: Option<(Vec<Stratum>, Vec<usize>)> = None;
+    let (points_clusters, cluster_origin) = match &sample {
+        Some(sample) => {
+            let sizes: Vec<usize> = points_clusters.iter().map(HashSet::len).collect();
+            let strata = estimate::sample_clusters(&sizes, sample);
+            let mut picked: Vec<usize> = strata
+                .iter()
+                .flat_map(|s| s.sampled.iter().copied())
+                .collect();
+            picked.sort_unstable();
+            tracing::warn!(
+                "Sample run (seed {}): {} of {} clusters, final_classification.json is marked as \
+                 a sample",
+                sample.seed,
+                picked.len(),
+                points_clusters.len()
+            );
+            let clusters = picked.iter().map(|&i| points_clusters[i].clone()).collect();
+            let origin = picked.iter().map(|&i| cluster_origin[i]).collect();
+            sampled = Some((strata, picked));
+            (clusters, origin)
+        }
+        None => (points_clusters, cluster_origin),
+    };
+    let size_of = |id: &Uuid| points_metadata.get(id).and_then(|(pt, _)| pt.size);
+    let estimated_savings: Vec<u64> = points_clusters
+        .par_iter()
+        .map(|cluster| savings::estimated_savings(cluster, size_of))
+        .collect();
+    // Clusters with the biggest potential savings go through GIF/CLIP first
+    let schedule = Schedule::by_priority_desc(&estimated_savings);
     // Vec<(Option<Vec<KeptTextAnomaliesPic>>, Option<Vec<NeedTriageGifs>>, Option<KeptNonGif>, Option<Vec<OtherNeedDeletePics>>)>
-    let extract_clusters_res = extract_clusters(&points_clusters, &points_metadata);
+    let extract_clusters_res = extract_clusters(
+        points_clusters.par_iter().enumerate(),
+        &points_metadata,
+        &candidate,
+        cli.text_cluster_cap,
+        thresholds.text_sim,
+    );
+    for (text, gifs, non_gif, others) in &extract_clusters_res {
+        let ids = text.iter().chain(gifs).chain(others).flatten().copied();
+        coverage.saw(Stage::Extract, ids.chain(*non_gif));
+    }
     let all_kept_text_anomalies: Vec<Option<&Vec<&Uuid>>> = extract_clusters_res
         .iter()
         .map(|(opt_text, _, _, _)| opt_text.as_ref())
@@ -268,22 +723,22 @@ fn main() -> Result<()> {
         .iter()
         .map(|(_, opt_gifs, _, _)| opt_gifs.as_ref())
         .collect();
-    // flatten!
-    let all_need_triage_gifs_flat: Vec<&Uuid> = all_need_triage_gifs
+    // flatten! (in schedule order, so downloads follow the same priority)
+    let all_need_triage_gifs_flat: Vec<&Uuid> = schedule
+        .order()
         .iter()
-        .filter_map(|opt_ref| *opt_ref)
+        .filter_map(|&idx| all_need_triage_gifs[idx])
         .flat_map(|vec_of_uuids| vec_of_uuids.iter().copied())
         .collect();
     // flatten!
-    let all_kept_non_gif_path_map: HashMap<&Uuid, String> = all_need_triage_gifs_flat
-        .iter()
-        .map(|&uuid| (uuid, format!("nekoimg_stage9_gifs/{}.gif", uuid)))
-        .collect();
-    // flatten!
-    let all_kept_non_gif_path_ref: Vec<(&Uuid, &str)> = all_kept_non_gif_path_map
-        .iter()
-        .map(|(&uuid, path)| (uuid, path.as_str()))
-        .collect();
+    let all_kept_non_gif_path_map: HashMap<&Uuid, String> = triage_candidate::triage_path_map(
+        &all_need_triage_gifs_flat,
+        &points_metadata,
+        inputs
+            .triage_dir
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Triage dir is not valid UTF-8"))?,
+    );
     let all_kept_non_gif: Vec<Option<&Uuid>> = extract_clusters_res
         .iter()
         .map(|(_, _, opt_ng, _)| *opt_ng)
@@ -311,22 +766,11 @@ fn main() -> Result<()> {
         "all_kept_non_gif, len = {:?}",
         all_kept_non_gif.iter().filter(|opt| opt.is_some()).count()
     );
-
-    // Now, we need download all_need_triage_gifs_flat from S3
-    tracing::info!("Starting S3 download for triage GIFs...");
-    let triage_gif_downloader = S3Downloader::new(20, false)?;
-    let download_result =
-        triage_gif_downloader.download_files(all_kept_non_gif_path_ref.as_slice());
-    match download_result {
-        Ok(_) => tracing::info!("Successfully downloaded all triage GIFs."),
-        Err(e) => tracing::error!("Failed to download triage GIFs: {}", e),
-    }
+    drop(phase);
 
     // Now, Refine GIFs
     // TODO: boki fefe7ce9-6965-541a-b103-a56364fb7ea8 vs bbdc9c8d-b333-54b5-b438-15fda974be7e
-    tracing::info!("Starting refining GIFs...");
-    let clip_config = ClipConfig::baai_bge_vl_large();
-    let refine_gif_worker = GifWorker::new(clip_config.image_size as u32); // in
+    let mut refine_gif_worker = AnimatedMediaWorker::new(clip_config.image_size as u32); // in
     let triage_req: TriageGifGroupsGifStageReq = all_need_triage_gifs
         .iter()
         .map(|&opt| {
@@ -344,71 +788,808 @@ fn main() -> Result<()> {
             })
         })
         .collect();
-    serde_json::to_string(&triage_req).map(|s| fs::write("triage_gifs_req.json", s))??;
-    let mut refine_gif_res = refine_gif_worker.process(&triage_req)?;
-    serde_json::to_string(&refine_gif_res).map(|s| fs::write("triage_gifs_res.json", s))??;
-    tracing::info!("Refine GIFs result: {:?}", refine_gif_res.len());
+    serde_json::to_string(&triage_req).map(|s| fs::write(output("triage_gifs_req.json"), s))??;
+    let triage_req = schedule.apply(triage_req);
 
-    // Calculate all gif embeddings
-    let clip_req: TriageGifGroupsClipStageReq = refine_gif_res
-        .iter_mut()
-        .map(|opt_pair| opt_pair.as_mut().map(|p| p.prepare_clip_gif_pair.take()))
+    // Download, refine and embed batch by batch, so a time budget can stop between them
+    let triage_gif_downloader = S3Downloader::new(cli.download_worker_num, false)?
+        .max_dimension(cli.max_download_dimension)
+        .chunks(cli.download_chunk_size, cli.download_chunk_concurrency)
+        .max_bytes_per_sec(cli.max_download_bytes_per_sec)
+        .prefetch_cache(cli.prefetch_bytes);
+    let batch_clusters = match (&budget, cli.prefetch_bytes) {
+        (None, None) => triage_req.len(),
+        _ => cli.budget_batch_clusters,
+    };
+    let batch_paths = |range: Range<usize>| {
+        schedule.order()[range]
+            .iter()
+            .filter_map(|&idx| all_need_triage_gifs[idx])
+            .flat_map(|vec_of_uuids| vec_of_uuids.iter().copied())
+            .filter_map(|uuid| {
+                let path = all_kept_non_gif_path_map.get(uuid)?;
+                let remote = triage_candidate::remote_path(uuid, &points_metadata)
+                    .expect("Remote path must be present for GIFs");
+                Some((uuid, remote, path.as_str()))
+            })
+            .collect::<Vec<(&Uuid, &str, &str)>>()
+    };
+    let next_batch = |end: usize| end..(end + batch_clusters).min(triage_req.len());
+    let mut refine_gif_res: TriageGifGroupsGifStageRes = Vec::new();
+    let mut refine_gif_dump: Vec<serde_json::Value> = Vec::new();
+    let mut clip_res: TriageGifGroupsClipStageRes = Vec::new();
+    let mut stored_gifs: Vec<StoredGif> = Vec::new();
+    let processed = run_batches(triage_req.len(), batch_clusters, budget.as_ref(), |range| {
+        let _batch = tracing::info_span!("stage9.batch", clusters = ?range).entered();
+        let batch_path_ref = batch_paths(range.clone());
+        tracing::info!(
+            "Starting S3 download for {} triage GIFs of clusters {}..{}",
+            batch_path_ref.len(),
+            range.start,
+            range.end
+        );
+        let downloaded = tracing::info_span!("stage9.download", gifs = batch_path_ref.len())
+            .in_scope(|| triage_gif_downloader.download_files(batch_path_ref.as_slice()));
+        coverage.saw(Stage::Download, batch_path_ref.iter().map(|&(id, _, _)| id));
+        let mut aborted = None;
+        match downloaded {
+            Ok(_) => tracing::info!("Successfully downloaded all triage GIFs."),
+            Err(DownloadError::Final(files)) => {
+                tracing::error!("Failed to download {} triage GIFs", files.len());
+                for file in &files {
+                    coverage.fail(Stage::Download, [file.file_id], &file.error);
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to download triage GIFs: {}", e);
+                aborted = Some(e.to_string());
+            }
+        }
+        let stored = triage_gif_downloader.take_stored();
+        // the batch stopped midway, the GIFs it left without a local copy failed with it
+        if let Some(reason) = aborted {
+            let missing = batch_path_ref
+                .iter()
+                .filter(|&&(_, _, local)| !Path::new(local).exists())
+                .map(|&(id, _, _)| id);
+            coverage.fail(Stage::Download, missing, &reason);
+        }
+        refine_gif_worker.note_downscaled(stored.iter().filter_map(|gif| {
+            gif.downscaled_to?;
+            Some((gif.id, gif.original_dimensions?))
+        }));
+        stored_gifs.extend(stored);
+        let prefetched = triage_gif_downloader.prefetch(&batch_paths(next_batch(range.end)));
+        if prefetched > 0 {
+            tracing::info!("Prefetching {} triage GIFs of the next batch", prefetched);
+        }
+
+        tracing::info!("Starting refining GIFs...");
+        let mut batch_gif_res = tracing::info_span!("stage9.refine")
+            .in_scope(|| refine_gif_worker.process(&triage_req[range.clone()]))?;
+        for res in &batch_gif_res {
+            refine_gif_dump.push(serde_json::to_value(res)?);
+        }
+
+        // Calculate all gif embeddings
+        let clip_req: TriageGifGroupsClipStageReq = batch_gif_res
+            .iter_mut()
+            .map(|opt_pair| opt_pair.as_mut().map(|p| p.prepare_clip_gif_pair.take()))
+            .collect();
+        for pair in batch_gif_res.iter().flatten() {
+            coverage.saw(Stage::Refine, coverage::refined(pair));
+        }
+        let group = tracing::info_span!("stage9.group", mode = ?cli.triage_mode);
+        let batch_clip_res = group.in_scope(|| grouper.group(clip_req))?;
+        for pair in batch_clip_res.iter().flatten().flatten() {
+            coverage.saw(Stage::Group, coverage::grouped(pair));
+        }
+        clip_res.extend(batch_clip_res);
+        refine_gif_res.extend(batch_gif_res);
+        if let Some(budget) = &budget {
+            tracing::info!(
+                "{} of {} clusters processed after {:?}",
+                range.end,
+                triage_req.len(),
+                budget.elapsed()
+            );
+        }
+        Ok(())
+    })?;
+    // the budget stopped before the batch fetched ahead
+    let cancelled = triage_gif_downloader.cancel_prefetch(&batch_paths(next_batch(processed)));
+    if let Some(stats) = triage_gif_downloader.prefetch_stats() {
+        tracing::info!(
+            "Prefetch hit rate {:.3}: {:?}, {} left unfinished",
+            stats.hit_rate(),
+            stats,
+            cancelled
+        );
+    }
+    if processed < triage_req.len() {
+        tracing::warn!(
+            "Time budget nearly used up, {} of {} clusters left for the next run",
+            triage_req.len() - processed,
+            triage_req.len()
+        );
+    }
+    // Back to the original order, only the processed clusters
+    let refine_gif_res = schedule.restore_prefix(refine_gif_res);
+    let refine_gif_dump: Vec<serde_json::Value> = schedule
+        .restore_prefix(refine_gif_dump)
+        .into_iter()
+        .map(|(_, res)| res)
         .collect();
-    let model_path = PathBuf::from(env::var("CLIP_MODEL_PATH")?);
-    let worker = ClipWorker::new(model_path.to_str().unwrap(), clip_config, DType::BF16, true)?;
-    let clip_res = worker.get_images_embedding_adapted::<bf16>(clip_req)?;
-    let serde_clip_res = serde_json::to_string(&clip_res)?;
-    fs::write("clip_embeddings.json", serde_clip_res)?;
+    serde_json::to_string(&refine_gif_dump)
+        .map(|s| fs::write(output("triage_gifs_res.json"), s))??;
+    tracing::info!("Refine GIFs result: {:?}", refine_gif_res.len());
+    serde_json::to_string(&stored_gifs)
+        .map(|s| atomic_write(output("downloaded_gifs.json"), s))??;
+    let (original_bytes, stored_bytes) = stored_gifs.iter().fold((0, 0), |(o, s), gif| {
+        (o + gif.original_bytes, s + gif.stored_bytes)
+    });
+    tracing::info!(
+        "Downloaded {} GIFs, {} downscaled, {:.2} MB stored of {:.2} MB",
+        stored_gifs.len(),
+        stored_gifs
+            .iter()
+            .filter(|gif| gif.downscaled_to.is_some())
+            .count(),
+        stored_bytes as f64 / 1e6,
+        original_bytes as f64 / 1e6
+    );
+    let mut clip_res = schedule.restore_prefix(clip_res);
+    let serde_clip_res =
+        serde_json::to_string(&clip_res.iter().map(|(_, res)| res).collect::<Vec<_>>())?;
+    atomic_write(output("clip_embeddings.json"), serde_clip_res)?;
     tracing::info!("Clip embeddings calculated!");
 
     // final stage
-    let final_classification = extract_clusters_res
-        .into_iter()
-        .zip(refine_gif_res.into_iter())
-        .zip(clip_res.into_iter())
-        .map(|((mut cluster_tuple, gif_stage_pair), clip_stage_pair)| {
-            let (kept_text_anomalies_group, _, kept_non_gif, other_need_delete_group) =
-                &mut cluster_tuple;
-            FinalClassification {
-                kept_text_anomalies_group: kept_text_anomalies_group
-                    .take()
-                    .map(|vec| vec.into_iter().copied().collect()),
-                triaged_gif_and_invalid_group: gif_stage_pair
-                    .as_ref()
-                    .and_then(|pair| pair.invalid_gif_id.as_ref())
-                    .map(|(uuids, strings)| {
-                        (
-                            uuids.into_iter().map(|uuid| **uuid).collect(),
-                            strings.clone(),
-                        )
-                    }),
-                triaged_gif_and_discard_same_frame_group: gif_stage_pair
-                    .as_ref()
-                    .and_then(|pair| pair.discard_same_frame_gif_id.as_ref())
-                    .map(|vec| vec.into_iter().map(|uuid| **uuid).collect()),
-                triaged_gif_and_then_will_keep_group: clip_stage_pair
-                    .as_ref()
-                    .and_then(|inner_opt| inner_opt.as_ref())
-                    .and_then(|pair| pair.kept_gifs.as_ref())
-                    .map(|gifs| gifs.iter().map(|gif| *gif.uuid).collect()),
-                triaged_gif_and_then_will_delete_group: clip_stage_pair
-                    .as_ref()
-                    .and_then(|inner_opt| inner_opt.as_ref())
-                    .and_then(|pair| pair.discard_duplicate_gifs.as_ref())
-                    .map(|gifs| gifs.iter().map(|gif| *gif.uuid).collect()),
-                kept_non_gif: kept_non_gif.take().copied(),
-                other_need_delete_group: other_need_delete_group
-                    .take()
-                    .map(|vec| vec.into_iter().copied().collect()),
+    let phase = tracing::info_span!("stage9.classify").entered();
+    let mut final_classification: Vec<FinalClassification> = Vec::with_capacity(clip_res.len());
+    let mut conflicted: HashSet<usize> = HashSet::new();
+    for ((idx, gif_stage_pair), (clip_idx, clip_stage_pair)) in
+        refine_gif_res.into_iter().zip(&clip_res)
+    {
+        debug_assert_eq!(idx, *clip_idx);
+        let built = FinalClassificationBuilder::from(&extract_clusters_res[idx])
+            .gif_stage(gif_stage_pair.as_ref())
+            .clip_stage(clip_stage_pair.as_ref())
+            .cluster_index(cluster_origin[idx])
+            .sample(sample)
+            .build();
+        match built {
+            Ok(fc) => final_classification.push(fc),
+            // one contradicting cluster is left out, not the whole run
+            Err(e) => {
+                tracing::error!("{}, the cluster is left out", e);
+                coverage.fail(Stage::Classify, &points_clusters[idx], &e.to_string());
+                conflicted.insert(idx);
             }
-        })
-        .collect::<Vec<FinalClassification>>();
+        }
+    }
+    if !conflicted.is_empty() {
+        tracing::warn!(
+            "{} clusters left out of the classification, see unaccounted_points.json",
+            conflicted.len()
+        );
+    }
+    clip_res.retain(|(idx, _)| !conflicted.contains(idx));
+    let final_cluster_idx: Vec<usize> = clip_res.iter().map(|&(idx, _)| idx).collect();
     // dump it!
     serde_json::to_string(&final_classification)
-        .map(|s| fs::write("final_classification.json", s))??;
+        .map(|s| atomic_write(output("final_classification.json"), s))??;
     tracing::info!(
         "Final classification result: {:?}",
         final_classification.len()
     );
+    for fc in &final_classification {
+        coverage.saw(Stage::Classify, &fc.handled());
+    }
+    if budget.is_some() {
+        let remaining_clusters: Vec<&HashSet<Uuid>> = schedule
+            .remaining(processed)
+            .into_iter()
+            .map(|idx| &points_clusters[idx])
+            .collect();
+        atomic_write_with(output("remaining_clusters.pkl"), |w| {
+            write_clusters(w, remaining_clusters.iter().copied())
+        })?;
+        tracing::info!(
+            "Saved {} remaining clusters to remaining_clusters.pkl",
+            remaining_clusters.len()
+        );
+        for cluster in remaining_clusters {
+            coverage.defer(Stage::Schedule, cluster, "time budget used up");
+        }
+    }
+    let coverage_report = coverage.reconcile(&points_clusters, &final_classification);
+    serde_json::to_string_pretty(&coverage_report)
+        .map(|s| atomic_write(output("unaccounted_points.json"), s))??;
+    tracing::info!(
+        "Coverage of {} input points: {:?}",
+        coverage_report.input_points,
+        coverage_report.buckets
+    );
+    if !coverage_report.conflicting.is_empty() {
+        tracing::warn!(
+            "{} points are in more than one of kept, deleted, deferred and failed, see unaccounted_points.json",
+            coverage_report.conflicting.len()
+        );
+    }
+    if !coverage_report.is_complete() {
+        tracing::error!(
+            "{} input points are neither kept, deleted, deferred nor failed, see unaccounted_points.json",
+            coverage_report.unaccounted.len()
+        );
+        if cli.strict_coverage {
+            anyhow::bail!(
+                "{} unaccounted points with --strict-coverage",
+                coverage_report.unaccounted.len()
+            );
+        }
+    }
+    drop(phase);
+    let phase = tracing::info_span!("stage9.review").entered();
+    let explorer: Option<PointExplorer<f32, 768>> = match inputs.point_explorer.as_deref() {
+        Some(path) => Some(
+            PointExplorerBuilder::new()
+                .path(path.to_string_lossy())
+                .build()?,
+        ),
+        None => {
+            tracing::warn!("No point explorer given, review queue has no similarities");
+            None
+        }
+    };
+    let review_rules = ReviewRules {
+        uncertain_below: cli.review_uncertain_below,
+        protected_tags: cli.review_protected_tags.into_iter().collect(),
+        size_ratio: cli.review_size_ratio,
+        url_prefix: cli.review_url_prefix,
+        text_max_graphemes: cli.report_text_max_graphemes,
+    };
+    let review_queue = build_review_queue(
+        &final_classification,
+        &points_metadata,
+        |a, b| {
+            let pe = explorer.as_ref()?;
+            Some(cosine_sim(pe.get_vector(a)?, pe.get_vector(b)?))
+        },
+        &review_rules,
+    );
+    serde_json::to_string_pretty(&review_queue)
+        .map(|s| atomic_write(output("review_queue.json"), s))??;
+    tracing::info!("{} clusters need a manual review", review_queue.len());
+    let explanations: Vec<ClusterExplanation> = final_classification
+        .iter()
+        .zip(&final_cluster_idx)
+        .zip(&clip_res)
+        .enumerate()
+        .map(|(index, ((fc, &idx), (_, clip_stage_pair)))| {
+            let unmerged: Vec<(Uuid, Uuid, f32)> = clip_stage_pair
+                .iter()
+                .flatten()
+                .flat_map(|p| p.unmerged_gifs.iter().flatten())
+                .map(|u| (*u.gif, *u.kept, u.coverage))
+                .collect();
+            explain_cluster(
+                index,
+                &points_clusters[idx],
+                fc,
+                &text_subclusters(
+                    &points_clusters[idx],
+                    &points_metadata,
+                    cli.text_cluster_cap,
+                    thresholds.text_sim,
+                ),
+                &unmerged,
+                &points_metadata,
+                &|a, b| {
+                    let pe = explorer.as_ref()?;
+                    Some(cosine_sim(pe.get_vector(a)?, pe.get_vector(b)?))
+                },
+            )
+        })
+        .collect();
+    write_explanations(output("explanations.jsonl"), &explanations)?;
+    tracing::info!("Explained {} clusters", explanations.len());
+    drop(phase);
+    let phase = tracing::info_span!("stage9.shared_text").entered();
+    let text_index = TextIndex::build(
+        points_metadata
+            .par_iter()
+            .filter_map(|(id, (pt, _))| Some((*id, pt.text_info.as_ref()?.text.as_str()))),
+    );
+    if text_index.is_empty() {
+        tracing::warn!("No point has an OCR text, shared_text_groups.json lists nothing");
+    }
+    let cluster_of: HashMap<Uuid, usize> = points_clusters
+        .iter()
+        .zip(&cluster_origin)
+        .flat_map(|(cluster, &origin)| cluster.iter().map(move |id| (*id, origin)))
+        .collect();
+    let shared_text_report = shared_texts(
+        &text_index,
+        &cluster_of,
+        &SharedTextConfig {
+            min_clusters: cli.shared_text_min_clusters,
+            max_distance: cli.shared_text_simhash_distance,
+            top: cli.shared_text_top,
+            max_text_graphemes: cli.report_text_max_graphemes,
+        },
+    );
+    serde_json::to_string_pretty(&shared_text_report)
+        .map(|s| atomic_write(output("shared_text_groups.json"), s))??;
+    tracing::info!(
+        "{} distinct texts over {} points, {} seen in at least {} clusters",
+        shared_text_report.distinct_texts,
+        shared_text_report.text_points,
+        shared_text_report.groups.len(),
+        cli.shared_text_min_clusters
+    );
+    drop(phase);
+    let _phase = tracing::info_span!("stage9.savings").entered();
+    let mut realized_savings = vec![0u64; points_clusters.len()];
+    let realized: Vec<(usize, u64)> = final_cluster_idx
+        .par_iter()
+        .zip(&final_classification)
+        .map(|(&idx, fc)| (idx, savings::realized_savings(fc, size_of)))
+        .collect();
+    for (idx, bytes) in realized {
+        realized_savings[idx] = bytes;
+    }
+    let summary = SavingsSummary::new(
+        &schedule.order()[..processed],
+        &estimated_savings,
+        &realized_savings,
+    )
+    .triage_mode(cli.triage_mode);
+    serde_json::to_string(&summary).map(|s| atomic_write(output("savings_summary.json"), s))??;
+    tracing::info!(
+        "This run freed approximately {:.2} GB (estimated {:.2} GB)",
+        summary.realized_gb(),
+        summary.estimated_gb()
+    );
+    if let (Some(sample), Some((strata, picked))) = (sample, &sampled) {
+        let outcomes: HashMap<usize, ClusterOutcome> = final_cluster_idx
+            .iter()
+            .zip(&final_classification)
+            .map(|(&idx, fc)| {
+                let outcome = ClusterOutcome {
+                    deletions: fc.discarded().len() as u64,
+                    bytes: realized_savings[idx],
+                };
+                (picked[idx], outcome)
+            })
+            .collect();
+        let report = EstimateReport::new(sample, strata, |idx| {
+            outcomes.get(&idx).copied().unwrap_or_default()
+        });
+        serde_json::to_string_pretty(&report)
+            .map(|s| atomic_write(output("estimate_report.json"), s))??;
+        tracing::info!(
+            "Extrapolated from {} of {} clusters: {:.0} deletions ({:.0} to {:.0}), {:.2} GB \
+             ({:.2} to {:.2}) at 95% confidence",
+            report.sampled_clusters,
+            report.clusters,
+            report.deletions.total,
+            report.deletions.low,
+            report.deletions.high,
+            report.gb.total,
+            report.gb.low,
+            report.gb.high
+        );
+    }
+    let metrics_file = inputs.output_dir.join(&cli.metrics_file);
+    shared::metrics::dump_prometheus_to(&metrics_file)?;
+    tracing::info!("Metrics saved to {}", metrics_file.display());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::config::DEFAULT_TEXT_SIM;
+    use shared::fixtures::{ClusterSpec, Fixture, FixtureSpec, GifMotion};
+    use shared::structure::{TriageGifClip, TriageGifGroupsClipStagePair, UnmergedGif};
+
+    const GOLDEN_EXPLANATIONS: &str = "../assets/golden/stage9_explanations.jsonl";
+
+    fn metadata(fixture: &Fixture) -> HashMap<Uuid, (NekoPoint, NekoPointExt)> {
+        fixture
+            .points
+            .iter()
+            .map(|p| (p.id, (p.clone(), fixture.exts[&p.id].clone())))
+            .collect()
+    }
+
+    fn sorted<'a>(ids: impl IntoIterator<Item = &'a Uuid>) -> Vec<Uuid> {
+        let mut ids: Vec<Uuid> = ids.into_iter().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn test_text_anomalies_clusters() {
+        let fixture = FixtureSpec::new(1)
+            .cluster(ClusterSpec::new(3, 0.99).text_sim(0.95))
+            .cluster(ClusterSpec::new(2, 0.99).text_sim(0.95))
+            .cluster(ClusterSpec::new(3, 0.99).text_sim(0.5))
+            .generate()
+            .unwrap();
+        let points_metadata = metadata(&fixture);
+        let text_points: Vec<&Uuid> = fixture.truth.iter().flatten().collect();
+        let text = find_text_anomalies_clusters(
+            &text_points,
+            &points_metadata,
+            DEFAULT_TEXT_CLUSTER_CAP,
+            DEFAULT_TEXT_SIM,
+        );
+        assert_eq!(text.strategy, TextStrategy::Greedy);
+        let clusters = text.clusters;
+        // texts below the text similarity threshold each stay alone
+        let mut expected: Vec<Vec<Uuid>> = fixture.truth[..2].to_vec();
+        expected.extend(fixture.truth[2].iter().map(|id| vec![*id]));
+        let clusters: Vec<Vec<Uuid>> = clusters.into_iter().map(sorted).collect();
+        assert_eq!(
+            clusters,
+            expected.into_iter().map(|c| sorted(&c)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_extract_text_only_cluster() {
+        let fixture = FixtureSpec::new(2)
+            .cluster(ClusterSpec::new(3, 0.99).text_sim(0.95))
+            .generate()
+            .unwrap();
+        let points_metadata = metadata(&fixture);
+        let clusters = vec![fixture.truth[0].iter().copied().collect::<HashSet<_>>()];
+        let extracted = extract_clusters(
+            clusters.par_iter().enumerate(),
+            &points_metadata,
+            &AnimatedCandidate::default(),
+            DEFAULT_TEXT_CLUSTER_CAP,
+            DEFAULT_TEXT_SIM,
+        );
+        let (anomalies, gifs, kept, rest) = &extracted[0];
+        // the largest text point is kept, the others are reported without any GIF triage
+        let largest = fixture.truth[0][2];
+        assert_eq!(anomalies.as_deref(), Some(&[&largest][..]));
+        assert!(gifs.is_none() && kept.is_none());
+        assert_eq!(
+            sorted(rest.clone().unwrap()),
+            sorted(&fixture.truth[0][..2])
+        );
+    }
+
+    #[test]
+    fn test_extract_mixed_cluster() {
+        let fixture = FixtureSpec::new(3)
+            .cluster(ClusterSpec::new(2, 0.99).text_sim(0.95))
+            .cluster(ClusterSpec::new(3, 0.99).gif(6, GifMotion::Moving))
+            .cluster(ClusterSpec::new(2, 0.99))
+            .generate()
+            .unwrap();
+        let points_metadata = metadata(&fixture);
+        let clusters = vec![
+            fixture
+                .truth
+                .iter()
+                .flatten()
+                .copied()
+                .collect::<HashSet<_>>(),
+        ];
+        let extracted = extract_clusters(
+            clusters.par_iter().enumerate(),
+            &points_metadata,
+            &AnimatedCandidate::default(),
+            DEFAULT_TEXT_CLUSTER_CAP,
+            DEFAULT_TEXT_SIM,
+        );
+        let (anomalies, gifs, kept, delete) = &extracted[0];
+        let [text, gif, png] = &fixture.truth[..] else {
+            unreachable!()
+        };
+        assert_eq!(anomalies.as_deref(), Some(&[&text[1]][..]));
+        // with GIFs in the cluster no static image is kept, the GIF triage decides
+        assert_eq!(sorted(gifs.clone().unwrap()), sorted(gif));
+        assert!(kept.is_none());
+        let mut expected = vec![text[0]];
+        expected.extend(png);
+        assert_eq!(sorted(delete.clone().unwrap()), sorted(&expected));
+    }
+
+    #[test]
+    fn test_text_strategy_at_cap() {
+        let fixture = FixtureSpec::new(10)
+            .cluster(ClusterSpec::new(3, 0.99).text_sim(0.95))
+            .cluster(ClusterSpec::new(2, 0.99).text_sim(0.95))
+            .generate()
+            .unwrap();
+        let points_metadata = metadata(&fixture);
+        let text_points: Vec<&Uuid> = fixture.truth.iter().flatten().collect();
+        let greedy =
+            find_text_anomalies_clusters(&text_points, &points_metadata, 5, DEFAULT_TEXT_SIM);
+        let union_find =
+            find_text_anomalies_clusters(&text_points, &points_metadata, 4, DEFAULT_TEXT_SIM);
+        assert_eq!(greedy.strategy, TextStrategy::Greedy);
+        assert_eq!(union_find.strategy, TextStrategy::UnionFind);
+        let sorted_clusters = |text: TextClusters| {
+            let mut clusters: Vec<Vec<Uuid>> = text.clusters.into_iter().map(sorted).collect();
+            clusters.sort_unstable();
+            clusters
+        };
+        assert_eq!(sorted_clusters(greedy), sorted_clusters(union_find));
+    }
+
+    #[test]
+    fn test_extract_every_text_point_alone() {
+        let fixture = FixtureSpec::new(11)
+            .cluster(ClusterSpec::new(4, 0.99).text_sim(0.5))
+            .generate()
+            .unwrap();
+        let points_metadata = metadata(&fixture);
+        let clusters = vec![fixture.truth[0].iter().copied().collect::<HashSet<_>>()];
+        // as many text anomaly clusters as text points, under and over the cap
+        for cap in [DEFAULT_TEXT_CLUSTER_CAP, 0] {
+            let extracted = extract_clusters(
+                clusters.par_iter().enumerate(),
+                &points_metadata,
+                &AnimatedCandidate::default(),
+                cap,
+                DEFAULT_TEXT_SIM,
+            );
+            let (anomalies, gifs, kept, rest) = &extracted[0];
+            assert_eq!(
+                sorted(anomalies.clone().unwrap()),
+                sorted(&fixture.truth[0])
+            );
+            assert!(gifs.is_none() && kept.is_none());
+            assert_eq!(rest.as_deref(), Some(&[][..]));
+        }
+    }
+
+    #[test]
+    fn test_coverage_catches_dropped_gif() {
+        let fixture = FixtureSpec::new(12)
+            .cluster(ClusterSpec::new(3, 0.99).gif(6, GifMotion::Moving))
+            .cluster(ClusterSpec::new(2, 0.99))
+            .generate()
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("coverage_{}", Uuid::new_v4()));
+        let paths = fixture.write(&dir).unwrap();
+        let points_metadata = metadata(&fixture);
+        let clusters: Vec<HashSet<Uuid>> = fixture
+            .truth
+            .iter()
+            .map(|c| c.iter().copied().collect())
+            .collect();
+        let extracted = extract_clusters(
+            clusters.par_iter().enumerate(),
+            &points_metadata,
+            &AnimatedCandidate::default(),
+            DEFAULT_TEXT_CLUSTER_CAP,
+            DEFAULT_TEXT_SIM,
+        );
+        let coverage = CoverageSink::new();
+        for (text, gifs, non_gif, others) in &extracted {
+            let ids = text.iter().chain(gifs).chain(others).flatten().copied();
+            coverage.saw(Stage::Extract, ids.chain(*non_gif));
+        }
+        let gif_paths: HashMap<Uuid, String> = fixture.truth[0]
+            .iter()
+            .map(|id| {
+                let (key, _) = fixture
+                    .objects
+                    .iter()
+                    .find(|(key, _)| key.starts_with(&id.to_string()))
+                    .unwrap();
+                (*id, paths.objects.join(key).to_string_lossy().into_owned())
+            })
+            .collect();
+        let triage_req: TriageGifGroupsGifStageReq = extracted
+            .iter()
+            .map(|(_, gifs, _, _)| {
+                gifs.as_ref().map(|gifs| {
+                    gifs.iter()
+                        .map(|&uuid| TriageGif {
+                            uuid,
+                            path: &gif_paths[uuid],
+                            size: points_metadata[uuid].0.size.unwrap(),
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        let mut gif_res = AnimatedMediaWorker::new(32).process(&triage_req).unwrap();
+        for pair in gif_res.iter().flatten() {
+            coverage.saw(Stage::Refine, coverage::refined(pair));
+        }
+        // a grouper losing one GIF: the biggest is kept, the smallest reported nowhere
+        let clip_res: TriageGifGroupsClipStageRes = gif_res
+            .iter_mut()
+            .map(|pair| {
+                let mut gifs = pair.as_mut()?.prepare_clip_gif_pair.take()?;
+                gifs.sort_by_key(|gif| std::cmp::Reverse(gif.size));
+                let mut gifs = gifs.into_iter().map(|clip: TriageGifClip| TriageGif {
+                    uuid: clip.id,
+                    path: clip.path,
+                    size: clip.size,
+                });
+                Some(Some(TriageGifGroupsClipStagePair {
+                    kept_gifs: Some(vec![gifs.next()?]),
+                    discard_duplicate_gifs: Some(vec![gifs.next()?]),
+                    unmerged_gifs: None,
+                }))
+            })
+            .collect();
+        for pair in clip_res.iter().flatten().flatten() {
+            coverage.saw(Stage::Group, coverage::grouped(pair));
+        }
+        let final_classification: Vec<FinalClassification> = extracted
+            .iter()
+            .zip(&gif_res)
+            .zip(&clip_res)
+            .map(|((extracted, gif_pair), clip_pair)| {
+                FinalClassificationBuilder::from(extracted)
+                    .gif_stage(gif_pair.as_ref())
+                    .clip_stage(clip_pair.as_ref())
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        let report = coverage.reconcile(&clusters, &final_classification);
+        let smallest = *fixture.truth[0]
+            .iter()
+            .min_by_key(|id| points_metadata[*id].0.size)
+            .unwrap();
+        assert_eq!(
+            report.unaccounted,
+            vec![coverage::Unaccounted {
+                id: smallest,
+                last_stage: Some(Stage::Refine),
+            }]
+        );
+        assert!(report.conflicting.is_empty());
+        assert_eq!(report.input_points, 5);
+    }
+
+    /// Set `UPDATE_GOLDEN=1` to rewrite the golden file after an intended change
+    #[test]
+    fn test_explanations_golden() {
+        let mut fixture = FixtureSpec::new(9)
+            .cluster(ClusterSpec::new(3, 0.99).text_sim(0.95))
+            .cluster(ClusterSpec::new(3, 0.99))
+            .cluster(ClusterSpec::new(3, 0.97).gif(6, GifMotion::Moving))
+            .cluster(ClusterSpec::new(2, 0.99).gif(6, GifMotion::Still))
+            .generate()
+            .unwrap();
+        // fixed sizes, the encoded ones depend on the image encoders
+        for (i, point) in fixture.points.iter_mut().enumerate() {
+            point.size = Some(1000 + 100 * i);
+        }
+        let dir = std::env::temp_dir().join(format!("explanations_{}", Uuid::new_v4()));
+        let paths = fixture.write(&dir).unwrap();
+        let points_metadata = metadata(&fixture);
+        let explorer = fixture.image_explorer();
+        let [text, png, moving, still] = &fixture.truth[..] else {
+            unreachable!()
+        };
+        let clusters: Vec<HashSet<Uuid>> = vec![
+            text.iter().chain(png).copied().collect(),
+            moving.iter().chain(still).copied().collect(),
+        ];
+        let extracted = extract_clusters(
+            clusters.par_iter().enumerate(),
+            &points_metadata,
+            &AnimatedCandidate::default(),
+            DEFAULT_TEXT_CLUSTER_CAP,
+            DEFAULT_TEXT_SIM,
+        );
+        let object = |id: &Uuid| -> String {
+            let (key, _) = fixture
+                .objects
+                .iter()
+                .find(|(key, _)| key.starts_with(&id.to_string()))
+                .unwrap();
+            paths.objects.join(key).to_string_lossy().into_owned()
+        };
+        let gif_paths: HashMap<Uuid, String> = moving
+            .iter()
+            .chain(still)
+            .map(|id| (*id, object(id)))
+            .collect();
+        let triage_req: TriageGifGroupsGifStageReq = extracted
+            .iter()
+            .map(|(_, gifs, _, _)| {
+                gifs.as_ref().map(|gifs| {
+                    gifs.iter()
+                        .map(|&uuid| TriageGif {
+                            uuid,
+                            path: &gif_paths[uuid],
+                            size: points_metadata[uuid].0.size.unwrap(),
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        let gif_res = AnimatedMediaWorker::new(32).process(&triage_req).unwrap();
+        // CLIP stand-in: the biggest moving GIF is kept, the smallest split off by the frame check
+        fn copy<'a>(gif: &TriageGif<'a>) -> TriageGif<'a> {
+            TriageGif {
+                uuid: gif.uuid,
+                path: gif.path,
+                size: gif.size,
+            }
+        }
+        let clip_res: TriageGifGroupsClipStageRes = triage_req
+            .iter()
+            .map(|gifs| {
+                let mut moving_gifs: Vec<&TriageGif> = gifs
+                    .as_ref()?
+                    .iter()
+                    .filter(|gif| moving.contains(gif.uuid))
+                    .collect();
+                moving_gifs.sort_by_key(|gif| std::cmp::Reverse(gif.size));
+                let [kept, duplicate, unmerged] = moving_gifs[..] else {
+                    unreachable!()
+                };
+                Some(Some(TriageGifGroupsClipStagePair {
+                    kept_gifs: Some(vec![copy(kept), copy(unmerged)]),
+                    discard_duplicate_gifs: Some(vec![copy(duplicate)]),
+                    unmerged_gifs: Some(vec![UnmergedGif {
+                        gif: unmerged.uuid,
+                        kept: kept.uuid,
+                        coverage: 0.5,
+                    }]),
+                }))
+            })
+            .collect();
+        let explanations: Vec<ClusterExplanation> = extracted
+            .iter()
+            .zip(&gif_res)
+            .zip(&clip_res)
+            .enumerate()
+            .map(|(idx, ((extracted, gif_pair), clip_pair))| {
+                let fc = FinalClassificationBuilder::from(extracted)
+                    .gif_stage(gif_pair.as_ref())
+                    .clip_stage(clip_pair.as_ref())
+                    .cluster_index(idx)
+                    .build()
+                    .unwrap();
+                let unmerged: Vec<(Uuid, Uuid, f32)> = clip_pair
+                    .iter()
+                    .flatten()
+                    .flat_map(|p| p.unmerged_gifs.iter().flatten())
+                    .map(|u| (*u.gif, *u.kept, u.coverage))
+                    .collect();
+                explain_cluster(
+                    idx,
+                    &clusters[idx],
+                    &fc,
+                    &text_subclusters(
+                        &clusters[idx],
+                        &points_metadata,
+                        DEFAULT_TEXT_CLUSTER_CAP,
+                        DEFAULT_TEXT_SIM,
+                    ),
+                    &unmerged,
+                    &points_metadata,
+                    &|a, b| Some(cosine_sim(explorer.get_vector(a)?, explorer.get_vector(b)?)),
+                )
+            })
+            .collect();
+        let out = dir.join("explanations.jsonl");
+        write_explanations(&out, &explanations).unwrap();
+        let written = fs::read_to_string(&out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(GOLDEN_EXPLANATIONS, &written).unwrap();
+        }
+        let golden = fs::read_to_string(GOLDEN_EXPLANATIONS).unwrap();
+        assert_eq!(written, golden, "set UPDATE_GOLDEN=1 to accept the change");
+    }
+}
@@ -0,0 +1,242 @@
+//! Images fetched over HTTP, fed to [`ClipWorker`](crate::clip_worker::ClipWorker) like paths
+
+use crate::clip_worker::{ClipWorkerInput, resize_to_raw};
+use image::ImageReader;
+use shared::image_ext::decode_image;
+use std::io::Cursor;
+use std::time::Duration;
+use ureq::Agent;
+
+/// Limits of a single fetch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FetchConfig {
+    /// Whole request, connecting through reading the last byte
+    pub timeout: Duration,
+    /// Responses with a longer body are rejected rather than buffered
+    pub max_bytes: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl FetchConfig {
+    /// Agent enforcing the timeout, shared by the fetches of a batch to reuse connections
+    pub fn agent(&self) -> Agent {
+        Agent::config_builder()
+            .timeout_global(Some(self.timeout))
+            .http_status_as_error(false)
+            .build()
+            .into()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteImageError {
+    #[error("Fetching {url} timed out after {timeout:?}")]
+    Timeout { url: String, timeout: Duration },
+    #[error("{url} is larger than {max_bytes} bytes")]
+    TooLarge { url: String, max_bytes: u64 },
+    #[error("{url} answered with status {status}")]
+    Status { url: String, status: u16 },
+    #[error("Failed to fetch {url}: {source}")]
+    Request {
+        url: String,
+        #[source]
+        source: ureq::Error,
+    },
+    #[error("Failed to decode {url}: {source}")]
+    Decode {
+        url: String,
+        #[source]
+        source: image::ImageError,
+    },
+}
+
+/// Image behind a URL, downloaded and decoded on every [`ClipWorkerInput::to_raw`]
+#[derive(Debug, Clone)]
+pub struct RemoteImage {
+    pub url: String,
+    pub fetch: FetchConfig,
+}
+
+impl RemoteImage {
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Self {
+            url: url.into(),
+            fetch: FetchConfig::default(),
+        }
+    }
+
+    pub fn with_fetch(mut self, fetch: FetchConfig) -> Self {
+        self.fetch = fetch;
+        self
+    }
+
+    /// Response body, at most `max_bytes` of it
+    pub fn fetch(&self, agent: &Agent) -> Result<Vec<u8>, RemoteImageError> {
+        let mut response = agent
+            .get(&self.url)
+            .call()
+            .map_err(|e| self.request_error(e))?;
+        let status = response.status().as_u16();
+        if status >= 400 {
+            return Err(RemoteImageError::Status {
+                url: self.url.clone(),
+                status,
+            });
+        }
+        response
+            .body_mut()
+            .with_config()
+            .limit(self.fetch.max_bytes)
+            .read_to_vec()
+            .map_err(|e| self.request_error(e))
+    }
+
+    /// Fetched, decoded and resized like [`ClipWorkerInput::to_raw`] of a path
+    pub fn fetch_raw(
+        &self,
+        agent: &Agent,
+        size: usize,
+        apply_orientation: bool,
+    ) -> Result<Vec<u8>, RemoteImageError> {
+        let body = self.fetch(agent)?;
+        let reader = ImageReader::new(Cursor::new(body));
+        let img =
+            decode_image(reader, apply_orientation).map_err(|e| RemoteImageError::Decode {
+                url: self.url.clone(),
+                source: e,
+            })?;
+        Ok(resize_to_raw(&img, size))
+    }
+
+    fn request_error(&self, e: ureq::Error) -> RemoteImageError {
+        let url = self.url.clone();
+        match e {
+            ureq::Error::Timeout(_) => RemoteImageError::Timeout {
+                url,
+                timeout: self.fetch.timeout,
+            },
+            ureq::Error::BodyExceedsLimit(_) => RemoteImageError::TooLarge {
+                url,
+                max_bytes: self.fetch.max_bytes,
+            },
+            source => RemoteImageError::Request { url, source },
+        }
+    }
+}
+
+impl ClipWorkerInput for RemoteImage {
+    fn to_raw(&self, size: usize, apply_orientation: bool) -> anyhow::Result<Vec<u8>> {
+        Ok(self.fetch_raw(&self.fetch.agent(), size, apply_orientation)?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use http_body_util::Full;
+    use hyper::body::{Bytes, Incoming};
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    pub(crate) const FIXTURE: &str = "../assets/test_images/bsn_0.jpg";
+
+    async fn route(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+        let body = match req.uri().path() {
+            "/bsn_0.jpg" => std::fs::read(FIXTURE).unwrap(),
+            "/slow.jpg" => {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                std::fs::read(FIXTURE).unwrap()
+            }
+            "/large.jpg" => vec![0; 256 * 1024],
+            "/broken.jpg" => b"not an image".to_vec(),
+            _ => {
+                let mut response = Response::new(Full::new(Bytes::new()));
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                return Ok(response);
+            }
+        };
+        Ok(Response::new(Full::new(Bytes::from(body))))
+    }
+
+    /// Serves the routes above on a background thread, for the rest of the test binary
+    pub(crate) fn serve() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(async move {
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service_fn(route))
+                            .await;
+                    });
+                }
+            });
+        });
+        addr
+    }
+
+    #[test]
+    fn test_fetch_raw() -> anyhow::Result<()> {
+        let addr = serve();
+        let remote = RemoteImage::new(format!("http://{}/bsn_0.jpg", addr));
+        assert_eq!(remote.to_raw(224, true)?, FIXTURE.to_raw(224, true)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_errors() {
+        let addr = serve();
+        let fetch = FetchConfig {
+            timeout: Duration::from_millis(300),
+            max_bytes: 64 * 1024,
+        };
+        let agent = fetch.agent();
+        let fetch_raw = |path: &str| {
+            RemoteImage::new(format!("http://{}{}", addr, path))
+                .with_fetch(fetch)
+                .fetch_raw(&agent, 224, true)
+                .unwrap_err()
+        };
+        assert!(matches!(
+            fetch_raw("/slow.jpg"),
+            RemoteImageError::Timeout { timeout, .. } if timeout == fetch.timeout
+        ));
+        assert!(matches!(
+            fetch_raw("/large.jpg"),
+            RemoteImageError::TooLarge {
+                max_bytes: 65536,
+                ..
+            }
+        ));
+        assert!(matches!(
+            fetch_raw("/missing.jpg"),
+            RemoteImageError::Status { status: 404, .. }
+        ));
+        assert!(matches!(
+            fetch_raw("/broken.jpg"),
+            RemoteImageError::Decode { .. }
+        ));
+        let err = fetch_raw("/large.jpg");
+        assert!(err.to_string().contains("/large.jpg"), "{}", err);
+    }
+}
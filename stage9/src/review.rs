@@ -0,0 +1,544 @@
+use serde::{Deserialize, Serialize};
+use shared::structure::{FinalClassification, NekoPoint, NekoPointExt};
+use shared::text::sanitize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use uuid::Uuid;
+
+/// Why a cluster was put in the review queue
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewReason {
+    /// A deleted member's closest kept member is below `uncertain_below`
+    UncertainSimilarity,
+    /// Members disagree on a protected tag
+    MixedProtectedTags,
+    /// A deleted member is over `size_ratio` times bigger than every kept one
+    KeptMuchSmaller,
+}
+
+/// Flagging rules of the review queue
+#[derive(Debug, Clone)]
+pub struct ReviewRules {
+    pub uncertain_below: f32,
+    pub protected_tags: HashSet<String>,
+    pub size_ratio: f64,
+    /// Joined with the S3 key into `url`
+    pub url_prefix: Option<String>,
+    /// Member texts are cut to this many graphemes
+    pub text_max_graphemes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReviewMember {
+    pub id: Uuid,
+    /// What the automatic decision does with it
+    pub kept: bool,
+    pub remote_path: Option<String>,
+    pub url: Option<String>,
+    pub size: Option<usize>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub text: Option<String>,
+    pub categories: Option<Vec<String>>,
+}
+
+/// Closest kept member of a deleted one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReviewSimilarity {
+    pub deleted: Uuid,
+    pub kept: Uuid,
+    pub similarity: f32,
+}
+
+/// `"approve"`, `"keep_all"` or `{"custom": [uuid, ...]}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewDecision {
+    /// The automatic decision stands
+    Approve,
+    /// Nothing in the cluster is deleted
+    KeepAll,
+    /// Exactly these members are kept, the rest deleted
+    Custom(Vec<Uuid>),
+}
+
+/// One entry of `review_queue.json`, the reviewed file has the same schema
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReviewItem {
+    /// Index into `final_classification.json`
+    pub index: usize,
+    pub reasons: Vec<ReviewReason>,
+    pub members: Vec<ReviewMember>,
+    pub similarities: Vec<ReviewSimilarity>,
+    /// Set by the reviewer, items left unset keep the automatic decision
+    #[serde(default)]
+    pub decision: Option<ReviewDecision>,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ReviewError {
+    #[error("Review of cluster {index} is out of range, there are {len} clusters")]
+    OutOfRange { index: usize, len: usize },
+    #[error("Cluster {index} is reviewed more than once")]
+    Duplicate { index: usize },
+    #[error(
+        "Review of cluster {index} lists other members, the classification changed since export"
+    )]
+    ClusterMismatch { index: usize },
+    #[error("Review of cluster {index} keeps {id}, which is not a member")]
+    ForeignKeep { index: usize, id: Uuid },
+    #[error("Review of cluster {index} keeps nothing, delete requests do not go through review")]
+    EmptyKeep { index: usize },
+}
+
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct MergeReport {
+    pub approved: usize,
+    pub kept_all: usize,
+    pub custom: usize,
+    pub undecided: usize,
+}
+
+fn review_member(
+    id: Uuid,
+    kept: bool,
+    points_metadata: &HashMap<Uuid, (NekoPoint, NekoPointExt)>,
+    url_prefix: Option<&str>,
+    text_max_graphemes: usize,
+) -> ReviewMember {
+    let point = points_metadata.get(&id).map(|(pt, _)| pt);
+    let remote_path = crate::triage_candidate::remote_path(&id, points_metadata);
+    ReviewMember {
+        id,
+        kept,
+        remote_path: remote_path.map(str::to_string),
+        url: url_prefix.zip(remote_path).map(|(prefix, path)| {
+            format!(
+                "{}/{}",
+                prefix.trim_end_matches('/'),
+                path.trim_start_matches('/')
+            )
+        }),
+        size: point.and_then(|pt| pt.size),
+        width: point.map(|pt| pt.width),
+        height: point.map(|pt| pt.height),
+        text: point.and_then(|pt| {
+            pt.text_info
+                .as_ref()
+                .map(|t| sanitize(&t.text, text_max_graphemes))
+        }),
+        categories: point.and_then(|pt| pt.categories.clone()),
+    }
+}
+
+/// Flags one cluster, `None` when no rule applies or nothing is deleted
+pub fn review_item<S>(
+    index: usize,
+    classification: &FinalClassification,
+    points_metadata: &HashMap<Uuid, (NekoPoint, NekoPointExt)>,
+    similarity: &S,
+    rules: &ReviewRules,
+) -> Option<ReviewItem>
+where
+    S: Fn(&Uuid, &Uuid) -> Option<f32>,
+{
+    let kept = classification.kept();
+    let deleted = classification.discarded();
+    if deleted.is_empty() {
+        return None;
+    }
+    let mut reasons = Vec::new();
+    let similarities: Vec<ReviewSimilarity> = deleted
+        .iter()
+        .filter_map(|d| {
+            kept.iter()
+                .filter_map(|k| similarity(k, d).map(|s| (k, s)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(k, s)| ReviewSimilarity {
+                    deleted: *d,
+                    kept: *k,
+                    similarity: s,
+                })
+        })
+        .collect();
+    if similarities
+        .iter()
+        .any(|s| s.similarity < rules.uncertain_below)
+    {
+        reasons.push(ReviewReason::UncertainSimilarity);
+    }
+    let protected = |id: &Uuid| -> BTreeSet<&str> {
+        points_metadata
+            .get(id)
+            .and_then(|(pt, _)| pt.categories.as_ref())
+            .into_iter()
+            .flatten()
+            .filter(|tag| rules.protected_tags.contains(*tag))
+            .map(String::as_str)
+            .collect()
+    };
+    let tag_sets: Vec<BTreeSet<&str>> = kept.iter().chain(deleted.iter()).map(protected).collect();
+    if tag_sets.windows(2).any(|pair| pair[0] != pair[1]) {
+        reasons.push(ReviewReason::MixedProtectedTags);
+    }
+    let size_of = |id: &Uuid| points_metadata.get(id).and_then(|(pt, _)| pt.size);
+    let biggest_kept = kept.iter().filter_map(size_of).max();
+    let biggest_deleted = deleted.iter().filter_map(size_of).max();
+    if biggest_kept
+        .zip(biggest_deleted)
+        .is_some_and(|(k, d)| d as f64 > k as f64 * rules.size_ratio)
+    {
+        reasons.push(ReviewReason::KeptMuchSmaller);
+    }
+    if reasons.is_empty() {
+        return None;
+    }
+    let url_prefix = rules.url_prefix.as_deref();
+    let members = kept
+        .iter()
+        .map(|&id| (id, true))
+        .chain(deleted.iter().map(|&id| (id, false)))
+        .map(|(id, kept)| {
+            review_member(
+                id,
+                kept,
+                points_metadata,
+                url_prefix,
+                rules.text_max_graphemes,
+            )
+        })
+        .collect();
+    Some(ReviewItem {
+        index,
+        reasons,
+        members,
+        similarities,
+        decision: None,
+    })
+}
+
+pub fn build_review_queue<S>(
+    classifications: &[FinalClassification],
+    points_metadata: &HashMap<Uuid, (NekoPoint, NekoPointExt)>,
+    similarity: S,
+    rules: &ReviewRules,
+) -> Vec<ReviewItem>
+where
+    S: Fn(&Uuid, &Uuid) -> Option<f32>,
+{
+    classifications
+        .iter()
+        .enumerate()
+        .filter_map(|(index, fc)| review_item(index, fc, points_metadata, &similarity, rules))
+        .collect()
+}
+
+fn members(classification: &FinalClassification) -> HashSet<Uuid> {
+    let mut members: HashSet<Uuid> = classification.kept().into_iter().collect();
+    members.extend(classification.discarded());
+    members
+}
+
+/// The entry a reviewer's keep list turns into, every automatic group is dropped
+fn reviewed(
+    entry: &FinalClassification,
+    members: &HashSet<Uuid>,
+    keep: &HashSet<Uuid>,
+) -> FinalClassification {
+    let mut kept: Vec<Uuid> = keep.iter().copied().collect();
+    let mut deleted: Vec<Uuid> = members.difference(keep).copied().collect();
+    kept.sort();
+    deleted.sort();
+    FinalClassification {
+        kept_text_anomalies_group: None,
+        triaged_gif_and_invalid_group: None,
+        triaged_gif_and_discard_same_frame_group: None,
+        triaged_gif_and_then_will_keep_group: None,
+        triaged_gif_and_then_will_delete_group: None,
+        kept_non_gif: None,
+        other_need_delete_group: Some(deleted).filter(|d| !d.is_empty()),
+        reviewed_keep_group: Some(kept),
+        gif_metadata: entry.gif_metadata.clone(),
+        cluster_index: entry.cluster_index,
+        sample: entry.sample,
+    }
+}
+
+/// Applies reviewed decisions in place, nothing is changed unless every item is valid
+pub fn merge_reviews(
+    classifications: &mut [FinalClassification],
+    reviews: &[ReviewItem],
+) -> Result<MergeReport, ReviewError> {
+    let mut report = MergeReport::default();
+    let mut seen = HashSet::new();
+    let mut overrides = Vec::new();
+    for item in reviews {
+        let index = item.index;
+        let entry = classifications.get(index).ok_or(ReviewError::OutOfRange {
+            index,
+            len: classifications.len(),
+        })?;
+        if !seen.insert(index) {
+            return Err(ReviewError::Duplicate { index });
+        }
+        let members = members(entry);
+        let listed: HashSet<Uuid> = item.members.iter().map(|m| m.id).collect();
+        if members != listed {
+            return Err(ReviewError::ClusterMismatch { index });
+        }
+        match &item.decision {
+            None => report.undecided += 1,
+            Some(ReviewDecision::Approve) => report.approved += 1,
+            Some(ReviewDecision::KeepAll) => {
+                report.kept_all += 1;
+                overrides.push((index, reviewed(entry, &members, &members)));
+            }
+            Some(ReviewDecision::Custom(keep)) => {
+                if keep.is_empty() {
+                    return Err(ReviewError::EmptyKeep { index });
+                }
+                if let Some(&id) = keep.iter().find(|id| !members.contains(id)) {
+                    return Err(ReviewError::ForeignKeep { index, id });
+                }
+                report.custom += 1;
+                let keep: HashSet<Uuid> = keep.iter().copied().collect();
+                overrides.push((index, reviewed(entry, &members, &keep)));
+            }
+        }
+    }
+    for (index, entry) in overrides {
+        classifications[index] = entry;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::structure::{NekoPointExtResource, NekoPointText};
+    use shared::test_util::{self, id};
+
+    /// keeps 1, deletes 2 and 3
+    fn classification() -> FinalClassification {
+        FinalClassification {
+            triaged_gif_and_then_will_delete_group: Some(vec![id(3)]),
+            ..test_util::classification(1, &[2])
+        }
+    }
+
+    fn metadata() -> HashMap<Uuid, (NekoPoint, NekoPointExt)> {
+        [(1, 100, "a"), (2, 150, "a,r18"), (3, 500, "a")]
+            .into_iter()
+            .map(|(n, size, tags): (u128, usize, &str)| {
+                let pt = NekoPoint {
+                    id: id(n),
+                    height: 10,
+                    width: 20,
+                    size: Some(size),
+                    categories: Some(tags.split(',').map(str::to_string).collect()),
+                    text_info: None,
+                    qdrant_num_id: None,
+                    extra: None,
+                };
+                let ext = NekoPointExt {
+                    source: Some(NekoPointExtResource::Local(format!("{}.png", id(n)))),
+                };
+                (id(n), (pt, ext))
+            })
+            .collect()
+    }
+
+    fn rules() -> ReviewRules {
+        ReviewRules {
+            uncertain_below: 0.99,
+            protected_tags: HashSet::new(),
+            size_ratio: 10.0,
+            url_prefix: None,
+            text_max_graphemes: 8,
+        }
+    }
+
+    fn item(decision: Option<ReviewDecision>) -> ReviewItem {
+        let metadata = metadata();
+        let mut item =
+            review_item(0, &classification(), &metadata, &|_, _| Some(0.5), &rules()).unwrap();
+        item.decision = decision;
+        item
+    }
+
+    #[test]
+    fn test_rules() {
+        let metadata = metadata();
+        let fc = classification();
+        let sim = |_: &Uuid, d: &Uuid| Some(if *d == id(2) { 0.995 } else { 0.98 });
+        let mut rules = rules();
+        let flagged = review_item(0, &fc, &metadata, &sim, &rules).unwrap();
+        assert_eq!(flagged.reasons, vec![ReviewReason::UncertainSimilarity]);
+        assert_eq!(flagged.similarities.len(), 2);
+        assert_eq!(
+            flagged
+                .members
+                .iter()
+                .map(|m| (m.id, m.kept))
+                .collect::<Vec<_>>(),
+            vec![(id(1), true), (id(3), false), (id(2), false)]
+        );
+        assert_eq!(
+            flagged.members[0].remote_path,
+            Some(format!("{}.png", id(1)))
+        );
+        assert!(flagged.members[0].url.is_none());
+        // confident clusters are left alone
+        assert!(review_item(0, &fc, &metadata, &|_, _| Some(0.999), &rules).is_none());
+        assert!(review_item(0, &fc, &metadata, &|_, _| None, &rules).is_none());
+        rules.protected_tags.insert("r18".to_string());
+        rules.size_ratio = 4.0;
+        rules.url_prefix = Some("https://img.example/".to_string());
+        let flagged = review_item(0, &fc, &metadata, &|_, _| None, &rules).unwrap();
+        assert_eq!(
+            flagged.reasons,
+            vec![
+                ReviewReason::MixedProtectedTags,
+                ReviewReason::KeptMuchSmaller
+            ]
+        );
+        assert_eq!(
+            flagged.members[0].url,
+            Some(format!("https://img.example/{}.png", id(1)))
+        );
+    }
+
+    #[test]
+    fn test_member_text() {
+        let mut metadata = metadata();
+        for (n, text) in [(1, "caf\u{65}\u{301} \u{202e}au lait, ok?"), (3, "短い")] {
+            metadata.get_mut(&id(n)).unwrap().0.text_info = Some(NekoPointText {
+                text: text.to_string(),
+                text_vector: vec![],
+            });
+        }
+        let flagged =
+            review_item(0, &classification(), &metadata, &|_, _| Some(0.5), &rules()).unwrap();
+        let texts: Vec<Option<&str>> = flagged.members.iter().map(|m| m.text.as_deref()).collect();
+        assert_eq!(texts, vec![Some("caf\u{e9} au …"), Some("短い"), None]);
+        // the stored text is left as read
+        assert!(
+            metadata[&id(1)]
+                .0
+                .text_info
+                .as_ref()
+                .unwrap()
+                .text
+                .contains('\u{202e}')
+        );
+    }
+
+    #[test]
+    fn test_decision_json() {
+        let json = serde_json::to_value(item(Some(ReviewDecision::Custom(vec![id(2)])))).unwrap();
+        assert_eq!(json["decision"]["custom"][0], id(2).to_string());
+        let mut json = serde_json::to_value(item(None)).unwrap();
+        json.as_object_mut().unwrap().remove("decision");
+        json["reasons"] = serde_json::json!(["kept_much_smaller"]);
+        let parsed: ReviewItem = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.decision, None);
+        for (text, decision) in [
+            ("\"approve\"", ReviewDecision::Approve),
+            ("\"keep_all\"", ReviewDecision::KeepAll),
+        ] {
+            assert_eq!(
+                serde_json::from_str::<ReviewDecision>(text).unwrap(),
+                decision
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_approve_and_undecided() {
+        let mut fcs = vec![classification(), classification()];
+        let mut second = item(None);
+        second.index = 1;
+        let report =
+            merge_reviews(&mut fcs, &[item(Some(ReviewDecision::Approve)), second]).unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                approved: 1,
+                undecided: 1,
+                ..Default::default()
+            }
+        );
+        for fc in fcs.iter() {
+            assert_eq!(fc.kept(), vec![id(1)]);
+            assert!(fc.reviewed_keep_group.is_none());
+        }
+    }
+
+    #[test]
+    fn test_merge_keep_all() {
+        let mut fcs = vec![classification()];
+        let report = merge_reviews(&mut fcs, &[item(Some(ReviewDecision::KeepAll))]).unwrap();
+        assert_eq!(report.kept_all, 1);
+        assert_eq!(fcs[0].reviewed_keep_group, Some(vec![id(1), id(2), id(3)]));
+        assert!(fcs[0].discarded().is_empty());
+        assert!(fcs[0].kept_non_gif.is_none());
+    }
+
+    #[test]
+    fn test_merge_custom() {
+        let mut fcs = vec![classification()];
+        let decision = ReviewDecision::Custom(vec![id(3), id(2), id(3)]);
+        let report = merge_reviews(&mut fcs, &[item(Some(decision))]).unwrap();
+        assert_eq!(report.custom, 1);
+        assert_eq!(fcs[0].kept(), vec![id(2), id(3)]);
+        assert_eq!(fcs[0].discarded(), vec![id(1)]);
+        assert!(fcs[0].triaged_gif_and_then_will_delete_group.is_none());
+    }
+
+    #[test]
+    fn test_merge_rejects_invalid_reviews() {
+        let original = vec![classification()];
+        let cases = [
+            (
+                item(Some(ReviewDecision::Custom(vec![id(1), id(9)]))),
+                ReviewError::ForeignKeep {
+                    index: 0,
+                    id: id(9),
+                },
+            ),
+            (
+                item(Some(ReviewDecision::Custom(vec![]))),
+                ReviewError::EmptyKeep { index: 0 },
+            ),
+            (
+                ReviewItem {
+                    index: 3,
+                    ..item(Some(ReviewDecision::KeepAll))
+                },
+                ReviewError::OutOfRange { index: 3, len: 1 },
+            ),
+            (
+                ReviewItem {
+                    members: item(None).members[..2].to_vec(),
+                    ..item(Some(ReviewDecision::KeepAll))
+                },
+                ReviewError::ClusterMismatch { index: 0 },
+            ),
+        ];
+        for (review, expected) in cases {
+            let mut fcs = original.clone();
+            assert_eq!(merge_reviews(&mut fcs, &[review]).unwrap_err(), expected);
+        }
+        // a valid item before a bad one is not applied either
+        let mut fcs = original.clone();
+        let reviews = [
+            item(Some(ReviewDecision::KeepAll)),
+            item(Some(ReviewDecision::Approve)),
+        ];
+        assert_eq!(
+            merge_reviews(&mut fcs, &reviews).unwrap_err(),
+            ReviewError::Duplicate { index: 0 }
+        );
+        assert_eq!(fcs[0].kept(), vec![id(1)]);
+    }
+}
@@ -1,24 +1,48 @@
+use crate::downscale::{StoredGif, downscale_gif, fit_within, gif_dimensions};
+use bytes::Bytes;
 use futures::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
+use shared::atomic_write::tmp_path;
+use shared::metrics;
 use shared::opendal::GenShinOperator;
-use std::ops::Deref;
+use shared::prefetch::{PrefetchStats, Prefetcher};
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
+/// Bytes per ranged request unless configured otherwise
+pub const DEFAULT_CHUNK_SIZE: u64 = 8 << 20;
+/// Ranges of one object in flight unless configured otherwise
+pub const DEFAULT_CHUNK_CONCURRENCY: usize = 4;
+
 #[derive(Debug)]
 struct Stage9OpenDALOperator {
     op: GenShinOperator,
     worker_num: usize,
     overwrite: bool,
+    /// GIFs whose canvas exceeds this are stored downscaled
+    max_dimension: Option<u32>,
+    /// Bytes per ranged request
+    chunk_size: u64,
+    /// Ranges of one object in flight, at most `worker_num * chunk_concurrency` chunks are held
+    chunk_concurrency: usize,
+    /// Shared by every worker
+    throttle: Option<Throttle>,
+    /// Every GIF stored since the last [`S3Downloader::take_stored`]
+    stored: Mutex<Vec<StoredGif>>,
+    /// Serves the downloads when set, filled ahead by [`S3Downloader::prefetch`]
+    prefetcher: Option<Prefetcher<GenShinOperator>>,
     // TODO: pre-check
 }
 
 #[derive(Debug)]
 pub struct DownloadErrorFile<'a> {
-    file_id: &'a Uuid,
-    error: String,
+    pub file_id: &'a Uuid,
+    pub error: String,
 }
 
 #[derive(Debug, Error)]
@@ -39,17 +63,30 @@ impl Deref for Stage9OpenDALOperator {
 
 impl Stage9OpenDALOperator {
     fn new(worker_num: usize, overwrite: bool) -> Result<Self, anyhow::Error> {
-        let op = GenShinOperator::new()?;
-        Ok(Self {
+        Ok(Self::with_operator(
+            GenShinOperator::new()?,
+            worker_num,
+            overwrite,
+        ))
+    }
+
+    fn with_operator(op: GenShinOperator, worker_num: usize, overwrite: bool) -> Self {
+        Self {
             op,
             worker_num,
             overwrite,
-        })
+            max_dimension: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            chunk_concurrency: DEFAULT_CHUNK_CONCURRENCY,
+            throttle: None,
+            stored: Mutex::new(Vec::new()),
+            prefetcher: None,
+        }
     }
 
     async fn download_files<'a>(
         &self,
-        file_list: &'a [(&'a Uuid, &'a str)],
+        file_list: &'a [(&'a Uuid, &'a str, &'a str)],
     ) -> Result<(), DownloadError<'a>> {
         let pb = ProgressBar::new(file_list.len() as u64);
         let style = ProgressStyle::default_bar()
@@ -84,12 +121,125 @@ impl Stage9OpenDALOperator {
         }
     }
 
+    /// Moves the download at `part` to `dest`, downscaled first if it is a GIF over
+    /// `max_dimension`
+    ///
+    /// `head` is the start of the object, enough to read a GIF's canvas from its header. Only a
+    /// GIF to downscale is read back into memory.
+    async fn store(
+        &self,
+        id: &Uuid,
+        part: &Path,
+        dest: &str,
+        original_bytes: u64,
+        head: &[u8],
+    ) -> anyhow::Result<()> {
+        let original_dimensions = gif_dimensions(head);
+        let downscaled = match (self.max_dimension, original_dimensions) {
+            (Some(max), Some(dims)) if fit_within(dims, max) != dims => {
+                let original = fs::read(part).await?;
+                // re-encoding is CPU bound, run it on the blocking pool so it does not stall
+                // the other downloads polled on this worker thread
+                match tokio::task::spawn_blocking(move || downscale_gif(&original, max)).await? {
+                    Ok(downscaled) => downscaled,
+                    Err(e) => {
+                        // stored as is, the GIF worker reports it if it cannot decode it either
+                        tracing::warn!("Failed to downscale GIF {}: {}", id, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let (stored_bytes, downscaled_to) = match downscaled {
+            Some((bytes, canvas)) => {
+                metrics::counter("gif_downscaled_total", &[]).inc();
+                fs::write(part, &bytes).await?;
+                (bytes.len() as u64, Some(canvas))
+            }
+            None => (original_bytes, None),
+        };
+        fs::rename(part, dest).await?;
+        metrics::counter("download_bytes_total", &[("kind", "original")]).add(original_bytes);
+        metrics::counter("download_bytes_total", &[("kind", "stored")]).add(stored_bytes);
+        self.stored.lock().unwrap().push(StoredGif {
+            id: *id,
+            original_bytes,
+            stored_bytes,
+            original_dimensions,
+            downscaled_to,
+        });
+        Ok(())
+    }
+
+    /// Streams `s3_path` into `part`, `chunk_size` ranges fetched `chunk_concurrency` at a time
+    /// and written in order as they arrive, returning its size and first chunk
+    ///
+    /// An object whose size the store does not report is read in one request, one the
+    /// prefetcher serves is written in one go.
+    async fn fetch(&self, s3_path: &str, part: &Path) -> anyhow::Result<(u64, Bytes)> {
+        if let Some(prefetcher) = &self.prefetcher {
+            let whole = prefetcher.get(s3_path).await?.to_bytes();
+            let mut file = fs::File::create(part).await?;
+            let written = tokio::io::copy(&mut whole.as_ref(), &mut file).await?;
+            file.sync_all().await?;
+            return Ok((written, whole));
+        }
+        let len = self.op.stat(s3_path).await?.content_length();
+        let chunk_size = self.chunk_size.max(1);
+        let ranges: Vec<Option<Range<u64>>> = match len {
+            0 => vec![None],
+            len => (0..len)
+                .step_by(chunk_size as usize)
+                .map(|start| Some(start..(start + chunk_size).min(len)))
+                .collect(),
+        };
+        let mut chunks = futures::stream::iter(ranges.into_iter().map(|range| async move {
+            match range {
+                Some(range) => {
+                    if let Some(throttle) = &self.throttle {
+                        throttle.wait(range.end - range.start).await;
+                    }
+                    self.op.read_range(s3_path, range).await
+                }
+                None => {
+                    let whole = self.op.read(s3_path).await?;
+                    if let Some(throttle) = &self.throttle {
+                        throttle.wait(whole.len() as u64).await;
+                    }
+                    Ok(whole)
+                }
+            }
+        }))
+        .buffered(self.chunk_concurrency.max(1));
+        let mut file = fs::File::create(part).await?;
+        let mut written = 0;
+        let mut head = Bytes::new();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?.to_bytes();
+            written += tokio::io::copy(&mut chunk.as_ref(), &mut file).await?;
+            if head.is_empty() {
+                head = chunk;
+            }
+        }
+        file.sync_all().await?;
+        anyhow::ensure!(
+            len == 0 || written == len,
+            "Got {} of {} bytes of {}",
+            written,
+            len,
+            s3_path
+        );
+        Ok((written, head))
+    }
+
+    /// `file` is (id, remote path, local path), the download only appears at the local path
+    /// once complete
     async fn download_file_atomic<'a>(
         &self,
-        file: (&'a Uuid, &'a str),
+        file: (&'a Uuid, &'a str, &'a str),
     ) -> Result<(), DownloadErrorFile<'a>> {
-        let (file_id, file_name) = file;
-        let s3_path = format!("NekoImage/{}.gif", file_id);
+        let (file_id, s3_path, file_name) = file;
         match fs::try_exists(&file_name).await {
             Ok(true) if !self.overwrite => {
                 // tracing::warn!(
@@ -106,40 +256,48 @@ impl Stage9OpenDALOperator {
             }
             _ => {}
         }
-        let mut buffer = Vec::<u8>::new();
-        let mut stream = self
-            .op
-            .read(&s3_path)
-            .await
-            .map_err(|e| DownloadErrorFile {
-                file_id,
-                error: e.to_string(),
-            })?;
-        while let Some(chunk_res) = StreamExt::next(&mut stream).await {
-            let chunk = chunk_res.map_err(|e| DownloadErrorFile {
-                file_id,
-                error: e.to_string(),
-            })?;
-            buffer.extend_from_slice(&chunk);
+        let part = tmp_path(Path::new(file_name));
+        let res = async {
+            let (original_bytes, head) = self.fetch(s3_path, &part).await?;
+            self.store(file_id, &part, file_name, original_bytes, &head)
+                .await
         }
-        let mut fs_file = fs::File::create(&file_name)
-            .await
-            .map_err(|e| DownloadErrorFile {
-                file_id,
-                error: e.to_string(),
-            })?;
-        fs_file
-            .write_all(&buffer)
-            .await
-            .map_err(|e| DownloadErrorFile {
+        .await;
+        res.map_err(|e| {
+            let _ = std::fs::remove_file(&part);
+            DownloadErrorFile {
                 file_id,
                 error: e.to_string(),
-            })?;
-        fs_file.flush().await.map_err(|e| DownloadErrorFile {
-            file_id,
-            error: e.to_string(),
-        })?;
-        Ok(())
+            }
+        })
+    }
+}
+
+/// Spaces requests out so that together they stay under a byte rate
+#[derive(Debug)]
+struct Throttle {
+    bytes_per_sec: f64,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the turn of a request of `bytes`, which pushes the next turn back by the time
+    /// they take at the rate
+    async fn wait(&self, bytes: u64) {
+        let at = {
+            let mut next = self.next.lock().unwrap();
+            let at = (*next).max(Instant::now());
+            *next = at + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec);
+            at
+        };
+        tokio::time::sleep_until(tokio::time::Instant::from_std(at)).await;
     }
 }
 
@@ -150,19 +308,158 @@ pub struct S3Downloader {
 
 impl S3Downloader {
     pub fn new(worker_num: usize, overwrite: bool) -> anyhow::Result<Self> {
-        let op = Stage9OpenDALOperator::new(worker_num, overwrite)?;
+        Ok(Self::with_op(Stage9OpenDALOperator::new(
+            worker_num, overwrite,
+        )?))
+    }
+
+    fn with_op(op: Stage9OpenDALOperator) -> Self {
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .worker_threads(op.worker_num)
             .enable_all()
             .build()
             .expect("Failed to create Tokio runtime");
-        Ok(Self { op, runtime })
+        Self { op, runtime }
+    }
+
+    /// Store GIFs whose canvas exceeds `max` pixels on a side downscaled to fit it
+    pub fn max_dimension(mut self, max: Option<u32>) -> Self {
+        self.op.max_dimension = max;
+        self
+    }
+
+    /// Download objects in ranges of `chunk_size` bytes, `concurrency` of them in flight at a time
+    pub fn chunks(mut self, chunk_size: u64, concurrency: usize) -> Self {
+        self.op.chunk_size = chunk_size;
+        self.op.chunk_concurrency = concurrency;
+        self
+    }
+
+    /// Keep all downloads together under `max` bytes per second
+    pub fn max_bytes_per_sec(mut self, max: Option<u64>) -> Self {
+        self.op.throttle = max.filter(|max| *max > 0).map(Throttle::new);
+        self
+    }
+
+    /// Fetch whole objects through a [`Prefetcher`] keeping up to `cache_bytes` of them, so
+    /// [`Self::prefetch`] can get the next batch while the current one is processed. Ranged
+    /// requests and the byte rate limit no longer apply
+    pub fn prefetch_cache(mut self, cache_bytes: Option<usize>) -> Self {
+        self.op.prefetcher = cache_bytes.map(|bytes| {
+            let source = GenShinOperator::from(self.op.op.op.clone());
+            Prefetcher::new(source, self.op.worker_num, bytes)
+        });
+        self
+    }
+
+    /// Starts fetching the files of `file_list` without a local copy in the background, returns
+    /// how many were started. Does nothing without [`Self::prefetch_cache`]
+    pub fn prefetch(&self, file_list: &[(&Uuid, &str, &str)]) -> usize {
+        let Some(prefetcher) = &self.op.prefetcher else {
+            return 0;
+        };
+        let _runtime = self.runtime.enter();
+        prefetcher.schedule(
+            file_list
+                .iter()
+                .filter(|&&(_, _, local)| self.op.overwrite || !Path::new(local).exists())
+                .map(|&(_, remote, _)| remote),
+        )
+    }
+
+    /// Drops the background fetches of `file_list` still running, returns how many were dropped
+    pub fn cancel_prefetch(&self, file_list: &[(&Uuid, &str, &str)]) -> usize {
+        match &self.op.prefetcher {
+            Some(prefetcher) => prefetcher.cancel(file_list.iter().map(|&(_, remote, _)| remote)),
+            None => 0,
+        }
+    }
+
+    pub fn prefetch_stats(&self) -> Option<PrefetchStats> {
+        self.op.prefetcher.as_ref().map(Prefetcher::stats)
+    }
+
+    /// GIFs stored since the last call, files skipped as already present are not in it
+    pub fn take_stored(&self) -> Vec<StoredGif> {
+        std::mem::take(&mut *self.op.stored.lock().unwrap())
     }
 
     pub fn download_files<'a>(
         &self,
-        file_list: &'a [(&'a Uuid, &'a str)],
+        file_list: &'a [(&'a Uuid, &'a str, &'a str)],
     ) -> Result<(), DownloadError<'a>> {
         self.runtime.block_on(self.op.download_files(file_list))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranged_download() {
+        let dir = std::env::temp_dir().join(format!("s3_downloader_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("remote")).unwrap();
+        let object: Vec<u8> = (0..100_003u32).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(dir.join("remote/a.gif"), &object).unwrap();
+        let op = GenShinOperator::fs(dir.join("remote").to_str().unwrap()).unwrap();
+        let downloader = S3Downloader::with_op(Stage9OpenDALOperator::with_operator(op, 2, false))
+            .chunks(4096, 3)
+            .max_bytes_per_sec(Some(1 << 30));
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let local_a = dir.join("a.gif").to_str().unwrap().to_owned();
+        let local_b = dir.join("b.gif").to_str().unwrap().to_owned();
+        let files = [
+            (&a, "a.gif", local_a.as_str()),
+            (&b, "b.gif", local_b.as_str()),
+        ];
+        match downloader.download_files(&files) {
+            Err(DownloadError::Final(failed)) => {
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].file_id, &b);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(std::fs::read(&local_a).unwrap(), object);
+        assert!(!Path::new(&local_b).exists());
+        let parts = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "tmp"));
+        assert_eq!(parts.count(), 0);
+        let stored = downloader.take_stored();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(
+            (stored[0].original_bytes, stored[0].stored_bytes),
+            (100_003, 100_003)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prefetched_download() {
+        let dir = std::env::temp_dir().join(format!("s3_downloader_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("remote")).unwrap();
+        std::fs::write(dir.join("remote/a.gif"), b"GIF89a a").unwrap();
+        std::fs::write(dir.join("remote/b.gif"), b"GIF89a b").unwrap();
+        let op = GenShinOperator::fs(dir.join("remote").to_str().unwrap()).unwrap();
+        let downloader = S3Downloader::with_op(Stage9OpenDALOperator::with_operator(op, 2, false))
+            .prefetch_cache(Some(1 << 20));
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let local_a = dir.join("a.gif").to_str().unwrap().to_owned();
+        let local_b = dir.join("b.gif").to_str().unwrap().to_owned();
+        let first = [(&a, "a.gif", local_a.as_str())];
+        let next = [(&b, "b.gif", local_b.as_str())];
+        assert_eq!(downloader.prefetch(&next), 1);
+        downloader.download_files(&first).unwrap();
+        downloader.download_files(&next).unwrap();
+        assert_eq!(std::fs::read(&local_a).unwrap(), b"GIF89a a");
+        assert_eq!(std::fs::read(&local_b).unwrap(), b"GIF89a b");
+        let stats = downloader.prefetch_stats().unwrap();
+        // only the first batch was fetched on demand
+        assert_eq!((stats.misses, stats.hits + stats.joined), (1, 1));
+        // stored locally, nothing left to prefetch
+        assert_eq!(downloader.prefetch(&next), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
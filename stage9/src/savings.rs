@@ -0,0 +1,172 @@
+use crate::hash_triage::TriageMode;
+use serde::Serialize;
+use shared::structure::FinalClassification;
+use uuid::Uuid;
+
+pub(crate) const GB: f64 = (1u64 << 30) as f64;
+
+/// Sum of member sizes minus the largest one, i.e. the bytes freed if a single point is kept
+///
+/// Points without a known size count as zero.
+pub fn estimated_savings<'a, I, F>(members: I, size_of: F) -> u64
+where
+    I: IntoIterator<Item = &'a Uuid>,
+    F: Fn(&Uuid) -> Option<usize>,
+{
+    let (total, largest) = members
+        .into_iter()
+        .map(|id| size_of(id).unwrap_or(0) as u64)
+        .fold((0u64, 0u64), |(total, largest), size| {
+            (total + size, largest.max(size))
+        });
+    total - largest
+}
+
+/// Bytes of every point the classification deletes
+pub fn realized_savings<F>(classification: &FinalClassification, size_of: F) -> u64
+where
+    F: Fn(&Uuid) -> Option<usize>,
+{
+    let invalid = classification
+        .triaged_gif_and_invalid_group
+        .as_ref()
+        .map(|(ids, _)| ids);
+    [
+        invalid,
+        classification
+            .triaged_gif_and_discard_same_frame_group
+            .as_ref(),
+        classification
+            .triaged_gif_and_then_will_delete_group
+            .as_ref(),
+        classification.other_need_delete_group.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .flatten()
+    .map(|id| size_of(id).unwrap_or(0) as u64)
+    .sum()
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClusterSavings {
+    /// Index into `global_clusters.pkl` / `final_classification.json`
+    pub index: usize,
+    /// Position the cluster was processed at
+    pub rank: usize,
+    pub estimated: u64,
+    pub realized: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SavingsSummary {
+    /// How the GIF duplicates behind `realized` were grouped
+    pub triage_mode: TriageMode,
+    pub total_estimated: u64,
+    pub total_realized: u64,
+    pub clusters: Vec<ClusterSavings>,
+}
+
+impl SavingsSummary {
+    /// `order[k]` is the original index processed k-th, clusters left out of `order` were not
+    /// processed and count towards neither total
+    pub fn new(order: &[usize], estimated: &[u64], realized: &[u64]) -> Self {
+        let clusters: Vec<ClusterSavings> = order
+            .iter()
+            .enumerate()
+            .map(|(rank, &index)| ClusterSavings {
+                index,
+                rank,
+                estimated: estimated[index],
+                realized: realized[index],
+            })
+            .collect();
+        Self {
+            triage_mode: TriageMode::default(),
+            total_estimated: clusters.iter().map(|c| c.estimated).sum(),
+            total_realized: clusters.iter().map(|c| c.realized).sum(),
+            clusters,
+        }
+    }
+
+    pub fn triage_mode(mut self, mode: TriageMode) -> Self {
+        self.triage_mode = mode;
+        self
+    }
+
+    #[inline]
+    pub fn realized_gb(&self) -> f64 {
+        self.total_realized as f64 / GB
+    }
+
+    #[inline]
+    pub fn estimated_gb(&self) -> f64 {
+        self.total_estimated as f64 / GB
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn sizes() -> HashMap<Uuid, usize> {
+        HashMap::from([
+            (Uuid::from_u128(1), 100),
+            (Uuid::from_u128(2), 300),
+            (Uuid::from_u128(3), 50),
+        ])
+    }
+
+    #[test]
+    fn test_estimated_savings() {
+        let sizes = sizes();
+        let size_of = |id: &Uuid| sizes.get(id).copied();
+        let ids: Vec<Uuid> = (1..=3).map(Uuid::from_u128).collect();
+        assert_eq!(estimated_savings(&ids, size_of), 150);
+        // unknown points count as zero
+        let with_missing: Vec<Uuid> = (1..=5).map(Uuid::from_u128).collect();
+        assert_eq!(estimated_savings(&with_missing, size_of), 150);
+        let only_missing = [Uuid::from_u128(4), Uuid::from_u128(5)];
+        assert_eq!(estimated_savings(&only_missing, size_of), 0);
+        assert_eq!(estimated_savings(&[Uuid::from_u128(2)], size_of), 0);
+        assert_eq!(estimated_savings(&[], size_of), 0);
+    }
+
+    #[test]
+    fn test_realized_savings() {
+        let sizes = sizes();
+        let classification = FinalClassification {
+            kept_text_anomalies_group: Some(vec![Uuid::from_u128(2)]),
+            triaged_gif_and_invalid_group: Some((vec![Uuid::from_u128(1)], vec![String::new()])),
+            triaged_gif_and_then_will_delete_group: Some(vec![Uuid::from_u128(9)]),
+            other_need_delete_group: Some(vec![Uuid::from_u128(3)]),
+            ..Default::default()
+        };
+        assert_eq!(
+            realized_savings(&classification, |id| sizes.get(id).copied()),
+            150
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = SavingsSummary::new(&[2, 0, 1], &[10, 5, 20], &[8, 0, 20]);
+        assert_eq!(summary.total_estimated, 35);
+        assert_eq!(summary.total_realized, 28);
+        assert_eq!(summary.clusters[0].index, 2);
+        assert_eq!(summary.clusters[0].estimated, 20);
+        assert_eq!(summary.clusters[2].rank, 2);
+        assert_eq!(summary.clusters[2].realized, 0);
+        // a run stopped after its first cluster
+        let summary = SavingsSummary::new(&[2], &[10, 5, 20], &[0, 0, 20]);
+        assert_eq!(summary.total_estimated, 20);
+        assert_eq!(summary.total_realized, 20);
+        assert_eq!(summary.clusters.len(), 1);
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["triage_mode"], "clip");
+        let summary = summary.triage_mode(TriageMode::HashOnly);
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["triage_mode"], "hash_only");
+    }
+}
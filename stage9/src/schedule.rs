@@ -0,0 +1,93 @@
+/// Processing order over per-cluster work, `order[k]` is the original index handled k-th
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    order: Vec<usize>,
+}
+
+impl Schedule {
+    /// Highest priority first, ties keep their original relative order
+    pub fn by_priority_desc<K: Ord>(priorities: &[K]) -> Self {
+        let mut order: Vec<usize> = (0..priorities.len()).collect();
+        order.sort_by(|&a, &b| priorities[b].cmp(&priorities[a]));
+        Self { order }
+    }
+
+    #[inline]
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// Moves `items` (original order) into processing order
+    pub fn apply<T>(&self, items: Vec<T>) -> Vec<T> {
+        assert_eq!(items.len(), self.order.len(), "Schedule length mismatch");
+        let mut slots: Vec<Option<T>> = items.into_iter().map(Some).collect();
+        self.order
+            .iter()
+            .map(|&idx| slots[idx].take().expect("Schedule is not a permutation"))
+            .collect()
+    }
+
+    /// Inverse of [`Schedule::apply`] over the first `items.len()` processed entries, pairs each
+    /// result with its original index, in original order
+    pub fn restore_prefix<T>(&self, items: Vec<T>) -> Vec<(usize, T)> {
+        assert!(items.len() <= self.order.len(), "Schedule length mismatch");
+        let mut restored: Vec<(usize, T)> = self.order.iter().copied().zip(items).collect();
+        restored.sort_unstable_by_key(|&(idx, _)| idx);
+        restored
+    }
+
+    /// Original indices left after the first `processed` entries, ascending
+    pub fn remaining(&self, processed: usize) -> Vec<usize> {
+        let mut rest = self.order[processed.min(self.order.len())..].to_vec();
+        rest.sort_unstable();
+        rest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_by_priority_desc() {
+        let schedule = Schedule::by_priority_desc(&[10u64, 30, 0, 30, 20]);
+        assert_eq!(schedule.order(), &[1, 3, 4, 0, 2]);
+        assert!(Schedule::by_priority_desc::<u64>(&[]).order().is_empty());
+    }
+
+    #[test]
+    fn test_restore_order() {
+        let schedule = Schedule::by_priority_desc(&[10u64, 30, 0, 30, 20]);
+        let items = vec!["a", "b", "c", "d", "e"];
+        let scheduled = schedule.apply(items.clone());
+        assert_eq!(scheduled, vec!["b", "d", "e", "a", "c"]);
+        // results computed in processing order land back on their original index
+        let results: Vec<String> = scheduled.iter().map(|s| s.to_uppercase()).collect();
+        let values = |restored: Vec<(usize, String)>| -> Vec<String> {
+            restored.into_iter().map(|(_, v)| v).collect()
+        };
+        assert_eq!(
+            values(schedule.restore_prefix(results)),
+            vec!["A", "B", "C", "D", "E"]
+        );
+        let restored = schedule.restore_prefix(schedule.apply(items.clone()));
+        assert_eq!(restored.iter().map(|&(_, v)| v).collect::<Vec<_>>(), items);
+        let identity = Schedule::by_priority_desc(&[0u64; 5]);
+        assert_eq!(identity.apply(items.clone()), items);
+    }
+
+    #[test]
+    fn test_restore_prefix() {
+        let schedule = Schedule::by_priority_desc(&[10u64, 30, 0, 30, 20]);
+        // stopped after "b", "d" and "e"
+        let scheduled = schedule.apply(vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(
+            schedule.restore_prefix(scheduled[..3].to_vec()),
+            vec![(1, "b"), (3, "d"), (4, "e")]
+        );
+        assert_eq!(schedule.remaining(3), vec![0, 2]);
+        assert_eq!(schedule.remaining(0), vec![0, 1, 2, 3, 4]);
+        assert!(schedule.remaining(5).is_empty());
+        assert!(schedule.restore_prefix(Vec::<&str>::new()).is_empty());
+    }
+}
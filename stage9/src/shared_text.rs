@@ -0,0 +1,466 @@
+//! Texts shared across clusters, e.g. the caption of a meme template reused on unrelated images
+//!
+//! The text anomaly logic only compares the texts of one cluster, this pass indexes the OCR text
+//! of every point of the points map and reports the texts spread over many clusters.
+
+use crate::text_cluster::find;
+use rayon::prelude::*;
+use serde::Serialize;
+use shared::text::{clean, truncate_graphemes};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use uuid::Uuid;
+
+/// Clusters a text has to be seen in for its points to get a shared text group
+pub const DEFAULT_SHARED_TEXT_MIN_CLUSTERS: usize = 3;
+
+/// Most bits two simhashes may differ in, more would not fit one band per differing bit
+pub const MAX_SIMHASH_DISTANCE: u32 = 15;
+
+/// Distinct simhashes in a band bucket past which they are no longer compared pairwise
+const MAX_BUCKET: usize = 512;
+/// Hashes following it in hash order each hash of an oversized bucket is compared to
+const BUCKET_WINDOW: usize = 64;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// [`clean`]ed, lowercased, whitespace runs collapsed to one space and trimmed, `None` if nothing
+/// is left
+pub fn normalize_text(text: &str) -> Option<String> {
+    let normalized = clean(text)
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!normalized.is_empty()).then_some(normalized)
+}
+
+/// Stable across builds and platforms, unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(FNV_OFFSET, |h, &b| (h ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// 64 bit simhash of the character trigrams, texts differing by a few characters differ in few
+/// bits, trigrams rather than words so texts without spaces (CJK) work too
+pub fn simhash(text: &str) -> u64 {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return 0;
+    }
+    let mut weights = [0i32; 64];
+    let mut shingle = String::new();
+    for window in chars.windows(3.min(chars.len())) {
+        shingle.clear();
+        shingle.extend(window);
+        let hash = fnv1a(shingle.as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += match hash >> bit & 1 {
+                1 => 1,
+                _ => -1,
+            };
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| **w > 0)
+        .fold(0, |h, (bit, _)| h | 1 << bit)
+}
+
+/// Points of every distinct normalized text, each text stored once and referred to by position
+#[derive(Debug, Default)]
+pub struct TextIndex {
+    texts: Vec<String>,
+    points: Vec<Vec<Uuid>>,
+}
+
+impl TextIndex {
+    /// Built in parallel, every worker interns the texts it sees and the maps are merged, texts
+    /// and their points end up sorted
+    pub fn build<'a, I>(points: I) -> Self
+    where
+        I: IntoParallelIterator<Item = (Uuid, &'a str)>,
+    {
+        let merged = points
+            .into_par_iter()
+            .filter_map(|(id, text)| Some((id, normalize_text(text)?)))
+            .fold(
+                HashMap::new,
+                |mut map: HashMap<String, Vec<Uuid>>, (id, text)| {
+                    map.entry(text).or_default().push(id);
+                    map
+                },
+            )
+            .reduce(HashMap::new, |a, b| {
+                let (mut big, small) = match a.len() >= b.len() {
+                    true => (a, b),
+                    false => (b, a),
+                };
+                for (text, ids) in small {
+                    big.entry(text).or_default().extend(ids);
+                }
+                big
+            });
+        let mut entries: Vec<(String, Vec<Uuid>)> = merged.into_iter().collect();
+        entries.par_sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let (texts, mut points): (Vec<String>, Vec<Vec<Uuid>>) = entries.into_iter().unzip();
+        points.par_iter_mut().for_each(|ids| ids.sort_unstable());
+        Self { texts, points }
+    }
+
+    /// Distinct normalized texts
+    pub fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty()
+    }
+
+    /// Points carrying a text
+    pub fn point_count(&self) -> usize {
+        self.points.iter().map(Vec::len).sum()
+    }
+
+    /// Texts whose simhashes are within `max_distance` bits, chained, each text alone if `None`
+    ///
+    /// Texts sharing a simhash are one group before any comparison. A band bucket holding more
+    /// than [`MAX_BUCKET`] distinct hashes compares each only to the [`BUCKET_WINDOW`] next ones
+    /// in hash order, so near duplicates differing in high bits may stay apart there.
+    fn groups(&self, max_distance: Option<u32>) -> Vec<Vec<usize>> {
+        let Some(max_distance) = max_distance.map(|d| d.min(MAX_SIMHASH_DISTANCE)) else {
+            return (0..self.len()).map(|idx| vec![idx]).collect();
+        };
+        let hashes: Vec<u64> = self.texts.par_iter().map(|t| simhash(t)).collect();
+        let mut parent: Vec<usize> = (0..self.len()).collect();
+        let mut first_of: HashMap<u64, usize> = HashMap::new();
+        for (idx, &hash) in hashes.iter().enumerate() {
+            parent[idx] = *first_of.entry(hash).or_insert(idx);
+        }
+        let mut distinct: Vec<(u64, usize)> = first_of.into_iter().collect();
+        distinct.sort_unstable();
+        // texts within d bits agree on at least one of d + 1 bands
+        let bands = max_distance as usize + 1;
+        let bounds: Vec<u32> = (0..=bands).map(|b| (b * 64 / bands) as u32).collect();
+        for band in bounds.windows(2) {
+            let mask = match band[1] - band[0] {
+                64 => u64::MAX,
+                width => ((1u64 << width) - 1) << band[0],
+            };
+            let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+            for (pos, (hash, _)) in distinct.iter().enumerate() {
+                buckets.entry(hash & mask).or_default().push(pos);
+            }
+            for bucket in buckets.values().filter(|b| b.len() > 1) {
+                let window = match bucket.len() > MAX_BUCKET {
+                    true => BUCKET_WINDOW,
+                    false => bucket.len(),
+                };
+                for (pos, &a) in bucket.iter().enumerate() {
+                    for &b in bucket[pos + 1..].iter().take(window) {
+                        let ((hash_a, i), (hash_b, j)) = (distinct[a], distinct[b]);
+                        if (hash_a ^ hash_b).count_ones() <= max_distance {
+                            let (root_i, root_j) = (find(&mut parent, i), find(&mut parent, j));
+                            parent[root_i.max(root_j)] = root_i.min(root_j);
+                        }
+                    }
+                }
+            }
+        }
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for idx in 0..self.len() {
+            groups.entry(find(&mut parent, idx)).or_default().push(idx);
+        }
+        groups.into_values().collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SharedTextConfig {
+    pub min_clusters: usize,
+    /// Texts within this many simhash bits are one text, only identical texts when `None`
+    pub max_distance: Option<u32>,
+    /// Most duplicated texts listed in the report
+    pub top: usize,
+    /// Reported texts are cut to this many graphemes, grouping sees them whole
+    pub max_text_graphemes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TextCount {
+    pub text: String,
+    pub points: usize,
+    pub clusters: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SharedTextGroup {
+    pub shared_text_group_id: usize,
+    /// The variant most points carry
+    pub text: String,
+    /// Distinct normalized texts merged into the group, 1 unless near duplicates are merged
+    pub variants: usize,
+    pub points: Vec<Uuid>,
+    /// Indices of the loaded clusters, as [`FinalClassification::cluster_index`]
+    ///
+    /// [`FinalClassification::cluster_index`]: shared::structure::FinalClassification::cluster_index
+    pub clusters: Vec<usize>,
+    /// Points in no cluster, each counting as a cluster of its own
+    pub unclustered: usize,
+}
+
+impl SharedTextGroup {
+    /// Clusters the text was seen in
+    pub fn spread(&self) -> usize {
+        self.clusters.len() + self.unclustered
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SharedTextReport {
+    pub config: Option<SharedTextConfig>,
+    pub text_points: usize,
+    pub distinct_texts: usize,
+    /// Texts carried by the most points, most first
+    pub top: Vec<TextCount>,
+    /// Texts seen in at least `min_clusters` clusters, most spread first
+    pub groups: Vec<SharedTextGroup>,
+}
+
+/// Groups the texts of `index` and reports those seen in at least `config.min_clusters` clusters,
+/// `cluster_of` maps a point to its cluster index
+pub fn shared_texts(
+    index: &TextIndex,
+    cluster_of: &HashMap<Uuid, usize>,
+    config: &SharedTextConfig,
+) -> SharedTextReport {
+    let mut groups: Vec<SharedTextGroup> = index
+        .groups(config.max_distance)
+        .into_par_iter()
+        .map(|members| {
+            let text = members
+                .iter()
+                .max_by(|&&a, &&b| {
+                    let (pa, pb) = (index.points[a].len(), index.points[b].len());
+                    pa.cmp(&pb).then(index.texts[b].cmp(&index.texts[a]))
+                })
+                .map(|&idx| {
+                    truncate_graphemes(&index.texts[idx], config.max_text_graphemes).into_owned()
+                })
+                .unwrap_or_default();
+            let mut points: Vec<Uuid> = members
+                .iter()
+                .flat_map(|&idx| index.points[idx].iter().copied())
+                .collect();
+            points.sort_unstable();
+            let mut clusters = BTreeSet::new();
+            let mut unclustered = 0;
+            for id in points.iter() {
+                match cluster_of.get(id) {
+                    Some(&cluster) => {
+                        clusters.insert(cluster);
+                    }
+                    None => unclustered += 1,
+                }
+            }
+            SharedTextGroup {
+                shared_text_group_id: 0,
+                text,
+                variants: members.len(),
+                points,
+                clusters: clusters.into_iter().collect(),
+                unclustered,
+            }
+        })
+        .collect();
+    groups.sort_unstable_by(|a, b| {
+        b.points
+            .len()
+            .cmp(&a.points.len())
+            .then_with(|| a.text.cmp(&b.text))
+    });
+    let top = groups
+        .iter()
+        .take(config.top)
+        .map(|g| TextCount {
+            text: g.text.clone(),
+            points: g.points.len(),
+            clusters: g.spread(),
+        })
+        .collect();
+    groups.retain(|g| g.spread() >= config.min_clusters.max(2));
+    groups.sort_by_key(|g| Reverse(g.spread()));
+    for (id, group) in groups.iter_mut().enumerate() {
+        group.shared_text_group_id = id;
+    }
+    SharedTextReport {
+        config: Some(config.clone()),
+        text_points: index.point_count(),
+        distinct_texts: index.len(),
+        top,
+        groups,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(min_clusters: usize, max_distance: Option<u32>) -> SharedTextConfig {
+        SharedTextConfig {
+            min_clusters,
+            max_distance,
+            top: 10,
+            max_text_graphemes: shared::text::DEFAULT_MAX_GRAPHEMES,
+        }
+    }
+
+    #[test]
+    fn test_normalize_text() {
+        assert_eq!(
+            normalize_text("  When  the\tCODE\n compiles ").as_deref(),
+            Some("when the code compiles")
+        );
+        assert_eq!(normalize_text("ÄÖ  ü").as_deref(), Some("äö ü"));
+        assert_eq!(normalize_text(" \n\t "), None);
+        assert_eq!(normalize_text(""), None);
+        // composed and decomposed, with stray controls, are one text
+        assert_eq!(
+            normalize_text("Cafe\u{301}\u{0} \u{202e}AU\nLAIT"),
+            normalize_text("café au lait")
+        );
+        assert_eq!(normalize_text("\u{7}\u{200f}\u{202c}"), None);
+    }
+
+    #[test]
+    fn test_simhash() {
+        let distance = |a: &str, b: &str| (simhash(a) ^ simhash(b)).count_ones();
+        let text = "nobody: absolutely nobody: me at 3am reading the documentation";
+        assert_eq!(simhash(text), simhash(text));
+        assert_eq!(simhash(""), 0);
+        assert_ne!(simhash("ab"), 0);
+        let close = distance(
+            text,
+            "nobody: absolutely nobody: me at 4am reading the documentation",
+        );
+        let far = distance(
+            text,
+            "this is fine, the house is on fire and i am drinking coffee",
+        );
+        assert!(close <= 8, "{}", close);
+        assert!(far > close + 8, "{} {}", far, close);
+        // no spaces to split words on
+        assert!(distance("今日は良い天気ですね本当に", "今日は良い天気ですね本当だ") <= 8);
+    }
+
+    #[test]
+    fn test_shared_texts() {
+        let id = Uuid::from_u128;
+        let texts = [
+            (1, "Me  at 3am"),
+            (2, "me at 3am"),
+            (3, "ME AT 3AM "),
+            (4, "me at 3am"),
+            (5, "unique caption"),
+            (6, "  "),
+            (7, "me at 4am"),
+        ];
+        let index = TextIndex::build(texts.iter().map(|&(n, t)| (id(n), t)).collect::<Vec<_>>());
+        assert_eq!((index.len(), index.point_count()), (3, 6));
+        // 1 and 2 in one cluster, 3 in another, 4 in none
+        let cluster_of = HashMap::from([(id(1), 10), (id(2), 10), (id(3), 11), (id(7), 12)]);
+
+        let report = shared_texts(&index, &cluster_of, &config(3, None));
+        assert_eq!(report.groups.len(), 1);
+        let group = &report.groups[0];
+        assert_eq!(group.text, "me at 3am");
+        assert_eq!(group.points, (1..=4).map(id).collect::<Vec<_>>());
+        assert_eq!(
+            (group.clusters.clone(), group.unclustered),
+            (vec![10, 11], 1)
+        );
+        assert_eq!(
+            report.top[0],
+            TextCount {
+                text: "me at 3am".to_string(),
+                points: 4,
+                clusters: 3,
+            }
+        );
+        assert_eq!(report.top.len(), 3);
+        assert!(
+            shared_texts(&index, &cluster_of, &config(4, None))
+                .groups
+                .is_empty()
+        );
+
+        // near duplicates merged, the most common variant names the group
+        let report = shared_texts(&index, &cluster_of, &config(4, Some(12)));
+        assert_eq!(report.groups.len(), 1);
+        let group = &report.groups[0];
+        assert_eq!((group.text.as_str(), group.variants), ("me at 3am", 2));
+        assert_eq!(group.clusters, vec![10, 11, 12]);
+    }
+
+    #[test]
+    fn test_long_texts_truncated() {
+        let id = Uuid::from_u128;
+        let long = "草".repeat(50_000);
+        let index = TextIndex::build((1..=3).map(|n| (id(n), long.as_str())).collect::<Vec<_>>());
+        let cluster_of = HashMap::from([(id(1), 0), (id(2), 1), (id(3), 2)]);
+        let mut config = config(3, Some(4));
+        config.max_text_graphemes = 5;
+        let report = shared_texts(&index, &cluster_of, &config);
+        assert_eq!(report.groups[0].text, "草草草草草…");
+        assert_eq!(report.top[0].text, "草草草草草…");
+    }
+
+    #[test]
+    fn test_groups_bounded() {
+        let texts: Vec<(Uuid, String)> = (0..20_000u128)
+            .map(|n| {
+                (
+                    Uuid::from_u128(n),
+                    format!("caption number {} of many", n % 10_000),
+                )
+            })
+            .collect();
+        let index = TextIndex::build(
+            texts
+                .iter()
+                .map(|(id, t)| (*id, t.as_str()))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(index.len(), 10_000);
+        // 16 bands of 4 bits, buckets of far more than MAX_BUCKET texts
+        let groups = index.groups(Some(MAX_SIMHASH_DISTANCE));
+        let mut seen: Vec<usize> = groups.iter().flatten().copied().collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..index.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_build_matches_sequential() {
+        let texts: Vec<(Uuid, String)> = (0..5000u128)
+            .map(|n| (Uuid::from_u128(n), format!("Caption  {}", n % 37)))
+            .collect();
+        let index = TextIndex::build(
+            texts
+                .iter()
+                .map(|(id, t)| (*id, t.as_str()))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!((index.len(), index.point_count()), (37, 5000));
+        for (text, points) in index.texts.iter().zip(&index.points) {
+            let n: u128 = text.trim_start_matches("caption ").parse().unwrap();
+            let expected: Vec<Uuid> = (0..5000u128)
+                .filter(|m| m % 37 == n)
+                .map(Uuid::from_u128)
+                .collect();
+            assert_eq!(points, &expected);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Clustering of the text points of one cluster, greedy up to a member-count cap and
+//! union-find over the pairs above the threshold past it
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Text points of one cluster above which the greedy clustering gives way to union-find
+pub const DEFAULT_TEXT_CLUSTER_CAP: usize = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextStrategy {
+    /// A point joins the first cluster it is above the threshold with every member of
+    Greedy,
+    /// Connected components of the pairs above the threshold, members need not all be similar
+    UnionFind,
+}
+
+impl TextStrategy {
+    #[inline]
+    pub fn for_points(text_points: usize, cap: usize) -> Self {
+        match text_points > cap {
+            true => TextStrategy::UnionFind,
+            false => TextStrategy::Greedy,
+        }
+    }
+}
+
+/// Text anomaly clusters of one cluster and the strategy that found them
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextClusters<'a> {
+    pub strategy: TextStrategy,
+    pub clusters: Vec<Vec<&'a Uuid>>,
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm > 0.0 {
+        true => vector.iter().map(|x| x / norm).collect(),
+        false => vec![0.0; vector.len()],
+    }
+}
+
+pub(crate) fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Groups `points` linked by a chain of pairs whose cosine similarity is above `threshold`,
+/// clusters and their members in the order of `points`
+pub fn union_find_clusters<'a>(
+    points: &[(&'a Uuid, &[f32])],
+    threshold: f32,
+) -> Vec<Vec<&'a Uuid>> {
+    let vectors: Vec<Vec<f32>> = points.par_iter().map(|(_, v)| normalized(v)).collect();
+    let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let edges: Vec<(usize, usize)> = (0..vectors.len())
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let vectors = &vectors;
+            (i + 1..vectors.len())
+                .filter(move |&j| dot(&vectors[i], &vectors[j]) > threshold)
+                .map(move |j| (i, j))
+        })
+        .collect();
+    let mut parent: Vec<usize> = (0..points.len()).collect();
+    for (i, j) in edges {
+        let (root_i, root_j) = (find(&mut parent, i), find(&mut parent, j));
+        if root_i != root_j {
+            parent[root_j.max(root_i)] = root_i.min(root_j);
+        }
+    }
+    let mut cluster_of_root: Vec<Option<usize>> = vec![None; points.len()];
+    let mut clusters: Vec<Vec<&Uuid>> = Vec::new();
+    for (i, &(id, _)) in points.iter().enumerate() {
+        let root = find(&mut parent, i);
+        let idx = *cluster_of_root[root].get_or_insert_with(|| {
+            clusters.push(Vec::new());
+            clusters.len() - 1
+        });
+        clusters[idx].push(id);
+    }
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_points() {
+        assert_eq!(TextStrategy::for_points(3, 3), TextStrategy::Greedy);
+        assert_eq!(TextStrategy::for_points(4, 3), TextStrategy::UnionFind);
+        assert_eq!(TextStrategy::for_points(0, 0), TextStrategy::Greedy);
+    }
+
+    #[test]
+    fn test_union_find_chains() {
+        let ids: Vec<Uuid> = (1..=4).map(Uuid::from_u128).collect();
+        // a ~ b and b ~ c but a !~ c, d is alone
+        let vectors: [&[f32]; 4] = [&[1.0, 0.0], &[0.8, 0.6], &[0.28, 0.96], &[-1.0, 0.0]];
+        let points: Vec<(&Uuid, &[f32])> = ids.iter().zip(vectors).collect();
+        let clusters = union_find_clusters(&points, 0.7);
+        assert_eq!(
+            clusters,
+            vec![vec![&ids[0], &ids[1], &ids[2]], vec![&ids[3]]]
+        );
+        assert_eq!(union_find_clusters(&points, 0.99).len(), 4);
+        assert!(union_find_clusters(&[], 0.7).is_empty());
+    }
+}
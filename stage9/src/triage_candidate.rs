@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use shared::structure::{NekoPoint, NekoPointExt, NekoPointExtResource};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Extensions of the formats that animate, a plain `.png` is almost always still
+pub const DEFAULT_ANIMATED_EXTS: [&str; 3] = ["gif", "webp", "apng"];
+
+/// Per-point exception to the extension rule, e.g. a single-frame `.gif`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TreatAs {
+    Animated,
+    Static,
+}
+
+/// Decides which cluster members go through the GIF/CLIP triage path
+#[derive(Debug, Clone)]
+pub struct AnimatedCandidate {
+    extensions: HashSet<String>,
+    overrides: HashMap<Uuid, TreatAs>,
+}
+
+impl Default for AnimatedCandidate {
+    fn default() -> Self {
+        Self::new(DEFAULT_ANIMATED_EXTS)
+    }
+}
+
+impl AnimatedCandidate {
+    pub fn new<I, S>(extensions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self {
+            extensions: extensions
+                .into_iter()
+                .map(|ext| ext.as_ref().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|ext| !ext.is_empty())
+                .collect(),
+            overrides: HashMap::new(),
+        }
+    }
+
+    pub fn with_overrides(mut self, overrides: HashMap<Uuid, TreatAs>) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// JSON object of `uuid -> "animated" | "static"`
+    pub fn load_overrides<P: AsRef<Path>>(path: P) -> anyhow::Result<HashMap<Uuid, TreatAs>> {
+        let data = fs::read(path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    #[inline]
+    pub fn extensions(&self) -> &HashSet<String> {
+        &self.extensions
+    }
+
+    pub fn is_candidate(&self, id: &Uuid, ext: &str) -> bool {
+        match self.overrides.get(id) {
+            Some(TreatAs::Animated) => true,
+            Some(TreatAs::Static) => false,
+            None => self.extensions.contains(&ext.to_ascii_lowercase()),
+        }
+    }
+}
+
+/// `<dir>/<uuid>.<ext>` keeping the point's real extension
+#[inline]
+pub fn local_triage_path(dir: &str, id: &Uuid, ext: &str) -> String {
+    format!("{}/{}.{}", dir.trim_end_matches('/'), id, ext)
+}
+
+/// Local download target for every triage candidate, derived from the S3 entry path
+pub fn triage_path_map<'a>(
+    ids: &[&'a Uuid],
+    points_metadata: &HashMap<Uuid, (NekoPoint, NekoPointExt)>,
+    dir: &str,
+) -> HashMap<&'a Uuid, String> {
+    ids.iter()
+        .filter_map(|&id| {
+            let (_, ext) = points_metadata.get(id)?;
+            Some((id, local_triage_path(dir, id, ext.ext())))
+        })
+        .collect()
+}
+
+/// Remote object path of a point as recorded in the S3 listing
+pub fn remote_path<'a>(
+    id: &Uuid,
+    points_metadata: &'a HashMap<Uuid, (NekoPoint, NekoPointExt)>,
+) -> Option<&'a str> {
+    match points_metadata.get(id)?.1.source.as_ref()? {
+        NekoPointExtResource::Local(path) => Some(path.as_str()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(id: Uuid, path: &str) -> (Uuid, (NekoPoint, NekoPointExt)) {
+        let pt = NekoPoint {
+            id,
+            height: 1,
+            width: 1,
+            size: None,
+            categories: None,
+            text_info: None,
+            qdrant_num_id: None,
+            extra: None,
+        };
+        let ext = NekoPointExt {
+            source: Some(NekoPointExtResource::Local(path.to_string())),
+        };
+        (id, (pt, ext))
+    }
+
+    #[test]
+    fn test_default_extensions() {
+        let candidate = AnimatedCandidate::default();
+        let id = Uuid::new_v4();
+        assert!(candidate.is_candidate(&id, "gif"));
+        assert!(candidate.is_candidate(&id, "GIF"));
+        assert!(candidate.is_candidate(&id, "webp"));
+        assert!(candidate.is_candidate(&id, "apng"));
+        assert!(!candidate.is_candidate(&id, "png"));
+        assert!(!candidate.is_candidate(&id, "mp4"));
+    }
+
+    #[test]
+    fn test_extensions_and_overrides() {
+        let animated = Uuid::new_v4();
+        let single_frame = Uuid::new_v4();
+        let candidate =
+            AnimatedCandidate::new(["gif", ".WebP", "mp4", ""]).with_overrides(HashMap::from([
+                (animated, TreatAs::Animated),
+                (single_frame, TreatAs::Static),
+            ]));
+        assert_eq!(candidate.extensions().len(), 3);
+        let other = Uuid::new_v4();
+        assert!(candidate.is_candidate(&other, "webp"));
+        assert!(candidate.is_candidate(&other, "mp4"));
+        assert!(!candidate.is_candidate(&other, "jpg"));
+        assert!(candidate.is_candidate(&animated, "png"));
+        assert!(!candidate.is_candidate(&single_frame, "gif"));
+    }
+
+    #[test]
+    fn test_overrides_json() {
+        let id = Uuid::new_v4();
+        let json = format!(r#"{{"{}": "static"}}"#, id);
+        let overrides: HashMap<Uuid, TreatAs> = serde_json::from_str(&json).unwrap();
+        assert_eq!(overrides.get(&id), Some(&TreatAs::Static));
+    }
+
+    #[test]
+    fn test_path_construction_mixed_extensions() {
+        let (gif, webp, mp4) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let metadata: HashMap<Uuid, (NekoPoint, NekoPointExt)> = HashMap::from([
+            point(gif, &format!("NekoImage/{}.gif", gif)),
+            point(webp, &format!("NekoImage/{}.webp", webp)),
+            point(mp4, &format!("NekoImage/{}.mp4", mp4)),
+        ]);
+        let cluster = [&gif, &webp, &mp4];
+        let paths = triage_path_map(&cluster, &metadata, "nekoimg_stage9_gifs/");
+        assert_eq!(paths[&gif], format!("nekoimg_stage9_gifs/{}.gif", gif));
+        assert_eq!(paths[&webp], format!("nekoimg_stage9_gifs/{}.webp", webp));
+        assert_eq!(paths[&mp4], format!("nekoimg_stage9_gifs/{}.mp4", mp4));
+        assert_eq!(
+            remote_path(&webp, &metadata),
+            Some(format!("NekoImage/{}.webp", webp).as_str())
+        );
+        assert_eq!(remote_path(&Uuid::new_v4(), &metadata), None);
+    }
+}